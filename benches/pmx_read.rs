@@ -0,0 +1,197 @@
+//! Times reading the vertices of a synthetic 500k-vertex model through a `dyn Read`, and the
+//! whole model with its 500k triangles through `Pmx::read` and from memory with `Pmx::parse`.
+//!
+//! Run with `cargo bench --bench pmx_read`. The field-by-field reader below is how vertices used
+//! to be read and is kept as a baseline. When `Pmx::parse` was added it took about two thirds of
+//! the time of `Pmx::read` on the same bytes, and reporting progress didn't make it measurably
+//! slower.
+//!
+//! With `--features rayon` it also times `Pmx::parse` with the vertices, triangles and morphs
+//! decoded on the rayon thread pool, and prints the number of threads it ran on. The size pass in
+//! front of the vertices and morphs is sequential and costs about a fifth of the sequential
+//! parse, so the parallel path only pays off with a few threads. On 1 thread it took 148ms
+//! against 87ms sequentially, which is why it's skipped there whatever `parallel_min_bytes` is;
+//! it hasn't been timed on more cores yet.
+
+// The arrays are converted into the vek types with the vek feature
+#![allow(clippy::useless_conversion)]
+
+use std::io::{Cursor, Read};
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+
+use byteorder::{ReadBytesExt, LE};
+use mmd::pmx::validate::Section;
+use mmd::pmx::weight_deform::{Bdef2, WeightDeform};
+use mmd::{
+  DefaultConfig, HeaderReader, IndexSize, Pmx, PmxReadOptions, Settings, TextEncoding, Vertex,
+  VertexReader,
+};
+
+const VERTICES: u32 = 500_000;
+const RUNS: usize = 10;
+
+fn model() -> Vec<u8> {
+  let vertices = (0..VERTICES)
+    .map(|i| Vertex::<DefaultConfig> {
+      position: [i as f32, 1.0, 2.0].into(),
+      normal: [0.0, 1.0, 0.0].into(),
+      uv: [0.5, 0.5].into(),
+      additional: Default::default(),
+      weight_deform: WeightDeform::Bdef2(Bdef2 {
+        bone_1_index: (i % 100) as i32,
+        bone_2_index: (i % 100 + 1) as i32,
+        bone_1_weight: 0.75,
+      }),
+      edge_scale: 1.0,
+    })
+    .collect();
+  let pmx = Pmx {
+    version: 2.0,
+    settings: Settings {
+      text_encoding: TextEncoding::UTF8,
+      additional_vec4_count: 0,
+      vertex_index_size: IndexSize::I32,
+      texture_index_size: IndexSize::I8,
+      material_index_size: IndexSize::I8,
+      bone_index_size: IndexSize::I16,
+      morph_index_size: IndexSize::I8,
+      rigidbody_index_size: IndexSize::I8,
+    },
+    model_local_name: String::new(),
+    model_universal_name: String::new(),
+    local_comments: String::new(),
+    universal_comments: String::new(),
+    extra_globals: vec![],
+    vertices,
+    surfaces: (0..VERTICES as i32)
+      .map(|i| [i, (i + 1) % VERTICES as i32, (i + 2) % VERTICES as i32])
+      .collect(),
+    textures: vec![],
+    materials: vec![],
+    bones: vec![],
+    morphs: vec![],
+    display_frames: vec![],
+    rigid_bodies: vec![],
+    joints: vec![],
+    soft_bodies: vec![],
+    skipped_sections: Default::default(),
+  };
+
+  let mut bytes = Vec::new();
+  pmx.write(&mut bytes).unwrap();
+  bytes
+}
+
+/// Decodes a BDEF2 vertex with one call into the reader per field.
+fn read_by_field<R: Read>(read: &mut R) -> Vertex<DefaultConfig> {
+  let mut v = [0.0; 8];
+  for c in v.iter_mut() {
+    *c = read.read_f32::<LE>().unwrap();
+  }
+  assert_eq!(read.read_u8().unwrap(), 1);
+  let weight_deform = WeightDeform::Bdef2(Bdef2 {
+    bone_1_index: read.read_i16::<LE>().unwrap().into(),
+    bone_2_index: read.read_i16::<LE>().unwrap().into(),
+    bone_1_weight: read.read_f32::<LE>().unwrap(),
+  });
+
+  Vertex {
+    position: [v[0], v[1], v[2]].into(),
+    normal: [v[3], v[4], v[5]].into(),
+    uv: [v[6], v[7]].into(),
+    additional: Default::default(),
+    weight_deform,
+    edge_scale: read.read_f32::<LE>().unwrap(),
+  }
+}
+
+/// Best time of a few runs, to keep noise out.
+fn time(mut f: impl FnMut()) -> Duration {
+  (0..RUNS)
+    .map(|_| {
+      let start = Instant::now();
+      f();
+      start.elapsed()
+    })
+    .min()
+    .unwrap()
+}
+
+fn main() {
+  let bytes = model();
+
+  let by_field = time(|| {
+    let mut cursor = Cursor::new(&bytes);
+    HeaderReader::new(&mut cursor).unwrap();
+    let mut read: &mut dyn Read = &mut cursor;
+    assert_eq!(read.read_i32::<LE>().unwrap(), VERTICES as i32);
+    for _ in 0..VERTICES {
+      std::hint::black_box(read_by_field(&mut read));
+    }
+  });
+  let records = time(|| {
+    let mut cursor = Cursor::new(&bytes);
+    let read: &mut dyn Read = &mut cursor;
+    let mut vertices = VertexReader::new(HeaderReader::new(read).unwrap()).unwrap();
+    for vertex in vertices.iter::<DefaultConfig>() {
+      std::hint::black_box(vertex.unwrap());
+    }
+  });
+  let read = time(|| {
+    std::hint::black_box(Pmx::<DefaultConfig>::read(&bytes[..]).unwrap());
+  });
+  let parse = time(|| {
+    std::hint::black_box(Pmx::<DefaultConfig>::parse(&bytes).unwrap());
+  });
+  let progress = time(|| {
+    let mut on_progress = |_, _, _| ControlFlow::Continue(());
+    let mut options = PmxReadOptions {
+      on_progress: Some(&mut on_progress),
+      ..Default::default()
+    };
+    std::hint::black_box(Pmx::<DefaultConfig>::parse_with(&bytes, &mut options).unwrap());
+  });
+  let skip = time(|| {
+    let mut options = PmxReadOptions {
+      skip: Section::Vertices | Section::Surfaces,
+      ..Default::default()
+    };
+    std::hint::black_box(Pmx::<DefaultConfig>::read_with(&bytes[..], &mut options).unwrap());
+  });
+  #[cfg(feature = "rayon")]
+  let parallel = {
+    let parse = |parallel_min_bytes| {
+      let mut options = PmxReadOptions {
+        parallel_min_bytes,
+        ..Default::default()
+      };
+      Pmx::<DefaultConfig>::parse_with(&bytes, &mut options).unwrap()
+    };
+    (
+      time(|| {
+        std::hint::black_box(parse(usize::MAX));
+      }),
+      time(|| {
+        std::hint::black_box(parse(0));
+      }),
+    )
+  };
+  println!("{} vertices", VERTICES);
+  println!("  field by field:      {:?}", by_field);
+  println!("  VertexReader:        {:?}", records);
+  println!("whole model");
+  println!("  Pmx::read:           {:?}", read);
+  println!("  Pmx::parse:          {:?}", parse);
+  println!("  with progress:       {:?}", progress);
+  println!("  skipping the mesh:   {:?}", skip);
+  #[cfg(feature = "rayon")]
+  {
+    println!(
+      "Pmx::parse with rayon on {} threads",
+      rayon::current_num_threads()
+    );
+    println!("  sequential:          {:?}", parallel.0);
+    println!("  parallel:            {:?}", parallel.1);
+  }
+}
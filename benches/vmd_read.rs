@@ -0,0 +1,95 @@
+//! Times reading a synthetic 100k-keyframe motion section through a `dyn Read`.
+//!
+//! Run with `cargo bench --bench vmd_read`. The field-by-field reader below is how records used to
+//! be read and is kept as a baseline.
+
+// The arrays are converted into the vek types with the vek feature
+#![allow(clippy::useless_conversion)]
+
+use std::io::{Cursor, Read};
+use std::time::{Duration, Instant};
+
+use byteorder::{ReadBytesExt, LE};
+use encoding_rs::SHIFT_JIS;
+use mmd::vmd::MotionFrame;
+use mmd::DefaultConfig;
+
+const FRAMES: u32 = 100_000;
+const RUNS: usize = 10;
+
+fn section() -> Vec<u8> {
+  let frames = (0..FRAMES)
+    .map(|i| MotionFrame::<DefaultConfig> {
+      name: format!("bone{}", i % 100),
+      raw_name: None,
+      frame_no: i / 100,
+      position: [i as f32, 1.0, 2.0].into(),
+      rotation: [0.0, 0.0, 0.0, 1.0].into(),
+      interpolation: [20; 64],
+    })
+    .collect::<Vec<_>>();
+
+  let mut bytes = Vec::new();
+  MotionFrame::write_all(&mut bytes, &frames).unwrap();
+  bytes
+}
+
+/// Decodes a record with one call into the reader per field.
+fn read_by_field<R: Read>(read: &mut R) -> MotionFrame {
+  let mut raw_name = [0; 15];
+  read.read_exact(&mut raw_name).unwrap();
+  let len = raw_name.iter().position(|&x| x == 0).unwrap_or(15);
+  let name = SHIFT_JIS.decode(&raw_name[..len]).0.into_owned();
+  let frame_no = read.read_u32::<LE>().unwrap();
+  let mut values = [0.0; 7];
+  for v in values.iter_mut() {
+    *v = read.read_f32::<LE>().unwrap();
+  }
+  let mut interpolation = [0; 64];
+  read.read_exact(&mut interpolation).unwrap();
+
+  MotionFrame {
+    name,
+    raw_name: Some(raw_name),
+    frame_no,
+    position: [values[0], values[1], values[2]].into(),
+    rotation: [values[3], values[4], values[5], values[6]].into(),
+    interpolation,
+  }
+}
+
+/// Best time of a few runs, to keep noise out.
+fn time(mut f: impl FnMut()) -> Duration {
+  (0..RUNS)
+    .map(|_| {
+      let start = Instant::now();
+      f();
+      start.elapsed()
+    })
+    .min()
+    .unwrap()
+}
+
+fn main() {
+  let bytes = section();
+
+  let by_field = time(|| {
+    let mut cursor = Cursor::new(&bytes[4..]);
+    let mut read: &mut dyn Read = &mut cursor;
+    let frames = (0..FRAMES)
+      .map(|_| read_by_field(&mut read))
+      .collect::<Vec<_>>();
+    std::hint::black_box(frames);
+  });
+  let records = time(|| {
+    let mut cursor = Cursor::new(&bytes);
+    let mut read: &mut dyn Read = &mut cursor;
+    let frames = MotionFrame::<DefaultConfig>::read_all(&mut read).unwrap();
+    assert_eq!(frames.len(), FRAMES as usize);
+    std::hint::black_box(frames);
+  });
+
+  println!("{} motion frames", FRAMES);
+  println!("  field by field:        {:?}", by_field);
+  println!("  MotionFrame::read_all: {:?}", records);
+}
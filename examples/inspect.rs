@@ -4,7 +4,7 @@ use std::fs::File;
 use std::io::BufReader;
 
 fn main() -> Result<(), Error> {
-  let filename = env::args().skip(1).next().unwrap();
+  let filename = env::args().nth(1).unwrap();
   println!("Inspect file: {}", filename);
 
   use mmd::pmx::reader::*;
@@ -12,6 +12,7 @@ fn main() -> Result<(), Error> {
   let header = HeaderReader::new(BufReader::new(File::open(filename)?))?;
 
   println!("{}", header);
+  let version = header.version;
 
   let vertices = VertexReader::<_>::new(header)?;
   println!("\nVertex count: {}", vertices.count);
@@ -61,5 +62,13 @@ fn main() -> Result<(), Error> {
     println!("\n{}) {}", i, j?);
   }
 
+  if mmd::pmx::model::has_soft_bodies(version) {
+    let mut soft_bodies = SoftBodyReader::<_>::new(joints)?;
+    println!("\n\nSoft Bodies:");
+    for (i, s) in soft_bodies.iter::<DefaultConfig>().enumerate() {
+      println!("\n{}) {}", i, s?);
+    }
+  }
+
   Ok(())
 }
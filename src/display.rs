@@ -1,4 +1,4 @@
-use std::fmt::Display;
+use core::fmt::Display;
 
 #[repr(transparent)]
 pub(crate) struct DisplayOption<'a, T>(Option<&'a T>);
@@ -10,7 +10,7 @@ impl<'a, T> DisplayOption<'a, T> {
 }
 
 impl<'a, T: Display> Display for DisplayOption<'a, T> {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+  fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
     if let Some(t) = self.0 {
       T::fmt(t, f)
     } else {
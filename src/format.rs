@@ -0,0 +1,155 @@
+//! Telling the formats apart by their first bytes, for files with wrong or missing extensions.
+
+use crate::io::{Cursor, Read};
+use crate::pmd::Pmd;
+use crate::vmd::Vmd;
+use crate::vpd::Vpd;
+use crate::{Config, DefaultConfig, Error, Pmx, Result};
+use alloc::vec::Vec;
+
+const PMX_MAGIC: &[u8] = b"PMX ";
+
+/// The bytes `sniff` looks at at most, the longest magic behind a UTF-8 byte order mark.
+pub const SNIFF_LEN: usize = 26;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Format {
+  Vmd,
+  Vpd,
+  Pmx,
+  Pmd,
+}
+
+/// The format of a file by its magic, given at least its first `SNIFF_LEN` bytes or the whole
+/// file if it's shorter.
+///
+/// Both VMD versions are recognized, and VPD poses in Shift_JIS as well as in UTF-8 with or
+/// without a byte order mark.
+pub fn sniff(bytes: &[u8]) -> Option<Format> {
+  let text = bytes.strip_prefix(crate::vpd::UTF8_BOM).unwrap_or(bytes);
+  if bytes.starts_with(crate::vmd::VMD_HEADER) || bytes.starts_with(crate::vmd::VMD_HEADER_V1) {
+    Some(Format::Vmd)
+  } else if text.starts_with(crate::vpd::HEADER.as_bytes()) {
+    Some(Format::Vpd)
+  } else if bytes.starts_with(PMX_MAGIC) {
+    Some(Format::Pmx)
+  } else if bytes.starts_with(crate::pmd::MAGIC) {
+    Some(Format::Pmd)
+  } else {
+    None
+  }
+}
+
+/// A file parsed by `open`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MmdFile<C: Config = DefaultConfig> {
+  Vmd(Vmd<C>),
+  Vpd(Vpd<C>),
+  Pmx(Pmx<C>),
+  Pmd(Pmd<C>),
+}
+
+impl<C: Config> MmdFile<C> {
+  /// Reads a file of any format, see `open`.
+  pub fn read<R: Read>(mut read: R) -> Result<Self> {
+    let mut prefix = Vec::with_capacity(SNIFF_LEN);
+    (&mut read)
+      .take(SNIFF_LEN as u64)
+      .read_to_end(&mut prefix)?;
+    let format = match sniff(&prefix) {
+      Some(format) => format,
+      None => return Err(Error::UnknownFormat(prefix)),
+    };
+
+    let mut read = Cursor::new(prefix).chain(read);
+    Ok(match format {
+      Format::Vmd => MmdFile::Vmd(Vmd::read(&mut read)?),
+      Format::Vpd => MmdFile::Vpd(Vpd::read(read)?),
+      Format::Pmx => MmdFile::Pmx(Pmx::read(read)?),
+      Format::Pmd => MmdFile::Pmd(Pmd::read(read)?),
+    })
+  }
+
+  pub fn format(&self) -> Format {
+    match self {
+      MmdFile::Vmd(_) => Format::Vmd,
+      MmdFile::Vpd(_) => Format::Vpd,
+      MmdFile::Pmx(_) => Format::Pmx,
+      MmdFile::Pmd(_) => Format::Pmd,
+    }
+  }
+}
+
+/// Reads a motion, pose or model, dispatching on `sniff` rather than an extension. Input of
+/// none of the formats fails with `Error::UnknownFormat` and its first bytes.
+pub fn open<R: Read>(read: R) -> Result<MmdFile> {
+  MmdFile::read(read)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FIXTURES: [(&[u8], Format); 8] = [
+    (include_bytes!("../fixtures/camera.vmd"), Format::Vmd),
+    (include_bytes!("../fixtures/issue1.vmd"), Format::Vmd),
+    (include_bytes!("../fixtures/motion.vmd"), Format::Vmd),
+    (include_bytes!("../fixtures/pose.vpd"), Format::Vpd),
+    (include_bytes!("../fixtures/model.pmx"), Format::Pmx),
+    (include_bytes!("../fixtures/model_axes.pmx"), Format::Pmx),
+    (include_bytes!("../fixtures/model_uv4.pmx"), Format::Pmx),
+    (include_bytes!("../fixtures/model.pmd"), Format::Pmd),
+  ];
+
+  #[test]
+  fn test_sniff() {
+    for (bytes, format) in FIXTURES.iter() {
+      assert_eq!(sniff(&bytes[..SNIFF_LEN]), Some(*format));
+    }
+
+    let mut v1 = b"Vocaloid Motion Data file\0".to_vec();
+    v1.resize(50, 0);
+    assert_eq!(sniff(&v1), Some(Format::Vmd));
+    let utf8 = "\u{feff}Vocaloid Pose Data file\r\n";
+    assert_eq!(sniff(utf8.as_bytes()), Some(Format::Vpd));
+
+    assert_eq!(sniff(b""), None);
+    assert_eq!(sniff(b"PMX"), None);
+    assert_eq!(sniff(b"Vocaloid Motion Data 0003\0"), None);
+    // A fixed xorshift sequence, none of it starts with a magic
+    let mut state = 0x2545_f491_u32;
+    let random: Vec<u8> = (0..64)
+      .map(|_| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as u8
+      })
+      .collect();
+    assert_eq!(sniff(&random), None);
+  }
+
+  #[test]
+  fn test_open() {
+    for (bytes, format) in FIXTURES.iter() {
+      assert_eq!(open(*bytes).unwrap().format(), *format);
+    }
+    let (pmx, _) = FIXTURES[4];
+    assert_eq!(open(pmx).unwrap(), MmdFile::Pmx(Pmx::read(pmx).unwrap()));
+
+    let text = "\u{feff}Vocaloid Pose Data file\r\n\r\nmodel.osm;\r\n0;\r\n";
+    match open(text.as_bytes()).unwrap() {
+      MmdFile::Vpd(vpd) => assert_eq!(vpd.parent_model, "model.osm"),
+      file => panic!("read as {:?}", file.format()),
+    }
+
+    assert!(matches!(
+      open(&b"Pm"[..]),
+      Err(Error::UnknownFormat(prefix)) if prefix == b"Pm"
+    ));
+    assert!(matches!(
+      open(&[0xffu8; 100][..]),
+      Err(Error::UnknownFormat(prefix)) if prefix.len() == SNIFF_LEN
+    ));
+  }
+}
@@ -0,0 +1,202 @@
+//! Entry points of the fuzz targets in `fuzz/`, kept here so the smoke test below exercises the
+//! same read paths with mutated fixtures.
+//!
+//! Every target only checks that its input can't panic, the results are thrown away.
+
+use std::io::Cursor;
+
+use crate::pmd::Pmd;
+use crate::pmx::model::has_soft_bodies;
+use crate::vmd::{
+  CameraFrame, DecodeMode, LightFrame, MorphFrame, MotionFrame, PropertyFrame, ShadowFrame, Vmd,
+  VmdHeader, VmdReader,
+};
+use crate::vpd::{ReadMode, Vpd};
+use crate::{
+  BoneReader, DefaultConfig, DisplayReader, HeaderReader, JointReader, MaterialReader, MorphReader,
+  Pmx, PmxReadOptions, Result, RigidBodyReader, SoftBodyReader, SurfaceReader, TextureReader,
+  VertexReader,
+};
+
+/// The header and the frame readers one section after another, then the whole motion.
+pub fn vmd(data: &[u8]) {
+  let _ = vmd_sections(data);
+  let _ = vmd_skipped(data);
+  let _ = Vmd::<DefaultConfig>::read_complete(&mut &data[..], DecodeMode::Strict);
+  let _ = Vmd::<DefaultConfig>::read_lenient(&mut &data[..]);
+}
+
+fn vmd_sections(mut data: &[u8]) -> Result<()> {
+  VmdHeader::read(&mut data)?;
+  MotionFrame::<DefaultConfig>::read_all(&mut data)?;
+  MorphFrame::read_all(&mut data)?;
+  CameraFrame::<DefaultConfig>::read_all(&mut data)?;
+  LightFrame::<DefaultConfig>::read_all(&mut data)?;
+  ShadowFrame::read_all(&mut data)?;
+  PropertyFrame::read_all(&mut data)?;
+  Ok(())
+}
+
+fn vmd_skipped(data: &[u8]) -> Result<()> {
+  let mut reader = VmdReader::new(Cursor::new(data))?;
+  reader.skip_motion_frames()?;
+  reader.skip_morph_frames()?;
+  reader.skip_camera_frames()?;
+  reader.skip_light_frames()?;
+  reader.skip_shadow_frames()?;
+  reader.read_property_frames()?;
+  Ok(())
+}
+
+/// A pose, strict and lenient.
+pub fn vpd(data: &[u8]) {
+  let _ = Vpd::<DefaultConfig>::read(data);
+  let _ = Vpd::<DefaultConfig>::read_with(data, ReadMode::Lenient);
+}
+
+/// The header and section readers one record after another, then the whole model from a reader
+/// and from a slice.
+pub fn pmx(data: &[u8]) {
+  let _ = pmx_sections(data);
+  let _ = Pmx::<DefaultConfig>::read(data);
+  let _ = Pmx::<DefaultConfig>::parse(data);
+  #[cfg(feature = "rayon")]
+  let _ = Pmx::<DefaultConfig>::parse_with(
+    data,
+    &mut PmxReadOptions {
+      parallel_min_bytes: 0,
+      ..PmxReadOptions::default()
+    },
+  );
+  let _ = Pmx::<DefaultConfig>::read_with(data, &mut PmxReadOptions::default());
+}
+
+fn pmx_sections(data: &[u8]) -> Result<()> {
+  let header = HeaderReader::new(data)?;
+  let version = header.version;
+  let mut vertices = VertexReader::new(header)?;
+  while vertices.next::<DefaultConfig>()?.is_some() {}
+  let mut surfaces = SurfaceReader::new(vertices)?;
+  while surfaces.next::<DefaultConfig>()?.is_some() {}
+  let mut textures = TextureReader::new(surfaces)?;
+  while textures.next()?.is_some() {}
+  let mut materials = MaterialReader::new(textures)?;
+  while materials.next::<DefaultConfig>()?.is_some() {}
+  let mut bones = BoneReader::new(materials)?;
+  while bones.next::<DefaultConfig>()?.is_some() {}
+  let mut morphs = MorphReader::new(bones)?;
+  while morphs.next::<DefaultConfig>()?.is_some() {}
+  let mut displays = DisplayReader::new(morphs)?;
+  while displays.next::<DefaultConfig>()?.is_some() {}
+  let mut rigid_bodies = RigidBodyReader::new(displays)?;
+  while rigid_bodies.next::<DefaultConfig>()?.is_some() {}
+  let mut joints = JointReader::new(rigid_bodies)?;
+  while joints.next::<DefaultConfig>()?.is_some() {}
+  if has_soft_bodies(version) {
+    let mut soft_bodies = SoftBodyReader::new(joints)?;
+    while soft_bodies.next::<DefaultConfig>()?.is_some() {}
+  }
+  Ok(())
+}
+
+/// A PMD model.
+pub fn pmd(data: &[u8]) {
+  let _ = Pmd::<DefaultConfig>::read(data);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Mutated inputs per seed, `MMD_FUZZ_ITERATIONS` raises it for a longer run.
+  const ITERATIONS: usize = 300;
+
+  /// Counts and lengths a corrupt file is likely to hold.
+  const INTERESTING: [u32; 8] = [0, 1, 0x7f, 0xff, 0x7fff_ffff, 0x8000_0000, 0xffff_fffe, !0];
+
+  /// xorshift64, deterministic so a failure reproduces.
+  struct Rng(u64);
+
+  impl Rng {
+    fn next(&mut self) -> u64 {
+      self.0 ^= self.0 << 13;
+      self.0 ^= self.0 >> 7;
+      self.0 ^= self.0 << 17;
+      self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+      (self.next() % n.max(1) as u64) as usize
+    }
+  }
+
+  fn mutate(rng: &mut Rng, seed: &[u8]) -> Vec<u8> {
+    let mut data = seed.to_vec();
+    for _ in 0..=rng.below(4) {
+      let at = rng.below(data.len());
+      match rng.below(5) {
+        0 => data.truncate(at),
+        1 if at < data.len() => data[at] ^= 1 << rng.below(8),
+        2 if at < data.len() => data[at] = rng.next() as u8,
+        3 => {
+          let value = INTERESTING[rng.below(INTERESTING.len())].to_le_bytes();
+          let end = (at + 4).min(data.len());
+          data[at..end].copy_from_slice(&value[..end - at]);
+        }
+        _ => {
+          let insert = rng.next().to_le_bytes();
+          data.splice(at..at, insert[..rng.below(8)].iter().copied());
+        }
+      }
+    }
+    data
+  }
+
+  fn smoke(target: fn(&[u8]), seeds: &[&[u8]]) {
+    let iterations = std::env::var("MMD_FUZZ_ITERATIONS")
+      .ok()
+      .and_then(|n| n.parse().ok())
+      .unwrap_or(ITERATIONS);
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    for seed in seeds {
+      target(seed);
+      for _ in 0..iterations {
+        target(&mutate(&mut rng, seed));
+      }
+    }
+  }
+
+  #[test]
+  fn test_vmd() {
+    smoke(
+      vmd,
+      &[
+        include_bytes!("../fixtures/motion.vmd"),
+        include_bytes!("../fixtures/camera.vmd"),
+        include_bytes!("../fixtures/issue1.vmd"),
+      ],
+    );
+  }
+
+  #[test]
+  fn test_vpd() {
+    smoke(vpd, &[include_bytes!("../fixtures/pose.vpd")]);
+  }
+
+  #[test]
+  fn test_pmx() {
+    smoke(
+      pmx,
+      &[
+        include_bytes!("../fixtures/model.pmx"),
+        include_bytes!("../fixtures/model_axes.pmx"),
+        include_bytes!("../fixtures/model_uv4.pmx"),
+      ],
+    );
+  }
+
+  #[test]
+  fn test_pmd() {
+    smoke(pmd, &[include_bytes!("../fixtures/model.pmd")]);
+  }
+}
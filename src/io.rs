@@ -0,0 +1,369 @@
+//! The reading the parsers are written against, `std::io` with the `std` feature.
+//!
+//! Without `std` this is a small stand-in with the same names for what the parsers use: `Read`
+//! and `BufRead` implemented for byte slices, `BufReader`, `Cursor`, and an `Error` that only
+//! carries its kind.
+
+#[cfg(feature = "std")]
+pub use byteorder::ReadBytesExt;
+#[cfg(feature = "std")]
+pub use std::io::{BufRead, BufReader, Cursor, Error, ErrorKind, Read, Result};
+
+#[cfg(not(feature = "std"))]
+pub use self::no_std::*;
+
+#[cfg(not(feature = "std"))]
+mod no_std {
+  use alloc::vec::Vec;
+  use byteorder::ByteOrder;
+  use core::fmt;
+
+  #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+  #[non_exhaustive]
+  pub enum ErrorKind {
+    UnexpectedEof,
+    Interrupted,
+    InvalidData,
+    Other,
+  }
+
+  /// An I/O error, of which only the kind is known.
+  #[derive(Debug, Clone, PartialEq, Eq)]
+  pub struct Error(ErrorKind);
+
+  impl Error {
+    pub fn kind(&self) -> ErrorKind {
+      self.0
+    }
+  }
+
+  impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+      Error(kind)
+    }
+  }
+
+  impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self.0 {
+        ErrorKind::UnexpectedEof => write!(f, "unexpected end of file"),
+        ErrorKind::Interrupted => write!(f, "operation interrupted"),
+        ErrorKind::InvalidData => write!(f, "invalid data"),
+        ErrorKind::Other => write!(f, "other error"),
+      }
+    }
+  }
+
+  pub type Result<T> = core::result::Result<T, Error>;
+
+  pub trait Read {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+
+    fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<()> {
+      while !buf.is_empty() {
+        match self.read(buf) {
+          Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+          Ok(n) => buf = &mut buf[n..],
+          Err(e) if e.kind() == ErrorKind::Interrupted => {}
+          Err(e) => return Err(e),
+        }
+      }
+      Ok(())
+    }
+
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+      let start = buf.len();
+      let mut chunk = [0; 1024];
+      loop {
+        match self.read(&mut chunk) {
+          Ok(0) => return Ok(buf.len() - start),
+          Ok(n) => buf.extend_from_slice(&chunk[..n]),
+          Err(e) if e.kind() == ErrorKind::Interrupted => {}
+          Err(e) => return Err(e),
+        }
+      }
+    }
+
+    fn take(self, limit: u64) -> Take<Self>
+    where
+      Self: Sized,
+    {
+      Take { inner: self, limit }
+    }
+
+    fn chain<R: Read>(self, next: R) -> Chain<Self, R>
+    where
+      Self: Sized,
+    {
+      Chain {
+        first: self,
+        second: next,
+        done_first: false,
+      }
+    }
+  }
+
+  impl Read for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+      let len = buf.len().min(self.len());
+      let (read, rest) = self.split_at(len);
+      buf[..len].copy_from_slice(read);
+      *self = rest;
+      Ok(len)
+    }
+  }
+
+  impl<R: Read + ?Sized> Read for &mut R {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+      (**self).read(buf)
+    }
+  }
+
+  pub struct Take<R> {
+    inner: R,
+    limit: u64,
+  }
+
+  impl<R: Read> Read for Take<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+      let len = (buf.len() as u64).min(self.limit) as usize;
+      let read = self.inner.read(&mut buf[..len])?;
+      self.limit -= read as u64;
+      Ok(read)
+    }
+  }
+
+  pub struct Chain<A, B> {
+    first: A,
+    second: B,
+    done_first: bool,
+  }
+
+  impl<A: Read, B: Read> Read for Chain<A, B> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+      if !self.done_first {
+        match self.first.read(buf)? {
+          0 if !buf.is_empty() => self.done_first = true,
+          n => return Ok(n),
+        }
+      }
+      self.second.read(buf)
+    }
+  }
+
+  /// A position in bytes, for backing off from a section that doesn't fit.
+  pub struct Cursor<T> {
+    inner: T,
+    position: u64,
+  }
+
+  impl<T> Cursor<T> {
+    pub fn new(inner: T) -> Self {
+      Cursor { inner, position: 0 }
+    }
+
+    pub fn get_ref(&self) -> &T {
+      &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+      self.inner
+    }
+
+    pub fn position(&self) -> u64 {
+      self.position
+    }
+
+    pub fn set_position(&mut self, position: u64) {
+      self.position = position;
+    }
+  }
+
+  impl<T: AsRef<[u8]>> Read for Cursor<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+      let bytes = self.inner.as_ref();
+      let start = (self.position as usize).min(bytes.len());
+      let read = (&bytes[start..]).read(buf)?;
+      self.position += read as u64;
+      Ok(read)
+    }
+  }
+
+  pub trait BufRead: Read {
+    fn fill_buf(&mut self) -> Result<&[u8]>;
+
+    fn consume(&mut self, amount: usize);
+
+    fn read_until(&mut self, byte: u8, buf: &mut Vec<u8>) -> Result<usize> {
+      let mut read = 0;
+      loop {
+        let (done, used) = {
+          let available = self.fill_buf()?;
+          match available.iter().position(|&b| b == byte) {
+            Some(i) => {
+              buf.extend_from_slice(&available[..=i]);
+              (true, i + 1)
+            }
+            None => {
+              buf.extend_from_slice(available);
+              (available.is_empty(), available.len())
+            }
+          }
+        };
+        self.consume(used);
+        read += used;
+        if done {
+          return Ok(read);
+        }
+      }
+    }
+  }
+
+  impl BufRead for &[u8] {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+      Ok(self)
+    }
+
+    fn consume(&mut self, amount: usize) {
+      *self = &self[amount..];
+    }
+  }
+
+  /// Buffers the reads from `R` to find the lines of a pose.
+  pub struct BufReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    position: usize,
+  }
+
+  impl<R: Read> BufReader<R> {
+    pub fn new(inner: R) -> Self {
+      BufReader {
+        inner,
+        buf: Vec::new(),
+        position: 0,
+      }
+    }
+  }
+
+  impl<R: Read> Read for BufReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+      let mut available = self.fill_buf()?;
+      let read = available.read(buf)?;
+      self.consume(read);
+      Ok(read)
+    }
+  }
+
+  impl<R: Read> BufRead for BufReader<R> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+      if self.position == self.buf.len() {
+        self.buf.resize(8192, 0);
+        let read = self.inner.read(&mut self.buf);
+        self.buf.truncate(*read.as_ref().unwrap_or(&0));
+        self.position = 0;
+        read?;
+      }
+      Ok(&self.buf[self.position..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+      self.position = (self.position + amount).min(self.buf.len());
+    }
+  }
+
+  /// The integer and float reads of `byteorder::ReadBytesExt`, which needs `std`.
+  pub trait ReadBytesExt: Read {
+    fn read_u8(&mut self) -> Result<u8> {
+      let mut buf = [0; 1];
+      self.read_exact(&mut buf)?;
+      Ok(buf[0])
+    }
+
+    fn read_u16<B: ByteOrder>(&mut self) -> Result<u16> {
+      let mut buf = [0; 2];
+      self.read_exact(&mut buf)?;
+      Ok(B::read_u16(&buf))
+    }
+
+    fn read_u32<B: ByteOrder>(&mut self) -> Result<u32> {
+      let mut buf = [0; 4];
+      self.read_exact(&mut buf)?;
+      Ok(B::read_u32(&buf))
+    }
+
+    fn read_i32<B: ByteOrder>(&mut self) -> Result<i32> {
+      let mut buf = [0; 4];
+      self.read_exact(&mut buf)?;
+      Ok(B::read_i32(&buf))
+    }
+
+    fn read_f32<B: ByteOrder>(&mut self) -> Result<f32> {
+      let mut buf = [0; 4];
+      self.read_exact(&mut buf)?;
+      Ok(B::read_f32(&buf))
+    }
+  }
+
+  impl<R: Read + ?Sized> ReadBytesExt for R {}
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::vmd::Vmd;
+  use crate::vpd::Vpd;
+  use crate::{DefaultConfig, Format, Pmx};
+
+  const FIXTURE_MOTION_VMD: &[u8] = include_bytes!("../fixtures/motion.vmd");
+  const FIXTURE_MODEL_PMX: &[u8] = include_bytes!("../fixtures/model.pmx");
+  const FIXTURE_POSE_VPD: &[u8] = include_bytes!("../fixtures/pose.vpd");
+
+  /// Hands out at most 3 bytes per read.
+  struct Trickle<'a>(&'a [u8]);
+
+  impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+      let len = buf.len().min(3);
+      self.0.read(&mut buf[..len])
+    }
+  }
+
+  #[test]
+  fn test_read_slices() {
+    let vmd = Vmd::<DefaultConfig>::read(&mut &FIXTURE_MOTION_VMD[..]).unwrap();
+    assert_eq!(vmd.motion_frames.len(), 164);
+    assert_eq!(vmd.trailing_bytes, 0);
+
+    let pmx = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    assert_eq!(Pmx::parse(FIXTURE_MODEL_PMX).unwrap(), pmx);
+    assert_eq!(Pmx::read(Trickle(FIXTURE_MODEL_PMX)).unwrap(), pmx);
+
+    let vpd = Vpd::<DefaultConfig>::read(FIXTURE_POSE_VPD).unwrap();
+    assert_eq!(Vpd::read(Trickle(FIXTURE_POSE_VPD)).unwrap(), vpd);
+
+    assert_eq!(
+      crate::open(FIXTURE_MODEL_PMX).unwrap().format(),
+      Format::Pmx
+    );
+  }
+
+  #[test]
+  fn test_read_helpers() {
+    let mut read = Cursor::new(&b"ab\ncd"[..]).chain(Trickle(b"ef\n"));
+    let mut line = Vec::new();
+    read.read_exact(&mut [0; 1]).unwrap();
+    assert_eq!(Read::take(&mut read, 3).read_to_end(&mut line).unwrap(), 3);
+    assert_eq!(line, b"b\nc");
+
+    let mut lines = BufReader::new(read);
+    line.clear();
+    assert_eq!(lines.read_until(b'\n', &mut line).unwrap(), 4);
+    assert_eq!(line, b"def\n");
+    assert_eq!(lines.read_until(b'\n', &mut line).unwrap(), 0);
+    assert_eq!(
+      ReadBytesExt::read_u8(&mut lines).unwrap_err().kind(),
+      ErrorKind::UnexpectedEof
+    );
+  }
+}
@@ -1,19 +1,44 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 #![deny(warnings)]
+#![allow(clippy::should_implement_trait)]
+// The arrays of the tests are converted into the vek types with the vek feature
+#![cfg_attr(test, allow(clippy::useless_conversion))]
 
+extern crate alloc;
+
+pub mod format;
+#[doc(hidden)]
+#[cfg(feature = "std")]
+pub mod fuzz;
+pub mod io;
+mod math;
+pub mod pmd;
 pub mod pmx;
+#[cfg(feature = "serde")]
+mod serde_array;
+#[cfg(feature = "std")]
+pub mod validation;
 pub mod vmd;
 pub mod vpd;
 
+pub use self::format::{open, sniff, Format, MmdFile};
 pub use self::pmx::bone::Bone;
-pub use self::pmx::error::{Error, Result};
+#[cfg(feature = "std")]
+pub use self::pmx::builder::PmxBuilder;
+pub use self::pmx::error::{Error, ErrorLocation, Result};
 pub use self::pmx::material::Material;
+pub use self::pmx::model::{Pmx, PmxReadOptions};
+pub use self::pmx::name::{Language, LocalizedName};
 pub use self::pmx::reader::{
   self, BoneReader, DisplayReader, HeaderReader, JointReader, MaterialReader, MorphReader,
-  RigidBodyReader, SurfaceReader, TextureReader, VertexReader,
+  RigidBodyReader, SliceReader, SoftBodyReader, SurfaceReader, TextureReader, VertexReader,
 };
 pub use self::pmx::settings::Settings;
+pub use self::pmx::typed_index::TypedConfig;
 pub use self::pmx::types::*;
 pub use self::pmx::vertex::Vertex;
 pub use self::pmx::weight_deform::WeightDeform;
+#[cfg(feature = "std")]
+pub use self::pmx::PmxWriteOptions;
 
 mod display;
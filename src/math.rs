@@ -0,0 +1,258 @@
+//! Small vector and quaternion helpers over plain arrays.
+//!
+//! Quaternions are stored in `[x, y, z, w]` order like in the file formats, matrices are
+//! column-major.
+//!
+//! Without `std` only the conversions are used, the float functions behind the rest need it.
+#![cfg_attr(not(feature = "std"), allow(dead_code))]
+
+use crate::{AsSlice, Float};
+
+/// Copies the first `N` components of a vector, e.g. one of the `Config` math types, narrowed
+/// to `f32`.
+pub(crate) fn to_array<const N: usize>(v: &impl AsSlice) -> [f32; N] {
+  let mut out = [0.0; N];
+  for (o, v) in out.iter_mut().zip(v.as_slice()) {
+    *o = v.to_f32();
+  }
+  out
+}
+
+/// Conversion of `f32` arrays into the vectors of a `Config`, widening them to its `Float`.
+pub(crate) trait Widen<const N: usize> {
+  fn widen<F: Float, V: From<[F; N]>>(self) -> V;
+}
+
+impl<const N: usize> Widen<N> for [f32; N] {
+  fn widen<F: Float, V: From<[F; N]>>(self) -> V {
+    V::from(self.map(F::from_f32))
+  }
+}
+
+pub(crate) fn lerp(a: f32, b: f32, t: f32) -> f32 {
+  a + (b - a) * t
+}
+
+pub(crate) fn lerp3(a: [f32; 3], b: [f32; 3], t: [f32; 3]) -> [f32; 3] {
+  [
+    lerp(a[0], b[0], t[0]),
+    lerp(a[1], b[1], t[1]),
+    lerp(a[2], b[2], t[2]),
+  ]
+}
+
+pub(crate) fn dot4(a: [f32; 4], b: [f32; 4]) -> f32 {
+  a[0] * b[0] + a[1] * b[1] + a[2] * b[2] + a[3] * b[3]
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn normalize4(q: [f32; 4]) -> [f32; 4] {
+  let len = dot4(q, q).sqrt();
+  if len == 0.0 {
+    return [0.0, 0.0, 0.0, 1.0];
+  }
+  [q[0] / len, q[1] / len, q[2] / len, q[3] / len]
+}
+
+#[cfg(feature = "std")]
+/// Spherical interpolation along the shortest arc.
+pub(crate) fn slerp(a: [f32; 4], b: [f32; 4], t: f32) -> [f32; 4] {
+  let mut cos = dot4(a, b);
+  let b = if cos < 0.0 {
+    cos = -cos;
+    [-b[0], -b[1], -b[2], -b[3]]
+  } else {
+    b
+  };
+
+  let (wa, wb) = if cos > 0.9995 {
+    // Nearly parallel, fall back to a normalized lerp
+    (1.0 - t, t)
+  } else {
+    let theta = cos.acos();
+    let sin = theta.sin();
+    (((1.0 - t) * theta).sin() / sin, (t * theta).sin() / sin)
+  };
+
+  normalize4([
+    a[0] * wa + b[0] * wb,
+    a[1] * wa + b[1] * wb,
+    a[2] * wa + b[2] * wb,
+    a[3] * wa + b[3] * wb,
+  ])
+}
+
+/// Hamilton product, rotating by `b` first and then by `a`.
+pub(crate) fn quat_mul(a: [f32; 4], b: [f32; 4]) -> [f32; 4] {
+  let [ax, ay, az, aw] = a;
+  let [bx, by, bz, bw] = b;
+  [
+    aw * bx + ax * bw + ay * bz - az * by,
+    aw * by - ax * bz + ay * bw + az * bx,
+    aw * bz + ax * by - ay * bx + az * bw,
+    aw * bw - ax * bx - ay * by - az * bz,
+  ]
+}
+
+/// Rotates a vector by a unit quaternion.
+pub(crate) fn rotate3(q: [f32; 4], v: [f32; 3]) -> [f32; 3] {
+  let [x, y, z, _] = quat_mul(
+    quat_mul(q, [v[0], v[1], v[2], 0.0]),
+    [-q[0], -q[1], -q[2], q[3]],
+  );
+  [x, y, z]
+}
+
+#[cfg(feature = "std")]
+/// Quaternion of Euler angles applied around Z, then X, then Y, like the MMD camera.
+pub(crate) fn quat_from_euler_yxz([x, y, z]: [f32; 3]) -> [f32; 4] {
+  let axis = |angle: f32, i: usize| {
+    let (sin, cos) = (angle * 0.5).sin_cos();
+    let mut q = [0.0, 0.0, 0.0, cos];
+    q[i] = sin;
+    q
+  };
+  quat_mul(quat_mul(axis(y, 1), axis(x, 0)), axis(z, 2))
+}
+
+#[cfg(feature = "std")]
+/// Inverse of `quat_from_euler_yxz`, with X in `-π/2..=π/2` and Y and Z in `-π..=π`.
+pub(crate) fn euler_yxz_from_quat(q: [f32; 4]) -> [f32; 3] {
+  let [x, y, z, w] = normalize4(q);
+  let m02 = 2.0 * (x * z + w * y);
+  let m12 = 2.0 * (y * z - w * x);
+  let m22 = 1.0 - 2.0 * (x * x + y * y);
+  let pitch = (-m12).clamp(-1.0, 1.0).asin();
+
+  if m12.abs() < 0.99999 {
+    let m10 = 2.0 * (x * y + w * z);
+    let m11 = 1.0 - 2.0 * (x * x + z * z);
+    [pitch, m02.atan2(m22), m10.atan2(m11)]
+  } else {
+    // Gimbal lock, the roll is folded into the yaw
+    let m00 = 1.0 - 2.0 * (y * y + z * z);
+    let m20 = 2.0 * (x * z - w * y);
+    [pitch, (-m20).atan2(m00), 0.0]
+  }
+}
+
+pub(crate) fn sub3(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+  [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+pub(crate) fn dot3(a: [f32; 3], b: [f32; 3]) -> f32 {
+  a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+pub(crate) fn cross3(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+  [
+    a[1] * b[2] - a[2] * b[1],
+    a[2] * b[0] - a[0] * b[2],
+    a[0] * b[1] - a[1] * b[0],
+  ]
+}
+
+/// `f32::sqrt`, which core doesn't have, or Newton's method from half the exponent without std.
+fn sqrt(x: f32) -> f32 {
+  #[cfg(feature = "std")]
+  {
+    x.sqrt()
+  }
+  #[cfg(not(feature = "std"))]
+  {
+    if x <= 0.0 || !x.is_finite() {
+      return if x < 0.0 { f32::NAN } else { x };
+    }
+    let mut root = f32::from_bits((x.to_bits() >> 1) + 0x1fc0_0000);
+    for _ in 0..4 {
+      root = 0.5 * (root + x / root);
+    }
+    root
+  }
+}
+
+/// Scales to unit length, keeping zero vectors as they are.
+pub(crate) fn normalize3(v: [f32; 3]) -> [f32; 3] {
+  let len = sqrt(v[0] * v[0] + v[1] * v[1] + v[2] * v[2]);
+  if len == 0.0 {
+    return v;
+  }
+  [v[0] / len, v[1] / len, v[2] / len]
+}
+
+pub(crate) const IDENTITY: [[f32; 4]; 4] = [
+  [1.0, 0.0, 0.0, 0.0],
+  [0.0, 1.0, 0.0, 0.0],
+  [0.0, 0.0, 1.0, 0.0],
+  [0.0, 0.0, 0.0, 1.0],
+];
+
+pub(crate) fn translation([x, y, z]: [f32; 3]) -> [[f32; 4]; 4] {
+  let mut m = IDENTITY;
+  m[3] = [x, y, z, 1.0];
+  m
+}
+
+/// The rotation of a unit quaternion followed by the translation `t`.
+pub(crate) fn rotation_translation([x, y, z, w]: [f32; 4], t: [f32; 3]) -> [[f32; 4]; 4] {
+  [
+    [
+      1.0 - 2.0 * (y * y + z * z),
+      2.0 * (x * y + w * z),
+      2.0 * (x * z - w * y),
+      0.0,
+    ],
+    [
+      2.0 * (x * y - w * z),
+      1.0 - 2.0 * (x * x + z * z),
+      2.0 * (y * z + w * x),
+      0.0,
+    ],
+    [
+      2.0 * (x * z + w * y),
+      2.0 * (y * z - w * x),
+      1.0 - 2.0 * (x * x + y * y),
+      0.0,
+    ],
+    [t[0], t[1], t[2], 1.0],
+  ]
+}
+
+/// Transforms by `b` first and then by `a`.
+pub(crate) fn mat4_mul(a: &[[f32; 4]; 4], b: &[[f32; 4]; 4]) -> [[f32; 4]; 4] {
+  let mut m = [[0.0; 4]; 4];
+  for (column, b) in m.iter_mut().zip(b) {
+    for (row, out) in column.iter_mut().enumerate() {
+      *out = (0..4).map(|k| a[k][row] * b[k]).sum();
+    }
+  }
+  m
+}
+
+/// Inverse of a matrix made of a rotation and a translation only.
+pub(crate) fn rigid_inverse(m: &[[f32; 4]; 4]) -> [[f32; 4]; 4] {
+  let mut inverse = IDENTITY;
+  for (i, column) in inverse.iter_mut().take(3).enumerate() {
+    for (j, out) in column.iter_mut().take(3).enumerate() {
+      *out = m[j][i];
+    }
+  }
+  for i in 0..3 {
+    inverse[3][i] = -(0..3).map(|j| m[i][j] * m[3][j]).sum::<f32>();
+  }
+  inverse
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_normalize3() {
+    let [x, y, z] = normalize3([3.0, 0.0, -4.0]);
+    assert!((x - 0.6).abs() < 1e-6 && y == 0.0 && (z + 0.8).abs() < 1e-6);
+    let [x, y, z] = normalize3([100.0, 200.0, 200.0]);
+    assert!((x - 1.0 / 3.0).abs() < 1e-6 && (y - 2.0 / 3.0).abs() < 1e-6 && y == z);
+    assert_eq!(normalize3([0.0; 3]), [0.0; 3]);
+  }
+}
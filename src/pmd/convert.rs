@@ -0,0 +1,645 @@
+//! Conversion of PMD models into PMX ones.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::f32::consts::PI;
+
+use enumflags2::BitFlags;
+
+use super::{BoneType, MorphType, Pmd, NO_BONE, NO_TOON};
+use crate::math::{normalize3, to_array, Widen};
+use crate::pmx::bone::{
+  Additional, AngleLimits, BoneFlags, Connection, IKLink, InverseKinematics as PmxIk,
+};
+use crate::pmx::display::{DisplayElement, DisplayFrame};
+use crate::pmx::joint::{Joint, JointType};
+use crate::pmx::material::{DrawingFlags, EnvironmentBlendMode, Toon};
+use crate::pmx::morph::{Morph, Offsets, Panel, VertexOffset};
+use crate::pmx::rigid_body::RigidBody;
+use crate::pmx::types::smallest_index_size;
+use crate::pmx::weight_deform::{Bdef1, Bdef2};
+use crate::{
+  Bone, Config, Error, IndexSize, LocalizedName, Material, Pmx, Result, Settings, TextEncoding,
+  Vertex, WeightDeform,
+};
+
+/// Limits MMD applies to knee IK links, which only bend backwards around X.
+const KNEE_LOWER_LIMIT: [f32; 3] = [-PI, 0.0, 0.0];
+const KNEE_UPPER_LIMIT: [f32; 3] = [-0.5 * PI / 180.0, 0.0, 0.0];
+
+fn index<I: TryFrom<i32>>(value: impl Into<i64>) -> Result<I> {
+  let value = value.into();
+  i32::try_from(value)
+    .ok()
+    .and_then(|v| I::try_from(v).ok())
+    .ok_or(Error::IndexOverflow(value))
+}
+
+fn bone_index<I: TryFrom<i32>>(value: u16) -> Result<I> {
+  if value == NO_BONE {
+    index(-1)
+  } else {
+    index(value)
+  }
+}
+
+fn optional_bone_index<I: TryFrom<i32>>(value: u16) -> Result<Option<I>> {
+  if value == NO_BONE {
+    Ok(None)
+  } else {
+    index(value).map(Some)
+  }
+}
+
+fn english_name(names: Option<&Vec<String>>, i: usize) -> String {
+  names.and_then(|n| n.get(i)).cloned().unwrap_or_default()
+}
+
+impl<C: Config> Pmd<C> {
+  /// Converts the model into a PMX 2.0 one, the way PMX editors do.
+  ///
+  /// Where PMX has no exact equivalent:
+  /// - Vertices with all the weight on one bone become BDEF1, the others BDEF2. The edge flag
+  ///   turns into an edge scale of 0 or 1.
+  /// - Materials are named `材質N` and draw shadows, are double-sided when translucent and have
+  ///   an edge with the edge flag. The `texture*sphere` names are split into a texture and a
+  ///   multiplied `.sph` or additive `.spa` environment map.
+  /// - Toon indices whose file name is the default `toonNN.bmp` refer to the shared toons, the
+  ///   others to a texture.
+  /// - IK limit angles are multiplied by 4 and links named `ひざ` get MMD's knee limits.
+  ///   `RotateInfluenced` bones inherit the full rotation of their `ik_parent` and `Twist`
+  ///   bones get a fixed axis towards their tail.
+  /// - The base morph is dropped and the other morphs get absolute vertex indices.
+  /// - Display frames start with `Root` holding the first bone and `表情` with the morphs.
+  /// - Rigid body positions are made absolute with the position of their bone.
+  ///
+  /// Fails with `Error::IndexOverflow` on indices `C` can't hold or skin vertices outside the
+  /// base morph.
+  pub fn into_pmx(self) -> Result<Pmx<C>> {
+    let english = self.english;
+    let english_bones = english.as_ref().map(|e| &e.bone_names);
+    let english_morphs = english.as_ref().map(|e| &e.morph_names);
+    let english_frames = english.as_ref().map(|e| &e.bone_display_names);
+
+    let base = self
+      .morphs
+      .iter()
+      .position(|m| m.morph_type == MorphType::Base);
+    let morph_count = self.morphs.len() - base.map_or(0, |_| 1);
+
+    let settings = Settings {
+      text_encoding: TextEncoding::UTF16LE,
+      additional_vec4_count: 0,
+      vertex_index_size: smallest_index_size(self.vertices.len(), true),
+      texture_index_size: IndexSize::I8,
+      material_index_size: smallest_index_size(self.materials.len(), false),
+      bone_index_size: smallest_index_size(self.bones.len(), false),
+      morph_index_size: smallest_index_size(morph_count, false),
+      rigidbody_index_size: smallest_index_size(self.rigid_bodies.len(), false),
+    };
+
+    let vertices = self
+      .vertices
+      .into_iter()
+      .map(|v| {
+        let [bone_1, bone_2] = v.bone_indices;
+        let weight_deform = match v.bone_1_weight {
+          _ if bone_1 == bone_2 => WeightDeform::Bdef1(Bdef1 {
+            bone_index: bone_index(bone_1)?,
+          }),
+          100..=u8::MAX => WeightDeform::Bdef1(Bdef1 {
+            bone_index: bone_index(bone_1)?,
+          }),
+          0 => WeightDeform::Bdef1(Bdef1 {
+            bone_index: bone_index(bone_2)?,
+          }),
+          weight => WeightDeform::Bdef2(Bdef2 {
+            bone_1_index: bone_index(bone_1)?,
+            bone_2_index: bone_index(bone_2)?,
+            bone_1_weight: weight as f32 / 100.0,
+          }),
+        };
+
+        Ok(Vertex {
+          position: v.position,
+          normal: v.normal,
+          uv: v.uv,
+          additional: core::iter::empty().collect(),
+          weight_deform,
+          edge_scale: if v.edge_flag == 0 { 1.0 } else { 0.0 },
+        })
+      })
+      .collect::<Result<_>>()?;
+
+    let surfaces = self
+      .surfaces
+      .iter()
+      .map(|&[a, b, c]| Ok([index(a)?, index(b)?, index(c)?]))
+      .collect::<Result<_>>()?;
+
+    let mut textures: Vec<String> = Vec::new();
+    let mut texture_index = |name: &str| -> Result<Option<C::TextureIndex>> {
+      if name.is_empty() {
+        return Ok(None);
+      }
+      let i = match textures.iter().position(|t| t == name) {
+        Some(i) => i,
+        None => {
+          textures.push(name.to_string());
+          textures.len() - 1
+        }
+      };
+      index(i as i64).map(Some)
+    };
+
+    let mut materials = Vec::with_capacity(self.materials.len());
+    for (i, m) in self.materials.into_iter().enumerate() {
+      let (mut texture, mut sphere) = ("", "");
+      for name in m.texture.split('*') {
+        let lower = name.to_ascii_lowercase();
+        if lower.ends_with(".sph") || lower.ends_with(".spa") {
+          sphere = name;
+        } else {
+          texture = name;
+        }
+      }
+      let environment_blend_mode = if sphere.is_empty() {
+        EnvironmentBlendMode::Disabled
+      } else if sphere.to_ascii_lowercase().ends_with(".spa") {
+        EnvironmentBlendMode::Additive
+      } else {
+        EnvironmentBlendMode::Multiply
+      };
+
+      let toon = if m.toon_index == NO_TOON {
+        Toon::Texture(None)
+      } else {
+        let default = format!("toon{:02}.bmp", m.toon_index as u32 + 1);
+        match self
+          .toon_textures
+          .as_ref()
+          .and_then(|t| t.get(m.toon_index as usize))
+        {
+          Some(name) if !name.eq_ignore_ascii_case(&default) => Toon::Texture(texture_index(name)?),
+          _ => Toon::Internal(m.toon_index),
+        }
+      };
+
+      let mut draw_flags =
+        DrawingFlags::GroundShadow | DrawingFlags::DrawShadow | DrawingFlags::ReceiveShadow;
+      if m.alpha < 1.0 {
+        draw_flags |= DrawingFlags::NoCull;
+      }
+      if m.edge_flag != 0 {
+        draw_flags |= DrawingFlags::HasEdge;
+      }
+
+      let [r, g, b] = to_array(&m.diffuse_color);
+      materials.push(Material {
+        name: LocalizedName {
+          ja: format!("材質{}", i + 1),
+          en: format!("Material{}", i + 1),
+        },
+        diffuse_color: [r, g, b, m.alpha].widen(),
+        specular_color: m.specular_color,
+        specular_strength: m.specular_strength,
+        ambient_color: m.ambient_color,
+        draw_flags: draw_flags.into(),
+        edge_color: [0.0, 0.0, 0.0, 1.0].widen(),
+        edge_scale: 1.0,
+        texture_index: texture_index(texture)?,
+        environment_index: texture_index(sphere)?,
+        environment_blend_mode,
+        toon,
+        metadata: String::new(),
+        surface_count: index(m.surface_count)?,
+      });
+    }
+
+    let positions: Vec<[f32; 3]> = self.bones.iter().map(|b| to_array(&b.position)).collect();
+    let pmd_bones = &self.bones;
+    let mut bones = Vec::with_capacity(pmd_bones.len());
+    for (i, b) in pmd_bones.iter().enumerate() {
+      let mut bone_flags = BitFlags::from(BoneFlags::Rotatable);
+      match b.bone_type {
+        BoneType::RotateMove | BoneType::Ik => bone_flags |= BoneFlags::Movable,
+        _ => {}
+      }
+      match b.bone_type {
+        BoneType::IkTarget | BoneType::Invisible => {}
+        _ => bone_flags |= BoneFlags::Display | BoneFlags::CanOperate,
+      }
+
+      let connection = if b.tail != 0 && b.tail != NO_BONE {
+        bone_flags |= BoneFlags::Connection;
+        Connection::Index(Some(index(b.tail)?))
+      } else {
+        Connection::Position([0.0; 3].widen())
+      };
+
+      let additional = if b.bone_type == BoneType::RotateInfluenced {
+        bone_flags |= BoneFlags::AddRotation;
+        Some(Additional {
+          parent: bone_index(b.ik_parent)?,
+          rate: 1.0,
+        })
+      } else {
+        None
+      };
+
+      let fixed_axis = match positions.get(b.tail as usize) {
+        Some(tail) if b.bone_type == BoneType::Twist => {
+          bone_flags |= BoneFlags::FixedAxis;
+          let [x, y, z] = positions[i];
+          Some(normalize3([tail[0] - x, tail[1] - y, tail[2] - z]).widen())
+        }
+        _ => None,
+      };
+
+      let inverse_kinematics = match self.iks.iter().find(|ik| ik.bone as usize == i) {
+        Some(ik) => {
+          bone_flags |= BoneFlags::InverseKinematics;
+          let links = ik
+            .links
+            .iter()
+            .map(|&link| {
+              let knee = pmd_bones
+                .get(link as usize)
+                .is_some_and(|b| b.name.contains("ひざ"));
+              Ok(IKLink {
+                ik_bone: bone_index(link)?,
+                limits: knee.then(|| AngleLimits {
+                  lower: KNEE_LOWER_LIMIT.widen(),
+                  upper: KNEE_UPPER_LIMIT.widen(),
+                }),
+              })
+            })
+            .collect::<Result<_>>()?;
+
+          Some(PmxIk {
+            ik_bone: bone_index(ik.target)?,
+            iterations: ik.iterations.into(),
+            limit_angle: ik.limit_angle * 4.0,
+            links,
+          })
+        }
+        None => None,
+      };
+
+      bones.push(Bone {
+        name: LocalizedName {
+          ja: b.name.clone(),
+          en: english_name(english_bones, i),
+        },
+        position: b.position.clone(),
+        parent: optional_bone_index(b.parent)?,
+        transform_level: 0,
+        bone_flags,
+        connection,
+        additional,
+        fixed_axis,
+        local_axis: None,
+        external_parent_transform: None,
+        inverse_kinematics,
+      });
+    }
+
+    let base_vertices: Vec<u32> = match base {
+      Some(i) => self.morphs[i].vertices.iter().map(|v| v.index).collect(),
+      None => Vec::new(),
+    };
+    let mut morphs = Vec::with_capacity(morph_count);
+    for (i, m) in self.morphs.into_iter().enumerate() {
+      if Some(i) == base {
+        continue;
+      }
+
+      let offsets = m
+        .vertices
+        .into_iter()
+        .map(|v| {
+          let vertex = base_vertices
+            .get(v.index as usize)
+            .ok_or(Error::IndexOverflow(v.index.into()))?;
+          Ok(VertexOffset {
+            vertex: index(*vertex)?,
+            offset: v.offset,
+          })
+        })
+        .collect::<Result<_>>()?;
+
+      let panel = match m.morph_type {
+        MorphType::Eyebrows => Panel::Eyebrows,
+        MorphType::Eyes => Panel::Eyes,
+        MorphType::Mouth => Panel::Mouth,
+        _ => Panel::Other,
+      };
+      morphs.push(Morph {
+        name: LocalizedName {
+          ja: m.name,
+          en: english_name(english_morphs, morphs.len()),
+        },
+        panel,
+        offsets: Offsets::Vertex(offsets),
+      });
+    }
+
+    let morph_index = |i: u16| -> Result<C::MorphIndex> {
+      match base {
+        Some(base) if (i as usize) > base => index(i - 1),
+        _ => index(i),
+      }
+    };
+    let mut display_frames = vec![
+      DisplayFrame {
+        name: LocalizedName {
+          ja: "Root".to_string(),
+          en: "Root".to_string(),
+        },
+        special_flag: true,
+        elements: if bones.is_empty() {
+          vec![]
+        } else {
+          vec![DisplayElement::Bone(index(0)?)]
+        },
+      },
+      DisplayFrame {
+        name: LocalizedName {
+          ja: "表情".to_string(),
+          en: "Exp".to_string(),
+        },
+        special_flag: true,
+        elements: self
+          .morph_display
+          .iter()
+          .map(|&i| Ok(DisplayElement::Morph(morph_index(i)?)))
+          .collect::<Result<_>>()?,
+      },
+    ];
+    for (i, name) in self.bone_display_names.iter().enumerate() {
+      let elements = self
+        .bone_display
+        .iter()
+        .filter(|d| d.frame as usize == i + 1)
+        .map(|d| Ok(DisplayElement::Bone(index(d.bone)?)))
+        .collect::<Result<_>>()?;
+      display_frames.push(DisplayFrame {
+        name: LocalizedName {
+          ja: name.trim().to_string(),
+          en: english_name(english_frames, i).trim().to_string(),
+        },
+        special_flag: false,
+        elements,
+      });
+    }
+
+    let rigid_bodies = self
+      .rigid_bodies
+      .into_iter()
+      .map(|r| {
+        // Rigid bodies without a bone are relative to the first one
+        let bone = if r.bone_index == NO_BONE {
+          0
+        } else {
+          r.bone_index as usize
+        };
+        let [bx, by, bz] = positions.get(bone).copied().unwrap_or([0.0; 3]);
+        let [x, y, z] = to_array(&r.shape_position);
+
+        Ok(RigidBody {
+          name: LocalizedName {
+            ja: r.name,
+            en: String::new(),
+          },
+          bone_index: optional_bone_index(r.bone_index)?,
+          group_id: r.group_id,
+          non_collision_mask: r.non_collision_mask,
+          shape: r.shape,
+          shape_size: r.shape_size,
+          shape_position: [x + bx, y + by, z + bz].widen(),
+          shape_rotation: r.shape_rotation,
+          mass: r.mass,
+          move_attenuation: r.move_attenuation,
+          rotation_damping: r.rotation_damping,
+          repulsion: r.repulsion,
+          friction: r.friction,
+          physics_mode: r.physics_mode,
+        })
+      })
+      .collect::<Result<_>>()?;
+
+    let joints = self
+      .joints
+      .into_iter()
+      .map(|j| {
+        Ok(Joint {
+          name: LocalizedName {
+            ja: j.name,
+            en: String::new(),
+          },
+          joint_type: JointType::SpringFree,
+          rigid_body_a: index(j.rigid_body_a)?,
+          rigid_body_b: index(j.rigid_body_b)?,
+          position: j.position,
+          rotation: j.rotation,
+          position_min: j.position_min,
+          position_max: j.position_max,
+          rotation_min: j.rotation_min,
+          rotation_max: j.rotation_max,
+          position_spring: j.position_spring,
+          rotation_spring: j.rotation_spring,
+        })
+      })
+      .collect::<Result<_>>()?;
+
+    let (model_universal_name, universal_comments) = english
+      .map(|e| (e.model_name, e.comment))
+      .unwrap_or_default();
+    let settings = Settings {
+      texture_index_size: smallest_index_size(textures.len(), false),
+      ..settings
+    };
+
+    Ok(Pmx {
+      version: 2.0,
+      settings,
+      model_local_name: self.model_name,
+      model_universal_name,
+      local_comments: self.comment,
+      universal_comments,
+      extra_globals: Vec::new(),
+      vertices,
+      surfaces,
+      textures,
+      materials,
+      bones,
+      morphs,
+      display_frames,
+      rigid_bodies,
+      joints,
+      soft_bodies: Vec::new(),
+      skipped_sections: BitFlags::empty(),
+    })
+  }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+  use super::*;
+  use crate::DefaultConfig;
+  use std::io::Cursor;
+
+  const FIXTURE_MODEL_PMD: &[u8] = include_bytes!("../../fixtures/model.pmd");
+
+  fn convert() -> Pmx {
+    Pmd::<DefaultConfig>::read(Cursor::new(FIXTURE_MODEL_PMD))
+      .unwrap()
+      .into_pmx()
+      .unwrap()
+  }
+
+  #[test]
+  fn test_into_pmx() {
+    let pmx = convert();
+
+    assert_eq!(pmx.model_local_name, "テストモデル");
+    assert_eq!(pmx.model_universal_name, "Test model");
+    assert_eq!(pmx.vertices.len(), 6);
+    assert_eq!(
+      pmx.vertices[1].weight_deform,
+      WeightDeform::Bdef2(Bdef2 {
+        bone_1_index: 1,
+        bone_2_index: 2,
+        bone_1_weight: 0.5,
+      })
+    );
+    // Weight 0 puts everything on the second bone
+    assert_eq!(
+      pmx.vertices[2].weight_deform,
+      WeightDeform::Bdef1(Bdef1 { bone_index: 3 })
+    );
+    assert_eq!(pmx.vertices[2].edge_scale, 0.0);
+    assert_eq!(pmx.surfaces.len(), 4);
+
+    assert_eq!(pmx.textures, ["body.bmp", "face.sph"]);
+    let material = &pmx.materials[0];
+    assert_eq!(material.texture_index, Some(0));
+    assert_eq!(material.environment_index, Some(1));
+    assert_eq!(
+      material.environment_blend_mode,
+      EnvironmentBlendMode::Multiply
+    );
+    assert_eq!(material.toon, Toon::Internal(0));
+    assert!(material.draw_flags.contains(DrawingFlags::HasEdge));
+    assert_eq!(pmx.materials[1].toon, Toon::Texture(None));
+    assert!(pmx.materials[1].draw_flags.contains(DrawingFlags::NoCull));
+
+    assert_eq!(pmx.bones.len(), 6);
+    assert_eq!(pmx.bones[0].parent, None);
+    assert_eq!(pmx.bones[4].name.en, "leg IK_L");
+    let ik = pmx.bones[4].inverse_kinematics.as_ref().unwrap();
+    assert_eq!(ik.ik_bone, 3);
+    assert_eq!(ik.iterations, 40);
+    assert_eq!(ik.limit_angle, 2.0);
+    assert_eq!(ik.links.len(), 2);
+    assert_eq!(ik.links[0].ik_bone, 2);
+    let limits = ik.links[0].limits.unwrap();
+    assert_eq!(to_array::<3>(&limits.lower), KNEE_LOWER_LIMIT);
+    assert_eq!(to_array::<3>(&limits.upper), KNEE_UPPER_LIMIT);
+    assert_eq!(ik.links[1].limits, None);
+    assert!(!pmx.bones[3].bone_flags.contains(BoneFlags::Display));
+    assert!(pmx.bones[4].bone_flags.contains(BoneFlags::Movable));
+
+    // The base morph is dropped
+    assert_eq!(pmx.morphs.len(), 2);
+    let morph = &pmx.morphs[0];
+    assert_eq!(morph.name.ja, "あ");
+    assert_eq!(morph.name.en, "a");
+    assert_eq!(morph.panel, Panel::Mouth);
+    assert_eq!(
+      morph.offsets,
+      Offsets::Vertex(vec![
+        VertexOffset {
+          vertex: 4,
+          offset: [0.0, 0.1, 0.0].into(),
+        },
+        VertexOffset {
+          vertex: 5,
+          offset: [0.0, -0.1, 0.0].into(),
+        },
+      ])
+    );
+
+    let names: Vec<_> = pmx
+      .display_frames
+      .iter()
+      .map(|d| d.name.ja.as_str())
+      .collect();
+    assert_eq!(names, ["Root", "表情", "足", "その他"]);
+    assert_eq!(
+      pmx.display_frames[1].elements,
+      [DisplayElement::Morph(0), DisplayElement::Morph(1)]
+    );
+    assert_eq!(
+      pmx.display_frames[2].elements,
+      [
+        DisplayElement::Bone(1),
+        DisplayElement::Bone(2),
+        DisplayElement::Bone(4)
+      ]
+    );
+    assert_eq!(pmx.display_frames[3].name.en, "Other");
+
+    assert_eq!(
+      to_array::<3>(&pmx.rigid_bodies[0].shape_position),
+      [0.0, 9.0, 0.0]
+    );
+    assert_eq!(pmx.joints[0].rigid_body_b, 0);
+  }
+
+  #[test]
+  fn test_into_pmx_writes() {
+    let pmx = convert();
+    let mut bytes = Vec::new();
+    pmx.write(&mut bytes).unwrap();
+
+    assert_eq!(Pmx::read(Cursor::new(bytes)).unwrap(), pmx);
+  }
+
+  #[cfg(any(feature = "glam", feature = "nalgebra"))]
+  fn util_read_through_pmx<C: Config + PartialEq + std::fmt::Debug>() -> Pmx<C> {
+    let pmx = Pmd::<C>::read(Cursor::new(FIXTURE_MODEL_PMD))
+      .unwrap()
+      .into_pmx()
+      .unwrap();
+    let mut bytes = Vec::new();
+    pmx.write(&mut bytes).unwrap();
+
+    let read = Pmx::<C>::read(Cursor::new(bytes)).unwrap();
+    assert_eq!(read, pmx);
+    read
+  }
+
+  #[cfg(feature = "glam")]
+  #[test]
+  fn test_into_pmx_glam() {
+    let pmx = util_read_through_pmx::<crate::GlamConfig>();
+    assert_eq!(
+      pmx.rigid_bodies[0].shape_position,
+      glam::Vec3::new(0.0, 9.0, 0.0)
+    );
+  }
+
+  #[cfg(feature = "nalgebra")]
+  #[test]
+  fn test_into_pmx_nalgebra() {
+    let pmx = util_read_through_pmx::<crate::NalgebraConfig>();
+    assert_eq!(
+      pmx.rigid_bodies[0].shape_position,
+      nalgebra::Vector3::new(0.0, 9.0, 0.0)
+    );
+  }
+}
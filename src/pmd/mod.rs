@@ -0,0 +1,449 @@
+//! Legacy PMD 1.0 models, the format before PMX.
+
+use alloc::{string::String, vec::Vec};
+use core::convert::TryFrom;
+#[cfg(feature = "std")]
+use std::{fs::File, io::BufReader, path::Path};
+
+use byteorder::LE;
+
+use crate::io::{Cursor, Read, ReadBytesExt};
+use crate::pmx::reader::helpers::ReadHelpers;
+use crate::pmx::rigid_body::{PhysicsMode, ShapeType};
+use crate::vmd::{read_string, DecodeMode};
+use crate::{Config, Error, Result};
+
+mod convert;
+pub mod types;
+
+pub use self::types::*;
+
+pub(crate) const MAGIC: &[u8] = b"Pmd";
+const NAME_SIZE: usize = 20;
+const COMMENT_SIZE: usize = 256;
+const BONE_DISPLAY_NAME_SIZE: usize = 50;
+const TOON_TEXTURE_SIZE: usize = 100;
+const TOON_TEXTURE_COUNT: usize = 10;
+/// Upper bound of the memory reserved up front for a section, counts come from the file.
+const MAX_RESERVED: usize = 4096;
+
+fn read_name<R: Read>(read: &mut R, size: usize) -> Result<String> {
+  read_string(read, size, DecodeMode::Lossy)
+}
+
+fn read_list<R: Read, T>(
+  read: &mut R,
+  count: usize,
+  mut read_item: impl FnMut(&mut R) -> Result<T>,
+) -> Result<Vec<T>> {
+  let mut items = Vec::with_capacity(count.min(MAX_RESERVED));
+  for _ in 0..count {
+    items.push(read_item(read)?);
+  }
+  Ok(items)
+}
+
+fn read_vertex<R: Read, C: Config>(read: &mut R) -> Result<Vertex<C>> {
+  Ok(Vertex {
+    position: read.read_vec3::<C>()?,
+    normal: read.read_vec3::<C>()?,
+    uv: read.read_vec2::<C>()?,
+    bone_indices: [read.read_u16::<LE>()?, read.read_u16::<LE>()?],
+    bone_1_weight: read.read_u8()?,
+    edge_flag: read.read_u8()?,
+  })
+}
+
+fn read_material<R: Read, C: Config>(read: &mut R) -> Result<Material<C>> {
+  Ok(Material {
+    diffuse_color: read.read_vec3::<C>()?,
+    alpha: read.read_f32::<LE>()?,
+    specular_strength: read.read_f32::<LE>()?,
+    specular_color: read.read_vec3::<C>()?,
+    ambient_color: read.read_vec3::<C>()?,
+    toon_index: read.read_u8()?,
+    edge_flag: read.read_u8()?,
+    surface_count: read.read_u32::<LE>()?,
+    texture: read_name(read, NAME_SIZE)?,
+  })
+}
+
+fn read_bone<R: Read, C: Config>(read: &mut R) -> Result<Bone<C>> {
+  Ok(Bone {
+    name: read_name(read, NAME_SIZE)?,
+    parent: read.read_u16::<LE>()?,
+    tail: read.read_u16::<LE>()?,
+    bone_type: BoneType::from(read.read_u8()?),
+    ik_parent: read.read_u16::<LE>()?,
+    position: read.read_vec3::<C>()?,
+  })
+}
+
+fn read_ik<R: Read>(read: &mut R) -> Result<Ik> {
+  let bone = read.read_u16::<LE>()?;
+  let target = read.read_u16::<LE>()?;
+  let link_count = read.read_u8()?;
+  let iterations = read.read_u16::<LE>()?;
+  let limit_angle = read.read_f32::<LE>()?;
+  let links = read_list(read, link_count.into(), |read| Ok(read.read_u16::<LE>()?))?;
+
+  Ok(Ik {
+    bone,
+    target,
+    iterations,
+    limit_angle,
+    links,
+  })
+}
+
+fn read_morph<R: Read, C: Config>(read: &mut R) -> Result<Morph<C>> {
+  let name = read_name(read, NAME_SIZE)?;
+  let vertex_count = read.read_u32::<LE>()?;
+  let morph_type = MorphType::from(read.read_u8()?);
+  let vertices = read_list(read, vertex_count as usize, |read| {
+    Ok(MorphVertex {
+      index: read.read_u32::<LE>()?,
+      offset: read.read_vec3::<C>()?,
+    })
+  })?;
+
+  Ok(Morph {
+    name,
+    morph_type,
+    vertices,
+  })
+}
+
+fn read_rigid_body<R: Read, C: Config>(read: &mut R) -> Result<RigidBody<C>> {
+  Ok(RigidBody {
+    name: read_name(read, NAME_SIZE)?,
+    bone_index: read.read_u16::<LE>()?,
+    group_id: read.read_u8()?,
+    non_collision_mask: read.read_u16::<LE>()?,
+    shape: ShapeType::try_from(read.read_u8()?)?,
+    shape_size: read.read_vec3::<C>()?,
+    shape_position: read.read_vec3::<C>()?,
+    shape_rotation: read.read_vec3::<C>()?,
+    mass: read.read_f32::<LE>()?,
+    move_attenuation: read.read_f32::<LE>()?,
+    rotation_damping: read.read_f32::<LE>()?,
+    repulsion: read.read_f32::<LE>()?,
+    friction: read.read_f32::<LE>()?,
+    physics_mode: PhysicsMode::try_from(read.read_u8()?)?,
+  })
+}
+
+fn read_joint<R: Read, C: Config>(read: &mut R) -> Result<Joint<C>> {
+  Ok(Joint {
+    name: read_name(read, NAME_SIZE)?,
+    rigid_body_a: read.read_u32::<LE>()?,
+    rigid_body_b: read.read_u32::<LE>()?,
+    position: read.read_vec3::<C>()?,
+    rotation: read.read_vec3::<C>()?,
+    position_min: read.read_vec3::<C>()?,
+    position_max: read.read_vec3::<C>()?,
+    rotation_min: read.read_vec3::<C>()?,
+    rotation_max: read.read_vec3::<C>()?,
+    position_spring: read.read_vec3::<C>()?,
+    rotation_spring: read.read_vec3::<C>()?,
+  })
+}
+
+fn has_more(tail: &Cursor<Vec<u8>>) -> bool {
+  (tail.position() as usize) < tail.get_ref().len()
+}
+
+impl<C: Config> Pmd<C> {
+  #[cfg(feature = "std")]
+  pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+    Self::read(BufReader::new(File::open(path)?))
+  }
+
+  /// Reads a model, fails with `Error::InvalidHeader` if it doesn't start with `Pmd`.
+  ///
+  /// Each of the trailing English name, toon texture and physics blocks may be missing, along
+  /// with the ones after it.
+  pub fn read<R: Read>(mut read: R) -> Result<Self> {
+    let read = &mut read;
+    let mut magic = [0; 3];
+    read.read_exact(&mut magic)?;
+    if magic != MAGIC {
+      return Err(Error::InvalidHeader);
+    }
+    let version = read.read_f32::<LE>()?;
+    let model_name = read_name(read, NAME_SIZE)?;
+    let comment = read_name(read, COMMENT_SIZE)?;
+
+    let count = read.read_u32::<LE>()?;
+    let vertices = read_list(read, count as usize, read_vertex)?;
+    let count = read.read_u32::<LE>()?;
+    let surfaces = read_list(read, count as usize / 3, |read| {
+      Ok([
+        read.read_u16::<LE>()?,
+        read.read_u16::<LE>()?,
+        read.read_u16::<LE>()?,
+      ])
+    })?;
+    // Indices of an incomplete triangle
+    for _ in 0..count % 3 {
+      read.read_u16::<LE>()?;
+    }
+    let count = read.read_u32::<LE>()?;
+    let materials = read_list(read, count as usize, read_material)?;
+    let count = read.read_u16::<LE>()?;
+    let bones = read_list(read, count.into(), read_bone)?;
+    let count = read.read_u16::<LE>()?;
+    let iks = read_list(read, count.into(), read_ik)?;
+    let count = read.read_u16::<LE>()?;
+    let morphs = read_list(read, count.into(), read_morph)?;
+    let count = read.read_u8()?;
+    let morph_display = read_list(read, count.into(), |read| Ok(read.read_u16::<LE>()?))?;
+    let count = read.read_u8()?;
+    let bone_display_names = read_list(read, count.into(), |read| {
+      read_name(read, BONE_DISPLAY_NAME_SIZE)
+    })?;
+    let count = read.read_u32::<LE>()?;
+    let bone_display = read_list(read, count as usize, |read| {
+      Ok(BoneDisplay {
+        bone: read.read_u16::<LE>()?,
+        frame: read.read_u8()?,
+      })
+    })?;
+
+    let mut pmd = Pmd {
+      version,
+      model_name,
+      comment,
+      vertices,
+      surfaces,
+      materials,
+      bones,
+      iks,
+      morphs,
+      morph_display,
+      bone_display_names,
+      bone_display,
+      english: None,
+      toon_textures: None,
+      rigid_bodies: Vec::new(),
+      joints: Vec::new(),
+    };
+
+    let mut tail = Vec::new();
+    read.read_to_end(&mut tail)?;
+    let tail = &mut Cursor::new(tail);
+    if !has_more(tail) {
+      return Ok(pmd);
+    }
+    if tail.read_u8()? != 0 {
+      pmd.english = Some(EnglishNames {
+        model_name: read_name(tail, NAME_SIZE)?,
+        comment: read_name(tail, COMMENT_SIZE)?,
+        bone_names: read_list(tail, pmd.bones.len(), |read| read_name(read, NAME_SIZE))?,
+        morph_names: read_list(tail, pmd.morphs.len().saturating_sub(1), |read| {
+          read_name(read, NAME_SIZE)
+        })?,
+        bone_display_names: read_list(tail, pmd.bone_display_names.len(), |read| {
+          read_name(read, BONE_DISPLAY_NAME_SIZE)
+        })?,
+      });
+    }
+
+    if !has_more(tail) {
+      return Ok(pmd);
+    }
+    pmd.toon_textures = Some(read_list(tail, TOON_TEXTURE_COUNT, |read| {
+      read_name(read, TOON_TEXTURE_SIZE)
+    })?);
+
+    if !has_more(tail) {
+      return Ok(pmd);
+    }
+    let count = tail.read_u32::<LE>()?;
+    pmd.rigid_bodies = read_list(tail, count as usize, read_rigid_body)?;
+    let count = tail.read_u32::<LE>()?;
+    pmd.joints = read_list(tail, count as usize, read_joint)?;
+
+    Ok(pmd)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::math::to_array;
+  use crate::DefaultConfig;
+
+  const FIXTURE_MODEL_PMD: &[u8] = include_bytes!("../../fixtures/model.pmd");
+  /// Offsets of the English names, toon textures and physics blocks in the fixture, counted by
+  /// hand from the record sizes of the format in `test_fixture_layout`.
+  const ENGLISH_OFFSET: usize = 1219;
+  const TOON_OFFSET: usize = 1756;
+  const PHYSICS_OFFSET: usize = 2756;
+
+  fn read(bytes: &[u8]) -> Result<Pmd> {
+    Pmd::<DefaultConfig>::read(Cursor::new(bytes))
+  }
+
+  #[test]
+  fn test_read_fixture() {
+    let pmd = read(FIXTURE_MODEL_PMD).unwrap();
+
+    assert_eq!(pmd.version, 1.0);
+    assert_eq!(pmd.model_name, "テストモデル");
+    assert_eq!(pmd.comment, "合成したテスト用のモデル\n");
+
+    assert_eq!(pmd.vertices.len(), 6);
+    let vertex = &pmd.vertices[1];
+    assert_eq!(to_array::<3>(&vertex.position), [1.0, 0.0, 0.0]);
+    assert_eq!(vertex.bone_indices, [1, 2]);
+    assert_eq!(vertex.bone_1_weight, 50);
+    assert_eq!(pmd.vertices[2].edge_flag, 1);
+    assert_eq!(pmd.surfaces, [[0, 1, 2], [2, 1, 3], [2, 3, 4], [4, 3, 5]]);
+
+    assert_eq!(pmd.materials.len(), 2);
+    assert_eq!(pmd.materials[0].texture, "body.bmp*face.sph");
+    assert_eq!(pmd.materials[0].surface_count, 6);
+    assert_eq!(pmd.materials[1].toon_index, NO_TOON);
+
+    let names: Vec<_> = pmd.bones.iter().map(|b| b.name.as_str()).collect();
+    assert_eq!(
+      names,
+      [
+        "センター",
+        "左足",
+        "左ひざ",
+        "左足首",
+        "左足ＩＫ",
+        "左つま先"
+      ]
+    );
+    assert_eq!(pmd.bones[0].parent, NO_BONE);
+    assert_eq!(pmd.bones[4].bone_type, BoneType::Ik);
+    assert_eq!(
+      pmd.iks,
+      [Ik {
+        bone: 4,
+        target: 3,
+        iterations: 40,
+        limit_angle: 0.5,
+        links: vec![2, 1],
+      }]
+    );
+
+    assert_eq!(pmd.morphs.len(), 3);
+    assert_eq!(pmd.morphs[0].morph_type, MorphType::Base);
+    assert_eq!(pmd.morphs[1].name, "あ");
+    assert_eq!(pmd.morphs[1].vertices[1].index, 1);
+    assert_eq!(pmd.morph_display, [1, 2]);
+    assert_eq!(pmd.bone_display_names, ["足\n", "その他\n"]);
+    assert_eq!(pmd.bone_display.len(), 4);
+
+    let english = pmd.english.as_ref().unwrap();
+    assert_eq!(english.model_name, "Test model");
+    assert_eq!(english.bone_names[4], "leg IK_L");
+    assert_eq!(english.morph_names, ["a", "blink"]);
+    assert_eq!(english.bone_display_names, ["Legs", "Other"]);
+    assert_eq!(pmd.toon_textures.as_ref().unwrap()[9], "toon10.bmp");
+    assert_eq!(pmd.rigid_bodies.len(), 1);
+    assert_eq!(pmd.rigid_bodies[0].name, "頭");
+    assert_eq!(pmd.rigid_bodies[0].shape, ShapeType::Capsule);
+    assert_eq!(pmd.rigid_bodies[0].physics_mode, PhysicsMode::Dynamic);
+    assert_eq!(pmd.joints.len(), 1);
+    assert_eq!(to_array::<3>(&pmd.joints[0].rotation_spring), [10.0; 3]);
+  }
+
+  fn u16_at(offset: usize) -> u16 {
+    u16::from_le_bytes([FIXTURE_MODEL_PMD[offset], FIXTURE_MODEL_PMD[offset + 1]])
+  }
+
+  fn u32_at(offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&FIXTURE_MODEL_PMD[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+  }
+
+  /// The fixture was made by this crate, so its layout is checked against the sizes of the
+  /// records rather than against the reader.
+  #[test]
+  fn test_fixture_layout() {
+    assert_eq!(&FIXTURE_MODEL_PMD[..3], b"Pmd");
+    // 3 magic, 4 version, 20 name and 256 comment bytes
+    assert_eq!(u32_at(283), 6);
+    // 38 bytes a vertex, the bones and weight of the second at 32
+    assert_eq!(f32::from_bits(u32_at(287 + 38)), 1.0);
+    assert_eq!([u16_at(287 + 38 + 32), u16_at(287 + 38 + 34)], [1, 2]);
+    assert_eq!(FIXTURE_MODEL_PMD[287 + 38 + 36], 50);
+    assert_eq!(u32_at(515), 12);
+    // 2 bytes a vertex index, then 70 bytes a material with the face count at 46
+    assert_eq!(u32_at(543), 2);
+    assert_eq!(u32_at(547 + 46), 6);
+    assert_eq!(&FIXTURE_MODEL_PMD[547 + 50..547 + 67], b"body.bmp*face.sph");
+    // 39 bytes a bone with the parent at 20 and the type at 24
+    assert_eq!(u16_at(687), 6);
+    assert_eq!(u16_at(689 + 20), NO_BONE);
+    assert_eq!(FIXTURE_MODEL_PMD[689 + 4 * 39 + 24], 2);
+    // An IK of 11 bytes and 2 links
+    assert_eq!(u16_at(923), 1);
+    assert_eq!([u16_at(925), u16_at(927)], [4, 3]);
+    assert_eq!(FIXTURE_MODEL_PMD[929], 2);
+    // Morphs of 25 bytes and 16 a vertex, with 2, 2 and 1 vertices
+    assert_eq!(u16_at(940), 3);
+    assert_eq!(u32_at(942 + 20), 2);
+    assert_eq!(u32_at(942 + 57 + 20), 2);
+    assert_eq!(u32_at(942 + 2 * 57 + 20), 1);
+    // 2 morphs shown, 2 bone display names of 50 bytes and 4 displayed bones of 3
+    assert_eq!(FIXTURE_MODEL_PMD[1097], 2);
+    assert_eq!(FIXTURE_MODEL_PMD[1102], 2);
+    assert_eq!(u32_at(1203), 4);
+    assert_eq!(1203 + 4 + 4 * 3, ENGLISH_OFFSET);
+    // The flag, 20 + 256 bytes of the model and 20 a bone, morph without the base and 50 a
+    // display name
+    assert_eq!(FIXTURE_MODEL_PMD[ENGLISH_OFFSET], 1);
+    assert_eq!(
+      ENGLISH_OFFSET + 1 + 276 + 6 * 20 + 2 * 20 + 2 * 50,
+      TOON_OFFSET
+    );
+    assert_eq!(
+      &FIXTURE_MODEL_PMD[TOON_OFFSET..TOON_OFFSET + 10],
+      b"toon01.bmp"
+    );
+    assert_eq!(TOON_OFFSET + 10 * 100, PHYSICS_OFFSET);
+    assert_eq!(u32_at(PHYSICS_OFFSET), 1);
+  }
+
+  #[test]
+  fn test_read_without_extensions() {
+    let full = read(FIXTURE_MODEL_PMD).unwrap();
+
+    let pmd = read(&FIXTURE_MODEL_PMD[..ENGLISH_OFFSET]).unwrap();
+    assert_eq!(pmd.bones, full.bones);
+    assert_eq!(pmd.english, None);
+    assert_eq!(pmd.toon_textures, None);
+    assert!(pmd.rigid_bodies.is_empty());
+
+    let pmd = read(&FIXTURE_MODEL_PMD[..TOON_OFFSET]).unwrap();
+    assert_eq!(pmd.english, full.english);
+    assert_eq!(pmd.toon_textures, None);
+
+    let pmd = read(&FIXTURE_MODEL_PMD[..PHYSICS_OFFSET]).unwrap();
+    assert_eq!(pmd.toon_textures, full.toon_textures);
+    assert!(pmd.joints.is_empty());
+
+    // Without English names
+    let mut bytes = FIXTURE_MODEL_PMD[..ENGLISH_OFFSET].to_vec();
+    bytes.push(0);
+    bytes.extend_from_slice(&FIXTURE_MODEL_PMD[TOON_OFFSET..]);
+    let pmd = read(&bytes).unwrap();
+    assert_eq!(pmd.english, None);
+    assert_eq!(pmd.joints, full.joints);
+  }
+
+  #[test]
+  fn test_read_invalid() {
+    assert!(matches!(read(b"PMX \0\0\0\0"), Err(Error::InvalidHeader)));
+    // Truncated inside a required section or an extension block
+    assert!(read(&FIXTURE_MODEL_PMD[..ENGLISH_OFFSET - 1]).is_err());
+    assert!(read(&FIXTURE_MODEL_PMD[..TOON_OFFSET + 10]).is_err());
+  }
+}
@@ -0,0 +1,245 @@
+use crate::pmx::rigid_body::{PhysicsMode, ShapeType};
+use crate::{Config, DefaultConfig};
+use alloc::{string::String, vec::Vec};
+use core::fmt::{Display, Formatter};
+
+/// Bone index of PMD files meaning no bone, e.g. for the parent of root bones.
+pub const NO_BONE: u16 = 0xffff;
+
+/// Toon index of materials without a toon texture.
+pub const NO_TOON: u8 = 0xff;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Vertex<C: Config = DefaultConfig> {
+  pub position: C::Vec3,
+  pub normal: C::Vec3,
+  pub uv: C::Vec2,
+  pub bone_indices: [u16; 2],
+  /// Weight of the first bone from 0 to 100, the second bone gets the rest.
+  pub bone_1_weight: u8,
+  /// 1 hides the edge around the vertex.
+  pub edge_flag: u8,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Material<C: Config = DefaultConfig> {
+  pub diffuse_color: C::Vec3,
+  pub alpha: f32,
+  pub specular_strength: f32,
+  pub specular_color: C::Vec3,
+  pub ambient_color: C::Vec3,
+  /// Index into the toon textures, `NO_TOON` for none.
+  pub toon_index: u8,
+  /// 1 draws the edge of the material.
+  pub edge_flag: u8,
+  /// Number of face indices, 3 per triangle.
+  pub surface_count: u32,
+  /// Texture and sphere map file names joined by `*`, e.g. `body.bmp*face.sph`.
+  pub texture: String,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BoneType {
+  Rotate,
+  RotateMove,
+  Ik,
+  Unknown,
+  IkLinked,
+  RotateInfluenced,
+  IkTarget,
+  Invisible,
+  Twist,
+  RotateFollow,
+  Other(u8),
+}
+
+impl Display for BoneType {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
+    match self {
+      BoneType::Rotate => write!(f, "rotate"),
+      BoneType::RotateMove => write!(f, "rotate and move"),
+      BoneType::Ik => write!(f, "IK"),
+      BoneType::Unknown => write!(f, "unknown"),
+      BoneType::IkLinked => write!(f, "IK linked"),
+      BoneType::RotateInfluenced => write!(f, "rotate influenced"),
+      BoneType::IkTarget => write!(f, "IK target"),
+      BoneType::Invisible => write!(f, "invisible"),
+      BoneType::Twist => write!(f, "twist"),
+      BoneType::RotateFollow => write!(f, "rotate follow"),
+      BoneType::Other(bone_type) => write!(f, "other({})", bone_type),
+    }
+  }
+}
+
+impl From<u8> for BoneType {
+  fn from(value: u8) -> Self {
+    match value {
+      0 => BoneType::Rotate,
+      1 => BoneType::RotateMove,
+      2 => BoneType::Ik,
+      3 => BoneType::Unknown,
+      4 => BoneType::IkLinked,
+      5 => BoneType::RotateInfluenced,
+      6 => BoneType::IkTarget,
+      7 => BoneType::Invisible,
+      8 => BoneType::Twist,
+      9 => BoneType::RotateFollow,
+      bone_type => BoneType::Other(bone_type),
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bone<C: Config = DefaultConfig> {
+  pub name: String,
+  /// `NO_BONE` for root bones.
+  pub parent: u16,
+  /// The bone the tail points to, 0 or `NO_BONE` for none.
+  pub tail: u16,
+  pub bone_type: BoneType,
+  /// The IK bone for `IkLinked` bones and the influencing bone for `RotateInfluenced` ones.
+  pub ik_parent: u16,
+  pub position: C::Vec3,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ik {
+  pub bone: u16,
+  pub target: u16,
+  pub iterations: u16,
+  /// Rotation limit per iteration, PMX stores 4 times this value in radians.
+  pub limit_angle: f32,
+  /// Bones of the chain from the target up.
+  pub links: Vec<u16>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MorphType {
+  /// The vertices all other morphs refer to.
+  Base,
+  Eyebrows,
+  Eyes,
+  Mouth,
+  Other,
+  Unknown(u8),
+}
+
+impl Display for MorphType {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
+    match self {
+      MorphType::Base => write!(f, "base"),
+      MorphType::Eyebrows => write!(f, "eyebrows"),
+      MorphType::Eyes => write!(f, "eyes"),
+      MorphType::Mouth => write!(f, "mouth"),
+      MorphType::Other => write!(f, "other"),
+      MorphType::Unknown(morph_type) => write!(f, "unknown({})", morph_type),
+    }
+  }
+}
+
+impl From<u8> for MorphType {
+  fn from(value: u8) -> Self {
+    match value {
+      0 => MorphType::Base,
+      1 => MorphType::Eyebrows,
+      2 => MorphType::Eyes,
+      3 => MorphType::Mouth,
+      4 => MorphType::Other,
+      morph_type => MorphType::Unknown(morph_type),
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MorphVertex<C: Config = DefaultConfig> {
+  /// A vertex index in the base morph and an index into the base morph vertices otherwise.
+  pub index: u32,
+  /// The position in the base morph and an offset from it otherwise.
+  pub offset: C::Vec3,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Morph<C: Config = DefaultConfig> {
+  pub name: String,
+  pub morph_type: MorphType,
+  pub vertices: Vec<MorphVertex<C>>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BoneDisplay {
+  pub bone: u16,
+  /// Index into `Pmd::bone_display_names`, starting at 1 as 0 is the root frame.
+  pub frame: u8,
+}
+
+/// The optional block of English names.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct EnglishNames {
+  pub model_name: String,
+  pub comment: String,
+  pub bone_names: Vec<String>,
+  /// Names of the morphs except the base morph.
+  pub morph_names: Vec<String>,
+  pub bone_display_names: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RigidBody<C: Config = DefaultConfig> {
+  pub name: String,
+  /// `NO_BONE` for rigid bodies not attached to a bone.
+  pub bone_index: u16,
+  pub group_id: u8,
+  pub non_collision_mask: u16,
+  pub shape: ShapeType,
+  pub shape_size: C::Vec3,
+  /// Position relative to the bone.
+  pub shape_position: C::Vec3,
+  pub shape_rotation: C::Vec3,
+  pub mass: f32,
+  pub move_attenuation: f32,
+  pub rotation_damping: f32,
+  pub repulsion: f32,
+  pub friction: f32,
+  pub physics_mode: PhysicsMode,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Joint<C: Config = DefaultConfig> {
+  pub name: String,
+  pub rigid_body_a: u32,
+  pub rigid_body_b: u32,
+  pub position: C::Vec3,
+  pub rotation: C::Vec3,
+  pub position_min: C::Vec3,
+  pub position_max: C::Vec3,
+  pub rotation_min: C::Vec3,
+  pub rotation_max: C::Vec3,
+  pub position_spring: C::Vec3,
+  pub rotation_spring: C::Vec3,
+}
+
+/// A whole PMD model.
+///
+/// The sections after the bone display list are optional, `english`, `toon_textures` and the
+/// physics sections are empty for files without them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pmd<C: Config = DefaultConfig> {
+  pub version: f32,
+  pub model_name: String,
+  pub comment: String,
+  pub vertices: Vec<Vertex<C>>,
+  pub surfaces: Vec<[u16; 3]>,
+  pub materials: Vec<Material<C>>,
+  pub bones: Vec<Bone<C>>,
+  pub iks: Vec<Ik>,
+  pub morphs: Vec<Morph<C>>,
+  /// Morphs shown in the panel, as indices into `morphs`.
+  pub morph_display: Vec<u16>,
+  pub bone_display_names: Vec<String>,
+  pub bone_display: Vec<BoneDisplay>,
+  pub english: Option<EnglishNames>,
+  /// File names of the 10 toon textures, `toon01.bmp` to `toon10.bmp` by default.
+  pub toon_textures: Option<Vec<String>>,
+  pub rigid_bodies: Vec<RigidBody<C>>,
+  pub joints: Vec<Joint<C>>,
+}
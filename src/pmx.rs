@@ -1,12 +1,48 @@
 pub mod bone;
+#[cfg(feature = "std")]
+pub mod bounds;
+#[cfg(feature = "std")]
+pub mod builder;
 pub mod display;
 pub mod error;
+#[cfg(feature = "std")]
+pub mod ik;
+#[cfg(feature = "std")]
+pub mod index;
 pub mod joint;
 pub mod material;
+#[cfg(feature = "std")]
+pub mod merge;
+pub mod model;
 pub mod morph;
+pub mod name;
+#[cfg(feature = "std")]
+pub mod normals;
+#[cfg(feature = "std")]
+pub mod obj;
+#[cfg(feature = "std")]
+pub mod pose;
+#[cfg(feature = "rapier3d")]
+pub mod rapier;
 pub mod reader;
 pub mod rigid_body;
 pub mod settings;
+#[cfg(feature = "std")]
+pub mod skeleton;
+#[cfg(feature = "std")]
+pub mod skinning;
+pub mod soft_body;
+#[cfg(feature = "std")]
+pub mod tangents;
+#[cfg(feature = "std")]
+pub mod texture_path;
+pub mod typed_index;
 pub mod types;
+pub mod validate;
 pub mod vertex;
 pub mod weight_deform;
+#[cfg(feature = "std")]
+mod writer;
+
+#[cfg(feature = "std")]
+pub use writer::PmxWriteOptions;
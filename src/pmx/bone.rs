@@ -1,9 +1,17 @@
+use alloc::{format, string::ToString, vec::Vec};
+use core::fmt::{Debug, Display, Formatter};
 use enumflags2::{bitflags, BitFlags};
 use itertools::Itertools;
-use std::fmt::{Debug, Display, Formatter};
 
-use crate::{display::DisplayOption, Config};
+#[cfg(feature = "serde")]
+use crate::SerdeConfig;
+use crate::{display::DisplayOption, Config, LocalizedName};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
+/// The bits of the flag word of a bone, which also tells which optional fields the bone has.
+///
+/// Every bit has a variant, so the word read from a file is written back as it was.
 #[bitflags]
 #[derive(Copy, Clone, PartialEq, Debug)]
 #[repr(u16)]
@@ -22,19 +30,27 @@ pub enum BoneFlags {
   LocalAxis = 0b0000_1000_0000_0000,
   PhysicalTransform = 0b0001_0000_0000_0000,
   ExternalParentTransform = 0b0010_0000_0000_0000,
+  Unknown14 = 0b0100_0000_0000_0000,
+  Unknown15 = 0b1000_0000_0000_0000,
 }
 
 struct BoneFlagsFmt(BitFlags<BoneFlags>);
 
 impl Display for BoneFlagsFmt {
-  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), core::fmt::Error> {
     write!(f, "{}", self.0.iter().map(|v| format!("{:?}", v)).join("|"))
   }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub enum Connection<C: Config> {
-  Index(C::BoneIndex),
+  /// `None` for bones without a tail.
+  Index(Option<C::BoneIndex>),
   Position(C::Vec3),
 }
 
@@ -43,15 +59,20 @@ where
   C::BoneIndex: Display,
   C::Vec3: Display,
 {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     match self {
-      Connection::Index(t) => write!(f, "index({})", t),
+      Connection::Index(t) => write!(f, "index({})", DisplayOption::new(t)),
       Connection::Position(i) => write!(f, "offset({})", i),
     }
   }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct Additional<C: Config> {
   pub parent: C::BoneIndex,
   pub rate: f32,
@@ -61,12 +82,17 @@ impl<C: Config> Display for Additional<C>
 where
   C::BoneIndex: Display,
 {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     write!(f, "{} at rate {}", self.parent, self.rate)
   }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct LocalAxis<C: Config> {
   pub x: C::Vec3,
   pub z: C::Vec3,
@@ -76,15 +102,22 @@ impl<C: Config> Display for LocalAxis<C>
 where
   C::Vec3: Display,
 {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     write!(f, "x: {} z: {}", self.x, self.z)
   }
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct InverseKinematics<C: Config> {
   pub ik_bone: C::BoneIndex,
+  /// How often the links are rotated towards the target.
   pub iterations: u32,
+  /// The largest rotation of a link in one iteration, in radians.
   pub limit_angle: f32,
   pub links: Vec<IKLink<C>>,
 }
@@ -94,7 +127,7 @@ where
   C::BoneIndex: Display,
   C::Vec3: Display,
 {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     write!(
       f,
       "index: {} iterations: {} limit angle: {}\n{}",
@@ -106,10 +139,67 @@ where
   }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+/// The Euler angles an IK link may rotate to, in radians.
+///
+/// Some models store the limits of knees reversed on purpose, `Pmx::validate` reports them
+/// instead of repairing them.
+#[derive(Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
+pub struct AngleLimits<C: Config> {
+  pub lower: C::Vec3,
+  pub upper: C::Vec3,
+}
+
+// Derived impls would need `C: Clone` rather than the vectors
+impl<C: Config> Clone for AngleLimits<C> {
+  fn clone(&self) -> Self {
+    AngleLimits {
+      lower: self.lower.clone(),
+      upper: self.upper.clone(),
+    }
+  }
+}
+
+impl<C: Config> Copy for AngleLimits<C> where C::Vec3: Copy {}
+
+impl<C: Config> AngleLimits<C> {
+  /// The lower and upper limits in degrees.
+  pub fn limits_degrees(&self) -> ([f32; 3], [f32; 3]) {
+    let degrees = |v: &C::Vec3| crate::math::to_array::<3>(v).map(f32::to_degrees);
+    (degrees(&self.lower), degrees(&self.upper))
+  }
+
+  /// The axes whose lower limit is above the upper one.
+  pub fn reversed_axes(&self) -> impl Iterator<Item = usize> {
+    let lower = crate::math::to_array::<3>(&self.lower);
+    let upper = crate::math::to_array::<3>(&self.upper);
+    (0..3).filter(move |&axis| lower[axis] > upper[axis])
+  }
+}
+
+impl<C: Config> Display for AngleLimits<C>
+where
+  C::Vec3: Display,
+{
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
+    write!(f, "[{} - {}]", self.lower, self.upper)
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct IKLink<C: Config> {
   pub ik_bone: C::BoneIndex,
-  pub limits: Option<(C::Vec3, C::Vec3)>,
+  /// `None` for links that rotate freely.
+  pub limits: Option<AngleLimits<C>>,
 }
 
 impl<C: Config> Display for IKLink<C>
@@ -117,21 +207,27 @@ where
   C::BoneIndex: Display,
   C::Vec3: Display,
 {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     write!(f, "link: {} ", self.ik_bone,)?;
-    if let Some((ref low, ref high)) = self.limits {
-      write!(f, "limits: [{} - {}]", low, high)
+    if let Some(ref limits) = self.limits {
+      write!(f, "limits: {}", limits)
     } else {
       write!(f, "unlimited")
     }
   }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct Bone<C: Config> {
-  pub local_name: String,
-  pub universal_name: String,
+  pub name: LocalizedName,
   pub position: C::Vec3,
-  pub parent: C::BoneIndex,
+  /// `None` for root bones.
+  pub parent: Option<C::BoneIndex>,
   pub transform_level: i32,
   pub bone_flags: BitFlags<BoneFlags>,
   pub connection: Connection<C>,
@@ -142,12 +238,77 @@ pub struct Bone<C: Config> {
   pub inverse_kinematics: Option<InverseKinematics<C>>,
 }
 
+impl<C: Config> Bone<C> {
+  /// The flag word as stored in the file.
+  pub fn raw_flags(&self) -> u16 {
+    self.bone_flags.bits()
+  }
+
+  /// Whether the tail is another bone rather than an offset, see `connection`.
+  pub fn has_tail_bone(&self) -> bool {
+    self.bone_flags.contains(BoneFlags::Connection)
+  }
+
+  pub fn is_rotatable(&self) -> bool {
+    self.bone_flags.contains(BoneFlags::Rotatable)
+  }
+
+  pub fn is_movable(&self) -> bool {
+    self.bone_flags.contains(BoneFlags::Movable)
+  }
+
+  pub fn is_visible(&self) -> bool {
+    self.bone_flags.contains(BoneFlags::Display)
+  }
+
+  /// Whether the user can select and move the bone in the editor.
+  pub fn is_enabled(&self) -> bool {
+    self.bone_flags.contains(BoneFlags::CanOperate)
+  }
+
+  pub fn is_ik(&self) -> bool {
+    self.bone_flags.contains(BoneFlags::InverseKinematics)
+  }
+
+  /// Whether the bone adds the rotation of `additional.parent`.
+  pub fn inherits_rotation(&self) -> bool {
+    self.bone_flags.contains(BoneFlags::AddRotation)
+  }
+
+  /// Whether the bone adds the movement of `additional.parent`.
+  pub fn inherits_movement(&self) -> bool {
+    self.bone_flags.contains(BoneFlags::AddMovement)
+  }
+
+  /// Whether the inherited transform is the local one of the parent rather than its user input.
+  pub fn inherits_local(&self) -> bool {
+    self.bone_flags.contains(BoneFlags::AddLocalDeform)
+  }
+
+  pub fn has_fixed_axis(&self) -> bool {
+    self.bone_flags.contains(BoneFlags::FixedAxis)
+  }
+
+  pub fn has_local_axis(&self) -> bool {
+    self.bone_flags.contains(BoneFlags::LocalAxis)
+  }
+
+  /// Whether the bone is transformed after the physics simulation.
+  pub fn after_physics(&self) -> bool {
+    self.bone_flags.contains(BoneFlags::PhysicalTransform)
+  }
+
+  pub fn has_external_parent(&self) -> bool {
+    self.bone_flags.contains(BoneFlags::ExternalParentTransform)
+  }
+}
+
 impl<C: Config> Display for Bone<C>
 where
   C::BoneIndex: Display,
   C::Vec3: Display,
 {
-  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), core::fmt::Error> {
     write!(
       f,
       r"local name: {}, universal name: {},
@@ -155,10 +316,10 @@ position: {}, parent: {}, transform level: {},
 flags: {},
 connection: {}, additional: {}, fixed axis: {}, local axis {}, parent transform: {},
 inverse kinematics: {}",
-      self.local_name,
-      self.universal_name,
+      self.name.ja,
+      self.name.en,
       self.position,
-      self.parent,
+      DisplayOption::new(&self.parent),
       self.transform_level,
       BoneFlagsFmt(self.bone_flags),
       self.connection,
@@ -170,3 +331,26 @@ inverse kinematics: {}",
     )
   }
 }
+
+#[cfg(all(test, feature = "std"))]
+pub(crate) mod tests {
+  use super::*;
+  use crate::DefaultConfig;
+
+  /// A rotatable and movable bone without a tail or any of the optional parts.
+  pub(crate) fn bone(name: &str, parent: Option<i32>, position: [f32; 3]) -> Bone<DefaultConfig> {
+    Bone {
+      name: LocalizedName::new(name, ""),
+      position: position.into(),
+      parent,
+      transform_level: 0,
+      bone_flags: BoneFlags::Rotatable | BoneFlags::Movable,
+      connection: Connection::Index(None),
+      additional: None,
+      fixed_axis: None,
+      local_axis: None,
+      external_parent_transform: None,
+      inverse_kinematics: None,
+    }
+  }
+}
@@ -0,0 +1,202 @@
+//! Bounds of the vertices of a model, for framing and culling.
+
+use crate::{
+  math::{to_array, Widen},
+  pmx::types::index_to_usize,
+  Config, Error, Pmx, Result,
+};
+use std::convert::TryInto;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BoundingBox<C: Config> {
+  pub min: C::Vec3,
+  pub max: C::Vec3,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BoundingSphere<C: Config> {
+  pub center: C::Vec3,
+  pub radius: f32,
+}
+
+/// The box of the points added to it.
+struct Bounds {
+  min: [f32; 3],
+  max: [f32; 3],
+}
+
+impl Bounds {
+  fn new() -> Self {
+    Bounds {
+      min: [f32::INFINITY; 3],
+      max: [f32::NEG_INFINITY; 3],
+    }
+  }
+
+  fn add(&mut self, p: [f32; 3]) {
+    for (i, c) in p.iter().enumerate() {
+      self.min[i] = self.min[i].min(*c);
+      self.max[i] = self.max[i].max(*c);
+    }
+  }
+
+  /// `None` if no points were added.
+  fn finish<C: Config>(self) -> Option<BoundingBox<C>> {
+    if self.min[0] > self.max[0] {
+      return None;
+    }
+    Some(BoundingBox {
+      min: self.min.widen(),
+      max: self.max.widen(),
+    })
+  }
+}
+
+fn distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+  let d = [a[0] - b[0], a[1] - b[1], a[2] - b[2]];
+  (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt()
+}
+
+impl<C: Config> Pmx<C> {
+  /// The box around all vertices, `None` for models without vertices.
+  pub fn bounding_box(&self) -> Option<BoundingBox<C>> {
+    let mut bounds = Bounds::new();
+    for v in &self.vertices {
+      bounds.add(to_array::<3>(&v.position));
+    }
+    bounds.finish()
+  }
+
+  /// A sphere around all vertices with Ritter's method, which is somewhat larger than the
+  /// smallest one. `None` for models without vertices.
+  pub fn bounding_sphere(&self) -> Option<BoundingSphere<C>> {
+    let positions = || self.vertices.iter().map(|v| to_array::<3>(&v.position));
+    let farthest = |from: [f32; 3]| {
+      positions().fold(from, |far, p| {
+        if distance(from, p) > distance(from, far) {
+          p
+        } else {
+          far
+        }
+      })
+    };
+
+    let a = farthest(positions().next()?);
+    let b = farthest(a);
+    let mut center = [
+      (a[0] + b[0]) / 2.0,
+      (a[1] + b[1]) / 2.0,
+      (a[2] + b[2]) / 2.0,
+    ];
+    let mut radius = distance(a, b) / 2.0;
+    for p in positions() {
+      let d = distance(center, p);
+      if d > radius {
+        // Grow just enough to reach `p`, keeping the far side of the sphere in place
+        let grown = (radius + d) / 2.0;
+        let t = (grown - radius) / d;
+        for (c, p) in center.iter_mut().zip(&p) {
+          *c += (p - *c) * t;
+        }
+        radius = grown;
+      }
+    }
+
+    Some(BoundingSphere {
+      center: center.widen(),
+      radius,
+    })
+  }
+
+  /// The box around the vertices of the surfaces of `material`, `None` for materials without
+  /// surfaces.
+  ///
+  /// # Panics
+  ///
+  /// If there's no material at `material`.
+  pub fn material_bounds(&self, material: usize) -> Result<Option<BoundingBox<C>>> {
+    let mut bounds = Bounds::new();
+    for vertex in self.material_surfaces(material)?.iter().flatten() {
+      match index_to_usize(vertex).filter(|&v| v < self.vertices.len()) {
+        Some(v) => bounds.add(to_array(&self.vertices[v].position)),
+        None => {
+          return Err(Error::IndexOverflow(
+            vertex.clone().try_into().unwrap_or(i64::MAX),
+          ))
+        }
+      }
+    }
+    Ok(bounds.finish())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::DefaultConfig;
+
+  fn model() -> Pmx<DefaultConfig> {
+    let mut pmx =
+      Pmx::<DefaultConfig>::read(&include_bytes!("../../fixtures/model.pmx")[..]).unwrap();
+    let positions = [
+      [-1.0, 0.0, 2.0],
+      [3.0, 1.0, 0.0],
+      [0.0, -2.0, 1.0],
+      [5.0, 5.0, 5.0],
+      [0.0, 0.0, 0.0],
+      [1.0, 1.0, 1.0],
+    ];
+    for (v, p) in pmx.vertices.iter_mut().zip(positions.iter()) {
+      v.position = (*p).into();
+    }
+    pmx.surfaces = vec![[0, 1, 2], [3, 4, 5]];
+    pmx.materials[0].surface_count = 3;
+    pmx.materials[1].surface_count = 3;
+    pmx
+  }
+
+  fn corners(b: BoundingBox<DefaultConfig>) -> ([f32; 3], [f32; 3]) {
+    (to_array(&b.min), to_array(&b.max))
+  }
+
+  #[test]
+  fn test_bounding_box() {
+    let mut pmx = model();
+    assert_eq!(
+      corners(pmx.bounding_box().unwrap()),
+      ([-1.0, -2.0, 0.0], [5.0, 5.0, 5.0])
+    );
+    assert_eq!(
+      corners(pmx.material_bounds(0).unwrap().unwrap()),
+      ([-1.0, -2.0, 0.0], [3.0, 1.0, 2.0])
+    );
+    assert_eq!(
+      corners(pmx.material_bounds(1).unwrap().unwrap()),
+      ([0.0, 0.0, 0.0], [5.0, 5.0, 5.0])
+    );
+
+    pmx.surfaces.pop();
+    pmx.materials[1].surface_count = 0;
+    assert_eq!(pmx.material_bounds(1).unwrap(), None);
+    pmx.vertices.clear();
+    assert_eq!(pmx.bounding_box(), None);
+    assert_eq!(pmx.bounding_sphere(), None);
+  }
+
+  #[test]
+  fn test_bounding_sphere() {
+    let mut pmx = model();
+    let sphere = pmx.bounding_sphere().unwrap();
+    for v in &pmx.vertices {
+      let p = to_array::<3>(&v.position);
+      assert!(distance(to_array(&sphere.center), p) <= sphere.radius + 1e-5);
+    }
+
+    pmx.vertices.truncate(2);
+    pmx.vertices[0].position = [-1.0, 0.0, 0.0].into();
+    pmx.vertices[1].position = [1.0, 0.0, 0.0].into();
+    let sphere = pmx.bounding_sphere().unwrap();
+    assert_eq!(to_array::<3>(&sphere.center), [0.0; 3]);
+    assert_eq!(sphere.radius, 1.0);
+  }
+}
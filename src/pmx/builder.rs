@@ -0,0 +1,330 @@
+//! Building models from code, see `PmxBuilder`.
+
+use crate::math::Widen;
+use crate::pmx::bone::{BoneFlags, Connection};
+use crate::pmx::display::{DisplayElement, DisplayFrame};
+use crate::pmx::material::{EnvironmentBlendMode, MaterialFlags, Toon};
+use crate::pmx::weight_deform::Bdef1;
+use crate::{
+  Bone, Config, DefaultConfig, Error, IndexSize, LocalizedName, Material, Pmx, Result, Settings,
+  TextEncoding, Vertex, WeightDeform,
+};
+use enumflags2::BitFlags;
+use std::convert::TryFrom;
+
+/// A vertex added to a `PmxBuilder`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VertexHandle(usize);
+
+/// A bone added to a `PmxBuilder`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BoneHandle(usize);
+
+struct PendingVertex {
+  position: [f32; 3],
+  normal: [f32; 3],
+  uv: [f32; 2],
+  bone: BoneHandle,
+}
+
+struct PendingBone {
+  name: String,
+  position: [f32; 3],
+  parent: Option<BoneHandle>,
+}
+
+/// Builds a PMX 2.0 model, picking the indices and their sizes.
+///
+/// Materials draw the triangles added since the material before them, so add the triangles of a
+/// material first and then the material.
+pub struct PmxBuilder<C: Config = DefaultConfig> {
+  name: String,
+  vertices: Vec<PendingVertex>,
+  triangles: Vec<[VertexHandle; 3]>,
+  materials: Vec<Material<C>>,
+  /// The triangles drawn by the materials so far.
+  drawn: usize,
+  bones: Vec<PendingBone>,
+}
+
+pub(crate) fn index<I: TryFrom<i32>>(index: usize) -> Result<I> {
+  i32::try_from(index)
+    .ok()
+    .and_then(|i| I::try_from(i).ok())
+    .ok_or(Error::IndexOverflow(index as i64))
+}
+
+impl<C: Config> PmxBuilder<C> {
+  pub fn new(name: impl Into<String>) -> Self {
+    PmxBuilder {
+      name: name.into(),
+      vertices: Vec::new(),
+      triangles: Vec::new(),
+      materials: Vec::new(),
+      drawn: 0,
+      bones: Vec::new(),
+    }
+  }
+
+  /// Adds a vertex moved entirely by `bone`.
+  pub fn add_vertex(
+    &mut self,
+    position: [f32; 3],
+    normal: [f32; 3],
+    uv: [f32; 2],
+    bone: BoneHandle,
+  ) -> VertexHandle {
+    self.vertices.push(PendingVertex {
+      position,
+      normal,
+      uv,
+      bone,
+    });
+    VertexHandle(self.vertices.len() - 1)
+  }
+
+  pub fn add_triangle(&mut self, a: VertexHandle, b: VertexHandle, c: VertexHandle) -> &mut Self {
+    self.triangles.push([a, b, c]);
+    self
+  }
+
+  /// Adds a material drawing the triangles added since the last material, returned to set the
+  /// fields beyond the diffuse color.
+  pub fn add_material(&mut self, name: impl Into<String>, diffuse: [f32; 4]) -> &mut Material<C> {
+    let triangles = self.triangles.len() - self.drawn;
+    self.drawn = self.triangles.len();
+    self.materials.push(Material {
+      name: LocalizedName::new(name, ""),
+      diffuse_color: diffuse.widen(),
+      specular_color: [0.0; 3].widen(),
+      specular_strength: 0.0,
+      ambient_color: [diffuse[0] * 0.5, diffuse[1] * 0.5, diffuse[2] * 0.5].widen(),
+      draw_flags: MaterialFlags::default(),
+      edge_color: [0.0, 0.0, 0.0, 1.0].widen(),
+      edge_scale: 1.0,
+      texture_index: None,
+      environment_index: None,
+      environment_blend_mode: EnvironmentBlendMode::Disabled,
+      toon: Toon::Internal(0),
+      metadata: String::new(),
+      // Overflows are reported by `build`
+      surface_count: i32::try_from(triangles * 3).unwrap_or(-1),
+    });
+    self.materials.last_mut().unwrap()
+  }
+
+  /// Adds a rotatable and movable bone.
+  pub fn add_bone(
+    &mut self,
+    name: impl Into<String>,
+    position: [f32; 3],
+    parent: Option<BoneHandle>,
+  ) -> BoneHandle {
+    self.bones.push(PendingBone {
+      name: name.into(),
+      position,
+      parent,
+    });
+    BoneHandle(self.bones.len() - 1)
+  }
+
+  /// The model, with the root bones in the `Root` display frame.
+  ///
+  /// Fails with `Error::InvalidHandle` for handles of another builder and with
+  /// `Error::SurfaceCountMismatch` for triangles added after the last material.
+  pub fn build(self) -> Result<Pmx<C>> {
+    let bone = |handle: BoneHandle| {
+      if handle.0 < self.bones.len() {
+        index(handle.0)
+      } else {
+        Err(Error::InvalidHandle {
+          kind: "bone",
+          index: handle.0,
+        })
+      }
+    };
+    let vertex = |handle: VertexHandle| {
+      if handle.0 < self.vertices.len() {
+        index(handle.0)
+      } else {
+        Err(Error::InvalidHandle {
+          kind: "vertex",
+          index: handle.0,
+        })
+      }
+    };
+
+    if self.drawn != self.triangles.len() {
+      return Err(Error::SurfaceCountMismatch {
+        materials: self.drawn as u64 * 3,
+        surfaces: self.triangles.len() * 3,
+      });
+    }
+    if let Some(m) = self.materials.iter().find(|m| m.surface_count < 0) {
+      return Err(Error::InvalidSurfaceCount(m.surface_count));
+    }
+
+    let vertices = self
+      .vertices
+      .iter()
+      .map(|v| {
+        Ok(Vertex {
+          position: v.position.widen(),
+          normal: v.normal.widen(),
+          uv: v.uv.widen(),
+          additional: std::iter::empty().collect(),
+          weight_deform: WeightDeform::Bdef1(Bdef1 {
+            bone_index: bone(v.bone)?,
+          }),
+          edge_scale: 1.0,
+        })
+      })
+      .collect::<Result<_>>()?;
+    let surfaces = self
+      .triangles
+      .iter()
+      .map(|t| Ok([vertex(t[0])?, vertex(t[1])?, vertex(t[2])?]))
+      .collect::<Result<_>>()?;
+
+    let mut roots = Vec::new();
+    let mut bones = Vec::with_capacity(self.bones.len());
+    for (i, b) in self.bones.iter().enumerate() {
+      let parent = b.parent.map(bone).transpose()?;
+      if parent.is_none() {
+        roots.push(DisplayElement::Bone(index(i)?));
+      }
+      bones.push(Bone {
+        name: LocalizedName::new(b.name.as_str(), ""),
+        position: b.position.widen(),
+        parent,
+        transform_level: 0,
+        bone_flags: BoneFlags::Connection
+          | BoneFlags::Rotatable
+          | BoneFlags::Movable
+          | BoneFlags::Display
+          | BoneFlags::CanOperate,
+        connection: Connection::Index(None),
+        additional: None,
+        fixed_axis: None,
+        local_axis: None,
+        external_parent_transform: None,
+        inverse_kinematics: None,
+      });
+    }
+
+    let frame = |ja: &str, en: &str, elements| DisplayFrame {
+      name: LocalizedName::new(ja, en),
+      special_flag: true,
+      elements,
+    };
+    let mut pmx = Pmx {
+      version: 2.0,
+      settings: Settings {
+        text_encoding: TextEncoding::UTF16LE,
+        additional_vec4_count: 0,
+        vertex_index_size: IndexSize::I32,
+        texture_index_size: IndexSize::I32,
+        material_index_size: IndexSize::I32,
+        bone_index_size: IndexSize::I32,
+        morph_index_size: IndexSize::I32,
+        rigidbody_index_size: IndexSize::I32,
+      },
+      model_local_name: self.name,
+      model_universal_name: String::new(),
+      local_comments: String::new(),
+      universal_comments: String::new(),
+      extra_globals: Vec::new(),
+      vertices,
+      surfaces,
+      textures: Vec::new(),
+      materials: self.materials,
+      bones,
+      morphs: Vec::new(),
+      display_frames: vec![
+        frame("Root", "Root", roots),
+        frame("表情", "Exp", Vec::new()),
+      ],
+      rigid_bodies: Vec::new(),
+      joints: Vec::new(),
+      soft_bodies: Vec::new(),
+      skipped_sections: BitFlags::empty(),
+    };
+    pmx.settings = pmx.shrunk_settings();
+    Ok(pmx)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  fn quad() -> PmxBuilder {
+    let mut builder = PmxBuilder::new("quad");
+    let root = builder.add_bone("センター", [0.0; 3], None);
+    builder.add_bone("上半身", [0.0, 1.0, 0.0], Some(root));
+    let normal = [0.0, 0.0, -1.0];
+    let a = builder.add_vertex([0.0, 0.0, 0.0], normal, [0.0, 1.0], root);
+    let b = builder.add_vertex([1.0, 0.0, 0.0], normal, [1.0, 1.0], root);
+    let c = builder.add_vertex([0.0, 1.0, 0.0], normal, [0.0, 0.0], root);
+    let d = builder.add_vertex([1.0, 1.0, 0.0], normal, [1.0, 0.0], root);
+    builder.add_triangle(a, c, b);
+    builder.add_material("lower", [1.0; 4]);
+    builder.add_triangle(b, c, d);
+    builder
+      .add_material("upper", [0.5, 0.5, 0.5, 1.0])
+      .edge_scale = 0.5;
+    builder
+  }
+
+  #[test]
+  fn test_build() {
+    let pmx = quad().build().unwrap();
+    assert!(pmx.validate().is_valid());
+    assert_eq!(pmx.surfaces, [[0, 2, 1], [1, 2, 3]]);
+    assert_eq!(pmx.materials[1].surface_count, 3);
+    assert_eq!(pmx.materials[1].edge_scale, 0.5);
+    assert_eq!(pmx.bones[1].parent, Some(0));
+    assert_eq!(pmx.settings.bone_index_size, IndexSize::I8);
+    assert_eq!(pmx.display_frames[0].elements, [DisplayElement::Bone(0)]);
+
+    let mut bytes = Vec::new();
+    pmx.write(&mut bytes).unwrap();
+    assert_eq!(Pmx::read(Cursor::new(&bytes)).unwrap(), pmx);
+  }
+
+  #[test]
+  fn test_build_errors() {
+    let mut builder = quad();
+    let v = VertexHandle(0);
+    builder.add_triangle(v, v, v);
+    assert!(matches!(
+      builder.build(),
+      Err(Error::SurfaceCountMismatch {
+        materials: 6,
+        surfaces: 9
+      })
+    ));
+
+    let mut builder = quad();
+    builder.add_vertex([0.0; 3], [0.0; 3], [0.0; 2], BoneHandle(2));
+    assert!(matches!(
+      builder.build(),
+      Err(Error::InvalidHandle {
+        kind: "bone",
+        index: 2
+      })
+    ));
+
+    let mut builder = quad();
+    builder.add_triangle(v, v, VertexHandle(4));
+    builder.add_material("broken", [1.0; 4]);
+    assert!(matches!(
+      builder.build(),
+      Err(Error::InvalidHandle {
+        kind: "vertex",
+        index: 4
+      })
+    ));
+  }
+}
@@ -1,50 +1,65 @@
-use crate::Config;
+#[cfg(feature = "serde")]
+use crate::SerdeConfig;
+use crate::{Config, LocalizedName};
+use alloc::{string::ToString, vec::Vec};
+use core::fmt::{Debug, Display, Formatter};
 use itertools::Itertools;
-use std::fmt::{Debug, Display, Formatter};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub enum Frame<C: Config> {
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
+pub enum DisplayElement<C: Config> {
   Bone(C::BoneIndex),
   Morph(C::MorphIndex),
 }
 
-impl<C: Config> Display for Frame<C>
+impl<C: Config> Display for DisplayElement<C>
 where
   C::BoneIndex: Display,
   C::MorphIndex: Display,
 {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     match self {
-      Frame::Bone(id) => write!(f, "bone {}", id),
-      Frame::Morph(id) => write!(f, "morph {}", id),
+      DisplayElement::Bone(id) => write!(f, "bone {}", id),
+      DisplayElement::Morph(id) => write!(f, "morph {}", id),
     }
   }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct DisplayFrame<C: Config> {
-  pub local_name: String,
-  pub universal_name: String,
+  pub name: LocalizedName,
   pub special_flag: bool,
-  pub frames: Vec<Frame<C>>,
+  pub elements: Vec<DisplayElement<C>>,
 }
 
 impl<C: Config> Display for DisplayFrame<C>
 where
-  Frame<C>: Display,
+  DisplayElement<C>: Display,
 {
-  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), core::fmt::Error> {
     write!(
       f,
       r"local name: {}, universal name: {},
-flag: {}, frames: {}",
-      self.local_name,
-      self.universal_name,
+flag: {}, elements: {}",
+      self.name.ja,
+      self.name.en,
       if self.special_flag {
         "special"
       } else {
         "normal"
       },
-      self.frames.iter().map(ToString::to_string).join(", "),
+      self.elements.iter().map(ToString::to_string).join(", "),
     )
   }
 }
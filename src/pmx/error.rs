@@ -1,42 +1,316 @@
+#![allow(non_local_definitions)]
+
+use alloc::{borrow::Cow, boxed::Box, string::String, vec::Vec};
+use core::fmt::{Display, Formatter};
 use err_derive::Error;
-use std::borrow::Cow;
 
+/// The errors of reading, writing and processing the formats.
+///
+/// New variants may be added for new failure modes, so matches need a wildcard arm outside of
+/// this crate.
 #[derive(Debug, Error)]
+#[non_exhaustive]
 pub enum Error {
   #[error(display = "Invalid header")]
   InvalidHeader,
+  #[error(display = "Unknown file format starting with {:x?}", _0)]
+  UnknownFormat(Vec<u8>),
   #[error(display = "{}", _0)]
-  Io(#[error(source)] std::io::Error),
+  Io(#[error(source)] crate::io::Error),
   #[error(display = "Wrong signature {:?}", _0)]
   WrongSignature([u8; 4]),
   #[error(display = "Globals count less than 8 {}", _0)]
   GlobalsCountLessThan8(u8),
+  #[error(
+    display = "Invalid additional vec4 count {}, at most 4 are allowed",
+    _0
+  )]
+  InvalidAdditionalVec4Count(u8),
   #[error(display = "Unknown index size {}", _0)]
   UnknownIndexSize(u8),
   #[error(display = "Unknown text encoding {}", _0)]
   UnknownTextEncoding(u8),
+  #[error(display = "Reader used after an earlier read failed")]
+  Poisoned,
+  #[error(display = "Invalid text length {}", _0)]
+  InvalidTextLength(i32),
+  #[error(display = "Truncated text, read {} of {} bytes", read, expected)]
+  TruncatedText { expected: usize, read: usize },
   #[error(display = "Decode text {}", _0)]
   DecodeText(Cow<'static, str>),
-  #[error(display = "Unknown weigh type {}", _0)]
+  #[error(display = "Unknown weight type {}", _0)]
   UnknownWeightType(u8),
   #[error(display = "Index overflow {}", _0)]
   IndexOverflow(i64),
   #[error(display = "Invalid environment blendMode {}", _0)]
   InvalidEnvironmentBlendMode(u8),
+  #[error(
+    display = "Invalid environment blend mode {} of material {}",
+    mode,
+    material
+  )]
+  InvalidMaterialBlendMode { material: String, mode: u8 },
   #[error(display = "Invalid toon reference {}", _0)]
   InvalidToonReference(u8),
-  #[error(display = "Invalid morph type {}", _0)]
-  InvalidMorphType(u8),
+  #[error(display = "Invalid type {} of morph {}", kind, morph)]
+  InvalidMorphType { kind: u8, morph: i32 },
   #[error(display = "Invalid material offset method {}", _0)]
   InvalidMaterialOffsetMethod(u8),
-  #[error(display = "Invalid display frame type {}", _0)]
-  InvalidFrameType(u8),
+  #[error(display = "Invalid element type {} in display frame {}", kind, frame)]
+  InvalidFrameType { kind: u8, frame: i32 },
   #[error(display = "Invalid rigid body shape type {}", _0)]
   InvalidShapeType(u8),
   #[error(display = "Invalid rigid body physics mode {}", _0)]
   InvalidPhysicsMode(u8),
   #[error(display = "Invalid joint type {}", _0)]
   InvalidJointType(u8),
+  #[error(display = "Invalid soft body shape {}", _0)]
+  InvalidSoftBodyShape(u8),
+  #[error(display = "Invalid soft body flags 0b{:b}", _0)]
+  InvalidSoftBodyFlags(u8),
+  #[error(display = "Invalid soft body aero model {}", _0)]
+  InvalidAeroModel(i32),
+  #[error(
+    display = "Truncated {} section, read {} of {} frames",
+    section,
+    read,
+    expected
+  )]
+  TruncatedSection {
+    section: &'static str,
+    expected: u32,
+    read: u32,
+  },
+  #[error(display = "Malformed name {:x?}", raw)]
+  DecodeName { raw: Vec<u8> },
+  #[error(display = "Frame {} overflows with offset {}", frame_no, offset)]
+  FrameOverflow { frame_no: u32, offset: u32 },
+  #[error(
+    display = "Frame {} is out of range when retimed by {}",
+    frame_no,
+    ratio
+  )]
+  RetimeOverflow { frame_no: u32, ratio: f32 },
+  #[error(display = "Both motions key {:?} at frame {}", name, frame_no)]
+  MergeCollision { name: String, frame_no: u32 },
+  #[error(
+    display = "Rotation of {:?} at frame {} has norm {}",
+    name,
+    frame_no,
+    norm
+  )]
+  InvalidRotation {
+    name: String,
+    frame_no: u32,
+    norm: f32,
+  },
+  #[error(display = "{} bytes left after the last section", bytes)]
+  TrailingBytes { bytes: usize },
+  #[error(display = "Line {}: {}", line, message)]
+  VpdParse { line: usize, message: String },
+  #[error(display = "{:?} can't be encoded in Shift_JIS", name)]
+  EncodeName { name: String },
+  #[error(display = "Declared {} bones, found {}", declared, found)]
+  CountMismatch { declared: u32, found: u32 },
+  #[error(
+    display = "Flag {:?} of bone {:?} doesn't match its fields",
+    flag,
+    name
+  )]
+  BoneFlagMismatch {
+    name: String,
+    flag: crate::pmx::bone::BoneFlags,
+  },
+  #[error(
+    display = "PMX {} has no soft body section, found {} soft bodies",
+    version,
+    count
+  )]
+  UnexpectedSoftBodies { version: f32, count: usize },
+  #[error(display = "Texture path {:?} leaves the model directory", _0)]
+  TexturePathTraversal(String),
+  #[error(display = "The {} section was already read", _0)]
+  SectionAlreadyRead(&'static str),
+  #[error(display = "Surface count {} is not a multiple of 3", _0)]
+  InvalidSurfaceCount(i32),
+  #[error(
+    display = "Surfaces of material {} end at {}, past the {} surfaces",
+    material,
+    end,
+    count
+  )]
+  MaterialSurfacesOutOfRange {
+    material: usize,
+    end: usize,
+    count: usize,
+  },
+  #[error(display = "Bone {} is its own ancestor", _0)]
+  BoneParentCycle(usize),
+  #[error(display = "Parent {} of bone {} is out of range", parent, bone)]
+  BoneParentOutOfRange { bone: usize, parent: i64 },
+  #[error(display = "IK of bone {} refers to missing bone {}", bone, index)]
+  IkBoneOutOfRange { bone: usize, index: i64 },
+  #[error(display = "There's no bone named {:?}", _0)]
+  BoneNotFound(String),
+  #[error(
+    display = "Bone {} appends the transform of missing bone {}",
+    bone,
+    parent
+  )]
+  AppendBoneOutOfRange { bone: usize, parent: i64 },
+  #[error(
+    display = "Joint {:?} connects missing rigid body {}",
+    joint,
+    rigid_body
+  )]
+  JointBodyOutOfRange { joint: String, rigid_body: i64 },
+  #[error(display = "Joints of type {} can't be converted", _0)]
+  UnsupportedJointType(crate::pmx::joint::JointType),
+  #[error(display = "There's no morph {}", _0)]
+  MorphOutOfRange(usize),
+  #[error(display = "Morph {:?} offsets missing vertex {}", morph, vertex)]
+  MorphVertexOutOfRange { morph: String, vertex: i64 },
+  #[error(display = "Group morph {:?} refers to missing morph {}", morph, target)]
+  GroupMorphTargetOutOfRange { morph: String, target: i64 },
+  #[error(display = "Group morphs {:?} refer to each other in a cycle", _0)]
+  GroupMorphCycle(Vec<String>),
+  #[error(
+    display = "Group morph {:?} is nested more than {} levels deep",
+    morph,
+    depth
+  )]
+  GroupMorphTooDeep { morph: String, depth: usize },
+  #[error(
+    display = "The materials draw {} surfaces of the {} surfaces",
+    materials,
+    surfaces
+  )]
+  SurfaceCountMismatch { materials: u64, surfaces: usize },
+  #[error(
+    display = "Unexpected end of input, needed {} bytes but {} are left",
+    needed,
+    available
+  )]
+  UnexpectedEnd { needed: usize, available: usize },
+  #[error(display = "Reading was cancelled")]
+  Cancelled,
+  #[error(display = "The sections {:?} were skipped while reading", _0)]
+  SkippedSections(enumflags2::BitFlags<crate::pmx::validate::Section>),
+  #[error(display = "The builder has no {} {}", kind, index)]
+  InvalidHandle { kind: &'static str, index: usize },
+  #[error(display = "{} at {}", source, location)]
+  Context {
+    location: ErrorLocation,
+    #[error(source)]
+    source: Box<Error>,
+  },
+}
+
+/// Where in a PMX file reading failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorLocation {
+  /// The bytes read from the start of the file, the field that failed ends at or before it.
+  pub offset: u64,
+  /// The section being read like `"bone"`, or `"header"`.
+  pub section: &'static str,
+  /// The element in the section, `None` for the header and the counts of the sections.
+  pub index: Option<usize>,
+}
+
+impl Display for ErrorLocation {
+  fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+    write!(f, "byte {} in the {} section", self.offset, self.section)?;
+    match self.index {
+      Some(index) => write!(f, ", element {}", index),
+      None => Ok(()),
+    }
+  }
+}
+
+impl Error {
+  /// The error inside any `Error::Context`.
+  pub fn root(&self) -> &Error {
+    match self {
+      Error::Context { source, .. } => source.root(),
+      e => e,
+    }
+  }
+
+  /// Where reading failed, for errors of the PMX readers.
+  pub fn location(&self) -> Option<&ErrorLocation> {
+    match self {
+      Error::Context { location, .. } => Some(location),
+      _ => None,
+    }
+  }
+
+  /// Wraps the error in `Error::Context`, unless it has a location already or is `Poisoned`,
+  /// which is about an earlier error.
+  pub(crate) fn context(self, offset: u64, section: &'static str, index: Option<usize>) -> Error {
+    match self {
+      Error::Context { .. } | Error::Poisoned => self,
+      e => Error::Context {
+        location: ErrorLocation {
+          offset,
+          section,
+          index,
+        },
+        source: Box::new(e),
+      },
+    }
+  }
 }
 
-pub type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[cfg(feature = "std")]
+  use std::error::Error as _;
+
+  #[test]
+  fn test_display() {
+    let e = Error::TruncatedSection {
+      section: "bone",
+      expected: 10,
+      read: 4,
+    };
+    assert_eq!(e.to_string(), "Truncated bone section, read 4 of 10 frames");
+    assert_eq!(
+      Error::InvalidTextLength(-2).to_string(),
+      "Invalid text length -2"
+    );
+    let e = Error::VpdParse {
+      line: 3,
+      message: "expected `;`".to_string(),
+    };
+    assert_eq!(e.to_string(), "Line 3: expected `;`");
+    assert_eq!(
+      Error::IndexOverflow(300)
+        .context(1234, "vertex", Some(7))
+        .to_string(),
+      "Index overflow 300 at byte 1234 in the vertex section, element 7"
+    );
+    assert_eq!(
+      Error::InvalidHeader.context(0, "header", None).to_string(),
+      "Invalid header at byte 0 in the header section"
+    );
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn test_source() {
+    let io = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "eof");
+    let e = Error::Io(io).context(16, "texture", Some(1));
+    // The error at the location, then the `std::io::Error` inside it
+    let source = e.source().unwrap();
+    assert_eq!(source.to_string(), "eof");
+    let io = source.source().unwrap().downcast_ref::<std::io::Error>();
+    assert_eq!(io.unwrap().kind(), std::io::ErrorKind::UnexpectedEof);
+    assert!(Error::Cancelled.source().is_none());
+
+    let boxed: Box<dyn std::error::Error + Send + Sync> = Box::new(Error::Cancelled);
+    assert_eq!(boxed.to_string(), "Reading was cancelled");
+  }
+}
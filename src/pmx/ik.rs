@@ -0,0 +1,345 @@
+//! CCD IK of the IK bones, run from the hook of `Pose::evaluate_with`.
+//!
+//! Every iteration rotates the links from the target towards the root so the target moves onto
+//! the IK bone, by at most the unit angle of the chain. Links limited around a single axis, like
+//! knees, are turned in their plane only.
+
+use crate::{
+  math::{
+    cross3, dot3, normalize3, quat_mul, rigid_inverse, rotate3, rotation_translation, sub3,
+    to_array, Widen,
+  },
+  pmx::{
+    pose::{Pose, IDENTITY_ROTATION},
+    skeleton::{IkChain, Matrix4},
+  },
+  Config,
+};
+
+/// Rotations below this, in radians, are left out.
+const MIN_ANGLE: f32 = 1e-5;
+
+fn position(m: &Matrix4) -> [f32; 3] {
+  [m[3][0], m[3][1], m[3][2]]
+}
+
+fn transform_point(m: &Matrix4, p: [f32; 3]) -> [f32; 3] {
+  let mut out = [0.0; 3];
+  for (row, out) in out.iter_mut().enumerate() {
+    *out = m[0][row] * p[0] + m[1][row] * p[1] + m[2][row] * p[2] + m[3][row];
+  }
+  out
+}
+
+/// `v` scaled to unit length, `None` if it's too short to have a direction.
+fn unit(v: [f32; 3]) -> Option<[f32; 3]> {
+  if dot3(v, v) < 1e-12 {
+    None
+  } else {
+    Some(normalize3(v))
+  }
+}
+
+fn axis_angle(axis: [f32; 3], angle: f32) -> [f32; 4] {
+  let (sin, cos) = (angle * 0.5).sin_cos();
+  [axis[0] * sin, axis[1] * sin, axis[2] * sin, cos]
+}
+
+fn conjugate([x, y, z, w]: [f32; 4]) -> [f32; 4] {
+  [-x, -y, -z, w]
+}
+
+/// Euler angles of a rotation around X, then Y, then Z.
+fn euler_xyz(q: [f32; 4]) -> [f32; 3] {
+  let m = rotation_translation(q, [0.0; 3]);
+  let sin_y = (-m[0][2]).clamp(-1.0, 1.0);
+  if sin_y.abs() < 0.99999 {
+    [m[1][2].atan2(m[2][2]), sin_y.asin(), m[0][1].atan2(m[0][0])]
+  } else {
+    // Gimbal lock, the rotation around X is folded into Z
+    [0.0, sin_y.asin(), (-m[1][0]).atan2(m[1][1])]
+  }
+}
+
+fn quat_from_euler_xyz([x, y, z]: [f32; 3]) -> [f32; 4] {
+  quat_mul(
+    quat_mul(
+      axis_angle([0.0, 0.0, 1.0], z),
+      axis_angle([0.0, 1.0, 0.0], y),
+    ),
+    axis_angle([1.0, 0.0, 0.0], x),
+  )
+}
+
+/// Clamps without panicking on limits that are the wrong way around.
+fn clamp(v: f32, lower: f32, upper: f32) -> f32 {
+  v.max(lower).min(upper)
+}
+
+/// The axis of limits that only allow rotating around one axis.
+fn single_axis(lower: [f32; 3], upper: [f32; 3]) -> Option<usize> {
+  let limited = |i: usize| lower[i] != 0.0 || upper[i] != 0.0;
+  match (limited(0), limited(1), limited(2)) {
+    (true, false, false) => Some(0),
+    (false, true, false) => Some(1),
+    (false, false, true) => Some(2),
+    _ => None,
+  }
+}
+
+/// Solves the IK of `chain` on `pose`, whose bones up to the IK bone must be evaluated.
+///
+/// The IK rotations of the links are replaced and the links and the bones below them updated.
+/// Returns the IK rotations of the links in their order. The iterations stop early once the
+/// target stops getting closer, the closest solution is kept.
+///
+/// # Panics
+///
+/// If the bones of `chain` aren't in `pose`.
+pub fn solve<C: Config>(pose: &mut Pose<'_, C>, chain: &IkChain<C>) -> Vec<C::Vec4> {
+  let root = match chain.links.last() {
+    Some(root) => root.bone,
+    None => return Vec::new(),
+  };
+  for link in &chain.links {
+    pose.ik[link.bone] = IDENTITY_ROTATION;
+  }
+  pose.update_subtree(root);
+
+  let mut plane_angles = vec![0.0; chain.links.len()];
+  let mut best = f32::INFINITY;
+  let mut saved = vec![IDENTITY_ROTATION; chain.links.len()];
+  for iteration in 0..chain.iterations {
+    iterate(pose, chain, iteration, &mut plane_angles);
+    let distance = sub3(
+      position(&pose.world[chain.target]),
+      position(&pose.world[chain.bone]),
+    );
+    let distance = dot3(distance, distance);
+    if distance < best {
+      best = distance;
+      for (saved, link) in saved.iter_mut().zip(&chain.links) {
+        *saved = pose.ik[link.bone];
+      }
+    } else {
+      for (saved, link) in saved.iter().zip(&chain.links) {
+        pose.ik[link.bone] = *saved;
+      }
+      pose.update_subtree(root);
+      break;
+    }
+  }
+
+  chain
+    .links
+    .iter()
+    .map(|l| pose.ik[l.bone].widen())
+    .collect()
+}
+
+/// A hook for `Pose::evaluate_with` solving `chains` as their IK bones are reached, e.g. those
+/// of `BoneHierarchy::ik_chains`.
+pub fn hook<'c, 'a, C: Config>(
+  chains: &'c [IkChain<C>],
+) -> impl FnMut(&mut Pose<'a, C>, usize) + 'c {
+  move |pose, bone| {
+    for chain in chains.iter().filter(|c| c.bone == bone) {
+      solve(pose, chain);
+    }
+  }
+}
+
+fn iterate<C: Config>(
+  pose: &mut Pose<'_, C>,
+  chain: &IkChain<C>,
+  iteration: u32,
+  plane_angles: &mut [f32],
+) {
+  let goal = position(&pose.world[chain.bone]);
+  for (i, link) in chain.links.iter().enumerate() {
+    if link.bone == chain.target {
+      continue;
+    }
+    // Both directions in the space of the link, a target or goal on the link has none
+    let inverse = rigid_inverse(&pose.world[link.bone]);
+    let to_goal = unit(transform_point(&inverse, goal));
+    let to_target = unit(transform_point(
+      &inverse,
+      position(&pose.world[chain.target]),
+    ));
+    let (to_goal, to_target) = match (to_goal, to_target) {
+      (Some(g), Some(t)) => (g, t),
+      _ => continue,
+    };
+    let angle = dot3(to_target, to_goal)
+      .clamp(-1.0, 1.0)
+      .acos()
+      .min(chain.limit_angle);
+    let limits = link
+      .limits
+      .as_ref()
+      .map(|l| (to_array::<3>(&l.lower), to_array::<3>(&l.upper)));
+    let input = pose.input[link.bone].1;
+
+    let plane = limits.and_then(|(lower, upper)| {
+      let axis = single_axis(lower, upper)?;
+      Some((axis, lower, upper))
+    });
+    let rotation = match plane {
+      // Turned even when aligned already, so the limits hold from the first iteration
+      Some((axis, lower, upper)) => {
+        let mut unit_axis = [0.0; 3];
+        unit_axis[axis] = 1.0;
+        let towards = |angle: f32| {
+          let turned = rotate3(axis_angle(unit_axis, angle), to_target);
+          dot3(turned, to_goal)
+        };
+        let step = if towards(angle) > towards(-angle) {
+          angle
+        } else {
+          -angle
+        };
+        let mut turned = plane_angles[i] + step;
+        // The first iteration may pick the other way around if only that one is in the limits
+        if iteration == 0 && (turned < lower[axis] || turned > upper[axis]) {
+          let half = (lower[axis] + upper[axis]) * 0.5;
+          if (-turned > lower[axis] && -turned < upper[axis])
+            || (half - turned).abs() > (half + turned).abs()
+          {
+            turned = -turned;
+          }
+        }
+        let turned = clamp(turned, lower[axis], upper[axis]);
+        plane_angles[i] = turned;
+        axis_angle(unit_axis, turned)
+      }
+      None => {
+        if angle < MIN_ANGLE {
+          continue;
+        }
+        let axis = match unit(cross3(to_target, to_goal)) {
+          Some(axis) => axis,
+          // Pointing straight away, any axis would do and none is picked
+          None => continue,
+        };
+        let current = quat_mul(pose.ik[link.bone], input);
+        let rotation = quat_mul(current, axis_angle(axis, angle));
+        match limits {
+          Some((lower, upper)) => {
+            let euler = euler_xyz(rotation);
+            quat_from_euler_xyz([
+              clamp(euler[0], lower[0], upper[0]),
+              clamp(euler[1], lower[1], upper[1]),
+              clamp(euler[2], lower[2], upper[2]),
+            ])
+          }
+          None => rotation,
+        }
+      }
+    };
+    pose.ik[link.bone] = quat_mul(rotation, conjugate(input));
+    pose.update_subtree(link.bone);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::pmx::bone::tests::bone;
+  use crate::pmx::bone::{AngleLimits, BoneFlags, IKLink, InverseKinematics};
+  use crate::pmx::pose::PhysicsPass;
+  use crate::pmx::skeleton::BoneHierarchy;
+  use crate::{Bone, DefaultConfig};
+
+  const KNEE_UPPER: f32 = -0.008_726_646;
+
+  /// A leg of two links 5 long down from the hip at 10 and an IK bone for the ankle.
+  fn leg() -> Vec<Bone<DefaultConfig>> {
+    let mut ik = bone("", None, [0.0; 3]);
+    ik.bone_flags |= BoneFlags::InverseKinematics;
+    ik.inverse_kinematics = Some(InverseKinematics {
+      ik_bone: 2,
+      iterations: 40,
+      limit_angle: 2.0,
+      links: vec![
+        IKLink {
+          ik_bone: 1,
+          limits: Some(AngleLimits {
+            lower: [-std::f32::consts::PI, 0.0, 0.0].into(),
+            upper: [KNEE_UPPER, 0.0, 0.0].into(),
+          }),
+        },
+        IKLink {
+          ik_bone: 0,
+          limits: None,
+        },
+      ],
+    });
+    vec![
+      bone("", None, [0.0, 10.0, 0.0]),
+      bone("", Some(0), [0.0, 5.0, 0.0]),
+      bone("", Some(1), [0.0, 0.0, 0.0]),
+      ik,
+    ]
+  }
+
+  /// Poses the leg with the IK bone moved to `goal` and returns the ankle.
+  fn reach(bones: &[Bone<DefaultConfig>], goal: [f32; 3]) -> (Pose<'_>, [f32; 3]) {
+    let chains = BoneHierarchy::new(bones).unwrap().ik_chains().unwrap();
+    let mut pose = Pose::new(bones).unwrap();
+    let mut locals = vec![None; bones.len()];
+    locals[3] = Some(crate::pmx::pose::LocalTransform {
+      translation: goal.into(),
+      rotation: IDENTITY_ROTATION.into(),
+    });
+    pose.set_locals(&locals);
+    pose.evaluate_with(PhysicsPass::Before, hook(&chains));
+    let ankle = to_array::<3>(&pose.position(2));
+    (pose, ankle)
+  }
+
+  fn knee_angle(pose: &Pose) -> f32 {
+    let q = to_array::<4>(&pose.ik_rotation(1));
+    2.0 * q[0].atan2(q[3])
+  }
+
+  #[test]
+  fn test_reachable_target() {
+    let bones = leg();
+    let goal = [0.0, 3.0, 1.0];
+    let (pose, ankle) = reach(&bones, goal);
+    let miss = sub3(ankle, goal);
+    assert!(dot3(miss, miss).sqrt() < 0.01, "{:?}", ankle);
+    let knee = knee_angle(&pose);
+    assert!((-std::f32::consts::PI..=KNEE_UPPER).contains(&knee));
+
+    // The same inputs solve the same way
+    let (_, again) = reach(&bones, goal);
+    assert_eq!(again, ankle);
+  }
+
+  #[test]
+  fn test_unreachable_target() {
+    let bones = leg();
+    let (pose, ankle) = reach(&bones, [0.0, -5.0, 0.0]);
+    // The knee can't straighten fully, so the ankle stays just short of the stretched leg
+    let knee = knee_angle(&pose);
+    assert!(knee <= KNEE_UPPER + 1e-6, "{}", knee);
+    let miss = sub3(ankle, [0.0, -5.0, 0.0]);
+    assert!((dot3(miss, miss).sqrt() - 5.0).abs() < 0.01);
+  }
+
+  #[test]
+  fn test_goal_on_joint() {
+    let bones = leg();
+    for goal in [[0.0, 5.0, 0.0], [0.0, 10.0, 0.0]] {
+      let (pose, _) = reach(&bones, goal);
+      assert!(pose
+        .worlds()
+        .iter()
+        .flatten()
+        .flatten()
+        .all(|c| c.is_finite()));
+    }
+  }
+}
@@ -0,0 +1,147 @@
+//! Lookup of bones, morphs and materials by name.
+
+use std::collections::HashMap;
+
+use crate::vpd::duplicated;
+use crate::{Config, LocalizedName, Pmx};
+
+/// Which names a `PmxIndex` finds elements by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum IndexedNames {
+  #[default]
+  Japanese,
+  /// The English names too, unless they are empty. A Japanese name always wins over the same
+  /// English name of another element.
+  JapaneseAndEnglish,
+}
+
+/// Bones, morphs and materials of a model by name, see `Pmx::index`.
+///
+/// It borrows the model, so it can't go stale and doesn't take part in comparing or writing it.
+#[derive(Debug, Clone)]
+pub struct PmxIndex<'a> {
+  bones: HashMap<&'a str, usize>,
+  morphs: HashMap<&'a str, usize>,
+  materials: HashMap<&'a str, usize>,
+}
+
+impl PmxIndex<'_> {
+  pub fn bone_index_by_name(&self, name: &str) -> Option<usize> {
+    self.bones.get(name).copied()
+  }
+
+  pub fn morph_index_by_name(&self, name: &str) -> Option<usize> {
+    self.morphs.get(name).copied()
+  }
+
+  pub fn material_index_by_name(&self, name: &str) -> Option<usize> {
+    self.materials.get(name).copied()
+  }
+}
+
+fn index_names<'a>(
+  names: impl Iterator<Item = &'a LocalizedName> + Clone,
+  indexed: IndexedNames,
+) -> HashMap<&'a str, usize> {
+  let mut map = HashMap::new();
+  for (i, name) in names.clone().enumerate() {
+    map.entry(name.ja.as_str()).or_insert(i);
+  }
+  if indexed == IndexedNames::JapaneseAndEnglish {
+    for (i, name) in names.enumerate().filter(|(_, n)| !n.en.is_empty()) {
+      map.entry(name.en.as_str()).or_insert(i);
+    }
+  }
+
+  map
+}
+
+impl<C: Config> Pmx<C> {
+  /// Builds an index for repeated lookups. Of duplicated names the first one is found.
+  pub fn index(&self, indexed: IndexedNames) -> PmxIndex<'_> {
+    PmxIndex {
+      bones: index_names(self.bones.iter().map(|b| &b.name), indexed),
+      morphs: index_names(self.morphs.iter().map(|m| &m.name), indexed),
+      materials: index_names(self.materials.iter().map(|m| &m.name), indexed),
+    }
+  }
+
+  /// The first bone with the Japanese name `name`, see `index` for many lookups.
+  pub fn bone_index_by_name(&self, name: &str) -> Option<usize> {
+    self.bones.iter().position(|b| b.name.ja == name)
+  }
+
+  /// The first morph with the Japanese name `name`, see `index` for many lookups.
+  pub fn morph_index_by_name(&self, name: &str) -> Option<usize> {
+    self.morphs.iter().position(|m| m.name.ja == name)
+  }
+
+  /// The first material with the Japanese name `name`, see `index` for many lookups.
+  pub fn material_index_by_name(&self, name: &str) -> Option<usize> {
+    self.materials.iter().position(|m| m.name.ja == name)
+  }
+
+  /// Japanese bone names used more than once, which lookups only find the first of.
+  pub fn duplicate_bones(&self) -> Vec<&str> {
+    duplicated(self.bones.iter().map(|b| b.name.ja.as_str()))
+  }
+
+  /// Japanese morph names used more than once, which lookups only find the first of.
+  pub fn duplicate_morphs(&self) -> Vec<&str> {
+    duplicated(self.morphs.iter().map(|m| m.name.ja.as_str()))
+  }
+
+  /// Japanese material names used more than once, which lookups only find the first of.
+  pub fn duplicate_materials(&self) -> Vec<&str> {
+    duplicated(self.materials.iter().map(|m| m.name.ja.as_str()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FIXTURE_MODEL_PMX: &[u8] = include_bytes!("../../fixtures/model.pmx");
+
+  #[test]
+  fn test_lookup_fixture() {
+    let pmx = Pmx::<crate::DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    let morph = pmx.morphs[1].name.ja.as_str();
+
+    assert_eq!(pmx.bone_index_by_name("左ひざ"), Some(2));
+    assert_eq!(pmx.bone_index_by_name("center"), None);
+    assert_eq!(pmx.morph_index_by_name(morph), Some(1));
+    assert_eq!(pmx.material_index_by_name("材質2"), Some(1));
+
+    let japanese = pmx.index(IndexedNames::Japanese);
+    assert_eq!(japanese.bone_index_by_name("左足ＩＫ"), Some(4));
+    assert_eq!(japanese.bone_index_by_name("center"), None);
+    assert_eq!(japanese.morph_index_by_name(morph), Some(1));
+    assert_eq!(japanese.material_index_by_name("材質1"), Some(0));
+    assert_eq!(japanese.material_index_by_name("missing"), None);
+
+    let both = pmx.index(IndexedNames::JapaneseAndEnglish);
+    assert_eq!(both.bone_index_by_name("center"), Some(0));
+    assert_eq!(both.bone_index_by_name("センター"), Some(0));
+  }
+
+  #[test]
+  fn test_duplicates() {
+    let mut pmx = Pmx::<crate::DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    assert!(pmx.duplicate_bones().is_empty());
+    assert!(pmx.duplicate_materials().is_empty());
+
+    let copy = pmx.bones[2].clone();
+    pmx.bones.push(copy);
+    // An English name that is the Japanese name of another bone
+    pmx.bones[5].name.en = "左ひざ".to_string();
+    pmx.materials[1].name.ja = "材質1".to_string();
+
+    assert_eq!(pmx.duplicate_bones(), ["左ひざ"]);
+    assert_eq!(pmx.duplicate_materials(), ["材質1"]);
+    assert_eq!(pmx.bone_index_by_name("左ひざ"), Some(2));
+    let index = pmx.index(IndexedNames::JapaneseAndEnglish);
+    assert_eq!(index.bone_index_by_name("左ひざ"), Some(2));
+    assert_eq!(index.material_index_by_name("材質1"), Some(0));
+  }
+}
@@ -1,8 +1,13 @@
-use crate::{Config, Error};
-use std::convert::TryFrom;
-use std::fmt::{Debug, Display, Formatter};
+#[cfg(feature = "serde")]
+use crate::SerdeConfig;
+use crate::{Config, Error, LocalizedName};
+use core::convert::TryFrom;
+use core::fmt::{Debug, Display, Formatter};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum JointType {
   SpringFree = 0,
@@ -14,7 +19,7 @@ pub enum JointType {
 }
 
 impl Display for JointType {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     match self {
       JointType::SpringFree => write!(f, "free (spring)"),
       JointType::Free => write!(f, "free"),
@@ -42,9 +47,14 @@ impl TryFrom<u8> for JointType {
   }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct Joint<C: Config> {
-  pub local_name: String,
-  pub universal_name: String,
+  pub name: LocalizedName,
   pub joint_type: JointType,
   pub rigid_body_a: C::RigidbodyIndex,
   pub rigid_body_b: C::RigidbodyIndex,
@@ -62,7 +72,7 @@ impl<C: Config> Display for Joint<C>
 where
   C::RigidbodyIndex: Display,
 {
-  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), core::fmt::Error> {
     write!(
       f,
       r"local name: {}, universal name: {},
@@ -71,8 +81,8 @@ position: {:?}, rotation: {:?},
 position min: {:?}, position max: {:?},
 rotation min: {:?}, rotation max: {:?},
 position spring: {:?}, rotation spring: {:?}",
-      self.local_name,
-      self.universal_name,
+      self.name.ja,
+      self.name.en,
       self.joint_type,
       self.rigid_body_a,
       self.rigid_body_b,
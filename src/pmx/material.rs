@@ -1,8 +1,13 @@
-use crate::{Config, Error};
+#[cfg(feature = "serde")]
+use crate::SerdeConfig;
+use crate::{display::DisplayOption, Config, Error, LocalizedName};
+use alloc::{format, string::String};
+use core::convert::TryFrom;
+use core::fmt::{Debug, Display, Formatter};
 use enumflags2::{bitflags, BitFlags};
 use itertools::Itertools;
-use std::convert::TryFrom;
-use std::fmt::{Debug, Display, Formatter};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[bitflags]
 #[derive(Copy, Clone, PartialEq, Debug)]
@@ -18,15 +23,104 @@ pub enum DrawingFlags {
   LineDrawing = 0b10000000,
 }
 
-struct DrawingFlagsFmt(BitFlags<DrawingFlags>);
+/// The drawing flag byte of a material.
+///
+/// The byte is kept as read, so bits without a `DrawingFlags` variant are written back too.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct MaterialFlags(u8);
 
-impl Display for DrawingFlagsFmt {
-  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
-    write!(f, "{}", self.0.iter().map(|v| format!("{:?}", v)).join("|"))
+impl MaterialFlags {
+  pub fn from_bits(bits: u8) -> Self {
+    MaterialFlags(bits)
+  }
+
+  /// The known flags.
+  pub fn bits(self) -> BitFlags<DrawingFlags> {
+    BitFlags::from_bits_truncate(self.0)
+  }
+
+  /// The whole byte, including unknown bits.
+  pub fn raw(self) -> u8 {
+    self.0
+  }
+
+  pub fn contains(self, flag: DrawingFlags) -> bool {
+    self.bits().contains(flag)
+  }
+
+  pub fn set(&mut self, flag: DrawingFlags, value: bool) {
+    if value {
+      self.0 |= flag as u8;
+    } else {
+      self.0 &= !(flag as u8);
+    }
+  }
+
+  /// Draws both sides of the faces.
+  pub fn no_cull(self) -> bool {
+    self.contains(DrawingFlags::NoCull)
+  }
+
+  pub fn ground_shadow(self) -> bool {
+    self.contains(DrawingFlags::GroundShadow)
+  }
+
+  /// Casts a self shadow.
+  pub fn draw_shadow(self) -> bool {
+    self.contains(DrawingFlags::DrawShadow)
+  }
+
+  pub fn receive_shadow(self) -> bool {
+    self.contains(DrawingFlags::ReceiveShadow)
+  }
+
+  /// Draws the edge (toon outline).
+  pub fn has_edge(self) -> bool {
+    self.contains(DrawingFlags::HasEdge)
+  }
+
+  /// Uses the first additional vec4 as vertex color, PMX 2.1 only.
+  pub fn vertex_color(self) -> bool {
+    self.contains(DrawingFlags::VertexColor)
+  }
+
+  /// Draws the vertices as points, PMX 2.1 only.
+  pub fn point_drawing(self) -> bool {
+    self.contains(DrawingFlags::PointDrawing)
+  }
+
+  /// Draws the edges of the faces as lines, PMX 2.1 only.
+  pub fn line_drawing(self) -> bool {
+    self.contains(DrawingFlags::LineDrawing)
+  }
+}
+
+impl From<BitFlags<DrawingFlags>> for MaterialFlags {
+  fn from(flags: BitFlags<DrawingFlags>) -> Self {
+    MaterialFlags(flags.bits())
+  }
+}
+
+impl From<DrawingFlags> for MaterialFlags {
+  fn from(flag: DrawingFlags) -> Self {
+    MaterialFlags(flag as u8)
+  }
+}
+
+impl Display for MaterialFlags {
+  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), core::fmt::Error> {
+    write!(
+      f,
+      "{}",
+      self.bits().iter().map(|v| format!("{:?}", v)).join("|")
+    )
   }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum EnvironmentBlendMode {
   Disabled = 0,
@@ -36,7 +130,7 @@ pub enum EnvironmentBlendMode {
 }
 
 impl Display for EnvironmentBlendMode {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     match self {
       EnvironmentBlendMode::Disabled => write!(f, "disabled"),
       EnvironmentBlendMode::Multiply => write!(f, "*"),
@@ -60,36 +154,63 @@ impl TryFrom<u8> for EnvironmentBlendMode {
   }
 }
 
+/// The number of shared toons of MMD, `toon01.bmp` to `toon10.bmp`.
+pub const SHARED_TOON_COUNT: u8 = 10;
+
+/// The toon texture of a material, chosen by the shared toon flag byte in the file.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub enum Toon<C: Config> {
-  Texture(C::TextureIndex),
+  /// `None` for no toon texture.
+  Texture(Option<C::TextureIndex>),
+  /// One of the shared toons of MMD, 0 for `toon01.bmp`. Values past `SHARED_TOON_COUNT` are
+  /// kept and reported by `Pmx::validate`.
   Internal(u8),
 }
 
+impl<C: Config> Toon<C> {
+  /// The file name of a shared toon, `None` for textures and unknown shared toons.
+  pub fn shared_file_name(&self) -> Option<String> {
+    match self {
+      Toon::Internal(i) if *i < SHARED_TOON_COUNT => Some(format!("toon{:02}.bmp", i + 1)),
+      _ => None,
+    }
+  }
+}
+
 impl<C: Config> Display for Toon<C>
 where
   C::TextureIndex: Display,
 {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     match self {
-      Toon::Texture(t) => write!(f, "texture({})", t),
+      Toon::Texture(t) => write!(f, "texture({})", DisplayOption::new(t)),
       Toon::Internal(i) => write!(f, "internal({})", i),
     }
   }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct Material<C: Config> {
-  pub local_name: String,
-  pub universal_name: String,
+  pub name: LocalizedName,
   pub diffuse_color: C::Vec4,
   pub specular_color: C::Vec3,
   pub specular_strength: f32,
   pub ambient_color: C::Vec3,
-  pub draw_flags: BitFlags<DrawingFlags>,
+  pub draw_flags: MaterialFlags,
   pub edge_color: C::Vec4,
   pub edge_scale: f32,
-  pub texture_index: C::TextureIndex,
-  pub environment_index: C::TextureIndex,
+  pub texture_index: Option<C::TextureIndex>,
+  pub environment_index: Option<C::TextureIndex>,
   pub environment_blend_mode: EnvironmentBlendMode,
   pub toon: Toon<C>,
   pub metadata: String,
@@ -102,24 +223,24 @@ where
   C::Vec4: Display,
   C::TextureIndex: Display,
 {
-  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), core::fmt::Error> {
     write!(
       f,
       r"local name: {}, universal name: {}
 diffuse: {}, specular: {}/{}, ambient: {}, flags: {}
 edge: {}/{}, texture: {}, environment: {}/{},
 toon: {}, metadata: {}, surfaces: {}",
-      self.local_name,
-      self.universal_name,
+      self.name.ja,
+      self.name.en,
       self.diffuse_color,
       self.specular_color,
       self.specular_strength,
       self.ambient_color,
-      DrawingFlagsFmt(self.draw_flags),
+      self.draw_flags,
       self.edge_color,
       self.edge_scale,
-      self.texture_index,
-      self.environment_index,
+      DisplayOption::new(&self.texture_index),
+      DisplayOption::new(&self.environment_index),
       self.environment_blend_mode,
       self.toon,
       self.metadata,
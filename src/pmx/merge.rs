@@ -0,0 +1,548 @@
+//! Appending one model to another, e.g. an accessory to a character, see `Pmx::merge`.
+
+use crate::math::Widen;
+use crate::pmx::bone::Connection;
+use crate::pmx::builder::index;
+use crate::pmx::display::DisplayElement;
+use crate::pmx::material::Toon;
+use crate::pmx::morph::Offsets;
+use crate::{Config, Error, IndexSize, Pmx, Result, Vertex, WeightDeform};
+use std::collections::{HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
+
+/// Options of `Pmx::merge`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MergeOptions<'a> {
+  /// The bone of the base model, by its Japanese name, the root bones of the other model are
+  /// attached to. They stay roots if `None`.
+  pub attach_to: Option<&'a str>,
+  /// Bones and morphs of the other model whose Japanese names the base model uses already get
+  /// this and the first free number from 2 appended, e.g. `頭_2`. `_` by default.
+  pub rename_suffix: &'a str,
+}
+
+impl Default for MergeOptions<'_> {
+  fn default() -> Self {
+    MergeOptions {
+      attach_to: None,
+      rename_suffix: "_",
+    }
+  }
+}
+
+/// Where the elements of a list of the other model end up: after the `offset` elements of the
+/// base model, `len` of them.
+#[derive(Clone, Copy)]
+struct Shift {
+  offset: usize,
+  len: usize,
+}
+
+/// `index` moved past the elements before its list, `Error::IndexOverflow` if it's past the end
+/// of its own. Negative ones like -1 for none stay as they are.
+fn shift<I: TryFrom<i32> + TryInto<i64> + Clone>(index: &I, list: Shift) -> Result<I> {
+  let i = index.clone().try_into().unwrap_or(i64::MAX);
+  if i < 0 {
+    return Ok(index.clone());
+  }
+  if i >= list.len as i64 {
+    return Err(Error::IndexOverflow(i));
+  }
+  let shifted = i + list.offset as i64;
+  i32::try_from(shifted)
+    .ok()
+    .and_then(|i| I::try_from(i).ok())
+    .ok_or(Error::IndexOverflow(shifted))
+}
+
+fn shift_all<'i, I: TryFrom<i32> + TryInto<i64> + Clone + 'i>(
+  indices: impl IntoIterator<Item = &'i mut I>,
+  list: Shift,
+) -> Result<()> {
+  for index in indices {
+    *index = shift(index, list)?;
+  }
+  Ok(())
+}
+
+/// Texture paths compared case-insensitively with either separator, like Windows does.
+fn normalize_texture(path: &str) -> String {
+  let path = path.replace('\\', "/").to_lowercase();
+  let mut path = path.as_str();
+  while let Some(rest) = path.strip_prefix("./") {
+    path = rest;
+  }
+  path.to_string()
+}
+
+/// The names of `names` with those in `base` renamed after `suffix`, see
+/// `MergeOptions::rename_suffix`.
+fn rename<'n>(
+  base: impl Iterator<Item = &'n str>,
+  names: &[&'n str],
+  suffix: &str,
+) -> Vec<Option<String>> {
+  let base: HashSet<&str> = base.collect();
+  let mut taken: HashSet<String> = base.iter().chain(names).map(|n| n.to_string()).collect();
+  names
+    .iter()
+    .map(|name| {
+      if !base.contains(name) {
+        return None;
+      }
+      let renamed = (2..)
+        .map(|n| format!("{}{}{}", name, suffix, n))
+        .find(|n| !taken.contains(n))
+        .unwrap();
+      taken.insert(renamed.clone());
+      Some(renamed)
+    })
+    .collect()
+}
+
+fn wider(a: IndexSize, b: IndexSize) -> IndexSize {
+  if b as u8 > a as u8 {
+    b
+  } else {
+    a
+  }
+}
+
+impl<C: Config + Clone> Pmx<C> {
+  /// Appends the elements of `other` to this model, moving its indices past the elements of this
+  /// one. Textures with the same path are shared.
+  ///
+  /// The index sizes of `settings` are widened where the lists outgrew them and the vertices of
+  /// both models padded to the larger number of additional vec4s. Fails with
+  /// `Error::BoneNotFound` for a missing `attach_to` bone, with `Error::IndexOverflow` for
+  /// indices of `other` past its own lists and with `Error::SkippedSections` if either model
+  /// was read without some sections. The model is left as it was on errors.
+  pub fn merge(&mut self, other: &Pmx<C>, options: &MergeOptions) -> Result<()> {
+    let skipped = self.skipped_sections | other.skipped_sections;
+    if !skipped.is_empty() {
+      return Err(Error::SkippedSections(skipped));
+    }
+    let attach_to = match options.attach_to {
+      Some(name) => Some(
+        self
+          .bone_index_by_name(name)
+          .ok_or_else(|| Error::BoneNotFound(name.to_string()))?,
+      ),
+      None => None,
+    };
+
+    let vertex_shift = Shift {
+      offset: self.vertices.len(),
+      len: other.vertices.len(),
+    };
+    let material_shift = Shift {
+      offset: self.materials.len(),
+      len: other.materials.len(),
+    };
+    let bone_shift = Shift {
+      offset: self.bones.len(),
+      len: other.bones.len(),
+    };
+    let morph_shift = Shift {
+      offset: self.morphs.len(),
+      len: other.morphs.len(),
+    };
+    let rigid_body_shift = Shift {
+      offset: self.rigid_bodies.len(),
+      len: other.rigid_bodies.len(),
+    };
+
+    let mut textures = self.textures.clone();
+    let mut known: HashMap<String, usize> = HashMap::new();
+    for (i, t) in textures.iter().enumerate().rev() {
+      known.insert(normalize_texture(t), i);
+    }
+    let texture_table: Vec<usize> = other
+      .textures
+      .iter()
+      .map(|t| {
+        *known.entry(normalize_texture(t)).or_insert_with(|| {
+          textures.push(t.clone());
+          textures.len() - 1
+        })
+      })
+      .collect();
+    let texture = |texture: &Option<C::TextureIndex>| -> Result<Option<C::TextureIndex>> {
+      let texture = match texture {
+        Some(texture) => texture,
+        None => return Ok(None),
+      };
+      let i: i64 = texture.clone().try_into().unwrap_or(i64::MAX);
+      let merged = usize::try_from(i)
+        .ok()
+        .and_then(|i| texture_table.get(i))
+        .ok_or(Error::IndexOverflow(i))?;
+      index(*merged).map(Some)
+    };
+
+    let additional_count = self
+      .settings
+      .additional_vec4_count
+      .max(other.settings.additional_vec4_count);
+    let pad = |v: &Vertex<C>| -> C::AdditionalVec4s {
+      let existing = v.additional.as_ref();
+      existing
+        .iter()
+        .cloned()
+        .chain((existing.len()..additional_count as usize).map(|_| [0.0; 4].widen()))
+        .collect()
+    };
+
+    let mut vertices = Vec::with_capacity(other.vertices.len());
+    for v in &other.vertices {
+      let mut v = v.clone();
+      v.additional = pad(&v);
+      match &mut v.weight_deform {
+        WeightDeform::Bdef1(w) => w.bone_index = shift(&w.bone_index, bone_shift)?,
+        WeightDeform::Bdef2(w) => {
+          shift_all([&mut w.bone_1_index, &mut w.bone_2_index], bone_shift)?
+        }
+        WeightDeform::Bdef4(w) => shift_all(
+          [
+            &mut w.bone_1_index,
+            &mut w.bone_2_index,
+            &mut w.bone_3_index,
+            &mut w.bone_4_index,
+          ],
+          bone_shift,
+        )?,
+        WeightDeform::Sdef(w) => shift_all([&mut w.bone_1_index, &mut w.bone_2_index], bone_shift)?,
+        WeightDeform::Qdef(w) => shift_all(
+          [
+            &mut w.bone_1_index,
+            &mut w.bone_2_index,
+            &mut w.bone_3_index,
+            &mut w.bone_4_index,
+          ],
+          bone_shift,
+        )?,
+      }
+      vertices.push(v);
+    }
+
+    let surfaces = other
+      .surfaces
+      .iter()
+      .map(|s| {
+        let mut s = s.clone();
+        shift_all(&mut s, vertex_shift)?;
+        Ok(s)
+      })
+      .collect::<Result<Vec<_>>>()?;
+
+    let materials = other
+      .materials
+      .iter()
+      .map(|m| {
+        let mut m = m.clone();
+        m.texture_index = texture(&m.texture_index)?;
+        m.environment_index = texture(&m.environment_index)?;
+        if let Toon::Texture(t) = &m.toon {
+          m.toon = Toon::Texture(texture(t)?);
+        }
+        Ok(m)
+      })
+      .collect::<Result<Vec<_>>>()?;
+
+    let bone_names: Vec<&str> = other.bones.iter().map(|b| b.name.ja.as_str()).collect();
+    let bone_renames = rename(
+      self.bones.iter().map(|b| b.name.ja.as_str()),
+      &bone_names,
+      options.rename_suffix,
+    );
+    let mut bones = Vec::with_capacity(other.bones.len());
+    for (b, renamed) in other.bones.iter().zip(bone_renames) {
+      let mut b = b.clone();
+      if let Some(renamed) = renamed {
+        b.name.ja = renamed;
+      }
+      b.parent = match &b.parent {
+        Some(parent) => Some(shift(parent, bone_shift)?),
+        None => attach_to.map(index).transpose()?,
+      };
+      if let Connection::Index(Some(tail)) = &mut b.connection {
+        *tail = shift(tail, bone_shift)?;
+      }
+      if let Some(additional) = &mut b.additional {
+        additional.parent = shift(&additional.parent, bone_shift)?;
+      }
+      if let Some(ik) = &mut b.inverse_kinematics {
+        ik.ik_bone = shift(&ik.ik_bone, bone_shift)?;
+        shift_all(ik.links.iter_mut().map(|l| &mut l.ik_bone), bone_shift)?;
+      }
+      bones.push(b);
+    }
+
+    let morph_names: Vec<&str> = other.morphs.iter().map(|m| m.name.ja.as_str()).collect();
+    let morph_renames = rename(
+      self.morphs.iter().map(|m| m.name.ja.as_str()),
+      &morph_names,
+      options.rename_suffix,
+    );
+    let mut morphs = Vec::with_capacity(other.morphs.len());
+    for (m, renamed) in other.morphs.iter().zip(morph_renames) {
+      let mut m = m.clone();
+      if let Some(renamed) = renamed {
+        m.name.ja = renamed;
+      }
+      match &mut m.offsets {
+        Offsets::Group(o) | Offsets::Flip(o) => {
+          shift_all(o.iter_mut().map(|o| &mut o.morph), morph_shift)?
+        }
+        Offsets::Vertex(o) => shift_all(o.iter_mut().map(|o| &mut o.vertex), vertex_shift)?,
+        Offsets::Bone(o) => shift_all(o.iter_mut().map(|o| &mut o.bone), bone_shift)?,
+        Offsets::UV(o)
+        | Offsets::AdditionalUV1(o)
+        | Offsets::AdditionalUV2(o)
+        | Offsets::AdditionalUV3(o)
+        | Offsets::AdditionalUV4(o) => {
+          shift_all(o.iter_mut().map(|o| &mut o.vertex), vertex_shift)?
+        }
+        // `None` stays all materials, which now includes those of this model
+        Offsets::Material(o) => shift_all(
+          o.iter_mut().filter_map(|o| o.material.as_mut()),
+          material_shift,
+        )?,
+        Offsets::Impulse(o) => {
+          shift_all(o.iter_mut().map(|o| &mut o.rigid_body), rigid_body_shift)?
+        }
+      }
+      morphs.push(m);
+    }
+
+    let mut display_frames = self.display_frames.clone();
+    for frame in &other.display_frames {
+      let mut frame = frame.clone();
+      for element in &mut frame.elements {
+        match element {
+          DisplayElement::Bone(b) => *b = shift(b, bone_shift)?,
+          DisplayElement::Morph(m) => *m = shift(m, morph_shift)?,
+        }
+      }
+      // The special frames, `Root` and `表情`, are shared
+      let same = display_frames
+        .iter_mut()
+        .find(|f| f.special_flag && frame.special_flag && f.name.ja == frame.name.ja);
+      match same {
+        Some(same) => same.elements.extend(frame.elements),
+        None => display_frames.push(frame),
+      }
+    }
+
+    let rigid_bodies = other
+      .rigid_bodies
+      .iter()
+      .map(|r| {
+        let mut r = r.clone();
+        if let Some(bone) = &mut r.bone_index {
+          *bone = shift(bone, bone_shift)?;
+        }
+        Ok(r)
+      })
+      .collect::<Result<Vec<_>>>()?;
+
+    let joints = other
+      .joints
+      .iter()
+      .map(|j| {
+        let mut j = j.clone();
+        shift_all([&mut j.rigid_body_a, &mut j.rigid_body_b], rigid_body_shift)?;
+        Ok(j)
+      })
+      .collect::<Result<Vec<_>>>()?;
+
+    let soft_bodies = other
+      .soft_bodies
+      .iter()
+      .map(|s| {
+        let mut s = s.clone();
+        s.material = shift(&s.material, material_shift)?;
+        for anchor in &mut s.anchors {
+          anchor.rigid_body = shift(&anchor.rigid_body, rigid_body_shift)?;
+          anchor.vertex = shift(&anchor.vertex, vertex_shift)?;
+        }
+        shift_all(&mut s.pinned_vertices, vertex_shift)?;
+        Ok(s)
+      })
+      .collect::<Result<Vec<_>>>()?;
+
+    for v in &mut self.vertices {
+      v.additional = pad(v);
+    }
+    self.vertices.extend(vertices);
+    self.surfaces.extend(surfaces);
+    self.textures = textures;
+    self.materials.extend(materials);
+    self.bones.extend(bones);
+    self.morphs.extend(morphs);
+    self.display_frames = display_frames;
+    self.rigid_bodies.extend(rigid_bodies);
+    self.joints.extend(joints);
+    if !soft_bodies.is_empty() {
+      self.version = self.version.max(2.1);
+    }
+    self.soft_bodies.extend(soft_bodies);
+
+    let shrunk = self.shrunk_settings();
+    let s = &mut self.settings;
+    s.additional_vec4_count = additional_count;
+    s.vertex_index_size = wider(s.vertex_index_size, shrunk.vertex_index_size);
+    s.texture_index_size = wider(s.texture_index_size, shrunk.texture_index_size);
+    s.material_index_size = wider(s.material_index_size, shrunk.material_index_size);
+    s.bone_index_size = wider(s.bone_index_size, shrunk.bone_index_size);
+    s.morph_index_size = wider(s.morph_index_size, shrunk.morph_index_size);
+    s.rigidbody_index_size = wider(s.rigidbody_index_size, shrunk.rigidbody_index_size);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::pmx::morph::{Morph, Panel, VertexOffset};
+  use crate::{LocalizedName, PmxBuilder};
+  use std::io::Cursor;
+
+  fn morph(name: &str, vertex: i32) -> Morph<crate::DefaultConfig> {
+    Morph {
+      name: LocalizedName::new(name, ""),
+      panel: Panel::Other,
+      offsets: Offsets::Vertex(vec![VertexOffset {
+        vertex,
+        offset: [0.0, 0.1, 0.0].into(),
+      }]),
+    }
+  }
+
+  fn base() -> Pmx {
+    let mut builder = PmxBuilder::new("base");
+    let center = builder.add_bone("センター", [0.0; 3], None);
+    let head = builder.add_bone("頭", [0.0, 1.0, 0.0], Some(center));
+    let normal = [0.0, 0.0, -1.0];
+    let a = builder.add_vertex([0.0; 3], normal, [0.0; 2], center);
+    let b = builder.add_vertex([1.0, 0.0, 0.0], normal, [1.0, 0.0], center);
+    let c = builder.add_vertex([0.0, 1.0, 0.0], normal, [0.0, 1.0], head);
+    builder.add_triangle(a, c, b);
+    builder.add_material("体", [1.0; 4]).texture_index = Some(0);
+    let mut pmx = builder.build().unwrap();
+    pmx.textures = vec!["tex\\a.png".to_string()];
+    pmx.morphs.push(morph("あ", 2));
+    pmx
+  }
+
+  fn accessory() -> Pmx {
+    let mut builder = PmxBuilder::new("accessory");
+    let head = builder.add_bone("頭", [0.0, 1.0, 0.0], None);
+    let ribbon = builder.add_bone("リボン", [0.0, 1.5, 0.0], Some(head));
+    let normal = [0.0, 0.0, -1.0];
+    let a = builder.add_vertex([0.0, 1.0, 0.0], normal, [0.0; 2], head);
+    let b = builder.add_vertex([1.0, 1.0, 0.0], normal, [1.0, 0.0], ribbon);
+    let c = builder.add_vertex([0.0, 2.0, 0.0], normal, [0.0, 1.0], ribbon);
+    let d = builder.add_vertex([1.0, 2.0, 0.0], normal, [1.0, 1.0], ribbon);
+    builder.add_triangle(a, c, b).add_triangle(b, c, d);
+    let material = builder.add_material("リボン", [1.0, 0.0, 0.0, 1.0]);
+    material.texture_index = Some(1);
+    material.environment_index = Some(0);
+    let mut pmx = builder.build().unwrap();
+    pmx.textures = vec!["b.png".to_string(), "./Tex/A.png".to_string()];
+    pmx.morphs.push(morph("あ", 3));
+    pmx
+  }
+
+  #[test]
+  fn test_merge() {
+    let mut pmx = base();
+    let other = accessory();
+    let options = MergeOptions {
+      attach_to: Some("頭"),
+      ..Default::default()
+    };
+    pmx.merge(&other, &options).unwrap();
+    assert!(pmx.validate().is_valid());
+
+    // The faces of the accessory follow the 3 vertices of the base
+    assert_eq!(pmx.surfaces, [[0, 2, 1], [3, 5, 4], [4, 5, 6]]);
+    assert_eq!(pmx.vertices[4].position, other.vertices[1].position);
+    assert!(matches!(
+      &pmx.vertices[4].weight_deform,
+      WeightDeform::Bdef1(w) if w.bone_index == 3
+    ));
+
+    assert_eq!(pmx.textures, ["tex\\a.png", "b.png"]);
+    assert_eq!(pmx.materials[1].texture_index, Some(0));
+    assert_eq!(pmx.materials[1].environment_index, Some(1));
+
+    let names: Vec<_> = pmx.bones.iter().map(|b| b.name.ja.as_str()).collect();
+    assert_eq!(names, ["センター", "頭", "頭_2", "リボン"]);
+    assert_eq!(pmx.bones[2].parent, Some(1));
+    assert_eq!(pmx.bones[3].parent, Some(2));
+
+    assert_eq!(pmx.morphs[1].name.ja, "あ_2");
+    assert!(matches!(
+      &pmx.morphs[1].offsets,
+      Offsets::Vertex(o) if o[0].vertex == 6
+    ));
+    // The root bones of both models share the `Root` frame
+    assert_eq!(pmx.display_frames.len(), 2);
+    assert_eq!(
+      pmx.display_frames[0].elements,
+      [DisplayElement::Bone(0), DisplayElement::Bone(2)]
+    );
+
+    let mut bytes = Vec::new();
+    pmx.write(&mut bytes).unwrap();
+    assert_eq!(Pmx::read(Cursor::new(&bytes)).unwrap(), pmx);
+  }
+
+  #[test]
+  fn test_merge_widens_indices() {
+    let mut builder = PmxBuilder::new("bones");
+    for i in 0..100 {
+      builder.add_bone(format!("骨{}", i), [0.0; 3], None);
+    }
+    let other = builder.build().unwrap();
+    let mut pmx = base();
+    assert_eq!(pmx.settings.bone_index_size, IndexSize::I8);
+    pmx.merge(&other, &MergeOptions::default()).unwrap();
+    assert_eq!(pmx.bones.len(), 102);
+    assert_eq!(pmx.settings.bone_index_size, IndexSize::I8);
+    pmx.merge(&other, &MergeOptions::default()).unwrap();
+    assert_eq!(pmx.settings.bone_index_size, IndexSize::I16);
+    assert_eq!(pmx.bones[102].name.ja, "骨0_2");
+    assert!(pmx.validate().is_valid());
+  }
+
+  #[test]
+  fn test_merge_errors() {
+    let mut pmx = base();
+    let options = MergeOptions {
+      attach_to: Some("首"),
+      ..Default::default()
+    };
+    assert!(matches!(
+      pmx.merge(&accessory(), &options),
+      Err(Error::BoneNotFound(name)) if name == "首"
+    ));
+
+    let mut other = accessory();
+    other.materials[0].texture_index = Some(5);
+    assert!(matches!(
+      pmx.merge(&other, &MergeOptions::default()),
+      Err(Error::IndexOverflow(5))
+    ));
+
+    // One past the bones of the accessory, which would be a bone of the merged model
+    let mut other = accessory();
+    other.bones[1].parent = Some(2);
+    assert!(matches!(
+      pmx.merge(&other, &MergeOptions::default()),
+      Err(Error::IndexOverflow(2))
+    ));
+    assert_eq!(pmx, base());
+  }
+}
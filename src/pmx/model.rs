@@ -0,0 +1,611 @@
+use crate::io::Read;
+use crate::pmx::display::DisplayFrame;
+use crate::pmx::joint::Joint;
+use crate::pmx::morph::Morph;
+use crate::pmx::rigid_body::RigidBody;
+use crate::pmx::soft_body::SoftBody;
+use crate::pmx::validate::Section;
+use crate::reader::slice::SliceSection;
+use crate::reader::*;
+#[cfg(feature = "serde")]
+use crate::SerdeConfig;
+use crate::{Bone, Config, DefaultConfig, Error, Material, MaybeSend, Result, Settings, Vertex};
+use alloc::{string::String, vec::Vec};
+use core::convert::TryFrom;
+use core::mem::take;
+use core::ops::{ControlFlow, Range};
+use enumflags2::BitFlags;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// The run of the face indices a material draws, `Pmx::surfaces` flattened.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MaterialRange {
+  pub start: usize,
+  /// A multiple of 3.
+  pub count: usize,
+}
+
+impl MaterialRange {
+  pub fn indices(&self) -> Range<usize> {
+    self.start..self.start + self.count
+  }
+}
+
+/// A whole model, read section by section with the readers in `pmx::reader`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
+pub struct Pmx<C: Config = DefaultConfig> {
+  pub version: f32,
+  pub settings: Settings,
+  pub model_local_name: String,
+  pub model_universal_name: String,
+  pub local_comments: String,
+  pub universal_comments: String,
+  /// Globals after the 8 known ones, written back as they are.
+  pub extra_globals: Vec<u8>,
+  pub vertices: Vec<Vertex<C>>,
+  /// Triangles of vertex indices, the file stores their number times 3.
+  pub surfaces: Vec<[C::VertexIndex; 3]>,
+  pub textures: Vec<String>,
+  pub materials: Vec<Material<C>>,
+  pub bones: Vec<Bone<C>>,
+  pub morphs: Vec<Morph<C>>,
+  pub display_frames: Vec<DisplayFrame<C>>,
+  pub rigid_bodies: Vec<RigidBody<C>>,
+  pub joints: Vec<Joint<C>>,
+  /// Always empty for PMX 2.0, only 2.1 files have the section.
+  pub soft_bodies: Vec<SoftBody<C>>,
+  /// The sections left out with `PmxReadOptions::skip`, which are empty whatever the file holds.
+  /// Models with skipped sections can't be written.
+  pub skipped_sections: BitFlags<Section>,
+}
+
+/// The elements reserved upfront for a section, larger sections grow as they're read so a
+/// corrupt count can't trigger a huge allocation.
+const MAX_RESERVED: usize = 1 << 16;
+
+/// Options of `Pmx::read_with` and `Pmx::parse_with`.
+pub struct PmxReadOptions<'a> {
+  /// Called with a section, the number of its elements read and its number of elements when the
+  /// section starts and ends, and every `progress_interval` vertices, triangles and morphs.
+  /// Returning `ControlFlow::Break` stops reading with `Error::Cancelled`.
+  pub on_progress: Option<&'a mut dyn FnMut(Section, usize, usize) -> ControlFlow<()>>,
+  /// 0 to only report the start and end of the sections, 4096 by default.
+  pub progress_interval: usize,
+  /// With the `rayon` feature, the vertices, surfaces and morphs of `Pmx::parse` that take at
+  /// least this many bytes are decoded on the rayon thread pool. The result is the same, but
+  /// progress is only reported at the start and end of those sections. They're decoded
+  /// sequentially if the pool has a single thread, unless this is 0. 256 KiB by default.
+  #[cfg(feature = "rayon")]
+  pub parallel_min_bytes: usize,
+  /// Sections moved past without keeping them, recorded in `Pmx::skipped_sections`. Vertices,
+  /// surfaces, textures and morphs are skipped without decoding them, the other sections are
+  /// still read and dropped. No sections by default.
+  pub skip: BitFlags<Section>,
+}
+
+impl Default for PmxReadOptions<'_> {
+  fn default() -> Self {
+    PmxReadOptions {
+      on_progress: None,
+      progress_interval: 4096,
+      skip: BitFlags::empty(),
+      #[cfg(feature = "rayon")]
+      parallel_min_bytes: 1 << 18,
+    }
+  }
+}
+
+impl PmxReadOptions<'_> {
+  fn progress(&mut self, section: Section, current: usize, total: usize) -> Result<()> {
+    if let Some(on_progress) = &mut self.on_progress {
+      if on_progress(section, current, total).is_break() {
+        return Err(Error::Cancelled);
+      }
+    }
+    Ok(())
+  }
+
+  /// Reads the `total` elements of `section` with `next`, reporting every `progress_interval`
+  /// of them if `in_between`.
+  fn collect<T>(
+    &mut self,
+    section: Section,
+    total: i32,
+    in_between: bool,
+    next: impl FnMut() -> Result<Option<T>>,
+  ) -> Result<Vec<T>> {
+    let total = total.max(0) as usize;
+    self.progress(section, 0, total)?;
+    self.collect_rest(section, total, in_between, next)
+  }
+
+  /// `collect` once the start of the section was reported.
+  fn collect_rest<T>(
+    &mut self,
+    section: Section,
+    total: usize,
+    in_between: bool,
+    mut next: impl FnMut() -> Result<Option<T>>,
+  ) -> Result<Vec<T>> {
+    let interval = match self.on_progress {
+      Some(_) if in_between => self.progress_interval,
+      _ => 0,
+    };
+    let mut list = Vec::with_capacity(total.min(MAX_RESERVED));
+    while let Some(element) = next()? {
+      list.push(element);
+      if interval != 0 && list.len() % interval == 0 && list.len() < total {
+        self.progress(section, list.len(), total)?;
+      }
+    }
+    self.progress(section, list.len(), total)?;
+    Ok(list)
+  }
+
+  /// `collect` for the sections of `Pmx::parse`, in parallel if they are large enough.
+  fn collect_slice<C: Config, S: SliceSection<C>>(
+    &mut self,
+    reader: &mut S,
+  ) -> Result<Vec<S::Item>> {
+    let total = reader.total().max(0) as usize;
+    self.progress(S::SECTION, 0, total)?;
+    #[cfg(feature = "rayon")]
+    {
+      if let Some(list) = reader.read_parallel(self.parallel_min_bytes) {
+        self.progress(S::SECTION, list.len(), total)?;
+        return Ok(list);
+      }
+    }
+    self.collect_rest(S::SECTION, total, true, || reader.next_slice())
+  }
+}
+
+impl<C: Config> Pmx<C> {
+  pub fn read<R: Read>(read: R) -> Result<Self> {
+    Self::read_with(read, &mut PmxReadOptions::default())
+  }
+
+  /// `read` with progress reports.
+  pub fn read_with<R: Read>(read: R, options: &mut PmxReadOptions) -> Result<Self> {
+    Self::read_sections(
+      HeaderReader::new(read)?,
+      options,
+      |v, options| options.collect(Section::Vertices, v.count, true, || v.next::<C>()),
+      |s, options| options.collect(Section::Surfaces, s.count / 3, true, || s.next::<C>()),
+      |m, options| options.collect(Section::Morphs, m.count, true, || m.next::<C>()),
+    )
+  }
+
+  /// Reads a model that is in memory as a whole, decoding the vertices and surfaces straight
+  /// from `bytes`. The result is the same as with `read`.
+  ///
+  /// With the `rayon` feature large sections are decoded in parallel, see
+  /// `PmxReadOptions::parallel_min_bytes`.
+  pub fn parse(bytes: &[u8]) -> Result<Self>
+  where
+    Vertex<C>: MaybeSend,
+    C::VertexIndex: MaybeSend,
+    Morph<C>: MaybeSend,
+  {
+    Self::parse_with(bytes, &mut PmxReadOptions::default())
+  }
+
+  /// `parse` with progress reports.
+  pub fn parse_with(bytes: &[u8], options: &mut PmxReadOptions) -> Result<Self>
+  where
+    Vertex<C>: MaybeSend,
+    C::VertexIndex: MaybeSend,
+    Morph<C>: MaybeSend,
+  {
+    Self::read_sections(
+      HeaderReader::new(SliceReader::new(bytes))?,
+      options,
+      |v, options| options.collect_slice::<C, _>(v),
+      |s, options| options.collect_slice::<C, _>(s),
+      |m, options| options.collect_slice::<C, _>(m),
+    )
+  }
+
+  fn read_sections<R: Read>(
+    mut header: HeaderReader<R>,
+    options: &mut PmxReadOptions,
+    read_vertices: impl FnOnce(&mut VertexReader<R>, &mut PmxReadOptions) -> Result<Vec<Vertex<C>>>,
+    read_surfaces: impl FnOnce(
+      &mut SurfaceReader<R>,
+      &mut PmxReadOptions,
+    ) -> Result<Vec<[C::VertexIndex; 3]>>,
+    read_morphs: impl FnOnce(&mut MorphReader<R>, &mut PmxReadOptions) -> Result<Vec<Morph<C>>>,
+  ) -> Result<Self> {
+    let version = header.version;
+    let settings = header.settings;
+    let model_local_name = take(&mut header.model_local_name);
+    let model_universal_name = take(&mut header.model_universal_name);
+    let local_comments = take(&mut header.local_comments);
+    let universal_comments = take(&mut header.universal_comments);
+    let extra_globals = take(&mut header.extra_globals);
+
+    let skip = options.skip;
+    // Empties the section if it's skipped, kept apart from an empty one by `skipped_sections`
+    macro_rules! section {
+      ($section:expr, $skip:expr, $read:expr) => {
+        if skip.contains($section) {
+          $skip;
+          Vec::new()
+        } else {
+          $read
+        }
+      };
+    }
+
+    let mut v = VertexReader::new(header)?;
+    let vertices = section!(
+      Section::Vertices,
+      v.skip()?,
+      read_vertices(&mut v, options)?
+    );
+    let mut s = SurfaceReader::new(v)?;
+    let surfaces = section!(
+      Section::Surfaces,
+      s.skip()?,
+      read_surfaces(&mut s, options)?
+    );
+    let mut t = TextureReader::new(s)?;
+    let textures = section!(
+      Section::Textures,
+      t.skip()?,
+      options.collect(Section::Textures, t.count, false, || t.next())?
+    );
+    let mut m = MaterialReader::new(t)?;
+    let materials = section!(
+      Section::Materials,
+      while m.next::<DefaultConfig>()?.is_some() {},
+      options.collect(Section::Materials, m.count, false, || m.next::<C>())?
+    );
+    let mut b = BoneReader::new(m)?;
+    let bones = section!(
+      Section::Bones,
+      while b.next::<DefaultConfig>()?.is_some() {},
+      options.collect(Section::Bones, b.count, false, || b.next::<C>())?
+    );
+    let mut m = MorphReader::new(b)?;
+    let morphs = section!(Section::Morphs, m.skip()?, read_morphs(&mut m, options)?);
+    let mut d = DisplayReader::new(m)?;
+    let display_frames = section!(
+      Section::DisplayFrames,
+      while d.next::<DefaultConfig>()?.is_some() {},
+      options.collect(Section::DisplayFrames, d.count, false, || d.next::<C>())?
+    );
+    let mut r = RigidBodyReader::new(d)?;
+    let rigid_bodies = section!(
+      Section::RigidBodies,
+      while r.next::<DefaultConfig>()?.is_some() {},
+      options.collect(Section::RigidBodies, r.count, false, || r.next::<C>())?
+    );
+    let mut j = JointReader::new(r)?;
+    let joints = section!(
+      Section::Joints,
+      while j.next::<DefaultConfig>()?.is_some() {},
+      options.collect(Section::Joints, j.count, false, || j.next::<C>())?
+    );
+    let soft_bodies = if has_soft_bodies(version) {
+      let mut s = SoftBodyReader::new(j)?;
+      section!(
+        Section::SoftBodies,
+        while s.next::<DefaultConfig>()?.is_some() {},
+        options.collect(Section::SoftBodies, s.count, false, || s.next::<C>())?
+      )
+    } else {
+      Vec::new()
+    };
+
+    Ok(Pmx {
+      version,
+      settings,
+      model_local_name,
+      model_universal_name,
+      local_comments,
+      universal_comments,
+      extra_globals,
+      vertices,
+      surfaces,
+      textures,
+      materials,
+      bones,
+      morphs,
+      display_frames,
+      rigid_bodies,
+      joints,
+      soft_bodies,
+      skipped_sections: skip,
+    })
+  }
+
+  /// The triangles drawn with `material`, which follow those of the materials before it.
+  ///
+  /// # Panics
+  ///
+  /// If there's no material at `material`.
+  pub fn material_surfaces(&self, material: usize) -> Result<&[[C::VertexIndex; 3]]> {
+    let mut range = 0..0;
+    for (i, m) in self.materials[..=material].iter().enumerate() {
+      let count = usize::try_from(m.surface_count)
+        .ok()
+        .filter(|count| count % 3 == 0)
+        .ok_or(Error::InvalidSurfaceCount(m.surface_count))?;
+      range = range.end..range.end + count / 3;
+      if range.end > self.surfaces.len() {
+        return Err(Error::MaterialSurfacesOutOfRange {
+          material: i,
+          end: range.end * 3,
+          count: self.surfaces.len() * 3,
+        });
+      }
+    }
+    Ok(&self.surfaces[range])
+  }
+
+  /// The face indices of every material, checked to be whole triangles and to cover all
+  /// surfaces exactly.
+  pub fn material_ranges(&self) -> Result<Vec<MaterialRange>> {
+    let mut start = 0;
+    let mut ranges = Vec::with_capacity(self.materials.len());
+    for m in &self.materials {
+      let count = usize::try_from(m.surface_count)
+        .ok()
+        .filter(|count| count % 3 == 0)
+        .ok_or(Error::InvalidSurfaceCount(m.surface_count))?;
+      ranges.push(MaterialRange { start, count });
+      start += count;
+    }
+    if start != self.surfaces.len() * 3 {
+      return Err(Error::SurfaceCountMismatch {
+        materials: start as u64,
+        surfaces: self.surfaces.len() * 3,
+      });
+    }
+    Ok(ranges)
+  }
+
+  /// The materials with their face indices in `material_ranges`, one per draw call.
+  pub fn submeshes(&self) -> Result<impl Iterator<Item = (&Material<C>, Range<usize>)>> {
+    let ranges = self.material_ranges()?;
+    Ok(
+      self
+        .materials
+        .iter()
+        .zip(ranges.into_iter().map(|r| r.indices())),
+    )
+  }
+}
+
+/// Whether files of `version` end with the soft body section, which PMX 2.1 added.
+pub fn has_soft_bodies(version: f32) -> bool {
+  version > 2.0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FIXTURE_MODEL_PMX: &[u8] = include_bytes!("../../fixtures/model.pmx");
+
+  fn progress(parse: bool, interval: usize) -> Vec<(Section, usize, usize)> {
+    let mut calls = Vec::new();
+    let mut on_progress = |section, current, total| {
+      calls.push((section, current, total));
+      ControlFlow::Continue(())
+    };
+    let mut options = PmxReadOptions {
+      on_progress: Some(&mut on_progress),
+      progress_interval: interval,
+      ..Default::default()
+    };
+    if parse {
+      Pmx::<DefaultConfig>::parse_with(FIXTURE_MODEL_PMX, &mut options).unwrap();
+    } else {
+      Pmx::<DefaultConfig>::read_with(FIXTURE_MODEL_PMX, &mut options).unwrap();
+    }
+    calls
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn test_read_f64() {
+    let pmx = Pmx::<crate::F64Config>::read(FIXTURE_MODEL_PMX).unwrap();
+    let diffuse = pmx.materials[0].diffuse_color;
+    // Widened from the f32 in the file, not rounded again
+    assert_eq!(diffuse, [1.0, f64::from(0.9f32), f64::from(0.8f32), 1.0]);
+    assert_ne!(diffuse[1], 0.9);
+
+    let mut bytes = Vec::new();
+    pmx.write(&mut bytes).unwrap();
+    assert_eq!(bytes, FIXTURE_MODEL_PMX);
+  }
+
+  #[test]
+  fn test_read_progress() {
+    let pmx = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    let calls = progress(false, 2);
+    assert_eq!(calls, progress(true, 2));
+
+    assert_eq!(pmx.vertices.len(), 6);
+    assert_eq!(
+      calls[..5],
+      [
+        (Section::Vertices, 0, 6),
+        (Section::Vertices, 2, 6),
+        (Section::Vertices, 4, 6),
+        (Section::Vertices, 6, 6),
+        (Section::Surfaces, 0, 4),
+      ]
+    );
+
+    // Only the start and the end of the sections without an interval
+    let sections = progress(false, 0);
+    let bones = pmx.bones.len();
+    assert_eq!(pmx.version, 2.1);
+    assert_eq!(sections.len(), 2 * 10);
+    assert_eq!(
+      sections[8..10],
+      [(Section::Bones, 0, bones), (Section::Bones, bones, bones)]
+    );
+    assert_eq!(
+      sections[17],
+      (Section::Joints, pmx.joints.len(), pmx.joints.len())
+    );
+  }
+
+  #[test]
+  fn test_cancel_reading() {
+    let mut calls = 0;
+    let mut on_progress = |section, _, _| {
+      calls += 1;
+      if section == Section::Bones {
+        ControlFlow::Break(())
+      } else {
+        ControlFlow::Continue(())
+      }
+    };
+    let mut options = PmxReadOptions {
+      on_progress: Some(&mut on_progress),
+      progress_interval: 0,
+      ..Default::default()
+    };
+    assert!(matches!(
+      Pmx::<DefaultConfig>::parse_with(FIXTURE_MODEL_PMX, &mut options),
+      Err(Error::Cancelled)
+    ));
+    assert_eq!(calls, 9);
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn test_skip_sections() {
+    let expected = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    let skip = Section::Vertices | Section::Surfaces | Section::Textures | Section::Morphs;
+    for parse in [false, true] {
+      let mut options = PmxReadOptions {
+        skip,
+        ..Default::default()
+      };
+      let pmx = if parse {
+        Pmx::<DefaultConfig>::parse_with(FIXTURE_MODEL_PMX, &mut options).unwrap()
+      } else {
+        Pmx::<DefaultConfig>::read_with(FIXTURE_MODEL_PMX, &mut options).unwrap()
+      };
+      assert_eq!(pmx.skipped_sections, skip);
+      assert!(pmx.vertices.is_empty() && pmx.surfaces.is_empty());
+      assert!(pmx.textures.is_empty() && pmx.morphs.is_empty());
+      assert_eq!(pmx.bones, expected.bones);
+      assert_eq!(pmx.materials, expected.materials);
+      assert_eq!(pmx.display_frames, expected.display_frames);
+      assert_eq!(pmx.joints, expected.joints);
+      assert!(matches!(
+        pmx.write(&mut Vec::new()),
+        Err(Error::SkippedSections(s)) if s == skip
+      ));
+    }
+
+    let mut options = PmxReadOptions {
+      skip: Section::Bones | Section::RigidBodies,
+      ..Default::default()
+    };
+    let pmx = Pmx::<DefaultConfig>::read_with(FIXTURE_MODEL_PMX, &mut options).unwrap();
+    assert!(pmx.bones.is_empty() && pmx.rigid_bodies.is_empty());
+    assert_eq!(pmx.morphs, expected.morphs);
+    assert_eq!(pmx.joints, expected.joints);
+    assert!(expected.skipped_sections.is_empty());
+  }
+
+  #[test]
+  fn test_skip_truncated() {
+    let mut options = PmxReadOptions {
+      skip: Section::Vertices.into(),
+      ..Default::default()
+    };
+    let e = Pmx::<DefaultConfig>::read_with(&FIXTURE_MODEL_PMX[..200], &mut options).unwrap_err();
+    assert!(matches!(e.root(), Error::UnexpectedEnd { .. }));
+    assert_eq!(e.location().unwrap().section, "vertex");
+  }
+
+  #[test]
+  fn test_material_ranges() {
+    let mut pmx = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    assert_eq!(
+      pmx.material_ranges().unwrap(),
+      [
+        MaterialRange { start: 0, count: 6 },
+        MaterialRange { start: 6, count: 6 }
+      ]
+    );
+    let submeshes: Vec<_> = pmx.submeshes().unwrap().collect();
+    assert_eq!(submeshes[1].0.name.ja, pmx.materials[1].name.ja);
+    assert_eq!(submeshes[1].1, 6..12);
+
+    pmx.materials[1].surface_count = 3;
+    assert!(matches!(
+      pmx.material_ranges(),
+      Err(Error::SurfaceCountMismatch {
+        materials: 9,
+        surfaces: 12
+      })
+    ));
+    pmx.materials[1].surface_count = 4;
+    assert!(matches!(
+      pmx.submeshes().err(),
+      Some(Error::InvalidSurfaceCount(4))
+    ));
+  }
+
+  #[test]
+  fn test_material_surfaces() {
+    let mut pmx = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    let first = pmx.materials[0].surface_count as usize / 3;
+    assert_eq!(pmx.material_surfaces(0).unwrap(), &pmx.surfaces[..first]);
+    assert_eq!(pmx.material_surfaces(1).unwrap(), &pmx.surfaces[first..]);
+
+    pmx.materials[0].surface_count += 1;
+    assert!(matches!(
+      pmx.material_surfaces(1),
+      Err(Error::InvalidSurfaceCount(c)) if c % 3 == 1
+    ));
+    pmx.materials[0].surface_count += 2;
+    assert!(matches!(
+      pmx.material_surfaces(1),
+      Err(Error::MaterialSurfacesOutOfRange {
+        material: 1,
+        end: 15,
+        count: 12
+      })
+    ));
+  }
+
+  #[cfg(feature = "serde")]
+  #[test]
+  fn test_serde_round_trip() {
+    let pmx = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    let json = serde_json::to_string(&pmx).unwrap();
+    assert_eq!(serde_json::from_str::<Pmx>(&json).unwrap(), pmx);
+    let bytes = bincode::serialize(&pmx).unwrap();
+    assert_eq!(bincode::deserialize::<Pmx>(&bytes).unwrap(), pmx);
+
+    // The typed indices are written as the integers they wrap
+    let typed = Pmx::<crate::TypedConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    assert_eq!(serde_json::to_string(&typed).unwrap(), json);
+    assert_eq!(
+      serde_json::from_str::<Pmx<crate::TypedConfig>>(&json).unwrap(),
+      typed
+    );
+
+    let value = serde_json::to_value(&pmx).unwrap();
+    assert_eq!(value["settings"]["text_encoding"], "UTF16LE");
+    assert_eq!(value["bones"][2]["parent"], 1);
+    assert_eq!(value["skipped_sections"], 0);
+  }
+}
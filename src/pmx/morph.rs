@@ -1,8 +1,18 @@
-use crate::{Config, Error};
-use std::convert::TryFrom;
-use std::fmt::{Debug, Display, Formatter};
+#[cfg(feature = "serde")]
+use crate::SerdeConfig;
+use crate::{
+  math::{to_array, Widen},
+  pmx::types::index_to_usize,
+  Config, Error, LocalizedName, Pmx,
+};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::convert::{TryFrom, TryInto};
+use core::fmt::{Debug, Display, Formatter};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum Panel {
   Hidden,
@@ -14,7 +24,7 @@ pub enum Panel {
 }
 
 impl Display for Panel {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     match self {
       Panel::Hidden => write!(f, "hidden"),
       Panel::Eyebrows => write!(f, "eyebrows"),
@@ -39,7 +49,25 @@ impl From<u8> for Panel {
   }
 }
 
+impl From<Panel> for u8 {
+  fn from(panel: Panel) -> Self {
+    match panel {
+      Panel::Hidden => 0,
+      Panel::Eyebrows => 1,
+      Panel::Eyes => 2,
+      Panel::Mouth => 3,
+      Panel::Other => 4,
+      Panel::Unknown(panel) => panel,
+    }
+  }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct GroupOffset<C: Config> {
   pub morph: C::MorphIndex,
   pub influence: f32,
@@ -49,12 +77,17 @@ impl<C: Config> Display for GroupOffset<C>
 where
   C::MorphIndex: Display,
 {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     write!(f, "{} at rate {}", self.morph, self.influence)
   }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct VertexOffset<C: Config> {
   pub vertex: C::VertexIndex,
   pub offset: C::Vec3,
@@ -65,12 +98,17 @@ where
   C::VertexIndex: Display,
   C::Vec3: Display,
 {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     write!(f, "{} offset {}", self.vertex, self.offset)
   }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct BoneOffset<C: Config> {
   pub bone: C::BoneIndex,
   pub translation: C::Vec3,
@@ -83,7 +121,7 @@ where
   C::Vec3: Display,
   C::Vec4: Display,
 {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     write!(
       f,
       "{} move {} rotate {}",
@@ -93,6 +131,11 @@ where
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct UVOffset<C: Config> {
   pub vertex: C::VertexIndex,
   pub offset: C::Vec4,
@@ -103,12 +146,13 @@ where
   C::VertexIndex: Display,
   C::Vec4: Display,
 {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     write!(f, "{} offset {}", self.vertex, self.offset)
   }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum OffsetMethod {
   Multiply = 0,
@@ -116,7 +160,7 @@ pub enum OffsetMethod {
 }
 
 impl Display for OffsetMethod {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     match self {
       OffsetMethod::Multiply => write!(f, "multiply"),
       OffsetMethod::Additive => write!(f, "additive"),
@@ -137,8 +181,14 @@ impl TryFrom<u8> for OffsetMethod {
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct MaterialOffset<C: Config> {
-  pub material: C::MaterialIndex,
+  /// `None` applies the offset to all materials.
+  pub material: Option<C::MaterialIndex>,
   pub method: OffsetMethod,
   pub diffuse_color: C::Vec4,
   pub specular_color: C::Vec3,
@@ -156,7 +206,7 @@ where
   C::Vec3: Display,
   C::Vec4: Display,
 {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     write!(
       f,
       r"diffuse: {}, specular: {}/{}, ambient: {}, edge: {}/{},
@@ -175,6 +225,11 @@ texture tint: {}, environment tint: {}, toon tint: {}",
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct ImpulseOffset<C: Config> {
   pub rigid_body: C::RigidbodyIndex,
   pub local: bool,
@@ -187,7 +242,7 @@ where
   C::RigidbodyIndex: Display,
   C::Vec3: Display,
 {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     write!(
       f,
       "{} {} velocity: {} torque: {}",
@@ -200,6 +255,11 @@ where
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub enum Offsets<C: Config> {
   Group(Vec<GroupOffset<C>>),
   Vertex(Vec<VertexOffset<C>>),
@@ -215,7 +275,7 @@ pub enum Offsets<C: Config> {
 }
 
 impl<C: Config> Display for Offsets<C> {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     match self {
       Offsets::Group(offsets) => write!(f, "group ({})", offsets.len()),
       Offsets::Vertex(offsets) => write!(f, "vertices ({})", offsets.len()),
@@ -232,9 +292,14 @@ impl<C: Config> Display for Offsets<C> {
   }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct Morph<C: Config> {
-  pub local_name: String,
-  pub universal_name: String,
+  pub name: LocalizedName,
   pub panel: Panel,
   pub offsets: Offsets<C>,
 }
@@ -243,12 +308,441 @@ impl<C: Config> Display for Morph<C>
 where
   Offsets<C>: Display,
 {
-  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), core::fmt::Error> {
     write!(
       f,
       r"local name: {}, universal name: {},
 panel: {}, offsets: {}",
-      self.local_name, self.universal_name, self.panel, self.offsets,
+      self.name.ja, self.name.en, self.panel, self.offsets,
     )
   }
 }
+
+/// The vertex attributes of a model with morphs applied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MorphedGeometry<C: Config> {
+  pub positions: Vec<C::Vec3>,
+  pub uvs: Vec<C::Vec2>,
+  pub additional_uvs: Vec<C::AdditionalVec4s>,
+}
+
+/// Adds the vertex, UV and additional UV offsets of the morphs at their weights, given as
+/// `(morph, weight)`, to the vertices of `pmx`.
+///
+/// The other morphs are ignored, group morphs included. Offsets of additional UVs the vertices
+/// don't have are ignored too.
+pub fn apply<C: Config>(
+  pmx: &Pmx<C>,
+  weights: &[(usize, f32)],
+) -> crate::Result<MorphedGeometry<C>> {
+  let vertices = &pmx.vertices;
+  let mut positions: Vec<[f32; 3]> = vertices.iter().map(|v| to_array(&v.position)).collect();
+  let mut uvs: Vec<[f32; 2]> = vertices.iter().map(|v| to_array(&v.uv)).collect();
+  let mut additional: Vec<Vec<[f32; 4]>> = vertices
+    .iter()
+    .map(|v| v.additional.as_ref().iter().map(to_array).collect())
+    .collect();
+
+  for &(morph, weight) in weights {
+    if weight == 0.0 {
+      continue;
+    }
+    let morph = pmx.morphs.get(morph).ok_or(Error::MorphOutOfRange(morph))?;
+    let vertex = |index: &C::VertexIndex| {
+      index_to_usize(index)
+        .filter(|&v| v < vertices.len())
+        .ok_or_else(|| Error::MorphVertexOutOfRange {
+          morph: morph.name.ja.clone(),
+          vertex: index.clone().try_into().unwrap_or(i64::MAX),
+        })
+    };
+    let (channel, offsets) = match &morph.offsets {
+      Offsets::Vertex(offsets) => {
+        for o in offsets {
+          let offset = to_array::<3>(&o.offset);
+          for (p, o) in positions[vertex(&o.vertex)?].iter_mut().zip(&offset) {
+            *p += o * weight;
+          }
+        }
+        continue;
+      }
+      Offsets::UV(offsets) => {
+        for o in offsets {
+          let offset = to_array::<2>(&o.offset);
+          for (uv, o) in uvs[vertex(&o.vertex)?].iter_mut().zip(&offset) {
+            *uv += o * weight;
+          }
+        }
+        continue;
+      }
+      Offsets::AdditionalUV1(offsets) => (0, offsets),
+      Offsets::AdditionalUV2(offsets) => (1, offsets),
+      Offsets::AdditionalUV3(offsets) => (2, offsets),
+      Offsets::AdditionalUV4(offsets) => (3, offsets),
+      _ => continue,
+    };
+    for o in offsets {
+      let offset = to_array::<4>(&o.offset);
+      if let Some(uv) = additional[vertex(&o.vertex)?].get_mut(channel) {
+        for (uv, o) in uv.iter_mut().zip(&offset) {
+          *uv += o * weight;
+        }
+      }
+    }
+  }
+
+  Ok(MorphedGeometry {
+    positions: positions.into_iter().map(Widen::widen).collect(),
+    uvs: uvs.into_iter().map(Widen::widen).collect(),
+    additional_uvs: additional
+      .into_iter()
+      .map(|uvs| uvs.into_iter().map(Widen::widen).collect())
+      .collect(),
+  })
+}
+
+/// How deep group morphs may nest in `flatten`, far more than real models do.
+pub const MAX_GROUP_DEPTH: usize = 16;
+
+/// The offsets a morph applies at a weight with its group morphs resolved, see `flatten`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlattenedMorph<'a, C: Config> {
+  /// Scaled by their weights and summed per vertex, in the order of the vertices.
+  pub vertices: Vec<VertexOffset<C>>,
+  /// Like `vertices`.
+  pub uvs: Vec<UVOffset<C>>,
+  /// Like `vertices`, for every additional UV.
+  pub additional_uvs: [Vec<UVOffset<C>>; 4],
+  /// With the weights they apply at, as they don't simply scale.
+  pub bones: Vec<(&'a BoneOffset<C>, f32)>,
+  /// With the weights they apply at, like `bones`.
+  pub materials: Vec<(&'a MaterialOffset<C>, f32)>,
+}
+
+/// Offsets summed per vertex, keeping the index of the first one.
+type Summed<I, const N: usize> = BTreeMap<usize, (I, [f32; N])>;
+
+struct Flattening<'a, C: Config> {
+  vertices: Summed<C::VertexIndex, 3>,
+  /// The UVs and then the additional UVs.
+  uvs: [Summed<C::VertexIndex, 4>; 5],
+  bones: Vec<(&'a BoneOffset<C>, f32)>,
+  materials: Vec<(&'a MaterialOffset<C>, f32)>,
+}
+
+fn add<I: Clone, const N: usize>(
+  summed: &mut Summed<I, N>,
+  vertex: (usize, &I),
+  offset: [f32; N],
+  weight: f32,
+) {
+  let (_, sum) = summed
+    .entry(vertex.0)
+    .or_insert_with(|| (vertex.1.clone(), [0.0; N]));
+  for (s, o) in sum.iter_mut().zip(&offset) {
+    *s += o * weight;
+  }
+}
+
+fn flatten_into<'a, C: Config>(
+  pmx: &'a Pmx<C>,
+  index: usize,
+  weight: f32,
+  path: &mut Vec<usize>,
+  out: &mut Flattening<'a, C>,
+) -> crate::Result<()> {
+  let morph = pmx.morphs.get(index).ok_or(Error::MorphOutOfRange(index))?;
+  if let Some(start) = path.iter().position(|&m| m == index) {
+    return Err(Error::GroupMorphCycle(
+      path[start..]
+        .iter()
+        .map(|&m| pmx.morphs[m].name.ja.clone())
+        .collect(),
+    ));
+  }
+
+  let vertex = |index: &C::VertexIndex| {
+    index_to_usize(index)
+      .filter(|&v| v < pmx.vertices.len())
+      .ok_or_else(|| Error::MorphVertexOutOfRange {
+        morph: morph.name.ja.clone(),
+        vertex: index.clone().try_into().unwrap_or(i64::MAX),
+      })
+  };
+  let (channel, offsets) = match &morph.offsets {
+    Offsets::Group(offsets) => {
+      if path.len() >= MAX_GROUP_DEPTH {
+        return Err(Error::GroupMorphTooDeep {
+          morph: morph.name.ja.clone(),
+          depth: MAX_GROUP_DEPTH,
+        });
+      }
+      path.push(index);
+      for o in offsets {
+        let target = index_to_usize(&o.morph)
+          .filter(|&m| m < pmx.morphs.len())
+          .ok_or_else(|| Error::GroupMorphTargetOutOfRange {
+            morph: morph.name.ja.clone(),
+            target: o.morph.clone().try_into().unwrap_or(i64::MAX),
+          })?;
+        flatten_into(pmx, target, weight * o.influence, path, out)?;
+      }
+      path.pop();
+      return Ok(());
+    }
+    Offsets::Vertex(offsets) => {
+      for o in offsets {
+        add(
+          &mut out.vertices,
+          (vertex(&o.vertex)?, &o.vertex),
+          to_array(&o.offset),
+          weight,
+        );
+      }
+      return Ok(());
+    }
+    Offsets::Bone(offsets) => {
+      out.bones.extend(offsets.iter().map(|o| (o, weight)));
+      return Ok(());
+    }
+    Offsets::Material(offsets) => {
+      out.materials.extend(offsets.iter().map(|o| (o, weight)));
+      return Ok(());
+    }
+    Offsets::UV(offsets) => (0, offsets),
+    Offsets::AdditionalUV1(offsets) => (1, offsets),
+    Offsets::AdditionalUV2(offsets) => (2, offsets),
+    Offsets::AdditionalUV3(offsets) => (3, offsets),
+    Offsets::AdditionalUV4(offsets) => (4, offsets),
+    Offsets::Flip(_) | Offsets::Impulse(_) => return Ok(()),
+  };
+  for o in offsets {
+    add(
+      &mut out.uvs[channel],
+      (vertex(&o.vertex)?, &o.vertex),
+      to_array(&o.offset),
+      weight,
+    );
+  }
+  Ok(())
+}
+
+/// Resolves the morph at `morph` applied at `weight` into the offsets of the morphs it's made of,
+/// multiplying the influences of nested group morphs.
+///
+/// Flip and impulse morphs are left out. Fails with `Error::GroupMorphCycle` naming the morphs
+/// of a cycle, with `Error::GroupMorphTooDeep` for groups nested deeper than `MAX_GROUP_DEPTH`,
+/// and with `Error::GroupMorphTargetOutOfRange` and `Error::MorphVertexOutOfRange` for morphs
+/// and vertices that don't exist.
+pub fn flatten<C: Config>(
+  pmx: &Pmx<C>,
+  morph: usize,
+  weight: f32,
+) -> crate::Result<FlattenedMorph<'_, C>> {
+  let mut out = Flattening::<C> {
+    vertices: BTreeMap::new(),
+    uvs: Default::default(),
+    bones: Vec::new(),
+    materials: Vec::new(),
+  };
+  flatten_into(pmx, morph, weight, &mut Vec::new(), &mut out)?;
+
+  let offsets = |summed: Summed<C::VertexIndex, 4>| {
+    summed
+      .into_values()
+      .map(|(vertex, offset)| UVOffset {
+        vertex,
+        offset: offset.widen(),
+      })
+      .collect()
+  };
+  let [uvs, uv1, uv2, uv3, uv4] = out.uvs;
+  Ok(FlattenedMorph {
+    vertices: out
+      .vertices
+      .into_values()
+      .map(|(vertex, offset)| VertexOffset {
+        vertex,
+        offset: offset.widen(),
+      })
+      .collect(),
+    uvs: offsets(uvs),
+    additional_uvs: [offsets(uv1), offsets(uv2), offsets(uv3), offsets(uv4)],
+    bones: out.bones,
+    materials: out.materials,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::DefaultConfig;
+
+  fn morph(name: &str, offsets: Offsets<DefaultConfig>) -> Morph<DefaultConfig> {
+    Morph {
+      name: LocalizedName::new(name, ""),
+      panel: Panel::Other,
+      offsets,
+    }
+  }
+
+  fn vertex_morph(name: &str, offsets: &[(i32, [f32; 3])]) -> Morph<DefaultConfig> {
+    let offsets = offsets
+      .iter()
+      .map(|&(vertex, offset)| VertexOffset {
+        vertex,
+        offset: offset.into(),
+      })
+      .collect();
+    morph(name, Offsets::Vertex(offsets))
+  }
+
+  fn group_morph(name: &str, offsets: &[(i32, f32)]) -> Morph<DefaultConfig> {
+    let offsets = offsets
+      .iter()
+      .map(|&(morph, influence)| GroupOffset { morph, influence })
+      .collect();
+    morph(name, Offsets::Group(offsets))
+  }
+
+  fn model(morphs: Vec<Morph<DefaultConfig>>) -> Pmx<DefaultConfig> {
+    let mut pmx =
+      Pmx::<DefaultConfig>::read(&include_bytes!("../../fixtures/model.pmx")[..]).unwrap();
+    pmx.morphs = morphs;
+    pmx
+  }
+
+  fn positions(geometry: &MorphedGeometry<DefaultConfig>) -> Vec<[f32; 3]> {
+    geometry.positions.iter().map(to_array).collect()
+  }
+
+  #[test]
+  fn test_apply_vertex_morph() {
+    let mut pmx =
+      Pmx::<DefaultConfig>::read(&include_bytes!("../../fixtures/model.pmx")[..]).unwrap();
+    // The vertex morph of the fixture is a blink
+    let (vertex, offset) = match &pmx.morphs[0].offsets {
+      Offsets::Vertex(offsets) => (
+        offsets[0].vertex as usize,
+        to_array::<3>(&offsets[0].offset),
+      ),
+      offsets => panic!("{}", offsets),
+    };
+    let rest = to_array::<3>(&pmx.vertices[vertex].position);
+    let at = |t: f32| {
+      [
+        rest[0] + offset[0] * t,
+        rest[1] + offset[1] * t,
+        rest[2] + offset[2] * t,
+      ]
+    };
+
+    let geometry = apply(&pmx, &[(0, 1.0)]).unwrap();
+    assert_eq!(positions(&geometry)[vertex], at(1.0));
+    let geometry = apply(&pmx, &[(0, 0.5), (4, 1.0)]).unwrap();
+    assert_eq!(positions(&geometry)[vertex], at(0.5));
+    assert_eq!(positions(&apply(&pmx, &[(0, 0.0)]).unwrap())[vertex], rest);
+
+    // The UV morph moves the UV of vertex 0, the additional one the first channel of vertex 1
+    let geometry = apply(&pmx, &[(2, 1.0), (3, 0.5)]).unwrap();
+    let uv = to_array::<2>(&pmx.vertices[0].uv);
+    assert_eq!(to_array::<2>(&geometry.uvs[0]), [uv[0] + 0.25, uv[1] + 0.5]);
+    let additional = to_array::<4>(&pmx.vertices[1].additional[0]);
+    assert_eq!(
+      to_array::<4>(&geometry.additional_uvs[1][0]),
+      [
+        additional[0],
+        additional[1],
+        additional[2] + 0.25,
+        additional[3] + 0.5
+      ]
+    );
+
+    assert!(matches!(
+      apply(&pmx, &[(9, 1.0)]),
+      Err(Error::MorphOutOfRange(9))
+    ));
+    if let Offsets::Vertex(offsets) = &mut pmx.morphs[0].offsets {
+      offsets[1].vertex = 6;
+    }
+    assert!(matches!(
+      apply(&pmx, &[(0, 1.0)]),
+      Err(Error::MorphVertexOutOfRange { vertex: 6, .. })
+    ));
+  }
+
+  #[test]
+  fn test_flatten_group_morph() {
+    let pmx = model(vec![
+      vertex_morph("a", &[(0, [1.0, 0.0, 0.0]), (1, [0.0, 2.0, 0.0])]),
+      vertex_morph("b", &[(1, [0.0, 1.0, 0.0]), (2, [0.0, 0.0, 4.0])]),
+      group_morph("ab", &[(0, 0.5), (1, 1.0)]),
+      group_morph("nested", &[(2, 0.5), (0, 1.0)]),
+    ]);
+
+    let flattened = flatten(&pmx, 2, 1.0).unwrap();
+    let offsets: Vec<_> = flattened
+      .vertices
+      .iter()
+      .map(|o| (o.vertex, to_array::<3>(&o.offset)))
+      .collect();
+    assert_eq!(
+      offsets,
+      [
+        (0, [0.5, 0.0, 0.0]),
+        (1, [0.0, 2.0, 0.0]),
+        (2, [0.0, 0.0, 4.0])
+      ]
+    );
+    assert!(flattened.uvs.is_empty() && flattened.bones.is_empty());
+
+    let flattened = flatten(&pmx, 3, 0.5).unwrap();
+    let offsets: Vec<_> = flattened
+      .vertices
+      .iter()
+      .map(|o| (o.vertex, to_array::<3>(&o.offset)))
+      .collect();
+    assert_eq!(
+      offsets,
+      [
+        (0, [0.625, 0.0, 0.0]),
+        (1, [0.0, 1.5, 0.0]),
+        (2, [0.0, 0.0, 1.0])
+      ]
+    );
+  }
+
+  #[test]
+  fn test_flatten_errors() {
+    let pmx = model(vec![
+      vertex_morph("a", &[(0, [1.0, 0.0, 0.0])]),
+      group_morph("b", &[(0, 1.0), (2, 1.0)]),
+      group_morph("c", &[(1, 1.0)]),
+      group_morph("d", &[(9, 1.0)]),
+    ]);
+    assert!(matches!(
+      flatten(&pmx, 1, 1.0),
+      Err(Error::GroupMorphCycle(names)) if names == ["b", "c"]
+    ));
+    assert!(matches!(
+      flatten(&pmx, 3, 1.0),
+      Err(Error::GroupMorphTargetOutOfRange { target: 9, .. })
+    ));
+    assert!(matches!(
+      flatten(&pmx, 4, 1.0),
+      Err(Error::MorphOutOfRange(4))
+    ));
+
+    // Every group refers to the next one
+    let mut morphs: Vec<_> = (0..MAX_GROUP_DEPTH as i32 + 1)
+      .map(|i| group_morph(&i.to_string(), &[(i + 1, 1.0)]))
+      .collect();
+    morphs.push(vertex_morph("end", &[(0, [1.0, 0.0, 0.0])]));
+    let pmx = model(morphs);
+    assert!(flatten(&pmx, 1, 1.0).is_ok());
+    assert!(matches!(
+      flatten(&pmx, 0, 1.0),
+      Err(Error::GroupMorphTooDeep { morph, .. }) if morph == "16"
+    ));
+  }
+}
@@ -0,0 +1,127 @@
+//! Names in both languages of PMX files.
+
+use alloc::string::String;
+use core::fmt::{Display, Formatter};
+use core::sync::atomic::{AtomicU8, Ordering};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[repr(u8)]
+pub enum Language {
+  Japanese = 0,
+  English = 1,
+}
+
+static PREFERRED_LANGUAGE: AtomicU8 = AtomicU8::new(Language::Japanese as u8);
+
+/// The language `LocalizedName::preferred` picks, Japanese unless changed.
+pub fn preferred_language() -> Language {
+  match PREFERRED_LANGUAGE.load(Ordering::Relaxed) {
+    1 => Language::English,
+    _ => Language::Japanese,
+  }
+}
+
+/// Sets the language `LocalizedName::preferred` picks for the whole program.
+pub fn set_preferred_language(language: Language) {
+  PREFERRED_LANGUAGE.store(language as u8, Ordering::Relaxed);
+}
+
+/// The local (Japanese) and universal (English) name of a model element, written back as they
+/// are.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LocalizedName {
+  pub ja: String,
+  pub en: String,
+}
+
+impl LocalizedName {
+  pub fn new(ja: impl Into<String>, en: impl Into<String>) -> Self {
+    LocalizedName {
+      ja: ja.into(),
+      en: en.into(),
+    }
+  }
+
+  /// The name in `language`, or in the other language if it is empty or only whitespace.
+  pub fn get(&self, language: Language) -> &str {
+    let (first, second) = match language {
+      Language::Japanese => (&self.ja, &self.en),
+      Language::English => (&self.en, &self.ja),
+    };
+    if first.trim().is_empty() {
+      second
+    } else {
+      first
+    }
+  }
+
+  /// The name in `preferred_language`.
+  pub fn preferred(&self) -> &str {
+    self.get(preferred_language())
+  }
+}
+
+impl Display for LocalizedName {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
+    write!(f, "{}", self.preferred())
+  }
+}
+
+macro_rules! impl_name {
+  ($($t:ty),*) => {
+    $(
+      impl<C: crate::Config> $t {
+        /// The name in `preferred_language`, see `LocalizedName::preferred`.
+        pub fn name(&self) -> &str {
+          self.name.preferred()
+        }
+      }
+    )*
+  };
+}
+
+impl_name!(
+  crate::Bone<C>,
+  crate::Material<C>,
+  crate::pmx::morph::Morph<C>,
+  crate::pmx::display::DisplayFrame<C>,
+  crate::pmx::rigid_body::RigidBody<C>,
+  crate::pmx::joint::Joint<C>,
+  crate::pmx::soft_body::SoftBody<C>
+);
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_get_falls_back() {
+    let name = LocalizedName::new("頭", " ");
+    assert_eq!(name.get(Language::Japanese), "頭");
+    assert_eq!(name.get(Language::English), "頭");
+
+    let name = LocalizedName::new("", "head");
+    assert_eq!(name.get(Language::Japanese), "head");
+    assert_eq!(
+      LocalizedName::new("頭", "head").get(Language::English),
+      "head"
+    );
+    assert_eq!(LocalizedName::default().get(Language::English), "");
+  }
+
+  #[test]
+  fn test_preferred_name() {
+    let pmx =
+      crate::Pmx::<crate::DefaultConfig>::read(&include_bytes!("../../fixtures/model.pmx")[..])
+        .unwrap();
+    assert_eq!(pmx.bones[0].name(), pmx.bones[0].name.ja);
+    assert_eq!(pmx.bones[0].name.get(Language::English), "center");
+    // The rigid bodies converted from PMD have no English names
+    assert_eq!(pmx.rigid_bodies[0].name.get(Language::English), "頭");
+    assert_eq!(pmx.rigid_bodies[0].name(), "頭");
+  }
+}
@@ -0,0 +1,222 @@
+//! Recomputing the vertex normals of a model from its faces, for models with broken normals.
+
+use crate::math::{cross3, dot3, normalize3, sub3, to_array, Widen};
+use crate::pmx::types::index_to_usize;
+use crate::{Config, Error, Pmx, Result};
+use std::convert::TryInto;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NormalMode {
+  /// Every vertex gets the normal of a face it's in. Vertices shared by faces get the normal of
+  /// the last of them, so faces that should look flat need vertices of their own.
+  Flat,
+  /// The normals of the faces around a vertex weighted by their areas.
+  AreaWeighted,
+  /// The normals of the faces around a vertex weighted by their angles at the vertex, which
+  /// doesn't depend on how the faces are split into triangles.
+  AngleWeighted,
+}
+
+/// The angle at `p` of the triangle `p`, `q`, `r`.
+fn angle(p: [f32; 3], q: [f32; 3], r: [f32; 3]) -> f32 {
+  let (a, b) = (sub3(q, p), sub3(r, p));
+  let cos = dot3(a, b) / (dot3(a, a) * dot3(b, b)).sqrt();
+  cos.clamp(-1.0, 1.0).acos()
+}
+
+impl<C: Config> Pmx<C> {
+  /// The vertex positions of the faces, `Error::IndexOverflow` for faces with vertices that
+  /// don't exist.
+  pub(crate) fn face_vertices(&self) -> Result<Vec<[usize; 3]>> {
+    self
+      .surfaces
+      .iter()
+      .map(|surface| {
+        let mut face = [0; 3];
+        for (f, vertex) in face.iter_mut().zip(surface) {
+          *f = index_to_usize(vertex)
+            .filter(|&v| v < self.vertices.len())
+            .ok_or_else(|| Error::IndexOverflow(vertex.clone().try_into().unwrap_or(i64::MAX)))?;
+        }
+        Ok(face)
+      })
+      .collect()
+  }
+
+  /// Replaces the normals of the vertices with ones computed from the faces in `surfaces`, whose
+  /// fronts wind clockwise in MMD's left-handed space.
+  ///
+  /// Faces without area are skipped, and vertices in no other face keep their normals. Nothing
+  /// is changed if a face refers to a vertex that doesn't exist.
+  pub fn recompute_normals(&mut self, mode: NormalMode) -> Result<()> {
+    let faces = self.face_vertices()?;
+    let positions: Vec<[f32; 3]> = self
+      .vertices
+      .iter()
+      .map(|v| to_array(&v.position))
+      .collect();
+
+    let mut normals: Vec<Option<[f32; 3]>> = vec![None; self.vertices.len()];
+    for face in &faces {
+      let p = [positions[face[0]], positions[face[1]], positions[face[2]]];
+      // Twice the area long
+      let normal = cross3(sub3(p[1], p[0]), sub3(p[2], p[0]));
+      let len = dot3(normal, normal).sqrt();
+      if !(len > 0.0 && len.is_finite()) {
+        continue;
+      }
+      let unit = [normal[0] / len, normal[1] / len, normal[2] / len];
+
+      for (k, &vertex) in face.iter().enumerate() {
+        let weighted = match mode {
+          NormalMode::Flat => {
+            normals[vertex] = Some(unit);
+            continue;
+          }
+          NormalMode::AreaWeighted => normal,
+          NormalMode::AngleWeighted => {
+            let a = angle(p[k], p[(k + 1) % 3], p[(k + 2) % 3]);
+            [unit[0] * a, unit[1] * a, unit[2] * a]
+          }
+        };
+        let sum = normals[vertex].get_or_insert([0.0; 3]);
+        for (s, w) in sum.iter_mut().zip(&weighted) {
+          *s += w;
+        }
+      }
+    }
+
+    for (vertex, normal) in self.vertices.iter_mut().zip(normals) {
+      // Opposite faces can cancel out
+      if let Some(normal) = normal.filter(|n| dot3(*n, *n) > 0.0) {
+        vertex.normal = normalize3(normal).widen();
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::pmx::builder::{PmxBuilder, VertexHandle};
+  use crate::DefaultConfig;
+
+  /// For every axis its sign and two directions along the face whose cross product points out.
+  const FACES: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+    ([1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+    ([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),
+    ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [1.0, 0.0, 0.0]),
+    ([0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+    ([0.0, 0.0, -1.0], [0.0, 1.0, 0.0], [1.0, 0.0, 0.0]),
+  ];
+
+  /// The corners of a face of the unit cube around the origin, in the order of two clockwise
+  /// triangles `0, 1, 2` and `1, 3, 2`.
+  fn corners((n, u, v): ([f32; 3], [f32; 3], [f32; 3])) -> [[f32; 3]; 4] {
+    let corner = |su: f32, sv: f32| {
+      let mut p = [0.0; 3];
+      for i in 0..3 {
+        p[i] = 0.5 * (n[i] + su * u[i] + sv * v[i]);
+      }
+      p
+    };
+    [
+      corner(-1.0, -1.0),
+      corner(1.0, -1.0),
+      corner(-1.0, 1.0),
+      corner(1.0, 1.0),
+    ]
+  }
+
+  /// A unit cube without normals, with vertices of its own for every face or shared by them.
+  fn cube(shared: bool) -> Pmx<DefaultConfig> {
+    let mut builder = PmxBuilder::new("cube");
+    let root = builder.add_bone("センター", [0.0; 3], None);
+    let mut vertices: Vec<([f32; 3], VertexHandle)> = Vec::new();
+    for face in FACES.iter() {
+      let handles: Vec<_> = corners(*face)
+        .iter()
+        .map(
+          |&p| match vertices.iter().find(|(q, _)| shared && *q == p) {
+            Some(&(_, handle)) => handle,
+            None => {
+              let handle = builder.add_vertex(p, [0.0; 3], [0.0; 2], root);
+              vertices.push((p, handle));
+              handle
+            }
+          },
+        )
+        .collect();
+      builder.add_triangle(handles[0], handles[1], handles[2]);
+      builder.add_triangle(handles[1], handles[3], handles[2]);
+    }
+    builder.add_material("cube", [1.0; 4]);
+    builder.build().unwrap()
+  }
+
+  fn normals(pmx: &Pmx<DefaultConfig>) -> Vec<[f32; 3]> {
+    pmx.vertices.iter().map(|v| to_array(&v.normal)).collect()
+  }
+
+  #[test]
+  fn test_flat_normals() {
+    let mut pmx = cube(false);
+    assert_eq!(pmx.vertices.len(), 24);
+    pmx.recompute_normals(NormalMode::Flat).unwrap();
+    let normals = normals(&pmx);
+    for (face, (n, _, _)) in FACES.iter().enumerate() {
+      assert_eq!(normals[face * 4..face * 4 + 4], [*n; 4]);
+    }
+
+    // Each face is flat, so weighting changes nothing
+    let mut smooth = cube(false);
+    smooth.recompute_normals(NormalMode::AreaWeighted).unwrap();
+    assert_eq!(smooth, pmx);
+  }
+
+  #[test]
+  fn test_smooth_normals() {
+    let mut pmx = cube(true);
+    assert_eq!(pmx.vertices.len(), 8);
+    pmx.recompute_normals(NormalMode::AngleWeighted).unwrap();
+    let third = 1.0 / 3.0f32.sqrt();
+    for (vertex, normal) in pmx.vertices.iter().zip(normals(&pmx)) {
+      let p = to_array::<3>(&vertex.position);
+      for i in 0..3 {
+        assert!((normal[i] - p[i].signum() * third).abs() < 1e-6);
+      }
+    }
+  }
+
+  #[test]
+  fn test_degenerate_faces() {
+    let mut pmx = cube(false);
+    let mut unused = pmx.vertices[0].clone();
+    unused.normal = [0.0, 1.0, 0.0].into();
+    pmx.vertices.push(unused);
+    // A face without area on a vertex that's in no other face
+    let degenerate = pmx.vertices[1].clone();
+    pmx.vertices.push(degenerate);
+    pmx.surfaces.push([25, 25, 0]);
+    pmx.materials[0].surface_count += 3;
+
+    pmx.recompute_normals(NormalMode::AreaWeighted).unwrap();
+    let normals = normals(&pmx);
+    assert!(normals.iter().flatten().all(|c| c.is_finite()));
+    assert_eq!(normals[0], [1.0, 0.0, 0.0]);
+    assert_eq!(normals[24], [0.0, 1.0, 0.0]);
+    assert_eq!(normals[25], [0.0; 3]);
+
+    pmx.surfaces.push([0, 1, 26]);
+    assert!(matches!(
+      pmx.recompute_normals(NormalMode::Flat),
+      Err(Error::IndexOverflow(26))
+    ));
+    assert_eq!(
+      crate::math::to_array::<3>(&pmx.vertices[0].normal),
+      [1.0, 0.0, 0.0]
+    );
+  }
+}
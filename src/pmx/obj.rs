@@ -0,0 +1,201 @@
+//! Export of the geometry of a model to Wavefront OBJ and MTL, for a look in other 3D tools.
+//!
+//! Weights, bones, morphs and physics have no place in OBJ and are left out.
+
+use crate::math::to_array;
+use crate::pmx::texture_path::texture_path;
+use crate::pmx::types::index_to_usize;
+use crate::{Config, Pmx, Result};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Options of `Pmx::export_obj`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObjOptions {
+  /// The name of the MTL file the OBJ file refers to, `model.mtl` by default.
+  pub mtl_name: String,
+  /// The directory the texture entries are resolved against with `texture_path`, empty by
+  /// default for paths relative to the model.
+  pub texture_dir: PathBuf,
+  /// Writes `1 - v` for V, as OBJ has V going up. On by default.
+  pub flip_v: bool,
+  /// Negates Z and reverses the winding of the faces for right-handed tools, MMD is left-handed.
+  /// Off by default.
+  pub right_handed: bool,
+}
+
+impl Default for ObjOptions {
+  fn default() -> Self {
+    ObjOptions {
+      mtl_name: "model.mtl".to_string(),
+      texture_dir: PathBuf::new(),
+      flip_v: true,
+      right_handed: false,
+    }
+  }
+}
+
+/// The name of a material in both files, numbered as names needn't be unique and without the
+/// whitespace that would end it.
+fn material_name(index: usize, name: &str) -> String {
+  let name: String = name
+    .chars()
+    .map(|c| if c.is_whitespace() { '_' } else { c })
+    .collect();
+  format!("{}_{}", index, name)
+}
+
+impl<C: Config> Pmx<C> {
+  /// Writes the vertices and faces to `obj`, one group per material, and the materials with
+  /// their colors and diffuse textures to `mtl`.
+  ///
+  /// Fails with the errors of `material_ranges` if the materials don't cover the faces.
+  pub fn export_obj<W: Write>(&self, mut obj: W, mut mtl: W, options: &ObjOptions) -> Result<()> {
+    let z = if options.right_handed { -1.0 } else { 1.0 };
+    let ranges = self.material_ranges()?;
+
+    writeln!(obj, "# {}", self.model_local_name)?;
+    writeln!(obj, "mtllib {}", options.mtl_name)?;
+    for vertex in &self.vertices {
+      let [x, y, pz] = to_array::<3>(&vertex.position);
+      writeln!(obj, "v {} {} {}", x, y, pz * z)?;
+    }
+    for vertex in &self.vertices {
+      let [u, v] = to_array::<2>(&vertex.uv);
+      writeln!(obj, "vt {} {}", u, if options.flip_v { 1.0 - v } else { v })?;
+    }
+    for vertex in &self.vertices {
+      let [x, y, nz] = to_array::<3>(&vertex.normal);
+      writeln!(obj, "vn {} {} {}", x, y, nz * z)?;
+    }
+
+    for ((i, material), range) in self.materials.iter().enumerate().zip(&ranges) {
+      writeln!(obj, "usemtl {}", material_name(i, &material.name.ja))?;
+      for surface in &self.surfaces[range.start / 3..(range.start + range.count) / 3] {
+        // OBJ counts from 1
+        let index = |i: usize| index_to_usize(&surface[i]).map_or(0, |v| v + 1);
+        let [a, b, c] = if options.right_handed {
+          [index(0), index(2), index(1)]
+        } else {
+          [index(0), index(1), index(2)]
+        };
+        writeln!(obj, "f {0}/{0}/{0} {1}/{1}/{1} {2}/{2}/{2}", a, b, c)?;
+      }
+    }
+
+    for (i, material) in self.materials.iter().enumerate() {
+      let [r, g, b, a] = to_array::<4>(&material.diffuse_color);
+      let [ar, ag, ab] = to_array::<3>(&material.ambient_color);
+      let [sr, sg, sb] = to_array::<3>(&material.specular_color);
+      writeln!(mtl, "newmtl {}", material_name(i, &material.name.ja))?;
+      writeln!(mtl, "Kd {} {} {}", r, g, b)?;
+      writeln!(mtl, "d {}", a)?;
+      writeln!(mtl, "Ka {} {} {}", ar, ag, ab)?;
+      writeln!(mtl, "Ks {} {} {}", sr, sg, sb)?;
+      writeln!(mtl, "Ns {}", material.specular_strength)?;
+      // Textures that don't exist are validation findings, not reasons to fail
+      let texture = material
+        .texture_index
+        .as_ref()
+        .and_then(index_to_usize)
+        .and_then(|t| self.textures.get(t));
+      if let Some(texture) = texture {
+        let path = texture_path(texture, &options.texture_dir, false)?;
+        writeln!(mtl, "map_Kd {}", path.path.display())?;
+      }
+      writeln!(mtl)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::DefaultConfig;
+
+  fn export(options: &ObjOptions) -> (Pmx, String, String) {
+    let pmx = Pmx::<DefaultConfig>::read(&include_bytes!("../../fixtures/model.pmx")[..]).unwrap();
+    let (mut obj, mut mtl) = (Vec::new(), Vec::new());
+    pmx.export_obj(&mut obj, &mut mtl, options).unwrap();
+    (
+      pmx,
+      String::from_utf8(obj).unwrap(),
+      String::from_utf8(mtl).unwrap(),
+    )
+  }
+
+  fn lines<'a>(text: &'a str, tag: &str) -> Vec<Vec<&'a str>> {
+    text
+      .lines()
+      .filter_map(|l| {
+        let mut words = l.split_whitespace();
+        (words.next() == Some(tag)).then(|| words.collect())
+      })
+      .collect()
+  }
+
+  /// The vertex indices of a face line, from 0.
+  fn face(words: &[&str]) -> Vec<i32> {
+    words
+      .iter()
+      .map(|w| w.split('/').next().unwrap().parse::<i32>().unwrap() - 1)
+      .collect()
+  }
+
+  #[test]
+  fn test_export_obj() {
+    let (pmx, obj, mtl) = export(&ObjOptions::default());
+    assert_eq!(lines(&obj, "mtllib"), [["model.mtl"]]);
+    assert_eq!(lines(&obj, "v").len(), pmx.vertices.len());
+    assert_eq!(lines(&obj, "vt").len(), pmx.vertices.len());
+    assert_eq!(lines(&obj, "vn").len(), pmx.vertices.len());
+    assert_eq!(lines(&obj, "usemtl"), [["0_材質1"], ["1_材質2"]]);
+
+    let faces = lines(&obj, "f");
+    assert_eq!(faces.len(), pmx.surfaces.len());
+    assert_eq!(face(&faces[3]), pmx.surfaces[3]);
+    assert_eq!(faces[3][0].split('/').collect::<Vec<_>>().len(), 3);
+
+    let uv = to_array::<2>(&pmx.vertices[1].uv);
+    let vt: Vec<f32> = lines(&obj, "vt")[1]
+      .iter()
+      .map(|w| w.parse().unwrap())
+      .collect();
+    assert_eq!(vt, [uv[0], 1.0 - uv[1]]);
+
+    assert_eq!(lines(&mtl, "newmtl").len(), 2);
+    assert_eq!(lines(&mtl, "map_Kd"), [["body.bmp"]]);
+    assert_eq!(lines(&mtl, "d")[1], ["0.5"]);
+    assert_eq!(lines(&mtl, "Kd")[0], ["1", "0.9", "0.8"]);
+  }
+
+  #[test]
+  fn test_export_obj_right_handed() {
+    let options = ObjOptions {
+      flip_v: false,
+      right_handed: true,
+      texture_dir: PathBuf::from("textures"),
+      ..Default::default()
+    };
+    let (pmx, obj, mtl) = export(&options);
+
+    let [a, b, c] = pmx.surfaces[3];
+    assert_eq!(face(&lines(&obj, "f")[3]), [a, c, b]);
+    let position = to_array::<3>(&pmx.vertices[2].position);
+    let v: Vec<f32> = lines(&obj, "v")[2]
+      .iter()
+      .map(|w| w.parse().unwrap())
+      .collect();
+    assert_eq!(v, [position[0], position[1], -position[2]]);
+    let uv = to_array::<2>(&pmx.vertices[1].uv);
+    assert_eq!(lines(&obj, "vt")[1], [uv[0].to_string(), uv[1].to_string()]);
+    assert_eq!(
+      lines(&mtl, "map_Kd"),
+      [[PathBuf::from("textures")
+        .join("body.bmp")
+        .display()
+        .to_string()]]
+    );
+  }
+}
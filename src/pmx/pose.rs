@@ -0,0 +1,472 @@
+//! World transforms of the bones of a model posed by a motion or a VPD pose.
+//!
+//! The bones are evaluated in the order MMD uses: the ones transformed before physics and then
+//! the ones after it, each by their transform level with parents before their children. Bones
+//! appending (付与) the rotation or movement of another bone add it scaled by their rate.
+
+use crate::{
+  math::{mat4_mul, quat_mul, rotation_translation, slerp, to_array, Widen},
+  pmx::{
+    skeleton::{parent_of, parent_order, rest_pose, Matrix4},
+    types::index_to_usize,
+  },
+  vmd::{sampler::BoneSample, track::BoneTrackSet},
+  vpd::Vpd,
+  Bone, Config, DefaultConfig, Error, Result,
+};
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+pub(crate) const IDENTITY_ROTATION: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
+
+/// The transform of a bone relative to its rest pose, as motions and poses store it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalTransform<C: Config = DefaultConfig> {
+  pub translation: C::Vec3,
+  pub rotation: C::Vec4,
+}
+
+impl<C: Config> From<BoneSample<C>> for LocalTransform<C> {
+  fn from(sample: BoneSample<C>) -> Self {
+    LocalTransform {
+      translation: sample.position,
+      rotation: sample.rotation,
+    }
+  }
+}
+
+/// The local transforms of `bones` from the tracks of a motion at `frame`, matched by their
+/// Japanese names. Bones without a track are `None`.
+pub fn motion_locals<C: Config>(
+  bones: &[Bone<C>],
+  tracks: &BoneTrackSet<C>,
+  frame: f32,
+) -> Vec<Option<LocalTransform<C>>> {
+  bones
+    .iter()
+    .map(|b| {
+      let sample = tracks.track(&b.name.ja)?.sample(frame)?;
+      Some(sample.into())
+    })
+    .collect()
+}
+
+/// The local transforms of `bones` in `vpd`, matched by their Japanese names. Bones missing
+/// from the pose are `None`, of bones listed twice the first transform is used.
+pub fn vpd_locals<C: Config>(bones: &[Bone<C>], vpd: &Vpd<C>) -> Vec<Option<LocalTransform<C>>> {
+  let mut transforms = HashMap::new();
+  for t in vpd.bone_transforms.iter().rev() {
+    transforms.insert(t.name.as_str(), t);
+  }
+  bones
+    .iter()
+    .map(|b| {
+      transforms.get(b.name.ja.as_str()).map(|t| LocalTransform {
+        translation: t.position.clone(),
+        rotation: t.rotation.clone(),
+      })
+    })
+    .collect()
+}
+
+/// Which bones `Pose::evaluate` transforms, see `Bone::after_physics`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PhysicsPass {
+  Before,
+  After,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Append {
+  parent: usize,
+  rate: f32,
+  rotation: bool,
+  movement: bool,
+  local: bool,
+}
+
+/// The local and world transforms of the bones of a model.
+///
+/// Without physics both passes are evaluated in turn. With physics the bones driven by rigid
+/// bodies are placed with `set_world` between them.
+pub struct Pose<'a, C: Config = DefaultConfig> {
+  bones: &'a [Bone<C>],
+  parents: Vec<Option<usize>>,
+  children: Vec<Vec<usize>>,
+  appends: Vec<Option<Append>>,
+  rest: Vec<[f32; 3]>,
+  inverse_bind: Vec<Matrix4>,
+  order: Vec<usize>,
+  /// Where the bones after physics start in `order`.
+  after_physics: usize,
+  pub(crate) input: Vec<([f32; 3], [f32; 4])>,
+  pub(crate) ik: Vec<[f32; 4]>,
+  appended: Vec<([f32; 3], [f32; 4])>,
+  pub(crate) world: Vec<Matrix4>,
+}
+
+impl<'a, C: Config> Pose<'a, C> {
+  /// The rest pose of `bones`. Fails like `parent_order` for missing parents and cycles, and with
+  /// `Error::AppendBoneOutOfRange` for missing append parents.
+  pub fn new(bones: &'a [Bone<C>]) -> Result<Self> {
+    let parents = (0..bones.len())
+      .map(|b| parent_of(bones, b))
+      .collect::<Result<Vec<_>>>()?;
+    let mut children = vec![Vec::new(); bones.len()];
+    for (bone, parent) in parents.iter().enumerate() {
+      if let Some(parent) = parent {
+        children[*parent].push(bone);
+      }
+    }
+    let mut order = parent_order(bones)?;
+    // Stable, so parents stay before their children on the same level
+    order.sort_by_key(|&b| (bones[b].after_physics(), bones[b].transform_level));
+    let after_physics = order.partition_point(|&b| !bones[b].after_physics());
+
+    let appends = bones
+      .iter()
+      .enumerate()
+      .map(|(bone, b)| {
+        let additional = match &b.additional {
+          Some(a) if b.inherits_rotation() || b.inherits_movement() => a,
+          _ => return Ok(None),
+        };
+        let parent = index_to_usize(&additional.parent)
+          .filter(|&p| p < bones.len())
+          .ok_or_else(|| Error::AppendBoneOutOfRange {
+            bone,
+            parent: additional.parent.clone().try_into().unwrap_or(i64::MAX),
+          })?;
+        // Appending to itself would do nothing but feed back into its own input
+        if parent == bone {
+          return Ok(None);
+        }
+        Ok(Some(Append {
+          parent,
+          rate: additional.rate,
+          rotation: b.inherits_rotation(),
+          movement: b.inherits_movement(),
+          local: b.inherits_local(),
+        }))
+      })
+      .collect::<Result<Vec<_>>>()?;
+
+    let rest = rest_pose(bones)?;
+    Ok(Pose {
+      bones,
+      parents,
+      children,
+      appends,
+      rest: rest.iter().map(|r| to_array::<3>(&r.local)).collect(),
+      inverse_bind: rest.iter().map(|r| r.inverse_bind).collect(),
+      order,
+      after_physics,
+      input: vec![([0.0; 3], IDENTITY_ROTATION); bones.len()],
+      ik: vec![IDENTITY_ROTATION; bones.len()],
+      appended: vec![([0.0; 3], IDENTITY_ROTATION); bones.len()],
+      world: rest.into_iter().map(|r| r.global).collect(),
+    })
+  }
+
+  pub fn bones(&self) -> &'a [Bone<C>] {
+    self.bones
+  }
+
+  /// The bones in the order they're evaluated, those before physics first.
+  pub fn order(&self) -> &[usize] {
+    &self.order
+  }
+
+  /// Sets the local transforms of the bones in their order, `None` and missing ones are at rest.
+  /// Also resets the IK rotations.
+  pub fn set_locals(&mut self, locals: &[Option<LocalTransform<C>>]) {
+    for (bone, input) in self.input.iter_mut().enumerate() {
+      *input = match locals.get(bone) {
+        Some(Some(l)) => (to_array(&l.translation), to_array(&l.rotation)),
+        _ => ([0.0; 3], IDENTITY_ROTATION),
+      };
+    }
+    self.ik.fill(IDENTITY_ROTATION);
+  }
+
+  /// The local transform of `bone` as set with `set_locals`.
+  pub fn local(&self, bone: usize) -> LocalTransform<C> {
+    let (translation, rotation) = self.input[bone];
+    LocalTransform {
+      translation: translation.widen(),
+      rotation: rotation.widen(),
+    }
+  }
+
+  /// Sets the rotation an IK solver applies to `bone` on top of its local rotation, identity
+  /// until set.
+  pub fn set_ik_rotation(&mut self, bone: usize, rotation: C::Vec4) {
+    self.ik[bone] = to_array(&rotation);
+  }
+
+  pub fn ik_rotation(&self, bone: usize) -> C::Vec4 {
+    self.ik[bone].widen()
+  }
+
+  /// Evaluates the bones of `pass` in order.
+  pub fn evaluate(&mut self, pass: PhysicsPass) {
+    self.evaluate_with(pass, |_, _| {})
+  }
+
+  /// Evaluates the bones of `pass` in order, calling `hook` after each bone. The hook can set the
+  /// IK rotations of the bones evaluated so far and update them with `update_bone`, the bones
+  /// after it see the changes.
+  pub fn evaluate_with(&mut self, pass: PhysicsPass, mut hook: impl FnMut(&mut Self, usize)) {
+    let range = match pass {
+      PhysicsPass::Before => 0..self.after_physics,
+      PhysicsPass::After => self.after_physics..self.order.len(),
+    };
+    for i in range {
+      let bone = self.order[i];
+      self.update_bone(bone);
+      hook(self, bone);
+    }
+  }
+
+  /// Recomputes the world transform of `bone` from its local transform, IK rotation and append,
+  /// and the world transform of its parent. Its children are left as they are.
+  pub fn update_bone(&mut self, bone: usize) {
+    let (mut translation, rotation) = self.input[bone];
+    let mut rotation = quat_mul(self.ik[bone], rotation);
+
+    let mut appended = ([0.0; 3], IDENTITY_ROTATION);
+    if let Some(a) = self.appends[bone] {
+      // Bones appending from a bone that appends itself take over only what it appended
+      let chained = !a.local && self.appends[a.parent].is_some();
+      if a.rotation {
+        let source = if chained {
+          self.appended[a.parent].1
+        } else {
+          self.input[a.parent].1
+        };
+        let source = quat_mul(self.ik[a.parent], source);
+        appended.1 = slerp(IDENTITY_ROTATION, source, a.rate);
+        rotation = quat_mul(rotation, appended.1);
+      }
+      if a.movement {
+        let source = if chained {
+          self.appended[a.parent].0
+        } else {
+          self.input[a.parent].0
+        };
+        appended.0 = source.map(|c| c * a.rate);
+        for (t, a) in translation.iter_mut().zip(appended.0) {
+          *t += a;
+        }
+      }
+    }
+    self.appended[bone] = appended;
+
+    let rest = self.rest[bone];
+    let local = rotation_translation(
+      rotation,
+      [
+        rest[0] + translation[0],
+        rest[1] + translation[1],
+        rest[2] + translation[2],
+      ],
+    );
+    self.world[bone] = match self.parents[bone] {
+      Some(parent) => mat4_mul(&self.world[parent], &local),
+      None => local,
+    };
+  }
+
+  /// `update_bone` for `bone` and all bones below it, parents before their children.
+  pub fn update_subtree(&mut self, bone: usize) {
+    let mut stack = vec![bone];
+    while let Some(bone) = stack.pop() {
+      self.update_bone(bone);
+      stack.extend(self.children[bone].iter().rev());
+    }
+  }
+
+  /// From the bone space of `bone` to the model space.
+  pub fn world(&self, bone: usize) -> &Matrix4 {
+    &self.world[bone]
+  }
+
+  pub fn worlds(&self) -> &[Matrix4] {
+    &self.world
+  }
+
+  /// Places `bone` in the model space, e.g. after physics moved its rigid body.
+  pub fn set_world(&mut self, bone: usize, world: Matrix4) {
+    self.world[bone] = world;
+  }
+
+  /// The position of `bone` in the model space.
+  pub fn position(&self, bone: usize) -> C::Vec3 {
+    let [x, y, z, _] = self.world[bone][3];
+    [x, y, z].widen()
+  }
+
+  /// The world transforms relative to the rest pose, which move the vertices bound to the bones.
+  pub fn skinning_matrices(&self) -> Vec<Matrix4> {
+    self
+      .world
+      .iter()
+      .zip(&self.inverse_bind)
+      .map(|(world, inverse_bind)| mat4_mul(world, inverse_bind))
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::pmx::bone::tests::bone;
+  use crate::pmx::bone::{Additional, BoneFlags};
+  use crate::vmd::MotionFrame;
+  use std::f32::consts::FRAC_PI_2;
+
+  const EPSILON: f32 = 1e-5;
+
+  fn chain() -> Vec<Bone<DefaultConfig>> {
+    vec![
+      bone("根", None, [0.0, 0.0, 0.0]),
+      bone("中", Some(0), [0.0, 1.0, 0.0]),
+      bone("先", Some(1), [0.0, 2.0, 0.0]),
+    ]
+  }
+
+  fn rotation_z(angle: f32) -> [f32; 4] {
+    let (sin, cos) = (angle * 0.5).sin_cos();
+    [0.0, 0.0, sin, cos]
+  }
+
+  fn assert_position(pose: &Pose, bone: usize, expected: [f32; 3]) {
+    let position = to_array::<3>(&pose.position(bone));
+    for (p, e) in position.iter().zip(expected) {
+      assert!((p - e).abs() < EPSILON, "{:?} != {:?}", position, expected);
+    }
+  }
+
+  fn evaluate(pose: &mut Pose) {
+    pose.evaluate(PhysicsPass::Before);
+    pose.evaluate(PhysicsPass::After);
+  }
+
+  #[test]
+  fn test_chain_keyframe() {
+    let bones = chain();
+    let tracks = BoneTrackSet::from_frames(vec![
+      MotionFrame::new("中", 0, [0.0; 3], IDENTITY_ROTATION),
+      MotionFrame::new("中", 10, [0.0; 3], rotation_z(FRAC_PI_2)),
+    ]);
+    let mut pose = Pose::new(&bones).unwrap();
+    evaluate(&mut pose);
+    assert_position(&pose, 2, [0.0, 2.0, 0.0]);
+
+    let locals = motion_locals(&bones, &tracks, 10.0);
+    assert!(locals[0].is_none() && locals[1].is_some());
+    pose.set_locals(&locals);
+    evaluate(&mut pose);
+    assert_position(&pose, 1, [0.0, 1.0, 0.0]);
+    assert_position(&pose, 2, [-1.0, 1.0, 0.0]);
+
+    // Halfway through the linear interpolation
+    pose.set_locals(&motion_locals(&bones, &tracks, 5.0));
+    evaluate(&mut pose);
+    let half = std::f32::consts::FRAC_1_SQRT_2;
+    assert_position(&pose, 2, [-half, 1.0 + half, 0.0]);
+
+    // The skinning matrices move the rest position of the tip to its posed one
+    let skinning = &pose.skinning_matrices()[2];
+    let tip = mat4_mul(skinning, &crate::math::translation([0.0, 2.0, 0.0]));
+    assert!((tip[3][0] + half).abs() < EPSILON);
+  }
+
+  #[test]
+  fn test_append_and_order() {
+    let mut bones = chain();
+    // Evaluated after the others by its level although it comes first
+    bones.insert(0, bone("付与", None, [1.0, 0.0, 0.0]));
+    for b in &mut bones[1..] {
+      b.parent = b.parent.map(|p| p + 1);
+    }
+    bones[0].transform_level = 1;
+    bones[0].bone_flags |= BoneFlags::AddRotation | BoneFlags::AddMovement;
+    bones[0].additional = Some(Additional {
+      parent: 2,
+      rate: 0.5,
+    });
+    bones[3].bone_flags |= BoneFlags::PhysicalTransform;
+
+    let mut pose = Pose::new(&bones).unwrap();
+    assert_eq!(pose.order(), [1, 2, 0, 3]);
+
+    let mut locals = vec![None; 4];
+    locals[2] = Some(LocalTransform {
+      translation: [0.0, 0.0, 2.0].into(),
+      rotation: rotation_z(FRAC_PI_2).into(),
+    });
+    pose.set_locals(&locals);
+    pose.evaluate(PhysicsPass::Before);
+    // Half the rotation and movement of 中
+    let q = to_array::<4>(&rotation_z(FRAC_PI_2 / 2.0));
+    let world = pose.world(0);
+    assert!((world[0][0] - q[3] * q[3] * 2.0 + 1.0).abs() < EPSILON);
+    assert_position(&pose, 0, [1.0, 0.0, 1.0]);
+    // The bone after physics is still at rest
+    assert_position(&pose, 3, [0.0, 2.0, 0.0]);
+    pose.evaluate(PhysicsPass::After);
+    assert_position(&pose, 3, [-1.0, 1.0, 2.0]);
+
+    bones[0].additional = Some(Additional {
+      parent: 9,
+      rate: 1.0,
+    });
+    assert!(matches!(
+      Pose::new(&bones),
+      Err(Error::AppendBoneOutOfRange { bone: 0, parent: 9 })
+    ));
+  }
+
+  #[test]
+  fn test_ik_hook() {
+    let bones = chain();
+    let mut pose = Pose::new(&bones).unwrap();
+    pose.evaluate_with(PhysicsPass::Before, |pose, bone| {
+      if bone == 1 {
+        pose.set_ik_rotation(1, rotation_z(-FRAC_PI_2).into());
+        pose.update_bone(1);
+      }
+    });
+    assert_position(&pose, 2, [1.0, 1.0, 0.0]);
+    assert_eq!(
+      to_array::<4>(&pose.ik_rotation(1)),
+      to_array::<4>(&rotation_z(-FRAC_PI_2))
+    );
+
+    // New locals clear the IK
+    pose.set_locals(&[]);
+    evaluate(&mut pose);
+    assert_position(&pose, 2, [0.0, 2.0, 0.0]);
+  }
+
+  #[test]
+  fn test_vpd_locals() {
+    let bones = chain();
+    let mut vpd = Vpd::<DefaultConfig>::new("pose".to_string());
+    for (name, angle) in [("先", 1.0), ("中", FRAC_PI_2), ("中", 0.0)] {
+      vpd.bone_transforms.push(crate::vpd::BoneTransform {
+        id: vpd.bone_transforms.len() as u32,
+        name: name.to_string(),
+        position: [0.0; 3].into(),
+        rotation: rotation_z(angle).into(),
+      });
+    }
+    let locals = vpd_locals(&bones, &vpd);
+    assert!(locals[0].is_none());
+    assert_eq!(
+      to_array::<4>(&locals[1].as_ref().unwrap().rotation),
+      rotation_z(FRAC_PI_2)
+    );
+  }
+}
@@ -0,0 +1,278 @@
+//! Conversion of rigid bodies and joints into `rapier3d` builders, behind the `rapier3d` feature.
+//!
+//! Nothing is inserted into a world: the caller adds the bodies with their colliders to its own
+//! sets and connects the joints to the handles it got for them. Positions stay in the
+//! coordinates of the model.
+
+use crate::math::{quat_from_euler_yxz, to_array};
+use crate::pmx::joint::{Joint, JointType};
+use crate::pmx::rigid_body::{PhysicsMode, RigidBody, ShapeType};
+use crate::pmx::types::index_to_usize;
+use crate::{Config, Error, Pmx, Result};
+use rapier3d::dynamics::{
+  GenericJointBuilder, JointAxesMask, JointAxis, MotorModel, RigidBodyBuilder,
+};
+use rapier3d::geometry::{
+  ColliderBuilder, Group, InteractionGroups, InteractionTestMode, SharedShape,
+};
+use rapier3d::math::{Pose, Rotation, Vector};
+use std::convert::TryInto;
+
+/// A rigid body with its collider, see `rigid_body`.
+#[derive(Clone, Debug)]
+pub struct RapierBody {
+  pub body: RigidBodyBuilder,
+  pub collider: ColliderBuilder,
+  /// The bone of the rigid body moves it rather than the simulation, for `PhysicsMode::Static`.
+  /// The body is fixed, so the caller has to set its position from the bone or make it
+  /// kinematic.
+  pub follows_bone: bool,
+}
+
+/// A joint between two rigid bodies, by their indices in the model, see `joint`.
+#[derive(Clone, Debug)]
+pub struct RapierJoint {
+  pub rigid_body_a: usize,
+  pub rigid_body_b: usize,
+  pub joint: GenericJointBuilder,
+}
+
+/// The world space pose of the shape of a rigid body, or of a joint.
+fn pose(position: [f32; 3], rotation: [f32; 3]) -> Pose {
+  Pose::from_parts(
+    Vector::from_array(position),
+    Rotation::from_array(quat_from_euler_yxz(rotation)),
+  )
+}
+
+fn rigid_body_pose<C: Config>(rigid_body: &RigidBody<C>) -> Pose {
+  pose(
+    to_array(&rigid_body.shape_position),
+    to_array(&rigid_body.shape_rotation),
+  )
+}
+
+/// The shape of a rigid body. Boxes are sized by their half extents like in MMD, capsules stand
+/// along Y, with a height that doesn't include the caps.
+pub fn shape<C: Config>(rigid_body: &RigidBody<C>) -> SharedShape {
+  let [x, y, z] = to_array(&rigid_body.shape_size);
+  match rigid_body.shape {
+    ShapeType::Sphere => SharedShape::ball(x),
+    ShapeType::Box => SharedShape::cuboid(x, y, z),
+    ShapeType::Capsule => SharedShape::capsule_y(y / 2.0, x),
+  }
+}
+
+/// The collision groups of a rigid body: a member of its `group_id`, colliding with the groups
+/// set in `non_collision_mask`.
+pub fn interaction_groups<C: Config>(rigid_body: &RigidBody<C>) -> InteractionGroups {
+  InteractionGroups::new(
+    Group::from_bits_truncate(1 << (rigid_body.group_id & 15)),
+    Group::from_bits_truncate(rigid_body.non_collision_mask.into()),
+    InteractionTestMode::And,
+  )
+}
+
+/// The builders of a rigid body, placed at its shape position. Static rigid bodies get a fixed
+/// body flagged with `follows_bone`, the others a dynamic one.
+pub fn rigid_body<C: Config>(rigid_body: &RigidBody<C>) -> RapierBody {
+  let body = match rigid_body.physics_mode {
+    PhysicsMode::Static => RigidBodyBuilder::fixed(),
+    PhysicsMode::Dynamic | PhysicsMode::DynamicPivoted => RigidBodyBuilder::dynamic(),
+  };
+  RapierBody {
+    body: body
+      .pose(rigid_body_pose(rigid_body))
+      .linear_damping(rigid_body.move_attenuation)
+      .angular_damping(rigid_body.rotation_damping),
+    collider: ColliderBuilder::new(shape(rigid_body))
+      .mass(rigid_body.mass)
+      .restitution(rigid_body.repulsion)
+      .friction(rigid_body.friction)
+      .collision_groups(interaction_groups(rigid_body)),
+    follows_bone: rigid_body.physics_mode == PhysicsMode::Static,
+  }
+}
+
+/// The builders of all rigid bodies of `pmx`, in order.
+pub fn rigid_bodies<C: Config>(pmx: &Pmx<C>) -> Vec<RapierBody> {
+  pmx.rigid_bodies.iter().map(rigid_body).collect()
+}
+
+/// A spring 6DOF joint between two of `rigid_bodies`, with its frame in the local space of both.
+///
+/// Axes with equal limits are locked and axes with a minimum above the maximum are free, the
+/// others are limited. The springs become force based motors towards the rest position of the
+/// joint. `JointType::Free` converts the same without the springs, the other joint types of
+/// PMX 2.1 fail with `Error::UnsupportedJointType`. Missing rigid bodies fail with
+/// `Error::JointBodyOutOfRange`.
+pub fn joint<C: Config>(rigid_bodies: &[RigidBody<C>], joint: &Joint<C>) -> Result<RapierJoint> {
+  let springs = match joint.joint_type {
+    JointType::SpringFree => true,
+    JointType::Free => false,
+    kind => return Err(Error::UnsupportedJointType(kind)),
+  };
+  let body = |index: &C::RigidbodyIndex| {
+    index_to_usize(index)
+      .filter(|&i| i < rigid_bodies.len())
+      .ok_or_else(|| Error::JointBodyOutOfRange {
+        joint: joint.name.ja.clone(),
+        rigid_body: index.clone().try_into().unwrap_or(i64::MAX),
+      })
+  };
+  let (a, b) = (body(&joint.rigid_body_a)?, body(&joint.rigid_body_b)?);
+
+  let axes = |axes: [JointAxis; 3], min: &C::Vec3, max: &C::Vec3, spring: &C::Vec3| {
+    let [min, max, spring]: [[f32; 3]; 3] = [to_array(min), to_array(max), to_array(spring)];
+    (0..3).map(move |i| (axes[i], min[i], max[i], spring[i]))
+  };
+  let axes: Vec<_> = axes(
+    [JointAxis::LinX, JointAxis::LinY, JointAxis::LinZ],
+    &joint.position_min,
+    &joint.position_max,
+    &joint.position_spring,
+  )
+  .chain(axes(
+    [JointAxis::AngX, JointAxis::AngY, JointAxis::AngZ],
+    &joint.rotation_min,
+    &joint.rotation_max,
+    &joint.rotation_spring,
+  ))
+  .collect();
+
+  let locked = axes
+    .iter()
+    .filter(|(_, min, max, _)| min == max)
+    .fold(JointAxesMask::empty(), |mask, &(axis, ..)| {
+      mask | axis.into()
+    });
+  let frame = pose(to_array(&joint.position), to_array(&joint.rotation));
+  let mut builder = GenericJointBuilder::new(locked)
+    .local_frame1(rigid_body_pose(&rigid_bodies[a]).inverse() * frame)
+    .local_frame2(rigid_body_pose(&rigid_bodies[b]).inverse() * frame);
+  for &(axis, min, max, stiffness) in axes.iter().filter(|(_, min, max, _)| min != max) {
+    if min < max {
+      builder = builder.limits(axis, [min, max]);
+    }
+    if springs && stiffness != 0.0 {
+      builder = builder
+        .motor_model(axis, MotorModel::ForceBased)
+        .motor_position(axis, 0.0, stiffness, 0.0);
+    }
+  }
+
+  Ok(RapierJoint {
+    rigid_body_a: a,
+    rigid_body_b: b,
+    joint: builder,
+  })
+}
+
+/// The builders of all joints of `pmx`, in order, failing like `joint`.
+pub fn joints<C: Config>(pmx: &Pmx<C>) -> Result<Vec<RapierJoint>> {
+  pmx
+    .joints
+    .iter()
+    .map(|j| joint(&pmx.rigid_bodies, j))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{DefaultConfig, LocalizedName};
+  use std::io::Cursor;
+
+  fn leg() -> RigidBody<DefaultConfig> {
+    RigidBody {
+      name: LocalizedName::new("右足", "leg_R"),
+      bone_index: Some(3),
+      group_id: 1,
+      non_collision_mask: !0b110,
+      shape: ShapeType::Capsule,
+      shape_size: [0.6, 3.0, 0.0].into(),
+      shape_position: [-1.0, 8.0, 0.0].into(),
+      shape_rotation: [0.0, 0.0, 0.0].into(),
+      mass: 2.0,
+      move_attenuation: 0.5,
+      rotation_damping: 0.5,
+      repulsion: 0.0,
+      friction: 0.5,
+      physics_mode: PhysicsMode::Dynamic,
+    }
+  }
+
+  #[test]
+  fn test_rigid_body() {
+    let converted = rigid_body(&leg());
+    assert!(!converted.follows_bone);
+    assert!(converted.body.build().is_dynamic());
+    assert_eq!(
+      converted.body.build().translation(),
+      Vector::new(-1.0, 8.0, 0.0)
+    );
+
+    let collider = converted.collider.build();
+    let capsule = collider.shape().as_capsule().unwrap();
+    assert_eq!(capsule.half_height(), 1.5);
+    assert_eq!(capsule.radius, 0.6);
+    assert_eq!(collider.mass(), 2.0);
+
+    let groups = collider.collision_groups();
+    assert_eq!(groups.memberships, Group::GROUP_2);
+    assert!(!groups.filter.contains(Group::GROUP_2));
+    assert!(!groups.filter.contains(Group::GROUP_3));
+    assert!(groups.filter.contains(Group::GROUP_1));
+    assert!(groups.test(interaction_groups(&RigidBody {
+      group_id: 0,
+      non_collision_mask: 0xffff,
+      ..leg()
+    })));
+    assert!(!groups.test(interaction_groups(&RigidBody {
+      group_id: 2,
+      non_collision_mask: 0xffff,
+      ..leg()
+    })));
+
+    let fixed = rigid_body(&RigidBody {
+      shape: ShapeType::Box,
+      physics_mode: PhysicsMode::Static,
+      ..leg()
+    });
+    assert!(fixed.follows_bone);
+    assert!(fixed.body.build().is_fixed());
+    let cuboid = fixed.collider.build();
+    let cuboid = cuboid.shape().as_cuboid().unwrap();
+    assert_eq!(cuboid.half_extents, Vector::new(0.6, 3.0, 0.0));
+  }
+
+  #[test]
+  fn test_joint() {
+    let pmx: Pmx = Pmx::read(Cursor::new(&include_bytes!("../../fixtures/model.pmx")[..])).unwrap();
+    assert_eq!(rigid_bodies(&pmx).len(), 1);
+    let joints = joints(&pmx).unwrap();
+    let converted = joints[0].joint.0;
+    assert_eq!((joints[0].rigid_body_a, joints[0].rigid_body_b), (0, 0));
+    // The position limits are all 0
+    assert_eq!(converted.locked_axes, JointAxesMask::LIN_AXES);
+    let limits = converted.limits(JointAxis::AngZ).unwrap();
+    assert_eq!((limits.min, limits.max), (-0.5, 0.5));
+    let motor = converted.motor(JointAxis::AngY).unwrap();
+    assert_eq!(
+      (motor.stiffness, motor.model),
+      (10.0, MotorModel::ForceBased)
+    );
+
+    let mut joint = pmx.joints[0].clone();
+    joint.rigid_body_b = 1;
+    assert!(matches!(
+      super::joint(&pmx.rigid_bodies, &joint),
+      Err(Error::JointBodyOutOfRange { rigid_body: 1, .. })
+    ));
+    joint.joint_type = JointType::Hinge;
+    assert!(matches!(
+      super::joint(&pmx.rigid_bodies, &joint),
+      Err(Error::UnsupportedJointType(JointType::Hinge))
+    ));
+  }
+}
@@ -1,15 +1,23 @@
+#[cfg(feature = "tokio")]
+pub mod async_read;
 pub mod bone;
 pub mod display;
 pub mod header;
-mod helpers;
+pub(crate) mod helpers;
 pub mod joint;
 pub mod material;
 pub mod morph;
+#[cfg(feature = "rayon")]
+mod parallel;
 pub mod rigid_body;
+pub mod slice;
+pub mod soft_body;
 pub mod surface;
 pub mod texture;
 pub mod vertex;
 
+#[cfg(feature = "tokio")]
+pub use async_read::{AsyncPmxReader, AsyncReadHelpers};
 pub use bone::BoneReader;
 pub use display::DisplayReader;
 pub use header::HeaderReader;
@@ -17,6 +25,8 @@ pub use joint::JointReader;
 pub use material::MaterialReader;
 pub use morph::MorphReader;
 pub use rigid_body::RigidBodyReader;
+pub use slice::SliceReader;
+pub use soft_body::SoftBodyReader;
 pub use surface::SurfaceReader;
 pub use texture::TextureReader;
 pub use vertex::VertexReader;
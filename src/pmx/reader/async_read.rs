@@ -0,0 +1,548 @@
+//! Reading from `tokio::io::AsyncRead`, behind the `tokio` feature.
+//!
+//! The records are decoded by the same code as the blocking readers, so both paths produce the
+//! same types and values.
+
+use crate::{
+  math::Widen,
+  pmx::display::DisplayFrame,
+  pmx::joint::Joint,
+  pmx::model::has_soft_bodies,
+  pmx::morph::Morph,
+  pmx::rigid_body::RigidBody,
+  pmx::soft_body::SoftBody,
+  pmx::types::*,
+  pmx::vertex::MAX_ADDITIONAL_VEC4S,
+  reader::helpers::{
+    decode_index, decode_optional_index, decode_text, decode_vertex_index, PositionReader,
+    MAX_TEXT_RESERVED,
+  },
+  reader::*,
+  vmd::decode_vec,
+  Bone, Config, DefaultConfig, Error, Material, Pmx, Result, Settings, Vertex,
+};
+use enumflags2::BitFlags;
+use std::convert::TryFrom;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+/// Bytes requested from the reader at least whenever a record doesn't fit the buffer.
+const CHUNK_SIZE: usize = 8192;
+
+const SECTIONS: [&str; 10] = [
+  "vertex",
+  "surface",
+  "texture",
+  "material",
+  "bone",
+  "morph",
+  "display frame",
+  "rigid body",
+  "joint",
+  "soft body",
+];
+
+/// A record in the buffer, decoded by the blocking readers.
+struct Record<'a, 'b> {
+  settings: Settings,
+  read: &'a mut &'b [u8],
+  /// The offset of the record in the file.
+  offset: u64,
+  /// The `count - remaining` of the section at the record.
+  index: i32,
+}
+
+/// A blocking reader over a single record, placed so that its errors have the offset and index
+/// of the record.
+macro_rules! reader {
+  ($reader:ident, $record:expr) => {
+    reader!($reader, $record, 1)
+  };
+  ($reader:ident, $record:expr, $step:expr) => {
+    $reader {
+      settings: $record.settings,
+      count: $record.index + $step,
+      remaining: $step,
+      read: PositionReader::new($record.read, $record.offset),
+      poison: false,
+    }
+  };
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for PositionReader<R> {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<std::io::Result<()>> {
+    let filled = buf.filled().len();
+    let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+    self.position += (buf.filled().len() - filled) as u64;
+    result
+  }
+}
+
+/// `ReadHelpers` for async readers.
+// The futures are `Send` whenever the reader is, which callers see through the blanket impl
+#[allow(async_fn_in_trait)]
+pub trait AsyncReadHelpers: AsyncRead + Unpin {
+  async fn read_text(&mut self, encoding: TextEncoding) -> Result<String> {
+    let size = self.read_i32_le().await?;
+    let size = usize::try_from(size).map_err(|_| Error::InvalidTextLength(size))?;
+    let mut buf = Vec::with_capacity(size.min(MAX_TEXT_RESERVED));
+    AsyncReadExt::take(&mut *self, size as u64)
+      .read_to_end(&mut buf)
+      .await?;
+    if buf.len() < size {
+      return Err(Error::TruncatedText {
+        expected: size,
+        read: buf.len(),
+      });
+    }
+    decode_text(&buf, encoding)
+  }
+
+  async fn read_vec2<C: Config>(&mut self) -> Result<C::Vec2> {
+    let mut buf = [0; 8];
+    self.read_exact(&mut buf).await?;
+    Ok(decode_vec::<2>(&buf).widen())
+  }
+
+  async fn read_vec3<C: Config>(&mut self) -> Result<C::Vec3> {
+    let mut buf = [0; 12];
+    self.read_exact(&mut buf).await?;
+    Ok(decode_vec::<3>(&buf).widen())
+  }
+
+  async fn read_vec4<C: Config>(&mut self) -> Result<C::Vec4> {
+    let mut buf = [0; 16];
+    self.read_exact(&mut buf).await?;
+    Ok(decode_vec::<4>(&buf).widen())
+  }
+
+  async fn read_index<I: Index>(&mut self, size: IndexSize) -> Result<I> {
+    let mut buf = [0; 4];
+    self.read_exact(&mut buf[..size as usize]).await?;
+    decode_index(&buf, size)
+  }
+
+  /// Reads an index where -1 at the width of `size` means none.
+  async fn read_optional_index<I: Index>(&mut self, size: IndexSize) -> Result<Option<I>> {
+    let mut buf = [0; 4];
+    self.read_exact(&mut buf[..size as usize]).await?;
+    decode_optional_index(&buf, size)
+  }
+
+  async fn read_vertex_index<I: VertexIndex>(&mut self, size: IndexSize) -> Result<I> {
+    let mut buf = [0; 4];
+    self.read_exact(&mut buf[..size as usize]).await?;
+    decode_vertex_index(&buf, size)
+  }
+}
+
+impl<R: AsyncRead + Unpin + ?Sized> AsyncReadHelpers for R {}
+
+/// Reads a model section by section from an async reader.
+///
+/// The sections are read in file order, calling a section method skips the sections before it
+/// and fails with `Error::SectionAlreadyRead` for sections already passed.
+///
+/// The section methods may be cancelled between records: the reader continues with the first
+/// record the cancelled call hadn't decoded yet, the records it had decoded are lost. Cancelling
+/// `AsyncPmxReader::new` loses the header, start again with a new reader then.
+pub struct AsyncPmxReader<R> {
+  /// The header, with `()` in place of the reader.
+  pub header: HeaderReader<()>,
+  read: PositionReader<R>,
+  buf: Vec<u8>,
+  start: usize,
+  eof: bool,
+  /// The next section whose count is unread.
+  next: usize,
+  /// The section being read, with its count read.
+  current: Option<usize>,
+  count: i32,
+  remaining: i32,
+  poison: bool,
+}
+
+impl<R: AsyncRead + Unpin> AsyncPmxReader<R> {
+  pub async fn new(read: R) -> Result<AsyncPmxReader<R>> {
+    let mut read = PositionReader::new(read, 0);
+    let header = match read_header(&mut read).await {
+      Ok(header) => header,
+      Err(e) => return Err(e.context(read.position, "header", None)),
+    };
+
+    Ok(AsyncPmxReader {
+      header,
+      read,
+      buf: Vec::new(),
+      start: 0,
+      eof: false,
+      next: 0,
+      current: None,
+      count: 0,
+      remaining: 0,
+      poison: false,
+    })
+  }
+
+  pub async fn vertices<C: Config>(&mut self) -> Result<Vec<Vertex<C>>> {
+    self
+      .section(0, 1, |r| one(reader!(VertexReader, r).next::<C>()))
+      .await
+  }
+
+  pub async fn surfaces<C: Config>(&mut self) -> Result<Vec<[C::VertexIndex; 3]>> {
+    self
+      .section(1, 3, |r| one(reader!(SurfaceReader, r, 3).next::<C>()))
+      .await
+  }
+
+  pub async fn textures(&mut self) -> Result<Vec<String>> {
+    self
+      .section(2, 1, |r| one(reader!(TextureReader, r).next()))
+      .await
+  }
+
+  pub async fn materials<C: Config>(&mut self) -> Result<Vec<Material<C>>> {
+    self
+      .section(3, 1, |r| one(reader!(MaterialReader, r).next::<C>()))
+      .await
+  }
+
+  pub async fn bones<C: Config>(&mut self) -> Result<Vec<Bone<C>>> {
+    self
+      .section(4, 1, |r| one(reader!(BoneReader, r).next::<C>()))
+      .await
+  }
+
+  pub async fn morphs<C: Config>(&mut self) -> Result<Vec<Morph<C>>> {
+    self
+      .section(5, 1, |r| one(reader!(MorphReader, r).next::<C>()))
+      .await
+  }
+
+  pub async fn display_frames<C: Config>(&mut self) -> Result<Vec<DisplayFrame<C>>> {
+    self
+      .section(6, 1, |r| one(reader!(DisplayReader, r).next::<C>()))
+      .await
+  }
+
+  pub async fn rigid_bodies<C: Config>(&mut self) -> Result<Vec<RigidBody<C>>> {
+    self
+      .section(7, 1, |r| one(reader!(RigidBodyReader, r).next::<C>()))
+      .await
+  }
+
+  pub async fn joints<C: Config>(&mut self) -> Result<Vec<Joint<C>>> {
+    self
+      .section(8, 1, |r| one(reader!(JointReader, r).next::<C>()))
+      .await
+  }
+
+  /// Always empty for PMX 2.0, which has no soft body section.
+  pub async fn soft_bodies<C: Config>(&mut self) -> Result<Vec<SoftBody<C>>> {
+    if !has_soft_bodies(self.header.version) {
+      return Ok(Vec::new());
+    }
+    self
+      .section(9, 1, |r| one(reader!(SoftBodyReader, r).next::<C>()))
+      .await
+  }
+
+  async fn section<T>(
+    &mut self,
+    section: usize,
+    step: i32,
+    mut decode: impl FnMut(Record) -> Result<T>,
+  ) -> Result<Vec<T>> {
+    self.seek(section).await?;
+    let mut list = Vec::new();
+    while self.remaining > 0 {
+      list.push(self.record(&mut decode).await?);
+      self.remaining -= step;
+    }
+    self.current = None;
+    Ok(list)
+  }
+
+  /// Reads up to the first record of `section`, skipping the records before it.
+  async fn seek(&mut self, section: usize) -> Result<()> {
+    loop {
+      if self.poison {
+        return Err(Error::Poisoned);
+      }
+      match self.current {
+        Some(current) if current == section => return Ok(()),
+        Some(current) => {
+          while self.remaining > 0 {
+            self.record(|r| skip(current, r)).await?;
+            self.remaining -= if current == 1 { 3 } else { 1 };
+          }
+          self.current = None;
+        }
+        None if self.next > section => {
+          return Err(Error::SectionAlreadyRead(SECTIONS[section]));
+        }
+        None => {
+          let next = self.next;
+          self.count = self
+            .record(|r| {
+              let mut read = PositionReader::new(r.read, r.offset);
+              match next {
+                1 => surface::read_surface_count(&mut read),
+                _ => read.read_count(SECTIONS[next]),
+              }
+            })
+            .await?;
+          self.remaining = self.count;
+          self.current = Some(self.next);
+          self.next += 1;
+        }
+      }
+    }
+  }
+
+  /// Decodes the record at the start of the buffer, reading more until it fits.
+  async fn record<T>(&mut self, mut decode: impl FnMut(Record) -> Result<T>) -> Result<T> {
+    loop {
+      let mut read = &self.buf[self.start..];
+      let offset = self.read.position - read.len() as u64;
+      let result = decode(Record {
+        settings: self.header.settings,
+        read: &mut read,
+        offset,
+        index: self.count - self.remaining,
+      });
+      match result {
+        Ok(value) => {
+          self.start = self.buf.len() - read.len();
+          return Ok(value);
+        }
+        Err(e) if !self.eof && is_truncated(e.root()) => {}
+        Err(e) => {
+          self.poison = true;
+          return Err(e);
+        }
+      }
+      if let Err(e) = self.fill().await {
+        self.poison = true;
+        return Err(e);
+      }
+    }
+  }
+
+  async fn fill(&mut self) -> Result<()> {
+    self.buf.drain(..self.start);
+    self.start = 0;
+    // Doubling what's requested keeps retrying long records linear
+    let wanted = self.buf.len().max(CHUNK_SIZE);
+    self.buf.reserve(wanted);
+    let target = self.buf.len() + wanted;
+    while self.buf.len() < target {
+      // `read_buf` only appends what it read, so cancelling here keeps the buffer intact
+      if self.read.read_buf(&mut self.buf).await? == 0 {
+        self.eof = true;
+        break;
+      }
+    }
+    Ok(())
+  }
+}
+
+impl<C: Config> Pmx<C> {
+  /// `Pmx::read` for async readers.
+  pub async fn read_async<R: AsyncRead + Unpin>(read: R) -> Result<Self> {
+    let mut reader = AsyncPmxReader::new(read).await?;
+    let vertices = reader.vertices().await?;
+    let surfaces = reader.surfaces::<C>().await?;
+    let textures = reader.textures().await?;
+    let materials = reader.materials().await?;
+    let bones = reader.bones().await?;
+    let morphs = reader.morphs().await?;
+    let display_frames = reader.display_frames().await?;
+    let rigid_bodies = reader.rigid_bodies().await?;
+    let joints = reader.joints().await?;
+    let soft_bodies = reader.soft_bodies().await?;
+
+    let header = reader.header;
+    Ok(Pmx {
+      version: header.version,
+      settings: header.settings,
+      model_local_name: header.model_local_name,
+      model_universal_name: header.model_universal_name,
+      local_comments: header.local_comments,
+      universal_comments: header.universal_comments,
+      extra_globals: header.extra_globals,
+      vertices,
+      surfaces,
+      textures,
+      materials,
+      bones,
+      morphs,
+      display_frames,
+      rigid_bodies,
+      joints,
+      soft_bodies,
+      skipped_sections: BitFlags::empty(),
+    })
+  }
+}
+
+async fn read_header<R: AsyncRead + Unpin>(read: &mut R) -> Result<HeaderReader<()>> {
+  let mut magic = [0u8; 4];
+  read.read_exact(&mut magic).await?;
+  if magic != [0x50, 0x4D, 0x58, 0x20] {
+    return Err(Error::WrongSignature(magic));
+  }
+
+  let version = read.read_f32_le().await?;
+  let globals_count = read.read_u8().await?;
+  if globals_count < 8 {
+    return Err(Error::GlobalsCountLessThan8(globals_count));
+  }
+
+  let mut globals = vec![0u8; globals_count as usize];
+  read.read_exact(&mut globals).await?;
+
+  if globals[1] > MAX_ADDITIONAL_VEC4S {
+    return Err(Error::InvalidAdditionalVec4Count(globals[1]));
+  }
+
+  let settings = Settings {
+    text_encoding: TextEncoding::try_from(globals[0])?,
+    additional_vec4_count: globals[1],
+    vertex_index_size: IndexSize::try_from(globals[2])?,
+    texture_index_size: IndexSize::try_from(globals[3])?,
+    material_index_size: IndexSize::try_from(globals[4])?,
+    bone_index_size: IndexSize::try_from(globals[5])?,
+    morph_index_size: IndexSize::try_from(globals[6])?,
+    rigidbody_index_size: IndexSize::try_from(globals[7])?,
+  };
+  Ok(HeaderReader {
+    version,
+    settings,
+    model_local_name: read.read_text(settings.text_encoding).await?,
+    model_universal_name: read.read_text(settings.text_encoding).await?,
+    local_comments: read.read_text(settings.text_encoding).await?,
+    universal_comments: read.read_text(settings.text_encoding).await?,
+    extra_globals: globals.split_off(8),
+    read: PositionReader::new((), 0),
+  })
+}
+
+/// Whether the record may fit once more is read.
+fn is_truncated(e: &Error) -> bool {
+  match e {
+    Error::Io(e) => e.kind() == std::io::ErrorKind::UnexpectedEof,
+    Error::TruncatedText { .. } => true,
+    _ => false,
+  }
+}
+
+fn one<T>(record: Result<Option<T>>) -> Result<T> {
+  // The readers are created with one record remaining, so this only fails with a broken reader
+  record.and_then(|r| {
+    r.ok_or(Error::UnexpectedEnd {
+      needed: 1,
+      available: 0,
+    })
+  })
+}
+
+fn skip(section: usize, r: Record) -> Result<()> {
+  match section {
+    0 => one(reader!(VertexReader, r).next::<DefaultConfig>()).map(drop),
+    1 => one(reader!(SurfaceReader, r, 3).next::<DefaultConfig>()).map(drop),
+    2 => one(reader!(TextureReader, r).next()).map(drop),
+    3 => one(reader!(MaterialReader, r).next::<DefaultConfig>()).map(drop),
+    4 => one(reader!(BoneReader, r).next::<DefaultConfig>()).map(drop),
+    5 => one(reader!(MorphReader, r).next::<DefaultConfig>()).map(drop),
+    6 => one(reader!(DisplayReader, r).next::<DefaultConfig>()).map(drop),
+    7 => one(reader!(RigidBodyReader, r).next::<DefaultConfig>()).map(drop),
+    8 => one(reader!(JointReader, r).next::<DefaultConfig>()).map(drop),
+    _ => one(reader!(SoftBodyReader, r).next::<DefaultConfig>()).map(drop),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::pmd::Pmd;
+  use std::io::Cursor;
+  use tokio::io::BufReader;
+
+  const FIXTURE_MODEL_PMD: &[u8] = include_bytes!("../../../fixtures/model.pmd");
+  const FIXTURE_MODEL_PMX: &[u8] = include_bytes!("../../../fixtures/model.pmx");
+
+  #[tokio::test]
+  async fn test_read_async() {
+    let expected = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    // A tiny buffer splits most records between reads
+    let read = BufReader::with_capacity(7, Cursor::new(FIXTURE_MODEL_PMX));
+    let pmx = Pmx::<DefaultConfig>::read_async(read).await.unwrap();
+    assert_eq!(pmx, expected);
+
+    let pmx = Pmx::<DefaultConfig>::read_async(FIXTURE_MODEL_PMX)
+      .await
+      .unwrap();
+    assert_eq!(pmx, expected);
+  }
+
+  #[tokio::test]
+  async fn test_read_async_pmx_20() {
+    let expected = Pmd::<DefaultConfig>::read(Cursor::new(FIXTURE_MODEL_PMD))
+      .unwrap()
+      .into_pmx()
+      .unwrap();
+    let mut bytes = Vec::new();
+    expected.write(&mut bytes).unwrap();
+
+    let read = BufReader::new(Cursor::new(bytes));
+    let pmx = Pmx::<DefaultConfig>::read_async(read).await.unwrap();
+    assert_eq!(pmx, expected);
+  }
+
+  #[tokio::test]
+  async fn test_read_async_sections() {
+    let expected = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    let read = BufReader::with_capacity(16, Cursor::new(FIXTURE_MODEL_PMX));
+    let mut reader = AsyncPmxReader::new(read).await.unwrap();
+    assert_eq!(
+      reader.header.model_universal_name,
+      expected.model_universal_name
+    );
+
+    let morphs = reader.morphs::<DefaultConfig>().await.unwrap();
+    assert_eq!(morphs, expected.morphs);
+    assert!(matches!(
+      reader.vertices::<DefaultConfig>().await,
+      Err(Error::SectionAlreadyRead("vertex"))
+    ));
+    let joints = reader.joints::<DefaultConfig>().await.unwrap();
+    assert_eq!(joints, expected.joints);
+  }
+
+  #[test]
+  fn test_read_async_send() {
+    fn assert_send<T: Send>(_: T) {}
+    assert_send(Pmx::<DefaultConfig>::read_async(Cursor::new(Vec::new())));
+  }
+
+  #[tokio::test]
+  async fn test_read_async_truncated() {
+    let truncated = &FIXTURE_MODEL_PMX[..FIXTURE_MODEL_PMX.len() - 10];
+    let mut reader = AsyncPmxReader::new(Cursor::new(truncated)).await.unwrap();
+    let e = reader.joints::<DefaultConfig>().await.unwrap_err();
+    assert!(matches!(e.root(), Error::Io(_)));
+    assert_eq!(e.location().unwrap().section, "joint");
+    assert_eq!(e.location().unwrap().offset, truncated.len() as u64);
+    assert!(matches!(
+      reader.soft_bodies::<DefaultConfig>().await,
+      Err(Error::Poisoned)
+    ));
+  }
+}
@@ -1,28 +1,35 @@
+use crate::io::{Read, ReadBytesExt};
 use crate::{
   pmx::bone::*,
-  reader::{helpers::ReadHelpers, MaterialReader},
-  Config, DefaultConfig, Error, Result, Settings,
+  reader::{
+    helpers::{reserve, PositionReader, ReadHelpers},
+    MaterialReader,
+  },
+  Config, DefaultConfig, Error, LocalizedName, Result, Settings,
 };
-use byteorder::{ReadBytesExt, LE};
+use byteorder::LE;
+use core::marker::PhantomData;
 use enumflags2::BitFlags;
-use std::marker::PhantomData;
-use std::{io::Read, usize};
+
+pub(crate) const SECTION: &str = "bone";
 
 pub struct BoneReader<R> {
   pub settings: Settings,
   pub count: i32,
   pub remaining: i32,
-  pub(crate) read: R,
+  pub(crate) read: PositionReader<R>,
   pub(crate) poison: bool,
 }
 
 impl<R: Read> BoneReader<R> {
   pub fn new(mut m: MaterialReader<R>) -> Result<BoneReader<R>> {
-    assert!(!m.poison);
+    if m.poison {
+      return Err(Error::Poisoned);
+    }
     while m.remaining > 0 {
       m.next::<DefaultConfig>()?;
     }
-    let count = m.read.read_i32::<LE>()?;
+    let count = m.read.read_count(SECTION)?;
 
     Ok(BoneReader {
       settings: m.settings,
@@ -34,12 +41,15 @@ impl<R: Read> BoneReader<R> {
   }
 
   pub fn next<C: Config>(&mut self) -> Result<Option<Bone<C>>> {
-    assert!(!self.poison);
+    if self.poison {
+      return Err(Error::Poisoned);
+    }
+    let index = (self.count - self.remaining) as usize;
     let result = self.next_impl::<C>();
     if result.is_err() {
       self.poison = true;
     }
-    result
+    result.map_err(|e| e.context(self.read.position, SECTION, Some(index)))
   }
 
   fn next_impl<C: Config>(&mut self) -> Result<Option<Bone<C>>> {
@@ -52,18 +62,21 @@ impl<R: Read> BoneReader<R> {
     let local_name = self.read.read_text(self.settings.text_encoding)?;
     let universal_name = self.read.read_text(self.settings.text_encoding)?;
     let position = self.read.read_vec3::<C>()?;
-    let parent = self.read.read_index(self.settings.bone_index_size)?;
+    let parent = self
+      .read
+      .read_optional_index(self.settings.bone_index_size)?;
     let transform_level = self.read.read_i32::<LE>()?;
-    let bone_flags = BitFlags::from_bits(self.read.read_u16::<LE>()?).unwrap();
+    let bone_flags = BitFlags::from_bits_truncate(self.read.read_u16::<LE>()?);
 
-    let connection = bone_flags
-      .contains(BoneFlags::Connection)
-      .then(|| {
-        Ok::<_, Error>(Connection::Index(
-          self.read.read_index(self.settings.bone_index_size)?,
-        ))
-      })
-      .unwrap_or_else(|| Ok::<_, Error>(Connection::Position(self.read.read_vec3::<C>()?)))?;
+    let connection = if bone_flags.contains(BoneFlags::Connection) {
+      Connection::Index(
+        self
+          .read
+          .read_optional_index(self.settings.bone_index_size)?,
+      )
+    } else {
+      Connection::Position(self.read.read_vec3::<C>()?)
+    };
 
     let additional = bone_flags
       .intersects(BoneFlags::AddRotation | BoneFlags::AddMovement)
@@ -99,14 +112,17 @@ impl<R: Read> BoneReader<R> {
       let ik_bone = self.read.read_index(self.settings.bone_index_size)?;
       let iterations = self.read.read_u32::<LE>()?;
       let limit_angle = self.read.read_f32::<LE>()?;
-      let link_count = self.read.read_u32::<LE>()? as usize;
-      let mut links = Vec::with_capacity(link_count);
+      let link_count = self.read.read_u32::<LE>()?;
+      let mut links = reserve(link_count);
       for _i in 0..link_count {
         let ik_bone = self
           .read
           .read_index::<C::BoneIndex>(self.settings.bone_index_size)?;
         let limits = if self.read.read_u8()? != 0 {
-          Some((self.read.read_vec3::<C>()?, self.read.read_vec3::<C>()?))
+          Some(AngleLimits {
+            lower: self.read.read_vec3::<C>()?,
+            upper: self.read.read_vec3::<C>()?,
+          })
         } else {
           None
         };
@@ -124,8 +140,10 @@ impl<R: Read> BoneReader<R> {
     };
 
     Ok(Some(Bone {
-      local_name,
-      universal_name,
+      name: LocalizedName {
+        ja: local_name,
+        en: universal_name,
+      },
       position,
       parent,
       transform_level,
@@ -139,7 +157,7 @@ impl<R: Read> BoneReader<R> {
     }))
   }
 
-  pub fn iter<C>(&mut self) -> BoneIterator<R, C> {
+  pub fn iter<C>(&mut self) -> BoneIterator<'_, R, C> {
     BoneIterator {
       reader: self,
       phantom: PhantomData,
@@ -164,14 +182,81 @@ impl<R: Read, C: Config> Iterator for BoneIterator<'_, R, C> {
 
   fn size_hint(&self) -> (usize, Option<usize>) {
     (
-      self.reader.remaining as usize,
-      Some(self.reader.remaining as usize),
+      self.reader.remaining.max(0) as usize,
+      Some(self.reader.remaining.max(0) as usize),
     )
   }
 }
 
 impl<R: Read, C: Config> ExactSizeIterator for BoneIterator<'_, R, C> {
   fn len(&self) -> usize {
-    self.reader.remaining as usize
+    self.reader.remaining.max(0) as usize
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::Pmx;
+
+  const FIXTURE_MODEL_AXES_PMX: &[u8] = include_bytes!("../../../fixtures/model_axes.pmx");
+
+  #[test]
+  fn test_read_axes() {
+    let pmx = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_AXES_PMX).unwrap();
+
+    let ankle = &pmx.bones[3];
+    assert!(ankle.has_local_axis() && !ankle.has_fixed_axis());
+    let axis = ankle.local_axis.unwrap();
+    assert_eq!(crate::math::to_array::<3>(&axis.x), [1.0, 0.0, 0.0]);
+    assert_eq!(crate::math::to_array::<3>(&axis.z), [0.0, 0.0, 1.0]);
+
+    let toe = &pmx.bones[5];
+    assert!(toe.has_fixed_axis() && !toe.has_local_axis());
+    assert_eq!(
+      crate::math::to_array::<3>(&toe.fixed_axis.unwrap()),
+      [0.0, 0.0, -1.0]
+    );
+    assert!(toe.is_rotatable() && !toe.is_movable() && !toe.has_tail_bone());
+    assert!(toe.bone_flags.contains(BoneFlags::Unknown15));
+    assert_eq!(toe.raw_flags(), 0b1000_0100_0000_0010);
+
+    // The next bone is read from the right place after the optional fields
+    assert!(pmx.bones[4].is_ik());
+    assert_eq!(
+      pmx.bones[4]
+        .inverse_kinematics
+        .as_ref()
+        .unwrap()
+        .links
+        .len(),
+      2
+    );
+  }
+
+  #[test]
+  fn test_read_ik_limits() {
+    let pmx = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_AXES_PMX).unwrap();
+    let ik = pmx.bones[4].inverse_kinematics.as_ref().unwrap();
+    assert_eq!(ik.iterations, 40);
+    assert_eq!(ik.limit_angle, 2.0);
+
+    let knee = ik.links[0].limits.unwrap();
+    assert_eq!(pmx.bones[ik.links[0].ik_bone as usize].name.ja, "左ひざ");
+    let (lower, upper) = knee.limits_degrees();
+    assert!((lower[0] + 180.0).abs() < 1e-3);
+    assert!(upper[0] < 0.0 && upper[0] > -1.0);
+    assert_eq!([lower[1], lower[2], upper[1], upper[2]], [0.0; 4]);
+    assert_eq!(knee.reversed_axes().count(), 0);
+    assert_eq!(ik.links[1].limits, None);
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn test_write_raw_flags() {
+    let pmx = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_AXES_PMX).unwrap();
+    let mut bytes = Vec::new();
+    pmx.write(&mut bytes).unwrap();
+    assert_eq!(bytes, FIXTURE_MODEL_AXES_PMX);
   }
 }
@@ -1,27 +1,34 @@
+use crate::io::{Read, ReadBytesExt};
 use crate::{
   pmx::display::*,
-  reader::{helpers::ReadHelpers, MorphReader},
-  Config, DefaultConfig, Error, Result, Settings,
+  reader::{
+    helpers::{reserve, PositionReader, ReadHelpers},
+    MorphReader,
+  },
+  Config, DefaultConfig, Error, LocalizedName, Result, Settings,
 };
-use byteorder::{ReadBytesExt, LE};
-use std::io::Read;
-use std::marker::PhantomData;
+use byteorder::LE;
+use core::marker::PhantomData;
+
+pub(crate) const SECTION: &str = "display frame";
 
 pub struct DisplayReader<R> {
   pub settings: Settings,
   pub count: i32,
   pub remaining: i32,
-  pub(crate) read: R,
+  pub(crate) read: PositionReader<R>,
   pub(crate) poison: bool,
 }
 
 impl<R: Read> DisplayReader<R> {
   pub fn new(mut m: MorphReader<R>) -> Result<DisplayReader<R>> {
-    assert!(!m.poison);
+    if m.poison {
+      return Err(Error::Poisoned);
+    }
     while m.remaining > 0 {
       m.next::<DefaultConfig>()?;
     }
-    let count = m.read.read_i32::<LE>()?;
+    let count = m.read.read_count(SECTION)?;
 
     Ok(DisplayReader {
       settings: m.settings,
@@ -33,12 +40,15 @@ impl<R: Read> DisplayReader<R> {
   }
 
   pub fn next<C: Config>(&mut self) -> Result<Option<DisplayFrame<C>>> {
-    assert!(!self.poison);
+    if self.poison {
+      return Err(Error::Poisoned);
+    }
+    let index = (self.count - self.remaining) as usize;
     let result = self.next_impl::<C>();
     if result.is_err() {
       self.poison = true;
     }
-    result
+    result.map_err(|e| e.context(self.read.position, SECTION, Some(index)))
   }
 
   fn next_impl<C: Config>(&mut self) -> Result<Option<DisplayFrame<C>>> {
@@ -51,28 +61,35 @@ impl<R: Read> DisplayReader<R> {
     let local_name = self.read.read_text(self.settings.text_encoding)?;
     let universal_name = self.read.read_text(self.settings.text_encoding)?;
     let special_flag = self.read.read_u8()? != 0;
-    let frame_count = self.read.read_u32::<LE>()?;
-    let mut frames = Vec::with_capacity(frame_count as usize);
-
-    for _ in 0..frame_count {
-      let frame = match self.read.read_u8()? {
-        0 => Frame::Bone(self.read.read_index(self.settings.bone_index_size)?),
-        1 => Frame::Morph(self.read.read_index(self.settings.morph_index_size)?),
-        e => return Err(Error::InvalidFrameType(e)),
+    let element_count = self.read.read_u32::<LE>()?;
+    let mut elements = reserve(element_count);
+
+    for _ in 0..element_count {
+      let element = match self.read.read_u8()? {
+        0 => DisplayElement::Bone(self.read.read_index(self.settings.bone_index_size)?),
+        1 => DisplayElement::Morph(self.read.read_index(self.settings.morph_index_size)?),
+        kind => {
+          return Err(Error::InvalidFrameType {
+            kind,
+            frame: self.count - self.remaining - 1,
+          })
+        }
       };
 
-      frames.push(frame);
+      elements.push(element);
     }
 
     Ok(Some(DisplayFrame {
-      local_name,
-      universal_name,
+      name: LocalizedName {
+        ja: local_name,
+        en: universal_name,
+      },
       special_flag,
-      frames,
+      elements,
     }))
   }
 
-  pub fn iter<C>(&mut self) -> DisplayIterator<R, C> {
+  pub fn iter<C>(&mut self) -> DisplayIterator<'_, R, C> {
     DisplayIterator {
       reader: self,
       phantom: PhantomData,
@@ -97,14 +114,87 @@ impl<R: Read, C: Config> Iterator for DisplayIterator<'_, R, C> {
 
   fn size_hint(&self) -> (usize, Option<usize>) {
     (
-      self.reader.remaining as usize,
-      Some(self.reader.remaining as usize),
+      self.reader.remaining.max(0) as usize,
+      Some(self.reader.remaining.max(0) as usize),
     )
   }
 }
 
 impl<R: Read, C: Config> ExactSizeIterator for DisplayIterator<'_, R, C> {
   fn len(&self) -> usize {
-    self.reader.remaining as usize
+    self.reader.remaining.max(0) as usize
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::reader::{
+    BoneReader, HeaderReader, MaterialReader, SurfaceReader, TextureReader, VertexReader,
+  };
+  use crate::ErrorLocation;
+
+  const FIXTURE_MODEL_PMX: &[u8] = include_bytes!("../../../fixtures/model.pmx");
+
+  fn display_reader<'a, 'b>(read: &'a mut &'b [u8]) -> DisplayReader<&'a mut &'b [u8]> {
+    let vertices = VertexReader::new(HeaderReader::new(read).unwrap()).unwrap();
+    let textures = TextureReader::new(SurfaceReader::new(vertices).unwrap()).unwrap();
+    let bones = BoneReader::new(MaterialReader::new(textures).unwrap()).unwrap();
+    DisplayReader::new(MorphReader::new(bones).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn test_read_display_frames() {
+    let mut read = FIXTURE_MODEL_PMX;
+    let mut display_frames = display_reader(&mut read);
+    let display_frames = display_frames
+      .iter::<DefaultConfig>()
+      .collect::<Result<Vec<_>>>()
+      .unwrap();
+
+    assert_eq!(display_frames[0].name.ja, "Root");
+    assert_eq!(display_frames[0].elements, [DisplayElement::Bone(0)]);
+    assert_eq!(display_frames[1].name.ja, "表情");
+    assert!(display_frames[1].special_flag);
+    assert_eq!(
+      display_frames[1].elements,
+      [DisplayElement::Morph(0), DisplayElement::Morph(1)]
+    );
+  }
+
+  #[test]
+  fn test_read_invalid_element_type() {
+    let mut read = FIXTURE_MODEL_PMX;
+    let mut display_frames = display_reader(&mut read);
+    display_frames.next::<DefaultConfig>().unwrap();
+    let mut tag = display_frames.read.position as usize;
+
+    // Skip both names, the flag and the element count of the second frame
+    for _ in 0..2 {
+      let mut len = [0; 4];
+      len.copy_from_slice(&FIXTURE_MODEL_PMX[tag..tag + 4]);
+      tag += 4 + i32::from_le_bytes(len) as usize;
+    }
+    tag += 1 + 4;
+    let mut bytes = FIXTURE_MODEL_PMX.to_vec();
+    assert_eq!(bytes[tag], 1);
+    bytes[tag] = 2;
+
+    let mut read = &bytes[..];
+    let mut display_frames = display_reader(&mut read);
+    assert!(display_frames.next::<DefaultConfig>().is_ok());
+    let e = display_frames.next::<DefaultConfig>().unwrap_err();
+    assert!(matches!(
+      e.root(),
+      Error::InvalidFrameType { kind: 2, frame: 1 }
+    ));
+    assert_eq!(
+      e.location(),
+      Some(&ErrorLocation {
+        offset: tag as u64 + 1,
+        section: "display frame",
+        index: Some(1),
+      })
+    );
   }
 }
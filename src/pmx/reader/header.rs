@@ -1,8 +1,14 @@
-use crate::{pmx::types::*, reader::helpers::ReadHelpers, Error, Settings};
-use byteorder::{ReadBytesExt, LE};
-use std::convert::TryFrom;
-use std::fmt::{Display, Formatter};
-use std::io::Read;
+use crate::io::{Read, ReadBytesExt};
+use crate::{
+  pmx::types::*,
+  pmx::vertex::MAX_ADDITIONAL_VEC4S,
+  reader::helpers::{PositionReader, ReadHelpers},
+  Error, Settings,
+};
+use alloc::{string::String, vec, vec::Vec};
+use byteorder::LE;
+use core::convert::TryFrom;
+use core::fmt::{Display, Formatter};
 
 pub struct HeaderReader<R> {
   pub version: f32,
@@ -11,11 +17,23 @@ pub struct HeaderReader<R> {
   pub model_universal_name: String,
   pub local_comments: String,
   pub universal_comments: String,
-  pub(crate) read: R,
+  /// Globals after the 8 known ones, which some tools add.
+  pub extra_globals: Vec<u8>,
+  pub(crate) read: PositionReader<R>,
 }
 
 impl<R: Read> HeaderReader<R> {
-  pub fn new(mut read: R) -> Result<HeaderReader<R>, Error> {
+  pub fn new(read: R) -> Result<HeaderReader<R>, Error> {
+    let mut read = PositionReader::new(read, 0);
+    match HeaderReader::read_header(&mut read) {
+      Ok(header) => Ok(header.with_read(read)),
+      Err(e) => Err(e.context(read.position, "header", None)),
+    }
+  }
+}
+
+impl HeaderReader<()> {
+  fn read_header(mut read: impl Read) -> Result<HeaderReader<()>, Error> {
     let mut magic = [0u8; 4];
     read.read_exact(&mut magic)?;
     if magic != [0x50, 0x4D, 0x58, 0x20] {
@@ -28,10 +46,13 @@ impl<R: Read> HeaderReader<R> {
       return Err(Error::GlobalsCountLessThan8(globals_count));
     }
 
-    let mut globals = Vec::with_capacity(globals_count as usize);
-    globals.resize(globals_count as usize, 0u8);
+    let mut globals = vec![0u8; globals_count as usize];
     read.read_exact(&mut globals)?;
 
+    if globals[1] > MAX_ADDITIONAL_VEC4S {
+      return Err(Error::InvalidAdditionalVec4Count(globals[1]));
+    }
+
     let settings = Settings {
       text_encoding: TextEncoding::try_from(globals[0])?,
       additional_vec4_count: globals[1],
@@ -43,20 +64,35 @@ impl<R: Read> HeaderReader<R> {
       rigidbody_index_size: IndexSize::try_from(globals[7])?,
     };
 
-    Ok(HeaderReader::<R> {
+    Ok(HeaderReader {
       version,
       settings,
       model_local_name: read.read_text(settings.text_encoding)?,
       model_universal_name: read.read_text(settings.text_encoding)?,
       local_comments: read.read_text(settings.text_encoding)?,
       universal_comments: read.read_text(settings.text_encoding)?,
-      read,
+      extra_globals: globals.split_off(8),
+      read: PositionReader::new((), 0),
     })
   }
+
+  /// The header with the reader of the sections after it.
+  pub(crate) fn with_read<R>(self, read: PositionReader<R>) -> HeaderReader<R> {
+    HeaderReader {
+      version: self.version,
+      settings: self.settings,
+      model_local_name: self.model_local_name,
+      model_universal_name: self.model_universal_name,
+      local_comments: self.local_comments,
+      universal_comments: self.universal_comments,
+      extra_globals: self.extra_globals,
+      read,
+    }
+  }
 }
 
 impl<R> Display for HeaderReader<R> {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     write!(f, "version: {}, ", self.version)?;
     self.settings.fmt(f)?;
     writeln!(
@@ -69,3 +105,52 @@ impl<R> Display for HeaderReader<R> {
     )
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ErrorLocation;
+  #[cfg(feature = "std")]
+  use crate::{DefaultConfig, Pmx};
+
+  const FIXTURE_MODEL_PMX: &[u8] = include_bytes!("../../../fixtures/model.pmx");
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn test_read_extra_globals() {
+    let expected = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    // The count follows the signature and the version, the globals follow the count
+    let mut bytes = FIXTURE_MODEL_PMX.to_vec();
+    assert_eq!(bytes[8], 8);
+    bytes[8] = 10;
+    bytes.splice(17..17, [0xab, 0xcd].iter().copied());
+
+    let header = HeaderReader::new(&bytes[..]).unwrap();
+    assert_eq!(header.settings, expected.settings);
+    assert_eq!(header.extra_globals, [0xab, 0xcd]);
+
+    let mut pmx = Pmx::<DefaultConfig>::read(&bytes[..]).unwrap();
+    let mut written = Vec::new();
+    pmx.write(&mut written).unwrap();
+    assert_eq!(written, bytes);
+
+    pmx.extra_globals.clear();
+    assert_eq!(pmx, expected);
+  }
+
+  #[test]
+  fn test_read_too_few_globals() {
+    let mut bytes = FIXTURE_MODEL_PMX.to_vec();
+    bytes[8] = 7;
+    let e = HeaderReader::new(&bytes[..]).err().unwrap();
+    assert!(matches!(e.root(), Error::GlobalsCountLessThan8(7)));
+    assert_eq!(
+      e.location(),
+      Some(&ErrorLocation {
+        offset: 9,
+        section: "header",
+        index: None,
+      })
+    );
+  }
+}
@@ -1,29 +1,106 @@
-use crate::{pmx::types::*, Error, Result};
-use byteorder::{ReadBytesExt, LE};
+use crate::io::{Read, ReadBytesExt};
+use crate::{math::Widen, pmx::types::*, Error, Result};
+use alloc::{
+  borrow::Cow,
+  {
+    string::{String, ToString},
+    vec::Vec,
+  },
+};
+use byteorder::{ByteOrder, LE};
+use core::convert::TryFrom;
 use encoding_rs::{UTF_16LE, UTF_8};
-use std::{borrow::Cow, io::Read};
 
-pub(crate) trait ReadHelpers: Read {
-  fn read_text(&mut self, encoding: TextEncoding) -> Result<String> {
+/// Bytes reserved upfront for a text, longer texts grow the buffer as they're read so a corrupt
+/// length can't trigger a huge allocation.
+pub(crate) const MAX_TEXT_RESERVED: usize = 4096;
+
+/// Records reserved upfront for a list inside a record, like morph offsets or IK links, for the
+/// same reason.
+pub(crate) const MAX_RECORDS_RESERVED: usize = 1024;
+
+/// An empty list with room for `count` records, at most `MAX_RECORDS_RESERVED`.
+pub(crate) fn reserve<T>(count: u32) -> Vec<T> {
+  Vec::with_capacity((count as usize).min(MAX_RECORDS_RESERVED))
+}
+
+/// Counts the bytes read through it, for the offsets in `Error::Context`.
+pub(crate) struct PositionReader<R> {
+  pub(crate) inner: R,
+  pub(crate) position: u64,
+}
+
+impl<R> PositionReader<R> {
+  /// A reader at `position` bytes from the start of the file.
+  pub(crate) fn new(inner: R, position: u64) -> Self {
+    PositionReader { inner, position }
+  }
+}
+
+impl<R: Read> PositionReader<R> {
+  /// Reads the count at the start of `section`.
+  pub(crate) fn read_count(&mut self, section: &'static str) -> Result<i32> {
+    self
+      .read_i32::<LE>()
+      .map_err(|e| Error::from(e).context(self.position, section, None))
+  }
+
+  /// Moves past `len` bytes without keeping them.
+  pub(crate) fn skip(&mut self, len: u64) -> Result<()> {
+    let mut buf = [0; 4096];
+    let mut skipped = 0;
+    while skipped < len {
+      let chunk = (len - skipped).min(buf.len() as u64) as usize;
+      match self.read(&mut buf[..chunk]) {
+        Ok(0) => break,
+        Ok(read) => skipped += read as u64,
+        Err(e) if e.kind() == crate::io::ErrorKind::Interrupted => {}
+        Err(e) => return Err(e.into()),
+      }
+    }
+    if skipped < len {
+      return Err(Error::UnexpectedEnd {
+        needed: len as usize,
+        available: skipped as usize,
+      });
+    }
+    Ok(())
+  }
+
+  /// Moves past a text without decoding it.
+  pub(crate) fn skip_text(&mut self) -> Result<()> {
     let size = self.read_i32::<LE>()?;
-    let mut buf = Vec::with_capacity(size as usize);
-    buf.resize(size as usize, 0u8);
-    self.read_exact(&mut buf)?;
+    let size = u64::try_from(size).map_err(|_| Error::InvalidTextLength(size))?;
+    self.skip(size)
+  }
+}
 
-    let (res, _encoding, is_malformed) = match encoding {
-      TextEncoding::UTF8 => UTF_8.decode(&buf),
-      TextEncoding::UTF16LE => UTF_16LE.decode(&buf),
-    };
+impl<R: Read> Read for PositionReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> crate::io::Result<usize> {
+    let read = self.inner.read(buf)?;
+    self.position += read as u64;
+    Ok(read)
+  }
+}
 
-    if is_malformed {
-      return Err(Error::DecodeText(Cow::Borrowed("malformed text")));
+pub(crate) trait ReadHelpers: Read {
+  fn read_text(&mut self, encoding: TextEncoding) -> Result<String> {
+    let size = self.read_i32::<LE>()?;
+    let size = usize::try_from(size).map_err(|_| Error::InvalidTextLength(size))?;
+    let mut buf = Vec::with_capacity(size.min(MAX_TEXT_RESERVED));
+    Read::take(&mut *self, size as u64).read_to_end(&mut buf)?;
+    if buf.len() < size {
+      return Err(Error::TruncatedText {
+        expected: size,
+        read: buf.len(),
+      });
     }
 
-    Ok(res.to_string())
+    decode_text(&buf, encoding)
   }
 
   fn read_vec2<C: Config>(&mut self) -> Result<C::Vec2> {
-    Ok([self.read_f32::<LE>()?, self.read_f32::<LE>()?].into())
+    Ok([self.read_f32::<LE>()?, self.read_f32::<LE>()?].widen())
   }
 
   fn read_vec3<C: Config>(&mut self) -> Result<C::Vec3> {
@@ -33,7 +110,7 @@ pub(crate) trait ReadHelpers: Read {
         self.read_f32::<LE>()?,
         self.read_f32::<LE>()?,
       ]
-      .into(),
+      .widen(),
     )
   }
 
@@ -45,43 +122,236 @@ pub(crate) trait ReadHelpers: Read {
         self.read_f32::<LE>()?,
         self.read_f32::<LE>()?,
       ]
-      .into(),
+      .widen(),
     )
   }
 
   fn read_index<I: Index>(&mut self, size: IndexSize) -> Result<I> {
-    match size {
-      IndexSize::I8 => {
-        let v = self.read_i8()?;
-        I::try_from(v).map_err(|_| Error::IndexOverflow(v.into()))
-      }
-      IndexSize::I16 => {
-        let v = self.read_i16::<LE>()?;
-        I::try_from(v).map_err(|_| Error::IndexOverflow(v.into()))
-      }
-      IndexSize::I32 => {
-        let v = self.read_i32::<LE>()?;
-        I::try_from(v).map_err(|_| Error::IndexOverflow(v.into()))
-      }
-    }
+    let mut buf = [0; 4];
+    self.read_exact(&mut buf[..size as usize])?;
+    decode_index(&buf, size)
+  }
+
+  /// Reads an index where -1 at the width of `size` means none, other negative values are
+  /// rejected.
+  fn read_optional_index<I: Index>(&mut self, size: IndexSize) -> Result<Option<I>> {
+    let mut buf = [0; 4];
+    self.read_exact(&mut buf[..size as usize])?;
+    decode_optional_index(&buf, size)
   }
 
   fn read_vertex_index<I: VertexIndex>(&mut self, size: IndexSize) -> Result<I> {
-    match size {
-      IndexSize::I8 => {
-        let v = self.read_u8()?;
-        I::try_from(v).map_err(|_| Error::IndexOverflow(v.into()))
-      }
-      IndexSize::I16 => {
-        let v = self.read_u16::<LE>()?;
-        I::try_from(v).map_err(|_| Error::IndexOverflow(v.into()))
-      }
-      IndexSize::I32 => {
-        let v = self.read_i32::<LE>()?;
-        I::try_from(v).map_err(|_| Error::IndexOverflow(v.into()))
-      }
-    }
+    let mut buf = [0; 4];
+    self.read_exact(&mut buf[..size as usize])?;
+    decode_vertex_index(&buf, size)
   }
 }
 
 impl<R: Read> ReadHelpers for R {}
+
+pub(crate) fn decode_text(buf: &[u8], encoding: TextEncoding) -> Result<String> {
+  let (res, _encoding, is_malformed) = match encoding {
+    TextEncoding::UTF8 => UTF_8.decode(buf),
+    TextEncoding::UTF16LE => UTF_16LE.decode(buf),
+  };
+
+  if is_malformed {
+    return Err(Error::DecodeText(Cow::Borrowed("malformed text")));
+  }
+
+  Ok(res.to_string())
+}
+
+/// Decodes an index from the start of `buf` for the readers that buffer whole records.
+pub(crate) fn decode_index<I: Index>(buf: &[u8], size: IndexSize) -> Result<I> {
+  match size {
+    IndexSize::I8 => {
+      let v = buf[0] as i8;
+      I::try_from(v).map_err(|_| Error::IndexOverflow(v.into()))
+    }
+    IndexSize::I16 => {
+      let v = LE::read_i16(buf);
+      I::try_from(v).map_err(|_| Error::IndexOverflow(v.into()))
+    }
+    IndexSize::I32 => {
+      let v = LE::read_i32(buf);
+      I::try_from(v).map_err(|_| Error::IndexOverflow(v.into()))
+    }
+  }
+}
+
+/// Decodes an index where -1 at the width of `size` means none, other negative values fail
+/// with `Error::IndexOverflow`.
+pub(crate) fn decode_optional_index<I: Index>(buf: &[u8], size: IndexSize) -> Result<Option<I>> {
+  let v: i32 = match size {
+    IndexSize::I8 => (buf[0] as i8).into(),
+    IndexSize::I16 => LE::read_i16(buf).into(),
+    IndexSize::I32 => LE::read_i32(buf),
+  };
+  match v {
+    -1 => Ok(None),
+    v if v < 0 => Err(Error::IndexOverflow(v.into())),
+    v => I::try_from(v)
+      .map(Some)
+      .map_err(|_| Error::IndexOverflow(v.into())),
+  }
+}
+
+/// Decodes a vertex index from the start of `buf`, 8 and 16-bit ones are unsigned.
+pub(crate) fn decode_vertex_index<I: VertexIndex>(buf: &[u8], size: IndexSize) -> Result<I> {
+  match size {
+    IndexSize::I8 => I::try_from(buf[0]).map_err(|_| Error::IndexOverflow(buf[0].into())),
+    IndexSize::I16 => {
+      let v = LE::read_u16(buf);
+      I::try_from(v).map_err(|_| Error::IndexOverflow(v.into()))
+    }
+    IndexSize::I32 => {
+      let v = LE::read_i32(buf);
+      I::try_from(v).map_err(|_| Error::IndexOverflow(v.into()))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ErrorLocation;
+
+  fn read_text(bytes: &[u8]) -> Result<String> {
+    let mut read = bytes;
+    read.read_text(TextEncoding::UTF8)
+  }
+
+  #[test]
+  fn test_read_text() {
+    assert_eq!(read_text(&[2, 0, 0, 0, b'o', b'k']).unwrap(), "ok");
+    assert!(matches!(
+      read_text(&(-1i32).to_le_bytes()),
+      Err(Error::InvalidTextLength(-1))
+    ));
+    assert!(matches!(
+      read_text(&i32::MAX.to_le_bytes()),
+      Err(Error::TruncatedText { read: 0, .. })
+    ));
+    assert!(matches!(
+      read_text(&[3, 0, 0, 0, b'o', b'k']),
+      Err(Error::TruncatedText {
+        expected: 3,
+        read: 2
+      })
+    ));
+  }
+
+  #[cfg(feature = "glam")]
+  #[test]
+  fn test_read_vec_glam() {
+    let bytes: Vec<u8> = [1.0f32, 2.0, 3.0]
+      .iter()
+      .flat_map(|c| c.to_le_bytes())
+      .collect();
+    let mut read = &bytes[..];
+    assert_eq!(
+      read.read_vec3::<crate::GlamConfig>().unwrap(),
+      glam::Vec3::new(1.0, 2.0, 3.0)
+    );
+  }
+
+  #[test]
+  fn test_read_optional_index() {
+    let mut read: &[u8] = &[0xff, 0x7f, 0xff, 0xff, 0xfe, 0xff];
+    assert_eq!(
+      read.read_optional_index::<i32>(IndexSize::I8).unwrap(),
+      None
+    );
+    assert_eq!(
+      read.read_optional_index::<i32>(IndexSize::I8).unwrap(),
+      Some(127)
+    );
+    assert_eq!(
+      read.read_optional_index::<i32>(IndexSize::I16).unwrap(),
+      None
+    );
+    assert!(matches!(
+      read.read_optional_index::<i32>(IndexSize::I16),
+      Err(Error::IndexOverflow(-2))
+    ));
+  }
+
+  const FIXTURE_MODEL_PMX: &[u8] = include_bytes!("../../../fixtures/model.pmx");
+
+  /// The offset after the texture count and after the bone count.
+  fn section_offsets() -> (usize, usize) {
+    use crate::reader::*;
+    let textures = TextureReader::new(
+      SurfaceReader::new(VertexReader::new(HeaderReader::new(FIXTURE_MODEL_PMX).unwrap()).unwrap())
+        .unwrap(),
+    )
+    .unwrap();
+    let texture_start = textures.read.position as usize;
+    let bones = BoneReader::new(MaterialReader::new(textures).unwrap()).unwrap();
+    (texture_start, bones.read.position as usize)
+  }
+
+  fn read_pmx(bytes: &[u8]) -> Error {
+    crate::Pmx::<crate::DefaultConfig>::read(bytes).unwrap_err()
+  }
+
+  #[test]
+  fn test_error_context() {
+    let (texture_start, bone_start) = section_offsets();
+
+    let mut bytes = FIXTURE_MODEL_PMX.to_vec();
+    bytes[texture_start..texture_start + 4].copy_from_slice(&(-1i32).to_le_bytes());
+    let e = read_pmx(&bytes);
+    assert!(matches!(e.root(), Error::InvalidTextLength(-1)));
+    assert_eq!(
+      e.location(),
+      Some(&ErrorLocation {
+        offset: texture_start as u64 + 4,
+        section: "texture",
+        index: Some(0),
+      })
+    );
+
+    // The parent follows both names and the position of the first bone
+    let mut parent = bone_start;
+    for _ in 0..2 {
+      parent += 4 + LE::read_i32(&FIXTURE_MODEL_PMX[parent..]) as usize;
+    }
+    parent += 12;
+    let size = crate::Pmx::<crate::DefaultConfig>::read(FIXTURE_MODEL_PMX)
+      .unwrap()
+      .settings
+      .bone_index_size as usize;
+    let mut bytes = FIXTURE_MODEL_PMX.to_vec();
+    bytes[parent..parent + size].copy_from_slice(&(-5i32).to_le_bytes()[..size]);
+    let e = read_pmx(&bytes);
+    assert!(matches!(e.root(), Error::IndexOverflow(-5)));
+    assert_eq!(
+      e.location(),
+      Some(&ErrorLocation {
+        offset: (parent + size) as u64,
+        section: "bone",
+        index: Some(0),
+      })
+    );
+    assert_eq!(
+      e.to_string(),
+      format!(
+        "Index overflow -5 at byte {} in the bone section, element 0",
+        parent + size
+      )
+    );
+
+    // Counts have no element
+    let e = read_pmx(&FIXTURE_MODEL_PMX[..bone_start - 2]);
+    assert_eq!(
+      e.location(),
+      Some(&ErrorLocation {
+        offset: bone_start as u64 - 2,
+        section: "bone",
+        index: None,
+      })
+    );
+  }
+}
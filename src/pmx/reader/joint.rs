@@ -1,28 +1,34 @@
+use crate::io::{Read, ReadBytesExt};
 use crate::{
   pmx::joint::*,
-  reader::{helpers::ReadHelpers, RigidBodyReader},
-  Config, DefaultConfig, Result, Settings,
+  reader::{
+    helpers::{PositionReader, ReadHelpers},
+    RigidBodyReader,
+  },
+  Config, DefaultConfig, Error, LocalizedName, Result, Settings,
 };
-use byteorder::{ReadBytesExt, LE};
-use std::convert::TryFrom;
-use std::io::Read;
-use std::marker::PhantomData;
+use core::convert::TryFrom;
+use core::marker::PhantomData;
+
+pub(crate) const SECTION: &str = "joint";
 
 pub struct JointReader<R> {
   pub settings: Settings,
   pub count: i32,
   pub remaining: i32,
-  pub(crate) read: R,
+  pub(crate) read: PositionReader<R>,
   pub(crate) poison: bool,
 }
 
 impl<R: Read> JointReader<R> {
   pub fn new(mut r: RigidBodyReader<R>) -> Result<JointReader<R>> {
-    assert!(!r.poison);
+    if r.poison {
+      return Err(Error::Poisoned);
+    }
     while r.remaining > 0 {
       r.next::<DefaultConfig>()?;
     }
-    let count = r.read.read_i32::<LE>()?;
+    let count = r.read.read_count(SECTION)?;
 
     Ok(JointReader {
       settings: r.settings,
@@ -34,12 +40,15 @@ impl<R: Read> JointReader<R> {
   }
 
   pub fn next<C: Config>(&mut self) -> Result<Option<Joint<C>>> {
-    assert!(!self.poison);
+    if self.poison {
+      return Err(Error::Poisoned);
+    }
+    let index = (self.count - self.remaining) as usize;
     let result = self.next_impl::<C>();
     if result.is_err() {
       self.poison = true;
     }
-    result
+    result.map_err(|e| e.context(self.read.position, SECTION, Some(index)))
   }
 
   fn next_impl<C: Config>(&mut self) -> Result<Option<Joint<C>>> {
@@ -50,8 +59,10 @@ impl<R: Read> JointReader<R> {
     self.remaining -= 1;
 
     Ok(Some(Joint {
-      local_name: self.read.read_text(self.settings.text_encoding)?,
-      universal_name: self.read.read_text(self.settings.text_encoding)?,
+      name: LocalizedName {
+        ja: self.read.read_text(self.settings.text_encoding)?,
+        en: self.read.read_text(self.settings.text_encoding)?,
+      },
       joint_type: JointType::try_from(self.read.read_u8()?)?,
       rigid_body_a: self.read.read_index(self.settings.rigidbody_index_size)?,
       rigid_body_b: self.read.read_index(self.settings.rigidbody_index_size)?,
@@ -66,7 +77,7 @@ impl<R: Read> JointReader<R> {
     }))
   }
 
-  pub fn iter<C>(&mut self) -> JointIterator<R, C> {
+  pub fn iter<C>(&mut self) -> JointIterator<'_, R, C> {
     JointIterator {
       reader: self,
       phantom: PhantomData,
@@ -91,14 +102,38 @@ impl<R: Read, C: Config> Iterator for JointIterator<'_, R, C> {
 
   fn size_hint(&self) -> (usize, Option<usize>) {
     (
-      self.reader.remaining as usize,
-      Some(self.reader.remaining as usize),
+      self.reader.remaining.max(0) as usize,
+      Some(self.reader.remaining.max(0) as usize),
     )
   }
 }
 
 impl<R: Read, C: Config> ExactSizeIterator for JointIterator<'_, R, C> {
   fn len(&self) -> usize {
-    self.reader.remaining as usize
+    self.reader.remaining.max(0) as usize
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{math::to_array, Pmx};
+
+  const FIXTURE_MODEL_PMX: &[u8] = include_bytes!("../../../fixtures/model.pmx");
+
+  #[test]
+  fn test_read_joint() {
+    let pmx = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    let joint = &pmx.joints[0];
+
+    assert_eq!(joint.name.ja, "首");
+    assert_eq!(joint.joint_type, JointType::SpringFree);
+    assert_eq!((joint.rigid_body_a, joint.rigid_body_b), (0, 0));
+    assert_eq!(to_array::<3>(&joint.position), [0.0, 9.0, 0.0]);
+    assert_eq!(to_array::<3>(&joint.position_min), [0.0; 3]);
+    assert_eq!(to_array::<3>(&joint.position_max), [0.0; 3]);
+    assert_eq!(to_array::<3>(&joint.rotation_min), [-0.5; 3]);
+    assert_eq!(to_array::<3>(&joint.rotation_max), [0.5; 3]);
+    assert_eq!(to_array::<3>(&joint.rotation_spring), [10.0; 3]);
   }
 }
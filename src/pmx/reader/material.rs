@@ -1,29 +1,35 @@
+use crate::io::{Read, ReadBytesExt};
 use crate::{
   pmx::material::*,
-  reader::{helpers::ReadHelpers, TextureReader},
-  Config, DefaultConfig, Error, Result, Settings,
+  reader::{
+    helpers::{PositionReader, ReadHelpers},
+    TextureReader,
+  },
+  Config, DefaultConfig, Error, LocalizedName, Result, Settings,
 };
-use byteorder::{ReadBytesExt, LE};
-use enumflags2::BitFlags;
-use std::convert::TryFrom;
-use std::io::Read;
-use std::marker::PhantomData;
+use byteorder::LE;
+use core::convert::TryFrom;
+use core::marker::PhantomData;
+
+pub(crate) const SECTION: &str = "material";
 
 pub struct MaterialReader<R> {
   pub settings: Settings,
   pub count: i32,
   pub remaining: i32,
-  pub(crate) read: R,
+  pub(crate) read: PositionReader<R>,
   pub(crate) poison: bool,
 }
 
 impl<R: Read> MaterialReader<R> {
   pub fn new(mut t: TextureReader<R>) -> Result<MaterialReader<R>> {
-    assert!(!t.poison);
+    if t.poison {
+      return Err(Error::Poisoned);
+    }
     while t.remaining > 0 {
       t.next()?;
     }
-    let count = t.read.read_i32::<LE>()?;
+    let count = t.read.read_count(SECTION)?;
 
     Ok(MaterialReader {
       settings: t.settings,
@@ -35,12 +41,15 @@ impl<R: Read> MaterialReader<R> {
   }
 
   pub fn next<C: Config>(&mut self) -> Result<Option<Material<C>>> {
-    assert!(!self.poison);
+    if self.poison {
+      return Err(Error::Poisoned);
+    }
+    let index = (self.count - self.remaining) as usize;
     let result = self.next_impl::<C>();
     if result.is_err() {
       self.poison = true;
     }
-    result
+    result.map_err(|e| e.context(self.read.position, SECTION, Some(index)))
   }
 
   fn next_impl<C: Config>(&mut self) -> Result<Option<Material<C>>> {
@@ -50,30 +59,47 @@ impl<R: Read> MaterialReader<R> {
 
     self.remaining -= 1;
 
+    let name = LocalizedName {
+      ja: self.read.read_text(self.settings.text_encoding)?,
+      en: self.read.read_text(self.settings.text_encoding)?,
+    };
     Ok(Some(Material {
-      local_name: self.read.read_text(self.settings.text_encoding)?,
-      universal_name: self.read.read_text(self.settings.text_encoding)?,
       diffuse_color: self.read.read_vec4::<C>()?,
       specular_color: self.read.read_vec3::<C>()?,
       specular_strength: self.read.read_f32::<LE>()?,
       ambient_color: self.read.read_vec3::<C>()?,
-      draw_flags: BitFlags::from_bits(self.read.read_u8()?).unwrap(),
+      draw_flags: MaterialFlags::from_bits(self.read.read_u8()?),
       edge_color: self.read.read_vec4::<C>()?,
       edge_scale: self.read.read_f32::<LE>()?,
-      texture_index: self.read.read_index(self.settings.texture_index_size)?,
-      environment_index: self.read.read_index(self.settings.texture_index_size)?,
-      environment_blend_mode: EnvironmentBlendMode::try_from(self.read.read_u8()?)?,
+      texture_index: self
+        .read
+        .read_optional_index(self.settings.texture_index_size)?,
+      environment_index: self
+        .read
+        .read_optional_index(self.settings.texture_index_size)?,
+      environment_blend_mode: {
+        let mode = self.read.read_u8()?;
+        EnvironmentBlendMode::try_from(mode).map_err(|_| Error::InvalidMaterialBlendMode {
+          material: name.ja.clone(),
+          mode,
+        })?
+      },
       toon: match self.read.read_u8()? {
-        0 => Toon::Texture(self.read.read_index(self.settings.texture_index_size)?),
+        0 => Toon::Texture(
+          self
+            .read
+            .read_optional_index(self.settings.texture_index_size)?,
+        ),
         1 => Toon::Internal(self.read.read_u8()?),
         e => return Err(Error::InvalidToonReference(e)),
       },
       metadata: self.read.read_text(self.settings.text_encoding)?,
       surface_count: self.read.read_i32::<LE>()?,
+      name,
     }))
   }
 
-  pub fn iter<C>(&mut self) -> MaterialIterator<R, C> {
+  pub fn iter<C>(&mut self) -> MaterialIterator<'_, R, C> {
     MaterialIterator {
       reader: self,
       phantom: PhantomData,
@@ -98,14 +124,105 @@ impl<R: Read, C: Config> Iterator for MaterialIterator<'_, R, C> {
 
   fn size_hint(&self) -> (usize, Option<usize>) {
     (
-      self.reader.remaining as usize,
-      Some(self.reader.remaining as usize),
+      self.reader.remaining.max(0) as usize,
+      Some(self.reader.remaining.max(0) as usize),
     )
   }
 }
 
 impl<R: Read, C: Config> ExactSizeIterator for MaterialIterator<'_, R, C> {
   fn len(&self) -> usize {
-    self.reader.remaining as usize
+    self.reader.remaining.max(0) as usize
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::Pmx;
+
+  const FIXTURE_MODEL_PMX: &[u8] = include_bytes!("../../../fixtures/model.pmx");
+
+  #[test]
+  fn test_read_material_flags() {
+    let pmx = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+
+    let flags = pmx.materials[0].draw_flags;
+    assert_eq!(flags.raw(), 0b0001_1110);
+    assert!(flags.ground_shadow() && flags.draw_shadow() && flags.receive_shadow());
+    assert!(flags.has_edge());
+    assert!(!flags.no_cull());
+
+    let flags = pmx.materials[1].draw_flags;
+    assert_eq!(
+      flags.bits(),
+      DrawingFlags::NoCull
+        | DrawingFlags::GroundShadow
+        | DrawingFlags::DrawShadow
+        | DrawingFlags::ReceiveShadow
+    );
+    assert!(!flags.has_edge());
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn test_read_toons() {
+    let mut pmx = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    assert_eq!(pmx.materials[0].toon, Toon::Internal(0));
+    assert_eq!(
+      pmx.materials[0].toon.shared_file_name().as_deref(),
+      Some("toon01.bmp")
+    );
+    assert_eq!(
+      pmx.materials[0].environment_blend_mode,
+      EnvironmentBlendMode::Multiply
+    );
+
+    pmx.materials[0].toon = Toon::Internal(12);
+    pmx.materials[1].toon = Toon::Texture(Some(1));
+    let mut bytes = Vec::new();
+    pmx.write(&mut bytes).unwrap();
+    let read = Pmx::<DefaultConfig>::read(&bytes[..]).unwrap();
+    assert_eq!(read.materials[0].toon, Toon::Internal(12));
+    assert_eq!(read.materials[0].toon.shared_file_name(), None);
+    assert_eq!(read.materials[1].toon, Toon::Texture(Some(1)));
+    assert_eq!(read.materials[1].toon.shared_file_name(), None);
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn test_read_invalid_blend_mode() {
+    let mut pmx = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    let mut additive = Vec::new();
+    pmx.materials[0].environment_blend_mode = EnvironmentBlendMode::Additive;
+    pmx.write(&mut additive).unwrap();
+
+    // The only byte that differs from the fixture is the blend mode
+    let offset = (0..additive.len())
+      .find(|&i| additive[i] != FIXTURE_MODEL_PMX[i])
+      .unwrap();
+    additive[offset] = 7;
+    let e = Pmx::<DefaultConfig>::read(&additive[..]).unwrap_err();
+    assert!(matches!(
+      e.root(),
+      Error::InvalidMaterialBlendMode { material, mode: 7 } if material == "材質1"
+    ));
+    assert_eq!(e.location().unwrap().index, Some(0));
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn test_material_flags_round_trip() {
+    let mut pmx = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    let mut flags = MaterialFlags::from_bits(0b1100_0001);
+    flags.set(DrawingFlags::HasEdge, true);
+    flags.set(DrawingFlags::NoCull, false);
+    assert!(flags.point_drawing() && flags.line_drawing() && flags.has_edge());
+    pmx.materials[0].draw_flags = flags;
+
+    let mut bytes = Vec::new();
+    pmx.write(&mut bytes).unwrap();
+    let read = Pmx::<DefaultConfig>::read(&bytes[..]).unwrap();
+    assert_eq!(read.materials[0].draw_flags.raw(), 0b1101_0000);
   }
 }
@@ -1,28 +1,36 @@
+use crate::io::{Read, ReadBytesExt};
 use crate::{
   pmx::morph::*,
-  reader::{helpers::ReadHelpers, BoneReader},
-  Config, DefaultConfig, Error, Result, Settings,
+  reader::{
+    helpers::{reserve, PositionReader, ReadHelpers},
+    BoneReader,
+  },
+  Config, DefaultConfig, Error, LocalizedName, Result, Settings,
 };
-use byteorder::{ReadBytesExt, LE};
-use std::convert::TryFrom;
-use std::io::Read;
-use std::marker::PhantomData;
+use alloc::vec::Vec;
+use byteorder::LE;
+use core::convert::TryFrom;
+use core::marker::PhantomData;
+
+pub(crate) const SECTION: &str = "morph";
 
 pub struct MorphReader<R> {
   pub settings: Settings,
   pub count: i32,
   pub remaining: i32,
-  pub(crate) read: R,
+  pub(crate) read: PositionReader<R>,
   pub(crate) poison: bool,
 }
 
 impl<R: Read> MorphReader<R> {
   pub fn new(mut b: BoneReader<R>) -> Result<MorphReader<R>> {
-    assert!(!b.poison);
+    if b.poison {
+      return Err(Error::Poisoned);
+    }
     while b.remaining > 0 {
       b.next::<DefaultConfig>()?;
     }
-    let count = b.read.read_i32::<LE>()?;
+    let count = b.read.read_count(SECTION)?;
 
     Ok(MorphReader {
       settings: b.settings,
@@ -34,15 +42,18 @@ impl<R: Read> MorphReader<R> {
   }
 
   pub fn next<C: Config>(&mut self) -> Result<Option<Morph<C>>> {
-    assert!(!self.poison);
+    if self.poison {
+      return Err(Error::Poisoned);
+    }
+    let index = (self.count - self.remaining) as usize;
     let result = self.next_impl::<C>();
     if result.is_err() {
       self.poison = true;
     }
-    result
+    result.map_err(|e| e.context(self.read.position, SECTION, Some(index)))
   }
 
-  fn next_impl<C: Config>(&mut self) -> Result<Option<Morph<C>>> {
+  pub(super) fn next_impl<C: Config>(&mut self) -> Result<Option<Morph<C>>> {
     if self.remaining <= 0 {
       return Ok(None);
     }
@@ -67,18 +78,66 @@ impl<R: Read> MorphReader<R> {
       8 => Offsets::Material(self.next_material_offsets(morph_count)?),
       9 => Offsets::Flip(self.next_morph_offsets(morph_count)?),
       10 => Offsets::Impulse(self.next_impulse_offsets(morph_count)?),
-      e => return Err(Error::InvalidMorphType(e)),
+      kind => {
+        return Err(Error::InvalidMorphType {
+          kind,
+          morph: self.count - self.remaining - 1,
+        })
+      }
     };
 
     Ok(Some(Morph {
-      local_name,
-      universal_name,
+      name: LocalizedName {
+        ja: local_name,
+        en: universal_name,
+      },
       panel,
       offsets,
     }))
   }
 
-  pub fn iter<C>(&mut self) -> MorphIterator<R, C> {
+  /// Moves past the remaining morphs. Their offsets are sized by their kind, but nothing is
+  /// decoded or allocated.
+  pub fn skip(&mut self) -> Result<()> {
+    if self.poison {
+      return Err(Error::Poisoned);
+    }
+    while self.remaining > 0 {
+      let index = (self.count - self.remaining) as usize;
+      if let Err(e) = self.skip_one() {
+        self.poison = true;
+        return Err(e.context(self.read.position, SECTION, Some(index)));
+      }
+    }
+    Ok(())
+  }
+
+  pub(super) fn skip_one(&mut self) -> Result<()> {
+    let s = self.settings;
+    self.read.skip_text()?;
+    self.read.skip_text()?;
+    self.read.read_u8()?;
+    let size = match self.read.read_u8()? {
+      0 | 9 => s.morph_index_size as u64 + 4,
+      1 => s.vertex_index_size as u64 + 12,
+      2 => s.bone_index_size as u64 + 28,
+      3..=7 => s.vertex_index_size as u64 + 16,
+      8 => s.material_index_size as u64 + 113,
+      10 => s.rigidbody_index_size as u64 + 25,
+      kind => {
+        return Err(Error::InvalidMorphType {
+          kind,
+          morph: self.count - self.remaining,
+        })
+      }
+    };
+    let count = self.read.read_u32::<LE>()?;
+    self.read.skip(count as u64 * size)?;
+    self.remaining -= 1;
+    Ok(())
+  }
+
+  pub fn iter<C>(&mut self) -> MorphIterator<'_, R, C> {
     MorphIterator {
       reader: self,
       phantom: PhantomData,
@@ -86,7 +145,7 @@ impl<R: Read> MorphReader<R> {
   }
 
   fn next_morph_offsets<C: Config>(&mut self, count: u32) -> Result<Vec<GroupOffset<C>>> {
-    let mut offsets = Vec::with_capacity(count as usize);
+    let mut offsets = reserve(count);
 
     for _ in 0..count {
       offsets.push(GroupOffset {
@@ -99,7 +158,7 @@ impl<R: Read> MorphReader<R> {
   }
 
   fn next_vertex_offsets<C: Config>(&mut self, count: u32) -> Result<Vec<VertexOffset<C>>> {
-    let mut offsets = Vec::with_capacity(count as usize);
+    let mut offsets = reserve(count);
 
     for _ in 0..count {
       offsets.push(VertexOffset {
@@ -114,7 +173,7 @@ impl<R: Read> MorphReader<R> {
   }
 
   fn next_bone_offsets<C: Config>(&mut self, count: u32) -> Result<Vec<BoneOffset<C>>> {
-    let mut offsets = Vec::with_capacity(count as usize);
+    let mut offsets = reserve(count);
 
     for _ in 0..count {
       offsets.push(BoneOffset {
@@ -128,7 +187,7 @@ impl<R: Read> MorphReader<R> {
   }
 
   fn next_uv_offsets<C: Config>(&mut self, count: u32) -> Result<Vec<UVOffset<C>>> {
-    let mut offsets = Vec::with_capacity(count as usize);
+    let mut offsets = reserve(count);
 
     for _ in 0..count {
       offsets.push(UVOffset {
@@ -143,11 +202,13 @@ impl<R: Read> MorphReader<R> {
   }
 
   fn next_material_offsets<C: Config>(&mut self, count: u32) -> Result<Vec<MaterialOffset<C>>> {
-    let mut offsets = Vec::with_capacity(count as usize);
+    let mut offsets = reserve(count);
 
     for _ in 0..count {
       offsets.push(MaterialOffset {
-        material: self.read.read_index(self.settings.material_index_size)?,
+        material: self
+          .read
+          .read_optional_index(self.settings.material_index_size)?,
         method: OffsetMethod::try_from(self.read.read_u8()?)?,
         diffuse_color: self.read.read_vec4::<C>()?,
         specular_color: self.read.read_vec3::<C>()?,
@@ -165,7 +226,7 @@ impl<R: Read> MorphReader<R> {
   }
 
   fn next_impulse_offsets<C: Config>(&mut self, count: u32) -> Result<Vec<ImpulseOffset<C>>> {
-    let mut offsets = Vec::with_capacity(count as usize);
+    let mut offsets = reserve(count);
 
     for _ in 0..count {
       offsets.push(ImpulseOffset {
@@ -197,14 +258,141 @@ impl<R: Read, C: Config> Iterator for MorphIterator<'_, R, C> {
 
   fn size_hint(&self) -> (usize, Option<usize>) {
     (
-      self.reader.remaining as usize,
-      Some(self.reader.remaining as usize),
+      self.reader.remaining.max(0) as usize,
+      Some(self.reader.remaining.max(0) as usize),
     )
   }
 }
 
 impl<R: Read, C: Config> ExactSizeIterator for MorphIterator<'_, R, C> {
   fn len(&self) -> usize {
-    self.reader.remaining as usize
+    self.reader.remaining.max(0) as usize
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::reader::{HeaderReader, MaterialReader, SurfaceReader, TextureReader, VertexReader};
+  use crate::ErrorLocation;
+  use std::f32::consts::FRAC_1_SQRT_2;
+
+  const FIXTURE_MODEL_PMX: &[u8] = include_bytes!("../../../fixtures/model.pmx");
+
+  fn morph_reader<'a, 'b>(read: &'a mut &'b [u8]) -> MorphReader<&'a mut &'b [u8]> {
+    let vertices = VertexReader::new(HeaderReader::new(read).unwrap()).unwrap();
+    let textures = TextureReader::new(SurfaceReader::new(vertices).unwrap()).unwrap();
+    let bones = BoneReader::new(MaterialReader::new(textures).unwrap()).unwrap();
+    MorphReader::new(bones).unwrap()
+  }
+
+  fn morphs() -> Vec<Morph<DefaultConfig>> {
+    let mut read = FIXTURE_MODEL_PMX;
+    let mut morphs = morph_reader(&mut read);
+    morphs
+      .iter::<DefaultConfig>()
+      .collect::<Result<_>>()
+      .unwrap()
+  }
+
+  #[test]
+  fn test_read_morphs() {
+    let morphs = morphs();
+    assert_eq!(morphs.len(), 9);
+
+    match &morphs[0].offsets {
+      Offsets::Vertex(offsets) => {
+        assert_eq!(offsets.len(), 2);
+        assert_eq!(offsets[0].vertex, 4);
+      }
+      offsets => panic!("{}", offsets),
+    }
+    assert_eq!(
+      morphs[2].offsets,
+      Offsets::UV(vec![UVOffset {
+        vertex: 0,
+        offset: [0.25, 0.5, 0.0, 0.0].into(),
+      }])
+    );
+    assert_eq!(
+      morphs[3].offsets,
+      Offsets::AdditionalUV1(vec![UVOffset {
+        vertex: 1,
+        offset: [0.0, 0.0, 0.5, 1.0].into(),
+      }])
+    );
+    assert_eq!(
+      morphs[4].offsets,
+      Offsets::Bone(vec![BoneOffset {
+        bone: 0,
+        translation: [0.0, 1.0, 0.0].into(),
+        rotation: [0.0, FRAC_1_SQRT_2, 0.0, FRAC_1_SQRT_2].into(),
+      }])
+    );
+  }
+
+  #[test]
+  fn test_read_material_morph() {
+    let morphs = morphs();
+    let offsets = match &morphs[5].offsets {
+      Offsets::Material(offsets) => offsets,
+      offsets => panic!("{}", offsets),
+    };
+
+    assert_eq!(morphs[5].name.ja, "材質");
+    assert_eq!(offsets[0].material, None);
+    assert_eq!(offsets[0].method, OffsetMethod::Multiply);
+    assert_eq!(
+      offsets[0].diffuse_color,
+      <DefaultConfig as Config>::Vec4::from([1.0, 1.0, 1.0, 0.5])
+    );
+    assert_eq!(offsets[1].material, Some(1));
+    assert_eq!(offsets[1].method, OffsetMethod::Additive);
+    assert_eq!(offsets[1].specular_strength, 2.0);
+    assert_eq!(offsets[1].edge_scale, 0.5);
+  }
+
+  #[test]
+  fn test_read_group_flip_impulse() {
+    let morphs = morphs();
+    assert!(matches!(&morphs[6].offsets, Offsets::Group(o) if o.len() == 2 && o[1].morph == 2));
+    assert!(matches!(&morphs[7].offsets, Offsets::Flip(o) if o[0].morph == 1));
+    assert!(matches!(&morphs[8].offsets, Offsets::Impulse(o) if o[0].local));
+  }
+
+  #[test]
+  fn test_read_unknown_morph_type() {
+    let mut read = FIXTURE_MODEL_PMX;
+    let morphs = morph_reader(&mut read);
+    let start = morphs.read.position as usize;
+
+    // Skip both names and the panel of the first morph
+    let mut kind = start;
+    for _ in 0..2 {
+      let mut len = [0; 4];
+      len.copy_from_slice(&FIXTURE_MODEL_PMX[kind..kind + 4]);
+      kind += 4 + i32::from_le_bytes(len) as usize;
+    }
+    kind += 1;
+    let mut bytes = FIXTURE_MODEL_PMX.to_vec();
+    assert_eq!(bytes[kind], 1);
+    bytes[kind] = 11;
+
+    let mut read = &bytes[..];
+    let mut morphs = morph_reader(&mut read);
+    // The type is checked once the offset count after it is read
+    let e = morphs.next::<DefaultConfig>().unwrap_err();
+    assert!(matches!(
+      e.root(),
+      Error::InvalidMorphType { kind: 11, morph: 0 }
+    ));
+    assert_eq!(
+      e.location(),
+      Some(&ErrorLocation {
+        offset: kind as u64 + 1 + 4,
+        section: "morph",
+        index: Some(0),
+      })
+    );
   }
 }
@@ -0,0 +1,399 @@
+//! Decoding the vertices, surfaces and morphs of `Pmx::parse` on the rayon thread pool, behind
+//! the `rayon` feature.
+//!
+//! The faces have a fixed size, so they are decoded in chunks straight from the slice. The
+//! vertices and morphs don't, a sequential pass walks their records for their sizes like `skip`
+//! does and groups them into runs of about `CHUNK_BYTES`, each decoded by a reader of its own. If
+//! any record is invalid the reader is rewound and the section read again sequentially, so errors
+//! are the same as without the feature.
+
+use crate::pmx::morph::Morph;
+use crate::reader::helpers::{decode_vertex_index, PositionReader};
+use crate::reader::vertex::{weights_len, VERTEX_PREFIX_SIZE};
+use crate::reader::{MorphReader, SliceReader, SurfaceReader, VertexReader};
+use crate::{Config, Error, Result, Vertex};
+use rayon::prelude::*;
+
+/// The bytes of records decoded by one task.
+const CHUNK_BYTES: usize = 1 << 16;
+
+/// A run of records: the offset of the first one in the file, their bytes and their number.
+type Chunk<'a> = (u64, &'a [u8], i32);
+
+/// The section readers over a slice with records of varying sizes, walked record by record.
+trait Records<'a> {
+  fn read(&mut self) -> &mut PositionReader<SliceReader<'a>>;
+
+  fn remaining(&mut self) -> &mut i32;
+
+  /// Moves past the next record without decoding it.
+  fn skip_one(&mut self) -> Result<()>;
+}
+
+impl<'a> Records<'a> for VertexReader<SliceReader<'a>> {
+  fn read(&mut self) -> &mut PositionReader<SliceReader<'a>> {
+    &mut self.read
+  }
+
+  fn remaining(&mut self) -> &mut i32 {
+    &mut self.remaining
+  }
+
+  fn skip_one(&mut self) -> Result<()> {
+    let additional = 16 * self.settings.additional_vec4_count as usize;
+    self.read.read_slice(VERTEX_PREFIX_SIZE + additional)?;
+    let kind = self.read.read_slice(1)?[0];
+    let len = weights_len(kind, self.settings.bone_index_size)?;
+    self.read.read_slice(len)?;
+    self.remaining -= 1;
+    Ok(())
+  }
+}
+
+impl<'a> Records<'a> for MorphReader<SliceReader<'a>> {
+  fn read(&mut self) -> &mut PositionReader<SliceReader<'a>> {
+    &mut self.read
+  }
+
+  fn remaining(&mut self) -> &mut i32 {
+    &mut self.remaining
+  }
+
+  fn skip_one(&mut self) -> Result<()> {
+    MorphReader::skip_one(self)
+  }
+}
+
+/// Whether a section of `len` bytes is decoded in parallel, never on a single thread where the
+/// sequential path is faster, unless `min_bytes` is 0.
+fn is_parallel(len: usize, min_bytes: usize) -> bool {
+  len >= min_bytes && (min_bytes == 0 || rayon::current_num_threads() > 1)
+}
+
+/// Decodes the remaining records of `reader` in parallel, `decode` reading the records of a
+/// chunk one after another. `None` after rewinding `reader` if they take fewer than `min_bytes`
+/// or one of them is invalid.
+fn read_parallel<'a, R, T>(
+  reader: &mut R,
+  min_bytes: usize,
+  decode: impl Fn(&R, PositionReader<SliceReader<'a>>, i32) -> Result<Vec<T>> + Sync,
+) -> Option<Vec<T>>
+where
+  R: Records<'a> + Sync,
+  T: Send,
+{
+  if !is_parallel(usize::MAX, min_bytes) {
+    return None;
+  }
+  let (position, inner, remaining) = {
+    let read = reader.read();
+    (read.position, read.inner.clone(), *reader.remaining())
+  };
+
+  let mut chunks = Vec::<Chunk<'a>>::new();
+  let mut chunk: Chunk<'a> = (position, inner.remaining(), 0);
+  let mut len = 0;
+  let mut valid = true;
+  while *reader.remaining() > 0 {
+    if reader.skip_one().is_err() {
+      valid = false;
+      break;
+    }
+    chunk.2 += 1;
+    let size = (reader.read().position - chunk.0) as usize;
+    if size >= CHUNK_BYTES || *reader.remaining() == 0 {
+      chunks.push((chunk.0, &chunk.1[..size], chunk.2));
+      len += size;
+      chunk = (reader.read().position, reader.read().inner.remaining(), 0);
+    }
+  }
+
+  let decoded = if valid && is_parallel(len, min_bytes) {
+    let reader = &*reader;
+    chunks
+      .into_par_iter()
+      .map(|(position, bytes, count)| {
+        decode(
+          reader,
+          PositionReader::new(SliceReader::new(bytes), position),
+          count,
+        )
+      })
+      .collect::<Result<Vec<Vec<T>>>>()
+      .ok()
+      .map(|chunks| {
+        let mut records = Vec::with_capacity(chunks.iter().map(Vec::len).sum());
+        chunks.into_iter().for_each(|chunk| records.extend(chunk));
+        records
+      })
+  } else {
+    None
+  };
+  if decoded.is_none() {
+    let read = reader.read();
+    read.position = position;
+    read.inner = inner;
+    *reader.remaining() = remaining;
+  }
+  decoded
+}
+
+impl<'a> VertexReader<SliceReader<'a>> {
+  pub(crate) fn read_parallel<C: Config>(&mut self, min_bytes: usize) -> Option<Vec<Vertex<C>>>
+  where
+    Vertex<C>: Send,
+  {
+    if self.poison {
+      return None;
+    }
+    read_parallel(self, min_bytes, |reader, read, count| {
+      let mut chunk = VertexReader {
+        settings: reader.settings,
+        count,
+        remaining: count,
+        read,
+        poison: false,
+      };
+      (0..count).map(|_| chunk.decode_next::<C>()).collect()
+    })
+  }
+}
+
+impl<'a> SurfaceReader<SliceReader<'a>> {
+  /// Decodes the remaining faces with `par_chunks_exact`, `None` without reading anything if
+  /// they take fewer than `min_bytes`, are cut off or one of them is invalid.
+  pub(crate) fn read_parallel<C: Config>(
+    &mut self,
+    min_bytes: usize,
+  ) -> Option<Vec<[C::VertexIndex; 3]>>
+  where
+    C::VertexIndex: Send,
+  {
+    if self.poison || self.remaining <= 0 {
+      return None;
+    }
+    let size = self.settings.vertex_index_size;
+    let face_size = 3 * size as usize;
+    // A count that isn't a multiple of 3 still reads the last face whole
+    let len = (self.remaining as usize).div_ceil(3) * face_size;
+    let bytes = self.read.inner.remaining().get(..len)?;
+    if !is_parallel(len, min_bytes) {
+      return None;
+    }
+
+    let faces = bytes
+      .par_chunks_exact(face_size)
+      .map(|buf| {
+        let vertex = |i: usize| decode_vertex_index(&buf[i * size as usize..], size);
+        Ok([vertex(0)?, vertex(1)?, vertex(2)?])
+      })
+      .collect::<Result<Vec<_>>>()
+      .ok()?;
+    self.read.read_slice(len).ok()?;
+    self.remaining = 0;
+    Some(faces)
+  }
+}
+
+impl<'a> MorphReader<SliceReader<'a>> {
+  pub(crate) fn read_parallel<C: Config>(&mut self, min_bytes: usize) -> Option<Vec<Morph<C>>>
+  where
+    Morph<C>: Send,
+  {
+    if self.poison {
+      return None;
+    }
+    read_parallel(self, min_bytes, |reader, read, count| {
+      let mut chunk = MorphReader {
+        settings: reader.settings,
+        count,
+        remaining: count,
+        read,
+        poison: false,
+      };
+      (0..count)
+        .map(|_| {
+          chunk
+            .next_impl::<C>()?
+            // A reader with a record remaining always reads it
+            .ok_or(Error::Poisoned)
+        })
+        .collect()
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::pmx::morph::{BoneOffset, Offsets, Panel, VertexOffset};
+  use crate::pmx::validate::Section;
+  use crate::pmx::weight_deform::*;
+  use crate::{DefaultConfig, LocalizedName, Pmx, PmxBuilder, PmxReadOptions};
+  use std::ops::ControlFlow;
+
+  const VERTICES: usize = 20_000;
+
+  /// A model with all kinds of weights, an additional vec4 per vertex and many morphs.
+  fn model() -> Vec<u8> {
+    let mut builder = PmxBuilder::<DefaultConfig>::new("parallel");
+    let root = builder.add_bone("センター", [0.0; 3], None);
+    builder.add_bone("頭", [0.0, 1.0, 0.0], Some(root));
+    let vertices: Vec<_> = (0..VERTICES)
+      .map(|i| {
+        let x = i as f32;
+        builder.add_vertex([x, 1.0, 2.0], [0.0, 1.0, 0.0], [0.5, x], root)
+      })
+      .collect();
+    for v in vertices.windows(3) {
+      builder.add_triangle(v[0], v[1], v[2]);
+    }
+    builder.add_material("体", [1.0; 4]);
+    let mut pmx = builder.build().unwrap();
+
+    pmx.version = 2.1;
+    pmx.settings.additional_vec4_count = 1;
+    for (i, v) in pmx.vertices.iter_mut().enumerate() {
+      let w = i as f32 / VERTICES as f32;
+      v.additional = std::iter::once([w, 0.0, 1.0, w].into()).collect();
+      v.weight_deform = match i % 5 {
+        0 => v.weight_deform.clone(),
+        1 => WeightDeform::Bdef2(Bdef2 {
+          bone_1_index: 0,
+          bone_2_index: 1,
+          bone_1_weight: w,
+        }),
+        2 => WeightDeform::Bdef4(Bdef4 {
+          bone_1_index: 0,
+          bone_2_index: 1,
+          bone_3_index: 0,
+          bone_4_index: 1,
+          bone_1_weight: w,
+          bone_2_weight: 1.0 - w,
+          bone_3_weight: 0.0,
+          bone_4_weight: 0.0,
+        }),
+        3 => WeightDeform::Sdef(Sdef {
+          bone_1_index: 0,
+          bone_2_index: 1,
+          bone_1_weight: w,
+          c: [0.0, w, 0.0].into(),
+          r0: [0.0, 0.0, w].into(),
+          r1: [w, 0.0, 0.0].into(),
+        }),
+        _ => WeightDeform::Qdef(Qdef {
+          bone_1_index: 1,
+          bone_2_index: 0,
+          bone_3_index: 1,
+          bone_4_index: 0,
+          bone_1_weight: 1.0 - w,
+          bone_2_weight: w,
+          bone_3_weight: 0.0,
+          bone_4_weight: 0.0,
+        }),
+      };
+    }
+    for m in 0..200 {
+      let offsets = if m % 10 == 0 {
+        Offsets::Bone(vec![BoneOffset {
+          bone: 1,
+          translation: [m as f32, 0.0, 0.0].into(),
+          rotation: [0.0, 0.0, 0.0, 1.0].into(),
+        }])
+      } else {
+        Offsets::Vertex(
+          (0..100)
+            .map(|i| VertexOffset {
+              vertex: m * 100 + i,
+              offset: [0.0, i as f32, m as f32].into(),
+            })
+            .collect(),
+        )
+      };
+      pmx.morphs.push(crate::pmx::morph::Morph {
+        name: LocalizedName::new(format!("モーフ{}", m), ""),
+        panel: Panel::Other,
+        offsets,
+      });
+    }
+
+    let mut bytes = Vec::new();
+    pmx.write(&mut bytes).unwrap();
+    bytes
+  }
+
+  fn parse(bytes: &[u8], parallel_min_bytes: usize) -> crate::Result<Pmx> {
+    let mut options = PmxReadOptions {
+      parallel_min_bytes,
+      ..Default::default()
+    };
+    Pmx::parse_with(bytes, &mut options)
+  }
+
+  #[test]
+  fn test_parallel_parse() {
+    let bytes = model();
+    let sequential = parse(&bytes, usize::MAX).unwrap();
+    assert_eq!(sequential.vertices.len(), VERTICES);
+    assert_eq!(parse(&bytes, 0).unwrap(), sequential);
+    assert_eq!(Pmx::parse(&bytes).unwrap(), sequential);
+    assert_eq!(Pmx::read(&bytes[..]).unwrap(), sequential);
+
+    // Only the start and end of the sections decoded in parallel are reported
+    let mut reports = Vec::new();
+    let mut on_progress = |section, current, total| {
+      reports.push((section, current, total));
+      ControlFlow::Continue(())
+    };
+    let mut options = PmxReadOptions {
+      on_progress: Some(&mut on_progress),
+      progress_interval: 1000,
+      parallel_min_bytes: 0,
+      ..Default::default()
+    };
+    Pmx::<DefaultConfig>::parse_with(&bytes, &mut options).unwrap();
+    let vertices: Vec<_> = reports
+      .iter()
+      .filter(|(section, ..)| *section == Section::Vertices)
+      .collect();
+    assert_eq!(
+      vertices,
+      [
+        &(Section::Vertices, 0, VERTICES),
+        &(Section::Vertices, VERTICES, VERTICES)
+      ]
+    );
+  }
+
+  #[test]
+  fn test_parallel_errors() {
+    let fixture = include_bytes!("../../../fixtures/model.pmx");
+    for len in 0..fixture.len() {
+      let parallel = parse(&fixture[..len], 0).unwrap_err();
+      let sequential = parse(&fixture[..len], usize::MAX).unwrap_err();
+      assert_eq!(format!("{:?}", parallel), format!("{:?}", sequential));
+    }
+
+    // An unknown weight kind in the middle of the vertices
+    let mut bytes = model();
+    let start = crate::reader::VertexReader::new(crate::HeaderReader::new(&bytes[..]).unwrap())
+      .unwrap()
+      .read
+      .position as usize;
+    // Position, normal, UV and the additional vec4, then BDEF1, BDEF2 and BDEF4 vertices with
+    // 8-bit bone indices before the kind of the SDEF vertex
+    let record = 32 + 16;
+    let kind = start + (record + 6) + (record + 11) + (record + 25) + record;
+    assert_eq!(bytes[kind], 3);
+    bytes[kind] = 7;
+    let parallel = parse(&bytes, 0).unwrap_err();
+    assert!(matches!(
+      parallel.root(),
+      crate::Error::UnknownWeightType(7)
+    ));
+    assert_eq!(parallel.location().unwrap().index, Some(3));
+    assert_eq!(
+      format!("{:?}", parallel),
+      format!("{:?}", parse(&bytes, usize::MAX).unwrap_err())
+    );
+  }
+}
@@ -1,28 +1,35 @@
+use crate::io::{Read, ReadBytesExt};
 use crate::{
   pmx::rigid_body::*,
-  reader::{helpers::ReadHelpers, DisplayReader},
-  Config, DefaultConfig, Result, Settings,
+  reader::{
+    helpers::{PositionReader, ReadHelpers},
+    DisplayReader,
+  },
+  Config, DefaultConfig, Error, LocalizedName, Result, Settings,
 };
-use byteorder::{ReadBytesExt, LE};
-use std::convert::TryFrom;
-use std::io::Read;
-use std::marker::PhantomData;
+use byteorder::LE;
+use core::convert::TryFrom;
+use core::marker::PhantomData;
+
+pub(crate) const SECTION: &str = "rigid body";
 
 pub struct RigidBodyReader<R> {
   pub settings: Settings,
   pub count: i32,
   pub remaining: i32,
-  pub(crate) read: R,
+  pub(crate) read: PositionReader<R>,
   pub(crate) poison: bool,
 }
 
 impl<R: Read> RigidBodyReader<R> {
   pub fn new(mut d: DisplayReader<R>) -> Result<RigidBodyReader<R>> {
-    assert!(!d.poison);
+    if d.poison {
+      return Err(Error::Poisoned);
+    }
     while d.remaining > 0 {
       d.next::<DefaultConfig>()?;
     }
-    let count = d.read.read_i32::<LE>()?;
+    let count = d.read.read_count(SECTION)?;
 
     Ok(RigidBodyReader {
       settings: d.settings,
@@ -34,12 +41,15 @@ impl<R: Read> RigidBodyReader<R> {
   }
 
   pub fn next<C: Config>(&mut self) -> Result<Option<RigidBody<C>>> {
-    assert!(!self.poison);
+    if self.poison {
+      return Err(Error::Poisoned);
+    }
+    let index = (self.count - self.remaining) as usize;
     let result = self.next_impl::<C>();
     if result.is_err() {
       self.poison = true;
     }
-    result
+    result.map_err(|e| e.context(self.read.position, SECTION, Some(index)))
   }
 
   fn next_impl<C: Config>(&mut self) -> Result<Option<RigidBody<C>>> {
@@ -50,9 +60,13 @@ impl<R: Read> RigidBodyReader<R> {
     self.remaining -= 1;
 
     Ok(Some(RigidBody {
-      local_name: self.read.read_text(self.settings.text_encoding)?,
-      universal_name: self.read.read_text(self.settings.text_encoding)?,
-      bone_index: self.read.read_index(self.settings.bone_index_size)?,
+      name: LocalizedName {
+        ja: self.read.read_text(self.settings.text_encoding)?,
+        en: self.read.read_text(self.settings.text_encoding)?,
+      },
+      bone_index: self
+        .read
+        .read_optional_index(self.settings.bone_index_size)?,
       group_id: self.read.read_u8()?,
       non_collision_mask: self.read.read_u16::<LE>()?,
       shape: ShapeType::try_from(self.read.read_u8()?)?,
@@ -63,12 +77,12 @@ impl<R: Read> RigidBodyReader<R> {
       move_attenuation: self.read.read_f32::<LE>()?,
       rotation_damping: self.read.read_f32::<LE>()?,
       repulsion: self.read.read_f32::<LE>()?,
-      fiction: self.read.read_f32::<LE>()?,
+      friction: self.read.read_f32::<LE>()?,
       physics_mode: PhysicsMode::try_from(self.read.read_u8()?)?,
     }))
   }
 
-  pub fn iter<C>(&mut self) -> RigidBodyIterator<R, C> {
+  pub fn iter<C>(&mut self) -> RigidBodyIterator<'_, R, C> {
     RigidBodyIterator {
       reader: self,
       phantom: PhantomData,
@@ -93,14 +107,48 @@ impl<R: Read, C: Config> Iterator for RigidBodyIterator<'_, R, C> {
 
   fn size_hint(&self) -> (usize, Option<usize>) {
     (
-      self.reader.remaining as usize,
-      Some(self.reader.remaining as usize),
+      self.reader.remaining.max(0) as usize,
+      Some(self.reader.remaining.max(0) as usize),
     )
   }
 }
 
 impl<R: Read, C: Config> ExactSizeIterator for RigidBodyIterator<'_, R, C> {
   fn len(&self) -> usize {
-    self.reader.remaining as usize
+    self.reader.remaining.max(0) as usize
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::Pmx;
+
+  const FIXTURE_MODEL_PMX: &[u8] = include_bytes!("../../../fixtures/model.pmx");
+
+  #[test]
+  fn test_read_rigid_body() {
+    let pmx = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    let rigid_body = &pmx.rigid_bodies[0];
+
+    assert_eq!(rigid_body.name.ja, "頭");
+    assert_eq!(rigid_body.bone_index, Some(0));
+    assert_eq!(rigid_body.non_collision_mask, 0xfffd);
+    assert_eq!(rigid_body.shape, ShapeType::Capsule);
+    assert_eq!(rigid_body.mass, 1.0);
+    assert_eq!(rigid_body.friction, 0.5);
+    assert_eq!(rigid_body.physics_mode, PhysicsMode::Dynamic);
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn test_read_rigid_body_without_bone() {
+    let mut pmx = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    pmx.rigid_bodies[0].bone_index = None;
+    let mut bytes = Vec::new();
+    pmx.write(&mut bytes).unwrap();
+
+    let read = Pmx::<DefaultConfig>::read(&bytes[..]).unwrap();
+    assert_eq!(read.rigid_bodies[0].bone_index, None);
   }
 }
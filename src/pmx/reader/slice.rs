@@ -0,0 +1,212 @@
+//! Reading models that are in memory as a whole, see `Pmx::parse`.
+
+use crate::io::Read;
+use crate::pmx::morph::Morph;
+use crate::pmx::validate::Section;
+use crate::reader::helpers::PositionReader;
+use crate::reader::{MorphReader, SurfaceReader, VertexReader};
+use crate::{Config, Error, MaybeSend, Result, Vertex};
+#[cfg(feature = "rayon")]
+use alloc::vec::Vec;
+
+/// Reads from a byte slice.
+///
+/// It works with all readers through `Read`, and the vertex and surface readers decode their
+/// records from the slice without copying them with their `read_all`.
+#[derive(Clone, Debug)]
+pub struct SliceReader<'a> {
+  bytes: &'a [u8],
+  position: usize,
+}
+
+impl<'a> SliceReader<'a> {
+  pub fn new(bytes: &'a [u8]) -> Self {
+    SliceReader { bytes, position: 0 }
+  }
+
+  /// The number of bytes read.
+  pub fn position(&self) -> usize {
+    self.position
+  }
+
+  /// The bytes left to read.
+  pub fn remaining(&self) -> &'a [u8] {
+    &self.bytes[self.position..]
+  }
+
+  /// The next `len` bytes, reading nothing when fewer are left.
+  pub(crate) fn read_slice(&mut self, len: usize) -> Result<&'a [u8]> {
+    let remaining = self.remaining();
+    let bytes = remaining.get(..len).ok_or(Error::UnexpectedEnd {
+      needed: len,
+      available: remaining.len(),
+    })?;
+    self.position += len;
+    Ok(bytes)
+  }
+}
+
+impl Read for SliceReader<'_> {
+  fn read(&mut self, buf: &mut [u8]) -> crate::io::Result<usize> {
+    let remaining = self.remaining();
+    let len = buf.len().min(remaining.len());
+    buf[..len].copy_from_slice(&remaining[..len]);
+    self.position += len;
+    Ok(len)
+  }
+}
+
+impl<'a> PositionReader<SliceReader<'a>> {
+  pub(crate) fn read_slice(&mut self, len: usize) -> Result<&'a [u8]> {
+    let bytes = self.inner.read_slice(len)?;
+    self.position += len as u64;
+    Ok(bytes)
+  }
+}
+
+/// The sections `Pmx::parse` decodes straight from the slice, and with the `rayon` feature on
+/// the rayon thread pool once they are large enough.
+pub(crate) trait SliceSection<C: Config> {
+  type Item;
+  const SECTION: Section;
+
+  /// The number of elements of the section.
+  fn total(&self) -> i32;
+
+  fn next_slice(&mut self) -> Result<Option<Self::Item>>;
+
+  /// The remaining elements if they take at least `min_bytes` and are all valid, see
+  /// `reader::parallel`.
+  #[cfg(feature = "rayon")]
+  fn read_parallel(&mut self, min_bytes: usize) -> Option<Vec<Self::Item>>;
+}
+
+impl<C: Config> SliceSection<C> for VertexReader<SliceReader<'_>>
+where
+  Vertex<C>: MaybeSend,
+{
+  type Item = Vertex<C>;
+  const SECTION: Section = Section::Vertices;
+
+  fn total(&self) -> i32 {
+    self.count
+  }
+
+  fn next_slice(&mut self) -> Result<Option<Vertex<C>>> {
+    VertexReader::next_slice(self)
+  }
+
+  #[cfg(feature = "rayon")]
+  fn read_parallel(&mut self, min_bytes: usize) -> Option<Vec<Vertex<C>>> {
+    VertexReader::read_parallel(self, min_bytes)
+  }
+}
+
+impl<C: Config> SliceSection<C> for SurfaceReader<SliceReader<'_>>
+where
+  C::VertexIndex: MaybeSend,
+{
+  type Item = [C::VertexIndex; 3];
+  const SECTION: Section = Section::Surfaces;
+
+  fn total(&self) -> i32 {
+    self.count / 3
+  }
+
+  fn next_slice(&mut self) -> Result<Option<[C::VertexIndex; 3]>> {
+    SurfaceReader::next_slice::<C>(self)
+  }
+
+  #[cfg(feature = "rayon")]
+  fn read_parallel(&mut self, min_bytes: usize) -> Option<Vec<[C::VertexIndex; 3]>> {
+    SurfaceReader::read_parallel::<C>(self, min_bytes)
+  }
+}
+
+impl<C: Config> SliceSection<C> for MorphReader<SliceReader<'_>>
+where
+  Morph<C>: MaybeSend,
+{
+  type Item = Morph<C>;
+  const SECTION: Section = Section::Morphs;
+
+  fn total(&self) -> i32 {
+    self.count
+  }
+
+  fn next_slice(&mut self) -> Result<Option<Morph<C>>> {
+    self.next()
+  }
+
+  #[cfg(feature = "rayon")]
+  fn read_parallel(&mut self, min_bytes: usize) -> Option<Vec<Morph<C>>> {
+    MorphReader::read_parallel(self, min_bytes)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{DefaultConfig, ErrorLocation, Pmx};
+
+  const FIXTURES: [&[u8]; 3] = [
+    include_bytes!("../../../fixtures/model.pmx"),
+    include_bytes!("../../../fixtures/model_uv4.pmx"),
+    include_bytes!("../../../fixtures/model_axes.pmx"),
+  ];
+
+  #[test]
+  fn test_parse_fixtures() {
+    for fixture in FIXTURES.iter() {
+      assert_eq!(
+        Pmx::<DefaultConfig>::parse(fixture).unwrap(),
+        Pmx::read(*fixture).unwrap()
+      );
+    }
+  }
+
+  #[test]
+  fn test_parse_truncated() {
+    let fixture = FIXTURES[0];
+    for len in 0..fixture.len() {
+      let parsed = Pmx::<DefaultConfig>::parse(&fixture[..len]).unwrap_err();
+      let read = Pmx::<DefaultConfig>::read(&fixture[..len]).unwrap_err();
+      let (parsed, read) = (parsed.location().unwrap(), read.location().unwrap());
+      assert_eq!((parsed.section, parsed.index), (read.section, read.index));
+    }
+
+    // The input ends inside the first triangle
+    let surfaces = crate::reader::SurfaceReader::new(
+      crate::reader::VertexReader::new(crate::HeaderReader::new(fixture).unwrap()).unwrap(),
+    )
+    .unwrap();
+    let start = surfaces.read.position as usize;
+    let e = Pmx::<DefaultConfig>::parse(&fixture[..start + 1]).unwrap_err();
+    assert!(matches!(
+      e.root(),
+      Error::UnexpectedEnd { available: 1, .. }
+    ));
+    assert_eq!(
+      e.location(),
+      Some(&ErrorLocation {
+        offset: start as u64,
+        section: "surface",
+        index: Some(0),
+      })
+    );
+  }
+
+  #[test]
+  fn test_slice_read() {
+    let mut read = SliceReader::new(&[1, 2, 3]);
+    let mut buf = [0; 2];
+    assert_eq!(read.read(&mut buf).unwrap(), 2);
+    assert_eq!(
+      read.read_slice(2).unwrap_err().to_string(),
+      "Unexpected end of input, needed 2 bytes but 1 are left"
+    );
+    assert_eq!(read.position(), 2);
+    assert_eq!(read.read_slice(1).unwrap(), [3]);
+    assert_eq!(read.read(&mut buf).unwrap(), 0);
+  }
+}
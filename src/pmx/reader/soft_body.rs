@@ -0,0 +1,190 @@
+use crate::io::{Read, ReadBytesExt};
+use crate::{
+  pmx::soft_body::*,
+  reader::{
+    helpers::{PositionReader, ReadHelpers},
+    JointReader,
+  },
+  Config, DefaultConfig, Error, LocalizedName, Result, Settings,
+};
+use alloc::vec::Vec;
+use byteorder::LE;
+use core::convert::TryFrom;
+use core::marker::PhantomData;
+use enumflags2::BitFlags;
+
+pub(crate) const SECTION: &str = "soft body";
+
+/// Reads the soft body section which only PMX 2.1 files have, check the header version with
+/// `pmx::model::has_soft_bodies` before creating one.
+pub struct SoftBodyReader<R> {
+  pub settings: Settings,
+  pub count: i32,
+  pub remaining: i32,
+  pub(crate) read: PositionReader<R>,
+  pub(crate) poison: bool,
+}
+
+impl<R: Read> SoftBodyReader<R> {
+  pub fn new(mut j: JointReader<R>) -> Result<SoftBodyReader<R>> {
+    if j.poison {
+      return Err(Error::Poisoned);
+    }
+    while j.remaining > 0 {
+      j.next::<DefaultConfig>()?;
+    }
+    let count = j.read.read_count(SECTION)?;
+
+    Ok(SoftBodyReader {
+      settings: j.settings,
+      count,
+      remaining: count,
+      read: j.read,
+      poison: false,
+    })
+  }
+
+  pub fn next<C: Config>(&mut self) -> Result<Option<SoftBody<C>>> {
+    if self.poison {
+      return Err(Error::Poisoned);
+    }
+    let index = (self.count - self.remaining) as usize;
+    let result = self.next_impl::<C>();
+    if result.is_err() {
+      self.poison = true;
+    }
+    result.map_err(|e| e.context(self.read.position, SECTION, Some(index)))
+  }
+
+  fn next_impl<C: Config>(&mut self) -> Result<Option<SoftBody<C>>> {
+    if self.remaining <= 0 {
+      return Ok(None);
+    }
+
+    self.remaining -= 1;
+
+    let local_name = self.read.read_text(self.settings.text_encoding)?;
+    let universal_name = self.read.read_text(self.settings.text_encoding)?;
+    let shape = SoftBodyShape::try_from(self.read.read_u8()?)?;
+    let material = self.read.read_index(self.settings.material_index_size)?;
+    let group_id = self.read.read_u8()?;
+    let non_collision_mask = self.read.read_u16::<LE>()?;
+    let flags = self.read.read_u8()?;
+    let flags = BitFlags::from_bits(flags).map_err(|_| Error::InvalidSoftBodyFlags(flags))?;
+    let link_distance = self.read.read_i32::<LE>()?;
+    let cluster_count = self.read.read_i32::<LE>()?;
+    let mass = self.read.read_f32::<LE>()?;
+    let collision_margin = self.read.read_f32::<LE>()?;
+    let aero_model = AeroModel::try_from(self.read.read_i32::<LE>()?)?;
+    let parameters = self.read_parameters()?;
+
+    let count = self.read.read_i32::<LE>()?;
+    let mut anchors = Vec::new();
+    for _ in 0..count {
+      anchors.push(Anchor {
+        rigid_body: self.read.read_index(self.settings.rigidbody_index_size)?,
+        vertex: self
+          .read
+          .read_vertex_index(self.settings.vertex_index_size)?,
+        near_mode: self.read.read_u8()? != 0,
+      });
+    }
+
+    let count = self.read.read_i32::<LE>()?;
+    let mut pinned_vertices = Vec::new();
+    for _ in 0..count {
+      pinned_vertices.push(
+        self
+          .read
+          .read_vertex_index(self.settings.vertex_index_size)?,
+      );
+    }
+
+    Ok(Some(SoftBody {
+      name: LocalizedName {
+        ja: local_name,
+        en: universal_name,
+      },
+      shape,
+      material,
+      group_id,
+      non_collision_mask,
+      flags,
+      link_distance,
+      cluster_count,
+      mass,
+      collision_margin,
+      aero_model,
+      parameters,
+      anchors,
+      pinned_vertices,
+    }))
+  }
+
+  fn read_parameters(&mut self) -> Result<SoftBodyParameters> {
+    let read = &mut self.read;
+    Ok(SoftBodyParameters {
+      velocity_correction: read.read_f32::<LE>()?,
+      damping: read.read_f32::<LE>()?,
+      drag: read.read_f32::<LE>()?,
+      lift: read.read_f32::<LE>()?,
+      pressure: read.read_f32::<LE>()?,
+      volume_conservation: read.read_f32::<LE>()?,
+      dynamic_friction: read.read_f32::<LE>()?,
+      pose_matching: read.read_f32::<LE>()?,
+      rigid_contact_hardness: read.read_f32::<LE>()?,
+      kinetic_contact_hardness: read.read_f32::<LE>()?,
+      soft_contact_hardness: read.read_f32::<LE>()?,
+      anchor_hardness: read.read_f32::<LE>()?,
+      cluster_rigid_hardness: read.read_f32::<LE>()?,
+      cluster_kinetic_hardness: read.read_f32::<LE>()?,
+      cluster_soft_hardness: read.read_f32::<LE>()?,
+      cluster_rigid_impulse_split: read.read_f32::<LE>()?,
+      cluster_kinetic_impulse_split: read.read_f32::<LE>()?,
+      cluster_soft_impulse_split: read.read_f32::<LE>()?,
+      velocity_iterations: read.read_i32::<LE>()?,
+      position_iterations: read.read_i32::<LE>()?,
+      drift_iterations: read.read_i32::<LE>()?,
+      cluster_iterations: read.read_i32::<LE>()?,
+      linear_stiffness: read.read_f32::<LE>()?,
+      area_stiffness: read.read_f32::<LE>()?,
+      volume_stiffness: read.read_f32::<LE>()?,
+    })
+  }
+
+  pub fn iter<C>(&mut self) -> SoftBodyIterator<'_, R, C> {
+    SoftBodyIterator {
+      reader: self,
+      phantom: PhantomData,
+    }
+  }
+}
+
+pub struct SoftBodyIterator<'a, R, C> {
+  reader: &'a mut SoftBodyReader<R>,
+  phantom: PhantomData<C>,
+}
+
+impl<R: Read, C: Config> Iterator for SoftBodyIterator<'_, R, C> {
+  type Item = Result<SoftBody<C>>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self
+      .reader
+      .next()
+      .map_or_else(|e| Some(Err(e)), |v| v.map(Ok))
+  }
+
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    (
+      self.reader.remaining.max(0) as usize,
+      Some(self.reader.remaining.max(0) as usize),
+    )
+  }
+}
+
+impl<R: Read, C: Config> ExactSizeIterator for SoftBodyIterator<'_, R, C> {
+  fn len(&self) -> usize {
+    self.reader.remaining.max(0) as usize
+  }
+}
@@ -1,26 +1,41 @@
+use crate::io::Read;
 use crate::{
-  reader::{helpers::ReadHelpers, VertexReader},
-  Config, DefaultConfig, Result, Settings,
+  reader::{
+    helpers::{decode_vertex_index, PositionReader},
+    SliceReader, VertexReader,
+  },
+  Config, DefaultConfig, Error, Result, Settings,
 };
-use byteorder::{ReadBytesExt, LE};
-use std::io::Read;
-use std::marker::PhantomData;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
 
+pub(crate) const SECTION: &str = "surface";
+
+/// Reads the count of the surface section, which is made of whole triangles.
+pub(crate) fn read_surface_count<R: Read>(read: &mut PositionReader<R>) -> Result<i32> {
+  let count = read.read_count(SECTION)?;
+  if count % 3 != 0 {
+    return Err(Error::InvalidSurfaceCount(count).context(read.position, SECTION, None));
+  }
+  Ok(count)
+}
+
+/// Reads the surfaces as triangles, the count is of their vertex indices.
 pub struct SurfaceReader<R> {
   pub settings: Settings,
   pub count: i32,
   pub remaining: i32,
-  pub(crate) read: R,
+  pub(crate) read: PositionReader<R>,
   pub(crate) poison: bool,
 }
 
 impl<R: Read> SurfaceReader<R> {
   pub fn new(mut v: VertexReader<R>) -> Result<SurfaceReader<R>> {
-    assert!(!v.poison);
-    while v.remaining > 0 {
-      v.next::<DefaultConfig>()?;
+    if v.poison {
+      return Err(Error::Poisoned);
     }
-    let count = v.read.read_i32::<LE>()?;
+    v.skip()?;
+    let count = read_surface_count(&mut v.read)?;
 
     Ok(SurfaceReader {
       settings: v.settings,
@@ -32,12 +47,15 @@ impl<R: Read> SurfaceReader<R> {
   }
 
   pub fn next<C: Config>(&mut self) -> Result<Option<[C::VertexIndex; 3]>> {
-    assert!(!self.poison);
+    if self.poison {
+      return Err(Error::Poisoned);
+    }
+    let index = (self.count - self.remaining) as usize / 3;
     let result = self.next_impl::<C>();
     if result.is_err() {
       self.poison = true;
     }
-    result
+    result.map_err(|e| e.context(self.read.position, SECTION, Some(index)))
   }
 
   fn next_impl<C: Config>(&mut self) -> Result<Option<[C::VertexIndex; 3]>> {
@@ -45,21 +63,31 @@ impl<R: Read> SurfaceReader<R> {
       return Ok(None);
     }
 
+    let size = self.settings.vertex_index_size;
+    let mut buf = [0; 12];
+    self.read.read_exact(&mut buf[..3 * size as usize])?;
+    let vertex = |i: usize| decode_vertex_index(&buf[i * size as usize..], size);
+
     self.remaining -= 3;
-    Ok(Some([
-      self
-        .read
-        .read_vertex_index(self.settings.vertex_index_size)?,
-      self
-        .read
-        .read_vertex_index(self.settings.vertex_index_size)?,
-      self
-        .read
-        .read_vertex_index(self.settings.vertex_index_size)?,
-    ]))
+    Ok(Some([vertex(0)?, vertex(1)?, vertex(2)?]))
+  }
+
+  /// Moves past the remaining triangles without reading them.
+  pub fn skip(&mut self) -> Result<()> {
+    if self.poison {
+      return Err(Error::Poisoned);
+    }
+    let index = (self.count - self.remaining) as usize / 3;
+    let len = self.remaining.max(0) as u64 * self.settings.vertex_index_size as u64;
+    if let Err(e) = self.read.skip(len) {
+      self.poison = true;
+      return Err(e.context(self.read.position, SECTION, Some(index)));
+    }
+    self.remaining = 0;
+    Ok(())
   }
 
-  pub fn iter<I>(&mut self) -> SurfaceIterator<R, I> {
+  pub fn iter<I>(&mut self) -> SurfaceIterator<'_, R, I> {
     SurfaceIterator {
       reader: self,
       phantom: PhantomData,
@@ -67,6 +95,47 @@ impl<R: Read> SurfaceReader<R> {
   }
 }
 
+impl<'a> SurfaceReader<SliceReader<'a>> {
+  /// Decodes the remaining triangles straight from the slice, like collecting `iter`.
+  pub fn read_all<C: Config>(&mut self) -> Result<Vec<[C::VertexIndex; 3]>> {
+    let size = self.settings.vertex_index_size as usize;
+    let capacity =
+      (self.remaining.max(0) as usize / 3).min(self.read.inner.remaining().len() / (3 * size));
+    let mut surfaces = Vec::with_capacity(capacity);
+    while let Some(surface) = self.next_slice::<C>()? {
+      surfaces.push(surface);
+    }
+    Ok(surfaces)
+  }
+
+  /// `next` decoding straight from the slice.
+  pub(crate) fn next_slice<C: Config>(&mut self) -> Result<Option<[C::VertexIndex; 3]>> {
+    if self.poison {
+      return Err(Error::Poisoned);
+    }
+    if self.remaining <= 0 {
+      return Ok(None);
+    }
+    let index = (self.count - self.remaining) as usize / 3;
+    let result = self.decode_next::<C>();
+    if result.is_err() {
+      self.poison = true;
+    }
+    result
+      .map(Some)
+      .map_err(|e| e.context(self.read.position, SECTION, Some(index)))
+  }
+
+  pub(super) fn decode_next<C: Config>(&mut self) -> Result<[C::VertexIndex; 3]> {
+    let size = self.settings.vertex_index_size;
+    let buf = self.read.read_slice(3 * size as usize)?;
+    let vertex = |i: usize| decode_vertex_index(&buf[i * size as usize..], size);
+
+    self.remaining -= 3;
+    Ok([vertex(0)?, vertex(1)?, vertex(2)?])
+  }
+}
+
 pub struct SurfaceIterator<'a, R, C = DefaultConfig> {
   reader: &'a mut SurfaceReader<R>,
   phantom: PhantomData<C>,
@@ -84,14 +153,52 @@ impl<R: Read, C: Config> Iterator for SurfaceIterator<'_, R, C> {
 
   fn size_hint(&self) -> (usize, Option<usize>) {
     (
-      self.reader.remaining as usize,
-      Some(self.reader.remaining as usize),
+      self.reader.remaining.max(0) as usize,
+      Some(self.reader.remaining.max(0) as usize),
     )
   }
 }
 
 impl<R: Read, C: Config> ExactSizeIterator for SurfaceIterator<'_, R, C> {
   fn len(&self) -> usize {
-    self.reader.remaining as usize
+    self.reader.remaining.max(0) as usize
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{reader::HeaderReader, ErrorLocation};
+  use byteorder::{ByteOrder, LE};
+
+  const FIXTURE_MODEL_PMX: &[u8] = include_bytes!("../../../fixtures/model.pmx");
+
+  #[test]
+  fn test_read_partial_triangle() {
+    let mut vertices = VertexReader::new(HeaderReader::new(FIXTURE_MODEL_PMX).unwrap()).unwrap();
+    while vertices.next::<DefaultConfig>().unwrap().is_some() {}
+    let count = vertices.read.position as usize;
+    assert_eq!(LE::read_i32(&FIXTURE_MODEL_PMX[count..]), 12);
+
+    let mut bytes = FIXTURE_MODEL_PMX.to_vec();
+    LE::write_i32(&mut bytes[count..], 11);
+    let vertices = VertexReader::new(HeaderReader::new(&bytes[..]).unwrap()).unwrap();
+    let e = SurfaceReader::new(vertices).err().unwrap();
+    assert!(matches!(e.root(), Error::InvalidSurfaceCount(11)));
+    assert_eq!(
+      e.location(),
+      Some(&ErrorLocation {
+        offset: count as u64 + 4,
+        section: SECTION,
+        index: None,
+      })
+    );
+    assert_eq!(
+      e.to_string(),
+      format!(
+        "Surface count 11 is not a multiple of 3 at byte {} in the surface section",
+        count + 4
+      )
+    );
   }
 }
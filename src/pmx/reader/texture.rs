@@ -1,25 +1,30 @@
+use crate::io::Read;
 use crate::{
-  reader::{helpers::ReadHelpers, SurfaceReader},
-  DefaultConfig, Result, Settings,
+  reader::{
+    helpers::{PositionReader, ReadHelpers},
+    SurfaceReader,
+  },
+  Error, Result, Settings,
 };
-use byteorder::{ReadBytesExt, LE};
-use std::io::Read;
+use alloc::string::String;
+
+pub(crate) const SECTION: &str = "texture";
 
 pub struct TextureReader<R> {
   pub settings: Settings,
   pub count: i32,
   pub remaining: i32,
-  pub(crate) read: R,
+  pub(crate) read: PositionReader<R>,
   pub(crate) poison: bool,
 }
 
 impl<R: Read> TextureReader<R> {
   pub fn new(mut s: SurfaceReader<R>) -> Result<TextureReader<R>> {
-    assert!(!s.poison);
-    while s.remaining > 0 {
-      s.next::<DefaultConfig>()?;
+    if s.poison {
+      return Err(Error::Poisoned);
     }
-    let count = s.read.read_i32::<LE>()?;
+    s.skip()?;
+    let count = s.read.read_count(SECTION)?;
 
     Ok(TextureReader {
       settings: s.settings,
@@ -31,12 +36,31 @@ impl<R: Read> TextureReader<R> {
   }
 
   pub fn next(&mut self) -> Result<Option<String>> {
-    assert!(!self.poison);
+    if self.poison {
+      return Err(Error::Poisoned);
+    }
+    let index = (self.count - self.remaining) as usize;
     let result = self.next_impl();
     if result.is_err() {
       self.poison = true;
     }
-    result
+    result.map_err(|e| e.context(self.read.position, SECTION, Some(index)))
+  }
+
+  /// Moves past the remaining textures without decoding them.
+  pub fn skip(&mut self) -> Result<()> {
+    if self.poison {
+      return Err(Error::Poisoned);
+    }
+    while self.remaining > 0 {
+      let index = (self.count - self.remaining) as usize;
+      if let Err(e) = self.read.skip_text() {
+        self.poison = true;
+        return Err(e.context(self.read.position, SECTION, Some(index)));
+      }
+      self.remaining -= 1;
+    }
+    Ok(())
   }
 
   fn next_impl(&mut self) -> Result<Option<String>> {
@@ -49,7 +73,7 @@ impl<R: Read> TextureReader<R> {
     self.read.read_text(self.settings.text_encoding).map(Some)
   }
 
-  pub fn iter(&mut self) -> TextureIterator<R> {
+  pub fn iter(&mut self) -> TextureIterator<'_, R> {
     TextureIterator { reader: self }
   }
 }
@@ -70,14 +94,14 @@ impl<R: Read> Iterator for TextureIterator<'_, R> {
 
   fn size_hint(&self) -> (usize, Option<usize>) {
     (
-      self.reader.remaining as usize,
-      Some(self.reader.remaining as usize),
+      self.reader.remaining.max(0) as usize,
+      Some(self.reader.remaining.max(0) as usize),
     )
   }
 }
 
 impl<R: Read> ExactSizeIterator for TextureIterator<'_, R> {
   fn len(&self) -> usize {
-    self.reader.remaining as usize
+    self.reader.remaining.max(0) as usize
   }
 }
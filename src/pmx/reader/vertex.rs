@@ -1,23 +1,115 @@
+use crate::io::{Read, ReadBytesExt};
 use crate::{
+  math::Widen,
   pmx::weight_deform::*,
-  reader::{helpers::ReadHelpers, HeaderReader},
-  Config, DefaultConfig, Error, Result, Settings, Vertex,
+  reader::{
+    helpers::{decode_index, PositionReader},
+    HeaderReader, SliceReader,
+  },
+  vmd::decode_vec,
+  Config, DefaultConfig, Error, IndexSize, Result, Settings, Vertex,
 };
-use byteorder::{ReadBytesExt, LE};
-use std::marker::PhantomData;
-use std::{io::Read, usize};
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LE};
+use core::marker::PhantomData;
+
+/// Position, normal and UV.
+pub(super) const VERTEX_PREFIX_SIZE: usize = 32;
+/// The largest weights, SDEF with 2 32-bit bones, a weight and 3 vectors.
+const MAX_WEIGHTS_SIZE: usize = 2 * 4 + 4 + 3 * 12;
+
+pub(crate) const SECTION: &str = "vertex";
+
+/// Numbers of bones and floats of the weights of `kind`, SDEF has a weight and 3 vectors.
+fn weights_layout(kind: u8) -> Result<(usize, usize)> {
+  Ok(match kind {
+    0 => (1, 0),
+    1 => (2, 1),
+    2 | 4 => (4, 4),
+    3 => (2, 10),
+    e => return Err(Error::UnknownWeightType(e)),
+  })
+}
+
+/// The size of the weights of `kind` and the edge scale after them.
+pub(super) fn weights_len(kind: u8, size: IndexSize) -> Result<usize> {
+  let (bones, floats) = weights_layout(kind)?;
+  Ok(bones * size as usize + floats * 4 + 4)
+}
+
+/// Decodes a vertex from its position, normal and UV in `prefix` and its weights and edge scale
+/// in `buf`.
+fn decode_vertex<C: Config>(
+  prefix: &[u8],
+  additional: C::AdditionalVec4s,
+  kind: u8,
+  buf: &[u8],
+  size: IndexSize,
+) -> Result<Vertex<C>> {
+  let (bones, _) = weights_layout(kind)?;
+  let bone = |i: usize| decode_index(&buf[i * size as usize..], size);
+  let weight = |i: usize| LE::read_f32(&buf[bones * size as usize + i * 4..]);
+  let vec3 = |i: usize| decode_vec::<3>(&buf[bones * size as usize + 4 + i * 12..]).widen();
+  let weight_deform = match kind {
+    0 => WeightDeform::Bdef1(Bdef1 {
+      bone_index: bone(0)?,
+    }),
+    1 => WeightDeform::Bdef2(Bdef2 {
+      bone_1_index: bone(0)?,
+      bone_2_index: bone(1)?,
+      bone_1_weight: weight(0),
+    }),
+    2 => WeightDeform::Bdef4(Bdef4 {
+      bone_1_index: bone(0)?,
+      bone_2_index: bone(1)?,
+      bone_3_index: bone(2)?,
+      bone_4_index: bone(3)?,
+      bone_1_weight: weight(0),
+      bone_2_weight: weight(1),
+      bone_3_weight: weight(2),
+      bone_4_weight: weight(3),
+    }),
+    3 => WeightDeform::Sdef(Sdef {
+      bone_1_index: bone(0)?,
+      bone_2_index: bone(1)?,
+      bone_1_weight: weight(0),
+      c: vec3(0),
+      r0: vec3(1),
+      r1: vec3(2),
+    }),
+    _ => WeightDeform::Qdef(Qdef {
+      bone_1_index: bone(0)?,
+      bone_2_index: bone(1)?,
+      bone_3_index: bone(2)?,
+      bone_4_index: bone(3)?,
+      bone_1_weight: weight(0),
+      bone_2_weight: weight(1),
+      bone_3_weight: weight(2),
+      bone_4_weight: weight(3),
+    }),
+  };
+
+  Ok(Vertex {
+    position: decode_vec::<3>(prefix).widen(),
+    normal: decode_vec::<3>(&prefix[12..]).widen(),
+    uv: decode_vec::<2>(&prefix[24..]).widen(),
+    additional,
+    weight_deform,
+    edge_scale: LE::read_f32(&buf[buf.len() - 4..]),
+  })
+}
 
 pub struct VertexReader<R> {
   pub settings: Settings,
   pub count: i32,
   pub remaining: i32,
-  pub(crate) read: R,
+  pub(crate) read: PositionReader<R>,
   pub(crate) poison: bool,
 }
 
 impl<R: Read> VertexReader<R> {
   pub fn new(mut header: HeaderReader<R>) -> Result<VertexReader<R>> {
-    let count = header.read.read_i32::<LE>()?;
+    let count = header.read.read_count(SECTION)?;
     Ok(VertexReader {
       settings: header.settings,
       count,
@@ -28,77 +120,77 @@ impl<R: Read> VertexReader<R> {
   }
 
   pub fn next<C: Config>(&mut self) -> Result<Option<Vertex<C>>> {
-    assert!(!self.poison);
+    if self.poison {
+      return Err(Error::Poisoned);
+    }
+    let index = (self.count - self.remaining) as usize;
     let result = self.next_impl::<C>();
     if result.is_err() {
       self.poison = true;
     }
-    result
+    result.map_err(|e| e.context(self.read.position, SECTION, Some(index)))
   }
 
   fn next_impl<C: Config>(&mut self) -> Result<Option<Vertex<C>>> {
-    if self.remaining == 0 {
+    if self.remaining <= 0 {
       return Ok(None);
     }
-    let position = self.read.read_vec3::<C>()?;
-    let normal = self.read.read_vec3::<C>()?;
-    let uv = self.read.read_vec2::<C>()?;
+    let mut buf = [0; VERTEX_PREFIX_SIZE];
+    self.read.read_exact(&mut buf)?;
     let additional = (0..self.settings.additional_vec4_count)
-      .map(|_| self.read.read_vec4::<C>())
+      .map(|_| {
+        let mut buf = [0; 16];
+        self.read.read_exact(&mut buf)?;
+        Ok(decode_vec::<4>(&buf).widen())
+      })
       .collect::<Result<C::AdditionalVec4s>>()?;
 
-    let weight_deform = match self.read.read_u8()? {
-      0u8 => WeightDeform::Bdef1(Bdef1 {
-        bone_index: self.read.read_index(self.settings.bone_index_size)?,
-      }),
-      1u8 => WeightDeform::Bdef2(Bdef2 {
-        bone_1_index: self.read.read_index(self.settings.bone_index_size)?,
-        bone_2_index: self.read.read_index(self.settings.bone_index_size)?,
-        bone_1_weight: self.read.read_f32::<LE>()?,
-      }),
-      2u8 => WeightDeform::Bdef4(Bdef4 {
-        bone_1_index: self.read.read_index(self.settings.bone_index_size)?,
-        bone_2_index: self.read.read_index(self.settings.bone_index_size)?,
-        bone_3_index: self.read.read_index(self.settings.bone_index_size)?,
-        bone_4_index: self.read.read_index(self.settings.bone_index_size)?,
-        bone_1_weight: self.read.read_f32::<LE>()?,
-        bone_2_weight: self.read.read_f32::<LE>()?,
-        bone_3_weight: self.read.read_f32::<LE>()?,
-        bone_4_weight: self.read.read_f32::<LE>()?,
-      }),
-      3u8 => WeightDeform::Sdef(Sdef {
-        bone_1_index: self.read.read_index(self.settings.bone_index_size)?,
-        bone_2_index: self.read.read_index(self.settings.bone_index_size)?,
-        bone_1_weight: self.read.read_f32::<LE>()?,
-        c: self.read.read_vec3::<C>()?,
-        r0: self.read.read_vec3::<C>()?,
-        r1: self.read.read_vec3::<C>()?,
-      }),
-      4u8 => WeightDeform::Qdef(Qdef {
-        bone_1_index: self.read.read_index(self.settings.bone_index_size)?,
-        bone_2_index: self.read.read_index(self.settings.bone_index_size)?,
-        bone_3_index: self.read.read_index(self.settings.bone_index_size)?,
-        bone_4_index: self.read.read_index(self.settings.bone_index_size)?,
-        bone_1_weight: self.read.read_f32::<LE>()?,
-        bone_2_weight: self.read.read_f32::<LE>()?,
-        bone_3_weight: self.read.read_f32::<LE>()?,
-        bone_4_weight: self.read.read_f32::<LE>()?,
-      }),
-      e => return Err(Error::UnknownWeightType(e)),
-    };
+    // The weights and the edge scale after them are read in one go once their size is known
+    let kind = self.read.read_u8()?;
+    let size = self.settings.bone_index_size;
+    let len = weights_len(kind, size)?;
+    let mut weights = [0; MAX_WEIGHTS_SIZE + 4];
+    self.read.read_exact(&mut weights[..len])?;
 
     self.remaining -= 1;
-    Ok(Some(Vertex {
-      position,
-      normal,
-      uv,
+    Ok(Some(decode_vertex(
+      &buf,
       additional,
-      weight_deform,
-      edge_scale: self.read.read_f32::<LE>()?,
-    }))
+      kind,
+      &weights[..len],
+      size,
+    )?))
+  }
+
+  /// Moves past the remaining vertices. Their weights are walked for their sizes, but nothing is
+  /// decoded or allocated.
+  pub fn skip(&mut self) -> Result<()> {
+    if self.poison {
+      return Err(Error::Poisoned);
+    }
+    while self.remaining > 0 {
+      let index = (self.count - self.remaining) as usize;
+      if let Err(e) = self.skip_one() {
+        self.poison = true;
+        return Err(e.context(self.read.position, SECTION, Some(index)));
+      }
+    }
+    Ok(())
+  }
+
+  fn skip_one(&mut self) -> Result<()> {
+    let additional = 16 * self.settings.additional_vec4_count as usize;
+    self.read.skip((VERTEX_PREFIX_SIZE + additional) as u64)?;
+    let kind = self.read.read_u8()?;
+    let len = weights_len(kind, self.settings.bone_index_size)?;
+    self.read.skip(len as u64)?;
+    self.remaining -= 1;
+    Ok(())
   }
 
-  pub fn iter<C>(&mut self) -> VertexIterator<R, C> {
+  /// Streams the vertices one at a time. `SurfaceReader::new` skips the ones left unread, after a
+  /// failed read it and `next` return `Error::Poisoned`.
+  pub fn iter<C>(&mut self) -> VertexIterator<'_, R, C> {
     VertexIterator {
       reader: self,
       phantom: PhantomData,
@@ -106,6 +198,52 @@ impl<R: Read> VertexReader<R> {
   }
 }
 
+impl<'a> VertexReader<SliceReader<'a>> {
+  /// Decodes the remaining vertices straight from the slice, like collecting `iter`.
+  pub fn read_all<C: Config>(&mut self) -> Result<Vec<Vertex<C>>> {
+    // A corrupt count can't reserve more than the smallest vertices left
+    let smallest = VERTEX_PREFIX_SIZE + 1 + weights_len(0, self.settings.bone_index_size)?;
+    let capacity =
+      (self.remaining.max(0) as usize).min(self.read.inner.remaining().len() / smallest);
+    let mut vertices = Vec::with_capacity(capacity);
+    while let Some(vertex) = self.next_slice::<C>()? {
+      vertices.push(vertex);
+    }
+    Ok(vertices)
+  }
+
+  /// `next` decoding straight from the slice.
+  pub(crate) fn next_slice<C: Config>(&mut self) -> Result<Option<Vertex<C>>> {
+    if self.poison {
+      return Err(Error::Poisoned);
+    }
+    if self.remaining <= 0 {
+      return Ok(None);
+    }
+    let index = (self.count - self.remaining) as usize;
+    let result = self.decode_next::<C>();
+    if result.is_err() {
+      self.poison = true;
+    }
+    result
+      .map(Some)
+      .map_err(|e| e.context(self.read.position, SECTION, Some(index)))
+  }
+
+  pub(super) fn decode_next<C: Config>(&mut self) -> Result<Vertex<C>> {
+    let prefix = self.read.read_slice(VERTEX_PREFIX_SIZE)?;
+    let additional = (0..self.settings.additional_vec4_count)
+      .map(|_| Ok(decode_vec::<4>(self.read.read_slice(16)?).widen()))
+      .collect::<Result<C::AdditionalVec4s>>()?;
+    let kind = self.read.read_slice(1)?[0];
+    let size = self.settings.bone_index_size;
+    let weights = self.read.read_slice(weights_len(kind, size)?)?;
+
+    self.remaining -= 1;
+    decode_vertex(prefix, additional, kind, weights, size)
+  }
+}
+
 pub struct VertexIterator<'a, R, C = DefaultConfig> {
   reader: &'a mut VertexReader<R>,
   phantom: PhantomData<C>,
@@ -123,14 +261,221 @@ impl<'a, R: Read, C: Config> Iterator for VertexIterator<'a, R, C> {
 
   fn size_hint(&self) -> (usize, Option<usize>) {
     (
-      self.reader.remaining as usize,
-      Some(self.reader.remaining as usize),
+      self.reader.remaining.max(0) as usize,
+      Some(self.reader.remaining.max(0) as usize),
     )
   }
 }
 
 impl<R: Read, C: Config> ExactSizeIterator for VertexIterator<'_, R, C> {
   fn len(&self) -> usize {
-    self.reader.remaining as usize
+    self.reader.remaining.max(0) as usize
+  }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+  use super::*;
+  use crate::{pmd::Pmd, reader::SurfaceReader, ErrorLocation, Pmx};
+  use std::io::Cursor;
+
+  const FIXTURE_MODEL_PMD: &[u8] = include_bytes!("../../../fixtures/model.pmd");
+  const FIXTURE_MODEL_PMX: &[u8] = include_bytes!("../../../fixtures/model.pmx");
+
+  fn model() -> (Pmx, Vec<u8>) {
+    let pmx = Pmd::read(Cursor::new(FIXTURE_MODEL_PMD))
+      .unwrap()
+      .into_pmx()
+      .unwrap();
+    let mut bytes = Vec::new();
+    pmx.write(&mut bytes).unwrap();
+    (pmx, bytes)
+  }
+
+  #[test]
+  fn test_stream_vertices() {
+    let (pmx, bytes) = model();
+    let mut vertices = VertexReader::new(HeaderReader::new(&bytes[..]).unwrap()).unwrap();
+
+    let mut iter = vertices.iter::<DefaultConfig>();
+    assert_eq!(iter.size_hint(), (6, Some(6)));
+    assert_eq!(iter.next().unwrap().unwrap(), pmx.vertices[0]);
+    assert_eq!(iter.len(), 5);
+
+    // The unread vertices are skipped
+    let mut surfaces = SurfaceReader::new(vertices).unwrap();
+    let read = surfaces.iter::<DefaultConfig>().collect::<Result<Vec<_>>>();
+    assert_eq!(read.unwrap(), pmx.surfaces);
+  }
+
+  #[test]
+  fn test_stream_negative_vertex_count() {
+    let header_size = {
+      let mut read = FIXTURE_MODEL_PMX;
+      HeaderReader::new(&mut read).unwrap();
+      FIXTURE_MODEL_PMX.len() - read.len()
+    };
+    let mut bytes = FIXTURE_MODEL_PMX[..header_size].to_vec();
+    bytes.extend_from_slice(&(-1i32).to_le_bytes());
+    let mut vertices = VertexReader::new(HeaderReader::new(&bytes[..]).unwrap()).unwrap();
+
+    let mut iter = vertices.iter::<DefaultConfig>();
+    assert_eq!(iter.size_hint(), (0, Some(0)));
+    assert_eq!(iter.len(), 0);
+    assert!(iter.next().is_none());
+  }
+
+  #[test]
+  fn test_stream_vertices_poisoned() {
+    let (_, bytes) = model();
+    let header_size = {
+      let mut read = &bytes[..];
+      HeaderReader::new(&mut read).unwrap();
+      bytes.len() - read.len()
+    };
+    // Cut in the middle of the second vertex
+    let truncated = &bytes[..header_size + 4 + 60];
+    let mut vertices = VertexReader::new(HeaderReader::new(truncated).unwrap()).unwrap();
+
+    assert!(vertices.next::<DefaultConfig>().unwrap().is_some());
+    let e = vertices.next::<DefaultConfig>().unwrap_err();
+    assert!(matches!(e.root(), Error::Io(_)));
+    assert_eq!(e.location().unwrap().offset, truncated.len() as u64);
+    assert_eq!(e.location().unwrap().index, Some(1));
+    assert!(matches!(
+      vertices.next::<DefaultConfig>(),
+      Err(Error::Poisoned)
+    ));
+    assert!(matches!(SurfaceReader::new(vertices), Err(Error::Poisoned)));
+  }
+
+  fn util_read_sdef<C: Config>() {
+    let mut vertices = VertexReader::new(HeaderReader::new(FIXTURE_MODEL_PMX).unwrap()).unwrap();
+    let vertices = vertices.iter::<C>().collect::<Result<Vec<_>>>().unwrap();
+
+    match &vertices[4].weight_deform {
+      WeightDeform::Sdef(sdef) => {
+        assert_eq!(sdef.bone_1_weight, 0.25);
+        assert_eq!(sdef.c, [0.0, 0.5, 0.0].widen());
+        assert_eq!(sdef.r0, [0.0, 0.75, 0.0].widen());
+        assert_eq!(sdef.r1, [0.0, 0.25, 0.0].widen());
+      }
+      _ => panic!("vertex 4 is not SDEF"),
+    }
+  }
+
+  #[test]
+  fn test_read_weight_deforms() {
+    let mut vertices = VertexReader::new(HeaderReader::new(FIXTURE_MODEL_PMX).unwrap()).unwrap();
+    let vertices = vertices
+      .iter::<DefaultConfig>()
+      .collect::<Result<Vec<_>>>()
+      .unwrap();
+
+    assert_eq!(
+      vertices[2].weight_deform,
+      WeightDeform::Bdef4(Bdef4 {
+        bone_1_index: 0,
+        bone_2_index: 1,
+        bone_3_index: 2,
+        bone_4_index: 3,
+        bone_1_weight: 0.25,
+        bone_2_weight: 0.25,
+        bone_3_weight: 0.25,
+        bone_4_weight: 0.25,
+      })
+    );
+    assert!(matches!(
+      &vertices[4].weight_deform,
+      WeightDeform::Sdef(Sdef {
+        bone_1_index: 1,
+        bone_2_index: 2,
+        ..
+      })
+    ));
+    assert_eq!(
+      vertices[5].weight_deform,
+      WeightDeform::Qdef(Qdef {
+        bone_1_index: 0,
+        bone_2_index: 1,
+        bone_3_index: 2,
+        bone_4_index: 3,
+        bone_1_weight: 0.4,
+        bone_2_weight: 0.3,
+        bone_3_weight: 0.2,
+        bone_4_weight: 0.1,
+      })
+    );
+    util_read_sdef::<DefaultConfig>();
+  }
+
+  #[cfg(feature = "glam")]
+  #[test]
+  fn test_read_sdef_glam() {
+    util_read_sdef::<crate::GlamConfig>();
+  }
+
+  #[cfg(feature = "nalgebra")]
+  #[test]
+  fn test_read_sdef_nalgebra() {
+    util_read_sdef::<crate::NalgebraConfig>();
+  }
+
+  #[test]
+  fn test_read_unknown_weight_type() {
+    let header_size = {
+      let mut read = FIXTURE_MODEL_PMX;
+      HeaderReader::new(&mut read).unwrap();
+      FIXTURE_MODEL_PMX.len() - read.len()
+    };
+    // The kind follows the count, position, normal, UV and the additional vec4
+    let kind = header_size + 4 + 32 + 16;
+    let mut bytes = FIXTURE_MODEL_PMX.to_vec();
+    assert!(bytes[kind] <= 4);
+    bytes[kind] = 5;
+
+    let mut vertices = VertexReader::new(HeaderReader::new(&bytes[..]).unwrap()).unwrap();
+    let e = vertices.next::<DefaultConfig>().unwrap_err();
+    assert!(matches!(e.root(), Error::UnknownWeightType(5)));
+    assert_eq!(
+      e.location(),
+      Some(&ErrorLocation {
+        offset: kind as u64 + 1,
+        section: "vertex",
+        index: Some(0),
+      })
+    );
+  }
+
+  #[test]
+  fn test_read_additional_uvs() {
+    let expected = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    let pmx =
+      Pmx::<DefaultConfig>::read(&include_bytes!("../../../fixtures/model_uv4.pmx")[..]).unwrap();
+    assert_eq!(pmx.settings.additional_vec4_count, 4);
+    for (i, vertex) in pmx.vertices.iter().enumerate() {
+      for channel in 0..4 {
+        assert_eq!(
+          crate::math::to_array::<4>(vertex.additional_uv(channel).unwrap()),
+          [i as f32, channel as f32 + 1.0, 0.25, 0.75]
+        );
+      }
+      assert!(vertex.additional_uv(4).is_none());
+      assert_eq!(vertex.weight_deform, expected.vertices[i].weight_deform);
+    }
+    // Everything after the vertices is read from the right offset
+    assert_eq!(pmx.surfaces, expected.surfaces);
+    assert_eq!(pmx.soft_bodies, expected.soft_bodies);
+    assert_eq!(pmx.joints, expected.joints);
+  }
+
+  #[test]
+  fn test_read_too_many_additional_uvs() {
+    let mut bytes = FIXTURE_MODEL_PMX.to_vec();
+    // The additional vec4 count is the second global
+    assert_eq!(bytes[10], 1);
+    bytes[10] = 5;
+    let e = HeaderReader::new(&bytes[..]).err().unwrap();
+    assert!(matches!(e.root(), Error::InvalidAdditionalVec4Count(5)));
   }
 }
@@ -1,8 +1,13 @@
-use crate::{Config, Error};
-use std::convert::TryFrom;
-use std::fmt::{Debug, Display, Formatter};
+#[cfg(feature = "serde")]
+use crate::SerdeConfig;
+use crate::{display::DisplayOption, Config, Error, LocalizedName};
+use core::convert::TryFrom;
+use core::fmt::{Debug, Display, Formatter};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum ShapeType {
   Sphere = 0,
@@ -11,7 +16,7 @@ pub enum ShapeType {
 }
 
 impl Display for ShapeType {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     match self {
       ShapeType::Sphere => write!(f, "sphere"),
       ShapeType::Box => write!(f, "box"),
@@ -34,6 +39,7 @@ impl TryFrom<u8> for ShapeType {
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum PhysicsMode {
   Static = 0,
@@ -42,7 +48,7 @@ pub enum PhysicsMode {
 }
 
 impl Display for PhysicsMode {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     match self {
       PhysicsMode::Static => write!(f, "static"),
       PhysicsMode::Dynamic => write!(f, "dynamic"),
@@ -64,10 +70,16 @@ impl TryFrom<u8> for PhysicsMode {
   }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct RigidBody<C: Config> {
-  pub local_name: String,
-  pub universal_name: String,
-  pub bone_index: C::BoneIndex,
+  pub name: LocalizedName,
+  /// `None` for rigid bodies not attached to a bone.
+  pub bone_index: Option<C::BoneIndex>,
   pub group_id: u8,
   pub non_collision_mask: u16,
   pub shape: ShapeType,
@@ -78,7 +90,7 @@ pub struct RigidBody<C: Config> {
   pub move_attenuation: f32,
   pub rotation_damping: f32,
   pub repulsion: f32,
-  pub fiction: f32,
+  pub friction: f32,
   pub physics_mode: PhysicsMode,
 }
 
@@ -86,7 +98,7 @@ impl<C: Config> Display for RigidBody<C>
 where
   C::BoneIndex: Display,
 {
-  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), core::fmt::Error> {
     write!(
       f,
       r"local name: {}, universal name: {},
@@ -94,10 +106,10 @@ bone index: {}, group id: {}, non_collision_mask: 0b{:b},
 shape: {}, size: {:?},
 pos: {:?}, rot: {:?},
 mass: {}, move attenuation: {}, rotaton damping: {}
-repulsion: {}, fiction: {}, physics: {}",
-      self.local_name,
-      self.universal_name,
-      self.bone_index,
+repulsion: {}, friction: {}, physics: {}",
+      self.name.ja,
+      self.name.en,
+      DisplayOption::new(&self.bone_index),
       self.group_id,
       self.non_collision_mask,
       self.shape,
@@ -108,7 +120,7 @@ repulsion: {}, fiction: {}, physics: {}",
       self.move_attenuation,
       self.rotation_damping,
       self.repulsion,
-      self.fiction,
+      self.friction,
       self.physics_mode,
     )
   }
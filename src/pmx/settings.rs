@@ -1,7 +1,10 @@
 use crate::pmx::types::*;
-use std::fmt::{Display, Formatter};
+use core::fmt::{Display, Formatter};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Settings {
   pub text_encoding: TextEncoding,
   pub additional_vec4_count: u8,
@@ -14,7 +17,7 @@ pub struct Settings {
 }
 
 impl Display for Settings {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     writeln!(
       f,
       r"encoding: {}, additional vec4s: {}, vertex index: {}, texture index: {},
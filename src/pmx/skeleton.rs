@@ -0,0 +1,315 @@
+//! The hierarchy and the rest pose of the bones of a model.
+//!
+//! The bones of a PMX file have positions in model space and no rotation at rest, so the global
+//! rest transform of a bone is the translation to its position.
+
+use crate::{
+  math::{mat4_mul, rigid_inverse, to_array, translation, Widen, IDENTITY},
+  pmx::{bone::AngleLimits, types::index_to_usize},
+  Bone, Config, Error, Result,
+};
+use std::convert::TryInto;
+
+/// A column-major 4x4 matrix, `m[column][row]` like `glam` and `nalgebra` store them.
+pub type Matrix4 = [[f32; 4]; 4];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RestBone<C: Config> {
+  /// The translation from the parent, the position for root bones.
+  pub local: C::Vec3,
+  /// From the bone space to the model space.
+  pub global: Matrix4,
+  /// The inverse of `global`, from the model space to the bone space.
+  pub inverse_bind: Matrix4,
+}
+
+/// The parent of bone `bone`, checked to exist.
+pub(crate) fn parent_of<C: Config>(bones: &[Bone<C>], bone: usize) -> Result<Option<usize>> {
+  match &bones[bone].parent {
+    None => Ok(None),
+    Some(parent) => match index_to_usize(parent).filter(|&p| p < bones.len()) {
+      Some(p) => Ok(Some(p)),
+      None => Err(Error::BoneParentOutOfRange {
+        bone,
+        parent: parent.clone().try_into().unwrap_or(i64::MAX),
+      }),
+    },
+  }
+}
+
+/// The bone indices with every parent before its children.
+///
+/// Parents may come after their children in the file, bones whose parents lead back to
+/// themselves fail with `Error::BoneParentCycle`.
+pub fn parent_order<C: Config>(bones: &[Bone<C>]) -> Result<Vec<usize>> {
+  #[derive(Copy, Clone, PartialEq)]
+  enum State {
+    New,
+    OnPath,
+    Done,
+  }
+
+  let mut state = vec![State::New; bones.len()];
+  let mut order = Vec::with_capacity(bones.len());
+  let mut path = Vec::new();
+  for start in 0..bones.len() {
+    let mut bone = Some(start);
+    while let Some(b) = bone {
+      match state[b] {
+        State::Done => break,
+        State::OnPath => return Err(Error::BoneParentCycle(b)),
+        State::New => {}
+      }
+      state[b] = State::OnPath;
+      path.push(b);
+      bone = parent_of(bones, b)?;
+    }
+    for b in path.drain(..).rev() {
+      state[b] = State::Done;
+      order.push(b);
+    }
+  }
+  Ok(order)
+}
+
+/// The rest transforms of `bones`, in their order.
+pub fn rest_pose<C: Config>(bones: &[Bone<C>]) -> Result<Vec<RestBone<C>>> {
+  let mut locals = vec![[0.0; 3]; bones.len()];
+  let mut globals = vec![IDENTITY; bones.len()];
+  for bone in parent_order(bones)? {
+    let position = to_array::<3>(&bones[bone].position);
+    // The parents are placed first by `parent_order`
+    let (local, parent) = match parent_of(bones, bone)? {
+      Some(parent) => {
+        let origin = to_array::<3>(&bones[parent].position);
+        let local = [
+          position[0] - origin[0],
+          position[1] - origin[1],
+          position[2] - origin[2],
+        ];
+        (local, globals[parent])
+      }
+      None => (position, IDENTITY),
+    };
+    locals[bone] = local;
+    globals[bone] = mat4_mul(&parent, &translation(local));
+  }
+
+  Ok(
+    locals
+      .into_iter()
+      .zip(globals)
+      .map(|(local, global)| RestBone {
+        local: local.widen(),
+        global,
+        inverse_bind: rigid_inverse(&global),
+      })
+      .collect(),
+  )
+}
+
+/// The children of every bone, built from the parents of the bones.
+pub struct BoneHierarchy<'a, C: Config> {
+  bones: &'a [Bone<C>],
+  parents: Vec<Option<usize>>,
+  children: Vec<Vec<usize>>,
+  roots: Vec<usize>,
+}
+
+impl<'a, C: Config> BoneHierarchy<'a, C> {
+  /// Fails like `parent_order` for missing parents and cycles.
+  pub fn new(bones: &'a [Bone<C>]) -> Result<Self> {
+    parent_order(bones)?;
+    let parents = (0..bones.len())
+      .map(|b| parent_of(bones, b))
+      .collect::<Result<Vec<_>>>()?;
+    let mut children = vec![Vec::new(); bones.len()];
+    let mut roots = Vec::new();
+    for (bone, parent) in parents.iter().enumerate() {
+      match parent {
+        Some(parent) => children[*parent].push(bone),
+        None => roots.push(bone),
+      }
+    }
+    Ok(BoneHierarchy {
+      bones,
+      parents,
+      children,
+      roots,
+    })
+  }
+
+  pub fn parent(&self, bone: usize) -> Option<usize> {
+    self.parents[bone]
+  }
+
+  /// The children of `bone` in the order of the bones.
+  pub fn children(&self, bone: usize) -> &[usize] {
+    &self.children[bone]
+  }
+
+  /// The bones without a parent, in their order.
+  pub fn roots(&self) -> &[usize] {
+    &self.roots
+  }
+
+  /// The parent of `bone`, its parent and so on up to a root.
+  pub fn ancestors(&self, bone: usize) -> impl Iterator<Item = usize> + '_ {
+    std::iter::successors(self.parents[bone], move |&b| self.parents[b])
+  }
+
+  /// All bones depth-first from the roots, every bone before its children.
+  pub fn depth_first(&self) -> impl Iterator<Item = usize> + '_ {
+    let mut stack: Vec<usize> = self.roots.iter().rev().copied().collect();
+    std::iter::from_fn(move || {
+      let bone = stack.pop()?;
+      stack.extend(self.children[bone].iter().rev());
+      Some(bone)
+    })
+  }
+
+  /// The IK chains of the IK bones, in their order.
+  pub fn ik_chains(&self) -> Result<Vec<IkChain<C>>> {
+    let check = |bone: usize, index: &C::BoneIndex| {
+      index_to_usize(index)
+        .filter(|&i| i < self.bones.len())
+        .ok_or_else(|| Error::IkBoneOutOfRange {
+          bone,
+          index: index.clone().try_into().unwrap_or(i64::MAX),
+        })
+    };
+
+    let mut chains = Vec::new();
+    for (bone, b) in self.bones.iter().enumerate() {
+      if let Some(ik) = &b.inverse_kinematics {
+        chains.push(IkChain {
+          bone,
+          target: check(bone, &ik.ik_bone)?,
+          iterations: ik.iterations,
+          limit_angle: ik.limit_angle,
+          links: ik
+            .links
+            .iter()
+            .map(|l| {
+              Ok(IkChainLink {
+                bone: check(bone, &l.ik_bone)?,
+                limits: l.limits.clone(),
+              })
+            })
+            .collect::<Result<_>>()?,
+        });
+      }
+    }
+    Ok(chains)
+  }
+}
+
+/// The IK of a bone with the bones as positions in the bone list.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IkChain<C: Config> {
+  /// The IK bone, which the target is moved to.
+  pub bone: usize,
+  pub target: usize,
+  pub iterations: u32,
+  pub limit_angle: f32,
+  /// The links from the target towards the root.
+  pub links: Vec<IkChainLink<C>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IkChainLink<C: Config> {
+  pub bone: usize,
+  pub limits: Option<AngleLimits<C>>,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::pmx::bone::tests::bone;
+  use crate::DefaultConfig;
+
+  #[test]
+  fn test_rest_pose_chain() {
+    // The tip comes first and its parent after it
+    let bones = [
+      bone("", Some(2), [0.0, 3.0, 1.0]),
+      bone("", None, [0.0, 1.0, 0.0]),
+      bone("", Some(1), [0.0, 2.0, 0.0]),
+    ];
+    assert_eq!(parent_order(&bones).unwrap(), [1, 2, 0]);
+
+    let rest = rest_pose(&bones).unwrap();
+    assert_eq!(to_array::<3>(&rest[1].local), [0.0, 1.0, 0.0]);
+    assert_eq!(to_array::<3>(&rest[2].local), [0.0, 1.0, 0.0]);
+    assert_eq!(to_array::<3>(&rest[0].local), [0.0, 1.0, 1.0]);
+    assert_eq!(rest[0].global, translation([0.0, 3.0, 1.0]));
+    assert_eq!(rest[2].global[3], [0.0, 2.0, 0.0, 1.0]);
+    for r in &rest {
+      assert_eq!(mat4_mul(&r.inverse_bind, &r.global), IDENTITY);
+    }
+  }
+
+  #[test]
+  fn test_rest_pose_errors() {
+    let bones = [
+      bone("", None, [0.0; 3]),
+      bone("", Some(2), [0.0; 3]),
+      bone("", Some(1), [0.0; 3]),
+    ];
+    assert!(matches!(rest_pose(&bones), Err(Error::BoneParentCycle(1))));
+
+    let bones = [bone("", Some(3), [0.0; 3])];
+    assert!(matches!(
+      parent_order(&bones),
+      Err(Error::BoneParentOutOfRange { bone: 0, parent: 3 })
+    ));
+  }
+
+  #[test]
+  fn test_bone_hierarchy() {
+    let pmx =
+      crate::Pmx::<DefaultConfig>::read(&include_bytes!("../../fixtures/model.pmx")[..]).unwrap();
+    let hierarchy = BoneHierarchy::new(&pmx.bones).unwrap();
+    // 左ひざ is a child of 左足
+    assert_eq!(pmx.bones[2].name.ja, "左ひざ");
+    assert_eq!(hierarchy.parent(2), Some(1));
+    assert_eq!(hierarchy.children(1), [2]);
+    assert_eq!(hierarchy.children(0), [1, 4]);
+    assert_eq!(hierarchy.roots(), [0]);
+    assert_eq!(hierarchy.ancestors(5).collect::<Vec<_>>(), [3, 2, 1, 0]);
+    assert_eq!(hierarchy.ancestors(0).count(), 0);
+    assert_eq!(
+      hierarchy.depth_first().collect::<Vec<_>>(),
+      [0, 1, 2, 3, 5, 4]
+    );
+
+    let chains = hierarchy.ik_chains().unwrap();
+    assert_eq!(chains.len(), 1);
+    let chain = &chains[0];
+    assert_eq!(pmx.bones[chain.bone].name.ja, "左足ＩＫ");
+    assert_eq!(chain.target, 3);
+    assert_eq!(
+      chain.links.iter().map(|l| l.bone).collect::<Vec<_>>(),
+      [2, 1]
+    );
+    let limits = chain.links[0].limits.unwrap();
+    assert_eq!(to_array::<3>(&limits.lower)[0], -std::f32::consts::PI);
+    assert!(to_array::<3>(&limits.upper)[0] < 0.0);
+    assert!(chain.links[1].limits.is_none());
+  }
+
+  #[test]
+  fn test_bone_hierarchy_orphans() {
+    let mut bones = vec![bone("", None, [0.0; 3]), bone("", None, [0.0; 3])];
+    let hierarchy = BoneHierarchy::new(&bones).unwrap();
+    assert_eq!(hierarchy.roots(), [0, 1]);
+    assert_eq!(hierarchy.depth_first().collect::<Vec<_>>(), [0, 1]);
+
+    bones[0].parent = Some(1);
+    bones[1].parent = Some(0);
+    assert!(matches!(
+      BoneHierarchy::new(&bones),
+      Err(Error::BoneParentCycle(_))
+    ));
+  }
+}
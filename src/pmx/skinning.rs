@@ -0,0 +1,184 @@
+//! Packing of the skinning weights into fixed size vertex attributes for GPUs.
+
+use crate::pmx::types::index_to_usize;
+use crate::{Config, Error, Pmx, Result, WeightDeform};
+use std::convert::{TryFrom, TryInto};
+
+/// Four bone indices per vertex, `u16` unless the model has more bones.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BoneIndices {
+  U16(Vec<[u16; 4]>),
+  U32(Vec<[u32; 4]>),
+}
+
+impl BoneIndices {
+  pub fn len(&self) -> usize {
+    match self {
+      BoneIndices::U16(indices) => indices.len(),
+      BoneIndices::U32(indices) => indices.len(),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+/// The skinning weights of all vertices as parallel arrays, see `Pmx::skinning_buffers`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SkinningBuffers {
+  pub indices: BoneIndices,
+  /// Adding up to 1 for every vertex, unused slots have bone 0 and weight 0.
+  pub weights: Vec<[f32; 4]>,
+  /// The SDEF vertices, packed with their linear weights.
+  pub sdef_vertices: Vec<usize>,
+  /// The QDEF vertices, packed with their linear weights.
+  pub qdef_vertices: Vec<usize>,
+}
+
+impl<C: Config> Pmx<C> {
+  /// Packs the weights of every vertex into four bones and weights, for linear blend skinning.
+  ///
+  /// BDEF1 and BDEF2 fill the first slots, BDEF4 weights are rescaled to add up to 1, the last
+  /// used slot taking the rounding errors. SDEF and QDEF vertices get their weights like BDEF2
+  /// and BDEF4 and are listed, for engines that skin them differently. Negative weights and the
+  /// weights of bone -1 are dropped, and vertices without any weight left follow bone 0.
+  ///
+  /// Fails with `Error::IndexOverflow` for bones that don't exist.
+  pub fn skinning_buffers(&self) -> Result<SkinningBuffers> {
+    let mut packed = Vec::with_capacity(self.vertices.len());
+    let mut weights = Vec::with_capacity(self.vertices.len());
+    let mut sdef_vertices = Vec::new();
+    let mut qdef_vertices = Vec::new();
+
+    for (i, vertex) in self.vertices.iter().enumerate() {
+      match vertex.weight_deform {
+        WeightDeform::Sdef(_) => sdef_vertices.push(i),
+        WeightDeform::Qdef(_) => qdef_vertices.push(i),
+        _ => {}
+      }
+
+      let mut bones = [0; 4];
+      let mut slots = [0.0; 4];
+      let mut used = 0;
+      for (bone, weight) in vertex.weight_deform.weights() {
+        let index = match index_to_usize(bone) {
+          Some(index) if index < self.bones.len() => index,
+          Some(_) => {
+            return Err(Error::IndexOverflow(
+              bone.clone().try_into().unwrap_or(i64::MAX),
+            ))
+          }
+          None => continue,
+        };
+        if weight > 0.0 {
+          bones[used] = index;
+          slots[used] = weight;
+          used += 1;
+        }
+      }
+
+      let sum: f32 = slots.iter().sum();
+      if used == 0 {
+        slots[0] = 1.0;
+      } else {
+        for slot in &mut slots[..used] {
+          *slot /= sum;
+        }
+        slots[used - 1] = 1.0 - slots[..used - 1].iter().sum::<f32>();
+      }
+      packed.push(bones);
+      weights.push(slots);
+    }
+
+    let indices = match u16::try_from(self.bones.len()) {
+      Ok(_) => BoneIndices::U16(packed.iter().map(|b| b.map(|b| b as u16)).collect()),
+      Err(_) => BoneIndices::U32(packed.iter().map(|b| b.map(|b| b as u32)).collect()),
+    };
+    Ok(SkinningBuffers {
+      indices,
+      weights,
+      sdef_vertices,
+      qdef_vertices,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::pmx::weight_deform::tests::model;
+  use crate::pmx::weight_deform::{Bdef1, Bdef2, Bdef4, Sdef};
+
+  #[test]
+  fn test_skinning_buffers() {
+    let pmx = model(vec![
+      WeightDeform::Bdef1(Bdef1 { bone_index: 3 }),
+      WeightDeform::Bdef2(Bdef2 {
+        bone_1_index: 1,
+        bone_2_index: 2,
+        bone_1_weight: 0.75,
+      }),
+      WeightDeform::Bdef4(Bdef4 {
+        bone_1_index: 5,
+        bone_2_index: -1,
+        bone_3_index: 2,
+        bone_4_index: 4,
+        bone_1_weight: 1.0,
+        bone_2_weight: 0.5,
+        bone_3_weight: 1.0,
+        bone_4_weight: 2.0,
+      }),
+      WeightDeform::Sdef(Sdef {
+        bone_1_index: 0,
+        bone_2_index: 1,
+        bone_1_weight: 0.25,
+        c: [0.0; 3].into(),
+        r0: [0.0; 3].into(),
+        r1: [0.0; 3].into(),
+      }),
+      // Nothing left to weight
+      WeightDeform::Bdef2(Bdef2 {
+        bone_1_index: -1,
+        bone_2_index: 2,
+        bone_1_weight: 1.0,
+      }),
+    ]);
+
+    let buffers = pmx.skinning_buffers().unwrap();
+    assert_eq!(
+      buffers.indices,
+      BoneIndices::U16(vec![
+        [3, 0, 0, 0],
+        [1, 2, 0, 0],
+        [5, 2, 4, 0],
+        [0, 1, 0, 0],
+        [0, 0, 0, 0],
+      ])
+    );
+    assert_eq!(
+      buffers.weights,
+      [
+        [1.0, 0.0, 0.0, 0.0],
+        [0.75, 0.25, 0.0, 0.0],
+        [0.25, 0.25, 0.5, 0.0],
+        [0.25, 0.75, 0.0, 0.0],
+        [1.0, 0.0, 0.0, 0.0],
+      ]
+    );
+    for weights in &buffers.weights {
+      assert_eq!(weights.iter().sum::<f32>(), 1.0);
+    }
+    assert_eq!(buffers.sdef_vertices, [3]);
+    assert!(buffers.qdef_vertices.is_empty());
+  }
+
+  #[test]
+  fn test_skinning_buffers_errors() {
+    let pmx = model(vec![WeightDeform::Bdef1(Bdef1 { bone_index: 6 })]);
+    assert!(matches!(
+      pmx.skinning_buffers(),
+      Err(Error::IndexOverflow(6))
+    ));
+  }
+}
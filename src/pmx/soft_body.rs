@@ -0,0 +1,216 @@
+#[cfg(feature = "serde")]
+use crate::SerdeConfig;
+use crate::{Config, Error, LocalizedName};
+use alloc::{format, vec::Vec};
+use core::convert::TryFrom;
+use core::fmt::{Display, Formatter};
+use enumflags2::{bitflags, BitFlags};
+use itertools::Itertools;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[repr(u8)]
+pub enum SoftBodyShape {
+  TriMesh = 0,
+  Rope = 1,
+}
+
+impl Display for SoftBodyShape {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
+    match self {
+      SoftBodyShape::TriMesh => write!(f, "tri mesh"),
+      SoftBodyShape::Rope => write!(f, "rope"),
+    }
+  }
+}
+
+impl TryFrom<u8> for SoftBodyShape {
+  type Error = Error;
+
+  fn try_from(value: u8) -> Result<Self, Self::Error> {
+    Ok(match value {
+      0 => SoftBodyShape::TriMesh,
+      1 => SoftBodyShape::Rope,
+      e => return Err(Error::InvalidSoftBodyShape(e)),
+    })
+  }
+}
+
+#[bitflags]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[repr(u8)]
+pub enum SoftBodyFlags {
+  BLink = 0b00000001,
+  ClusterCreation = 0b00000010,
+  LinkCrossing = 0b00000100,
+}
+
+struct SoftBodyFlagsFmt(BitFlags<SoftBodyFlags>);
+
+impl Display for SoftBodyFlagsFmt {
+  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), core::fmt::Error> {
+    write!(f, "{}", self.0.iter().map(|v| format!("{:?}", v)).join("|"))
+  }
+}
+
+/// How the soft body reacts to the air, on its vertices (`V`) or faces (`F`).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[repr(i32)]
+pub enum AeroModel {
+  VPoint = 0,
+  VTwoSided = 1,
+  VOneSided = 2,
+  FTwoSided = 3,
+  FOneSided = 4,
+}
+
+impl Display for AeroModel {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
+    match self {
+      AeroModel::VPoint => write!(f, "vertex point"),
+      AeroModel::VTwoSided => write!(f, "vertex two sided"),
+      AeroModel::VOneSided => write!(f, "vertex one sided"),
+      AeroModel::FTwoSided => write!(f, "face two sided"),
+      AeroModel::FOneSided => write!(f, "face one sided"),
+    }
+  }
+}
+
+impl TryFrom<i32> for AeroModel {
+  type Error = Error;
+
+  fn try_from(value: i32) -> Result<Self, Self::Error> {
+    Ok(match value {
+      0 => AeroModel::VPoint,
+      1 => AeroModel::VTwoSided,
+      2 => AeroModel::VOneSided,
+      3 => AeroModel::FTwoSided,
+      4 => AeroModel::FOneSided,
+      e => return Err(Error::InvalidAeroModel(e)),
+    })
+  }
+}
+
+/// The simulation parameters in file order, named after the Bullet soft body config.
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SoftBodyParameters {
+  /// `VCF`
+  pub velocity_correction: f32,
+  /// `DP`
+  pub damping: f32,
+  /// `DG`
+  pub drag: f32,
+  /// `LF`
+  pub lift: f32,
+  /// `PR`
+  pub pressure: f32,
+  /// `VC`
+  pub volume_conservation: f32,
+  /// `DF`
+  pub dynamic_friction: f32,
+  /// `MT`
+  pub pose_matching: f32,
+  /// `CHR`
+  pub rigid_contact_hardness: f32,
+  /// `KHR`
+  pub kinetic_contact_hardness: f32,
+  /// `SHR`
+  pub soft_contact_hardness: f32,
+  /// `AHR`
+  pub anchor_hardness: f32,
+  /// `SRHR_CL`
+  pub cluster_rigid_hardness: f32,
+  /// `SKHR_CL`
+  pub cluster_kinetic_hardness: f32,
+  /// `SSHR_CL`
+  pub cluster_soft_hardness: f32,
+  /// `SR_SPLT_CL`
+  pub cluster_rigid_impulse_split: f32,
+  /// `SK_SPLT_CL`
+  pub cluster_kinetic_impulse_split: f32,
+  /// `SS_SPLT_CL`
+  pub cluster_soft_impulse_split: f32,
+  /// `V_IT`
+  pub velocity_iterations: i32,
+  /// `P_IT`
+  pub position_iterations: i32,
+  /// `D_IT`
+  pub drift_iterations: i32,
+  /// `C_IT`
+  pub cluster_iterations: i32,
+  /// `LST`
+  pub linear_stiffness: f32,
+  /// `AST`
+  pub area_stiffness: f32,
+  /// `VST`
+  pub volume_stiffness: f32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
+pub struct Anchor<C: Config> {
+  pub rigid_body: C::RigidbodyIndex,
+  pub vertex: C::VertexIndex,
+  pub near_mode: bool,
+}
+
+/// A soft body of PMX 2.1, 2.0 files have no soft body section.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
+pub struct SoftBody<C: Config> {
+  pub name: LocalizedName,
+  pub shape: SoftBodyShape,
+  pub material: C::MaterialIndex,
+  pub group_id: u8,
+  pub non_collision_mask: u16,
+  pub flags: BitFlags<SoftBodyFlags>,
+  pub link_distance: i32,
+  pub cluster_count: i32,
+  pub mass: f32,
+  pub collision_margin: f32,
+  pub aero_model: AeroModel,
+  pub parameters: SoftBodyParameters,
+  pub anchors: Vec<Anchor<C>>,
+  pub pinned_vertices: Vec<C::VertexIndex>,
+}
+
+impl<C: Config> Display for SoftBody<C>
+where
+  C::MaterialIndex: Display,
+{
+  fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), core::fmt::Error> {
+    write!(
+      f,
+      r"local name: {}, universal name: {},
+shape: {}, material: {}, group id: {}, non_collision_mask: 0b{:b}, flags: {},
+link distance: {}, clusters: {}, mass: {}, margin: {}, aero model: {},
+anchors: {}, pinned vertices: {}",
+      self.name.ja,
+      self.name.en,
+      self.shape,
+      self.material,
+      self.group_id,
+      self.non_collision_mask,
+      SoftBodyFlagsFmt(self.flags),
+      self.link_distance,
+      self.cluster_count,
+      self.mass,
+      self.collision_margin,
+      self.aero_model,
+      self.anchors.len(),
+      self.pinned_vertices.len(),
+    )
+  }
+}
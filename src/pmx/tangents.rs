@@ -0,0 +1,191 @@
+//! Tangents for normal mapping, which PMX files don't store.
+
+use crate::math::{cross3, dot3, normalize3, sub3, to_array, Widen};
+use crate::{Config, Pmx, Result};
+
+/// The tangents and bitangents of the faces around a vertex, split by handedness.
+#[derive(Copy, Clone, Default)]
+struct Accumulated {
+  tangent: [[f32; 3]; 2],
+  bitangent: [[f32; 3]; 2],
+  weight: [f32; 2],
+}
+
+fn add(sum: &mut [f32; 3], v: [f32; 3]) {
+  for (s, v) in sum.iter_mut().zip(&v) {
+    *s += v;
+  }
+}
+
+impl<C: Config> Pmx<C> {
+  /// The tangent of every vertex, pointing along increasing U, with the handedness in `w`: the
+  /// bitangent along increasing V is `cross(normal, tangent) * w`.
+  ///
+  /// The tangents of the faces around a vertex are summed and made orthogonal to its normal.
+  /// Vertices on mirrored UV seams get the handedness most of their faces have, faces of the
+  /// other handedness are left out. Only the faces of `materials` are used if given, vertices in
+  /// none of them or only in faces without UV area get a zero tangent.
+  ///
+  /// Fails with the errors of `material_ranges` if the materials don't cover the faces, and
+  /// with `Error::IndexOverflow` for faces with vertices that don't exist.
+  ///
+  /// # Panics
+  ///
+  /// If there's no material at one of `materials`.
+  pub fn compute_tangents(&self, materials: Option<&[usize]>) -> Result<Vec<C::Vec4>> {
+    let faces = self.face_vertices()?;
+    let faces: Vec<[usize; 3]> = match materials {
+      None => faces,
+      Some(materials) => {
+        let ranges = self.material_ranges()?;
+        materials
+          .iter()
+          .flat_map(|&m| &faces[ranges[m].start / 3..(ranges[m].start + ranges[m].count) / 3])
+          .copied()
+          .collect()
+      }
+    };
+
+    let mut sums = vec![Accumulated::default(); self.vertices.len()];
+    for face in &faces {
+      let [a, b, c] = [
+        &self.vertices[face[0]],
+        &self.vertices[face[1]],
+        &self.vertices[face[2]],
+      ];
+      let p0 = to_array(&a.position);
+      let e1 = sub3(to_array(&b.position), p0);
+      let e2 = sub3(to_array(&c.position), p0);
+      let uv0: [f32; 2] = to_array(&a.uv);
+      let [du1, dv1] = {
+        let uv: [f32; 2] = to_array(&b.uv);
+        [uv[0] - uv0[0], uv[1] - uv0[1]]
+      };
+      let [du2, dv2] = {
+        let uv: [f32; 2] = to_array(&c.uv);
+        [uv[0] - uv0[0], uv[1] - uv0[1]]
+      };
+
+      let det = du1 * dv2 - du2 * dv1;
+      if det == 0.0 || !det.is_finite() {
+        continue;
+      }
+      let mut tangent = [0.0; 3];
+      let mut bitangent = [0.0; 3];
+      for i in 0..3 {
+        tangent[i] = (e1[i] * dv2 - e2[i] * dv1) / det;
+        bitangent[i] = (e2[i] * du1 - e1[i] * du2) / det;
+      }
+      if !tangent.iter().chain(&bitangent).all(|v| v.is_finite()) {
+        continue;
+      }
+
+      // Faces count by their area in UV space, so slivers don't outweigh the rest
+      let weight = det.abs();
+      for &vertex in face {
+        let normal = to_array(&self.vertices[vertex].normal);
+        let side = (dot3(cross3(normal, tangent), bitangent) < 0.0) as usize;
+        let sum = &mut sums[vertex];
+        add(&mut sum.tangent[side], tangent.map(|t| t * weight));
+        add(&mut sum.bitangent[side], bitangent.map(|b| b * weight));
+        sum.weight[side] += weight;
+      }
+    }
+
+    Ok(
+      self
+        .vertices
+        .iter()
+        .zip(&sums)
+        .map(|(vertex, sum)| {
+          let side = (sum.weight[1] > sum.weight[0]) as usize;
+          let normal = normalize3(to_array(&vertex.normal));
+          // Gram-Schmidt
+          let t = sum.tangent[side];
+          let d = dot3(normal, t);
+          let t = normalize3([
+            t[0] - normal[0] * d,
+            t[1] - normal[1] * d,
+            t[2] - normal[2] * d,
+          ]);
+          if sum.weight[side] == 0.0 || dot3(t, t) == 0.0 {
+            return [0.0; 4].widen();
+          }
+          let w = if dot3(cross3(normal, t), sum.bitangent[side]) < 0.0 {
+            -1.0
+          } else {
+            1.0
+          };
+          [t[0], t[1], t[2], w].widen()
+        })
+        .collect(),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::pmx::builder::PmxBuilder;
+  use crate::DefaultConfig;
+
+  /// A quad on the XY plane facing -Z, with U along `u_axis` and V along -Y like in images.
+  fn quad(u_sign: f32) -> Pmx<DefaultConfig> {
+    let mut builder = PmxBuilder::new("quad");
+    let root = builder.add_bone("センター", [0.0; 3], None);
+    let mut vertex = |x: f32, y: f32| {
+      let u = 0.5 + u_sign * x * 0.5;
+      builder.add_vertex([x, y, 0.0], [0.0, 0.0, -1.0], [u, 0.5 - y * 0.5], root)
+    };
+    let (a, b, c, d) = (
+      vertex(-1.0, -1.0),
+      vertex(1.0, -1.0),
+      vertex(-1.0, 1.0),
+      vertex(1.0, 1.0),
+    );
+    builder.add_triangle(a, c, b).add_triangle(b, c, d);
+    builder.add_material("quad", [1.0; 4]);
+    builder.build().unwrap()
+  }
+
+  fn tangents(pmx: &Pmx<DefaultConfig>, materials: Option<&[usize]>) -> Vec<[f32; 4]> {
+    pmx
+      .compute_tangents(materials)
+      .unwrap()
+      .iter()
+      .map(to_array)
+      .collect()
+  }
+
+  #[test]
+  fn test_quad_tangents() {
+    let pmx = quad(1.0);
+    // cross((0, 0, -1), (1, 0, 0)) = (0, -1, 0) is the bitangent along V
+    assert_eq!(tangents(&pmx, None), vec![[1.0, 0.0, 0.0, 1.0]; 4]);
+    assert_eq!(tangents(&pmx, Some(&[0])), vec![[1.0, 0.0, 0.0, 1.0]; 4]);
+    assert_eq!(tangents(&pmx, Some(&[])), vec![[0.0; 4]; 4]);
+
+    // Mirrored horizontally
+    let pmx = quad(-1.0);
+    assert_eq!(tangents(&pmx, None), vec![[-1.0, 0.0, 0.0, -1.0]; 4]);
+  }
+
+  #[test]
+  fn test_mirrored_seam() {
+    let mut pmx = quad(1.0);
+    // A narrow mirrored face sharing an edge with the quad
+    let mut vertex = pmx.vertices[1].clone();
+    vertex.position = [1.5, -1.0, 0.0].into();
+    vertex.uv = [0.75, 1.0].into();
+    pmx.vertices.push(vertex.clone());
+    vertex.position = [1.5, 1.0, 0.0].into();
+    vertex.uv = [0.75, 0.0].into();
+    pmx.vertices.push(vertex);
+    pmx.surfaces.push([1, 3, 4]);
+    pmx.materials[0].surface_count += 3;
+
+    let tangents = tangents(&pmx, None);
+    assert_eq!(tangents[1], [1.0, 0.0, 0.0, 1.0]);
+    assert_eq!(tangents[4], [-1.0, 0.0, 0.0, -1.0]);
+  }
+}
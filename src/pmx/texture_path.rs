@@ -0,0 +1,127 @@
+//! Resolution of the texture entries of a model, which are Windows paths relative to the model
+//! file like `tex\body.bmp`.
+
+use crate::{Config, Error, Pmx, Result};
+use std::path::{Path, PathBuf};
+
+/// Something about a texture entry that was repaired while resolving it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TexturePathWarning {
+  /// The entry was an absolute path like `C:\model\tex.png`, only its file name is kept.
+  Absolute,
+}
+
+/// A texture entry resolved against the directory of its model.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TexturePath {
+  /// The entry as stored in the file, to write it back untouched.
+  pub raw: String,
+  pub path: PathBuf,
+  pub warning: Option<TexturePathWarning>,
+}
+
+/// Resolves the texture entry `raw` relative to `model_dir`.
+///
+/// Backslashes and slashes both separate components. With `strict` entries leaving `model_dir`
+/// through `..` fail with `Error::TexturePathTraversal`, otherwise they are kept as they are.
+pub fn texture_path(raw: &str, model_dir: &Path, strict: bool) -> Result<TexturePath> {
+  let normalized = raw.replace('\\', "/");
+  let bytes = normalized.as_bytes();
+  let absolute = normalized.starts_with('/')
+    || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':');
+
+  let mut path = model_dir.to_path_buf();
+  if absolute {
+    if let Some(name) = normalized.rsplit('/').find(|c| !c.is_empty()) {
+      path.push(name);
+    }
+    return Ok(TexturePath {
+      raw: raw.to_string(),
+      path,
+      warning: Some(TexturePathWarning::Absolute),
+    });
+  }
+
+  let mut depth = 0usize;
+  for component in normalized.split('/') {
+    match component {
+      "" | "." => {}
+      ".." if depth > 0 => {
+        path.pop();
+        depth -= 1;
+      }
+      ".." if strict => return Err(Error::TexturePathTraversal(raw.to_string())),
+      component => {
+        path.push(component);
+        if component != ".." {
+          depth += 1;
+        }
+      }
+    }
+  }
+
+  Ok(TexturePath {
+    raw: raw.to_string(),
+    path,
+    warning: None,
+  })
+}
+
+impl<C: Config> Pmx<C> {
+  /// Resolves all entries of `textures` with `texture_path`, in order.
+  pub fn texture_paths(&self, model_dir: &Path, strict: bool) -> Result<Vec<TexturePath>> {
+    self
+      .textures
+      .iter()
+      .map(|t| texture_path(t, model_dir, strict))
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn resolve(raw: &str, strict: bool) -> Result<TexturePath> {
+    texture_path(raw, Path::new("models/miku"), strict)
+  }
+
+  #[test]
+  fn test_relative_paths() {
+    let expected: PathBuf = ["models", "miku", "tex", "body.bmp"].iter().collect();
+    for raw in &["tex\\body.bmp", "tex/body.bmp", ".\\tex\\\\body.bmp"] {
+      let path = resolve(raw, true).unwrap();
+      assert_eq!(path.path, expected);
+      assert_eq!(path.raw, *raw);
+      assert_eq!(path.warning, None);
+    }
+
+    let path = resolve("tex\\..\\face.png", true).unwrap();
+    assert_eq!(path.path, Path::new("models/miku/face.png"));
+  }
+
+  #[test]
+  fn test_absolute_paths() {
+    for raw in &[
+      "C:\\Users\\me\\model\\tex.png",
+      "d:/tex.png",
+      "\\\\server\\tex.png",
+    ] {
+      let path = resolve(raw, true).unwrap();
+      assert_eq!(path.path, Path::new("models/miku/tex.png"));
+      assert_eq!(path.warning, Some(TexturePathWarning::Absolute));
+    }
+  }
+
+  #[test]
+  fn test_traversal() {
+    assert!(matches!(
+      resolve("..\\..\\secret", true),
+      Err(Error::TexturePathTraversal(raw)) if raw == "..\\..\\secret"
+    ));
+    assert!(resolve("tex\\..\\..\\secret", true).is_err());
+
+    let path = resolve("..\\..\\secret", false).unwrap();
+    assert_eq!(path.path, Path::new("models/miku/../../secret"));
+  }
+}
@@ -0,0 +1,203 @@
+//! Index types that tell the sections of a model apart, see `TypedConfig`.
+//!
+//! With `DefaultConfig` all indices are `i32`, so a texture index can be used as a bone index
+//! without complaint. `TypedConfig` wraps each kind of index in its own type, so mixing them up
+//! fails to compile, while the fields of the parsed types keep their shapes.
+//!
+//! The wrappers convert from the integers of the file with `TryFrom` like the plain indices, so
+//! they can't also implement `From`, use `new` and `into_inner` instead.
+//!
+//! Reading with `TypedConfig` is the only way to get typed fields, a model read with another
+//! config isn't converted. The lists index with the wrappers whatever the config, though, so
+//! `pmx.bones[BoneIdx::new(i)]` works on a `DefaultConfig` model too.
+
+use crate::pmx::morph::Morph;
+use crate::pmx::rigid_body::RigidBody;
+use crate::pmx::types::index_to_usize;
+use crate::{Bone, Config, Material, Vertex};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::{TryFrom, TryInto};
+use core::fmt::{Display, Formatter};
+use core::ops::Deref;
+
+macro_rules! typed_index {
+  ($($(#[$meta:meta])* $name:ident: $($from:ty),*;)*) => {
+    $(
+      $(#[$meta])*
+      #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+      #[cfg_attr(
+        feature = "serde",
+        derive(serde::Serialize, serde::Deserialize),
+        serde(transparent)
+      )]
+      #[repr(transparent)]
+      pub struct $name<I = i32>(I);
+
+      impl<I> $name<I> {
+        pub fn new(index: I) -> Self {
+          $name(index)
+        }
+
+        pub fn into_inner(self) -> I {
+          self.0
+        }
+      }
+
+      impl<I> Deref for $name<I> {
+        type Target = I;
+
+        fn deref(&self) -> &I {
+          &self.0
+        }
+      }
+
+      impl<I: Display> Display for $name<I> {
+        fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
+          self.0.fmt(f)
+        }
+      }
+
+      $(
+        impl<I: TryFrom<$from>> TryFrom<$from> for $name<I> {
+          type Error = I::Error;
+
+          fn try_from(index: $from) -> Result<Self, Self::Error> {
+            I::try_from(index).map($name)
+          }
+        }
+      )*
+
+      impl<I: TryInto<i64>> TryFrom<$name<I>> for i64 {
+        type Error = I::Error;
+
+        fn try_from(index: $name<I>) -> Result<Self, Self::Error> {
+          index.0.try_into()
+        }
+      }
+    )*
+  };
+}
+
+typed_index!(
+  /// An index into `Pmx::vertices`.
+  VertexIdx: u8, u16, i32;
+  /// An index into `Pmx::textures`.
+  TextureIdx: i8, i16, i32;
+  /// An index into `Pmx::materials`.
+  MaterialIdx: i8, i16, i32;
+  /// An index into `Pmx::bones`.
+  BoneIdx: i8, i16, i32;
+  /// An index into `Pmx::morphs`.
+  MorphIdx: i8, i16, i32;
+  /// An index into `Pmx::rigid_bodies`.
+  RigidBodyIdx: i8, i16, i32;
+);
+
+macro_rules! impl_index {
+  ($($name:ident => $element:ident),*) => {
+    $(
+      /// Panics for negative indices and indices past the end, like indexing with `usize`.
+      impl<C: Config, I: TryInto<i64> + Clone> core::ops::Index<$name<I>> for Vec<$element<C>> {
+        type Output = $element<C>;
+
+        fn index(&self, index: $name<I>) -> &$element<C> {
+          &self[index_to_usize(&index.0).expect("negative index")]
+        }
+      }
+    )*
+  };
+}
+
+impl_index!(
+  VertexIdx => Vertex,
+  MaterialIdx => Material,
+  BoneIdx => Bone,
+  MorphIdx => Morph,
+  RigidBodyIdx => RigidBody
+);
+
+/// Panics for negative indices and indices past the end, like indexing with `usize`.
+impl<I: TryInto<i64> + Clone> core::ops::Index<TextureIdx<I>> for Vec<String> {
+  type Output = String;
+
+  fn index(&self, index: TextureIdx<I>) -> &String {
+    &self[index_to_usize(&index.0).expect("negative index")]
+  }
+}
+
+/// `Config` with a distinct index type for every section and the vectors of `DefaultConfig`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct TypedConfig;
+
+impl Config for TypedConfig {
+  type VertexIndex = VertexIdx;
+  type TextureIndex = TextureIdx;
+  type MaterialIndex = MaterialIdx;
+  type BoneIndex = BoneIdx;
+  type MorphIndex = MorphIdx;
+  type RigidbodyIndex = RigidBodyIdx;
+
+  type Float = f32;
+  type Vec2 = <crate::DefaultConfig as Config>::Vec2;
+  type Vec3 = <crate::DefaultConfig as Config>::Vec3;
+  type Vec4 = <crate::DefaultConfig as Config>::Vec4;
+  type AdditionalVec4s = <crate::DefaultConfig as Config>::AdditionalVec4s;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::Pmx;
+  #[cfg(feature = "std")]
+  use crate::{pmx::material::Toon, DefaultConfig};
+
+  const FIXTURE_MODEL_PMX: &[u8] = include_bytes!("../../fixtures/model.pmx");
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn test_read_typed_indices() {
+    let pmx = Pmx::<TypedConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    let untyped = Pmx::<DefaultConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+
+    let parent = pmx.bones[2].parent.unwrap();
+    assert_eq!(parent, BoneIdx::new(1));
+    assert_eq!(*parent, 1);
+    assert_eq!(pmx.bones[parent].name.ja, "左足");
+    assert_eq!(
+      pmx.materials[0].texture_index.map(TextureIdx::into_inner),
+      untyped.materials[0].texture_index
+    );
+    let texture = pmx.materials[0].texture_index.unwrap();
+    assert_eq!(pmx.textures[texture], untyped.textures[*texture as usize]);
+    assert_eq!(
+      untyped.bones[BoneIdx::new(2)].name,
+      pmx.bones[BoneIdx::new(2)].name
+    );
+    assert!(matches!(pmx.materials[1].toon, Toon::Texture(None)));
+    assert_eq!(
+      pmx.vertices[pmx.surfaces[0][1]].position,
+      untyped.vertices[untyped.surfaces[0][1] as usize].position
+    );
+    assert_eq!(pmx.validate(), untyped.validate());
+
+    let mut bytes = Vec::new();
+    pmx.write(&mut bytes).unwrap();
+    assert_eq!(bytes, FIXTURE_MODEL_PMX);
+  }
+
+  #[test]
+  fn test_convert_typed_index() {
+    assert_eq!(BoneIdx::<i32>::try_from(-1i8).unwrap().into_inner(), -1);
+    assert!(VertexIdx::<u8>::try_from(300i32).is_err());
+    assert_eq!(i64::try_from(MorphIdx::new(7i16)), Ok(7));
+    assert_eq!(RigidBodyIdx::new(3).to_string(), "3");
+  }
+
+  #[test]
+  #[should_panic(expected = "negative index")]
+  fn test_negative_typed_index() {
+    let pmx = Pmx::<TypedConfig>::read(FIXTURE_MODEL_PMX).unwrap();
+    let _ = &pmx.bones[BoneIdx::new(-1)];
+  }
+}
@@ -1,10 +1,17 @@
+#[cfg(not(feature = "arrayvec"))]
+use alloc::vec::Vec;
+#[cfg(feature = "arrayvec")]
 use arrayvec::ArrayVec;
 
 use crate::Error;
-use std::fmt::{Display, Formatter};
-use std::{convert::TryFrom, fmt::Debug, iter::FromIterator};
+use core::convert::{TryFrom, TryInto};
+use core::fmt::{Display, Formatter};
+use core::{fmt::Debug, iter::FromIterator};
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum TextEncoding {
   UTF16LE = 0,
@@ -12,7 +19,7 @@ pub enum TextEncoding {
 }
 
 impl Display for TextEncoding {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     match self {
       TextEncoding::UTF16LE => write!(f, "utf16 le"),
       TextEncoding::UTF8 => write!(f, "utf8"),
@@ -33,6 +40,7 @@ impl TryFrom<u8> for TextEncoding {
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum IndexSize {
   I8 = 1,
@@ -41,7 +49,7 @@ pub enum IndexSize {
 }
 
 impl Display for IndexSize {
-  fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
     match self {
       IndexSize::I8 => write!(f, "8-bit"),
       IndexSize::I16 => write!(f, "16-bit"),
@@ -63,11 +71,159 @@ impl TryFrom<u8> for IndexSize {
   }
 }
 
-pub trait Index: TryFrom<i8> + TryFrom<i16> + TryFrom<i32> + Clone + Debug + Eq {}
-impl<I: TryFrom<i8> + TryFrom<i16> + TryFrom<i32> + Clone + Debug + Eq> Index for I {}
+/// The smallest size for indices into a list of `len` elements. Vertex indices are unsigned
+/// at 8 and 16 bits, the others are signed so that -1 stays apart from the indices.
+pub(crate) fn smallest_index_size(len: usize, unsigned: bool) -> IndexSize {
+  let (max_8, max_16) = if unsigned {
+    (u8::MAX as usize, u16::MAX as usize)
+  } else {
+    (i8::MAX as usize, i16::MAX as usize)
+  };
+  match len.saturating_sub(1) {
+    max if max <= max_8 => IndexSize::I8,
+    max if max <= max_16 => IndexSize::I16,
+    _ => IndexSize::I32,
+  }
+}
+
+pub trait Index:
+  TryFrom<i8> + TryFrom<i16> + TryFrom<i32> + TryInto<i64> + Clone + Debug + Eq
+{
+}
+impl<I: TryFrom<i8> + TryFrom<i16> + TryFrom<i32> + TryInto<i64> + Clone + Debug + Eq> Index for I {}
+
+/// The position of `index` in its list, `None` for negative indices.
+pub(crate) fn index_to_usize<I: TryInto<i64> + Clone>(index: &I) -> Option<usize> {
+  index
+    .clone()
+    .try_into()
+    .ok()
+    .and_then(|i| usize::try_from(i).ok())
+}
+
+pub trait VertexIndex:
+  TryFrom<u8> + TryFrom<u16> + TryFrom<i32> + TryInto<i64> + Clone + Debug + Eq
+{
+}
+impl<I: TryFrom<u8> + TryFrom<u16> + TryFrom<i32> + TryInto<i64> + Clone + Debug + Eq> VertexIndex
+  for I
+{
+}
+
+/// The components of the vector types of a `Config`, `f32` or `f64`.
+///
+/// The files store `f32`s, which are widened when read and narrowed when written.
+pub trait Float: Copy + Debug + Display + PartialEq + PartialOrd + Default {
+  fn from_f32(v: f32) -> Self;
+  /// Narrows to `f32` for `f32` components, used for the numbers of text formats.
+  fn from_f64(v: f64) -> Self;
+  fn to_f32(self) -> f32;
+}
+
+impl Float for f32 {
+  fn from_f32(v: f32) -> Self {
+    v
+  }
+
+  fn from_f64(v: f64) -> Self {
+    v as f32
+  }
+
+  fn to_f32(self) -> f32 {
+    self
+  }
+}
+
+impl Float for f64 {
+  fn from_f32(v: f32) -> Self {
+    v.into()
+  }
+
+  fn from_f64(v: f64) -> Self {
+    v
+  }
+
+  fn to_f32(self) -> f32 {
+    self as f32
+  }
+}
+
+/// Read access to the components of the vector types of a `Config`.
+///
+/// Implemented for arrays and, behind their features, for the vectors of `vek`, `glam`
+/// and `nalgebra`.
+pub trait AsSlice {
+  type Float: Float;
 
-pub trait VertexIndex: TryFrom<u8> + TryFrom<u16> + TryFrom<i32> + Clone + Debug + Eq {}
-impl<I: TryFrom<u8> + TryFrom<u16> + TryFrom<i32> + Clone + Debug + Eq> VertexIndex for I {}
+  fn as_slice(&self) -> &[Self::Float];
+}
+
+impl<F: Float, const N: usize> AsSlice for [F; N] {
+  type Float = F;
+
+  fn as_slice(&self) -> &[F] {
+    self
+  }
+}
+
+#[cfg(feature = "vek")]
+macro_rules! impl_as_slice_vek {
+  ($($vec:ident),*) => {
+    $(impl AsSlice for vek::$vec<f32> {
+      type Float = f32;
+
+      fn as_slice(&self) -> &[f32] {
+        self.as_ref()
+      }
+    })*
+  };
+}
+
+#[cfg(feature = "vek")]
+impl_as_slice_vek!(Vec2, Vec3, Vec4);
+
+#[cfg(feature = "glam")]
+macro_rules! impl_as_slice_glam {
+  ($($vec:ident: $n:literal),*) => {
+    $(impl AsSlice for glam::$vec {
+      type Float = f32;
+
+      fn as_slice(&self) -> &[f32] {
+        AsRef::<[f32; $n]>::as_ref(self)
+      }
+    })*
+  };
+}
+
+#[cfg(feature = "glam")]
+impl_as_slice_glam!(Vec2: 2, Vec3: 3, Vec4: 4);
+
+#[cfg(feature = "nalgebra")]
+macro_rules! impl_as_slice_nalgebra {
+  ($($vec:ident),*) => {
+    $(impl AsSlice for nalgebra::$vec<f32> {
+      type Float = f32;
+
+      fn as_slice(&self) -> &[f32] {
+        nalgebra::Matrix::as_slice(self)
+      }
+    })*
+  };
+}
+
+#[cfg(feature = "nalgebra")]
+impl_as_slice_nalgebra!(Vector2, Vector3, Vector4);
+
+/// `Send` with the `rayon` feature, which decodes large sections of `Pmx::parse` on the rayon
+/// thread pool, and implemented by all types without it.
+#[cfg(feature = "rayon")]
+pub trait MaybeSend: Send {}
+#[cfg(feature = "rayon")]
+impl<T: Send> MaybeSend for T {}
+#[cfg(not(feature = "rayon"))]
+pub trait MaybeSend {}
+#[cfg(not(feature = "rayon"))]
+impl<T> MaybeSend for T {}
 
 pub trait Config {
   type VertexIndex: VertexIndex;
@@ -77,12 +233,53 @@ pub trait Config {
   type MorphIndex: Index;
   type RigidbodyIndex: Index;
 
-  type Vec2: From<[f32; 2]> + Clone + Debug + PartialEq;
-  type Vec3: From<[f32; 3]> + Clone + Debug + PartialEq;
-  type Vec4: From<[f32; 4]> + Clone + Debug + PartialEq;
-  type AdditionalVec4s: FromIterator<Self::Vec4> + Clone + Debug + PartialEq;
+  /// The components of the vectors, `f32` in the files.
+  type Float: Float;
+  type Vec2: From<[Self::Float; 2]> + AsSlice<Float = Self::Float> + Clone + Debug + PartialEq;
+  type Vec3: From<[Self::Float; 3]> + AsSlice<Float = Self::Float> + Clone + Debug + PartialEq;
+  type Vec4: From<[Self::Float; 4]> + AsSlice<Float = Self::Float> + Clone + Debug + PartialEq;
+  type AdditionalVec4s: FromIterator<Self::Vec4> + AsRef<[Self::Vec4]> + Clone + Debug + PartialEq;
+}
+
+/// A `Config` whose indices and vectors implement `Serialize` and `Deserialize`, the bound of the
+/// serde impls of the types generic over a config. Implemented for every such config.
+#[cfg(feature = "serde")]
+pub trait SerdeConfig:
+  Config<
+  VertexIndex: Serialize + DeserializeOwned,
+  TextureIndex: Serialize + DeserializeOwned,
+  MaterialIndex: Serialize + DeserializeOwned,
+  BoneIndex: Serialize + DeserializeOwned,
+  MorphIndex: Serialize + DeserializeOwned,
+  RigidbodyIndex: Serialize + DeserializeOwned,
+  Float: Serialize + DeserializeOwned,
+  Vec2: Serialize + DeserializeOwned,
+  Vec3: Serialize + DeserializeOwned,
+  Vec4: Serialize + DeserializeOwned,
+  AdditionalVec4s: Serialize + DeserializeOwned,
+>
+{
 }
 
+#[cfg(feature = "serde")]
+impl<C> SerdeConfig for C where
+  C: Config<
+    VertexIndex: Serialize + DeserializeOwned,
+    TextureIndex: Serialize + DeserializeOwned,
+    MaterialIndex: Serialize + DeserializeOwned,
+    BoneIndex: Serialize + DeserializeOwned,
+    MorphIndex: Serialize + DeserializeOwned,
+    RigidbodyIndex: Serialize + DeserializeOwned,
+    Float: Serialize + DeserializeOwned,
+    Vec2: Serialize + DeserializeOwned,
+    Vec3: Serialize + DeserializeOwned,
+    Vec4: Serialize + DeserializeOwned,
+    AdditionalVec4s: Serialize + DeserializeOwned,
+  >
+{
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 pub struct DefaultConfig;
 
 impl Config for DefaultConfig {
@@ -93,6 +290,7 @@ impl Config for DefaultConfig {
   type MorphIndex = i32;
   type RigidbodyIndex = i32;
 
+  type Float = f32;
   #[cfg(feature = "vek")]
   type Vec2 = vek::Vec2<f32>;
   #[cfg(not(feature = "vek"))]
@@ -113,3 +311,118 @@ impl Config for DefaultConfig {
   #[cfg(not(feature = "arrayvec"))]
   type AdditionalVec4s = Vec<Self::Vec4>;
 }
+
+/// `Config` with `f64` arrays, for processing that would accumulate the rounding of `f32`.
+///
+/// The values of PMX and VMD files are widened from their `f32`s, those of VPD files are
+/// parsed as `f64`. Most helpers of this crate still compute in `f32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct F64Config;
+
+impl Config for F64Config {
+  type VertexIndex = i32;
+  type TextureIndex = i32;
+  type MaterialIndex = i32;
+  type BoneIndex = i32;
+  type MorphIndex = i32;
+  type RigidbodyIndex = i32;
+
+  type Float = f64;
+  type Vec2 = [f64; 2];
+  type Vec3 = [f64; 3];
+  type Vec4 = [f64; 4];
+
+  #[cfg(feature = "arrayvec")]
+  type AdditionalVec4s = ArrayVec<Self::Vec4, 4>;
+  #[cfg(not(feature = "arrayvec"))]
+  type AdditionalVec4s = Vec<Self::Vec4>;
+}
+
+/// `Config` with the vector types of `glam`.
+#[cfg(feature = "glam")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct GlamConfig;
+
+#[cfg(feature = "glam")]
+impl Config for GlamConfig {
+  type VertexIndex = i32;
+  type TextureIndex = i32;
+  type MaterialIndex = i32;
+  type BoneIndex = i32;
+  type MorphIndex = i32;
+  type RigidbodyIndex = i32;
+
+  type Float = f32;
+  type Vec2 = glam::Vec2;
+  type Vec3 = glam::Vec3;
+  type Vec4 = glam::Vec4;
+
+  #[cfg(feature = "arrayvec")]
+  type AdditionalVec4s = ArrayVec<Self::Vec4, 4>;
+  #[cfg(not(feature = "arrayvec"))]
+  type AdditionalVec4s = Vec<Self::Vec4>;
+}
+
+/// `Config` with the vector types of `nalgebra`.
+///
+/// Rotations stay `Vector4`s in the `[x, y, z, w]` order of the files, `unit_quaternion` and
+/// `rotation` convert them from and to `UnitQuaternion`s.
+#[cfg(feature = "nalgebra")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct NalgebraConfig;
+
+#[cfg(feature = "nalgebra")]
+impl Config for NalgebraConfig {
+  type VertexIndex = i32;
+  type TextureIndex = i32;
+  type MaterialIndex = i32;
+  type BoneIndex = i32;
+  type MorphIndex = i32;
+  type RigidbodyIndex = i32;
+
+  type Float = f32;
+  type Vec2 = nalgebra::Vector2<f32>;
+  type Vec3 = nalgebra::Vector3<f32>;
+  type Vec4 = nalgebra::Vector4<f32>;
+
+  #[cfg(feature = "arrayvec")]
+  type AdditionalVec4s = ArrayVec<Self::Vec4, 4>;
+  #[cfg(not(feature = "arrayvec"))]
+  type AdditionalVec4s = Vec<Self::Vec4>;
+}
+
+#[cfg(feature = "nalgebra")]
+impl NalgebraConfig {
+  /// Normalizes an `[x, y, z, w]` rotation into a quaternion.
+  pub fn unit_quaternion(rotation: &nalgebra::Vector4<f32>) -> nalgebra::UnitQuaternion<f32> {
+    let [x, y, z, w] = [rotation.x, rotation.y, rotation.z, rotation.w];
+    nalgebra::UnitQuaternion::from_quaternion(nalgebra::Quaternion::new(w, x, y, z))
+  }
+
+  /// The `[x, y, z, w]` rotation of a quaternion.
+  pub fn rotation(quaternion: &nalgebra::UnitQuaternion<f32>) -> nalgebra::Vector4<f32> {
+    let q = quaternion.quaternion();
+    nalgebra::Vector4::new(q.i, q.j, q.k, q.w)
+  }
+}
+
+#[cfg(all(test, feature = "nalgebra"))]
+mod tests {
+  use super::*;
+  use core::f32::consts::FRAC_1_SQRT_2;
+  use nalgebra::{UnitQuaternion, Vector3, Vector4};
+
+  #[test]
+  fn test_nalgebra_quaternion_order() {
+    // 90° around Y, stored as [x, y, z, w]
+    let rotation = Vector4::new(0.0, FRAC_1_SQRT_2, 0.0, FRAC_1_SQRT_2);
+    let quaternion = NalgebraConfig::unit_quaternion(&rotation);
+
+    let expected =
+      UnitQuaternion::from_axis_angle(&Vector3::y_axis(), core::f32::consts::FRAC_PI_2);
+    assert!(quaternion.angle_to(&expected) < 1e-6);
+    let turned = quaternion * Vector3::new(1.0, 0.0, 0.0);
+    assert!((turned - Vector3::new(0.0, 0.0, -1.0)).norm() < 1e-6);
+    assert!((NalgebraConfig::rotation(&quaternion) - rotation).norm() < 1e-6);
+  }
+}
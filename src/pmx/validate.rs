@@ -0,0 +1,544 @@
+//! Consistency checks of a whole model, see `Pmx::validate`.
+
+use crate::pmx::material::{Toon, SHARED_TOON_COUNT};
+use crate::pmx::morph::Offsets;
+use crate::pmx::weight_deform::WeightDeform;
+use crate::{Config, Pmx};
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt::{Display, Formatter};
+use enumflags2::bitflags;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Severity {
+  /// The model loads but probably doesn't look as intended.
+  Warning,
+  /// The model refers to data that doesn't exist or breaks the layout of its sections.
+  Error,
+}
+
+#[bitflags]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[repr(u16)]
+pub enum Section {
+  Vertices,
+  Surfaces,
+  Textures,
+  Materials,
+  Bones,
+  Morphs,
+  DisplayFrames,
+  RigidBodies,
+  Joints,
+  SoftBodies,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+  /// A skinning weight of a vertex is below 0.
+  NegativeWeight {
+    weight: f32,
+  },
+  /// A face refers to a vertex past the end of the vertices.
+  VertexOutOfRange {
+    vertex: i64,
+  },
+  /// The surface count of a material is not a multiple of 3.
+  PartialTriangle {
+    surface_count: i32,
+  },
+  /// The surface counts of all materials don't add up to the number of face indices.
+  SurfaceCountMismatch {
+    materials: i64,
+    surfaces: usize,
+  },
+  /// A material uses a shared toon past `toon10.bmp`.
+  UnknownSharedToon {
+    toon: u8,
+  },
+  /// A material refers to a texture past the end of the textures.
+  TextureOutOfRange {
+    texture: i64,
+  },
+  ParentOutOfRange {
+    parent: i64,
+  },
+  /// The bone is its own ancestor.
+  ParentCycle,
+  /// The target or a link of an IK bone doesn't exist.
+  IkBoneOutOfRange {
+    bone: i64,
+  },
+  /// The lower angle limit of an IK link is above the upper one, on purpose for some knees.
+  ReversedIkLimits {
+    link: usize,
+    axis: usize,
+  },
+  /// An element of a morph refers to a vertex, bone, material, morph or rigid body that doesn't
+  /// exist.
+  MorphElementOutOfRange {
+    element: usize,
+    index: i64,
+  },
+}
+
+impl Problem {
+  pub fn severity(&self) -> Severity {
+    match self {
+      Problem::NegativeWeight { .. }
+      | Problem::UnknownSharedToon { .. }
+      | Problem::ReversedIkLimits { .. } => Severity::Warning,
+      _ => Severity::Error,
+    }
+  }
+}
+
+impl Display for Problem {
+  fn fmt(&self, f: &mut Formatter) -> Result<(), core::fmt::Error> {
+    match self {
+      Problem::NegativeWeight { weight } => write!(f, "negative weight {}", weight),
+      Problem::VertexOutOfRange { vertex } => write!(f, "vertex {} out of range", vertex),
+      Problem::PartialTriangle { surface_count } => {
+        write!(f, "surface count {} is not a multiple of 3", surface_count)
+      }
+      Problem::SurfaceCountMismatch {
+        materials,
+        surfaces,
+      } => write!(
+        f,
+        "materials cover {} face indices of {}",
+        materials, surfaces
+      ),
+      Problem::UnknownSharedToon { toon } => write!(f, "unknown shared toon {}", toon),
+      Problem::TextureOutOfRange { texture } => write!(f, "texture {} out of range", texture),
+      Problem::ParentOutOfRange { parent } => write!(f, "parent {} out of range", parent),
+      Problem::ParentCycle => write!(f, "bone is its own ancestor"),
+      Problem::IkBoneOutOfRange { bone } => write!(f, "IK bone {} out of range", bone),
+      Problem::ReversedIkLimits { link, axis } => write!(
+        f,
+        "lower limit above the upper one on axis {} of IK link {}",
+        axis, link
+      ),
+      Problem::MorphElementOutOfRange { element, index } => {
+        write!(f, "element {} refers to {} out of range", element, index)
+      }
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Finding {
+  pub severity: Severity,
+  pub section: Section,
+  /// Index of the element in its section, `None` for the section as a whole.
+  pub index: Option<usize>,
+  pub problem: Problem,
+}
+
+/// Everything `Pmx::validate` found, in the order of the sections.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct ModelValidation {
+  pub findings: Vec<Finding>,
+}
+
+impl ModelValidation {
+  /// Whether there are no findings of `Severity::Error`.
+  pub fn is_valid(&self) -> bool {
+    self.findings.iter().all(|f| f.severity != Severity::Error)
+  }
+
+  fn push(&mut self, section: Section, index: Option<usize>, problem: Problem) {
+    self.findings.push(Finding {
+      severity: problem.severity(),
+      section,
+      index,
+      problem,
+    });
+  }
+}
+
+fn to_i64<I: TryInto<i64> + Clone>(index: &I) -> i64 {
+  index.clone().try_into().unwrap_or(i64::MAX)
+}
+
+fn in_range(index: i64, len: usize) -> bool {
+  index >= 0 && (index as u64) < len as u64
+}
+
+impl<C: Config> Pmx<C> {
+  /// Checks that the indices of the model refer to existing elements, that the materials tile
+  /// the faces, that bones have no parent cycles and that skinning weights aren't negative.
+  ///
+  /// All findings are collected instead of stopping at the first one.
+  pub fn validate(&self) -> ModelValidation {
+    let mut validation = ModelValidation::default();
+
+    for (i, vertex) in self.vertices.iter().enumerate() {
+      let weights: &[f32] = match &vertex.weight_deform {
+        WeightDeform::Bdef1(_) => &[],
+        WeightDeform::Bdef2(w) => &[w.bone_1_weight, 1.0 - w.bone_1_weight],
+        WeightDeform::Bdef4(w) => &[
+          w.bone_1_weight,
+          w.bone_2_weight,
+          w.bone_3_weight,
+          w.bone_4_weight,
+        ],
+        WeightDeform::Sdef(w) => &[w.bone_1_weight, 1.0 - w.bone_1_weight],
+        WeightDeform::Qdef(w) => &[
+          w.bone_1_weight,
+          w.bone_2_weight,
+          w.bone_3_weight,
+          w.bone_4_weight,
+        ],
+      };
+      if let Some(&weight) = weights.iter().find(|&&w| w < 0.0) {
+        validation.push(
+          Section::Vertices,
+          Some(i),
+          Problem::NegativeWeight { weight },
+        );
+      }
+    }
+
+    for (i, surface) in self.surfaces.iter().enumerate() {
+      for vertex in surface.iter().map(to_i64) {
+        if !in_range(vertex, self.vertices.len()) {
+          validation.push(
+            Section::Surfaces,
+            Some(i),
+            Problem::VertexOutOfRange { vertex },
+          );
+        }
+      }
+    }
+
+    let mut covered = 0i64;
+    for (i, material) in self.materials.iter().enumerate() {
+      covered += i64::from(material.surface_count);
+      if material.surface_count % 3 != 0 {
+        validation.push(
+          Section::Materials,
+          Some(i),
+          Problem::PartialTriangle {
+            surface_count: material.surface_count,
+          },
+        );
+      }
+
+      let toon = match &material.toon {
+        Toon::Texture(texture) => texture.as_ref(),
+        Toon::Internal(toon) => {
+          if *toon >= SHARED_TOON_COUNT {
+            validation.push(
+              Section::Materials,
+              Some(i),
+              Problem::UnknownSharedToon { toon: *toon },
+            );
+          }
+          None
+        }
+      };
+      let textures = [&material.texture_index, &material.environment_index];
+      for texture in textures.iter().filter_map(|t| t.as_ref()).chain(toon) {
+        let texture = to_i64(texture);
+        if !in_range(texture, self.textures.len()) {
+          validation.push(
+            Section::Materials,
+            Some(i),
+            Problem::TextureOutOfRange { texture },
+          );
+        }
+      }
+    }
+    if covered != self.surfaces.len() as i64 * 3 {
+      validation.push(
+        Section::Materials,
+        None,
+        Problem::SurfaceCountMismatch {
+          materials: covered,
+          surfaces: self.surfaces.len() * 3,
+        },
+      );
+    }
+
+    let parents: Vec<Option<i64>> = self
+      .bones
+      .iter()
+      .map(|b| b.parent.as_ref().map(to_i64))
+      .collect();
+    for (i, bone) in self.bones.iter().enumerate() {
+      if let Some(parent) = parents[i] {
+        if !in_range(parent, self.bones.len()) {
+          validation.push(
+            Section::Bones,
+            Some(i),
+            Problem::ParentOutOfRange { parent },
+          );
+        }
+      }
+
+      // A chain longer than the bones that doesn't come back to `i` ends in another cycle
+      let mut parent = parents[i];
+      for _ in 0..self.bones.len() {
+        match parent {
+          Some(p) if p == i as i64 => {
+            validation.push(Section::Bones, Some(i), Problem::ParentCycle);
+            break;
+          }
+          Some(p) if in_range(p, self.bones.len()) => parent = parents[p as usize],
+          _ => break,
+        }
+      }
+
+      if let Some(ik) = &bone.inverse_kinematics {
+        let links = ik.links.iter().map(|l| &l.ik_bone);
+        for ik_bone in core::iter::once(&ik.ik_bone).chain(links).map(to_i64) {
+          if !in_range(ik_bone, self.bones.len()) {
+            validation.push(
+              Section::Bones,
+              Some(i),
+              Problem::IkBoneOutOfRange { bone: ik_bone },
+            );
+          }
+        }
+        for (link, limits) in ik.links.iter().enumerate() {
+          for axis in limits.limits.iter().flat_map(|l| l.reversed_axes()) {
+            validation.push(
+              Section::Bones,
+              Some(i),
+              Problem::ReversedIkLimits { link, axis },
+            );
+          }
+        }
+      }
+    }
+
+    for (i, morph) in self.morphs.iter().enumerate() {
+      let (indices, len): (Vec<i64>, usize) = match &morph.offsets {
+        Offsets::Group(offsets) | Offsets::Flip(offsets) => (
+          offsets.iter().map(|o| to_i64(&o.morph)).collect(),
+          self.morphs.len(),
+        ),
+        Offsets::Vertex(offsets) => (
+          offsets.iter().map(|o| to_i64(&o.vertex)).collect(),
+          self.vertices.len(),
+        ),
+        Offsets::Bone(offsets) => (
+          offsets.iter().map(|o| to_i64(&o.bone)).collect(),
+          self.bones.len(),
+        ),
+        Offsets::UV(offsets)
+        | Offsets::AdditionalUV1(offsets)
+        | Offsets::AdditionalUV2(offsets)
+        | Offsets::AdditionalUV3(offsets)
+        | Offsets::AdditionalUV4(offsets) => (
+          offsets.iter().map(|o| to_i64(&o.vertex)).collect(),
+          self.vertices.len(),
+        ),
+        // No material applies the offset to all of them
+        Offsets::Material(offsets) => (
+          offsets
+            .iter()
+            .map(|o| o.material.as_ref().map_or(0, to_i64))
+            .collect(),
+          self.materials.len().max(1),
+        ),
+        Offsets::Impulse(offsets) => (
+          offsets.iter().map(|o| to_i64(&o.rigid_body)).collect(),
+          self.rigid_bodies.len(),
+        ),
+      };
+      for (element, &index) in indices.iter().enumerate() {
+        if !in_range(index, len) {
+          validation.push(
+            Section::Morphs,
+            Some(i),
+            Problem::MorphElementOutOfRange { element, index },
+          );
+        }
+      }
+    }
+
+    validation
+  }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+  use super::*;
+  use crate::pmd::Pmd;
+  use crate::pmx::morph::{Morph, Panel, VertexOffset};
+  use crate::pmx::weight_deform::Bdef2;
+  use crate::LocalizedName;
+  use std::io::Cursor;
+
+  const FIXTURE_MODEL_PMD: &[u8] = include_bytes!("../../fixtures/model.pmd");
+
+  fn model() -> Pmx {
+    Pmd::read(Cursor::new(FIXTURE_MODEL_PMD))
+      .unwrap()
+      .into_pmx()
+      .unwrap()
+  }
+
+  fn problems(pmx: &Pmx) -> Vec<(Section, Option<usize>, Problem)> {
+    pmx
+      .validate()
+      .findings
+      .into_iter()
+      .map(|f| (f.section, f.index, f.problem))
+      .collect()
+  }
+
+  #[test]
+  fn test_validate_fixture() {
+    assert_eq!(model().validate(), ModelValidation::default());
+  }
+
+  #[test]
+  fn test_validate_surfaces() {
+    let mut pmx = model();
+    pmx.surfaces[1][2] = 6;
+    pmx.materials[0].surface_count += 1;
+
+    let validation = pmx.validate();
+    assert!(!validation.is_valid());
+    assert_eq!(
+      problems(&pmx),
+      [
+        (
+          Section::Surfaces,
+          Some(1),
+          Problem::VertexOutOfRange { vertex: 6 }
+        ),
+        (
+          Section::Materials,
+          Some(0),
+          Problem::PartialTriangle { surface_count: 7 }
+        ),
+        (
+          Section::Materials,
+          None,
+          Problem::SurfaceCountMismatch {
+            materials: 13,
+            surfaces: 12
+          }
+        ),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_validate_textures() {
+    let mut pmx = model();
+    pmx.materials[1].texture_index = Some(2);
+    pmx.materials[1].toon = Toon::Texture(Some(-2));
+    pmx.materials[0].toon = Toon::Internal(10);
+
+    assert_eq!(
+      problems(&pmx),
+      [
+        (
+          Section::Materials,
+          Some(0),
+          Problem::UnknownSharedToon { toon: 10 }
+        ),
+        (
+          Section::Materials,
+          Some(1),
+          Problem::TextureOutOfRange { texture: 2 }
+        ),
+        (
+          Section::Materials,
+          Some(1),
+          Problem::TextureOutOfRange { texture: -2 }
+        ),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_validate_bones() {
+    let mut pmx = model();
+    pmx.bones[0].parent = Some(2);
+    pmx.bones[5].parent = Some(6);
+    let ik = pmx.bones[4].inverse_kinematics.as_mut().unwrap();
+    ik.links[1].ik_bone = 9;
+    let limits = ik.links[0].limits.as_mut().unwrap();
+    std::mem::swap(&mut limits.lower, &mut limits.upper);
+
+    assert_eq!(
+      problems(&pmx),
+      [
+        (Section::Bones, Some(0), Problem::ParentCycle),
+        (Section::Bones, Some(1), Problem::ParentCycle),
+        (Section::Bones, Some(2), Problem::ParentCycle),
+        (
+          Section::Bones,
+          Some(4),
+          Problem::IkBoneOutOfRange { bone: 9 }
+        ),
+        (
+          Section::Bones,
+          Some(4),
+          Problem::ReversedIkLimits { link: 0, axis: 0 }
+        ),
+        (
+          Section::Bones,
+          Some(5),
+          Problem::ParentOutOfRange { parent: 6 }
+        ),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_validate_morphs_and_weights() {
+    let mut pmx = model();
+    pmx.vertices[3].weight_deform = WeightDeform::Bdef2(Bdef2 {
+      bone_1_index: 0,
+      bone_2_index: 1,
+      bone_1_weight: 1.25,
+    });
+    pmx.morphs.push(Morph {
+      name: LocalizedName {
+        ja: "壊れ".to_string(),
+        en: String::new(),
+      },
+      panel: Panel::Other,
+      offsets: Offsets::Vertex(vec![
+        VertexOffset {
+          vertex: 0,
+          offset: [0.0; 3].into(),
+        },
+        VertexOffset {
+          vertex: 6,
+          offset: [0.0; 3].into(),
+        },
+      ]),
+    });
+
+    let validation = pmx.validate();
+    // Negative weights are only a warning
+    assert!(!validation.is_valid());
+    assert_eq!(validation.findings[0].severity, Severity::Warning);
+    assert_eq!(
+      problems(&pmx),
+      [
+        (
+          Section::Vertices,
+          Some(3),
+          Problem::NegativeWeight { weight: -0.25 }
+        ),
+        (
+          Section::Morphs,
+          Some(2),
+          Problem::MorphElementOutOfRange {
+            element: 1,
+            index: 6
+          }
+        ),
+      ]
+    );
+  }
+}
@@ -1,10 +1,31 @@
+#[cfg(feature = "serde")]
+use crate::SerdeConfig;
 use crate::{Config, WeightDeform};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
+/// The most additional vec4s a vertex can have.
+pub const MAX_ADDITIONAL_VEC4S: u8 = 4;
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct Vertex<C: Config> {
   pub position: C::Vec3,
   pub normal: C::Vec3,
   pub uv: C::Vec2,
+  /// `Settings::additional_vec4_count` additional UVs.
   pub additional: C::AdditionalVec4s,
   pub weight_deform: WeightDeform<C>,
   pub edge_scale: f32,
 }
+
+impl<C: Config> Vertex<C> {
+  /// The additional UV `channel`, 0 is `ADD_UV1` of the shaders.
+  pub fn additional_uv(&self, channel: usize) -> Option<&C::Vec4> {
+    self.additional.as_ref().get(channel)
+  }
+}
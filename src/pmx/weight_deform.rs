@@ -1,15 +1,39 @@
-use crate::Config;
+#[cfg(feature = "serde")]
+use crate::SerdeConfig;
+use crate::{Config, Pmx};
+use alloc::{vec, vec::Vec};
+use core::convert::TryInto;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct Bdef1<C: Config> {
   pub bone_index: C::BoneIndex,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct Bdef2<C: Config> {
   pub bone_1_index: C::BoneIndex,
   pub bone_2_index: C::BoneIndex,
   pub bone_1_weight: f32,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct Bdef4<C: Config> {
   pub bone_1_index: C::BoneIndex,
   pub bone_2_index: C::BoneIndex,
@@ -21,6 +45,12 @@ pub struct Bdef4<C: Config> {
   pub bone_4_weight: f32,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct Sdef<C: Config> {
   pub bone_1_index: C::BoneIndex,
   pub bone_2_index: C::BoneIndex,
@@ -30,6 +60,12 @@ pub struct Sdef<C: Config> {
   pub r1: C::Vec3,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct Qdef<C: Config> {
   pub bone_1_index: C::BoneIndex,
   pub bone_2_index: C::BoneIndex,
@@ -41,6 +77,12 @@ pub struct Qdef<C: Config> {
   pub bone_4_weight: f32,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub enum WeightDeform<C: Config> {
   Bdef1(Bdef1<C>),
   Bdef2(Bdef2<C>),
@@ -48,3 +90,303 @@ pub enum WeightDeform<C: Config> {
   Sdef(Sdef<C>),
   Qdef(Qdef<C>),
 }
+
+/// Weights whose sum is this close to 1 are left alone by `Pmx::normalize_weights`, so that it
+/// doesn't keep rescaling by rounding errors.
+const NORMALIZED: f32 = 4.0 * f32::EPSILON;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WeightProblem {
+  /// The weights of a BDEF4 or QDEF vertex don't add up to 1.
+  Sum(f32),
+  /// A weight is below 0, also reported for BDEF2 and SDEF weights above 1.
+  Negative(f32),
+  /// Two bones with non-zero weights are the same.
+  DuplicateBone(i64),
+}
+
+/// A problem with the skinning weights of a vertex, see `Pmx::check_weights`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WeightIssue {
+  pub vertex: usize,
+  pub problem: WeightProblem,
+}
+
+impl<C: Config> WeightDeform<C> {
+  /// The bones and their weights, the implied second weight included.
+  pub(crate) fn weights(&self) -> Vec<(&C::BoneIndex, f32)> {
+    match self {
+      WeightDeform::Bdef1(w) => vec![(&w.bone_index, 1.0)],
+      WeightDeform::Bdef2(w) => vec![
+        (&w.bone_1_index, w.bone_1_weight),
+        (&w.bone_2_index, 1.0 - w.bone_1_weight),
+      ],
+      WeightDeform::Sdef(w) => vec![
+        (&w.bone_1_index, w.bone_1_weight),
+        (&w.bone_2_index, 1.0 - w.bone_1_weight),
+      ],
+      WeightDeform::Bdef4(Bdef4 {
+        bone_1_index,
+        bone_2_index,
+        bone_3_index,
+        bone_4_index,
+        bone_1_weight,
+        bone_2_weight,
+        bone_3_weight,
+        bone_4_weight,
+      })
+      | WeightDeform::Qdef(Qdef {
+        bone_1_index,
+        bone_2_index,
+        bone_3_index,
+        bone_4_index,
+        bone_1_weight,
+        bone_2_weight,
+        bone_3_weight,
+        bone_4_weight,
+      }) => vec![
+        (bone_1_index, *bone_1_weight),
+        (bone_2_index, *bone_2_weight),
+        (bone_3_index, *bone_3_weight),
+        (bone_4_index, *bone_4_weight),
+      ],
+    }
+  }
+}
+
+fn check<C: Config>(vertex: usize, deform: &WeightDeform<C>, tolerance: f32) -> Vec<WeightIssue> {
+  let weights = deform.weights();
+  let mut issues = Vec::new();
+  let mut push = |problem| issues.push(WeightIssue { vertex, problem });
+
+  if let Some(&(_, weight)) = weights.iter().find(|(_, w)| *w < 0.0) {
+    push(WeightProblem::Negative(weight));
+  }
+  let sum: f32 = weights.iter().map(|(_, w)| w).sum();
+  if (sum - 1.0).abs() > tolerance {
+    push(WeightProblem::Sum(sum));
+  }
+  let used: Vec<_> = weights.iter().filter(|(_, w)| *w != 0.0).collect();
+  for (i, (bone, _)) in used.iter().enumerate() {
+    if used[..i].iter().any(|(b, _)| b == bone) {
+      push(WeightProblem::DuplicateBone(
+        (*bone).clone().try_into().unwrap_or(i64::MAX),
+      ));
+      break;
+    }
+  }
+  issues
+}
+
+/// Merges the weights of the same bone, drops the ones that aren't positive and rescales the
+/// rest to add up to 1. The bones without weight are the first bone again.
+fn normalize<I: Clone + PartialEq>(bones: &mut [&mut I], weights: &mut [f32]) {
+  let mut merged: Vec<(I, f32)> = Vec::with_capacity(bones.len());
+  for (bone, &weight) in bones.iter().zip(weights.iter()) {
+    if weight <= 0.0 {
+      continue;
+    }
+    match merged.iter_mut().find(|(b, _)| b == &**bone) {
+      Some((_, w)) => *w += weight,
+      None => merged.push(((**bone).clone(), weight)),
+    }
+  }
+  // Nothing to rescale
+  if merged.is_empty() {
+    return;
+  }
+
+  let sum: f32 = merged.iter().map(|(_, w)| w).sum();
+  let first = merged[0].0.clone();
+  for (i, (bone, weight)) in bones.iter_mut().zip(weights.iter_mut()).enumerate() {
+    match merged.get(i) {
+      Some((b, w)) => {
+        **bone = b.clone();
+        *weight = w / sum;
+      }
+      None => {
+        **bone = first.clone();
+        *weight = 0.0;
+      }
+    }
+  }
+  // The last weight takes the rounding errors of the others
+  let last = merged.len() - 1;
+  weights[last] = 1.0 - weights[..last].iter().sum::<f32>();
+}
+
+impl<C: Config> Pmx<C> {
+  /// Finds the vertices whose weights don't add up to 1 within `tolerance`, are negative or
+  /// give weight to the same bone twice, in the order of the vertices.
+  pub fn check_weights(&self, tolerance: f32) -> Vec<WeightIssue> {
+    self
+      .vertices
+      .iter()
+      .enumerate()
+      .flat_map(|(i, v)| check(i, &v.weight_deform, tolerance))
+      .collect()
+  }
+
+  /// Repairs the BDEF2 and BDEF4 vertices `check_weights` reports, keeping their kind.
+  ///
+  /// Negative weights become 0, the weights of the same bone are merged and the rest are
+  /// rescaled to add up to exactly 1 in the first slots. SDEF and QDEF vertices are left alone,
+  /// as are vertices without any positive weight. Normalizing twice changes nothing.
+  pub fn normalize_weights(&mut self) {
+    for vertex in &mut self.vertices {
+      if check(0, &vertex.weight_deform, NORMALIZED).is_empty() {
+        continue;
+      }
+      match &mut vertex.weight_deform {
+        WeightDeform::Bdef2(w) => {
+          let mut weights = [w.bone_1_weight, 1.0 - w.bone_1_weight];
+          normalize(
+            &mut [&mut w.bone_1_index, &mut w.bone_2_index],
+            &mut weights,
+          );
+          w.bone_1_weight = weights[0];
+        }
+        WeightDeform::Bdef4(w) => {
+          let mut weights = [
+            w.bone_1_weight,
+            w.bone_2_weight,
+            w.bone_3_weight,
+            w.bone_4_weight,
+          ];
+          normalize(
+            &mut [
+              &mut w.bone_1_index,
+              &mut w.bone_2_index,
+              &mut w.bone_3_index,
+              &mut w.bone_4_index,
+            ],
+            &mut weights,
+          );
+          w.bone_1_weight = weights[0];
+          w.bone_2_weight = weights[1];
+          w.bone_3_weight = weights[2];
+          w.bone_4_weight = weights[3];
+        }
+        WeightDeform::Bdef1(_) | WeightDeform::Sdef(_) | WeightDeform::Qdef(_) => {}
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use crate::DefaultConfig;
+
+  /// The fixture model cut down to one vertex per weight deform.
+  pub(crate) fn model(weights: Vec<WeightDeform<DefaultConfig>>) -> Pmx {
+    let mut pmx = Pmx::read(&include_bytes!("../../fixtures/model.pmx")[..]).unwrap();
+    pmx.vertices.truncate(weights.len());
+    for (vertex, weight_deform) in pmx.vertices.iter_mut().zip(weights) {
+      vertex.weight_deform = weight_deform;
+    }
+    pmx
+  }
+
+  fn bdef4(bones: [i32; 4], weights: [f32; 4]) -> WeightDeform<DefaultConfig> {
+    WeightDeform::Bdef4(Bdef4 {
+      bone_1_index: bones[0],
+      bone_2_index: bones[1],
+      bone_3_index: bones[2],
+      bone_4_index: bones[3],
+      bone_1_weight: weights[0],
+      bone_2_weight: weights[1],
+      bone_3_weight: weights[2],
+      bone_4_weight: weights[3],
+    })
+  }
+
+  fn sum(deform: &WeightDeform<DefaultConfig>) -> f32 {
+    deform.weights().iter().map(|(_, w)| w).sum()
+  }
+
+  #[test]
+  fn test_check_weights() {
+    let pmx = model(vec![
+      bdef4([0, 1, 2, 3], [0.5, 0.25, 0.25, 0.0]),
+      bdef4([0, 1, 2, 3], [0.5, 0.25, 0.125, 0.0625]),
+      WeightDeform::Bdef2(Bdef2 {
+        bone_1_index: 0,
+        bone_2_index: 1,
+        bone_1_weight: 1.25,
+      }),
+      bdef4([1, 2, 1, 0], [0.5, 0.25, 0.25, 0.0]),
+    ]);
+
+    assert_eq!(
+      pmx.check_weights(0.01),
+      [
+        WeightIssue {
+          vertex: 1,
+          problem: WeightProblem::Sum(0.9375)
+        },
+        WeightIssue {
+          vertex: 2,
+          problem: WeightProblem::Negative(-0.25)
+        },
+        WeightIssue {
+          vertex: 3,
+          problem: WeightProblem::DuplicateBone(1)
+        },
+      ]
+    );
+    assert_eq!(pmx.check_weights(0.1).len(), 2);
+  }
+
+  #[test]
+  fn test_normalize_weights() {
+    let sdef = WeightDeform::Sdef(Sdef {
+      bone_1_index: 0,
+      bone_2_index: 1,
+      bone_1_weight: 1.5,
+      c: [0.0; 3].into(),
+      r0: [0.0; 3].into(),
+      r1: [0.0; 3].into(),
+    });
+    let mut pmx = model(vec![
+      bdef4([0, 1, 2, 3], [0.5, 0.25, 0.2, 0.02]),
+      bdef4([0, 1, 2, 3], [0.5, 0.3, 0.25, 0.0]),
+      WeightDeform::Bdef2(Bdef2 {
+        bone_1_index: 0,
+        bone_2_index: 1,
+        bone_1_weight: 1.25,
+      }),
+      bdef4([1, 2, 1, 0], [0.5, 0.25, 0.25, -0.1]),
+      sdef.clone(),
+    ]);
+
+    pmx.normalize_weights();
+    assert_eq!(
+      pmx.check_weights(NORMALIZED),
+      [WeightIssue {
+        vertex: 4,
+        problem: WeightProblem::Negative(-0.5)
+      }]
+    );
+    for vertex in &pmx.vertices[..4] {
+      assert_eq!(sum(&vertex.weight_deform), 1.0);
+    }
+    assert!(matches!(
+      &pmx.vertices[2].weight_deform,
+      WeightDeform::Bdef2(w) if w.bone_1_weight == 1.0
+    ));
+    assert!(matches!(
+      &pmx.vertices[3].weight_deform,
+      WeightDeform::Bdef4(w)
+        if (w.bone_1_index, w.bone_2_index, w.bone_3_index, w.bone_4_index) == (1, 2, 1, 1)
+          && (w.bone_1_weight - 0.75).abs() < 1e-6
+          && w.bone_3_weight == 0.0
+          && w.bone_4_weight == 0.0
+    ));
+    assert_eq!(pmx.vertices[4].weight_deform, sdef);
+
+    let normalized = pmx.clone();
+    pmx.normalize_weights();
+    assert_eq!(pmx, normalized);
+  }
+}
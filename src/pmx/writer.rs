@@ -0,0 +1,1123 @@
+use crate::{
+  pmx::{
+    bone::*, display::*, joint::Joint, material::*, model::has_soft_bodies, morph::*,
+    rigid_body::RigidBody, soft_body::SoftBody, types::*, vertex::MAX_ADDITIONAL_VEC4S,
+    weight_deform::*,
+  },
+  Bone, Config, Error, Material, Pmx, Result, Settings, Vertex,
+};
+use byteorder::{WriteBytesExt, LE};
+use std::convert::TryInto;
+use std::io::Write;
+
+/// Mirrors `ReadHelpers`.
+trait WriteHelpers: Write {
+  fn write_text(&mut self, text: &str, encoding: TextEncoding) -> Result<()> {
+    let buf = match encoding {
+      TextEncoding::UTF8 => text.as_bytes().to_vec(),
+      TextEncoding::UTF16LE => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+    };
+
+    self.write_i32::<LE>(buf.len() as i32)?;
+    self.write_all(&buf)?;
+    Ok(())
+  }
+
+  fn write_vec<F: Float>(&mut self, v: &[F]) -> Result<()> {
+    for &c in v {
+      self.write_f32::<LE>(c.to_f32())?;
+    }
+    Ok(())
+  }
+
+  fn write_index<I: Index>(&mut self, index: &I, size: IndexSize) -> Result<()> {
+    let v = to_i64(index)?;
+    let overflow = || Error::IndexOverflow(v);
+    match size {
+      IndexSize::I8 => self.write_i8(v.try_into().map_err(|_| overflow())?)?,
+      IndexSize::I16 => self.write_i16::<LE>(v.try_into().map_err(|_| overflow())?)?,
+      IndexSize::I32 => self.write_i32::<LE>(v.try_into().map_err(|_| overflow())?)?,
+    }
+    Ok(())
+  }
+
+  /// Writes `None` as -1, mirroring `read_optional_index`.
+  fn write_optional_index<I: Index>(&mut self, index: &Option<I>, size: IndexSize) -> Result<()> {
+    match index {
+      Some(index) => self.write_index(index, size),
+      None => match size {
+        IndexSize::I8 => Ok(self.write_i8(-1)?),
+        IndexSize::I16 => Ok(self.write_i16::<LE>(-1)?),
+        IndexSize::I32 => Ok(self.write_i32::<LE>(-1)?),
+      },
+    }
+  }
+
+  fn write_vertex_index<I: VertexIndex>(&mut self, index: &I, size: IndexSize) -> Result<()> {
+    let v = to_i64(index)?;
+    let overflow = || Error::IndexOverflow(v);
+    match size {
+      IndexSize::I8 => self.write_u8(v.try_into().map_err(|_| overflow())?)?,
+      IndexSize::I16 => self.write_u16::<LE>(v.try_into().map_err(|_| overflow())?)?,
+      IndexSize::I32 => self.write_i32::<LE>(v.try_into().map_err(|_| overflow())?)?,
+    }
+    Ok(())
+  }
+}
+
+impl<W: Write> WriteHelpers for W {}
+
+fn to_i64<I: TryInto<i64> + Clone>(index: &I) -> Result<i64> {
+  // Only indices beyond i64, e.g. huge u64s, fail here
+  index
+    .clone()
+    .try_into()
+    .map_err(|_| Error::IndexOverflow(i64::MAX))
+}
+
+fn write_count<W: Write>(write: &mut W, count: usize) -> Result<()> {
+  let count = count
+    .try_into()
+    .map_err(|_| Error::IndexOverflow(count as i64))?;
+  write.write_i32::<LE>(count)?;
+  Ok(())
+}
+
+/// Checks that `flag` is set exactly when the bone has the fields it needs.
+fn check_flag<C: Config>(bone: &Bone<C>, flag: BoneFlags, has_fields: bool) -> Result<()> {
+  if bone.bone_flags.contains(flag) != has_fields {
+    return Err(Error::BoneFlagMismatch {
+      name: bone.name.ja.clone(),
+      flag,
+    });
+  }
+  Ok(())
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PmxWriteOptions {
+  /// Writes the indices with the sizes of `Pmx::shrunk_settings` instead of `settings`.
+  pub shrink_indices: bool,
+}
+
+impl<C: Config> Pmx<C> {
+  /// `settings` with the smallest index sizes that fit every element of the lists.
+  pub fn shrunk_settings(&self) -> Settings {
+    Settings {
+      vertex_index_size: smallest_index_size(self.vertices.len(), true),
+      texture_index_size: smallest_index_size(self.textures.len(), false),
+      material_index_size: smallest_index_size(self.materials.len(), false),
+      bone_index_size: smallest_index_size(self.bones.len(), false),
+      morph_index_size: smallest_index_size(self.morphs.len(), false),
+      rigidbody_index_size: smallest_index_size(self.rigid_bodies.len(), false),
+      ..self.settings
+    }
+  }
+
+  /// Writes the model with the sizes and text encoding of `settings`.
+  ///
+  /// Indices that don't fit their size fail with `Error::IndexOverflow` and bones whose flags
+  /// don't match their optional fields with `Error::BoneFlagMismatch`. A model read with
+  /// `Pmx::read` is written back byte for byte. Soft bodies in a PMX 2.0 model fail with
+  /// `Error::UnexpectedSoftBodies`.
+  pub fn write<W: Write>(&self, write: &mut W) -> Result<()> {
+    self.write_with(write, &PmxWriteOptions::default())
+  }
+
+  /// `Pmx::write` with `options`.
+  pub fn write_with<W: Write>(&self, write: &mut W, options: &PmxWriteOptions) -> Result<()> {
+    let shrunk;
+    let s = if options.shrink_indices {
+      shrunk = self.shrunk_settings();
+      &shrunk
+    } else {
+      &self.settings
+    };
+    if !self.skipped_sections.is_empty() {
+      return Err(Error::SkippedSections(self.skipped_sections));
+    }
+    let soft_bodies = has_soft_bodies(self.version);
+    if !soft_bodies && !self.soft_bodies.is_empty() {
+      return Err(Error::UnexpectedSoftBodies {
+        version: self.version,
+        count: self.soft_bodies.len(),
+      });
+    }
+
+    if s.additional_vec4_count > MAX_ADDITIONAL_VEC4S {
+      return Err(Error::InvalidAdditionalVec4Count(s.additional_vec4_count));
+    }
+
+    write.write_all(b"PMX ")?;
+    write.write_f32::<LE>(self.version)?;
+    let globals_count = 8 + self.extra_globals.len();
+    write.write_u8(
+      globals_count
+        .try_into()
+        .map_err(|_| Error::IndexOverflow(globals_count as i64))?,
+    )?;
+    write.write_all(&[
+      s.text_encoding as u8,
+      s.additional_vec4_count,
+      s.vertex_index_size as u8,
+      s.texture_index_size as u8,
+      s.material_index_size as u8,
+      s.bone_index_size as u8,
+      s.morph_index_size as u8,
+      s.rigidbody_index_size as u8,
+    ])?;
+    write.write_all(&self.extra_globals)?;
+    for text in &[
+      &self.model_local_name,
+      &self.model_universal_name,
+      &self.local_comments,
+      &self.universal_comments,
+    ] {
+      write.write_text(text, s.text_encoding)?;
+    }
+
+    write_count(write, self.vertices.len())?;
+    for vertex in &self.vertices {
+      write_vertex(write, s, vertex)?;
+    }
+
+    write_count(write, self.surfaces.len() * 3)?;
+    for surface in &self.surfaces {
+      for index in surface {
+        write.write_vertex_index(index, s.vertex_index_size)?;
+      }
+    }
+
+    write_count(write, self.textures.len())?;
+    for texture in &self.textures {
+      write.write_text(texture, s.text_encoding)?;
+    }
+
+    write_count(write, self.materials.len())?;
+    for material in &self.materials {
+      write_material(write, s, material)?;
+    }
+
+    write_count(write, self.bones.len())?;
+    for bone in &self.bones {
+      write_bone(write, s, bone)?;
+    }
+
+    write_count(write, self.morphs.len())?;
+    for morph in &self.morphs {
+      write_morph(write, s, morph)?;
+    }
+
+    write_count(write, self.display_frames.len())?;
+    for display_frame in &self.display_frames {
+      write_display_frame(write, s, display_frame)?;
+    }
+
+    write_count(write, self.rigid_bodies.len())?;
+    for rigid_body in &self.rigid_bodies {
+      write_rigid_body(write, s, rigid_body)?;
+    }
+
+    write_count(write, self.joints.len())?;
+    for joint in &self.joints {
+      write_joint(write, s, joint)?;
+    }
+
+    if soft_bodies {
+      write_count(write, self.soft_bodies.len())?;
+      for soft_body in &self.soft_bodies {
+        write_soft_body(write, s, soft_body)?;
+      }
+    }
+
+    Ok(())
+  }
+}
+
+fn write_vertex<W: Write, C: Config>(
+  write: &mut W,
+  s: &Settings,
+  vertex: &Vertex<C>,
+) -> Result<()> {
+  write.write_vec(vertex.position.as_slice())?;
+  write.write_vec(vertex.normal.as_slice())?;
+  write.write_vec(vertex.uv.as_slice())?;
+  let additional = vertex.additional.as_ref();
+  if additional.len() != s.additional_vec4_count as usize {
+    return Err(Error::CountMismatch {
+      declared: s.additional_vec4_count.into(),
+      found: additional.len() as u32,
+    });
+  }
+  for v in additional {
+    write.write_vec(v.as_slice())?;
+  }
+
+  let size = s.bone_index_size;
+  match &vertex.weight_deform {
+    WeightDeform::Bdef1(w) => {
+      write.write_u8(0)?;
+      write.write_index(&w.bone_index, size)?;
+    }
+    WeightDeform::Bdef2(w) => {
+      write.write_u8(1)?;
+      write.write_index(&w.bone_1_index, size)?;
+      write.write_index(&w.bone_2_index, size)?;
+      write.write_f32::<LE>(w.bone_1_weight)?;
+    }
+    WeightDeform::Bdef4(w) => {
+      write.write_u8(2)?;
+      write.write_index(&w.bone_1_index, size)?;
+      write.write_index(&w.bone_2_index, size)?;
+      write.write_index(&w.bone_3_index, size)?;
+      write.write_index(&w.bone_4_index, size)?;
+      write.write_f32::<LE>(w.bone_1_weight)?;
+      write.write_f32::<LE>(w.bone_2_weight)?;
+      write.write_f32::<LE>(w.bone_3_weight)?;
+      write.write_f32::<LE>(w.bone_4_weight)?;
+    }
+    WeightDeform::Sdef(w) => {
+      write.write_u8(3)?;
+      write.write_index(&w.bone_1_index, size)?;
+      write.write_index(&w.bone_2_index, size)?;
+      write.write_f32::<LE>(w.bone_1_weight)?;
+      write.write_vec(w.c.as_slice())?;
+      write.write_vec(w.r0.as_slice())?;
+      write.write_vec(w.r1.as_slice())?;
+    }
+    WeightDeform::Qdef(w) => {
+      write.write_u8(4)?;
+      write.write_index(&w.bone_1_index, size)?;
+      write.write_index(&w.bone_2_index, size)?;
+      write.write_index(&w.bone_3_index, size)?;
+      write.write_index(&w.bone_4_index, size)?;
+      write.write_f32::<LE>(w.bone_1_weight)?;
+      write.write_f32::<LE>(w.bone_2_weight)?;
+      write.write_f32::<LE>(w.bone_3_weight)?;
+      write.write_f32::<LE>(w.bone_4_weight)?;
+    }
+  }
+
+  write.write_f32::<LE>(vertex.edge_scale)?;
+  Ok(())
+}
+
+fn write_material<W: Write, C: Config>(
+  write: &mut W,
+  s: &Settings,
+  material: &Material<C>,
+) -> Result<()> {
+  write.write_text(&material.name.ja, s.text_encoding)?;
+  write.write_text(&material.name.en, s.text_encoding)?;
+  write.write_vec(material.diffuse_color.as_slice())?;
+  write.write_vec(material.specular_color.as_slice())?;
+  write.write_f32::<LE>(material.specular_strength)?;
+  write.write_vec(material.ambient_color.as_slice())?;
+  write.write_u8(material.draw_flags.raw())?;
+  write.write_vec(material.edge_color.as_slice())?;
+  write.write_f32::<LE>(material.edge_scale)?;
+  write.write_optional_index(&material.texture_index, s.texture_index_size)?;
+  write.write_optional_index(&material.environment_index, s.texture_index_size)?;
+  write.write_u8(material.environment_blend_mode as u8)?;
+  match &material.toon {
+    Toon::Texture(index) => {
+      write.write_u8(0)?;
+      write.write_optional_index(index, s.texture_index_size)?;
+    }
+    Toon::Internal(index) => {
+      write.write_u8(1)?;
+      write.write_u8(*index)?;
+    }
+  }
+  write.write_text(&material.metadata, s.text_encoding)?;
+  write.write_i32::<LE>(material.surface_count)?;
+  Ok(())
+}
+
+fn write_bone<W: Write, C: Config>(write: &mut W, s: &Settings, bone: &Bone<C>) -> Result<()> {
+  check_flag(
+    bone,
+    BoneFlags::Connection,
+    matches!(bone.connection, Connection::Index(_)),
+  )?;
+  if bone.additional.is_some()
+    != bone
+      .bone_flags
+      .intersects(BoneFlags::AddRotation | BoneFlags::AddMovement)
+  {
+    return Err(Error::BoneFlagMismatch {
+      name: bone.name.ja.clone(),
+      flag: BoneFlags::AddRotation,
+    });
+  }
+  check_flag(bone, BoneFlags::FixedAxis, bone.fixed_axis.is_some())?;
+  check_flag(bone, BoneFlags::LocalAxis, bone.local_axis.is_some())?;
+  check_flag(
+    bone,
+    BoneFlags::ExternalParentTransform,
+    bone.external_parent_transform.is_some(),
+  )?;
+  check_flag(
+    bone,
+    BoneFlags::InverseKinematics,
+    bone.inverse_kinematics.is_some(),
+  )?;
+
+  let size = s.bone_index_size;
+  write.write_text(&bone.name.ja, s.text_encoding)?;
+  write.write_text(&bone.name.en, s.text_encoding)?;
+  write.write_vec(bone.position.as_slice())?;
+  write.write_optional_index(&bone.parent, size)?;
+  write.write_i32::<LE>(bone.transform_level)?;
+  write.write_u16::<LE>(bone.bone_flags.bits())?;
+
+  match &bone.connection {
+    Connection::Index(index) => write.write_optional_index(index, size)?,
+    Connection::Position(position) => write.write_vec(position.as_slice())?,
+  }
+  if let Some(additional) = &bone.additional {
+    write.write_index(&additional.parent, size)?;
+    write.write_f32::<LE>(additional.rate)?;
+  }
+  if let Some(fixed_axis) = &bone.fixed_axis {
+    write.write_vec(fixed_axis.as_slice())?;
+  }
+  if let Some(local_axis) = &bone.local_axis {
+    write.write_vec(local_axis.x.as_slice())?;
+    write.write_vec(local_axis.z.as_slice())?;
+  }
+  if let Some(external_parent_transform) = bone.external_parent_transform {
+    write.write_i32::<LE>(external_parent_transform)?;
+  }
+  if let Some(ik) = &bone.inverse_kinematics {
+    write.write_index(&ik.ik_bone, size)?;
+    write.write_u32::<LE>(ik.iterations)?;
+    write.write_f32::<LE>(ik.limit_angle)?;
+    write.write_u32::<LE>(ik.links.len() as u32)?;
+    for link in &ik.links {
+      write.write_index(&link.ik_bone, size)?;
+      match &link.limits {
+        Some(limits) => {
+          write.write_u8(1)?;
+          write.write_vec(limits.lower.as_slice())?;
+          write.write_vec(limits.upper.as_slice())?;
+        }
+        None => write.write_u8(0)?,
+      }
+    }
+  }
+  Ok(())
+}
+
+fn write_morph<W: Write, C: Config>(write: &mut W, s: &Settings, morph: &Morph<C>) -> Result<()> {
+  write.write_text(&morph.name.ja, s.text_encoding)?;
+  write.write_text(&morph.name.en, s.text_encoding)?;
+  write.write_u8(morph.panel.into())?;
+
+  let (morph_type, count) = match &morph.offsets {
+    Offsets::Group(offsets) => (0, offsets.len()),
+    Offsets::Vertex(offsets) => (1, offsets.len()),
+    Offsets::Bone(offsets) => (2, offsets.len()),
+    Offsets::UV(offsets) => (3, offsets.len()),
+    Offsets::AdditionalUV1(offsets) => (4, offsets.len()),
+    Offsets::AdditionalUV2(offsets) => (5, offsets.len()),
+    Offsets::AdditionalUV3(offsets) => (6, offsets.len()),
+    Offsets::AdditionalUV4(offsets) => (7, offsets.len()),
+    Offsets::Material(offsets) => (8, offsets.len()),
+    Offsets::Flip(offsets) => (9, offsets.len()),
+    Offsets::Impulse(offsets) => (10, offsets.len()),
+  };
+  write.write_u8(morph_type)?;
+  write.write_u32::<LE>(count as u32)?;
+
+  match &morph.offsets {
+    Offsets::Group(offsets) | Offsets::Flip(offsets) => {
+      for offset in offsets {
+        write.write_index(&offset.morph, s.morph_index_size)?;
+        write.write_f32::<LE>(offset.influence)?;
+      }
+    }
+    Offsets::Vertex(offsets) => {
+      for offset in offsets {
+        write.write_vertex_index(&offset.vertex, s.vertex_index_size)?;
+        write.write_vec(offset.offset.as_slice())?;
+      }
+    }
+    Offsets::Bone(offsets) => {
+      for offset in offsets {
+        write.write_index(&offset.bone, s.bone_index_size)?;
+        write.write_vec(offset.translation.as_slice())?;
+        write.write_vec(offset.rotation.as_slice())?;
+      }
+    }
+    Offsets::UV(offsets)
+    | Offsets::AdditionalUV1(offsets)
+    | Offsets::AdditionalUV2(offsets)
+    | Offsets::AdditionalUV3(offsets)
+    | Offsets::AdditionalUV4(offsets) => {
+      for offset in offsets {
+        write.write_vertex_index(&offset.vertex, s.vertex_index_size)?;
+        write.write_vec(offset.offset.as_slice())?;
+      }
+    }
+    Offsets::Material(offsets) => {
+      for offset in offsets {
+        write.write_optional_index(&offset.material, s.material_index_size)?;
+        write.write_u8(offset.method as u8)?;
+        write.write_vec(offset.diffuse_color.as_slice())?;
+        write.write_vec(offset.specular_color.as_slice())?;
+        write.write_f32::<LE>(offset.specular_strength)?;
+        write.write_vec(offset.ambient_color.as_slice())?;
+        write.write_vec(offset.edge_color.as_slice())?;
+        write.write_f32::<LE>(offset.edge_scale)?;
+        write.write_vec(offset.texture_tint.as_slice())?;
+        write.write_vec(offset.environment_tint.as_slice())?;
+        write.write_vec(offset.toon_tint.as_slice())?;
+      }
+    }
+    Offsets::Impulse(offsets) => {
+      for offset in offsets {
+        write.write_index(&offset.rigid_body, s.rigidbody_index_size)?;
+        write.write_u8(offset.local as u8)?;
+        write.write_vec(offset.velocity.as_slice())?;
+        write.write_vec(offset.torque.as_slice())?;
+      }
+    }
+  }
+  Ok(())
+}
+
+fn write_display_frame<W: Write, C: Config>(
+  write: &mut W,
+  s: &Settings,
+  display_frame: &DisplayFrame<C>,
+) -> Result<()> {
+  write.write_text(&display_frame.name.ja, s.text_encoding)?;
+  write.write_text(&display_frame.name.en, s.text_encoding)?;
+  write.write_u8(display_frame.special_flag as u8)?;
+  write.write_u32::<LE>(display_frame.elements.len() as u32)?;
+  for element in &display_frame.elements {
+    match element {
+      DisplayElement::Bone(index) => {
+        write.write_u8(0)?;
+        write.write_index(index, s.bone_index_size)?;
+      }
+      DisplayElement::Morph(index) => {
+        write.write_u8(1)?;
+        write.write_index(index, s.morph_index_size)?;
+      }
+    }
+  }
+  Ok(())
+}
+
+fn write_rigid_body<W: Write, C: Config>(
+  write: &mut W,
+  s: &Settings,
+  rigid_body: &RigidBody<C>,
+) -> Result<()> {
+  write.write_text(&rigid_body.name.ja, s.text_encoding)?;
+  write.write_text(&rigid_body.name.en, s.text_encoding)?;
+  write.write_optional_index(&rigid_body.bone_index, s.bone_index_size)?;
+  write.write_u8(rigid_body.group_id)?;
+  write.write_u16::<LE>(rigid_body.non_collision_mask)?;
+  write.write_u8(rigid_body.shape as u8)?;
+  write.write_vec(rigid_body.shape_size.as_slice())?;
+  write.write_vec(rigid_body.shape_position.as_slice())?;
+  write.write_vec(rigid_body.shape_rotation.as_slice())?;
+  write.write_f32::<LE>(rigid_body.mass)?;
+  write.write_f32::<LE>(rigid_body.move_attenuation)?;
+  write.write_f32::<LE>(rigid_body.rotation_damping)?;
+  write.write_f32::<LE>(rigid_body.repulsion)?;
+  write.write_f32::<LE>(rigid_body.friction)?;
+  write.write_u8(rigid_body.physics_mode as u8)?;
+  Ok(())
+}
+
+fn write_joint<W: Write, C: Config>(write: &mut W, s: &Settings, joint: &Joint<C>) -> Result<()> {
+  write.write_text(&joint.name.ja, s.text_encoding)?;
+  write.write_text(&joint.name.en, s.text_encoding)?;
+  write.write_u8(joint.joint_type as u8)?;
+  write.write_index(&joint.rigid_body_a, s.rigidbody_index_size)?;
+  write.write_index(&joint.rigid_body_b, s.rigidbody_index_size)?;
+  for v in &[
+    &joint.position,
+    &joint.rotation,
+    &joint.position_min,
+    &joint.position_max,
+    &joint.rotation_min,
+    &joint.rotation_max,
+    &joint.position_spring,
+    &joint.rotation_spring,
+  ] {
+    write.write_vec(v.as_slice())?;
+  }
+  Ok(())
+}
+
+fn write_soft_body<W: Write, C: Config>(
+  write: &mut W,
+  s: &Settings,
+  soft_body: &SoftBody<C>,
+) -> Result<()> {
+  write.write_text(&soft_body.name.ja, s.text_encoding)?;
+  write.write_text(&soft_body.name.en, s.text_encoding)?;
+  write.write_u8(soft_body.shape as u8)?;
+  write.write_index(&soft_body.material, s.material_index_size)?;
+  write.write_u8(soft_body.group_id)?;
+  write.write_u16::<LE>(soft_body.non_collision_mask)?;
+  write.write_u8(soft_body.flags.bits())?;
+  write.write_i32::<LE>(soft_body.link_distance)?;
+  write.write_i32::<LE>(soft_body.cluster_count)?;
+  write.write_f32::<LE>(soft_body.mass)?;
+  write.write_f32::<LE>(soft_body.collision_margin)?;
+  write.write_i32::<LE>(soft_body.aero_model as i32)?;
+
+  let p = &soft_body.parameters;
+  for v in &[
+    p.velocity_correction,
+    p.damping,
+    p.drag,
+    p.lift,
+    p.pressure,
+    p.volume_conservation,
+    p.dynamic_friction,
+    p.pose_matching,
+    p.rigid_contact_hardness,
+    p.kinetic_contact_hardness,
+    p.soft_contact_hardness,
+    p.anchor_hardness,
+    p.cluster_rigid_hardness,
+    p.cluster_kinetic_hardness,
+    p.cluster_soft_hardness,
+    p.cluster_rigid_impulse_split,
+    p.cluster_kinetic_impulse_split,
+    p.cluster_soft_impulse_split,
+  ] {
+    write.write_f32::<LE>(*v)?;
+  }
+  for v in &[
+    p.velocity_iterations,
+    p.position_iterations,
+    p.drift_iterations,
+    p.cluster_iterations,
+  ] {
+    write.write_i32::<LE>(*v)?;
+  }
+  for v in &[p.linear_stiffness, p.area_stiffness, p.volume_stiffness] {
+    write.write_f32::<LE>(*v)?;
+  }
+
+  write_count(write, soft_body.anchors.len())?;
+  for anchor in &soft_body.anchors {
+    write.write_index(&anchor.rigid_body, s.rigidbody_index_size)?;
+    write.write_vertex_index(&anchor.vertex, s.vertex_index_size)?;
+    write.write_u8(anchor.near_mode as u8)?;
+  }
+  write_count(write, soft_body.pinned_vertices.len())?;
+  for vertex in &soft_body.pinned_vertices {
+    write.write_vertex_index(vertex, s.vertex_index_size)?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::pmx::bone::tests::bone;
+  use crate::pmx::joint::JointType;
+  use crate::pmx::rigid_body::{PhysicsMode, ShapeType};
+  use crate::pmx::soft_body::*;
+  use crate::DefaultConfig;
+  use crate::LocalizedName;
+  use enumflags2::BitFlags;
+  use std::io::Cursor;
+
+  fn model(settings: Settings) -> Pmx {
+    let additional = || {
+      (0..settings.additional_vec4_count)
+        .map(|i| [i as f32, 0.5, 0.25, 1.0].into())
+        .collect()
+    };
+    let vertex = |x: f32, weight_deform| Vertex {
+      position: [x, 1.0, -1.0].into(),
+      normal: [0.0, 0.0, -1.0].into(),
+      uv: [x, 0.5].into(),
+      additional: additional(),
+      weight_deform,
+      edge_scale: 1.0,
+    };
+
+    let tail = Connection::Position([0.0, 1.0, 0.0].into());
+    let mut ik = bone("左足ＩＫ", Some(0), [0.0, 1.0, 0.0]);
+    ik.bone_flags |= BoneFlags::InverseKinematics | BoneFlags::Connection;
+    ik.connection = Connection::Index(None);
+    ik.inverse_kinematics = Some(InverseKinematics {
+      ik_bone: 2,
+      iterations: 40,
+      limit_angle: 2.0,
+      links: vec![
+        IKLink {
+          ik_bone: 1,
+          limits: Some(AngleLimits {
+            lower: [-3.0, 0.0, 0.0].into(),
+            upper: [-0.01, 0.0, 0.0].into(),
+          }),
+        },
+        IKLink {
+          ik_bone: 0,
+          limits: None,
+        },
+      ],
+    });
+    let mut twist = Bone {
+      connection: tail,
+      ..bone("左腕捩", Some(1), [0.0, 2.0, 0.0])
+    };
+    twist.bone_flags |= BoneFlags::AddRotation
+      | BoneFlags::FixedAxis
+      | BoneFlags::LocalAxis
+      | BoneFlags::ExternalParentTransform;
+    twist.additional = Some(Additional {
+      parent: 1,
+      rate: 0.5,
+    });
+    twist.fixed_axis = Some([1.0, 0.0, 0.0].into());
+    twist.local_axis = Some(LocalAxis {
+      x: [1.0, 0.0, 0.0].into(),
+      z: [0.0, 0.0, 1.0].into(),
+    });
+    twist.external_parent_transform = Some(3);
+
+    let morph = |name: &str, panel, offsets| Morph {
+      name: LocalizedName {
+        ja: name.to_string(),
+        en: name.to_string(),
+      },
+      panel,
+      offsets,
+    };
+    let uv = || {
+      vec![UVOffset {
+        vertex: 1,
+        offset: [0.1, 0.2, 0.0, 0.0].into(),
+      }]
+    };
+
+    Pmx {
+      version: 2.0,
+      settings,
+      model_local_name: "テスト".to_string(),
+      model_universal_name: "Test".to_string(),
+      local_comments: "コメント\r\n".to_string(),
+      universal_comments: String::new(),
+      extra_globals: Vec::new(),
+      vertices: vec![
+        vertex(0.0, WeightDeform::Bdef1(Bdef1 { bone_index: 0 })),
+        vertex(
+          1.0,
+          WeightDeform::Bdef2(Bdef2 {
+            bone_1_index: 0,
+            bone_2_index: 1,
+            bone_1_weight: 0.75,
+          }),
+        ),
+        vertex(
+          2.0,
+          WeightDeform::Bdef4(Bdef4 {
+            bone_1_index: 0,
+            bone_2_index: 1,
+            bone_3_index: 2,
+            bone_4_index: -1,
+            bone_1_weight: 0.5,
+            bone_2_weight: 0.25,
+            bone_3_weight: 0.25,
+            bone_4_weight: 0.0,
+          }),
+        ),
+        vertex(
+          3.0,
+          WeightDeform::Sdef(Sdef {
+            bone_1_index: 1,
+            bone_2_index: 2,
+            bone_1_weight: 0.5,
+            c: [0.0, 1.0, 0.0].into(),
+            r0: [0.0, 1.5, 0.0].into(),
+            r1: [0.0, 0.5, 0.0].into(),
+          }),
+        ),
+        vertex(
+          4.0,
+          WeightDeform::Qdef(Qdef {
+            bone_1_index: 0,
+            bone_2_index: 1,
+            bone_3_index: 2,
+            bone_4_index: 3,
+            bone_1_weight: 0.25,
+            bone_2_weight: 0.25,
+            bone_3_weight: 0.25,
+            bone_4_weight: 0.25,
+          }),
+        ),
+      ],
+      surfaces: vec![[0, 1, 2], [2, 3, 4]],
+      textures: vec!["tex\\body.png".to_string(), "toon01.bmp".to_string()],
+      materials: vec![
+        Material {
+          name: LocalizedName {
+            ja: "体".to_string(),
+            en: "body".to_string(),
+          },
+          diffuse_color: [1.0, 0.9, 0.8, 1.0].into(),
+          specular_color: [0.1; 3].into(),
+          specular_strength: 5.0,
+          ambient_color: [0.5; 3].into(),
+          draw_flags: (DrawingFlags::NoCull | DrawingFlags::HasEdge).into(),
+          edge_color: [0.0, 0.0, 0.0, 1.0].into(),
+          edge_scale: 1.0,
+          texture_index: Some(0),
+          environment_index: None,
+          environment_blend_mode: EnvironmentBlendMode::Multiply,
+          toon: Toon::Texture(Some(1)),
+          metadata: "メモ".to_string(),
+          surface_count: 3,
+        },
+        Material {
+          name: LocalizedName {
+            ja: "髪".to_string(),
+            en: String::new(),
+          },
+          diffuse_color: [0.2, 0.2, 0.2, 0.5].into(),
+          specular_color: [0.0; 3].into(),
+          specular_strength: 1.0,
+          ambient_color: [0.1; 3].into(),
+          draw_flags: MaterialFlags::default(),
+          edge_color: [0.0; 4].into(),
+          edge_scale: 0.0,
+          texture_index: None,
+          environment_index: None,
+          environment_blend_mode: EnvironmentBlendMode::Disabled,
+          toon: Toon::Internal(3),
+          metadata: String::new(),
+          surface_count: 3,
+        },
+      ],
+      bones: vec![
+        Bone {
+          connection: tail,
+          ..bone("センター", None, [0.0; 3])
+        },
+        Bone {
+          connection: tail,
+          ..bone("左腕", Some(0), [0.0, 1.0, 0.0])
+        },
+        ik,
+        twist,
+      ],
+      morphs: vec![
+        morph(
+          "まばたき",
+          Panel::Eyes,
+          Offsets::Vertex(vec![VertexOffset {
+            vertex: 4,
+            offset: [0.0, -0.1, 0.0].into(),
+          }]),
+        ),
+        morph(
+          "グループ",
+          Panel::Other,
+          Offsets::Group(vec![GroupOffset {
+            morph: 0,
+            influence: 0.5,
+          }]),
+        ),
+        morph(
+          "腕",
+          Panel::Hidden,
+          Offsets::Bone(vec![BoneOffset {
+            bone: 1,
+            translation: [0.0; 3].into(),
+            rotation: [0.0, 0.0, 0.0, 1.0].into(),
+          }]),
+        ),
+        morph("UV", Panel::Unknown(7), Offsets::UV(uv())),
+        morph("UV1", Panel::Mouth, Offsets::AdditionalUV1(uv())),
+        morph("UV2", Panel::Mouth, Offsets::AdditionalUV2(uv())),
+        morph("UV3", Panel::Mouth, Offsets::AdditionalUV3(uv())),
+        morph("UV4", Panel::Mouth, Offsets::AdditionalUV4(uv())),
+        morph(
+          "材質",
+          Panel::Eyebrows,
+          Offsets::Material(vec![MaterialOffset {
+            material: None,
+            method: OffsetMethod::Additive,
+            diffuse_color: [0.1; 4].into(),
+            specular_color: [0.2; 3].into(),
+            specular_strength: 0.3,
+            ambient_color: [0.4; 3].into(),
+            edge_color: [0.5; 4].into(),
+            edge_scale: 0.6,
+            texture_tint: [0.7; 4].into(),
+            environment_tint: [0.8; 4].into(),
+            toon_tint: [0.9; 4].into(),
+          }]),
+        ),
+        morph(
+          "フリップ",
+          Panel::Other,
+          Offsets::Flip(vec![GroupOffset {
+            morph: 1,
+            influence: 1.0,
+          }]),
+        ),
+        morph(
+          "インパルス",
+          Panel::Other,
+          Offsets::Impulse(vec![ImpulseOffset {
+            rigid_body: 0,
+            local: true,
+            velocity: [0.0, 1.0, 0.0].into(),
+            torque: [0.0; 3].into(),
+          }]),
+        ),
+      ],
+      display_frames: vec![
+        DisplayFrame {
+          name: LocalizedName {
+            ja: "Root".to_string(),
+            en: "Root".to_string(),
+          },
+          special_flag: true,
+          elements: vec![DisplayElement::Bone(0)],
+        },
+        DisplayFrame {
+          name: LocalizedName {
+            ja: "表情".to_string(),
+            en: "Exp".to_string(),
+          },
+          special_flag: true,
+          elements: vec![DisplayElement::Morph(0), DisplayElement::Morph(1)],
+        },
+      ],
+      rigid_bodies: vec![RigidBody {
+        name: LocalizedName {
+          ja: "頭".to_string(),
+          en: "head".to_string(),
+        },
+        bone_index: Some(1),
+        group_id: 2,
+        non_collision_mask: 0xfffd,
+        shape: ShapeType::Capsule,
+        shape_size: [1.0, 2.0, 0.0].into(),
+        shape_position: [0.0, 10.0, 0.0].into(),
+        shape_rotation: [0.0, 0.0, 1.57].into(),
+        mass: 1.0,
+        move_attenuation: 0.5,
+        rotation_damping: 0.5,
+        repulsion: 0.0,
+        friction: 0.5,
+        physics_mode: PhysicsMode::DynamicPivoted,
+      }],
+      joints: vec![Joint {
+        name: LocalizedName {
+          ja: "首".to_string(),
+          en: String::new(),
+        },
+        joint_type: JointType::SpringFree,
+        rigid_body_a: 0,
+        rigid_body_b: -1,
+        position: [0.0, 9.0, 0.0].into(),
+        rotation: [0.0; 3].into(),
+        position_min: [0.0; 3].into(),
+        position_max: [0.0; 3].into(),
+        rotation_min: [-0.5; 3].into(),
+        rotation_max: [0.5; 3].into(),
+        position_spring: [0.0; 3].into(),
+        rotation_spring: [10.0; 3].into(),
+      }],
+      soft_bodies: vec![],
+      skipped_sections: BitFlags::empty(),
+    }
+  }
+
+  fn soft_body() -> SoftBody<DefaultConfig> {
+    SoftBody {
+      name: LocalizedName {
+        ja: "スカート".to_string(),
+        en: "skirt".to_string(),
+      },
+      shape: SoftBodyShape::TriMesh,
+      material: 1,
+      group_id: 3,
+      non_collision_mask: 0xfff7,
+      flags: SoftBodyFlags::BLink | SoftBodyFlags::ClusterCreation,
+      link_distance: 2,
+      cluster_count: 4,
+      mass: 1.5,
+      collision_margin: 0.05,
+      aero_model: AeroModel::FTwoSided,
+      parameters: SoftBodyParameters {
+        damping: 0.1,
+        drag: 0.2,
+        velocity_iterations: 1,
+        cluster_iterations: 4,
+        volume_stiffness: 0.75,
+        ..Default::default()
+      },
+      anchors: vec![Anchor {
+        rigid_body: 0,
+        vertex: 2,
+        near_mode: true,
+      }],
+      pinned_vertices: vec![0, 1],
+    }
+  }
+
+  fn settings(text_encoding: TextEncoding, size: IndexSize) -> Settings {
+    Settings {
+      text_encoding,
+      additional_vec4_count: 0,
+      vertex_index_size: size,
+      texture_index_size: size,
+      material_index_size: size,
+      bone_index_size: size,
+      morph_index_size: size,
+      rigidbody_index_size: size,
+    }
+  }
+
+  fn write(pmx: &Pmx) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    pmx.write(&mut bytes)?;
+    Ok(bytes)
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let mixed = Settings {
+      additional_vec4_count: 2,
+      texture_index_size: IndexSize::I16,
+      rigidbody_index_size: IndexSize::I32,
+      ..settings(TextEncoding::UTF16LE, IndexSize::I8)
+    };
+    let all = [
+      mixed,
+      settings(TextEncoding::UTF8, IndexSize::I16),
+      Settings {
+        additional_vec4_count: 4,
+        ..settings(TextEncoding::UTF8, IndexSize::I32)
+      },
+    ];
+
+    for &settings in all.iter() {
+      let pmx = model(settings);
+      let bytes = write(&pmx).unwrap();
+      let read = Pmx::read(Cursor::new(&bytes)).unwrap();
+
+      assert_eq!(read, pmx);
+      assert_eq!(write(&read).unwrap(), bytes);
+    }
+  }
+
+  #[test]
+  fn test_shrink_indices() {
+    let mut pmx = model(settings(TextEncoding::UTF8, IndexSize::I32));
+    let mut bytes = Vec::new();
+    let options = PmxWriteOptions {
+      shrink_indices: true,
+    };
+    pmx.write_with(&mut bytes, &options).unwrap();
+    assert!(bytes.len() < write(&pmx).unwrap().len());
+
+    let read = Pmx::read(Cursor::new(&bytes)).unwrap();
+    assert_eq!(read.settings, settings(TextEncoding::UTF8, IndexSize::I8));
+    pmx.settings = read.settings;
+    assert_eq!(read, pmx);
+
+    let fixture =
+      Pmx::<DefaultConfig>::read(&include_bytes!("../../fixtures/model.pmx")[..]).unwrap();
+    let mut bytes = Vec::new();
+    fixture.write_with(&mut bytes, &options).unwrap();
+    let mut read = Pmx::read(Cursor::new(&bytes)).unwrap();
+    read.settings = fixture.settings;
+    assert_eq!(read, fixture);
+  }
+
+  #[test]
+  fn test_smallest_index_size() {
+    assert_eq!(smallest_index_size(0, false), IndexSize::I8);
+    assert_eq!(smallest_index_size(128, false), IndexSize::I8);
+    assert_eq!(smallest_index_size(129, false), IndexSize::I16);
+    assert_eq!(smallest_index_size(256, true), IndexSize::I8);
+    assert_eq!(smallest_index_size(257, true), IndexSize::I16);
+    assert_eq!(smallest_index_size(32769, false), IndexSize::I32);
+    assert_eq!(smallest_index_size(65536, true), IndexSize::I16);
+    assert_eq!(smallest_index_size(65537, true), IndexSize::I32);
+  }
+
+  #[test]
+  fn test_write_overflow() {
+    let mut pmx = model(settings(TextEncoding::UTF8, IndexSize::I8));
+    // Vertex indices are unsigned
+    pmx.surfaces[1] = [200, 255, 0];
+    pmx.vertices.resize(256, pmx.vertices[0].clone());
+    assert!(write(&pmx).is_ok());
+
+    pmx.surfaces[1] = [256, 0, 0];
+    assert!(matches!(write(&pmx), Err(Error::IndexOverflow(256))));
+
+    let mut pmx = model(settings(TextEncoding::UTF8, IndexSize::I8));
+    pmx.bones[0].parent = Some(128);
+    assert!(matches!(write(&pmx), Err(Error::IndexOverflow(128))));
+    pmx.bones[0].parent = None;
+    pmx.display_frames[1]
+      .elements
+      .push(DisplayElement::Morph(-129));
+    assert!(matches!(write(&pmx), Err(Error::IndexOverflow(-129))));
+  }
+
+  #[test]
+  fn test_write_inconsistent() {
+    let mut pmx = model(settings(TextEncoding::UTF8, IndexSize::I16));
+    pmx.bones[3].fixed_axis = None;
+    assert!(matches!(
+      write(&pmx),
+      Err(Error::BoneFlagMismatch {
+        flag: BoneFlags::FixedAxis,
+        ..
+      })
+    ));
+
+    let mut pmx = model(settings(TextEncoding::UTF8, IndexSize::I16));
+    pmx.vertices[2].additional = vec![[0.0; 4].into()].into_iter().collect();
+    assert!(matches!(
+      write(&pmx),
+      Err(Error::CountMismatch {
+        declared: 0,
+        found: 1
+      })
+    ));
+  }
+
+  #[test]
+  fn test_round_trip_soft_bodies() {
+    let mut pmx = model(settings(TextEncoding::UTF8, IndexSize::I16));
+    pmx.version = 2.1;
+    pmx.soft_bodies.push(soft_body());
+    let bytes = write(&pmx).unwrap();
+    let read = Pmx::read(Cursor::new(&bytes)).unwrap();
+    assert_eq!(read, pmx);
+
+    // The section is only read for 2.1
+    let mut pmx = model(settings(TextEncoding::UTF8, IndexSize::I16));
+    let mut bytes = write(&pmx).unwrap();
+    bytes.extend_from_slice(&[0xff; 4]);
+    assert_eq!(Pmx::read(Cursor::new(&bytes)).unwrap(), pmx);
+
+    pmx.soft_bodies.push(soft_body());
+    assert!(matches!(
+      write(&pmx),
+      Err(Error::UnexpectedSoftBodies { count: 1, .. })
+    ));
+  }
+}
@@ -0,0 +1,44 @@
+//! `#[serde(with = "crate::serde_array")]` for byte arrays longer than the 32 elements serde
+//! implements its traits for, written as tuples like the shorter ones.
+
+use core::fmt;
+use serde::de::{Error, SeqAccess, Visitor};
+use serde::ser::SerializeTuple;
+use serde::{Deserializer, Serializer};
+
+pub fn serialize<S: Serializer, const N: usize>(
+  bytes: &[u8; N],
+  serializer: S,
+) -> Result<S::Ok, S::Error> {
+  let mut tuple = serializer.serialize_tuple(N)?;
+  for byte in bytes {
+    tuple.serialize_element(byte)?;
+  }
+  tuple.end()
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+  deserializer: D,
+) -> Result<[u8; N], D::Error> {
+  struct Bytes<const N: usize>;
+
+  impl<'de, const N: usize> Visitor<'de> for Bytes<N> {
+    type Value = [u8; N];
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "an array of {} bytes", N)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<[u8; N], A::Error> {
+      let mut bytes = [0; N];
+      for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = seq
+          .next_element()?
+          .ok_or_else(|| A::Error::invalid_length(i, &self))?;
+      }
+      Ok(bytes)
+    }
+  }
+
+  deserializer.deserialize_tuple(N, Bytes)
+}
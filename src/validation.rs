@@ -0,0 +1,225 @@
+//! Checks of poses and motions against the model they are applied to.
+
+use std::collections::{HashMap, HashSet};
+
+use enumflags2::BitFlags;
+
+use crate::math::{dot4, normalize4, to_array};
+use crate::pmx::bone::BoneFlags;
+use crate::pmx::morph::Morph;
+use crate::vpd::{NameMatching, Vpd};
+use crate::{AsSlice, Bone, Config, Float};
+
+/// Positions and rotation angles below this count as no transform.
+const TOLERANCE: f32 = 1e-5;
+
+/// A bone transformed by a pose in a way its model doesn't allow.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BoneWarning {
+  /// The pose rotates a bone without the `Rotatable` flag.
+  NotRotatable { name: String },
+  /// The pose translates a bone without the `Movable` flag.
+  NotMovable { name: String },
+}
+
+/// What doesn't fit between a pose and a model, see `validate_pose`.
+///
+/// Names are listed once each, in the order of the pose.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PoseValidation {
+  /// Bones of the pose the model has no bone for.
+  pub missing_bones: Vec<String>,
+  /// Morphs of the pose the model has no morph for.
+  pub missing_morphs: Vec<String>,
+  pub warnings: Vec<BoneWarning>,
+}
+
+impl PoseValidation {
+  /// Whether the whole pose applies to the model.
+  pub fn is_valid(&self) -> bool {
+    self.missing_bones.is_empty() && self.missing_morphs.is_empty() && self.warnings.is_empty()
+  }
+}
+
+/// Checks that the bones and morphs of `vpd` exist in a model and that its bones may be rotated
+/// and translated as the pose does.
+///
+/// Names are compared with the local (Japanese) names of the model, which poses use.
+pub fn validate_pose<C: Config>(
+  vpd: &Vpd<C>,
+  bones: &[Bone<C>],
+  morphs: &[Morph<C>],
+  matching: NameMatching,
+) -> PoseValidation {
+  let mut flags = HashMap::with_capacity(bones.len());
+  for bone in bones {
+    flags
+      .entry(matching.key(&bone.name.ja))
+      .or_insert(bone.bone_flags);
+  }
+  let morph_names: HashSet<_> = morphs.iter().map(|m| matching.key(&m.name.ja)).collect();
+
+  let mut validation = PoseValidation::default();
+  let mut seen = HashSet::new();
+  for bone in &vpd.bone_transforms {
+    if !seen.insert(bone.name.as_str()) {
+      continue;
+    }
+
+    let bone_flags: BitFlags<BoneFlags> = match flags.get(&matching.key(&bone.name)) {
+      Some(&bone_flags) => bone_flags,
+      None => {
+        validation.missing_bones.push(bone.name.clone());
+        continue;
+      }
+    };
+
+    let rotation = normalize4(to_array(&bone.rotation));
+    let rotated = 1.0 - dot4(rotation, [0.0, 0.0, 0.0, 1.0]).abs() > TOLERANCE;
+    if rotated && !bone_flags.contains(BoneFlags::Rotatable) {
+      validation.warnings.push(BoneWarning::NotRotatable {
+        name: bone.name.clone(),
+      });
+    }
+
+    let translated = bone
+      .position
+      .as_slice()
+      .iter()
+      .any(|c| c.to_f32().abs() > TOLERANCE);
+    if translated && !bone_flags.contains(BoneFlags::Movable) {
+      validation.warnings.push(BoneWarning::NotMovable {
+        name: bone.name.clone(),
+      });
+    }
+  }
+
+  let mut seen = HashSet::new();
+  for morph in &vpd.morph_values {
+    if seen.insert(morph.name.as_str()) && !morph_names.contains(&matching.key(&morph.name)) {
+      validation.missing_morphs.push(morph.name.clone());
+    }
+  }
+
+  validation
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::pmx::bone::tests::bone;
+  use crate::pmx::morph::{Offsets, Panel};
+  use crate::vpd::{BoneTransform, MorphValue};
+  use crate::DefaultConfig;
+  use crate::LocalizedName;
+
+  fn morph(name: &str) -> Morph<DefaultConfig> {
+    Morph {
+      name: LocalizedName {
+        ja: name.to_string(),
+        en: String::new(),
+      },
+      panel: Panel::Eyes,
+      offsets: Offsets::Vertex(vec![]),
+    }
+  }
+
+  fn transform(name: &str, position: [f32; 3], rotation: [f32; 4]) -> BoneTransform {
+    BoneTransform {
+      id: 0,
+      name: name.to_string(),
+      position: position.into(),
+      rotation: rotation.into(),
+    }
+  }
+
+  fn pose() -> Vpd {
+    let identity = [0.0, 0.0, 0.0, 1.0];
+    let turned = [0.0, 0.5f32.sin(), 0.0, 0.5f32.cos()];
+
+    let mut vpd = Vpd::new("pose".to_string());
+    vpd.bone_transforms = vec![
+      transform("センター", [0.0, 1.0, 0.0], turned),
+      transform("左足ＩＫ", [1.0, 0.0, 0.0], identity),
+      transform("首", [0.0, 0.1, 0.0], turned),
+      transform("尻尾", [0.0; 3], turned),
+      transform("尻尾", [0.0; 3], turned),
+      transform("頭", [0.0; 3], identity),
+    ];
+    vpd.morph_values = ["まばたき", "あ", "ウインク"]
+      .iter()
+      .map(|&name| MorphValue {
+        id: 0,
+        name: name.to_string(),
+        weight: 1.0,
+        offset: [0.0; 3].into(),
+      })
+      .collect();
+    vpd
+  }
+
+  fn model() -> (Vec<Bone<DefaultConfig>>, Vec<Morph<DefaultConfig>>) {
+    (
+      vec![
+        bone("センター", None, [0.0; 3]),
+        bone("左足IK", None, [0.0; 3]),
+        Bone {
+          bone_flags: BoneFlags::Rotatable.into(),
+          ..bone("首", None, [0.0; 3])
+        },
+        // Only rotated by the pose, so the missing Movable flag is fine
+        Bone {
+          bone_flags: BitFlags::empty(),
+          ..bone("頭", None, [0.0; 3])
+        },
+      ],
+      vec![morph("まばたき"), morph("あ")],
+    )
+  }
+
+  #[test]
+  fn test_validate_exact() {
+    let (bones, morphs) = model();
+
+    let validation = validate_pose(&pose(), &bones, &morphs, NameMatching::Exact);
+
+    assert_eq!(validation.missing_bones, ["左足ＩＫ", "尻尾"]);
+    assert_eq!(validation.missing_morphs, ["ウインク"]);
+    assert_eq!(
+      validation.warnings,
+      [BoneWarning::NotMovable {
+        name: "首".to_string()
+      }]
+    );
+    assert!(!validation.is_valid());
+  }
+
+  #[test]
+  fn test_validate_normalized() {
+    let (mut bones, mut morphs) = model();
+    bones.push(Bone {
+      bone_flags: BitFlags::empty(),
+      ..bone("尻尾", None, [0.0; 3])
+    });
+
+    let validation = validate_pose(&pose(), &bones, &morphs, NameMatching::Normalized);
+
+    assert!(validation.missing_bones.is_empty());
+    assert_eq!(
+      validation.warnings,
+      [
+        BoneWarning::NotMovable {
+          name: "首".to_string()
+        },
+        BoneWarning::NotRotatable {
+          name: "尻尾".to_string()
+        },
+      ]
+    );
+
+    morphs.push(morph("ウインク"));
+    bones[2].bone_flags.insert(BoneFlags::Movable);
+    bones[4].bone_flags.insert(BoneFlags::Rotatable);
+    assert!(validate_pose(&pose(), &bones, &morphs, NameMatching::Normalized).is_valid());
+  }
+}
@@ -0,0 +1,386 @@
+//! Authoring of camera motions from eye and target positions or camera bones.
+
+use std::ops::Range;
+
+use super::sampler::sample_camera;
+use super::{BezierCurve, BezierInterpolation, CameraFrame, CameraInterpolation, MotionFrame};
+use crate::math::{euler_yxz_from_quat, quat_from_euler_yxz, rotate3, to_array, Widen};
+use crate::{Config, DefaultConfig};
+
+impl<C: Config> CameraFrame<C> {
+  /// Position of the camera itself, `distance` away from the target along the view direction.
+  ///
+  /// The rotation is taken as Euler angles applied around Z, X and then Y, with a view along +Z
+  /// when all angles are 0. As `distance` is negative for cameras in front of their target, the
+  /// eye then sits at -Z from it.
+  pub fn eye(&self) -> C::Vec3 {
+    let offset = rotate3(
+      quat_from_euler_yxz(to_array(&self.rotation)),
+      [0.0, 0.0, self.distance],
+    );
+    let [x, y, z] = to_array(&self.position);
+    [x + offset[0], y + offset[1], z + offset[2]].widen()
+  }
+}
+
+/// Converts camera keyframes to keyframes of a camera bone, e.g. of a dummy camera model.
+///
+/// The bone sits at the eye of the camera, see `CameraFrame::eye`, rotated by the Euler angles of
+/// the camera. The position and rotation curves are kept, the distance and field of view are lost.
+pub fn camera_frames_to_bone_motion<C: Config>(
+  frames: &[CameraFrame<C>],
+  bone_name: &str,
+) -> Vec<MotionFrame<C>> {
+  frames
+    .iter()
+    .map(|frame| {
+      let curves = frame.interpolation_curves();
+      let mut bone = MotionFrame {
+        name: bone_name.to_string(),
+        raw_name: None,
+        frame_no: frame.frame_no,
+        position: frame.eye(),
+        rotation: quat_from_euler_yxz(to_array(&frame.rotation)).widen(),
+        interpolation: [0; 64],
+      };
+      bone.set_interpolation_curves(&BezierInterpolation {
+        x: curves.x,
+        y: curves.y,
+        z: curves.z,
+        rotation: curves.rotation,
+      });
+      bone
+    })
+    .collect()
+}
+
+/// Converts keyframes of a camera bone back to camera keyframes, see
+/// `camera_frames_to_bone_motion`.
+///
+/// The target is put `distance` away from the bone, negative for a target in front of it, and
+/// every keyframe gets the same `fov`. Euler angles are kept within `-π..=π`. The distance and
+/// field of view curves are linear.
+pub fn bone_motion_to_camera_frames<C: Config>(
+  frames: &[MotionFrame<C>],
+  fov: u32,
+  distance: f32,
+) -> Vec<CameraFrame<C>> {
+  frames
+    .iter()
+    .map(|bone| {
+      let rotation = to_array(&bone.rotation);
+      let offset = rotate3(rotation, [0.0, 0.0, distance]);
+      let [x, y, z] = to_array(&bone.position);
+      let curves = bone.interpolation_curves();
+
+      let mut frame = CameraFrame {
+        frame_no: bone.frame_no,
+        distance,
+        position: [x - offset[0], y - offset[1], z - offset[2]].widen(),
+        rotation: euler_yxz_from_quat(rotation).widen(),
+        interpolation: [0; 24],
+        fov,
+        orthographic: false,
+      };
+      frame.set_interpolation_curves(&CameraInterpolation {
+        x: curves.x,
+        y: curves.y,
+        z: curves.z,
+        rotation: curves.rotation,
+        distance: BezierCurve::LINEAR,
+        fov: BezierCurve::LINEAR,
+      });
+      frame
+    })
+    .collect()
+}
+
+/// Euler angles viewing along `direction`, without roll.
+///
+/// Views straight up or down have no defined yaw and get a yaw of 0.
+fn view_rotation(direction: [f32; 3]) -> [f32; 3] {
+  let [x, y, z] = direction;
+  let horizontal = (x * x + z * z).sqrt();
+  if horizontal == 0.0 && y == 0.0 {
+    return [0.0; 3];
+  }
+
+  // atan2(0, 0) is 0, so no NaN for vertical views
+  [(-y).atan2(horizontal), x.atan2(z), 0.0]
+}
+
+/// Builds camera motions from eye and target positions, orbits and cuts.
+///
+/// Keyframes use linear curves. A keyframe on the frame of an earlier one replaces it.
+pub struct CameraMotionBuilder<C: Config = DefaultConfig> {
+  frames: Vec<CameraFrame<C>>,
+}
+
+impl<C: Config> Default for CameraMotionBuilder<C> {
+  fn default() -> Self {
+    Self { frames: Vec::new() }
+  }
+}
+
+impl<C: Config> CameraMotionBuilder<C> {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  fn key(
+    mut self,
+    frame_no: u32,
+    target: [f32; 3],
+    distance: f32,
+    rotation: [f32; 3],
+    fov: u32,
+  ) -> Self {
+    let mut frame = CameraFrame {
+      frame_no,
+      distance,
+      position: target.widen(),
+      rotation: rotation.widen(),
+      interpolation: [0; 24],
+      fov,
+      orthographic: false,
+    };
+    frame.set_interpolation_curves(&CameraInterpolation::LINEAR);
+
+    let i = self.frames.partition_point(|f| f.frame_no < frame_no);
+    match self.frames.get_mut(i) {
+      Some(existing) if existing.frame_no == frame_no => *existing = frame,
+      _ => self.frames.insert(i, frame),
+    }
+    self
+  }
+
+  /// Keys the camera at `eye` looking at `target` with a vertical field of view of `fov` degrees.
+  pub fn look_at(self, frame_no: u32, eye: [f32; 3], target: [f32; 3], fov: u32) -> Self {
+    let direction = [target[0] - eye[0], target[1] - eye[1], target[2] - eye[2]];
+    let length = direction.iter().map(|c| c * c).sum::<f32>().sqrt();
+
+    self.key(frame_no, target, -length, view_rotation(direction), fov)
+  }
+
+  /// Circles around `center` at `radius` over `frames`, turning `degrees` around the Y axis.
+  ///
+  /// The orbit starts with the camera at -Z from `center`, looking at it. Euler angles are
+  /// interpolated linearly, so two keyframes describe the whole circle, even past 360 degrees.
+  /// The field of view of the camera at the start of the orbit is kept, or 30 degrees for the
+  /// first keyframe.
+  pub fn orbit(self, frames: Range<u32>, center: [f32; 3], radius: f32, degrees: f32) -> Self {
+    let fov = self.fov_at(frames.start);
+    let end = frames.end.max(frames.start);
+
+    self.key(frames.start, center, -radius, [0.0; 3], fov).key(
+      end,
+      center,
+      -radius,
+      [0.0, degrees.to_radians(), 0.0],
+      fov,
+    )
+  }
+
+  /// Cuts to `eye` looking at `target` on `frame_no`.
+  ///
+  /// The shot so far is held with a keyframe on the frame before, which MMD plays as a hard cut.
+  pub fn cut_to(self, frame_no: u32, eye: [f32; 3], target: [f32; 3], fov: u32) -> Self {
+    let held = match frame_no.checked_sub(1) {
+      Some(before) if matches!(self.frames.first(), Some(f) if f.frame_no <= before) => {
+        sample_camera(&self.frames, before as f32).map(|sample| (before, sample))
+      }
+      _ => None,
+    };
+
+    let builder = match held {
+      Some((before, sample)) => {
+        let rotation = to_array(&sample.rotation);
+        let target = to_array(&sample.position);
+        let fov = sample.fov.round() as u32;
+        self.key(before, target, sample.distance, rotation, fov)
+      }
+      None => self,
+    };
+
+    builder.look_at(frame_no, eye, target, fov)
+  }
+
+  fn fov_at(&self, frame_no: u32) -> u32 {
+    sample_camera(&self.frames, frame_no as f32).map_or(30, |sample| sample.fov.round() as u32)
+  }
+
+  /// Returns the keyframes, sorted by `frame_no`.
+  pub fn build(self) -> Vec<CameraFrame<C>> {
+    self.frames
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const EPSILON: f32 = 1e-4;
+
+  fn assert_near(a: &[f32], b: &[f32]) {
+    assert!(
+      a.iter().zip(b).all(|(a, b)| (a - b).abs() < EPSILON),
+      "{:?} != {:?}",
+      a,
+      b
+    );
+  }
+
+  #[test]
+  fn test_look_at() {
+    let eyes = [
+      [0.0, 10.0, -45.0],
+      [30.0, 5.0, 12.0],
+      [-3.0, 20.0, 1.0],
+      // Straight down and up
+      [0.0, 50.0, 0.0],
+      [0.0, -50.0, 0.0],
+    ];
+
+    for eye in eyes.iter() {
+      let frames = CameraMotionBuilder::<DefaultConfig>::new()
+        .look_at(0, *eye, [0.0, 10.0, 0.0], 30)
+        .build();
+
+      let frame = &frames[0];
+      assert!(to_array::<3>(&frame.rotation).iter().all(|c| c.is_finite()));
+      assert!(frame.distance <= 0.0);
+      assert_near(frame.eye().as_slice(), eye);
+      assert_eq!(frame.interpolation_curves(), CameraInterpolation::LINEAR);
+    }
+
+    // The default MMD camera
+    let frames = CameraMotionBuilder::<DefaultConfig>::new()
+      .look_at(0, [0.0, 10.0, -45.0], [0.0, 10.0, 0.0], 30)
+      .build();
+    assert_eq!(frames[0].distance, -45.0);
+    assert_near(frames[0].rotation.as_slice(), &[0.0; 3]);
+
+    // Looking at itself
+    let frames = CameraMotionBuilder::<DefaultConfig>::new()
+      .look_at(0, [1.0; 3], [1.0; 3], 30)
+      .build();
+    assert_eq!(frames[0].distance, 0.0);
+    assert_near(frames[0].rotation.as_slice(), &[0.0; 3]);
+  }
+
+  #[test]
+  fn test_orbit() {
+    let center = [0.0, 10.0, 0.0];
+    let frames = CameraMotionBuilder::<DefaultConfig>::new()
+      .orbit(0..120, center, 40.0, 720.0)
+      .build();
+
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].fov, 30);
+    for frame in (0..=120).step_by(15) {
+      let sample = sample_camera(&frames, frame as f32).unwrap();
+      let key = CameraFrame::<DefaultConfig> {
+        frame_no: frame,
+        distance: sample.distance,
+        position: sample.position,
+        rotation: sample.rotation,
+        interpolation: [0; 24],
+        fov: 30,
+        orthographic: false,
+      };
+      let angle = (frame as f32 / 120.0) * 4.0 * std::f32::consts::PI;
+
+      assert_near(
+        key.eye().as_slice(),
+        &[-40.0 * angle.sin(), 10.0, -40.0 * angle.cos()],
+      );
+    }
+  }
+
+  #[test]
+  fn test_cut_to() {
+    let shot = CameraMotionBuilder::<DefaultConfig>::new()
+      .look_at(0, [0.0, 10.0, -45.0], [0.0, 10.0, 0.0], 30)
+      .look_at(60, [10.0, 10.0, -45.0], [0.0, 10.0, 0.0], 40);
+    let held = sample_camera(&shot.frames, 29.0).unwrap();
+
+    let frames = shot
+      .cut_to(30, [0.0, 20.0, -10.0], [0.0, 15.0, 0.0], 20)
+      .build();
+
+    assert_eq!(
+      frames.iter().map(|f| f.frame_no).collect::<Vec<_>>(),
+      [0, 29, 30, 60]
+    );
+    // The camera holds where it was on its way to the frame 60 keyframe
+    assert_eq!(frames[1].distance, held.distance);
+    assert_eq!(frames[1].position, held.position);
+    assert_eq!(frames[1].rotation, held.rotation);
+    assert_eq!(frames[1].fov, 35);
+    assert_near(frames[2].eye().as_slice(), &[0.0, 20.0, -10.0]);
+    assert_eq!(frames[2].fov, 20);
+
+    // Nothing to hold for the first keyframe
+    let frames = CameraMotionBuilder::<DefaultConfig>::new()
+      .cut_to(10, [0.0, 10.0, -45.0], [0.0, 10.0, 0.0], 30)
+      .build();
+    assert_eq!(frames.len(), 1);
+  }
+
+  #[test]
+  fn test_camera_bone_round_trip_fixture() {
+    let vmd = crate::vmd::Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(include_bytes!(
+      "../../fixtures/camera.vmd"
+    )))
+    .unwrap();
+    let frames = &vmd.camera_frames;
+    assert!(frames.iter().all(|f| f.distance == -180.0 && f.fov == 30));
+
+    let bones = camera_frames_to_bone_motion(frames, "カメラ");
+    assert_eq!(bones.len(), frames.len());
+    assert_eq!(bones[0].name, "カメラ");
+    let round_trip = bone_motion_to_camera_frames(&bones, 30, -180.0);
+
+    for (frame, original) in round_trip.iter().zip(frames) {
+      assert_eq!(frame.frame_no, original.frame_no);
+      assert_eq!(frame.distance, original.distance);
+      assert_eq!(frame.fov, original.fov);
+      assert_near(frame.position.as_slice(), original.position.as_slice());
+      assert_near(frame.eye().as_slice(), original.eye().as_slice());
+      // Looking straight down, yaw and roll can trade places, so compare the orientations
+      let rotation = quat_from_euler_yxz(to_array(&frame.rotation));
+      let expected = quat_from_euler_yxz(to_array(&original.rotation));
+      let cos = crate::math::dot4(rotation, expected);
+      assert!((cos.abs() - 1.0).abs() < EPSILON);
+
+      let curves = original.interpolation_curves();
+      assert_eq!(frame.interpolation_curves().x, curves.x);
+      assert_eq!(frame.interpolation_curves().rotation, curves.rotation);
+    }
+  }
+
+  #[test]
+  fn test_camera_bone_round_trip() {
+    let frames = CameraMotionBuilder::<DefaultConfig>::new()
+      .look_at(0, [0.0, 10.0, -45.0], [0.0, 10.0, 0.0], 30)
+      .look_at(30, [20.0, 15.0, 10.0], [0.0, 10.0, 0.0], 30)
+      .look_at(60, [-5.0, 2.0, -8.0], [1.0, 12.0, 3.0], 30)
+      .build()
+      .into_iter()
+      .map(|mut frame| {
+        frame.distance = -20.0;
+        frame
+      })
+      .collect::<Vec<_>>();
+
+    let bones = camera_frames_to_bone_motion(&frames, "カメラ");
+    assert_near(bones[0].position.as_slice(), &[0.0, 10.0, -20.0]);
+    let round_trip = bone_motion_to_camera_frames(&bones, 30, -20.0);
+
+    for (frame, original) in round_trip.iter().zip(&frames) {
+      assert_near(frame.position.as_slice(), original.position.as_slice());
+      assert_near(frame.rotation.as_slice(), original.rotation.as_slice());
+      assert_eq!(frame.interpolation, original.interpolation);
+    }
+  }
+}
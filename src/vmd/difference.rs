@@ -0,0 +1,168 @@
+//! Differences between motions, e.g. to extract an additive layer.
+
+use std::ops::RangeInclusive;
+
+use super::keyframe::Keyframe;
+use super::track::{BoneTrackSet, MorphTrackSet};
+use super::{MorphFrame, Vmd};
+use crate::math::{normalize4, quat_mul, to_array, Widen};
+use crate::Config;
+
+impl<C: Config> Vmd<C> {
+  /// Subtracts `base` from the bone and morph keyframes, clamping morph weights to `-1.0..=1.0`.
+  ///
+  /// See `difference_with`.
+  pub fn difference(&self, base: &Vmd<C>) -> Vmd<C> {
+    self.difference_with(base, -1.0..=1.0)
+  }
+
+  /// Subtracts `base` from the bone and morph keyframes, clamping morph weights to `weights`.
+  ///
+  /// The result has a keyframe for every keyframe of `self`, with `base` sampled at its frame.
+  /// Positions are `self - base` and rotations `base⁻¹ * self`, so applying the result on top of
+  /// `base` gives `self` back on these frames. Bones and morphs missing in `base` are taken as is
+  /// and interpolation curves come from `self`. Other sections are left empty.
+  pub fn difference_with(&self, base: &Vmd<C>, weights: RangeInclusive<f32>) -> Vmd<C> {
+    let bones = BoneTrackSet::from_frames(
+      base
+        .motion_frames
+        .iter()
+        .map(|f| f.with_frame_no(f.frame_no))
+        .collect(),
+    );
+    let morphs = MorphTrackSet::from_frames(base.morph_frames.clone());
+
+    let motion_frames = self
+      .motion_frames
+      .iter()
+      .map(|frame| {
+        let mut difference = frame.with_frame_no(frame.frame_no);
+        let sample = bones
+          .track(&frame.name)
+          .and_then(|track| track.sample(frame.frame_no as f32));
+
+        if let Some(sample) = sample {
+          let [x, y, z] = to_array(&frame.position);
+          let [bx, by, bz] = to_array(&sample.position);
+          let [qx, qy, qz, qw] = normalize4(to_array(&sample.rotation));
+          difference.position = [x - bx, y - by, z - bz].widen();
+          difference.rotation = quat_mul([-qx, -qy, -qz, qw], to_array(&frame.rotation)).widen();
+        }
+        difference
+      })
+      .collect();
+
+    let morph_frames = self
+      .morph_frames
+      .iter()
+      .map(|frame| {
+        let base = morphs
+          .weight_at(&frame.name, frame.frame_no as f32)
+          .unwrap_or(0.0);
+        MorphFrame {
+          weight: (frame.weight - base).clamp(*weights.start(), *weights.end()),
+          ..frame.clone()
+        }
+      })
+      .collect();
+
+    Vmd {
+      header: self.header.clone(),
+      motion_frames,
+      morph_frames,
+      ..Default::default()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::math::dot4;
+  use crate::vmd::MotionFrame;
+  use crate::DefaultConfig;
+
+  const EPSILON: f32 = 1e-4;
+
+  fn bone(name: &str, frame_no: u32, position: [f32; 3], angle: f32) -> MotionFrame {
+    let (sin, cos) = (angle * 0.5).sin_cos();
+    MotionFrame::new(name, frame_no, position, [sin, 0.0, 0.0, cos])
+  }
+
+  fn morph(name: &str, frame_no: u32, weight: f32) -> MorphFrame {
+    MorphFrame {
+      name: name.to_string(),
+      raw_name: None,
+      frame_no,
+      weight,
+    }
+  }
+
+  fn assert_identity(frame: &MotionFrame) {
+    assert!(to_array::<3>(&frame.position)
+      .iter()
+      .all(|c| c.abs() < EPSILON));
+    let rotation = to_array::<4>(&frame.rotation);
+    assert!((dot4(rotation, [0.0, 0.0, 0.0, 1.0]).abs() - 1.0).abs() < EPSILON);
+  }
+
+  #[test]
+  fn test_difference_self_fixture() {
+    let vmd = Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(include_bytes!(
+      "../../fixtures/motion.vmd"
+    )))
+    .unwrap();
+
+    let difference = vmd.difference(&vmd);
+
+    assert_eq!(difference.header.model_name, vmd.header.model_name);
+    assert_eq!(difference.motion_frames.len(), vmd.motion_frames.len());
+    difference.motion_frames.iter().for_each(assert_identity);
+    assert!(difference.morph_frames.iter().all(|f| f.weight == 0.0));
+    assert!(difference.property_frames.is_empty());
+  }
+
+  #[test]
+  fn test_difference_sampled() {
+    let vmd = Vmd::<DefaultConfig> {
+      motion_frames: vec![
+        bone("センター", 5, [1.0, 2.0, 3.0], 1.0),
+        bone("首", 0, [0.0; 3], 0.5),
+      ],
+      morph_frames: vec![morph("あ", 5, 0.2), morph("い", 0, 1.0)],
+      ..Default::default()
+    };
+    let base = Vmd::<DefaultConfig> {
+      motion_frames: vec![
+        bone("センター", 0, [0.0; 3], 0.0),
+        bone("センター", 10, [2.0, 0.0, 0.0], 0.8),
+      ],
+      morph_frames: vec![morph("あ", 0, 0.0), morph("あ", 10, 2.0)],
+      ..Default::default()
+    };
+
+    let difference = vmd.difference(&base);
+
+    // The base is sampled halfway between its keyframes
+    let center = &difference.motion_frames[0];
+    assert_eq!(center.frame_no, 5);
+    let position = to_array::<3>(&center.position);
+    assert!(position
+      .iter()
+      .zip(&[0.0, 2.0, 3.0])
+      .all(|(a, b)| (a - b).abs() < EPSILON));
+    let rotation = to_array::<4>(&center.rotation);
+    // The base is at 0.4 radians around X, so 0.6 are left
+    assert!((rotation[0] - 0.3f32.sin()).abs() < EPSILON);
+    assert!((rotation[3] - 0.3f32.cos()).abs() < EPSILON);
+
+    // Bones missing in the base are kept as is
+    assert_eq!(difference.motion_frames[1], vmd.motion_frames[1]);
+
+    assert!((difference.morph_frames[0].weight + 0.8).abs() < EPSILON);
+    assert_eq!(difference.morph_frames[1].weight, 1.0);
+
+    let clamped = vmd.difference_with(&base, 0.0..=1.0);
+    assert_eq!(clamped.morph_frames[0].weight, 0.0);
+  }
+}
@@ -0,0 +1,258 @@
+//! Editing single keyframes of a loaded motion.
+
+use super::keyframe::Keyframe;
+use super::sampler::sample_bone;
+use super::{BezierInterpolation, MotionFrame, Vmd};
+use crate::Config;
+
+/// How `Vmd::insert_key` treats the animation around the new keyframe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InsertMode {
+  /// Splits the curves of the segment the key falls into so the animation stays as it was,
+  /// up to the precision of the stored curves. The given position and rotation are ignored
+  /// in favour of the sampled ones, except when there is no segment to split.
+  Preserve,
+  /// Sets the given position and rotation with linear curves.
+  Overwrite,
+}
+
+impl<C: Config> Vmd<C> {
+  /// Inserts a keyframe of `bone`, replacing the one at the same frame number.
+  ///
+  /// The keyframe is put right after the previous keyframe of the bone, so motions sorted by
+  /// frame number stay sorted. With `InsertMode::Preserve` a replaced keyframe keeps its curves.
+  pub fn insert_key(
+    &mut self,
+    bone: &str,
+    frame_no: u32,
+    position: impl Into<C::Vec3>,
+    rotation: impl Into<C::Vec4>,
+    mode: InsertMode,
+  ) {
+    let mut key = MotionFrame::new(bone, frame_no, position, rotation);
+
+    let existing = self
+      .motion_frames
+      .iter()
+      .position(|f| f.name == bone && f.frame_no == frame_no);
+    if let Some(i) = existing {
+      if mode == InsertMode::Preserve {
+        key.interpolation = self.motion_frames[i].interpolation;
+      }
+      self.motion_frames[i] = key;
+      return;
+    }
+
+    let frames = || {
+      self
+        .motion_frames
+        .iter()
+        .enumerate()
+        .filter(|(_, f)| f.name == bone)
+    };
+    let prev = frames()
+      .filter(|(_, f)| f.frame_no < frame_no)
+      .max_by_key(|(_, f)| f.frame_no)
+      .map(|(i, _)| i);
+    let next = frames()
+      .filter(|(_, f)| f.frame_no > frame_no)
+      .min_by_key(|(_, f)| f.frame_no)
+      .map(|(i, _)| i);
+
+    if let (InsertMode::Preserve, Some(p), Some(n)) = (mode, prev, next) {
+      let copy = |i: usize| {
+        let frame = &self.motion_frames[i];
+        frame.with_frame_no(frame.frame_no)
+      };
+      let segment = [copy(p), copy(n)];
+      let sample = sample_bone(&segment, frame_no as f32).unwrap();
+      key.position = sample.position;
+      key.rotation = sample.rotation;
+
+      let [prev, next] = &segment;
+      let t = (frame_no - prev.frame_no) as f32 / (next.frame_no - prev.frame_no) as f32;
+      let curves = next.interpolation_curves();
+      let (x, x_rest) = curves.x.split(t);
+      let (y, y_rest) = curves.y.split(t);
+      let (z, z_rest) = curves.z.split(t);
+      let (rotation, rotation_rest) = curves.rotation.split(t);
+
+      key.set_interpolation_curves(&BezierInterpolation { x, y, z, rotation });
+      self.motion_frames[n].set_interpolation_curves(&BezierInterpolation {
+        x: x_rest,
+        y: y_rest,
+        z: z_rest,
+        rotation: rotation_rest,
+      });
+    }
+
+    let index = match (prev, next) {
+      (Some(p), _) => p + 1,
+      (None, Some(n)) => n,
+      (None, None) => self.motion_frames.len(),
+    };
+    self.motion_frames.insert(index, key);
+  }
+
+  /// Removes the keyframe of `bone` at `frame_no`, returning it if there was one.
+  pub fn remove_key(&mut self, bone: &str, frame_no: u32) -> Option<MotionFrame<C>> {
+    let index = self
+      .motion_frames
+      .iter()
+      .position(|f| f.name == bone && f.frame_no == frame_no)?;
+
+    Some(self.motion_frames.remove(index))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::math::{dot4, to_array};
+  use crate::vmd::track::BoneTrackSet;
+  use crate::vmd::BezierCurve;
+
+  fn motion() -> Vmd {
+    let mut start = MotionFrame::new("センター", 0, [0.0; 3], [0.0, 0.0, 0.0, 1.0]);
+    start.set_interpolation_curves(&BezierInterpolation::ease_in_out());
+    let mut end = MotionFrame::new(
+      "センター",
+      30,
+      [10.0, -5.0, 2.0],
+      [0.0, 0.5f32.sin(), 0.0, 0.5f32.cos()],
+    );
+    end.set_interpolation_curves(
+      &BezierInterpolation::ease_in()
+        .with_y(BezierCurve::EASE_OUT)
+        .with_rotation(BezierCurve::EASE_IN_OUT),
+    );
+
+    Vmd {
+      motion_frames: vec![
+        start,
+        MotionFrame::new("首", 0, [0.0; 3], [0.0, 0.0, 0.0, 1.0]),
+        end,
+      ],
+      ..Default::default()
+    }
+  }
+
+  fn sample(vmd: &Vmd, frame: f32) -> ([f32; 3], [f32; 4]) {
+    let sample = BoneTrackSet::from_frames(vmd.motion_frames.clone())
+      .track("センター")
+      .unwrap()
+      .sample(frame)
+      .unwrap();
+
+    (to_array(&sample.position), to_array(&sample.rotation))
+  }
+
+  #[test]
+  fn test_insert_preserve() {
+    let original = motion();
+
+    for &frame_no in &[1, 7, 12, 29] {
+      let mut vmd = original.clone();
+      vmd.insert_key(
+        "センター",
+        frame_no,
+        [0.0; 3],
+        [0.0, 0.0, 0.0, 1.0],
+        InsertMode::Preserve,
+      );
+
+      assert_eq!(vmd.motion_frames.len(), 4);
+      assert_eq!(vmd.motion_frames[1].frame_no, frame_no);
+      for step in 0..=300 {
+        let frame = step as f32 * 0.1;
+        let (position, rotation) = sample(&original, frame);
+        let (edited, edited_rotation) = sample(&vmd, frame);
+        for (a, b) in position.iter().zip(&edited) {
+          assert!(
+            (a - b).abs() < 0.1,
+            "{} at {}: {} {}",
+            frame_no,
+            frame,
+            a,
+            b
+          );
+        }
+        assert!(dot4(rotation, edited_rotation).abs() > 0.9999);
+      }
+    }
+  }
+
+  #[test]
+  fn test_insert_overwrite_and_replace() {
+    let mut vmd = motion();
+
+    vmd.insert_key(
+      "センター",
+      10,
+      [1.0; 3],
+      [0.0, 0.0, 0.0, 1.0],
+      InsertMode::Overwrite,
+    );
+    let key = &vmd.motion_frames[1];
+    assert_eq!(to_array::<3>(&key.position), [1.0; 3]);
+    assert_eq!(key.interpolation_curves(), BezierInterpolation::LINEAR);
+    // The following key is left as is
+    assert_eq!(vmd.motion_frames[3], motion().motion_frames[2]);
+
+    vmd.insert_key(
+      "センター",
+      30,
+      [2.0; 3],
+      [0.0, 0.0, 0.0, 1.0],
+      InsertMode::Preserve,
+    );
+    assert_eq!(vmd.motion_frames.len(), 4);
+    assert_eq!(to_array::<3>(&vmd.motion_frames[3].position), [2.0; 3]);
+    assert_eq!(
+      vmd.motion_frames[3].interpolation,
+      motion().motion_frames[2].interpolation
+    );
+
+    // Nothing to split after the last key
+    vmd.insert_key(
+      "センター",
+      40,
+      [3.0; 3],
+      [0.0, 0.0, 0.0, 1.0],
+      InsertMode::Preserve,
+    );
+    assert_eq!(to_array::<3>(&vmd.motion_frames[4].position), [3.0; 3]);
+
+    vmd.insert_key(
+      "腕",
+      5,
+      [0.0; 3],
+      [0.0, 0.0, 0.0, 1.0],
+      InsertMode::Preserve,
+    );
+    assert_eq!(vmd.motion_frames[5].name, "腕");
+  }
+
+  #[test]
+  fn test_remove_key() {
+    let mut vmd = motion();
+
+    let removed = vmd.remove_key("センター", 30).unwrap();
+    assert_eq!(removed.frame_no, 30);
+    assert_eq!(vmd.motion_frames.len(), 2);
+    assert_eq!(vmd.remove_key("センター", 30), None);
+    assert_eq!(vmd.remove_key("腕", 0), None);
+  }
+
+  #[test]
+  fn test_split_curve() {
+    let (left, right) = BezierCurve::LINEAR.split(0.5);
+    for &x in &[0.0, 0.25, 0.5, 0.75, 1.0] {
+      assert!((left.evaluate(x) - x).abs() < 0.02);
+      assert!((right.evaluate(x) - x).abs() < 0.02);
+    }
+
+    let (_, rest) = BezierCurve::STEP.split(0.3);
+    assert!(rest.evaluate(0.5) < 0.2);
+  }
+}
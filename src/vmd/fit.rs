@@ -0,0 +1,277 @@
+//! Fitting of Bezier keyframes to animation sampled on every frame, e.g. converted from BVH.
+
+use super::reduce::{angle_between, distance};
+use super::sampler::sample_bone;
+use super::{BezierCurve, BezierInterpolation, MotionFrame};
+use crate::math::{dot4, to_array, Widen};
+use crate::Config;
+
+/// Candidate x control values tried for every curve.
+const CONTROL_STEPS: [u8; 9] = [0, 16, 32, 48, 64, 80, 96, 112, 127];
+
+/// A keyframe with linear curves, widened to the vectors of `C`.
+fn new_frame<C: Config>(
+  name: &str,
+  frame_no: u32,
+  position: [f32; 3],
+  rotation: [f32; 4],
+) -> MotionFrame<C> {
+  let position: C::Vec3 = position.widen();
+  let rotation: C::Vec4 = rotation.widen();
+  MotionFrame::new(name, frame_no, position, rotation)
+}
+
+/// Finds the curve through the `(x, progress)` points with the least squared error.
+fn fit_curve(points: &[(f32, f32)]) -> BezierCurve {
+  if points.is_empty() {
+    return BezierCurve::LINEAR;
+  }
+
+  let quantize = |v: f32| (v * 127.0).round().clamp(0.0, 127.0) as u8;
+  let mut best = (f32::INFINITY, BezierCurve::LINEAR);
+
+  for &x1 in &CONTROL_STEPS {
+    for &x2 in &CONTROL_STEPS {
+      let shape = BezierCurve {
+        x1,
+        y1: 0,
+        x2,
+        y2: 0,
+      };
+
+      // The progress is linear in y1 and y2 once the curve parameters are known
+      let (mut aa, mut ab, mut bb, mut ay, mut by) = (0.0, 0.0, 0.0, 0.0, 0.0);
+      for &(x, y) in points {
+        let t = shape.parameter_at(x);
+        let s = 1.0 - t;
+        let (a, b, rest) = (3.0 * s * s * t, 3.0 * s * t * t, t * t * t);
+        aa += a * a;
+        ab += a * b;
+        bb += b * b;
+        ay += a * (y - rest);
+        by += b * (y - rest);
+      }
+      let det = aa * bb - ab * ab;
+      let (y1, y2) = if det.abs() > f32::EPSILON {
+        ((ay * bb - by * ab) / det, (by * aa - ay * ab) / det)
+      } else {
+        (x1 as f32 / 127.0, x2 as f32 / 127.0)
+      };
+
+      let curve = BezierCurve {
+        y1: quantize(y1),
+        y2: quantize(y2),
+        ..shape
+      };
+      let error: f32 = points
+        .iter()
+        .map(|&(x, y)| (curve.evaluate(x) - y).powi(2))
+        .sum();
+      if error < best.0 {
+        best = (error, curve);
+      }
+    }
+  }
+
+  best.1
+}
+
+/// Fits the curves of the segment from `start` to `end` and returns the keyframe ending it.
+fn fit_segment<C: Config>(
+  name: &str,
+  samples: &[(u32, [f32; 3], [f32; 4])],
+  start: usize,
+  end: usize,
+) -> MotionFrame<C> {
+  let (first, last) = (&samples[start], &samples[end]);
+  let span = (last.0 - first.0) as f32;
+  let inner = &samples[start + 1..end];
+  let time = |frame_no: u32| (frame_no - first.0) as f32 / span;
+
+  let channel = |axis: usize| {
+    let range = last.1[axis] - first.1[axis];
+    if range.abs() <= f32::EPSILON {
+      return BezierCurve::LINEAR;
+    }
+    let points: Vec<_> = inner
+      .iter()
+      .map(|s| (time(s.0), (s.1[axis] - first.1[axis]) / range))
+      .collect();
+    fit_curve(&points)
+  };
+  let total_angle = angle_between(first.2, last.2);
+  let rotation = if total_angle <= f32::EPSILON {
+    BezierCurve::LINEAR
+  } else {
+    let points: Vec<_> = inner
+      .iter()
+      .map(|s| (time(s.0), angle_between(first.2, s.2) / total_angle))
+      .collect();
+    fit_curve(&points)
+  };
+
+  let mut frame = new_frame(name, last.0, last.1, last.2);
+  frame.set_interpolation_curves(&BezierInterpolation {
+    x: channel(0),
+    y: channel(1),
+    z: channel(2),
+    rotation,
+  });
+  frame
+}
+
+/// Fits sparse keyframes of the bone `name` to `(frame_no, position, rotation)` samples sorted by
+/// frame number without duplicates, typically one for every frame.
+///
+/// Segments are split at the sample with the largest error until the fitted curves reproduce
+/// every sample within the tolerances, given in model units and radians. Rotations are flipped
+/// into the hemisphere of the previous sample first, so the result never turns the long way
+/// around. The first keyframe has linear curves.
+pub fn fit_keyframes<C: Config>(
+  name: &str,
+  samples: &[(u32, C::Vec3, C::Vec4)],
+  position_tolerance: f32,
+  rotation_tolerance: f32,
+) -> Vec<MotionFrame<C>> {
+  let mut aligned: Vec<(u32, [f32; 3], [f32; 4])> = Vec::with_capacity(samples.len());
+  for (frame_no, position, rotation) in samples {
+    let mut rotation = to_array(rotation);
+    if let Some(previous) = aligned.last() {
+      if dot4(previous.2, rotation) < 0.0 {
+        rotation = [-rotation[0], -rotation[1], -rotation[2], -rotation[3]];
+      }
+    }
+    aligned.push((*frame_no, to_array(position), rotation));
+  }
+
+  let first = match aligned.first() {
+    Some(first) => new_frame(name, first.0, first.1, first.2),
+    None => return Vec::new(),
+  };
+  let mut frames = vec![first];
+
+  // Segments still to fit, the next one on top
+  let mut pending = vec![(0, aligned.len() - 1)];
+  while let Some((start, end)) = pending.pop() {
+    if start == end {
+      continue;
+    }
+    let key = fit_segment::<C>(name, &aligned, start, end);
+
+    let (first, _, _) = aligned[start];
+    let segment = [
+      new_frame(name, first, aligned[start].1, aligned[start].2),
+      key,
+    ];
+    let mut worst = (0.0f32, start);
+    for (i, sample) in aligned.iter().enumerate().take(end).skip(start + 1) {
+      let fitted = sample_bone(&segment, sample.0 as f32).unwrap();
+      let position_error = distance(to_array(&fitted.position), sample.1) / position_tolerance;
+      let rotation_error = angle_between(to_array(&fitted.rotation), sample.2) / rotation_tolerance;
+      let error = position_error.max(rotation_error);
+      if error > worst.0 {
+        worst = (error, i);
+      }
+    }
+
+    if worst.0 > 1.0 {
+      pending.push((worst.1, end));
+      pending.push((start, worst.1));
+    } else {
+      let [_, key] = segment;
+      frames.push(key);
+    }
+  }
+
+  frames
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::DefaultConfig;
+
+  type Sample = (u32, [f32; 3], [f32; 4]);
+
+  fn baked(frames: u32, f: impl Fn(f32) -> ([f32; 3], f32)) -> Vec<Sample> {
+    (0..frames)
+      .map(|frame_no| {
+        let (position, angle) = f(frame_no as f32);
+        let (sin, cos) = (angle / 2.0).sin_cos();
+        (frame_no, position, [0.0, sin, 0.0, cos])
+      })
+      .collect()
+  }
+
+  fn fit(samples: &[Sample], tolerance: f32) -> Vec<MotionFrame> {
+    let samples: Vec<_> = samples
+      .iter()
+      .map(|&(frame_no, position, rotation)| (frame_no, position.into(), rotation.into()))
+      .collect();
+    fit_keyframes::<DefaultConfig>("センター", &samples, tolerance, tolerance)
+  }
+
+  fn assert_within(frames: &[MotionFrame], samples: &[Sample], tolerance: f32) {
+    for &(frame_no, position, rotation) in samples {
+      let sample = sample_bone(frames, frame_no as f32).unwrap();
+      let position_error = distance(to_array(&sample.position), position);
+      let rotation_error = angle_between(to_array(&sample.rotation), rotation);
+      assert!(position_error <= tolerance, "{}", position_error);
+      assert!(rotation_error <= tolerance, "{}", rotation_error);
+    }
+  }
+
+  #[test]
+  fn test_fit_smooth() {
+    let samples = baked(121, |t| {
+      let wave = (t / 20.0).sin();
+      ([wave * 10.0, t * t / 1000.0, 0.0], wave * 0.8)
+    });
+
+    let frames = fit(&samples, 0.02);
+
+    assert!(frames.len() < samples.len() / 5, "{}", frames.len());
+    assert_eq!(frames.first().unwrap().frame_no, 0);
+    assert_eq!(frames.last().unwrap().frame_no, 120);
+    assert!(frames.windows(2).all(|w| w[0].frame_no < w[1].frame_no));
+    assert!(frames.iter().all(|f| f.name == "センター"));
+    for frame in &frames {
+      let curves = frame.interpolation_curves();
+      for curve in &[curves.x, curves.y, curves.z, curves.rotation] {
+        assert!([curve.x1, curve.y1, curve.x2, curve.y2]
+          .iter()
+          .all(|&c| c <= 127));
+      }
+    }
+    assert_within(&frames, &samples, 0.02);
+  }
+
+  #[test]
+  fn test_fit_eased() {
+    // A single eased segment needs no keyframes in between
+    let curve = BezierCurve::EASE_IN_OUT;
+    let samples = baked(31, |t| {
+      let progress = curve.evaluate(t / 30.0);
+      ([progress * 5.0, 0.0, -progress * 2.0], progress)
+    });
+
+    let frames = fit(&samples, 0.05);
+
+    assert_eq!(frames.len(), 2);
+    assert_within(&frames, &samples, 0.05);
+  }
+
+  #[test]
+  fn test_fit_hemisphere() {
+    let mut samples = baked(60, |t| ([0.0; 3], t / 30.0));
+    for sample in samples.iter_mut().skip(1).step_by(2) {
+      let [x, y, z, w] = sample.2;
+      sample.2 = [-x, -y, -z, -w];
+    }
+
+    let frames = fit(&samples, 0.01);
+
+    assert!(frames.len() <= 3, "{}", frames.len());
+    assert_within(&frames, &samples, 0.01);
+  }
+}
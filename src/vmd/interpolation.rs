@@ -0,0 +1,454 @@
+use super::{CameraFrame, MotionFrame};
+use crate::Config;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// A cubic Bezier easing curve from (0, 0) to (127, 127) with two control points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BezierCurve {
+  pub x1: u8,
+  pub y1: u8,
+  pub x2: u8,
+  pub y2: u8,
+}
+
+impl BezierCurve {
+  pub const LINEAR: BezierCurve = BezierCurve {
+    x1: 20,
+    y1: 20,
+    x2: 107,
+    y2: 107,
+  };
+
+  /// Starts slowly and ends at full speed.
+  pub const EASE_IN: BezierCurve = BezierCurve {
+    x1: 64,
+    y1: 0,
+    x2: 127,
+    y2: 127,
+  };
+
+  /// Starts at full speed and slows down towards the end.
+  pub const EASE_OUT: BezierCurve = BezierCurve {
+    x1: 0,
+    y1: 0,
+    x2: 64,
+    y2: 127,
+  };
+
+  /// Slow at both ends, the S-curve MMD writes for its smoothed segments.
+  pub const EASE_IN_OUT: BezierCurve = BezierCurve {
+    x1: 64,
+    y1: 0,
+    x2: 64,
+    y2: 127,
+  };
+
+  /// Stays close to the previous value until the very end, approximating a hold.
+  pub const STEP: BezierCurve = BezierCurve {
+    x1: 127,
+    y1: 0,
+    x2: 127,
+    y2: 0,
+  };
+}
+
+impl Default for BezierCurve {
+  fn default() -> Self {
+    Self::LINEAR
+  }
+}
+
+/// Per-channel curves of a motion keyframe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BezierInterpolation {
+  pub x: BezierCurve,
+  pub y: BezierCurve,
+  pub z: BezierCurve,
+  pub rotation: BezierCurve,
+}
+
+impl BezierInterpolation {
+  pub const LINEAR: BezierInterpolation = BezierInterpolation {
+    x: BezierCurve::LINEAR,
+    y: BezierCurve::LINEAR,
+    z: BezierCurve::LINEAR,
+    rotation: BezierCurve::LINEAR,
+  };
+
+  /// The same curve on every channel.
+  pub const fn uniform(curve: BezierCurve) -> Self {
+    Self {
+      x: curve,
+      y: curve,
+      z: curve,
+      rotation: curve,
+    }
+  }
+
+  pub const fn linear() -> Self {
+    Self::LINEAR
+  }
+
+  pub const fn ease_in() -> Self {
+    Self::uniform(BezierCurve::EASE_IN)
+  }
+
+  pub const fn ease_out() -> Self {
+    Self::uniform(BezierCurve::EASE_OUT)
+  }
+
+  pub const fn ease_in_out() -> Self {
+    Self::uniform(BezierCurve::EASE_IN_OUT)
+  }
+
+  pub const fn step() -> Self {
+    Self::uniform(BezierCurve::STEP)
+  }
+
+  pub const fn with_x(self, x: BezierCurve) -> Self {
+    Self { x, ..self }
+  }
+
+  pub const fn with_y(self, y: BezierCurve) -> Self {
+    Self { y, ..self }
+  }
+
+  pub const fn with_z(self, z: BezierCurve) -> Self {
+    Self { z, ..self }
+  }
+
+  /// Sets the X, Y and Z curves.
+  pub const fn with_position(self, curve: BezierCurve) -> Self {
+    Self {
+      x: curve,
+      y: curve,
+      z: curve,
+      ..self
+    }
+  }
+
+  pub const fn with_rotation(self, rotation: BezierCurve) -> Self {
+    Self { rotation, ..self }
+  }
+
+  fn curves(&self) -> [&BezierCurve; 4] {
+    [&self.x, &self.y, &self.z, &self.rotation]
+  }
+
+  /// The first row of the block: x1, y1, x2 and y2 of every channel in X, Y, Z, rotation order.
+  fn to_row(self) -> [u8; 16] {
+    let mut row = [0; 16];
+
+    for (i, curve) in self.curves().iter().enumerate() {
+      row[i] = curve.x1;
+      row[4 + i] = curve.y1;
+      row[8 + i] = curve.x2;
+      row[12 + i] = curve.y2;
+    }
+
+    row
+  }
+
+  fn from_row(row: &[u8; 16]) -> Self {
+    let curve = |i: usize| BezierCurve {
+      x1: row[i],
+      y1: row[4 + i],
+      x2: row[8 + i],
+      y2: row[12 + i],
+    };
+
+    Self {
+      x: curve(0),
+      y: curve(1),
+      z: curve(2),
+      rotation: curve(3),
+    }
+  }
+
+  /// Decodes the 64-byte block of a motion frame.
+  ///
+  /// The block consists of four rows, each one shifted one byte further to the left.
+  /// MMD also reuses the 3rd and 4th byte of the first row as physics flags, so the
+  /// Z and rotation `x1` controls are taken from the second row instead.
+  pub fn from_bytes(bytes: &[u8; 64]) -> Self {
+    let mut row = [0; 16];
+    row.copy_from_slice(&bytes[..16]);
+    row[2] = bytes[17];
+    row[3] = bytes[18];
+
+    Self::from_row(&row)
+  }
+
+  /// Encodes the curves into the canonical 64-byte block.
+  pub fn to_bytes(self) -> [u8; 64] {
+    let mut bytes = [0; 64];
+    // Trailing bytes of the shifted rows as written by MMD
+    bytes[31] = 1;
+    bytes[46] = 1;
+    bytes[61] = 1;
+    self.write_bytes(&mut bytes);
+
+    bytes
+  }
+
+  /// Writes the curves into an existing block, keeping the physics flags and the
+  /// trailing bytes of the shifted rows untouched.
+  pub fn write_bytes(self, bytes: &mut [u8; 64]) {
+    let prev = Self::from_bytes(bytes);
+    let physics_disabled = bytes[2] != prev.z.x1 || bytes[3] != prev.rotation.x1;
+    let flags = [bytes[2], bytes[3]];
+
+    let row = self.to_row();
+    for k in 0..4 {
+      bytes[16 * k..16 * k + 16 - k].copy_from_slice(&row[k..]);
+    }
+
+    if physics_disabled {
+      bytes[2..4].copy_from_slice(&flags);
+    }
+  }
+}
+
+/// Per-channel curves of a camera keyframe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CameraInterpolation {
+  pub x: BezierCurve,
+  pub y: BezierCurve,
+  pub z: BezierCurve,
+  pub rotation: BezierCurve,
+  pub distance: BezierCurve,
+  pub fov: BezierCurve,
+}
+
+impl CameraInterpolation {
+  pub const LINEAR: CameraInterpolation = CameraInterpolation {
+    x: BezierCurve::LINEAR,
+    y: BezierCurve::LINEAR,
+    z: BezierCurve::LINEAR,
+    rotation: BezierCurve::LINEAR,
+    distance: BezierCurve::LINEAR,
+    fov: BezierCurve::LINEAR,
+  };
+
+  /// Decodes the 24-byte block of a camera frame.
+  ///
+  /// Unlike motion frames every channel is stored contiguously as x1, x2, y1, y2.
+  pub fn from_bytes(bytes: &[u8; 24]) -> Self {
+    let curve = |i: usize| BezierCurve {
+      x1: bytes[4 * i],
+      x2: bytes[4 * i + 1],
+      y1: bytes[4 * i + 2],
+      y2: bytes[4 * i + 3],
+    };
+
+    Self {
+      x: curve(0),
+      y: curve(1),
+      z: curve(2),
+      rotation: curve(3),
+      distance: curve(4),
+      fov: curve(5),
+    }
+  }
+
+  pub fn to_bytes(self) -> [u8; 24] {
+    let mut bytes = [0; 24];
+    let curves = [
+      self.x,
+      self.y,
+      self.z,
+      self.rotation,
+      self.distance,
+      self.fov,
+    ];
+
+    for (i, curve) in curves.iter().enumerate() {
+      bytes[4 * i..4 * i + 4].copy_from_slice(&[curve.x1, curve.x2, curve.y1, curve.y2]);
+    }
+
+    bytes
+  }
+}
+
+impl<C: Config> CameraFrame<C> {
+  pub fn interpolation_curves(&self) -> CameraInterpolation {
+    CameraInterpolation::from_bytes(&self.interpolation)
+  }
+
+  pub fn set_interpolation_curves(&mut self, curves: &CameraInterpolation) {
+    self.interpolation = curves.to_bytes();
+  }
+}
+
+impl<C: Config> MotionFrame<C> {
+  pub fn interpolation_curves(&self) -> BezierInterpolation {
+    BezierInterpolation::from_bytes(&self.interpolation)
+  }
+
+  pub fn set_interpolation_curves(&mut self, curves: &BezierInterpolation) {
+    curves.write_bytes(&mut self.interpolation);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::DefaultConfig;
+
+  const FIXTURE_MOTION_VMD: &[u8] = include_bytes!("../../fixtures/motion.vmd");
+  const FIXTURE_ISSUE1_VMD: &[u8] = include_bytes!("../../fixtures/issue1.vmd");
+
+  fn util_test_round_trip(bytes: &[u8]) {
+    let mut cursor = crate::io::Cursor::new(bytes);
+    crate::vmd::VmdHeader::read(&mut cursor).unwrap();
+
+    for frame in MotionFrame::<DefaultConfig>::read_all(&mut cursor).unwrap() {
+      let mut encoded = frame.clone();
+      encoded.set_interpolation_curves(&frame.interpolation_curves());
+
+      assert_eq!(encoded.interpolation[..], frame.interpolation[..]);
+    }
+  }
+
+  #[test]
+  fn test_interpolation_round_trip_motion() {
+    util_test_round_trip(FIXTURE_MOTION_VMD);
+  }
+
+  #[test]
+  fn test_interpolation_round_trip_issue1() {
+    util_test_round_trip(FIXTURE_ISSUE1_VMD);
+  }
+
+  #[test]
+  fn test_interpolation_linear() {
+    let bytes = BezierInterpolation::LINEAR.to_bytes();
+
+    let mut cursor = crate::io::Cursor::new(FIXTURE_ISSUE1_VMD);
+    crate::vmd::VmdHeader::read(&mut cursor).unwrap();
+    let frame = MotionFrame::<DefaultConfig>::read_all(&mut cursor)
+      .unwrap()
+      .remove(0);
+
+    assert_eq!(bytes[..], frame.interpolation[..]);
+    assert_eq!(frame.interpolation_curves(), BezierInterpolation::LINEAR);
+  }
+
+  #[test]
+  fn test_interpolation_decode_physics_flags() {
+    let mut cursor = crate::io::Cursor::new(FIXTURE_MOTION_VMD);
+    crate::vmd::VmdHeader::read(&mut cursor).unwrap();
+    let frames = MotionFrame::<DefaultConfig>::read_all(&mut cursor).unwrap();
+
+    let curves = frames
+      .iter()
+      .map(MotionFrame::interpolation_curves)
+      .find(|c| *c != BezierInterpolation::LINEAR)
+      .unwrap();
+
+    assert_eq!(
+      curves.x,
+      BezierCurve {
+        x1: 64,
+        y1: 0,
+        x2: 64,
+        y2: 127
+      }
+    );
+    assert_eq!(
+      curves.z,
+      BezierCurve {
+        x1: 64,
+        y1: 0,
+        x2: 64,
+        y2: 127
+      }
+    );
+    assert_eq!(curves.rotation, BezierCurve::LINEAR);
+  }
+
+  #[test]
+  fn test_interpolation_set_keeps_physics_flags() {
+    let mut frame: MotionFrame = MotionFrame::new("", 0, [0.0; 3], [0.0, 0.0, 0.0, 1.0]);
+    frame.interpolation[2] = 99;
+    frame.interpolation[3] = 15;
+
+    let mut curves = BezierInterpolation::LINEAR;
+    curves.z.x1 = 40;
+    frame.set_interpolation_curves(&curves);
+
+    assert_eq!(frame.interpolation[2..4], [99, 15]);
+    assert_eq!(frame.interpolation_curves(), curves);
+  }
+
+  #[test]
+  fn test_camera_interpolation_round_trip() {
+    let mut curves = CameraInterpolation::LINEAR;
+    curves.fov = BezierCurve {
+      x1: 1,
+      y1: 2,
+      x2: 3,
+      y2: 4,
+    };
+
+    let bytes = curves.to_bytes();
+
+    assert_eq!(bytes[20..], [1, 3, 2, 4]);
+    assert_eq!(CameraInterpolation::from_bytes(&bytes), curves);
+    assert_eq!(
+      CameraInterpolation::LINEAR.to_bytes(),
+      [20, 107, 20, 107].repeat(6)[..]
+    );
+  }
+
+  #[test]
+  fn test_interpolation_presets() {
+    // The smoothed curve written by MMD in the fixture
+    let mut cursor = crate::io::Cursor::new(FIXTURE_MOTION_VMD);
+    crate::vmd::VmdHeader::read(&mut cursor).unwrap();
+    let frame = MotionFrame::<DefaultConfig>::read_all(&mut cursor)
+      .unwrap()
+      .into_iter()
+      .find(|f| f.interpolation_curves().x == BezierCurve::EASE_IN_OUT)
+      .unwrap();
+    let curves = BezierInterpolation::ease_in_out()
+      .with_y(frame.interpolation_curves().y)
+      .with_rotation(frame.interpolation_curves().rotation);
+    let mut bytes = frame.interpolation;
+    curves.write_bytes(&mut bytes);
+    assert_eq!(bytes[..], frame.interpolation[..]);
+
+    let bytes = BezierInterpolation::ease_in().to_bytes();
+    assert_eq!(
+      bytes[..16],
+      [64, 64, 64, 64, 0, 0, 0, 0, 127, 127, 127, 127, 127, 127, 127, 127]
+    );
+    assert_eq!(bytes[16..19], [64, 64, 64]);
+    assert_eq!(bytes[31], 1);
+
+    let bytes = BezierInterpolation::linear()
+      .with_x(BezierCurve::EASE_OUT)
+      .with_rotation(BezierCurve::STEP)
+      .to_bytes();
+    assert_eq!(
+      bytes[..16],
+      [0, 20, 20, 127, 0, 20, 20, 0, 64, 107, 107, 127, 127, 107, 107, 0]
+    );
+    assert_eq!(bytes[48..52], [127, 0, 20, 20]);
+  }
+
+  #[test]
+  fn test_motion_frame_new() {
+    let frame =
+      MotionFrame::<DefaultConfig>::new("センター", 10, [0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]);
+
+    assert_eq!(frame.name, "センター");
+    assert_eq!(frame.raw_name, None);
+    assert_eq!(frame.frame_no, 10);
+    assert_eq!(frame.interpolation, BezierInterpolation::LINEAR.to_bytes());
+  }
+}
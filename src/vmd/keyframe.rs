@@ -0,0 +1,276 @@
+//! Operations shared by all keyframe sections.
+
+use super::{CameraFrame, LightFrame, MorphFrame, MotionFrame, PropertyFrame, ShadowFrame, Vmd};
+use crate::Config;
+
+pub(crate) trait Keyframe: Sized {
+  /// Name of the bone or morph, empty for sections without one.
+  fn track(&self) -> &str;
+  fn frame_no(&self) -> u32;
+  /// Copies the keyframe, `Clone` of the generic frames would require `C: Clone`.
+  fn with_frame_no(&self, frame_no: u32) -> Self;
+  /// Compares everything but the raw name bytes, `PartialEq` of the generic frames would
+  /// require `C: PartialEq`.
+  fn same_as(&self, other: &Self) -> bool;
+}
+
+impl<C: Config> Keyframe for MotionFrame<C> {
+  fn track(&self) -> &str {
+    &self.name
+  }
+
+  fn frame_no(&self) -> u32 {
+    self.frame_no
+  }
+
+  fn with_frame_no(&self, frame_no: u32) -> Self {
+    Self {
+      name: self.name.clone(),
+      raw_name: self.raw_name,
+      frame_no,
+      position: self.position.clone(),
+      rotation: self.rotation.clone(),
+      interpolation: self.interpolation,
+    }
+  }
+
+  fn same_as(&self, other: &Self) -> bool {
+    self.name == other.name
+      && self.frame_no == other.frame_no
+      && self.position == other.position
+      && self.rotation == other.rotation
+      && self.interpolation[..] == other.interpolation[..]
+  }
+}
+
+impl Keyframe for MorphFrame {
+  fn track(&self) -> &str {
+    &self.name
+  }
+
+  fn frame_no(&self) -> u32 {
+    self.frame_no
+  }
+
+  fn with_frame_no(&self, frame_no: u32) -> Self {
+    Self {
+      frame_no,
+      ..self.clone()
+    }
+  }
+
+  fn same_as(&self, other: &Self) -> bool {
+    self.name == other.name && self.frame_no == other.frame_no && self.weight == other.weight
+  }
+}
+
+impl<C: Config> Keyframe for CameraFrame<C> {
+  fn track(&self) -> &str {
+    ""
+  }
+
+  fn frame_no(&self) -> u32 {
+    self.frame_no
+  }
+
+  fn with_frame_no(&self, frame_no: u32) -> Self {
+    Self {
+      frame_no,
+      distance: self.distance,
+      position: self.position.clone(),
+      rotation: self.rotation.clone(),
+      interpolation: self.interpolation,
+      fov: self.fov,
+      orthographic: self.orthographic,
+    }
+  }
+
+  fn same_as(&self, other: &Self) -> bool {
+    self.frame_no == other.frame_no
+      && self.distance == other.distance
+      && self.position == other.position
+      && self.rotation == other.rotation
+      && self.interpolation == other.interpolation
+      && self.fov == other.fov
+      && self.orthographic == other.orthographic
+  }
+}
+
+impl<C: Config> Keyframe for LightFrame<C> {
+  fn track(&self) -> &str {
+    ""
+  }
+
+  fn frame_no(&self) -> u32 {
+    self.frame_no
+  }
+
+  fn with_frame_no(&self, frame_no: u32) -> Self {
+    Self {
+      frame_no,
+      color: self.color.clone(),
+      direction: self.direction.clone(),
+    }
+  }
+
+  fn same_as(&self, other: &Self) -> bool {
+    self.frame_no == other.frame_no
+      && self.color == other.color
+      && self.direction == other.direction
+  }
+}
+
+impl Keyframe for ShadowFrame {
+  fn track(&self) -> &str {
+    ""
+  }
+
+  fn frame_no(&self) -> u32 {
+    self.frame_no
+  }
+
+  fn with_frame_no(&self, frame_no: u32) -> Self {
+    Self { frame_no, ..*self }
+  }
+
+  fn same_as(&self, other: &Self) -> bool {
+    self == other
+  }
+}
+
+impl Keyframe for PropertyFrame {
+  fn track(&self) -> &str {
+    ""
+  }
+
+  fn frame_no(&self) -> u32 {
+    self.frame_no
+  }
+
+  fn with_frame_no(&self, frame_no: u32) -> Self {
+    Self {
+      frame_no,
+      ..self.clone()
+    }
+  }
+
+  fn same_as(&self, other: &Self) -> bool {
+    self == other
+  }
+}
+
+/// Keyframes dropped by `Vmd::normalize`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct NormalizeReport {
+  /// Keyframes identical to the one kept.
+  pub duplicates: usize,
+  /// Keyframes on the same bone or morph and frame as a later one with a different value.
+  pub conflicts: usize,
+}
+
+/// Sorts by track and frame number, keeping the last one of keyframes sharing both.
+fn normalize_frames<T: Keyframe>(frames: &mut Vec<T>, report: &mut NormalizeReport) {
+  let mut sorted = std::mem::take(frames);
+  sorted.sort_by(|a, b| (a.track(), a.frame_no()).cmp(&(b.track(), b.frame_no())));
+
+  let mut run: Vec<T> = Vec::new();
+  for frame in sorted {
+    if let Some(last) = run.last() {
+      if (last.track(), last.frame_no()) != (frame.track(), frame.frame_no()) {
+        flush_run(&mut run, frames, report);
+      }
+    }
+    run.push(frame);
+  }
+  flush_run(&mut run, frames, report);
+}
+
+fn flush_run<T: Keyframe>(run: &mut Vec<T>, frames: &mut Vec<T>, report: &mut NormalizeReport) {
+  let kept = match run.pop() {
+    Some(kept) => kept,
+    None => return,
+  };
+
+  for dropped in run.drain(..) {
+    if dropped.same_as(&kept) {
+      report.duplicates += 1;
+    } else {
+      report.conflicts += 1;
+    }
+  }
+  frames.push(kept);
+}
+
+impl<C: Config> Vmd<C> {
+  /// Sorts bone and morph keyframes by name and frame number, and camera, light and shadow
+  /// keyframes by frame number, keeping the last one of keyframes sharing both.
+  pub fn normalize(&mut self) -> NormalizeReport {
+    let mut report = NormalizeReport::default();
+
+    normalize_frames(&mut self.motion_frames, &mut report);
+    normalize_frames(&mut self.morph_frames, &mut report);
+    normalize_frames(&mut self.camera_frames, &mut report);
+    normalize_frames(&mut self.light_frames, &mut report);
+    normalize_frames(&mut self.shadow_frames, &mut report);
+
+    report
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::vmd::tests::bone_frame;
+  use crate::vmd::ShadowMode;
+  use crate::DefaultConfig;
+
+  fn shadow(frame_no: u32, distance: f32) -> ShadowFrame {
+    ShadowFrame {
+      frame_no,
+      mode: ShadowMode::Mode1,
+      distance,
+    }
+  }
+
+  #[test]
+  fn test_normalize() {
+    let mut vmd = Vmd::<DefaultConfig> {
+      motion_frames: vec![
+        bone_frame("頭", 10, 1.0),
+        bone_frame("センター", 5, 2.0),
+        bone_frame("頭", 0, 3.0),
+        bone_frame("センター", 5, 2.0),
+        bone_frame("頭", 10, 4.0),
+        bone_frame("センター", 0, 5.0),
+      ],
+      shadow_frames: vec![shadow(3, 0.1), shadow(1, 0.2), shadow(3, 0.3)],
+      ..Default::default()
+    };
+
+    let report = vmd.normalize();
+
+    assert_eq!(
+      report,
+      NormalizeReport {
+        duplicates: 1,
+        conflicts: 2,
+      }
+    );
+    let keys = vmd
+      .motion_frames
+      .iter()
+      .map(|f| (f.name.as_str(), f.frame_no, f.position[0]))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      keys,
+      [
+        ("センター", 0, 5.0),
+        ("センター", 5, 2.0),
+        ("頭", 0, 3.0),
+        ("頭", 10, 4.0)
+      ]
+    );
+    assert_eq!(vmd.shadow_frames, [shadow(1, 0.2), shadow(3, 0.3)]);
+    assert_eq!(vmd.normalize(), NormalizeReport::default());
+  }
+}
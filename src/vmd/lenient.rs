@@ -0,0 +1,337 @@
+//! Best-effort reading that reports anomalies instead of failing on them.
+
+use crate::io::{Cursor, ErrorKind, Read};
+use alloc::{
+  string::{String, ToString},
+  vec::Vec,
+};
+use std::collections::HashMap;
+
+use encoding_rs::SHIFT_JIS;
+
+use super::keyframe::Keyframe;
+use super::{
+  read_section_count, DecodeMode, MorphFrame, MotionFrame, Vmd, VmdHeader, MAX_RESERVED_FRAMES,
+};
+use crate::math::{dot4, to_array};
+use crate::Config;
+
+/// Rotations whose norm is further than this from 1 are reported as denormalized.
+const ROTATION_TOLERANCE: f32 = 1e-3;
+
+/// Something odd found by `Vmd::read_lenient`.
+///
+/// Indices count the records of the section, starting at 0.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+  /// A name is not valid Shift_JIS and was decoded as UTF-8 or with replacement characters. The
+  /// index is `None` for the model name. IK names are only caught with replacement characters,
+  /// as their bytes are not kept.
+  MalformedName {
+    section: &'static str,
+    index: Option<usize>,
+    name: String,
+  },
+  /// A bone rotation is not a unit quaternion.
+  DenormalizedRotation {
+    index: usize,
+    name: String,
+    frame_no: u32,
+    norm: f32,
+  },
+  /// The input ended before all frames of the section, the ones read are kept.
+  TruncatedSection {
+    section: &'static str,
+    expected: u32,
+    read: u32,
+  },
+  /// Bytes after the last section that parsed, including sections that don't fit in them.
+  TrailingBytes { bytes: usize },
+  /// A keyframe comes before an earlier keyframe of the same bone or morph in the file.
+  OutOfOrder {
+    section: &'static str,
+    index: usize,
+    name: String,
+    frame_no: u32,
+    previous: u32,
+  },
+}
+
+/// Reads a section, keeping the frames read before the input ends. Returns `None` if the stream
+/// ends right before the section and whether the section is complete otherwise.
+fn read_partial_section<R: Read, T>(
+  read: &mut R,
+  section: &'static str,
+  read_frame: impl Fn(&mut R) -> crate::Result<T>,
+  warnings: &mut Vec<Warning>,
+) -> crate::Result<Option<(Vec<T>, bool)>> {
+  let total_frames = match read_section_count(read) {
+    Ok(Some(total_frames)) => total_frames,
+    Ok(None) => return Ok(None),
+    Err(crate::Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+    Err(e) => return Err(e),
+  };
+
+  let mut frames = Vec::with_capacity(total_frames.min(MAX_RESERVED_FRAMES) as usize);
+  for _ in 0..total_frames {
+    match read_frame(read) {
+      Ok(frame) => frames.push(frame),
+      Err(crate::Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+        warnings.push(Warning::TruncatedSection {
+          section,
+          expected: total_frames,
+          read: frames.len() as u32,
+        });
+        return Ok(Some((frames, false)));
+      }
+      Err(e) => return Err(e),
+    }
+  }
+
+  Ok(Some((frames, true)))
+}
+
+fn is_malformed(raw: &[u8]) -> bool {
+  let raw = &raw[..raw.iter().position(|&x| x == 0).unwrap_or(raw.len())];
+  SHIFT_JIS.decode_without_bom_handling(raw).1
+}
+
+fn check_order<'a, T: Keyframe + 'a>(
+  section: &'static str,
+  frames: impl Iterator<Item = &'a T>,
+  warnings: &mut Vec<Warning>,
+) {
+  let mut latest: HashMap<&str, u32> = HashMap::new();
+
+  for (index, frame) in frames.enumerate() {
+    let previous = latest.entry(frame.track()).or_insert(frame.frame_no());
+    if frame.frame_no() < *previous {
+      warnings.push(Warning::OutOfOrder {
+        section,
+        index,
+        name: frame.track().to_string(),
+        frame_no: frame.frame_no(),
+        previous: *previous,
+      });
+    } else {
+      *previous = frame.frame_no();
+    }
+  }
+}
+
+impl<C: Config> Vmd<C> {
+  /// Reads as much of a motion as possible, collecting what was odd about it.
+  ///
+  /// Unlike `read`, sections cut short by the end of the input keep the frames read so far. Only
+  /// a broken header or I/O errors other than EOF fail.
+  pub fn read_lenient<R: Read>(read: &mut R) -> crate::Result<(Self, Vec<Warning>)> {
+    let mut warnings = Vec::new();
+    let mut vmd = Vmd {
+      header: VmdHeader::read(read)?,
+      ..Default::default()
+    };
+
+    let complete = match read_partial_section(read, "motion", MotionFrame::read, &mut warnings)? {
+      Some((frames, complete)) => {
+        vmd.motion_frames = frames;
+        complete
+      }
+      None => false,
+    };
+    let complete = complete
+      && match read_partial_section(read, "morph", MorphFrame::read, &mut warnings)? {
+        Some((frames, complete)) => {
+          vmd.morph_frames = frames;
+          complete
+        }
+        None => false,
+      };
+
+    if complete {
+      let mut tail = Vec::new();
+      read.read_to_end(&mut tail)?;
+      let mut tail = Cursor::new(&tail[..]);
+
+      vmd.read_tail(&mut tail, DecodeMode::Lossy)?;
+      vmd.trailing_bytes = tail.get_ref().len() - tail.position() as usize;
+      if vmd.trailing_bytes > 0 {
+        warnings.push(Warning::TrailingBytes {
+          bytes: vmd.trailing_bytes,
+        });
+      }
+    }
+
+    vmd.check(&mut warnings);
+
+    Ok((vmd, warnings))
+  }
+
+  /// Collects the warnings about the frames themselves.
+  fn check(&self, warnings: &mut Vec<Warning>) {
+    let header = &self.header;
+    if matches!(&header.raw_model_name, Some(raw) if is_malformed(raw)) {
+      warnings.push(Warning::MalformedName {
+        section: "header",
+        index: None,
+        name: header.model_name.clone(),
+      });
+    }
+
+    for (index, frame) in self.motion_frames.iter().enumerate() {
+      if matches!(&frame.raw_name, Some(raw) if is_malformed(raw)) {
+        warnings.push(Warning::MalformedName {
+          section: "motion",
+          index: Some(index),
+          name: frame.name.clone(),
+        });
+      }
+
+      let rotation = to_array(&frame.rotation);
+      let norm = dot4(rotation, rotation).sqrt();
+      if (norm - 1.0).abs() > ROTATION_TOLERANCE {
+        warnings.push(Warning::DenormalizedRotation {
+          index,
+          name: frame.name.clone(),
+          frame_no: frame.frame_no,
+          norm,
+        });
+      }
+    }
+
+    for (index, frame) in self.morph_frames.iter().enumerate() {
+      if matches!(&frame.raw_name, Some(raw) if is_malformed(raw)) {
+        warnings.push(Warning::MalformedName {
+          section: "morph",
+          index: Some(index),
+          name: frame.name.clone(),
+        });
+      }
+    }
+
+    for (index, frame) in self.property_frames.iter().enumerate() {
+      for (name, _) in &frame.ik_states {
+        if name.contains(char::REPLACEMENT_CHARACTER) {
+          warnings.push(Warning::MalformedName {
+            section: "property",
+            index: Some(index),
+            name: name.clone(),
+          });
+        }
+      }
+    }
+
+    check_order("motion", self.motion_frames.iter(), warnings);
+    check_order("morph", self.morph_frames.iter(), warnings);
+    check_order("camera", self.camera_frames.iter(), warnings);
+    check_order("light", self.light_frames.iter(), warnings);
+    check_order("shadow", self.shadow_frames.iter(), warnings);
+    check_order("property", self.property_frames.iter(), warnings);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::DefaultConfig;
+
+  const FIXTURE_MOTION_VMD: &[u8] = include_bytes!("../../fixtures/motion.vmd");
+  const FIXTURE_CAMERA_VMD: &[u8] = include_bytes!("../../fixtures/camera.vmd");
+  const FIXTURE_ISSUE1_VMD: &[u8] = include_bytes!("../../fixtures/issue1.vmd");
+
+  fn read_lenient(bytes: &[u8]) -> (Vmd, Vec<Warning>) {
+    Vmd::<DefaultConfig>::read_lenient(&mut Cursor::new(bytes)).unwrap()
+  }
+
+  #[test]
+  fn test_lenient_clean() {
+    for bytes in [FIXTURE_MOTION_VMD, FIXTURE_CAMERA_VMD].iter() {
+      let (vmd, warnings) = read_lenient(bytes);
+
+      assert_eq!(vmd, Vmd::read(&mut Cursor::new(bytes)).unwrap());
+      assert_eq!(warnings, []);
+    }
+  }
+
+  #[test]
+  fn test_lenient_truncated() {
+    let end = 50 + 4 + 164 * 111 + 4 + 10;
+    assert!(Vmd::<DefaultConfig>::read(&mut Cursor::new(&FIXTURE_MOTION_VMD[..end])).is_err());
+
+    let (vmd, warnings) = read_lenient(&FIXTURE_MOTION_VMD[..end]);
+
+    assert_eq!(vmd.motion_frames.len(), 164);
+    assert!(vmd.morph_frames.is_empty());
+    assert_eq!(
+      warnings,
+      [Warning::TruncatedSection {
+        section: "morph",
+        expected: 30,
+        read: 0,
+      }]
+    );
+  }
+
+  #[test]
+  fn test_lenient_issue1() {
+    let (vmd, warnings) = read_lenient(FIXTURE_ISSUE1_VMD);
+
+    assert_eq!(vmd.motion_frames.len(), 7);
+    assert_eq!(vmd.morph_frames.len(), 31);
+    // The data after the camera count is garbage
+    let bytes = Vmd::<DefaultConfig>::read(&mut Cursor::new(FIXTURE_ISSUE1_VMD))
+      .unwrap()
+      .trailing_bytes;
+    assert!(bytes > 0);
+    assert!(warnings.contains(&Warning::TrailingBytes { bytes }));
+  }
+
+  #[test]
+  fn test_lenient_frames() {
+    let mut vmd = Vmd::<DefaultConfig>::read(&mut Cursor::new(FIXTURE_MOTION_VMD)).unwrap();
+    vmd.motion_frames[3].rotation = [0.0, 0.0, 0.0, 2.0].into();
+    let center = vmd.motion_frames[0].name.clone();
+    let previous = vmd
+      .motion_frames
+      .iter()
+      .filter(|f| f.name == center)
+      .map(|f| f.frame_no)
+      .max()
+      .unwrap();
+    assert!(previous > 0);
+    let late = vmd.motion_frames[0].with_frame_no(0);
+    vmd.motion_frames.push(late);
+    // "à" in UTF-8, which is not valid Shift_JIS
+    let mut raw = [0; 15];
+    raw[..2].copy_from_slice(&[0xc3, 0xa0]);
+    vmd.morph_frames[0].name = "à".to_string();
+    vmd.morph_frames[0].raw_name = Some(raw);
+    let mut bytes = Vec::new();
+    vmd.write(&mut bytes).unwrap();
+
+    let (_, warnings) = read_lenient(&bytes);
+
+    assert_eq!(
+      warnings,
+      [
+        Warning::DenormalizedRotation {
+          index: 3,
+          name: vmd.motion_frames[3].name.clone(),
+          frame_no: vmd.motion_frames[3].frame_no,
+          norm: 2.0,
+        },
+        Warning::MalformedName {
+          section: "morph",
+          index: Some(0),
+          name: vmd.morph_frames[0].name.clone(),
+        },
+        Warning::OutOfOrder {
+          section: "motion",
+          index: 164,
+          name: center,
+          frame_no: 0,
+          previous,
+        },
+      ]
+    );
+  }
+}
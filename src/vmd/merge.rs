@@ -0,0 +1,221 @@
+//! Combining motions, e.g. a dance with a separately authored facial motion.
+
+use std::collections::HashSet;
+
+use super::keyframe::Keyframe;
+use super::Vmd;
+use crate::{Config, Error};
+
+/// What `Vmd::merge` does when both motions key the same bone or morph at the same frame.
+///
+/// Camera, light and shadow keyframes collide on the frame number alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MergePolicy {
+  KeepSelf,
+  KeepOther,
+  Error,
+}
+
+fn key<T: Keyframe>(frame: &T) -> (&str, u32) {
+  (frame.track(), frame.frame_no())
+}
+
+/// Merges a section into a new vector sorted by frame number.
+fn merge_frames<T: Keyframe>(
+  frames: &[T],
+  other: &[T],
+  frame_offset: u32,
+  policy: MergePolicy,
+) -> crate::Result<Vec<T>> {
+  let mut shifted = Vec::with_capacity(other.len());
+  for frame in other {
+    let frame_no = frame
+      .frame_no()
+      .checked_add(frame_offset)
+      .ok_or(Error::FrameOverflow {
+        frame_no: frame.frame_no(),
+        offset: frame_offset,
+      })?;
+    shifted.push(frame.with_frame_no(frame_no));
+  }
+
+  let own_keys = frames.iter().map(key).collect::<HashSet<_>>();
+  if policy == MergePolicy::Error {
+    if let Some((name, frame_no)) = shifted.iter().map(key).find(|k| own_keys.contains(k)) {
+      return Err(Error::MergeCollision {
+        name: name.to_string(),
+        frame_no,
+      });
+    }
+  }
+
+  let other_keys = shifted.iter().map(key).collect::<HashSet<_>>();
+  let mut merged = frames
+    .iter()
+    .filter(|f| policy != MergePolicy::KeepOther || !other_keys.contains(&key(*f)))
+    .map(|f| f.with_frame_no(f.frame_no()))
+    .collect::<Vec<_>>();
+
+  merged.extend(
+    shifted
+      .into_iter()
+      .filter(|f| policy != MergePolicy::KeepSelf || !own_keys.contains(&key(f))),
+  );
+  merged.sort_by_key(T::frame_no);
+
+  Ok(merged)
+}
+
+impl<C: Config> Vmd<C> {
+  /// Appends the bone, morph, camera, light and shadow keyframes of `other`, shifted by
+  /// `frame_offset` frames.
+  ///
+  /// Merged sections are sorted by frame number, keeping the relative order of keyframes on the
+  /// same frame. Property frames and the header of `self` are kept as is. On error `self` is
+  /// left unchanged.
+  pub fn merge(
+    &mut self,
+    other: &Vmd<C>,
+    frame_offset: u32,
+    policy: MergePolicy,
+  ) -> crate::Result<()> {
+    let motion_frames = merge_frames(
+      &self.motion_frames,
+      &other.motion_frames,
+      frame_offset,
+      policy,
+    )?;
+    let morph_frames = merge_frames(
+      &self.morph_frames,
+      &other.morph_frames,
+      frame_offset,
+      policy,
+    )?;
+    let camera_frames = merge_frames(
+      &self.camera_frames,
+      &other.camera_frames,
+      frame_offset,
+      policy,
+    )?;
+    let light_frames = merge_frames(
+      &self.light_frames,
+      &other.light_frames,
+      frame_offset,
+      policy,
+    )?;
+    let shadow_frames = merge_frames(
+      &self.shadow_frames,
+      &other.shadow_frames,
+      frame_offset,
+      policy,
+    )?;
+
+    self.motion_frames = motion_frames;
+    self.morph_frames = morph_frames;
+    self.camera_frames = camera_frames;
+    self.light_frames = light_frames;
+    self.shadow_frames = shadow_frames;
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::vmd::tests::bone_frame;
+  use crate::vmd::MorphFrame;
+  use crate::DefaultConfig;
+
+  fn morph(name: &str, frame_no: u32) -> MorphFrame {
+    MorphFrame {
+      name: name.to_string(),
+      raw_name: None,
+      frame_no,
+      weight: 1.0,
+    }
+  }
+
+  fn motions() -> (Vmd, Vmd) {
+    let dance = Vmd::<DefaultConfig> {
+      motion_frames: vec![
+        bone_frame("センター", 0, 1.0),
+        bone_frame("センター", 30, 2.0),
+      ],
+      ..Default::default()
+    };
+    let face = Vmd {
+      motion_frames: vec![bone_frame("センター", 0, 3.0), bone_frame("頭", 10, 4.0)],
+      morph_frames: vec![morph("まばたき", 0), morph("あ", 5)],
+      ..Default::default()
+    };
+
+    (dance, face)
+  }
+
+  #[test]
+  fn test_merge_offset_ordering() {
+    let (mut dance, face) = motions();
+
+    dance.merge(&face, 5, MergePolicy::Error).unwrap();
+
+    let keys = dance
+      .motion_frames
+      .iter()
+      .map(|f| (f.name.as_str(), f.frame_no))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      keys,
+      [
+        ("センター", 0),
+        ("センター", 5),
+        ("頭", 15),
+        ("センター", 30)
+      ]
+    );
+    assert_eq!(dance.morph_frames[0].frame_no, 5);
+    assert_eq!(dance.morph_frames[1].frame_no, 10);
+  }
+
+  #[test]
+  fn test_merge_collision() {
+    let (dance, face) = motions();
+
+    let mut merged = dance.clone();
+    merged.merge(&face, 0, MergePolicy::KeepSelf).unwrap();
+    assert_eq!(merged.motion_frames.len(), 3);
+    assert_eq!(merged.motion_frames[0].position[0], 1.0);
+
+    let mut merged = dance.clone();
+    merged.merge(&face, 0, MergePolicy::KeepOther).unwrap();
+    assert_eq!(merged.motion_frames.len(), 3);
+    assert_eq!(merged.motion_frames[0].position[0], 3.0);
+
+    let mut merged = dance.clone();
+    let err = merged.merge(&face, 0, MergePolicy::Error).unwrap_err();
+    assert!(matches!(
+      err,
+      Error::MergeCollision { ref name, frame_no: 0 } if name == "センター"
+    ));
+    assert_eq!(merged, dance);
+  }
+
+  #[test]
+  fn test_merge_overflow() {
+    let (mut dance, face) = motions();
+    let original = dance.clone();
+
+    let err = dance
+      .merge(&face, u32::MAX - 5, MergePolicy::KeepSelf)
+      .unwrap_err();
+
+    assert!(matches!(
+      err,
+      Error::FrameOverflow {
+        frame_no: 10,
+        offset
+      } if offset == u32::MAX - 5
+    ));
+    assert_eq!(dance, original);
+  }
+}
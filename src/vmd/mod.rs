@@ -1,60 +1,360 @@
-use std::io::Read;
-
-use byteorder::{ReadBytesExt, LE};
+use alloc::{
+  string::{String, ToString},
+  vec,
+  vec::Vec,
+};
+#[cfg(feature = "std")]
+use std::{fs::File, io::BufReader, path::Path};
+
+use byteorder::{ByteOrder, LE};
 use encoding_rs::SHIFT_JIS;
 
-const VMD_HEADER: &'static [u8] = b"Vocaloid Motion Data 0002\0";
-const VMD_MODEL_NAME_SIZE: usize = 20;
+use crate::io::{Cursor, ErrorKind, Read, ReadBytesExt};
+use crate::math::Widen;
+#[cfg(feature = "serde")]
+use crate::SerdeConfig;
+use crate::{Config, DefaultConfig};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "std")]
+mod camera;
+#[cfg(feature = "std")]
+mod difference;
+#[cfg(feature = "std")]
+mod edit;
+#[cfg(feature = "std")]
+mod fit;
+pub mod interpolation;
+#[cfg(feature = "std")]
+mod keyframe;
+#[cfg(feature = "std")]
+mod lenient;
+#[cfg(feature = "std")]
+mod merge;
+#[cfg(feature = "std")]
+mod reader;
+#[cfg(feature = "std")]
+mod reduce;
+#[cfg(feature = "std")]
+pub mod retarget;
+#[cfg(feature = "std")]
+pub mod sampler;
+#[cfg(feature = "std")]
+mod slice;
+#[cfg(feature = "std")]
+mod summary;
+#[cfg(feature = "std")]
+pub mod track;
+#[cfg(feature = "std")]
+mod transform;
+#[cfg(feature = "std")]
+mod writer;
+
+#[cfg(feature = "std")]
+pub use self::camera::{
+  bone_motion_to_camera_frames, camera_frames_to_bone_motion, CameraMotionBuilder,
+};
+#[cfg(feature = "std")]
+pub use self::edit::InsertMode;
+#[cfg(feature = "std")]
+pub use self::fit::fit_keyframes;
+pub use self::interpolation::{BezierCurve, BezierInterpolation, CameraInterpolation};
+#[cfg(feature = "std")]
+pub use self::keyframe::NormalizeReport;
+#[cfg(feature = "std")]
+pub use self::lenient::Warning;
+#[cfg(feature = "std")]
+pub use self::merge::MergePolicy;
+#[cfg(feature = "std")]
+pub use self::reader::VmdReader;
+#[cfg(feature = "std")]
+pub use self::reduce::reduce_keyframes;
+#[cfg(feature = "std")]
+pub use self::retarget::{match_bone_names, BoneMatch};
+#[cfg(feature = "std")]
+pub use self::summary::{BoneStats, MorphStats, StatisticsOrder, VmdSummary};
+#[cfg(feature = "std")]
+pub use self::transform::{RootTransformOptions, Rounding};
+#[cfg(feature = "std")]
+pub(crate) use self::writer::truncate_string;
+
+pub(crate) const VMD_HEADER: &[u8] = b"Vocaloid Motion Data 0002\0";
+pub(crate) const VMD_HEADER_V1: &[u8] = b"Vocaloid Motion Data file\0";
+pub(crate) const VMD_MODEL_NAME_SIZE: usize = 20;
+const VMD_MODEL_NAME_SIZE_V1: usize = 10;
+pub(crate) const VMD_BONE_NAME_SIZE: usize = 15;
+const VMD_IK_NAME_SIZE: usize = 20;
+
+// Sizes of the fixed-size records, read in one go
+const MOTION_FRAME_SIZE: usize = 111;
+const MORPH_FRAME_SIZE: usize = 23;
+const CAMERA_FRAME_SIZE: usize = 61;
+const LIGHT_FRAME_SIZE: usize = 28;
+const SHADOW_FRAME_SIZE: usize = 9;
+/// Upper bound of the memory reserved up front for a section, counts come from the file.
+const MAX_RESERVED_FRAMES: u32 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum VmdVersion {
+  /// `Vocaloid Motion Data file`, written by early MMD versions with 10-byte model names
+  V1,
+  /// `Vocaloid Motion Data 0002`
+  #[default]
+  V2,
+}
+
+impl VmdVersion {
+  fn magic(self) -> &'static [u8] {
+    match self {
+      VmdVersion::V1 => VMD_HEADER_V1,
+      VmdVersion::V2 => VMD_HEADER,
+    }
+  }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+  fn model_name_size(self) -> usize {
+    match self {
+      VmdVersion::V1 => VMD_MODEL_NAME_SIZE_V1,
+      VmdVersion::V2 => VMD_MODEL_NAME_SIZE,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct VmdHeader {
+  pub version: VmdVersion,
+  /// Signature bytes as stored in the file, some exporters leave garbage after the magic.
+  /// Written back as long as they start with the magic of `version`.
+  pub raw_signature: Option<[u8; 30]>,
   pub model_name: String,
+  /// Model name bytes as stored in the file, written back as long as they still decode to
+  /// `model_name`.
+  pub raw_model_name: Option<Vec<u8>>,
+}
+
+/// How names that are neither valid Shift_JIS nor UTF-8 are decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DecodeMode {
+  /// Fail with `Error::DecodeName`.
+  Strict,
+  /// Replace malformed sequences with U+FFFD, see `Vmd::malformed_names`.
+  #[default]
+  Lossy,
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub struct MotionFrame {
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
+pub struct MotionFrame<C: Config = DefaultConfig> {
   pub name: String,
+  /// Name bytes as stored in the file, written back as long as they still decode to `name`.
+  pub raw_name: Option<[u8; VMD_BONE_NAME_SIZE]>,
   pub frame_no: u32,
-  pub position: [f32; 3],
-  pub rotation: [f32; 4],
+  pub position: C::Vec3,
+  pub rotation: C::Vec4,
+  #[cfg_attr(feature = "serde", serde(with = "crate::serde_array"))]
   pub interpolation: [u8; 64],
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub struct SkinFrame {
-  pub unknown: [u8; 23]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MorphFrame {
+  pub name: String,
+  /// Name bytes as stored in the file, written back as long as they still decode to `name`.
+  pub raw_name: Option<[u8; VMD_BONE_NAME_SIZE]>,
+  pub frame_no: u32,
+  pub weight: f32,
 }
 
+#[deprecated(note = "renamed to `MorphFrame`")]
+pub type SkinFrame = MorphFrame;
+
 #[derive(Debug, Clone, PartialEq)]
-pub struct CameraFrame {
-  pub unknown: [u8; 61]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
+pub struct CameraFrame<C: Config = DefaultConfig> {
+  pub frame_no: u32,
+  pub distance: f32,
+  pub position: C::Vec3,
+  /// Euler angles in radians.
+  pub rotation: C::Vec3,
+  pub interpolation: [u8; 24],
+  pub fov: u32,
+  pub orthographic: bool,
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub struct LightFrame {
-  pub unknown: [u8; 28]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
+pub struct LightFrame<C: Config = DefaultConfig> {
+  pub frame_no: u32,
+  pub color: C::Vec3,
+  pub direction: C::Vec3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ShadowMode {
+  Off,
+  Mode1,
+  Mode2,
+  Unknown(u8),
+}
+
+impl From<u8> for ShadowMode {
+  fn from(value: u8) -> Self {
+    match value {
+      0 => ShadowMode::Off,
+      1 => ShadowMode::Mode1,
+      2 => ShadowMode::Mode2,
+      mode => ShadowMode::Unknown(mode),
+    }
+  }
+}
+
+impl From<ShadowMode> for u8 {
+  fn from(value: ShadowMode) -> Self {
+    match value {
+      ShadowMode::Off => 0,
+      ShadowMode::Mode1 => 1,
+      ShadowMode::Mode2 => 2,
+      ShadowMode::Unknown(mode) => mode,
+    }
+  }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ShadowFrame {
-  pub unknown: [u8; 9]
+  pub frame_no: u32,
+  pub mode: ShadowMode,
+  pub distance: f32,
 }
 
-fn read_string<R: Read>(read: &mut R, size: usize) -> crate::Result<String> {
+/// Display and IK states (表示・IK) of the model at a given frame.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PropertyFrame {
+  pub frame_no: u32,
+  pub visible: bool,
+  pub ik_states: Vec<(String, bool)>,
+}
+
+/// A whole motion file.
+///
+/// Sections missing at the end of the file are left empty. Vectors are stored as the math types
+/// of `C`, calls that don't otherwise pin it down need `Vmd::<DefaultConfig>::read`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
+pub struct Vmd<C: Config = DefaultConfig> {
+  pub header: VmdHeader,
+  pub motion_frames: Vec<MotionFrame<C>>,
+  pub morph_frames: Vec<MorphFrame>,
+  pub camera_frames: Vec<CameraFrame<C>>,
+  pub light_frames: Vec<LightFrame<C>>,
+  pub shadow_frames: Vec<ShadowFrame>,
+  pub property_frames: Vec<PropertyFrame>,
+  /// Bytes after the last section that parsed, e.g. junk left by converters. Not written back.
+  pub trailing_bytes: usize,
+}
+
+impl<C: Config> Default for Vmd<C> {
+  fn default() -> Self {
+    Self {
+      header: VmdHeader::default(),
+      motion_frames: Vec::new(),
+      morph_frames: Vec::new(),
+      camera_frames: Vec::new(),
+      light_frames: Vec::new(),
+      shadow_frames: Vec::new(),
+      property_frames: Vec::new(),
+      trailing_bytes: 0,
+    }
+  }
+}
+
+/// Iterator over the frames of a section, see `MotionFrame::read_iter`.
+///
+/// Stops after the first error.
+pub struct FrameIter<'a, R, T> {
+  read: &'a mut R,
+  remaining: u32,
+  read_frame: fn(&mut R) -> crate::Result<T>,
+}
+
+impl<'a, R: Read, T> FrameIter<'a, R, T> {
+  fn new(read: &'a mut R, total_frames: u32, read_frame: fn(&mut R) -> crate::Result<T>) -> Self {
+    Self {
+      read,
+      remaining: total_frames,
+      read_frame,
+    }
+  }
+}
+
+impl<R: Read, T> Iterator for FrameIter<'_, R, T> {
+  type Item = crate::Result<T>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.remaining == 0 {
+      return None;
+    }
+
+    let result = (self.read_frame)(self.read);
+    self.remaining = if result.is_ok() {
+      self.remaining - 1
+    } else {
+      0
+    };
+
+    Some(result)
+  }
+
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    (self.remaining as usize, Some(self.remaining as usize))
+  }
+}
+
+pub(crate) fn read_string<R: Read>(
+  read: &mut R,
+  size: usize,
+  mode: DecodeMode,
+) -> crate::Result<String> {
   let mut buf = vec![0; size];
   read.read_exact(&mut buf)?;
 
+  decode_string(&buf, mode)
+}
+
+fn decode_string(buf: &[u8], mode: DecodeMode) -> crate::Result<String> {
   // Truncate null bytes
   // NOTE: in some motion files the model name is filled with 0xfd after the null byte
-  let buf = &buf[0..buf.iter().position(|&x| x == 0).unwrap_or(size)];
+  let buf = &buf[0..buf.iter().position(|&x| x == 0).unwrap_or(buf.len())];
 
   // Convert to string (Shift_JIS)
   let (s, _, is_malformed) = SHIFT_JIS.decode(buf);
   let s = if is_malformed {
     // Try UTF-8, then fallback to Shift_JIS
-    std::str::from_utf8(buf)
-      .map(|s| s.to_string())
-      .unwrap_or_else(|_| s.to_string())
+    match (core::str::from_utf8(buf), mode) {
+      (Ok(s), _) => s.to_string(),
+      (Err(_), DecodeMode::Lossy) => s.to_string(),
+      (Err(_), DecodeMode::Strict) => return Err(crate::Error::DecodeName { raw: buf.to_vec() }),
+    }
   } else {
     s.to_string()
   };
@@ -62,59 +362,276 @@ fn read_string<R: Read>(read: &mut R, size: usize) -> crate::Result<String> {
   Ok(s)
 }
 
-fn read_vec<R: Read, const N: usize>(read: &mut R) -> crate::Result<[f32; N]> {
-  let mut buf = [0f32; N];
+/// Decodes `N` little-endian floats from the start of `buf`.
+pub(crate) fn decode_vec<const N: usize>(buf: &[u8]) -> [f32; N] {
+  let mut v = [0f32; N];
+  LE::read_f32_into(&buf[..N * 4], &mut v);
+  v
+}
+
+/// Reads a section frame count, returning `None` if the stream ends right before it.
+fn read_section_count<R: Read>(read: &mut R) -> crate::Result<Option<u32>> {
+  let mut buf = [0; 4];
+  let mut filled = 0;
+
+  while filled < buf.len() {
+    match read.read(&mut buf[filled..]) {
+      Ok(0) => break,
+      Ok(n) => filled += n,
+      Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+      Err(e) => return Err(e.into()),
+    }
+  }
+
+  match filled {
+    0 => Ok(None),
+    4 => Ok(Some(u32::from_le_bytes(buf))),
+    _ => Err(crate::io::Error::from(ErrorKind::UnexpectedEof).into()),
+  }
+}
+
+/// Reads `total_frames` records, reserving memory incrementally since the count is untrusted.
+fn read_frames<R: Read, T>(
+  read: &mut R,
+  section: &'static str,
+  total_frames: u32,
+  read_frame: impl Fn(&mut R) -> crate::Result<T>,
+) -> crate::Result<Vec<T>> {
+  let mut frames = Vec::with_capacity(total_frames.min(MAX_RESERVED_FRAMES) as usize);
+
+  for _ in 0..total_frames {
+    match read_frame(read) {
+      Ok(frame) => frames.push(frame),
+      Err(crate::Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+        return Err(crate::Error::TruncatedSection {
+          section,
+          expected: total_frames,
+          read: frames.len() as u32,
+        })
+      }
+      Err(e) => return Err(e),
+    }
+  }
+
+  Ok(frames)
+}
 
-  for i in 0..N {
-    buf[i] = read.read_f32::<LE>()?;
+/// Reads a whole section, returning `None` if the stream ends right before it.
+fn read_section<R: Read, T>(
+  read: &mut R,
+  section: &'static str,
+  read_frame: impl Fn(&mut R) -> crate::Result<T>,
+) -> crate::Result<Option<Vec<T>>> {
+  let total_frames = match read_section_count(read)? {
+    Some(total_frames) => total_frames,
+    None => return Ok(None),
+  };
+
+  read_frames(read, section, total_frames, read_frame).map(Some)
+}
+
+/// Reads a whole section from a buffer, returning `None` and rewinding if it is missing or doesn't
+/// fit in the buffer.
+fn read_fitting_section<'a, T>(
+  read: &mut Cursor<&'a [u8]>,
+  section: &'static str,
+  read_frame: impl Fn(&mut Cursor<&'a [u8]>) -> crate::Result<T>,
+) -> crate::Result<Option<Vec<T>>> {
+  let start = read.position();
+
+  match read_section(read, section, read_frame) {
+    Err(crate::Error::TruncatedSection { .. }) => {}
+    Err(crate::Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => {}
+    Ok(None) => {}
+    result => return result,
   }
 
-  Ok(buf)
+  read.set_position(start);
+  Ok(None)
+}
+
+impl<C: Config> Vmd<C> {
+  pub fn read<R: Read>(read: &mut R) -> crate::Result<Self> {
+    Self::read_with(read, DecodeMode::Lossy)
+  }
+
+  /// Reads a whole motion, ignoring junk after the last section.
+  ///
+  /// The motion and morph sections must be intact. Later sections are only read if they fit in
+  /// the rest of the input, anything else is counted in `trailing_bytes`.
+  pub fn read_with<R: Read>(read: &mut R, mode: DecodeMode) -> crate::Result<Self> {
+    let mut vmd = Vmd {
+      header: VmdHeader::read_with(read, mode)?,
+      ..Default::default()
+    };
+
+    // Old exporters stop writing after any section, so EOF between sections is not an error
+    vmd.motion_frames = match read_section(read, "motion", |r| MotionFrame::read_with(r, mode))? {
+      Some(frames) => frames,
+      None => return Ok(vmd),
+    };
+    vmd.morph_frames = match read_section(read, "morph", |r| MorphFrame::read_with(r, mode))? {
+      Some(frames) => frames,
+      None => return Ok(vmd),
+    };
+
+    // The rest is small compared to the bone keyframes, buffer it to back off from junk
+    let mut tail = Vec::new();
+    read.read_to_end(&mut tail)?;
+    let mut tail = Cursor::new(&tail[..]);
+
+    vmd.read_tail(&mut tail, mode)?;
+    vmd.trailing_bytes = tail.get_ref().len() - tail.position() as usize;
+
+    Ok(vmd)
+  }
+
+  /// Like `read_with` but fails with `Error::TrailingBytes` if anything follows the last section.
+  pub fn read_complete<R: Read>(read: &mut R, mode: DecodeMode) -> crate::Result<Self> {
+    let vmd = Self::read_with(read, mode)?;
+
+    match vmd.trailing_bytes {
+      0 => Ok(vmd),
+      bytes => Err(crate::Error::TrailingBytes { bytes }),
+    }
+  }
+
+  /// Reads the sections after the morphs, stopping in front of the first one that doesn't fit.
+  fn read_tail(&mut self, tail: &mut Cursor<&[u8]>, mode: DecodeMode) -> crate::Result<()> {
+    self.camera_frames = match read_fitting_section(tail, "camera", CameraFrame::read)? {
+      Some(frames) => frames,
+      None => return Ok(()),
+    };
+    self.light_frames = match read_fitting_section(tail, "light", LightFrame::read)? {
+      Some(frames) => frames,
+      None => return Ok(()),
+    };
+    self.shadow_frames = match read_fitting_section(tail, "shadow", ShadowFrame::read)? {
+      Some(frames) => frames,
+      None => return Ok(()),
+    };
+    self.property_frames =
+      read_fitting_section(tail, "property", |r| PropertyFrame::read_with(r, mode))?
+        .unwrap_or_default();
+
+    Ok(())
+  }
+
+  #[cfg(feature = "std")]
+  pub fn from_file<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+    Self::read(&mut BufReader::new(File::open(path)?))
+  }
+
+  /// Names that were not decoded cleanly by `DecodeMode::Lossy`, in file order.
+  pub fn malformed_names(&self) -> impl Iterator<Item = &str> {
+    let model_name = core::iter::once(self.header.model_name.as_str());
+    let bones = self.motion_frames.iter().map(|f| f.name.as_str());
+    let morphs = self.morph_frames.iter().map(|f| f.name.as_str());
+    let iks = self
+      .property_frames
+      .iter()
+      .flat_map(|f| f.ik_states.iter().map(|(name, _)| name.as_str()));
+
+    model_name
+      .chain(bones)
+      .chain(morphs)
+      .chain(iks)
+      .filter(|name| name.contains(char::REPLACEMENT_CHARACTER))
+  }
 }
 
 impl VmdHeader {
+  /// Replaces the model name, dropping the bytes read from the file.
+  pub fn set_model_name<S: Into<String>>(&mut self, model_name: S) {
+    self.model_name = model_name.into();
+    self.raw_model_name = None;
+  }
+
   pub fn read<R: Read>(read: &mut R) -> crate::Result<Self> {
+    Self::read_with(read, DecodeMode::Lossy)
+  }
+
+  pub fn read_with<R: Read>(read: &mut R, mode: DecodeMode) -> crate::Result<Self> {
     // Read header
     let mut buf = [0; 30];
     read.read_exact(&mut buf)?;
 
-    if buf[..VMD_HEADER.len()] != *VMD_HEADER {
-      return Err(crate::Error::InvalidHeader);
-    }
+    let version = [VmdVersion::V2, VmdVersion::V1]
+      .iter()
+      .copied()
+      .find(|v| buf.starts_with(v.magic()))
+      .ok_or(crate::Error::InvalidHeader)?;
+
+    let mut raw_model_name = vec![0; version.model_name_size()];
+    read.read_exact(&mut raw_model_name)?;
+    let model_name = decode_string(&raw_model_name, mode)?;
+
+    Ok(VmdHeader {
+      version,
+      raw_signature: Some(buf),
+      model_name,
+      raw_model_name: Some(raw_model_name),
+    })
+  }
+}
 
-    let model_name = read_string(read, VMD_MODEL_NAME_SIZE)?;
+impl<C: Config> MotionFrame<C> {
+  /// A keyframe with linear interpolation on every channel.
+  pub fn new<S: Into<String>>(
+    name: S,
+    frame_no: u32,
+    position: impl Into<C::Vec3>,
+    rotation: impl Into<C::Vec4>,
+  ) -> Self {
+    Self {
+      name: name.into(),
+      raw_name: None,
+      frame_no,
+      position: position.into(),
+      rotation: rotation.into(),
+      interpolation: BezierInterpolation::LINEAR.to_bytes(),
+    }
+  }
 
-    Ok(VmdHeader { model_name })
+  /// Replaces the name, dropping the bytes read from the file.
+  pub fn set_name<S: Into<String>>(&mut self, name: S) {
+    self.name = name.into();
+    self.raw_name = None;
   }
-}
 
-impl MotionFrame {
   pub fn read_all<R: Read>(read: &mut R) -> crate::Result<Vec<Self>> {
     let total_frames = read.read_u32::<LE>()?;
 
-    let mut frames = Vec::with_capacity(total_frames as usize);
+    read_frames(read, "motion", total_frames, Self::read)
+  }
 
-    for _ in 0..total_frames {
-      frames.push(Self::read(read)?);
-    }
+  /// Lazily reads the frames of the section one by one.
+  pub fn read_iter<R: Read>(read: &mut R) -> crate::Result<FrameIter<'_, R, Self>> {
+    let total_frames = read.read_u32::<LE>()?;
 
-    Ok(frames)
+    Ok(FrameIter::new(read, total_frames, Self::read))
   }
 
   pub fn read<R: Read>(read: &mut R) -> crate::Result<Self> {
-    let name = read_string(read, 15)?;
+    Self::read_with(read, DecodeMode::Lossy)
+  }
 
-    let frame_no = read.read_u32::<LE>()?;
-    let position = read_vec::<_, 3>(read)?;
-    let rotation = read_vec::<_, 4>(read)?;
-    let interpolation = {
-      let mut buf = [0; 64];
-      read.read_exact(&mut buf)?;
-      buf
-    };
+  pub fn read_with<R: Read>(read: &mut R, mode: DecodeMode) -> crate::Result<Self> {
+    let mut buf = [0; MOTION_FRAME_SIZE];
+    read.read_exact(&mut buf)?;
+
+    let mut raw_name = [0; VMD_BONE_NAME_SIZE];
+    raw_name.copy_from_slice(&buf[..VMD_BONE_NAME_SIZE]);
+    let name = decode_string(&raw_name, mode)?;
+    let frame_no = LE::read_u32(&buf[15..]);
+    let position = decode_vec::<3>(&buf[19..]).widen();
+    let rotation = decode_vec::<4>(&buf[31..]).widen();
+    let mut interpolation = [0; 64];
+    interpolation.copy_from_slice(&buf[47..]);
 
     Ok(Self {
       name,
+      raw_name: Some(raw_name),
       frame_no,
       position,
       rotation,
@@ -123,75 +640,117 @@ impl MotionFrame {
   }
 }
 
-impl SkinFrame {
+impl MorphFrame {
+  /// Replaces the name, dropping the bytes read from the file.
+  pub fn set_name<S: Into<String>>(&mut self, name: S) {
+    self.name = name.into();
+    self.raw_name = None;
+  }
+
   pub fn read_all<R: Read>(read: &mut R) -> crate::Result<Vec<Self>> {
     let total_frames = read.read_u32::<LE>()?;
 
-    let mut frames = Vec::with_capacity(total_frames as usize);
+    read_frames(read, "morph", total_frames, Self::read)
+  }
 
-    for _ in 0..total_frames {
-      frames.push(Self::read(read)?);
-    }
+  /// Lazily reads the frames of the section one by one.
+  pub fn read_iter<R: Read>(read: &mut R) -> crate::Result<FrameIter<'_, R, Self>> {
+    let total_frames = read.read_u32::<LE>()?;
 
-    Ok(frames)
+    Ok(FrameIter::new(read, total_frames, Self::read))
   }
 
   pub fn read<R: Read>(read: &mut R) -> crate::Result<Self> {
-    let unknown = {
-      let mut buf = [0; 23];
-      read.read_exact(&mut buf)?;
-      buf
-    };
+    Self::read_with(read, DecodeMode::Lossy)
+  }
 
-    Ok(Self { unknown })
+  pub fn read_with<R: Read>(read: &mut R, mode: DecodeMode) -> crate::Result<Self> {
+    let mut buf = [0; MORPH_FRAME_SIZE];
+    read.read_exact(&mut buf)?;
+
+    let mut raw_name = [0; VMD_BONE_NAME_SIZE];
+    raw_name.copy_from_slice(&buf[..VMD_BONE_NAME_SIZE]);
+    let name = decode_string(&raw_name, mode)?;
+    let frame_no = LE::read_u32(&buf[15..]);
+    let weight = LE::read_f32(&buf[19..]);
+
+    Ok(Self {
+      name,
+      raw_name: Some(raw_name),
+      frame_no,
+      weight,
+    })
   }
 }
 
-impl CameraFrame {
+impl<C: Config> CameraFrame<C> {
   pub fn read_all<R: Read>(read: &mut R) -> crate::Result<Vec<Self>> {
     let total_frames = read.read_u32::<LE>()?;
 
-    let mut frames = Vec::with_capacity(total_frames as usize);
+    read_frames(read, "camera", total_frames, Self::read)
+  }
 
-    for _ in 0..total_frames {
-      frames.push(Self::read(read)?);
-    }
+  /// Lazily reads the frames of the section one by one.
+  pub fn read_iter<R: Read>(read: &mut R) -> crate::Result<FrameIter<'_, R, Self>> {
+    let total_frames = read.read_u32::<LE>()?;
 
-    Ok(frames)
+    Ok(FrameIter::new(read, total_frames, Self::read))
   }
 
   pub fn read<R: Read>(read: &mut R) -> crate::Result<Self> {
-    let unknown = {
-      let mut buf = [0; 61];
-      read.read_exact(&mut buf)?;
-      buf
-    };
+    let mut buf = [0; CAMERA_FRAME_SIZE];
+    read.read_exact(&mut buf)?;
+
+    let frame_no = LE::read_u32(&buf);
+    let distance = LE::read_f32(&buf[4..]);
+    let position = decode_vec::<3>(&buf[8..]).widen();
+    let rotation = decode_vec::<3>(&buf[20..]).widen();
+    let mut interpolation = [0; 24];
+    interpolation.copy_from_slice(&buf[32..56]);
+    let fov = LE::read_u32(&buf[56..]);
+    // NOTE: the flag is stored as "perspective off", so 0 means a perspective camera
+    let orthographic = buf[60] != 0;
 
-    Ok(Self { unknown })
+    Ok(Self {
+      frame_no,
+      distance,
+      position,
+      rotation,
+      interpolation,
+      fov,
+      orthographic,
+    })
   }
 }
 
-impl LightFrame {
+impl<C: Config> LightFrame<C> {
   pub fn read_all<R: Read>(read: &mut R) -> crate::Result<Vec<Self>> {
     let total_frames = read.read_u32::<LE>()?;
 
-    let mut frames = Vec::with_capacity(total_frames as usize);
+    read_frames(read, "light", total_frames, Self::read)
+  }
 
-    for _ in 0..total_frames {
-      frames.push(Self::read(read)?);
-    }
+  /// Lazily reads the frames of the section one by one.
+  pub fn read_iter<R: Read>(read: &mut R) -> crate::Result<FrameIter<'_, R, Self>> {
+    let total_frames = read.read_u32::<LE>()?;
 
-    Ok(frames)
+    Ok(FrameIter::new(read, total_frames, Self::read))
   }
 
   pub fn read<R: Read>(read: &mut R) -> crate::Result<Self> {
-    let unknown = {
-      let mut buf = [0; 28];
-      read.read_exact(&mut buf)?;
-      buf
-    };
+    let mut buf = [0; LIGHT_FRAME_SIZE];
+    read.read_exact(&mut buf)?;
 
-    Ok(Self { unknown })
+    let frame_no = LE::read_u32(&buf);
+    // NOTE: some exporters write components slightly above 1.0, keep them as is
+    let color = decode_vec::<3>(&buf[4..]).widen();
+    let direction = decode_vec::<3>(&buf[16..]).widen();
+
+    Ok(Self {
+      frame_no,
+      color,
+      direction,
+    })
   }
 }
 
@@ -199,34 +758,123 @@ impl ShadowFrame {
   pub fn read_all<R: Read>(read: &mut R) -> crate::Result<Vec<Self>> {
     let total_frames = read.read_u32::<LE>()?;
 
-    let mut frames = Vec::with_capacity(total_frames as usize);
+    read_frames(read, "shadow", total_frames, Self::read)
+  }
 
-    for _ in 0..total_frames {
-      frames.push(Self::read(read)?);
-    }
+  /// Lazily reads the frames of the section one by one, yielding nothing if it is missing.
+  pub fn read_iter<R: Read>(read: &mut R) -> crate::Result<FrameIter<'_, R, Self>> {
+    let total_frames = read_section_count(read)?.unwrap_or(0);
 
-    Ok(frames)
+    Ok(FrameIter::new(read, total_frames, Self::read))
   }
 
   pub fn read<R: Read>(read: &mut R) -> crate::Result<Self> {
-    let unknown = {
-      let mut buf = [0; 9];
-      read.read_exact(&mut buf)?;
-      buf
+    let mut buf = [0; SHADOW_FRAME_SIZE];
+    read.read_exact(&mut buf)?;
+
+    let frame_no = LE::read_u32(&buf);
+    let mode = ShadowMode::from(buf[4]);
+    let distance = LE::read_f32(&buf[5..]);
+
+    Ok(Self {
+      frame_no,
+      mode,
+      distance,
+    })
+  }
+}
+
+impl PropertyFrame {
+  /// Reads the property section, which older exporters omit entirely.
+  pub fn read_all<R: Read>(read: &mut R) -> crate::Result<Vec<Self>> {
+    let total_frames = match read_section_count(read)? {
+      Some(total_frames) => total_frames,
+      None => return Ok(Vec::new()),
     };
 
-    Ok(Self { unknown })
+    read_frames(read, "property", total_frames, Self::read)
+  }
+
+  pub fn read<R: Read>(read: &mut R) -> crate::Result<Self> {
+    Self::read_with(read, DecodeMode::Lossy)
+  }
+
+  pub fn read_with<R: Read>(read: &mut R, mode: DecodeMode) -> crate::Result<Self> {
+    let frame_no = read.read_u32::<LE>()?;
+    let visible = read.read_u8()? != 0;
+    let total_iks = read.read_u32::<LE>()?;
+
+    let mut ik_states = Vec::with_capacity(total_iks.min(MAX_RESERVED_FRAMES) as usize);
+
+    for _ in 0..total_iks {
+      let name = read_string(read, VMD_IK_NAME_SIZE, mode)?;
+      let enabled = read.read_u8()? != 0;
+      ik_states.push((name, enabled));
+    }
+
+    Ok(Self {
+      frame_no,
+      visible,
+      ik_states,
+    })
   }
 }
 
 #[cfg(test)]
-mod tests {
-  const FIXTURE_MOTION_VMD: &'static [u8] = include_bytes!("../../fixtures/motion.vmd");
-  const FIXTURE_CAMERA_VMD: &'static [u8] = include_bytes!("../../fixtures/camera.vmd");
-  const FIXTURE_ISSUE1_VMD: &'static [u8] = include_bytes!("../../fixtures/issue1.vmd");
+pub(crate) mod tests {
+  use byteorder::{ByteOrder, LE};
+
+  use crate::math::to_array;
+  use crate::{AsSlice, Config, DefaultConfig};
+
+  /// Stand-in for the vector type of a math library, to exercise a non-default `Config`.
+  #[derive(Debug, Clone, PartialEq)]
+  pub(crate) struct Vector<const N: usize>([f32; N]);
+
+  impl<const N: usize> From<[f32; N]> for Vector<N> {
+    fn from(v: [f32; N]) -> Self {
+      Self(v)
+    }
+  }
+
+  impl<const N: usize> AsSlice for Vector<N> {
+    type Float = f32;
+
+    fn as_slice(&self) -> &[f32] {
+      &self.0
+    }
+  }
+
+  #[derive(Debug, Clone, PartialEq)]
+  pub(crate) struct VectorConfig;
+
+  impl Config for VectorConfig {
+    type VertexIndex = u32;
+    type TextureIndex = i32;
+    type MaterialIndex = i32;
+    type BoneIndex = i32;
+    type MorphIndex = i32;
+    type RigidbodyIndex = i32;
+
+    type Float = f32;
+    type Vec2 = Vector<2>;
+    type Vec3 = Vector<3>;
+    type Vec4 = Vector<4>;
+    type AdditionalVec4s = Vec<Vector<4>>;
+  }
+
+  /// A linear keyframe of `name`, moved by `x` along X and not rotated.
+  #[cfg(feature = "std")]
+  pub(crate) fn bone_frame(name: &str, frame_no: u32, x: f32) -> super::MotionFrame {
+    super::MotionFrame::new(name, frame_no, [x, 0.0, 0.0], [0.0, 0.0, 0.0, 1.0])
+  }
+
+  const FIXTURE_MOTION_VMD: &[u8] = include_bytes!("../../fixtures/motion.vmd");
+  const FIXTURE_CAMERA_VMD: &[u8] = include_bytes!("../../fixtures/camera.vmd");
+  const FIXTURE_ISSUE1_VMD: &[u8] = include_bytes!("../../fixtures/issue1.vmd");
 
   fn util_test_vmd_header(bytes: &[u8], model_name: &str) {
-    let header = super::VmdHeader::read(&mut std::io::Cursor::new(bytes)).unwrap();
+    let header = super::VmdHeader::read(&mut crate::io::Cursor::new(bytes)).unwrap();
     assert_eq!(header.model_name, model_name);
   }
 
@@ -245,12 +893,11 @@ mod tests {
     util_test_vmd_header(FIXTURE_ISSUE1_VMD, "初音ミク");
   }
 
-  #[test]
-  fn test_vmd_frame_motion() {
-    let mut cursor = std::io::Cursor::new(FIXTURE_MOTION_VMD);
+  fn util_test_vmd_frame_motion<C: Config>() {
+    let mut cursor = crate::io::Cursor::new(FIXTURE_MOTION_VMD);
     super::VmdHeader::read(&mut cursor).unwrap();
 
-    let frame = super::MotionFrame::read_all(&mut cursor).unwrap();
+    let frame = super::MotionFrame::<C>::read_all(&mut cursor).unwrap();
 
     assert_eq!(frame.len(), 164);
     assert_eq!(frame[0].name, "センター");
@@ -258,24 +905,455 @@ mod tests {
   }
 
   #[test]
-  fn test_vmd_frame_camera() {
-    let mut cursor = std::io::Cursor::new(FIXTURE_CAMERA_VMD);
+  fn test_vmd_frame_motion() {
+    util_test_vmd_frame_motion::<DefaultConfig>();
+    util_test_vmd_frame_motion::<VectorConfig>();
+  }
+
+  fn util_test_vmd_frame_camera<C: Config>() {
+    let mut cursor = crate::io::Cursor::new(FIXTURE_CAMERA_VMD);
     super::VmdHeader::read(&mut cursor).unwrap();
 
-    let frame = super::MotionFrame::read_all(&mut cursor).unwrap();
+    let frame = super::MotionFrame::<C>::read_all(&mut cursor).unwrap();
 
     assert_eq!(frame.len(), 0);
   }
 
   #[test]
-  fn test_vmd_frame_issue1() {
-    let mut cursor = std::io::Cursor::new(FIXTURE_ISSUE1_VMD);
+  fn test_vmd_frame_camera() {
+    util_test_vmd_frame_camera::<DefaultConfig>();
+    util_test_vmd_frame_camera::<VectorConfig>();
+  }
+
+  fn util_test_vmd_frame_issue1<C: Config>() {
+    let mut cursor = crate::io::Cursor::new(FIXTURE_ISSUE1_VMD);
     super::VmdHeader::read(&mut cursor).unwrap();
 
-    let frame = super::MotionFrame::read_all(&mut cursor).unwrap();
+    let frame = super::MotionFrame::<C>::read_all(&mut cursor).unwrap();
 
     assert_eq!(frame.len(), 7);
     assert_eq!(frame[0].name, "左目");
     assert_eq!(frame[0].frame_no, 0);
   }
+
+  #[test]
+  fn test_vmd_frame_issue1() {
+    util_test_vmd_frame_issue1::<DefaultConfig>();
+    util_test_vmd_frame_issue1::<VectorConfig>();
+  }
+
+  #[test]
+  fn test_vmd_morph_frame() {
+    let mut cursor = crate::io::Cursor::new(FIXTURE_MOTION_VMD);
+    super::VmdHeader::read(&mut cursor).unwrap();
+    super::MotionFrame::<DefaultConfig>::read_all(&mut cursor).unwrap();
+
+    let frame = super::MorphFrame::read_all(&mut cursor).unwrap();
+
+    assert_eq!(frame.len(), 30);
+    assert_eq!(frame[0].name, "真面目");
+    assert_eq!(frame[0].frame_no, 0);
+    assert!(frame.iter().all(|f| (0.0..=1.0).contains(&f.weight)));
+  }
+
+  fn util_test_vmd_camera_frame<C: Config>() {
+    let mut cursor = crate::io::Cursor::new(FIXTURE_CAMERA_VMD);
+    super::VmdHeader::read(&mut cursor).unwrap();
+    super::MotionFrame::<C>::read_all(&mut cursor).unwrap();
+    super::MorphFrame::read_all(&mut cursor).unwrap();
+
+    let frame = super::CameraFrame::<C>::read_all(&mut cursor).unwrap();
+
+    assert_eq!(frame.len(), 2);
+    assert_eq!(frame[0].frame_no, 0);
+    assert_eq!(frame[0].distance, -180.0);
+    assert_eq!(frame[0].fov, 30);
+    assert!(!frame[0].orthographic);
+    assert_eq!(frame[1].frame_no, 1);
+  }
+
+  #[test]
+  fn test_vmd_camera_frame() {
+    util_test_vmd_camera_frame::<DefaultConfig>();
+    util_test_vmd_camera_frame::<VectorConfig>();
+  }
+
+  fn util_test_vmd_light_frame<C: Config>() {
+    let mut cursor = crate::io::Cursor::new(FIXTURE_CAMERA_VMD);
+    super::VmdHeader::read(&mut cursor).unwrap();
+    super::MotionFrame::<C>::read_all(&mut cursor).unwrap();
+    super::MorphFrame::read_all(&mut cursor).unwrap();
+    super::CameraFrame::<C>::read_all(&mut cursor).unwrap();
+
+    let frame = super::LightFrame::<C>::read_all(&mut cursor).unwrap();
+
+    assert_eq!(frame.len(), 2);
+    assert_eq!(frame[0].frame_no, 0);
+    assert_eq!(to_array::<3>(&frame[0].color), [0.99609375; 3]);
+    assert_eq!(to_array::<3>(&frame[0].direction), [-0.5, -1.0, 0.5]);
+    assert_eq!(frame[1].frame_no, 1);
+  }
+
+  #[test]
+  fn test_vmd_light_frame() {
+    util_test_vmd_light_frame::<DefaultConfig>();
+    util_test_vmd_light_frame::<VectorConfig>();
+  }
+
+  #[test]
+  fn test_vmd_shadow_frame() {
+    let mut cursor = crate::io::Cursor::new(FIXTURE_CAMERA_VMD);
+    super::VmdHeader::read(&mut cursor).unwrap();
+    super::MotionFrame::<DefaultConfig>::read_all(&mut cursor).unwrap();
+    super::MorphFrame::read_all(&mut cursor).unwrap();
+    super::CameraFrame::<DefaultConfig>::read_all(&mut cursor).unwrap();
+    super::LightFrame::<DefaultConfig>::read_all(&mut cursor).unwrap();
+
+    let position = cursor.position() as usize;
+    let raw_count = LE::read_u32(&FIXTURE_CAMERA_VMD[position..]);
+
+    let frame = super::ShadowFrame::read_all(&mut cursor).unwrap();
+
+    assert_eq!(frame.len(), raw_count as usize);
+    assert_eq!(frame.len(), 2);
+    assert_eq!(frame[0].frame_no, 0);
+    assert_eq!(frame[0].mode, super::ShadowMode::Mode1);
+    assert_eq!(frame[1].frame_no, 1);
+    assert_eq!(frame[1].distance, 0.1);
+  }
+
+  #[test]
+  fn test_vmd_shadow_frame_empty() {
+    let mut cursor = crate::io::Cursor::new([0u8; 4]);
+
+    let frame = super::ShadowFrame::read_all(&mut cursor).unwrap();
+
+    assert!(frame.is_empty());
+  }
+
+  #[test]
+  fn test_vmd_shadow_mode_unknown() {
+    let mut bytes = vec![0u8; 4];
+    bytes.push(7);
+    bytes.extend_from_slice(&1.5f32.to_le_bytes());
+
+    let frame = super::ShadowFrame::read(&mut crate::io::Cursor::new(bytes)).unwrap();
+
+    assert_eq!(frame.mode, super::ShadowMode::Unknown(7));
+    assert_eq!(frame.distance, 1.5);
+  }
+
+  #[test]
+  fn test_vmd_property_frame() {
+    let mut cursor = crate::io::Cursor::new(FIXTURE_MOTION_VMD);
+    super::VmdHeader::read(&mut cursor).unwrap();
+    super::MotionFrame::<DefaultConfig>::read_all(&mut cursor).unwrap();
+    super::MorphFrame::read_all(&mut cursor).unwrap();
+    super::CameraFrame::<DefaultConfig>::read_all(&mut cursor).unwrap();
+    super::LightFrame::<DefaultConfig>::read_all(&mut cursor).unwrap();
+    super::ShadowFrame::read_all(&mut cursor).unwrap();
+
+    let frame = super::PropertyFrame::read_all(&mut cursor).unwrap();
+
+    assert_eq!(frame.len(), 2);
+    assert_eq!(frame[0].frame_no, 0);
+    assert!(frame[0].visible);
+    assert_eq!(frame[0].ik_states.len(), 7);
+    assert_eq!(frame[0].ik_states[3], ("左足ＩＫ".to_string(), true));
+  }
+
+  #[test]
+  fn test_vmd_property_frame_missing() {
+    let mut cursor = crate::io::Cursor::new(&[] as &[u8]);
+
+    let frame = super::PropertyFrame::read_all(&mut cursor).unwrap();
+
+    assert!(frame.is_empty());
+  }
+
+  #[test]
+  fn test_vmd_property_frame_truncated() {
+    let mut cursor = crate::io::Cursor::new([1u8, 0]);
+
+    assert!(super::PropertyFrame::read_all(&mut cursor).is_err());
+  }
+
+  fn util_test_vmd_read_motion<C: Config>() {
+    let vmd = super::Vmd::<C>::read(&mut crate::io::Cursor::new(FIXTURE_MOTION_VMD)).unwrap();
+
+    assert_eq!(vmd.header.model_name, "初音ミク");
+    assert_eq!(vmd.motion_frames.len(), 164);
+    assert_eq!(vmd.morph_frames.len(), 30);
+    assert_eq!(vmd.camera_frames.len(), 0);
+    assert_eq!(vmd.property_frames.len(), 2);
+  }
+
+  #[test]
+  fn test_vmd_read_motion() {
+    util_test_vmd_read_motion::<DefaultConfig>();
+    util_test_vmd_read_motion::<VectorConfig>();
+    #[cfg(feature = "nalgebra")]
+    util_test_vmd_read_motion::<crate::NalgebraConfig>();
+  }
+
+  fn util_test_vmd_read_camera<C: Config>() {
+    let vmd = super::Vmd::<C>::read(&mut crate::io::Cursor::new(FIXTURE_CAMERA_VMD)).unwrap();
+
+    assert_eq!(vmd.header.model_name, "カメラ・照明");
+    assert_eq!(vmd.motion_frames.len(), 0);
+    assert_eq!(vmd.camera_frames.len(), 2);
+    assert_eq!(vmd.light_frames.len(), 2);
+    assert_eq!(vmd.shadow_frames.len(), 2);
+  }
+
+  #[test]
+  fn test_vmd_read_camera() {
+    util_test_vmd_read_camera::<DefaultConfig>();
+    util_test_vmd_read_camera::<VectorConfig>();
+    #[cfg(feature = "nalgebra")]
+    util_test_vmd_read_camera::<crate::NalgebraConfig>();
+  }
+
+  #[test]
+  fn test_vmd_read_truncated_at_section() {
+    // Header, 164 motion frames and 30 morph frames, nothing after
+    let end = 50 + 4 + 164 * 111 + 4 + 30 * 23;
+    let vmd =
+      super::Vmd::<DefaultConfig>::read(&mut crate::io::Cursor::new(&FIXTURE_MOTION_VMD[..end]))
+        .unwrap();
+
+    assert_eq!(vmd.motion_frames.len(), 164);
+    assert_eq!(vmd.morph_frames.len(), 30);
+    assert!(vmd.camera_frames.is_empty());
+    assert!(vmd.property_frames.is_empty());
+  }
+
+  #[test]
+  fn test_vmd_read_truncated_in_record() {
+    let end = 50 + 4 + 164 * 111 + 4 + 10;
+
+    assert!(
+      super::Vmd::<DefaultConfig>::read(&mut crate::io::Cursor::new(&FIXTURE_MOTION_VMD[..end]))
+        .is_err()
+    );
+  }
+
+  #[test]
+  fn test_vmd_read_trailing_bytes() {
+    let original =
+      super::Vmd::<DefaultConfig>::read(&mut crate::io::Cursor::new(FIXTURE_MOTION_VMD)).unwrap();
+    assert_eq!(original.trailing_bytes, 0);
+
+    let mut state = 0x2545_f491u32;
+    for len in 1..=32 {
+      let mut bytes = FIXTURE_MOTION_VMD.to_vec();
+      bytes.extend((0..len).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as u8
+      }));
+
+      let vmd = super::Vmd::<DefaultConfig>::read(&mut crate::io::Cursor::new(&bytes)).unwrap();
+      assert_eq!(vmd.trailing_bytes, len);
+      assert_eq!(
+        super::Vmd {
+          trailing_bytes: 0,
+          ..vmd
+        },
+        original
+      );
+
+      let err = super::Vmd::<DefaultConfig>::read_complete(
+        &mut crate::io::Cursor::new(&bytes),
+        super::DecodeMode::Lossy,
+      )
+      .unwrap_err();
+      assert!(matches!(err, crate::Error::TrailingBytes { bytes } if bytes == len));
+    }
+  }
+
+  #[test]
+  fn test_vmd_read_junk_section() {
+    // Junk in place of the property section of a camera motion, claiming 1000 frames
+    let mut bytes = FIXTURE_CAMERA_VMD.to_vec();
+    bytes.extend_from_slice(&1000u32.to_le_bytes());
+    bytes.extend_from_slice(&[0xff; 6]);
+
+    let vmd = super::Vmd::<DefaultConfig>::read(&mut crate::io::Cursor::new(&bytes)).unwrap();
+
+    assert_eq!(vmd.shadow_frames.len(), 2);
+    assert!(vmd.property_frames.is_empty());
+    assert_eq!(vmd.trailing_bytes, 10);
+    assert!(super::Vmd::<DefaultConfig>::read_complete(
+      &mut crate::io::Cursor::new(FIXTURE_CAMERA_VMD),
+      super::DecodeMode::Lossy
+    )
+    .is_ok());
+  }
+
+  #[test]
+  fn test_vmd_read_iter() {
+    let mut cursor = crate::io::Cursor::new(FIXTURE_MOTION_VMD);
+    super::VmdHeader::read(&mut cursor).unwrap();
+
+    let iter = super::MotionFrame::<DefaultConfig>::read_iter(&mut cursor).unwrap();
+    assert_eq!(iter.size_hint(), (164, Some(164)));
+
+    let center = iter
+      .filter(|f| f.as_ref().map_or(true, |f| f.name == "センター"))
+      .collect::<crate::Result<Vec<_>>>()
+      .unwrap();
+    assert!(!center.is_empty());
+
+    let morphs = super::MorphFrame::read_iter(&mut cursor).unwrap();
+    assert_eq!(morphs.count(), 30);
+  }
+
+  #[test]
+  fn test_vmd_read_iter_truncated() {
+    let end = 50 + 4 + 111 + 10;
+    let mut cursor = crate::io::Cursor::new(&FIXTURE_MOTION_VMD[..end]);
+    super::VmdHeader::read(&mut cursor).unwrap();
+
+    let mut iter = super::MotionFrame::<DefaultConfig>::read_iter(&mut cursor).unwrap();
+
+    assert!(iter.next().unwrap().is_ok());
+    assert!(iter.next().unwrap().is_err());
+    assert!(iter.next().is_none());
+  }
+
+  #[test]
+  fn test_vmd_read_all_truncated() {
+    let mut bytes = 1000u32.to_le_bytes().to_vec();
+    bytes.extend_from_slice(&[0; 2 * 23]);
+
+    let err = super::MorphFrame::read_all(&mut crate::io::Cursor::new(bytes)).unwrap_err();
+
+    match err {
+      crate::Error::TruncatedSection {
+        section,
+        expected,
+        read,
+      } => {
+        assert_eq!(section, "morph");
+        assert_eq!(expected, 1000);
+        assert_eq!(read, 2);
+      }
+      e => panic!("unexpected error {}", e),
+    }
+  }
+
+  #[test]
+  fn test_vmd_read_all_absurd_count() {
+    let bytes = u32::MAX.to_le_bytes();
+
+    let err = super::MotionFrame::<DefaultConfig>::read_all(&mut crate::io::Cursor::new(bytes))
+      .unwrap_err();
+
+    assert!(matches!(
+      err,
+      crate::Error::TruncatedSection { read: 0, .. }
+    ));
+  }
+
+  #[test]
+  fn test_vmd_header_v1() {
+    let mut bytes = b"Vocaloid Motion Data file\0\0\0\0\0".to_vec();
+    bytes.extend_from_slice(&[0x8f, 0x89, 0x89, 0xb9, 0x83, 0x7e, 0x83, 0x4e, 0, 0]);
+    // An empty motion section follows right after the 10-byte model name
+    bytes.extend_from_slice(&[0; 4]);
+
+    let vmd = super::Vmd::<DefaultConfig>::read(&mut crate::io::Cursor::new(bytes)).unwrap();
+
+    assert_eq!(vmd.header.version, super::VmdVersion::V1);
+    assert_eq!(vmd.header.model_name, "初音ミク");
+    assert!(vmd.motion_frames.is_empty());
+  }
+
+  #[test]
+  fn test_vmd_header_v2() {
+    let header = super::VmdHeader::read(&mut crate::io::Cursor::new(FIXTURE_MOTION_VMD)).unwrap();
+
+    assert_eq!(header.version, super::VmdVersion::V2);
+  }
+
+  fn corrupted_motion_frame() -> Vec<u8> {
+    let mut bytes = vec![0u8; 111];
+    // A Shift_JIS lead byte followed by an invalid trail byte, which isn't UTF-8 either
+    bytes[..4].copy_from_slice(&[0x83, 0x5a, 0x81, 0xff]);
+    bytes
+  }
+
+  #[test]
+  fn test_vmd_decode_strict() {
+    let err = super::MotionFrame::<DefaultConfig>::read_with(
+      &mut crate::io::Cursor::new(corrupted_motion_frame()),
+      super::DecodeMode::Strict,
+    )
+    .unwrap_err();
+
+    match err {
+      crate::Error::DecodeName { raw } => assert_eq!(raw, [0x83, 0x5a, 0x81, 0xff]),
+      e => panic!("unexpected error {}", e),
+    }
+
+    let frame = super::MotionFrame::<DefaultConfig>::read_with(
+      &mut crate::io::Cursor::new(&FIXTURE_MOTION_VMD[54..]),
+      super::DecodeMode::Strict,
+    )
+    .unwrap();
+    assert_eq!(frame.name, "センター");
+  }
+
+  #[test]
+  fn test_vmd_decode_lossy() {
+    let mut bytes = FIXTURE_MOTION_VMD[..50].to_vec();
+    bytes.extend_from_slice(&1u32.to_le_bytes());
+    bytes.extend_from_slice(&corrupted_motion_frame());
+    bytes.extend_from_slice(&1u32.to_le_bytes());
+    bytes.extend_from_slice(b"ok\0\0\0\0\0\0\0\0\0\0\0\0\0");
+    bytes.extend_from_slice(&[0; 8]);
+
+    let vmd = super::Vmd::<DefaultConfig>::read(&mut crate::io::Cursor::new(&bytes)).unwrap();
+
+    assert!(vmd.motion_frames[0].name.starts_with('セ'));
+    assert_eq!(
+      vmd.malformed_names().collect::<Vec<_>>(),
+      [vmd.motion_frames[0].name.as_str()]
+    );
+    assert!(super::Vmd::<DefaultConfig>::read_with(
+      &mut crate::io::Cursor::new(&bytes),
+      super::DecodeMode::Strict
+    )
+    .is_err());
+  }
+
+  #[cfg(feature = "serde")]
+  #[test]
+  fn test_vmd_serde_round_trip() {
+    let vmd = super::Vmd::<DefaultConfig>::read(&mut &FIXTURE_MOTION_VMD[..]).unwrap();
+    let json = serde_json::to_string(&vmd).unwrap();
+    assert_eq!(serde_json::from_str::<super::Vmd>(&json).unwrap(), vmd);
+    let bytes = bincode::serialize(&vmd).unwrap();
+    assert_eq!(bincode::deserialize::<super::Vmd>(&bytes).unwrap(), vmd);
+
+    // The interpolation block is a plain array like the shorter ones
+    let frame = serde_json::to_value(&vmd.motion_frames[0]).unwrap();
+    assert_eq!(frame["interpolation"].as_array().unwrap().len(), 64);
+    assert_eq!(frame["raw_name"].as_array().unwrap().len(), 15);
+
+    let morph = super::MorphFrame {
+      name: "あ".to_string(),
+      raw_name: None,
+      frame_no: 3,
+      weight: 0.5,
+    };
+    assert_eq!(
+      serde_json::to_string(&morph).unwrap(),
+      r#"{"name":"あ","raw_name":null,"frame_no":3,"weight":0.5}"#
+    );
+    // The length of the name as u64, its 3 bytes, the tag of the option, 2 x 4 bytes
+    assert_eq!(bincode::serialize(&morph).unwrap().len(), 20);
+  }
 }
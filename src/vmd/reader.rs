@@ -0,0 +1,187 @@
+//! Reading selected sections of a seekable motion file.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use byteorder::{ReadBytesExt, LE};
+
+use super::{
+  CameraFrame, LightFrame, MorphFrame, MotionFrame, PropertyFrame, ShadowFrame, VmdHeader,
+  CAMERA_FRAME_SIZE, LIGHT_FRAME_SIZE, MORPH_FRAME_SIZE, MOTION_FRAME_SIZE, SHADOW_FRAME_SIZE,
+};
+use crate::Config;
+
+/// Reads the sections of a motion one by one, seeking over the ones that aren't needed.
+///
+/// Sections have to be read or skipped in file order: motion, morph, camera, light, shadow and
+/// property.
+pub struct VmdReader<R> {
+  read: R,
+  header: VmdHeader,
+}
+
+impl<R: Read + Seek> VmdReader<R> {
+  pub fn new(mut read: R) -> crate::Result<Self> {
+    let header = VmdHeader::read(&mut read)?;
+
+    Ok(Self { read, header })
+  }
+
+  pub fn header(&self) -> &VmdHeader {
+    &self.header
+  }
+
+  pub fn into_inner(self) -> R {
+    self.read
+  }
+
+  /// Seeks past a section of fixed-size records, returning the number of skipped records.
+  fn skip(&mut self, section: &'static str, record_size: u64) -> crate::Result<u32> {
+    let total_frames = self.read.read_u32::<LE>()?;
+    let start = self.read.stream_position()?;
+    let end = self.read.seek(SeekFrom::End(0))?;
+
+    let available = end.saturating_sub(start) / record_size;
+    if available < total_frames as u64 {
+      return Err(crate::Error::TruncatedSection {
+        section,
+        expected: total_frames,
+        read: available as u32,
+      });
+    }
+
+    self
+      .read
+      .seek(SeekFrom::Start(start + total_frames as u64 * record_size))?;
+
+    Ok(total_frames)
+  }
+
+  pub fn skip_motion_frames(&mut self) -> crate::Result<u32> {
+    self.skip("motion", MOTION_FRAME_SIZE as u64)
+  }
+
+  pub fn skip_morph_frames(&mut self) -> crate::Result<u32> {
+    self.skip("morph", MORPH_FRAME_SIZE as u64)
+  }
+
+  pub fn skip_camera_frames(&mut self) -> crate::Result<u32> {
+    self.skip("camera", CAMERA_FRAME_SIZE as u64)
+  }
+
+  pub fn skip_light_frames(&mut self) -> crate::Result<u32> {
+    self.skip("light", LIGHT_FRAME_SIZE as u64)
+  }
+
+  pub fn skip_shadow_frames(&mut self) -> crate::Result<u32> {
+    self.skip("shadow", SHADOW_FRAME_SIZE as u64)
+  }
+
+  pub fn read_motion_frames<C: Config>(&mut self) -> crate::Result<Vec<MotionFrame<C>>> {
+    MotionFrame::read_all(&mut self.read)
+  }
+
+  pub fn read_morph_frames(&mut self) -> crate::Result<Vec<MorphFrame>> {
+    MorphFrame::read_all(&mut self.read)
+  }
+
+  pub fn read_camera_frames<C: Config>(&mut self) -> crate::Result<Vec<CameraFrame<C>>> {
+    CameraFrame::read_all(&mut self.read)
+  }
+
+  pub fn read_light_frames<C: Config>(&mut self) -> crate::Result<Vec<LightFrame<C>>> {
+    LightFrame::read_all(&mut self.read)
+  }
+
+  pub fn read_shadow_frames(&mut self) -> crate::Result<Vec<ShadowFrame>> {
+    ShadowFrame::read_all(&mut self.read)
+  }
+
+  /// Reads the last section, which older exporters omit entirely.
+  pub fn read_property_frames(&mut self) -> crate::Result<Vec<PropertyFrame>> {
+    PropertyFrame::read_all(&mut self.read)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::DefaultConfig;
+
+  const FIXTURE_MOTION_VMD: &[u8] = include_bytes!("../../fixtures/motion.vmd");
+  const FIXTURE_CAMERA_VMD: &[u8] = include_bytes!("../../fixtures/camera.vmd");
+
+  /// Counts the bytes actually read, as opposed to seeked over.
+  struct Counting<R> {
+    inner: R,
+    read: usize,
+  }
+
+  impl<R: Read> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+      let n = self.inner.read(buf)?;
+      self.read += n;
+      Ok(n)
+    }
+  }
+
+  impl<R: Seek> Seek for Counting<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+      self.inner.seek(pos)
+    }
+  }
+
+  #[test]
+  fn test_reader_skip_to_camera() {
+    let vmd = crate::vmd::Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(FIXTURE_CAMERA_VMD))
+      .unwrap();
+    let mut reader = VmdReader::new(std::io::Cursor::new(FIXTURE_CAMERA_VMD)).unwrap();
+
+    assert_eq!(reader.skip_motion_frames().unwrap(), 0);
+    assert_eq!(reader.skip_morph_frames().unwrap(), 0);
+    let camera_frames = reader.read_camera_frames::<DefaultConfig>().unwrap();
+
+    assert_eq!(camera_frames, vmd.camera_frames);
+    assert_eq!(
+      reader.read_light_frames::<DefaultConfig>().unwrap(),
+      vmd.light_frames
+    );
+    assert_eq!(reader.skip_shadow_frames().unwrap(), 2);
+    assert!(reader.read_property_frames().unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_reader_skip_motion() {
+    let counting = Counting {
+      inner: std::io::Cursor::new(FIXTURE_MOTION_VMD),
+      read: 0,
+    };
+    let mut reader = VmdReader::new(counting).unwrap();
+
+    assert_eq!(reader.skip_motion_frames().unwrap(), 164);
+    assert_eq!(reader.skip_morph_frames().unwrap(), 30);
+    assert!(reader
+      .read_camera_frames::<DefaultConfig>()
+      .unwrap()
+      .is_empty());
+
+    // Only the header and the three section counts were read
+    assert_eq!(reader.into_inner().read, 50 + 3 * 4);
+  }
+
+  #[test]
+  fn test_reader_skip_truncated() {
+    let end = 50 + 4 + 100 * 111;
+    let mut reader = VmdReader::new(std::io::Cursor::new(&FIXTURE_MOTION_VMD[..end])).unwrap();
+
+    let err = reader.skip_motion_frames().unwrap_err();
+
+    assert!(matches!(
+      err,
+      crate::Error::TruncatedSection {
+        section: "motion",
+        expected: 164,
+        read: 100,
+      }
+    ));
+  }
+}
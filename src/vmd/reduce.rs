@@ -0,0 +1,172 @@
+//! Removal of keyframes that interpolation reproduces, e.g. in motions baked on every frame.
+
+use std::collections::HashMap;
+
+use super::{BezierInterpolation, MotionFrame};
+use crate::math::{dot4, lerp3, slerp, to_array};
+use crate::Config;
+
+/// Angle in radians between two rotations.
+pub(super) fn angle_between(a: [f32; 4], b: [f32; 4]) -> f32 {
+  if a == b {
+    return 0.0;
+  }
+  2.0 * dot4(a, b).abs().min(1.0).acos()
+}
+
+pub(super) fn distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+  ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+/// Whether linearly interpolating from `frames[start]` to `frames[end]` reproduces every
+/// keyframe in between.
+fn within_tolerance<C: Config>(
+  frames: &[MotionFrame<C>],
+  start: usize,
+  end: usize,
+  position_tolerance: f32,
+  rotation_tolerance: f32,
+) -> bool {
+  let (first, last) = (&frames[start], &frames[end]);
+  let span = (last.frame_no - first.frame_no) as f32;
+
+  frames[start + 1..end].iter().all(|frame| {
+    let t = (frame.frame_no - first.frame_no) as f32 / span;
+    let position = lerp3(to_array(&first.position), to_array(&last.position), [t; 3]);
+    let rotation = slerp(to_array(&first.rotation), to_array(&last.rotation), t);
+
+    let position_error = if position == to_array(&frame.position) {
+      0.0
+    } else {
+      distance(position, to_array(&frame.position))
+    };
+
+    position_error <= position_tolerance
+      && angle_between(rotation, to_array(&frame.rotation)) <= rotation_tolerance
+  })
+}
+
+/// Removes bone keyframes whose position and rotation are reproduced within the tolerances by
+/// linearly interpolating between the keyframes kept around them.
+///
+/// The first and last keyframe of every bone are always kept, and the kept keyframes get linear
+/// interpolation curves. Frames end up grouped by bone in order of first appearance and sorted by
+/// `frame_no`. Returns the number of removed keyframes per bone.
+pub fn reduce_keyframes<C: Config>(
+  frames: &mut Vec<MotionFrame<C>>,
+  position_tolerance: f32,
+  rotation_tolerance: f32,
+) -> HashMap<String, usize> {
+  let mut order = Vec::new();
+  let mut tracks: HashMap<String, Vec<MotionFrame<C>>> = HashMap::new();
+  for frame in frames.drain(..) {
+    if !tracks.contains_key(&frame.name) {
+      order.push(frame.name.clone());
+    }
+    tracks.entry(frame.name.clone()).or_default().push(frame);
+  }
+
+  let mut removed = HashMap::new();
+  for name in order {
+    let mut track = tracks.remove(&name).unwrap_or_default();
+    track.sort_by_key(|f| f.frame_no);
+
+    let mut kept = vec![false; track.len()];
+    let mut start = 0;
+    while start + 1 < track.len() {
+      // Extend the segment as long as the skipped keyframes are reproduced
+      let mut end = start + 1;
+      while end + 1 < track.len()
+        && within_tolerance(
+          &track,
+          start,
+          end + 1,
+          position_tolerance,
+          rotation_tolerance,
+        )
+      {
+        end += 1;
+      }
+      kept[start] = true;
+      start = end;
+    }
+    if let Some(last) = kept.last_mut() {
+      *last = true;
+    }
+
+    let total = track.len();
+    let mut kept = kept.into_iter();
+    track.retain(|_| kept.next().unwrap_or(true));
+    removed.insert(name, total - track.len());
+
+    for frame in &mut track {
+      frame.set_interpolation_curves(&BezierInterpolation::LINEAR);
+    }
+    frames.append(&mut track);
+  }
+
+  removed
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::vmd::sampler::sample_bone;
+  use crate::DefaultConfig;
+
+  fn baked(name: &str, frames: u32, f: impl Fn(f32) -> (f32, f32)) -> Vec<MotionFrame> {
+    (0..frames)
+      .map(|frame_no| {
+        let (x, angle) = f(frame_no as f32);
+        MotionFrame::<DefaultConfig> {
+          name: name.to_string(),
+          raw_name: None,
+          frame_no,
+          position: [x, 0.0, 0.0].into(),
+          rotation: [0.0, (angle / 2.0).sin(), 0.0, (angle / 2.0).cos()].into(),
+          interpolation: [0; 64],
+        }
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_reduce_sine() {
+    let wave = |t: f32| ((t / 10.0).sin() * 10.0, (t / 10.0).sin() * 0.5);
+    let original = baked("センター", 121, wave);
+    let mut frames = original.clone();
+
+    let removed = reduce_keyframes(&mut frames, 0.05, 0.01);
+
+    assert_eq!(removed["センター"], original.len() - frames.len());
+    assert!(frames.len() < original.len() / 2);
+    assert_eq!(frames.first().unwrap().frame_no, 0);
+    assert_eq!(frames.last().unwrap().frame_no, 120);
+    assert!(frames
+      .iter()
+      .all(|f| f.interpolation_curves() == BezierInterpolation::LINEAR));
+
+    for frame in &original {
+      let sample = sample_bone(&frames, frame.frame_no as f32).unwrap();
+      let position_error = distance(to_array(&sample.position), to_array(&frame.position));
+      let rotation_error = angle_between(to_array(&sample.rotation), to_array(&frame.rotation));
+      assert!(position_error <= 0.05 + 1e-4, "{}", position_error);
+      assert!(rotation_error <= 0.01 + 1e-3, "{}", rotation_error);
+    }
+  }
+
+  #[test]
+  fn test_reduce_zero_tolerance() {
+    let mut frames = baked("センター", 30, |_| (1.0, 0.25));
+    frames.extend(baked("頭", 30, |t| ((t * 0.3).sin(), 0.0)));
+
+    let removed = reduce_keyframes(&mut frames, 0.0, 0.0);
+
+    // Only the constant track is redundant
+    assert_eq!(removed["センター"], 28);
+    assert_eq!(removed["頭"], 0);
+    assert_eq!(frames.len(), 2 + 30);
+    assert_eq!(frames[0].name, "センター");
+    assert_eq!(frames[1].frame_no, 29);
+  }
+}
@@ -0,0 +1,294 @@
+//! Renaming of bone and morph keyframes, e.g. to drive models with English bone names.
+
+use std::collections::{HashMap, HashSet};
+
+use super::writer::truncate_string;
+use super::{Vmd, VMD_BONE_NAME_SIZE};
+use crate::Config;
+
+/// Japanese names of the MMD semi-standard bones paired with their common English names.
+pub const STANDARD_BONE_NAMES: &[(&str, &str)] = &[
+  ("全ての親", "master"),
+  ("操作中心", "view cnt"),
+  ("センター", "center"),
+  ("グルーブ", "groove"),
+  ("腰", "waist"),
+  ("上半身", "upper body"),
+  ("上半身2", "upper body2"),
+  ("下半身", "lower body"),
+  ("首", "neck"),
+  ("頭", "head"),
+  ("両目", "eyes"),
+  ("左目", "eye_L"),
+  ("左肩P", "shoulderP_L"),
+  ("左肩", "shoulder_L"),
+  ("左肩C", "shoulderC_L"),
+  ("左腕", "arm_L"),
+  ("左腕捩", "arm twist_L"),
+  ("左ひじ", "elbow_L"),
+  ("左手捩", "wrist twist_L"),
+  ("左手首", "wrist_L"),
+  ("左親指０", "thumb0_L"),
+  ("左親指１", "thumb1_L"),
+  ("左親指２", "thumb2_L"),
+  ("左人指１", "fore1_L"),
+  ("左人指２", "fore2_L"),
+  ("左人指３", "fore3_L"),
+  ("左中指１", "middle1_L"),
+  ("左中指２", "middle2_L"),
+  ("左中指３", "middle3_L"),
+  ("左薬指１", "third1_L"),
+  ("左薬指２", "third2_L"),
+  ("左薬指３", "third3_L"),
+  ("左小指１", "little1_L"),
+  ("左小指２", "little2_L"),
+  ("左小指３", "little3_L"),
+  ("腰キャンセル左", "waist cancel_L"),
+  ("左足", "leg_L"),
+  ("左ひざ", "knee_L"),
+  ("左足首", "ankle_L"),
+  ("左つま先", "toe_L"),
+  ("左足ＩＫ", "leg IK_L"),
+  ("左つま先ＩＫ", "toe IK_L"),
+  ("左足D", "leg D_L"),
+  ("左ひざD", "knee D_L"),
+  ("左足首D", "ankle D_L"),
+  ("左足先EX", "toe EX_L"),
+  ("右目", "eye_R"),
+  ("右肩P", "shoulderP_R"),
+  ("右肩", "shoulder_R"),
+  ("右肩C", "shoulderC_R"),
+  ("右腕", "arm_R"),
+  ("右腕捩", "arm twist_R"),
+  ("右ひじ", "elbow_R"),
+  ("右手捩", "wrist twist_R"),
+  ("右手首", "wrist_R"),
+  ("右親指０", "thumb0_R"),
+  ("右親指１", "thumb1_R"),
+  ("右親指２", "thumb2_R"),
+  ("右人指１", "fore1_R"),
+  ("右人指２", "fore2_R"),
+  ("右人指３", "fore3_R"),
+  ("右中指１", "middle1_R"),
+  ("右中指２", "middle2_R"),
+  ("右中指３", "middle3_R"),
+  ("右薬指１", "third1_R"),
+  ("右薬指２", "third2_R"),
+  ("右薬指３", "third3_R"),
+  ("右小指１", "little1_R"),
+  ("右小指２", "little2_R"),
+  ("右小指３", "little3_R"),
+  ("腰キャンセル右", "waist cancel_R"),
+  ("右足", "leg_R"),
+  ("右ひざ", "knee_R"),
+  ("右足首", "ankle_R"),
+  ("右つま先", "toe_R"),
+  ("右足ＩＫ", "leg IK_R"),
+  ("右つま先ＩＫ", "toe IK_R"),
+  ("右足D", "leg D_R"),
+  ("右ひざD", "knee D_R"),
+  ("右足首D", "ankle D_R"),
+  ("右足先EX", "toe EX_R"),
+];
+
+/// Maps the Japanese semi-standard bone names to English, for `Vmd::rename_bones`.
+pub fn bone_names_ja_to_en() -> HashMap<String, String> {
+  STANDARD_BONE_NAMES
+    .iter()
+    .map(|&(ja, en)| (ja.to_string(), en.to_string()))
+    .collect()
+}
+
+/// Maps the English semi-standard bone names back to Japanese, for `Vmd::rename_bones`.
+pub fn bone_names_en_to_ja() -> HashMap<String, String> {
+  STANDARD_BONE_NAMES
+    .iter()
+    .map(|&(ja, en)| (en.to_string(), ja.to_string()))
+    .collect()
+}
+
+/// Result of looking up a motion bone name in a model, see `match_bone_names`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BoneMatch {
+  Found(String),
+  /// Several model bones truncate to the same name in a motion file.
+  Ambiguous(Vec<String>),
+  Missing,
+}
+
+/// Cuts `name` the way MMD does when saving a motion, at 15 Shift_JIS bytes.
+fn truncate_name(name: &str) -> String {
+  truncate_string(name, VMD_BONE_NAME_SIZE)
+}
+
+/// Looks up the bone names of a motion among the bone names of a model.
+///
+/// Exact matches win, otherwise the model names are compared after truncating them to the length
+/// stored in motion files.
+pub fn match_bone_names(
+  motion_names: &[String],
+  model_names: &[String],
+) -> HashMap<String, BoneMatch> {
+  let exact = model_names.iter().collect::<HashSet<_>>();
+
+  let mut truncated: HashMap<String, Vec<&String>> = HashMap::new();
+  for name in model_names {
+    let candidates = truncated.entry(truncate_name(name)).or_default();
+    if !candidates.contains(&name) {
+      candidates.push(name);
+    }
+  }
+
+  motion_names
+    .iter()
+    .map(|motion_name| {
+      let found = if exact.contains(motion_name) {
+        BoneMatch::Found(motion_name.clone())
+      } else {
+        match truncated
+          .get(&truncate_name(motion_name))
+          .map(Vec::as_slice)
+        {
+          Some([name]) => BoneMatch::Found(name.to_string()),
+          Some(names) => BoneMatch::Ambiguous(names.iter().map(|n| n.to_string()).collect()),
+          None => BoneMatch::Missing,
+        }
+      };
+
+      (motion_name.clone(), found)
+    })
+    .collect()
+}
+
+type NameMut<'a> = (&'a mut String, &'a mut Option<[u8; VMD_BONE_NAME_SIZE]>);
+
+fn rename<'a>(names: impl Iterator<Item = NameMut<'a>>, map: &HashMap<String, String>) -> usize {
+  let mut renamed = 0;
+  for (name, raw_name) in names {
+    if let Some(new_name) = map.get(name.as_str()) {
+      name.clone_from(new_name);
+      *raw_name = None;
+      renamed += 1;
+    }
+  }
+
+  renamed
+}
+
+impl<C: Config> Vmd<C> {
+  /// Renames bone keyframes found in `map`, returning how many keyframes were renamed.
+  ///
+  /// Names longer than the 15 bytes of the file format are truncated when written.
+  pub fn rename_bones(&mut self, map: &HashMap<String, String>) -> usize {
+    rename(
+      self
+        .motion_frames
+        .iter_mut()
+        .map(|f| (&mut f.name, &mut f.raw_name)),
+      map,
+    )
+  }
+
+  /// Renames morph keyframes found in `map`, returning how many keyframes were renamed.
+  pub fn rename_morphs(&mut self, map: &HashMap<String, String>) -> usize {
+    rename(
+      self
+        .morph_frames
+        .iter_mut()
+        .map(|f| (&mut f.name, &mut f.raw_name)),
+      map,
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::DefaultConfig;
+
+  const FIXTURE_MOTION_VMD: &[u8] = include_bytes!("../../fixtures/motion.vmd");
+
+  #[test]
+  fn test_rename_bones_motion() {
+    let mut vmd =
+      Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(FIXTURE_MOTION_VMD)).unwrap();
+    let centers = vmd
+      .motion_frames
+      .iter()
+      .filter(|f| f.name == "センター")
+      .count();
+
+    let renamed = vmd.rename_bones(&bone_names_ja_to_en());
+
+    assert!(renamed >= centers);
+    assert_eq!(
+      vmd
+        .motion_frames
+        .iter()
+        .filter(|f| f.name == "center")
+        .count(),
+      centers
+    );
+    assert!(vmd.motion_frames.iter().all(|f| f.name != "センター"));
+    // Hair is not a standard bone
+    assert!(vmd.motion_frames.iter().any(|f| f.name == "左髪１"));
+
+    assert_eq!(vmd.rename_bones(&bone_names_ja_to_en()), 0);
+    assert_eq!(vmd.rename_bones(&bone_names_en_to_ja()), renamed);
+    assert!(vmd.motion_frames.iter().any(|f| f.name == "センター"));
+  }
+
+  #[test]
+  fn test_rename_morphs() {
+    let mut vmd =
+      Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(FIXTURE_MOTION_VMD)).unwrap();
+    let mut map = HashMap::new();
+    map.insert("まばたき".to_string(), "blink".to_string());
+
+    let renamed = vmd.rename_morphs(&map);
+
+    assert!(renamed > 0);
+    assert_eq!(
+      vmd
+        .morph_frames
+        .iter()
+        .filter(|f| f.name == "blink")
+        .count(),
+      renamed
+    );
+  }
+
+  #[test]
+  fn test_match_bone_names() {
+    let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+    let model = names(&[
+      "センター",
+      "左腕捩れボーン１",
+      "左腕捩れボーン２",
+      "右腕捩れボーン本体",
+    ]);
+    let motion = names(&["センター", "左腕捩れボーン", "右腕捩れボーン", "尻尾"]);
+
+    let matches = match_bone_names(&motion, &model);
+
+    assert_eq!(
+      matches["センター"],
+      BoneMatch::Found("センター".to_string())
+    );
+    assert_eq!(
+      matches["右腕捩れボーン"],
+      BoneMatch::Found("右腕捩れボーン本体".to_string())
+    );
+    assert_eq!(
+      matches["左腕捩れボーン"],
+      BoneMatch::Ambiguous(names(&["左腕捩れボーン１", "左腕捩れボーン２"]))
+    );
+    assert_eq!(matches["尻尾"], BoneMatch::Missing);
+  }
+
+  #[test]
+  fn test_standard_bone_names_unique() {
+    assert_eq!(bone_names_ja_to_en().len(), STANDARD_BONE_NAMES.len());
+    assert_eq!(bone_names_en_to_ja().len(), STANDARD_BONE_NAMES.len());
+  }
+}
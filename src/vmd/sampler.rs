@@ -0,0 +1,500 @@
+//! Evaluation of keyframed bones, camera, light and shadow at arbitrary times.
+
+use super::{BezierCurve, CameraFrame, LightFrame, MotionFrame, ShadowFrame, ShadowMode};
+use crate::math::{lerp, lerp3, normalize3, slerp, to_array, Widen};
+use crate::{Config, DefaultConfig};
+
+/// Interpolated transform of a bone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoneSample<C: Config = DefaultConfig> {
+  pub position: C::Vec3,
+  pub rotation: C::Vec4,
+}
+
+/// Interpolated state of the camera.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraSample<C: Config = DefaultConfig> {
+  pub distance: f32,
+  pub position: C::Vec3,
+  pub rotation: C::Vec3,
+  pub fov: f32,
+  pub orthographic: bool,
+}
+
+impl<C: Config> From<&CameraFrame<C>> for CameraSample<C> {
+  fn from(frame: &CameraFrame<C>) -> Self {
+    Self {
+      distance: frame.distance,
+      position: frame.position.clone(),
+      rotation: frame.rotation.clone(),
+      fov: frame.fov as f32,
+      orthographic: frame.orthographic,
+    }
+  }
+}
+
+/// Interpolated state of the light.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LightSample<C: Config = DefaultConfig> {
+  pub color: C::Vec3,
+  /// Direction the light shines in, normalized.
+  pub direction: C::Vec3,
+}
+
+/// The light of a new MMD project, a grey of 154/255 shining down, right to left and away.
+impl<C: Config> Default for LightSample<C> {
+  fn default() -> Self {
+    Self {
+      color: [154.0 / 255.0; 3].widen(),
+      direction: normalize3([-0.5, -1.0, 0.5]).widen(),
+    }
+  }
+}
+
+/// Interpolated self shadow settings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShadowSample {
+  pub mode: ShadowMode,
+  /// Shadow range as stored in the file, `0.1 - range / 100000` of the range shown in MMD.
+  pub distance: f32,
+}
+
+/// The self shadow of a new MMD project, mode 1 up to a range of 8875.
+impl Default for ShadowSample {
+  fn default() -> Self {
+    Self {
+      mode: ShadowMode::Mode1,
+      distance: 0.1 - 8875.0 / 100_000.0,
+    }
+  }
+}
+
+impl BezierCurve {
+  fn control_points(&self) -> [(f32, f32); 4] {
+    [
+      (0.0, 0.0),
+      (self.x1 as f32 / 127.0, self.y1 as f32 / 127.0),
+      (self.x2 as f32 / 127.0, self.y2 as f32 / 127.0),
+      (1.0, 1.0),
+    ]
+  }
+
+  /// The curve parameter at which the x component reaches `x`.
+  pub(super) fn parameter_at(&self, x: f32) -> f32 {
+    let [_, (x1, _), (x2, _), _] = self.control_points();
+
+    // The x component is monotonic for control points within the unit square
+    let (mut low, mut high) = (0.0f32, 1.0f32);
+    for _ in 0..32 {
+      let mid = (low + high) * 0.5;
+      if bezier(x1, x2, mid) < x {
+        low = mid;
+      } else {
+        high = mid;
+      }
+    }
+
+    (low + high) * 0.5
+  }
+
+  /// Evaluates the curve at `x` in `0.0..=1.0`, returning the eased progress.
+  pub fn evaluate(&self, x: f32) -> f32 {
+    let x = x.clamp(0.0, 1.0);
+    let [_, (_, y1), (_, y2), _] = self.control_points();
+
+    bezier(y1, y2, self.parameter_at(x))
+  }
+
+  /// Splits the curve at `x` in `0.0..=1.0` into the curves of the two halves, each rescaled to
+  /// the unit square and rounded to the stored precision.
+  ///
+  /// A half without any progress, e.g. the start of a hold, gets the linear curve.
+  pub fn split(&self, x: f32) -> (BezierCurve, BezierCurve) {
+    let x = x.clamp(0.0, 1.0);
+    let t = self.parameter_at(x);
+    let [p0, p1, p2, p3] = self.control_points();
+
+    // de Casteljau
+    let mix = |a: (f32, f32), b: (f32, f32)| (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t);
+    let (q0, q1, q2) = (mix(p0, p1), mix(p1, p2), mix(p2, p3));
+    let (r0, r1) = (mix(q0, q1), mix(q1, q2));
+    let mid = mix(r0, r1);
+
+    let rescale = |a: (f32, f32), b: (f32, f32), start: (f32, f32), end: (f32, f32)| {
+      let (width, height) = (end.0 - start.0, end.1 - start.1);
+      if width <= f32::EPSILON || height.abs() <= f32::EPSILON {
+        return BezierCurve::LINEAR;
+      }
+      let quantize = |v: f32| (v * 127.0).round().clamp(0.0, 127.0) as u8;
+      BezierCurve {
+        x1: quantize((a.0 - start.0) / width),
+        y1: quantize((a.1 - start.1) / height),
+        x2: quantize((b.0 - start.0) / width),
+        y2: quantize((b.1 - start.1) / height),
+      }
+    };
+
+    (rescale(q0, r0, p0, mid), rescale(r1, q2, mid, p3))
+  }
+}
+
+fn bezier(p1: f32, p2: f32, t: f32) -> f32 {
+  let s = 1.0 - t;
+  3.0 * s * s * t * p1 + 3.0 * s * t * t * p2 + t * t * t
+}
+
+/// Samples the keyframes of a single bone, sorted by `frame_no`, at `frame`.
+///
+/// Times before the first keyframe clamp to it and times after the last one hold its value.
+/// Returns `None` when there are no keyframes.
+pub fn sample_bone<C: Config>(frames: &[MotionFrame<C>], frame: f32) -> Option<BoneSample<C>> {
+  let first = frames.first()?;
+  let next = frames.partition_point(|f| (f.frame_no as f32) <= frame);
+
+  if next == 0 {
+    return Some(BoneSample {
+      position: first.position.clone(),
+      rotation: first.rotation.clone(),
+    });
+  }
+
+  let prev = &frames[next - 1];
+  let next = match frames.get(next) {
+    Some(next) => next,
+    None => {
+      return Some(BoneSample {
+        position: prev.position.clone(),
+        rotation: prev.rotation.clone(),
+      })
+    }
+  };
+
+  // The curves of a keyframe describe the transition leading up to it
+  let curves = next.interpolation_curves();
+  let t = (frame - prev.frame_no as f32) / (next.frame_no - prev.frame_no) as f32;
+
+  Some(BoneSample {
+    position: lerp3(
+      to_array(&prev.position),
+      to_array(&next.position),
+      [
+        curves.x.evaluate(t),
+        curves.y.evaluate(t),
+        curves.z.evaluate(t),
+      ],
+    )
+    .widen(),
+    rotation: slerp(
+      to_array(&prev.rotation),
+      to_array(&next.rotation),
+      curves.rotation.evaluate(t),
+    )
+    .widen(),
+  })
+}
+
+/// Samples camera keyframes, sorted by `frame_no`, at `frame`.
+///
+/// Keyframes exactly one frame apart are a cut in MMD, so the earlier one is held until the
+/// later one is reached. Returns `None` when there are no keyframes.
+pub fn sample_camera<C: Config>(frames: &[CameraFrame<C>], frame: f32) -> Option<CameraSample<C>> {
+  let first = frames.first()?;
+  let next = frames.partition_point(|f| (f.frame_no as f32) <= frame);
+
+  if next == 0 {
+    return Some(first.into());
+  }
+
+  let prev = &frames[next - 1];
+  let next = match frames.get(next) {
+    Some(next) if next.frame_no - prev.frame_no > 1 => next,
+    _ => return Some(prev.into()),
+  };
+
+  let curves = next.interpolation_curves();
+  let t = (frame - prev.frame_no as f32) / (next.frame_no - prev.frame_no) as f32;
+  let rotation_t = curves.rotation.evaluate(t);
+
+  Some(CameraSample {
+    distance: lerp(prev.distance, next.distance, curves.distance.evaluate(t)),
+    position: lerp3(
+      to_array(&prev.position),
+      to_array(&next.position),
+      [
+        curves.x.evaluate(t),
+        curves.y.evaluate(t),
+        curves.z.evaluate(t),
+      ],
+    )
+    .widen(),
+    // Euler angles are interpolated as is, so MMD can spin the camera over multiple turns
+    rotation: lerp3(
+      to_array(&prev.rotation),
+      to_array(&next.rotation),
+      [rotation_t; 3],
+    )
+    .widen(),
+    fov: lerp(prev.fov as f32, next.fov as f32, curves.fov.evaluate(t)),
+    orthographic: prev.orthographic,
+  })
+}
+
+/// Samples light keyframes, sorted by `frame_no`, at `frame`.
+///
+/// Color and direction are interpolated linearly, times after the last keyframe hold it and times
+/// before the first one, or any time without keyframes, get `LightSample::default`.
+pub fn sample_light<C: Config>(frames: &[LightFrame<C>], frame: f32) -> LightSample<C> {
+  let next = frames.partition_point(|f| (f.frame_no as f32) <= frame);
+  let prev = match next.checked_sub(1) {
+    Some(prev) => &frames[prev],
+    None => return LightSample::default(),
+  };
+
+  let (color, direction) = match frames.get(next) {
+    Some(next) => {
+      let t = (frame - prev.frame_no as f32) / (next.frame_no - prev.frame_no) as f32;
+      (
+        lerp3(to_array(&prev.color), to_array(&next.color), [t; 3]),
+        lerp3(to_array(&prev.direction), to_array(&next.direction), [t; 3]),
+      )
+    }
+    None => (to_array(&prev.color), to_array(&prev.direction)),
+  };
+
+  LightSample {
+    color: color.widen(),
+    direction: normalize3(direction).widen(),
+  }
+}
+
+/// Samples self shadow keyframes, sorted by `frame_no`, at `frame`.
+///
+/// The mode is held until the next keyframe while the distance is interpolated linearly. Like
+/// `sample_light`, times before the first keyframe get `ShadowSample::default`.
+pub fn sample_shadow(frames: &[ShadowFrame], frame: f32) -> ShadowSample {
+  let next = frames.partition_point(|f| (f.frame_no as f32) <= frame);
+  let prev = match next.checked_sub(1) {
+    Some(prev) => &frames[prev],
+    None => return ShadowSample::default(),
+  };
+
+  let distance = match frames.get(next) {
+    Some(next) => {
+      let t = (frame - prev.frame_no as f32) / (next.frame_no - prev.frame_no) as f32;
+      lerp(prev.distance, next.distance, t)
+    }
+    None => prev.distance,
+  };
+
+  ShadowSample {
+    mode: prev.mode,
+    distance,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::vmd::BezierInterpolation;
+
+  const EPSILON: f32 = 1e-4;
+
+  fn frame(frame_no: u32, position: [f32; 3], rotation: [f32; 4]) -> MotionFrame {
+    MotionFrame::new("センター", frame_no, position, rotation)
+  }
+
+  fn assert_close(a: &[f32], b: &[f32]) {
+    for (a, b) in a.iter().zip(b) {
+      assert!((a - b).abs() < EPSILON, "{:?} != {:?}", a, b);
+    }
+  }
+
+  #[test]
+  fn test_sample_linear() {
+    let half = std::f32::consts::FRAC_1_SQRT_2;
+    let frames = [
+      frame(0, [0.0, 0.0, 0.0], [0.0, 0.0, 0.0, 1.0]),
+      frame(10, [10.0, -20.0, 5.0], [0.0, half, 0.0, half]),
+    ];
+
+    let sample = sample_bone(&frames, 5.0).unwrap();
+
+    // Halfway to a 90 degree rotation around Y is 45 degrees
+    let (sin, cos) = (
+      std::f32::consts::FRAC_PI_8.sin(),
+      std::f32::consts::FRAC_PI_8.cos(),
+    );
+    assert_close(sample.position.as_slice(), &[5.0, -10.0, 2.5]);
+    assert_close(sample.rotation.as_slice(), &[0.0, sin, 0.0, cos]);
+  }
+
+  #[test]
+  fn test_sample_eased() {
+    let mut frames = [
+      frame(0, [0.0, 0.0, 0.0], [0.0, 0.0, 0.0, 1.0]),
+      frame(8, [10.0, 10.0, 0.0], [0.0, 0.0, 0.0, 1.0]),
+    ];
+    let mut curves = BezierInterpolation::LINEAR;
+    curves.x = BezierCurve {
+      x1: 127,
+      y1: 0,
+      x2: 127,
+      y2: 127,
+    };
+    frames[1].set_interpolation_curves(&curves);
+
+    // At the curve parameter 0.5 the control points give x = 0.875 and y = 0.5
+    let sample = sample_bone(&frames, 7.0).unwrap();
+
+    assert_close(sample.position.as_slice(), &[5.0, 8.75, 0.0]);
+  }
+
+  #[test]
+  fn test_sample_clamp() {
+    let frames = [
+      frame(10, [1.0, 2.0, 3.0], [0.0, 0.0, 0.0, 1.0]),
+      frame(20, [4.0, 5.0, 6.0], [0.0, 0.0, 0.0, 1.0]),
+    ];
+
+    assert_close(
+      sample_bone(&frames, 0.0).unwrap().position.as_slice(),
+      &[1.0, 2.0, 3.0],
+    );
+    assert_close(
+      sample_bone(&frames, 20.0).unwrap().position.as_slice(),
+      &[4.0, 5.0, 6.0],
+    );
+    assert_close(
+      sample_bone(&frames, 100.0).unwrap().position.as_slice(),
+      &[4.0, 5.0, 6.0],
+    );
+    assert!(sample_bone::<DefaultConfig>(&[], 0.0).is_none());
+  }
+
+  const FIXTURE_CAMERA_VMD: &[u8] = include_bytes!("../../fixtures/camera.vmd");
+
+  fn camera(frame_no: u32, distance: f32, x: f32, fov: u32) -> CameraFrame {
+    CameraFrame {
+      frame_no,
+      distance,
+      position: [x, 10.0, 0.0].into(),
+      rotation: [0.0; 3].into(),
+      interpolation: crate::vmd::CameraInterpolation::LINEAR.to_bytes(),
+      fov,
+      orthographic: false,
+    }
+  }
+
+  #[test]
+  fn test_sample_camera_fixture() {
+    let vmd = crate::vmd::Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(FIXTURE_CAMERA_VMD))
+      .unwrap();
+    let frames = &vmd.camera_frames;
+
+    let first = sample_camera(frames, 0.0).unwrap();
+    assert_eq!(first.distance, -180.0);
+    assert_eq!(first.position, frames[0].position);
+    assert_eq!(first.fov, 30.0);
+
+    // The keyframes are one frame apart, which is a cut
+    assert_eq!(sample_camera(frames, 0.5).unwrap(), first);
+
+    let second = sample_camera(frames, 1.0).unwrap();
+    assert_eq!(second.position, frames[1].position);
+    assert_eq!(second.rotation, frames[1].rotation);
+    assert_eq!(sample_camera(frames, 100.0).unwrap(), second);
+  }
+
+  #[test]
+  fn test_sample_camera_between() {
+    let frames = [camera(0, -10.0, 0.0, 30), camera(10, -30.0, 20.0, 50)];
+
+    let sample = sample_camera(&frames, 5.0).unwrap();
+
+    assert!((sample.distance + 20.0).abs() < EPSILON);
+    assert_close(sample.position.as_slice(), &[10.0, 10.0, 0.0]);
+    assert!((sample.fov - 40.0).abs() < EPSILON);
+  }
+
+  fn light(frame_no: u32, color: f32, direction: [f32; 3]) -> LightFrame {
+    LightFrame {
+      frame_no,
+      color: [color; 3].into(),
+      direction: direction.into(),
+    }
+  }
+
+  #[test]
+  fn test_sample_light_fixture() {
+    let vmd = crate::vmd::Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(FIXTURE_CAMERA_VMD))
+      .unwrap();
+    let frames = &vmd.light_frames;
+
+    let first = sample_light(frames, 0.0);
+    assert_close(first.color.as_slice(), frames[0].color.as_slice());
+    assert_close(
+      first.direction.as_slice(),
+      LightSample::<DefaultConfig>::default().direction.as_slice(),
+    );
+
+    let half = sample_light(frames, 0.5);
+    // The second keyframe is black
+    let color = to_array::<3>(&frames[0].color)[0] / 2.0;
+    assert_close(half.color.as_slice(), &[color; 3]);
+    assert_eq!(sample_light(frames, 10.0), sample_light(frames, 1.0));
+  }
+
+  #[test]
+  fn test_sample_light_between() {
+    let frames = [
+      light(10, 0.0, [1.0, 0.0, 0.0]),
+      light(20, 1.0, [0.0, 0.0, 2.0]),
+    ];
+
+    let sample = sample_light(&frames, 15.0);
+    assert_close(sample.color.as_slice(), &[0.5; 3]);
+    // Halfway between the directions, normalized
+    let expected = [0.5 / 1.25f32.sqrt(), 0.0, 1.0 / 1.25f32.sqrt()];
+    assert_close(sample.direction.as_slice(), &expected);
+
+    assert_close(
+      sample_light(&frames, 30.0).direction.as_slice(),
+      &[0.0, 0.0, 1.0],
+    );
+    assert_eq!(sample_light(&frames, 5.0), LightSample::default());
+    assert_eq!(
+      sample_light::<DefaultConfig>(&[], 5.0),
+      LightSample::default()
+    );
+  }
+
+  #[test]
+  fn test_sample_shadow() {
+    let vmd = crate::vmd::Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(FIXTURE_CAMERA_VMD))
+      .unwrap();
+    let half = sample_shadow(&vmd.shadow_frames, 0.5);
+    assert_eq!(half.mode, ShadowMode::Mode1);
+    assert!(
+      (half.distance - (vmd.shadow_frames[0].distance + vmd.shadow_frames[1].distance) / 2.0).abs()
+        < EPSILON
+    );
+
+    let frames = [
+      ShadowFrame {
+        frame_no: 0,
+        mode: ShadowMode::Off,
+        distance: 0.0,
+      },
+      ShadowFrame {
+        frame_no: 10,
+        mode: ShadowMode::Mode2,
+        distance: 0.05,
+      },
+    ];
+    let sample = sample_shadow(&frames, 9.0);
+    assert_eq!(sample.mode, ShadowMode::Off);
+    assert!((sample.distance - 0.045).abs() < EPSILON);
+    assert_eq!(sample_shadow(&frames, 10.0).mode, ShadowMode::Mode2);
+    assert_eq!(sample_shadow(&[], 0.0), ShadowSample::default());
+  }
+}
@@ -0,0 +1,363 @@
+//! Extraction of a frame range into a motion of its own.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use super::keyframe::Keyframe;
+use super::sampler::{sample_bone, sample_camera};
+use super::track::MorphTrack;
+use super::{
+  BezierInterpolation, CameraFrame, CameraInterpolation, LightFrame, MorphFrame, MotionFrame, Vmd,
+  VmdHeader,
+};
+use crate::math::{lerp3, to_array, Widen};
+use crate::Config;
+
+/// Keeps the keyframes within `range`, rebased to start at frame 0.
+///
+/// Every track with keyframes before the range gets a keyframe at its start, and every track with
+/// keyframes after the range one at its last frame, both built by `boundary` from the sorted
+/// keyframes of the track. Output is sorted by frame number, tracks in order of first appearance.
+fn slice_frames<T: Keyframe>(
+  frames: &[T],
+  range: &Range<u32>,
+  boundary: impl Fn(&[T], u32) -> T,
+) -> Vec<T> {
+  let mut order: Vec<&str> = Vec::new();
+  let mut tracks: HashMap<&str, Vec<T>> = HashMap::new();
+  for frame in frames {
+    let track = tracks.entry(frame.track()).or_insert_with(|| {
+      order.push(frame.track());
+      Vec::new()
+    });
+    track.push(frame.with_frame_no(frame.frame_no()));
+  }
+
+  let mut sliced = Vec::new();
+  if range.is_empty() {
+    return sliced;
+  }
+  let last = range.end - 1;
+
+  for name in order {
+    let mut track = tracks.remove(name).unwrap_or_default();
+    // Stable, so the later one of duplicated frame numbers wins like in the sampler
+    track.sort_by_key(T::frame_no);
+
+    let has = |frame_no: u32| track.iter().any(|f| f.frame_no() == frame_no);
+    if !has(range.start) && track.iter().any(|f| f.frame_no() < range.start) {
+      sliced.push(boundary(&track, range.start).with_frame_no(0));
+    }
+    sliced.extend(
+      track
+        .iter()
+        .filter(|f| range.contains(&f.frame_no()))
+        .map(|f| f.with_frame_no(f.frame_no() - range.start)),
+    );
+    if !has(last) && track.iter().any(|f| f.frame_no() > last) {
+      sliced.push(boundary(&track, last).with_frame_no(last - range.start));
+    }
+  }
+
+  sliced.sort_by_key(T::frame_no);
+  sliced
+}
+
+/// The last keyframe at or before `frame_no`, for sections that hold their value until the next.
+fn held<T: Keyframe>(frames: &[T], frame_no: u32) -> T {
+  let next = frames.partition_point(|f| f.frame_no() <= frame_no);
+  frames[next.saturating_sub(1)].with_frame_no(frame_no)
+}
+
+fn boundary_bone<C: Config>(frames: &[MotionFrame<C>], frame_no: u32) -> MotionFrame<C> {
+  let first = &frames[0];
+  let sample = sample_bone(frames, frame_no as f32).unwrap();
+  let mut frame = MotionFrame {
+    name: first.name.clone(),
+    raw_name: first.raw_name,
+    frame_no,
+    position: sample.position,
+    rotation: sample.rotation,
+    interpolation: [0; 64],
+  };
+  frame.set_interpolation_curves(&BezierInterpolation::LINEAR);
+  frame
+}
+
+fn boundary_morph(frames: &[MorphFrame], frame_no: u32) -> MorphFrame {
+  let track = MorphTrack::from_frames(frames.to_vec());
+  MorphFrame {
+    weight: track.weight_at(frame_no as f32).unwrap(),
+    ..held(frames, frame_no)
+  }
+}
+
+fn boundary_camera<C: Config>(frames: &[CameraFrame<C>], frame_no: u32) -> CameraFrame<C> {
+  let sample = sample_camera(frames, frame_no as f32).unwrap();
+  let mut frame = CameraFrame {
+    frame_no,
+    distance: sample.distance,
+    position: sample.position,
+    rotation: sample.rotation,
+    interpolation: [0; 24],
+    fov: sample.fov.round() as u32,
+    orthographic: sample.orthographic,
+  };
+  frame.set_interpolation_curves(&CameraInterpolation::LINEAR);
+  frame
+}
+
+/// Lights are interpolated linearly in MMD.
+fn boundary_light<C: Config>(frames: &[LightFrame<C>], frame_no: u32) -> LightFrame<C> {
+  let next = frames.partition_point(|f| f.frame_no <= frame_no);
+  let (prev, next) = match (next.checked_sub(1), frames.get(next)) {
+    (Some(prev), Some(next)) => (&frames[prev], next),
+    _ => return held(frames, frame_no),
+  };
+
+  let t = (frame_no - prev.frame_no) as f32 / (next.frame_no - prev.frame_no) as f32;
+  LightFrame {
+    frame_no,
+    color: lerp3(to_array(&prev.color), to_array(&next.color), [t; 3]).widen(),
+    direction: lerp3(to_array(&prev.direction), to_array(&next.direction), [t; 3]).widen(),
+  }
+}
+
+impl<C: Config> Vmd<C> {
+  /// Extracts the keyframes within `range` into a new motion starting at frame 0.
+  ///
+  /// Tracks cut by the range get keyframes sampled at its first and last frame, so the excerpt
+  /// doesn't start or end mid-interpolation. The synthesized keyframes use linear curves, so the
+  /// segments next to a cut only approximate the original easing.
+  pub fn slice(&self, range: Range<u32>) -> Vmd<C> {
+    Vmd {
+      header: VmdHeader {
+        version: self.header.version,
+        raw_signature: self.header.raw_signature,
+        model_name: self.header.model_name.clone(),
+        raw_model_name: self.header.raw_model_name.clone(),
+      },
+      motion_frames: slice_frames(&self.motion_frames, &range, boundary_bone),
+      morph_frames: slice_frames(&self.morph_frames, &range, boundary_morph),
+      camera_frames: slice_frames(&self.camera_frames, &range, boundary_camera),
+      light_frames: slice_frames(&self.light_frames, &range, boundary_light),
+      shadow_frames: slice_frames(&self.shadow_frames, &range, held),
+      property_frames: slice_frames(&self.property_frames, &range, held),
+      trailing_bytes: 0,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::vmd::track::BoneTrackSet;
+  use crate::vmd::{BezierCurve, PropertyFrame};
+  use crate::DefaultConfig;
+
+  const EPSILON: f32 = 1e-4;
+
+  fn bone(name: &str, frame_no: u32, x: f32, angle: f32) -> MotionFrame {
+    let (sin, cos) = (angle * 0.5).sin_cos();
+    let mut frame = MotionFrame {
+      name: name.to_string(),
+      raw_name: None,
+      frame_no,
+      position: [x, 0.0, 0.0].into(),
+      rotation: [0.0, sin, 0.0, cos].into(),
+      interpolation: [0; 64],
+    };
+    let ease = BezierCurve {
+      x1: 100,
+      y1: 0,
+      x2: 27,
+      y2: 127,
+    };
+    frame.set_interpolation_curves(&BezierInterpolation {
+      x: ease,
+      y: ease,
+      z: ease,
+      rotation: ease,
+    });
+    frame
+  }
+
+  fn camera(frame_no: u32, distance: f32, fov: u32) -> CameraFrame {
+    CameraFrame {
+      frame_no,
+      distance,
+      position: [0.0, 10.0, 0.0].into(),
+      rotation: [0.0, distance / 10.0, 0.0].into(),
+      interpolation: CameraInterpolation::LINEAR.to_bytes(),
+      fov,
+      orthographic: false,
+    }
+  }
+
+  fn motion() -> Vmd {
+    Vmd {
+      motion_frames: vec![
+        bone("センター", 0, 0.0, 0.0),
+        bone("左腕", 10, 5.0, 1.0),
+        bone("センター", 100, 10.0, 2.0),
+        bone("センター", 200, -10.0, 0.5),
+        bone("左腕", 120, 0.0, 0.0),
+        bone("首", 0, 1.0, 1.0),
+      ],
+      morph_frames: vec![
+        MorphFrame {
+          name: "まばたき".to_string(),
+          raw_name: None,
+          frame_no: 0,
+          weight: 0.0,
+        },
+        MorphFrame {
+          name: "まばたき".to_string(),
+          raw_name: None,
+          frame_no: 100,
+          weight: 1.0,
+        },
+      ],
+      camera_frames: vec![camera(0, -50.0, 30), camera(100, -10.0, 40)],
+      light_frames: vec![
+        LightFrame {
+          frame_no: 0,
+          color: [0.0, 0.0, 0.0].into(),
+          direction: [1.0, 0.0, 0.0].into(),
+        },
+        LightFrame {
+          frame_no: 100,
+          color: [1.0, 1.0, 1.0].into(),
+          direction: [0.0, 0.0, 1.0].into(),
+        },
+      ],
+      property_frames: vec![
+        PropertyFrame {
+          frame_no: 0,
+          visible: true,
+          ik_states: vec![("左足ＩＫ".to_string(), true)],
+        },
+        PropertyFrame {
+          frame_no: 60,
+          visible: false,
+          ik_states: vec![("左足ＩＫ".to_string(), false)],
+        },
+      ],
+      ..Default::default()
+    }
+  }
+
+  fn assert_near(a: &[f32], b: &[f32]) {
+    assert!(
+      a.iter().zip(b).all(|(a, b)| (a - b).abs() < EPSILON),
+      "{:?} != {:?}",
+      a,
+      b
+    );
+  }
+
+  #[test]
+  fn test_slice_boundaries() {
+    let vmd = motion();
+    let sliced = vmd.slice(50..150);
+
+    let tracks = BoneTrackSet::from_frames(vmd.motion_frames.clone());
+    let center: Vec<_> = sliced
+      .motion_frames
+      .iter()
+      .filter(|f| f.name == "センター")
+      .collect();
+    assert_eq!(
+      center.iter().map(|f| f.frame_no).collect::<Vec<_>>(),
+      [0, 50, 99]
+    );
+    for (frame, original) in center.iter().zip(&[50.0, 100.0, 149.0]) {
+      let sample = tracks.track("センター").unwrap().sample(*original).unwrap();
+      assert_near(frame.position.as_slice(), sample.position.as_slice());
+      assert_near(frame.rotation.as_slice(), sample.rotation.as_slice());
+    }
+    // Kept keyframes carry their curves, the synthesized ones are linear
+    assert_eq!(
+      center[1].interpolation[..],
+      vmd.motion_frames[2].interpolation[..]
+    );
+    assert_eq!(
+      center[0].interpolation_curves(),
+      BezierInterpolation::LINEAR
+    );
+
+    // 左腕 ends within the range and 首 before it, both just hold their pose
+    let arm: Vec<_> = sliced
+      .motion_frames
+      .iter()
+      .filter(|f| f.name == "左腕")
+      .map(|f| f.frame_no)
+      .collect();
+    assert_eq!(arm, [0, 70]);
+    let neck: Vec<_> = sliced
+      .motion_frames
+      .iter()
+      .filter(|f| f.name == "首")
+      .collect();
+    assert_eq!(neck.len(), 1);
+    assert_eq!(neck[0].frame_no, 0);
+    assert_near(neck[0].position.as_slice(), &[1.0, 0.0, 0.0]);
+
+    let blink: Vec<_> = sliced
+      .morph_frames
+      .iter()
+      .map(|f| (f.frame_no, f.weight))
+      .collect();
+    assert_eq!(blink, [(0, 0.5), (50, 1.0)]);
+
+    let expected = sample_camera(&vmd.camera_frames, 50.0).unwrap();
+    assert_eq!(sliced.camera_frames.len(), 2);
+    assert_eq!(sliced.camera_frames[0].frame_no, 0);
+    assert!((sliced.camera_frames[0].distance - expected.distance).abs() < EPSILON);
+    assert_near(
+      sliced.camera_frames[0].rotation.as_slice(),
+      expected.rotation.as_slice(),
+    );
+    assert_eq!(sliced.camera_frames[0].fov, 35);
+
+    assert_near(sliced.light_frames[0].color.as_slice(), &[0.5, 0.5, 0.5]);
+    assert_eq!(sliced.light_frames[1].frame_no, 50);
+
+    let properties: Vec<_> = sliced
+      .property_frames
+      .iter()
+      .map(|f| (f.frame_no, f.visible))
+      .collect();
+    assert_eq!(properties, [(0, true), (10, false)]);
+  }
+
+  #[test]
+  fn test_slice_header_and_empty() {
+    let mut vmd = motion();
+    vmd.header.set_model_name("初音ミク");
+
+    let sliced = vmd.slice(300..400);
+    assert_eq!(sliced.header.model_name, "初音ミク");
+    assert_eq!(sliced.header.version, vmd.header.version);
+    // Everything ended before the range, so only the held poses remain
+    assert!(sliced.motion_frames.iter().all(|f| f.frame_no == 0));
+    assert_eq!(sliced.motion_frames.len(), 3);
+
+    let empty = vmd.slice(100..100);
+    assert_eq!(empty.header.model_name, "初音ミク");
+    assert!(empty.motion_frames.is_empty());
+    assert!(empty.property_frames.is_empty());
+  }
+
+  #[test]
+  fn test_slice_fixture() {
+    let vmd = Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(include_bytes!(
+      "../../fixtures/motion.vmd"
+    )))
+    .unwrap();
+
+    assert_eq!(vmd.slice(0..2), vmd.slice(0..2).slice(0..2));
+    assert_eq!(vmd.slice(0..2).motion_frames.len(), vmd.motion_frames.len());
+    assert_eq!(vmd.slice(1..2).summary().bones, vmd.summary().bones);
+  }
+}
@@ -0,0 +1,309 @@
+//! Duration and keyframe statistics of a motion.
+
+use std::collections::{HashMap, HashSet};
+
+use super::Vmd;
+use crate::math::to_array;
+use crate::Config;
+
+/// Keyframe counts of a motion, see `Vmd::summary`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct VmdSummary {
+  pub bone_keyframes: usize,
+  pub morph_keyframes: usize,
+  pub camera_keyframes: usize,
+  /// Number of distinct bone names among the bone keyframes.
+  pub bones: usize,
+  /// Number of distinct morph names among the morph keyframes.
+  pub morphs: usize,
+}
+
+/// Order of the per-bone and per-morph statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum StatisticsOrder {
+  /// In order of the first keyframe of each name in the file.
+  #[default]
+  FirstAppearance,
+  /// Most keyframes first, ties in order of first appearance.
+  KeyframesDescending,
+}
+
+/// Keyframes of a single bone, see `Vmd::bone_statistics`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoneStats {
+  pub name: String,
+  pub keyframes: usize,
+  pub first_frame: u32,
+  pub last_frame: u32,
+  /// Component-wise minimum of the positions.
+  pub position_min: [f32; 3],
+  /// Component-wise maximum of the positions.
+  pub position_max: [f32; 3],
+  /// Whether any keyframe rotates the bone away from its rest pose.
+  pub rotates: bool,
+}
+
+/// Keyframes of a single morph, see `Vmd::morph_statistics`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MorphStats {
+  pub name: String,
+  pub keyframes: usize,
+  pub first_frame: u32,
+  pub last_frame: u32,
+  pub weight_min: f32,
+  pub weight_max: f32,
+}
+
+/// Aggregates frames per name in a single pass, in order of first appearance.
+fn aggregate<'a, T: 'a, S>(
+  frames: impl Iterator<Item = &'a T>,
+  name: fn(&T) -> &str,
+  init: impl Fn(&T) -> S,
+  update: impl Fn(&mut S, &T),
+) -> Vec<S> {
+  let mut index: HashMap<&str, usize> = HashMap::new();
+  let mut stats = Vec::new();
+
+  for frame in frames {
+    match index.get(name(frame)) {
+      Some(&i) => update(&mut stats[i], frame),
+      None => {
+        index.insert(name(frame), stats.len());
+        stats.push(init(frame));
+      }
+    }
+  }
+
+  stats
+}
+
+fn sort_stats<S>(stats: &mut [S], order: StatisticsOrder, keyframes: fn(&S) -> usize) {
+  if order == StatisticsOrder::KeyframesDescending {
+    // Stable, so ties keep their order of first appearance
+    stats.sort_by_key(|s| std::cmp::Reverse(keyframes(s)));
+  }
+}
+
+impl<C: Config> Vmd<C> {
+  /// Keyframe counts, frame range and extent of every bone.
+  pub fn bone_statistics(&self, order: StatisticsOrder) -> Vec<BoneStats> {
+    let rotates = |rotation: &C::Vec4| {
+      let [x, y, z, _] = to_array(rotation);
+      x.abs() > 1e-6 || y.abs() > 1e-6 || z.abs() > 1e-6
+    };
+
+    let mut stats = aggregate(
+      self.motion_frames.iter(),
+      |f| &f.name,
+      |f| BoneStats {
+        name: f.name.clone(),
+        keyframes: 1,
+        first_frame: f.frame_no,
+        last_frame: f.frame_no,
+        position_min: to_array(&f.position),
+        position_max: to_array(&f.position),
+        rotates: rotates(&f.rotation),
+      },
+      |stats, f| {
+        let position = to_array::<3>(&f.position);
+        stats.keyframes += 1;
+        stats.first_frame = stats.first_frame.min(f.frame_no);
+        stats.last_frame = stats.last_frame.max(f.frame_no);
+        for (i, &v) in position.iter().enumerate() {
+          stats.position_min[i] = stats.position_min[i].min(v);
+          stats.position_max[i] = stats.position_max[i].max(v);
+        }
+        stats.rotates |= rotates(&f.rotation);
+      },
+    );
+    sort_stats(&mut stats, order, |s| s.keyframes);
+
+    stats
+  }
+
+  /// Keyframe counts, frame range and weight range of every morph.
+  pub fn morph_statistics(&self, order: StatisticsOrder) -> Vec<MorphStats> {
+    let mut stats = aggregate(
+      self.morph_frames.iter(),
+      |f| &f.name,
+      |f| MorphStats {
+        name: f.name.clone(),
+        keyframes: 1,
+        first_frame: f.frame_no,
+        last_frame: f.frame_no,
+        weight_min: f.weight,
+        weight_max: f.weight,
+      },
+      |stats, f| {
+        stats.keyframes += 1;
+        stats.first_frame = stats.first_frame.min(f.frame_no);
+        stats.last_frame = stats.last_frame.max(f.frame_no);
+        stats.weight_min = stats.weight_min.min(f.weight);
+        stats.weight_max = stats.weight_max.max(f.weight);
+      },
+    );
+    sort_stats(&mut stats, order, |s| s.keyframes);
+
+    stats
+  }
+
+  /// Returns the largest `frame_no` across all sections, or 0 for an empty motion.
+  pub fn max_frame(&self) -> u32 {
+    let frame_nos = self
+      .motion_frames
+      .iter()
+      .map(|f| f.frame_no)
+      .chain(self.morph_frames.iter().map(|f| f.frame_no))
+      .chain(self.camera_frames.iter().map(|f| f.frame_no))
+      .chain(self.light_frames.iter().map(|f| f.frame_no))
+      .chain(self.shadow_frames.iter().map(|f| f.frame_no))
+      .chain(self.property_frames.iter().map(|f| f.frame_no));
+
+    frame_nos.max().unwrap_or(0)
+  }
+
+  /// Time of the last keyframe when played back at `fps` frames per second.
+  pub fn duration_seconds(&self, fps: f32) -> f32 {
+    self.max_frame() as f32 / fps
+  }
+
+  pub fn summary(&self) -> VmdSummary {
+    let bones = self
+      .motion_frames
+      .iter()
+      .map(|f| f.name.as_str())
+      .collect::<HashSet<_>>();
+    let morphs = self
+      .morph_frames
+      .iter()
+      .map(|f| f.name.as_str())
+      .collect::<HashSet<_>>();
+
+    VmdSummary {
+      bone_keyframes: self.motion_frames.len(),
+      morph_keyframes: self.morph_frames.len(),
+      camera_keyframes: self.camera_frames.len(),
+      bones: bones.len(),
+      morphs: morphs.len(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::vmd::{MotionFrame, ShadowFrame, ShadowMode};
+  use crate::DefaultConfig;
+
+  const FIXTURE_MOTION_VMD: &[u8] = include_bytes!("../../fixtures/motion.vmd");
+  const FIXTURE_CAMERA_VMD: &[u8] = include_bytes!("../../fixtures/camera.vmd");
+
+  #[test]
+  fn test_summary_motion() {
+    let vmd = Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(FIXTURE_MOTION_VMD)).unwrap();
+
+    assert_eq!(vmd.max_frame(), 1);
+    assert_eq!(
+      vmd.summary(),
+      VmdSummary {
+        bone_keyframes: 164,
+        morph_keyframes: 30,
+        camera_keyframes: 0,
+        bones: 82,
+        morphs: 15,
+      }
+    );
+  }
+
+  #[test]
+  fn test_summary_camera() {
+    let vmd = Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(FIXTURE_CAMERA_VMD)).unwrap();
+
+    assert_eq!(vmd.max_frame(), 1);
+    assert_eq!(vmd.duration_seconds(30.0), 1.0 / 30.0);
+    assert_eq!(vmd.summary().camera_keyframes, 2);
+    assert_eq!(vmd.summary().bones, 0);
+  }
+
+  #[test]
+  fn test_summary_empty() {
+    let vmd = Vmd::<DefaultConfig>::default();
+
+    assert_eq!(vmd.max_frame(), 0);
+    assert_eq!(vmd.duration_seconds(30.0), 0.0);
+    assert_eq!(vmd.summary(), VmdSummary::default());
+  }
+
+  #[test]
+  fn test_max_frame_any_section() {
+    let mut vmd = Vmd::<DefaultConfig>::default();
+    vmd.shadow_frames.push(ShadowFrame {
+      frame_no: 300,
+      mode: ShadowMode::Mode1,
+      distance: 0.1,
+    });
+
+    assert_eq!(vmd.max_frame(), 300);
+    assert_eq!(vmd.duration_seconds(30.0), 10.0);
+  }
+
+  #[test]
+  fn test_statistics_fixture() {
+    let vmd = Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(FIXTURE_MOTION_VMD)).unwrap();
+
+    let bones = vmd.bone_statistics(StatisticsOrder::FirstAppearance);
+    assert_eq!(bones.len(), 82);
+    assert_eq!(bones[0].name, "センター");
+    assert_eq!(bones.iter().map(|s| s.keyframes).sum::<usize>(), 164);
+    assert!(bones.iter().all(|s| s.first_frame <= s.last_frame));
+
+    let morphs = vmd.morph_statistics(StatisticsOrder::KeyframesDescending);
+    assert_eq!(morphs.len(), 15);
+    assert!(morphs.windows(2).all(|w| w[0].keyframes >= w[1].keyframes));
+    let blink = morphs.iter().find(|s| s.name == "まばたき").unwrap();
+    assert!(blink.weight_min <= blink.weight_max);
+  }
+
+  #[test]
+  fn test_bone_statistics() {
+    let frame = |name: &str, frame_no: u32, position: [f32; 3], rotation: [f32; 4]| MotionFrame {
+      name: name.to_string(),
+      raw_name: None,
+      frame_no,
+      position: position.into(),
+      rotation: rotation.into(),
+      interpolation: [0; 64],
+    };
+    let vmd = Vmd::<DefaultConfig> {
+      motion_frames: vec![
+        frame("首", 30, [0.0; 3], [0.0, 0.0, 0.0, 1.0]),
+        frame("センター", 10, [1.0, -2.0, 0.0], [0.0, 0.0, 0.0, 1.0]),
+        frame("センター", 0, [-1.0, 3.0, 0.5], [0.0, 0.0, 0.0, -1.0]),
+        frame("首", 0, [0.0; 3], [0.0, 0.1, 0.0, 0.995]),
+        frame("センター", 5, [0.0; 3], [0.0, 0.0, 0.0, 1.0]),
+      ],
+      ..Default::default()
+    };
+
+    let stats = vmd.bone_statistics(StatisticsOrder::KeyframesDescending);
+
+    assert_eq!(
+      stats[0],
+      BoneStats {
+        name: "センター".to_string(),
+        keyframes: 3,
+        first_frame: 0,
+        last_frame: 10,
+        position_min: [-1.0, -2.0, 0.0],
+        position_max: [1.0, 3.0, 0.5],
+        rotates: false,
+      }
+    );
+    assert_eq!(stats[1].name, "首");
+    assert_eq!((stats[1].first_frame, stats[1].last_frame), (0, 30));
+    assert!(stats[1].rotates);
+
+    let stats = vmd.bone_statistics(StatisticsOrder::FirstAppearance);
+    assert_eq!(stats[0].name, "首");
+  }
+}
@@ -0,0 +1,299 @@
+//! Keyframes grouped per bone or morph.
+
+use std::collections::HashMap;
+
+use super::sampler::{sample_bone, BoneSample};
+use super::{MorphFrame, MotionFrame};
+use crate::math::lerp;
+use crate::{Config, DefaultConfig};
+
+/// Sorts keyframes by frame number, keeping the later one of duplicated frame numbers.
+fn sort_keyframes<T>(mut frames: Vec<T>, frame_no: fn(&T) -> u32) -> Vec<T> {
+  frames.sort_by_key(frame_no);
+
+  let mut deduped: Vec<T> = Vec::with_capacity(frames.len());
+  for frame in frames {
+    match deduped.last_mut() {
+      Some(last) if frame_no(last) == frame_no(&frame) => *last = frame,
+      _ => deduped.push(frame),
+    }
+  }
+
+  deduped
+}
+
+/// Keyframes of a single bone, sorted by `frame_no` without duplicates.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoneTrack<C: Config = DefaultConfig> {
+  frames: Vec<MotionFrame<C>>,
+}
+
+impl<C: Config> Default for BoneTrack<C> {
+  fn default() -> Self {
+    Self { frames: Vec::new() }
+  }
+}
+
+impl<C: Config> BoneTrack<C> {
+  /// Builds a track from keyframes of one bone, keeping the later one of duplicated frame numbers.
+  pub fn from_frames(frames: Vec<MotionFrame<C>>) -> Self {
+    Self {
+      frames: sort_keyframes(frames, |f| f.frame_no),
+    }
+  }
+
+  pub fn keyframes(&self) -> &[MotionFrame<C>] {
+    &self.frames
+  }
+
+  /// Returns the last keyframe at or before `frame_no` and the first one after it.
+  pub fn surrounding(&self, frame_no: u32) -> (Option<&MotionFrame<C>>, Option<&MotionFrame<C>>) {
+    let next = self.frames.partition_point(|f| f.frame_no <= frame_no);
+
+    (
+      next.checked_sub(1).map(|prev| &self.frames[prev]),
+      self.frames.get(next),
+    )
+  }
+
+  pub fn sample(&self, frame: f32) -> Option<BoneSample<C>> {
+    sample_bone(&self.frames, frame)
+  }
+}
+
+/// Motion keyframes grouped by bone name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoneTrackSet<C: Config = DefaultConfig> {
+  tracks: HashMap<String, BoneTrack<C>>,
+}
+
+impl<C: Config> Default for BoneTrackSet<C> {
+  fn default() -> Self {
+    Self {
+      tracks: HashMap::new(),
+    }
+  }
+}
+
+impl<C: Config> BoneTrackSet<C> {
+  pub fn from_frames(frames: Vec<MotionFrame<C>>) -> Self {
+    let mut grouped: HashMap<String, Vec<MotionFrame<C>>> = HashMap::new();
+    for frame in frames {
+      grouped.entry(frame.name.clone()).or_default().push(frame);
+    }
+
+    Self {
+      tracks: grouped
+        .into_iter()
+        .map(|(name, frames)| (name, BoneTrack::from_frames(frames)))
+        .collect(),
+    }
+  }
+
+  pub fn track(&self, name: &str) -> Option<&BoneTrack<C>> {
+    self.tracks.get(name)
+  }
+
+  pub fn names(&self) -> impl Iterator<Item = &str> {
+    self.tracks.keys().map(String::as_str)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &BoneTrack<C>)> {
+    self
+      .tracks
+      .iter()
+      .map(|(name, track)| (name.as_str(), track))
+  }
+
+  pub fn len(&self) -> usize {
+    self.tracks.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.tracks.is_empty()
+  }
+}
+
+/// Keyframes of a single morph, sorted by `frame_no` without duplicates.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MorphTrack {
+  frames: Vec<MorphFrame>,
+}
+
+impl MorphTrack {
+  /// Builds a track from keyframes of one morph, keeping the later one of duplicated frame numbers.
+  pub fn from_frames(frames: Vec<MorphFrame>) -> Self {
+    Self {
+      frames: sort_keyframes(frames, |f| f.frame_no),
+    }
+  }
+
+  pub fn keyframes(&self) -> &[MorphFrame] {
+    &self.frames
+  }
+
+  /// Linearly interpolates the weight at `frame`, holding the boundary weights outside the track.
+  pub fn weight_at(&self, frame: f32) -> Option<f32> {
+    let first = self.frames.first()?;
+    let next = self
+      .frames
+      .partition_point(|f| (f.frame_no as f32) <= frame);
+
+    if next == 0 {
+      return Some(first.weight);
+    }
+
+    let prev = &self.frames[next - 1];
+    Some(match self.frames.get(next) {
+      Some(next) => {
+        let t = (frame - prev.frame_no as f32) / (next.frame_no - prev.frame_no) as f32;
+        lerp(prev.weight, next.weight, t)
+      }
+      None => prev.weight,
+    })
+  }
+}
+
+/// Morph keyframes grouped by morph name.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MorphTrackSet {
+  tracks: HashMap<String, MorphTrack>,
+}
+
+impl MorphTrackSet {
+  pub fn from_frames(frames: Vec<MorphFrame>) -> Self {
+    let mut grouped: HashMap<String, Vec<MorphFrame>> = HashMap::new();
+    for frame in frames {
+      grouped.entry(frame.name.clone()).or_default().push(frame);
+    }
+
+    Self {
+      tracks: grouped
+        .into_iter()
+        .map(|(name, frames)| (name, MorphTrack::from_frames(frames)))
+        .collect(),
+    }
+  }
+
+  pub fn track(&self, name: &str) -> Option<&MorphTrack> {
+    self.tracks.get(name)
+  }
+
+  pub fn weight_at(&self, name: &str, frame: f32) -> Option<f32> {
+    self.track(name)?.weight_at(frame)
+  }
+
+  pub fn names(&self) -> impl Iterator<Item = &str> {
+    self.tracks.keys().map(String::as_str)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &MorphTrack)> {
+    self
+      .tracks
+      .iter()
+      .map(|(name, track)| (name.as_str(), track))
+  }
+
+  pub fn len(&self) -> usize {
+    self.tracks.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.tracks.is_empty()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::vmd::tests::bone_frame;
+
+  const FIXTURE_MOTION_VMD: &[u8] = include_bytes!("../../fixtures/motion.vmd");
+
+  #[test]
+  fn test_track_set_grouping() {
+    let tracks = BoneTrackSet::from_frames(vec![
+      bone_frame("右腕", 10, 1.0),
+      bone_frame("左腕", 0, 2.0),
+      bone_frame("右腕", 0, 3.0),
+      bone_frame("右腕", 10, 4.0),
+    ]);
+
+    assert_eq!(tracks.len(), 2);
+
+    let track = tracks.track("右腕").unwrap();
+    let frame_nos = track
+      .keyframes()
+      .iter()
+      .map(|f| f.frame_no)
+      .collect::<Vec<_>>();
+    assert_eq!(frame_nos, [0, 10]);
+    // The later duplicate wins
+    assert_eq!(track.keyframes()[1].position[0], 4.0);
+    assert!(tracks.track("センター").is_none());
+  }
+
+  #[test]
+  fn test_track_surrounding() {
+    let track = BoneTrack::from_frames(vec![
+      bone_frame("右腕", 0, 0.0),
+      bone_frame("右腕", 10, 1.0),
+      bone_frame("右腕", 20, 2.0),
+    ]);
+
+    let (prev, next) = track.surrounding(15);
+    assert_eq!(prev.unwrap().frame_no, 10);
+    assert_eq!(next.unwrap().frame_no, 20);
+
+    let (prev, next) = track.surrounding(10);
+    assert_eq!(prev.unwrap().frame_no, 10);
+    assert_eq!(next.unwrap().frame_no, 20);
+
+    let (prev, next) = track.surrounding(30);
+    assert_eq!(prev.unwrap().frame_no, 20);
+    assert!(next.is_none());
+  }
+
+  #[test]
+  fn test_track_set_motion() {
+    let mut cursor = std::io::Cursor::new(FIXTURE_MOTION_VMD);
+    crate::vmd::VmdHeader::read(&mut cursor).unwrap();
+    let frames: Vec<MotionFrame> = MotionFrame::read_all(&mut cursor).unwrap();
+    let total = frames.len();
+
+    let tracks = BoneTrackSet::from_frames(frames);
+
+    let center = tracks.track("センター").unwrap();
+    assert_eq!(center.keyframes()[0].frame_no, 0);
+    assert!(center
+      .keyframes()
+      .windows(2)
+      .all(|w| w[0].frame_no < w[1].frame_no));
+    assert!(
+      tracks
+        .iter()
+        .map(|(_, t)| t.keyframes().len())
+        .sum::<usize>()
+        <= total
+    );
+  }
+
+  fn morph(frame_no: u32, weight: f32) -> MorphFrame {
+    MorphFrame {
+      name: "まばたき".to_string(),
+      raw_name: None,
+      frame_no,
+      weight,
+    }
+  }
+
+  #[test]
+  fn test_morph_weight_at() {
+    let tracks = MorphTrackSet::from_frames(vec![morph(10, 1.0), morph(0, 0.0)]);
+
+    assert_eq!(tracks.weight_at("まばたき", 5.0), Some(0.5));
+    assert_eq!(tracks.weight_at("まばたき", -1.0), Some(0.0));
+    assert_eq!(tracks.weight_at("まばたき", 20.0), Some(1.0));
+    assert_eq!(tracks.weight_at("あ", 5.0), None);
+  }
+}
@@ -0,0 +1,808 @@
+//! Conversions of motion data into other coordinate systems and units.
+
+use std::collections::HashMap;
+
+use super::keyframe::Keyframe;
+use super::writer::encode_string;
+use super::{CameraFrame, LightFrame, MotionFrame, Vmd, VMD_BONE_NAME_SIZE};
+use crate::math::{
+  dot4, euler_yxz_from_quat, normalize4, quat_from_euler_yxz, quat_mul, rotate3, to_array, Widen,
+};
+use crate::{AsSlice, Config, Error, Float};
+
+/// How `Vmd::retime` maps scaled frame numbers back to whole frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Rounding {
+  #[default]
+  Nearest,
+  Floor,
+  Ceil,
+}
+
+impl Rounding {
+  fn apply(self, frame: f64) -> f64 {
+    match self {
+      Rounding::Nearest => frame.round(),
+      Rounding::Floor => frame.floor(),
+      Rounding::Ceil => frame.ceil(),
+    }
+  }
+}
+
+/// Bones and sections moved by `Vmd::apply_root_transform_with`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RootTransformOptions {
+  /// Candidate root bones from the outermost one in. Only the first one with keyframes is
+  /// transformed, as its children already follow it.
+  pub bones: Vec<String>,
+  /// Whether camera keyframes are transformed too.
+  pub camera: bool,
+}
+
+impl Default for RootTransformOptions {
+  fn default() -> Self {
+    Self {
+      bones: ["全ての親", "センター", "グルーブ"]
+        .iter()
+        .map(|name| name.to_string())
+        .collect(),
+      camera: false,
+    }
+  }
+}
+
+/// Shifts `angle` by whole turns to the one closest to `near`.
+fn unwrap_angle(angle: f32, near: f32) -> f32 {
+  let turn = std::f32::consts::PI * 2.0;
+  angle + ((near - angle) / turn).round() * turn
+}
+
+/// Retimes a section, keeping the keyframe with the later source frame when several land on the
+/// same frame. Returns the new frames and the number of dropped ones.
+fn retime_frames<T: Keyframe>(
+  frames: &[T],
+  ratio: f32,
+  rounding: Rounding,
+) -> crate::Result<(Vec<T>, usize)> {
+  let mut retimed = Vec::with_capacity(frames.len());
+  for frame in frames {
+    let target = rounding.apply(frame.frame_no() as f64 * ratio as f64);
+    if !(0.0..=u32::MAX as f64).contains(&target) {
+      return Err(Error::RetimeOverflow {
+        frame_no: frame.frame_no(),
+        ratio,
+      });
+    }
+    retimed.push(target as u32);
+  }
+
+  // Index of the keyframe kept for every track and target frame
+  let mut winners: HashMap<(&str, u32), usize> = HashMap::new();
+  for (i, (frame, &target)) in frames.iter().zip(&retimed).enumerate() {
+    let winner = winners.entry((frame.track(), target)).or_insert(i);
+    if frames[*winner].frame_no() <= frame.frame_no() {
+      *winner = i;
+    }
+  }
+
+  let kept = frames
+    .iter()
+    .zip(&retimed)
+    .enumerate()
+    .filter(|&(i, (frame, &target))| winners[&(frame.track(), target)] == i)
+    .map(|(_, (frame, &target))| frame.with_frame_no(target))
+    .collect::<Vec<_>>();
+  let dropped = frames.len() - kept.len();
+
+  Ok((kept, dropped))
+}
+
+fn scale<F: Float, V: From<[F; 3]> + AsSlice<Float = F>>(v: &V, factor: f32) -> V {
+  let [x, y, z] = to_array(v);
+  [x * factor, y * factor, z * factor].widen()
+}
+
+/// Swaps a leading or trailing 左/右 (left/right) of a bone or morph name.
+fn mirror_name(name: &str) -> Option<String> {
+  let swap = |c: char| match c {
+    '左' => Some('右'),
+    '右' => Some('左'),
+    _ => None,
+  };
+
+  let first = name.chars().next()?;
+  if let Some(swapped) = swap(first) {
+    return Some(format!("{}{}", swapped, &name[first.len_utf8()..]));
+  }
+  let last = name.chars().next_back()?;
+  swap(last).map(|swapped| format!("{}{}", &name[..name.len() - last.len_utf8()], swapped))
+}
+
+/// Renames a keyframe, keeping the filler of the raw name bytes when the encoded length matches.
+fn rename_raw(
+  name: &mut String,
+  raw_name: &mut Option<[u8; VMD_BONE_NAME_SIZE]>,
+  new_name: String,
+) {
+  if let Some(raw) = raw_name {
+    let old = encode_string(name, VMD_BONE_NAME_SIZE);
+    let new = encode_string(&new_name, VMD_BONE_NAME_SIZE);
+    if old.len() == new.len() && raw.starts_with(&old) {
+      raw[..new.len()].copy_from_slice(&new);
+    } else {
+      *raw_name = None;
+    }
+  }
+  *name = new_name;
+}
+
+fn mirror_x<F: Float, V: From<[F; 3]> + AsSlice<Float = F>>(v: &V) -> V {
+  let [x, y, z] = to_array(v);
+  [-x, y, z].widen()
+}
+
+fn mirror_z<F: Float, V: From<[F; 3]> + AsSlice<Float = F>>(v: &V) -> V {
+  let [x, y, z] = to_array(v);
+  [x, y, -z].widen()
+}
+
+impl<C: Config> MotionFrame<C> {
+  /// Multiplies the position by `factor`, e.g. to convert MMD units of roughly 8 cm into meters.
+  ///
+  /// IK bones are plain bones in a motion and are scaled the same way.
+  pub fn scale(&mut self, factor: f32) {
+    self.position = scale(&self.position, factor);
+  }
+
+  /// Renormalizes the rotation if its norm is off from 1 by more than `tolerance`, replacing a
+  /// zero rotation with the identity. Returns whether the rotation was changed.
+  pub fn renormalize_rotation(&mut self, tolerance: f32) -> bool {
+    let rotation = to_array(&self.rotation);
+    if (dot4(rotation, rotation).sqrt() - 1.0).abs() <= tolerance {
+      return false;
+    }
+
+    // A zero rotation normalizes to the identity
+    self.rotation = normalize4(rotation).widen();
+    true
+  }
+
+  /// Mirrors the keyframe left to right, swapping 左 and 右 in the bone name.
+  pub fn mirror(&mut self) {
+    if let Some(name) = mirror_name(&self.name) {
+      rename_raw(&mut self.name, &mut self.raw_name, name);
+    }
+    self.position = mirror_x(&self.position);
+    let [x, y, z, w] = to_array(&self.rotation);
+    self.rotation = [x, -y, -z, w].widen();
+  }
+
+  /// Mirrors the keyframe along Z, converting between MMD's left-handed and a right-handed Y-up
+  /// coordinate system. Applying it twice restores the keyframe exactly.
+  pub fn flip_handedness(&mut self) {
+    self.position = mirror_z(&self.position);
+    let [x, y, z, w] = to_array(&self.rotation);
+    self.rotation = [-x, -y, z, w].widen();
+  }
+}
+
+impl<C: Config> CameraFrame<C> {
+  /// Multiplies the target position and the distance by `factor`.
+  pub fn scale(&mut self, factor: f32) {
+    self.position = scale(&self.position, factor);
+    self.distance *= factor;
+  }
+
+  /// Mirrors the keyframe left to right, see `MotionFrame::mirror`.
+  pub fn mirror(&mut self) {
+    self.position = mirror_x(&self.position);
+    let [x, y, z] = to_array(&self.rotation);
+    self.rotation = [x, -y, -z].widen();
+  }
+
+  /// Mirrors the keyframe along Z, see `MotionFrame::flip_handedness`.
+  pub fn flip_handedness(&mut self) {
+    self.position = mirror_z(&self.position);
+    let [x, y, z] = to_array(&self.rotation);
+    self.rotation = [-x, -y, z].widen();
+  }
+}
+
+impl<C: Config> LightFrame<C> {
+  /// Mirrors the light direction left to right, see `MotionFrame::mirror`.
+  pub fn mirror(&mut self) {
+    self.direction = mirror_x(&self.direction);
+  }
+
+  /// Mirrors the light direction along Z, see `MotionFrame::flip_handedness`.
+  pub fn flip_handedness(&mut self) {
+    self.direction = mirror_z(&self.direction);
+  }
+}
+
+impl<C: Config> Vmd<C> {
+  /// Renormalizes bone rotations whose norm is off by more than `tolerance`, see
+  /// `MotionFrame::renormalize_rotation`. Returns the bone names and frame numbers of the changed
+  /// keyframes.
+  pub fn renormalize_rotations(&mut self, tolerance: f32) -> Vec<(String, u32)> {
+    self
+      .motion_frames
+      .iter_mut()
+      .filter_map(|f| {
+        if f.renormalize_rotation(tolerance) {
+          Some((f.name.clone(), f.frame_no))
+        } else {
+          None
+        }
+      })
+      .collect()
+  }
+
+  /// Fails on the first bone rotation whose norm is off from 1 by more than `tolerance`.
+  pub fn validate_rotations(&self, tolerance: f32) -> crate::Result<()> {
+    for frame in &self.motion_frames {
+      let rotation = to_array(&frame.rotation);
+      let norm = dot4(rotation, rotation).sqrt();
+      if (norm - 1.0).abs() > tolerance {
+        return Err(Error::InvalidRotation {
+          name: frame.name.clone(),
+          frame_no: frame.frame_no,
+          norm,
+        });
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Multiplies bone positions, camera target positions and camera distances by `factor`.
+  ///
+  /// Rotations, interpolation curves and morph weights are left untouched.
+  pub fn scale(&mut self, factor: f32) {
+    for frame in &mut self.motion_frames {
+      frame.scale(factor);
+    }
+    for frame in &mut self.camera_frames {
+      frame.scale(factor);
+    }
+  }
+
+  /// Multiplies the frame numbers of all keyframes by `ratio`, e.g. 2.0 to go from 30 to 60 fps.
+  ///
+  /// When several keyframes of a bone, morph or section land on the same frame, the one with the
+  /// later source frame is kept. Returns the number of dropped keyframes, or an error leaving
+  /// `self` unchanged if a frame number leaves the `u32` range.
+  pub fn retime(&mut self, ratio: f32, rounding: Rounding) -> crate::Result<usize> {
+    let (motion_frames, motion_dropped) = retime_frames(&self.motion_frames, ratio, rounding)?;
+    let (morph_frames, morph_dropped) = retime_frames(&self.morph_frames, ratio, rounding)?;
+    let (camera_frames, camera_dropped) = retime_frames(&self.camera_frames, ratio, rounding)?;
+    let (light_frames, light_dropped) = retime_frames(&self.light_frames, ratio, rounding)?;
+    let (shadow_frames, shadow_dropped) = retime_frames(&self.shadow_frames, ratio, rounding)?;
+    let (property_frames, property_dropped) =
+      retime_frames(&self.property_frames, ratio, rounding)?;
+
+    self.motion_frames = motion_frames;
+    self.morph_frames = morph_frames;
+    self.camera_frames = camera_frames;
+    self.light_frames = light_frames;
+    self.shadow_frames = shadow_frames;
+    self.property_frames = property_frames;
+
+    Ok(
+      motion_dropped
+        + morph_dropped
+        + camera_dropped
+        + light_dropped
+        + shadow_dropped
+        + property_dropped,
+    )
+  }
+
+  /// Moves the whole motion by `rotation`, an `[x, y, z, w]` quaternion, followed by
+  /// `translation`, see `apply_root_transform_with`.
+  pub fn apply_root_transform(&mut self, translation: [f32; 3], rotation: [f32; 4]) {
+    self.apply_root_transform_with(translation, rotation, &RootTransformOptions::default());
+  }
+
+  /// Moves the whole motion by `rotation` followed by `translation`, e.g. to place a dance on a
+  /// stage.
+  ///
+  /// Keyframes of the outermost root bone present in `options.bones` are rotated about the origin
+  /// of the bone and translated, all other bones are local to it and are left untouched. Camera
+  /// rotations are treated as Euler angles applied around Z, X and then Y and keep their number of
+  /// turns. Positions in between keyframes only follow exactly when the curves of all axes match.
+  pub fn apply_root_transform_with(
+    &mut self,
+    translation: [f32; 3],
+    rotation: [f32; 4],
+    options: &RootTransformOptions,
+  ) {
+    let rotation = normalize4(rotation);
+    let moved = |position: [f32; 3]| {
+      let [x, y, z] = rotate3(rotation, position);
+      [x + translation[0], y + translation[1], z + translation[2]]
+    };
+
+    let root = options
+      .bones
+      .iter()
+      .find(|name| self.motion_frames.iter().any(|f| &f.name == *name));
+    if let Some(root) = root {
+      for frame in self.motion_frames.iter_mut().filter(|f| &f.name == root) {
+        frame.position = moved(to_array(&frame.position)).widen();
+        frame.rotation = quat_mul(rotation, to_array(&frame.rotation)).widen();
+      }
+    }
+
+    if options.camera {
+      let turn = euler_yxz_from_quat(rotation);
+      // Turns around Y only add to the yaw, which also holds at the poles of the Euler angles
+      let yaw_only = rotation[0].abs() < 1e-6 && rotation[2].abs() < 1e-6;
+      for frame in &mut self.camera_frames {
+        let euler = to_array::<3>(&frame.rotation);
+        frame.position = moved(to_array(&frame.position)).widen();
+        frame.rotation = if yaw_only {
+          [euler[0], euler[1] + turn[1], euler[2]]
+        } else {
+          let rotated = euler_yxz_from_quat(quat_mul(rotation, quat_from_euler_yxz(euler)));
+          [
+            unwrap_angle(rotated[0], euler[0] + turn[0]),
+            unwrap_angle(rotated[1], euler[1] + turn[1]),
+            unwrap_angle(rotated[2], euler[2] + turn[2]),
+          ]
+        }
+        .widen();
+      }
+    }
+  }
+
+  /// Mirrors the motion left to right, e.g. so that a dance leads with the other foot.
+  ///
+  /// Bones and morphs starting or ending with 左/右 swap names, and all bone, camera and light
+  /// keyframes are mirrored along X. Interpolation curves are kept as is.
+  pub fn mirror(&mut self) {
+    self.motion_frames.iter_mut().for_each(MotionFrame::mirror);
+    for frame in &mut self.morph_frames {
+      if let Some(name) = mirror_name(&frame.name) {
+        rename_raw(&mut frame.name, &mut frame.raw_name, name);
+      }
+    }
+    self.camera_frames.iter_mut().for_each(CameraFrame::mirror);
+    self.light_frames.iter_mut().for_each(LightFrame::mirror);
+  }
+
+  /// Mirrors all bone, camera and light keyframes along Z, see `MotionFrame::flip_handedness`.
+  pub fn flip_handedness(&mut self) {
+    self
+      .motion_frames
+      .iter_mut()
+      .for_each(MotionFrame::flip_handedness);
+    self
+      .camera_frames
+      .iter_mut()
+      .for_each(CameraFrame::flip_handedness);
+    self
+      .light_frames
+      .iter_mut()
+      .for_each(LightFrame::flip_handedness);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::vmd::tests::bone_frame;
+  use crate::vmd::CameraInterpolation;
+  use crate::DefaultConfig;
+
+  const FIXTURE_MOTION_VMD: &[u8] = include_bytes!("../../fixtures/motion.vmd");
+  const FIXTURE_CAMERA_VMD: &[u8] = include_bytes!("../../fixtures/camera.vmd");
+
+  /// Xorshift, to generate arbitrary but reproducible values.
+  struct Values(u32);
+
+  impl Values {
+    fn next(&mut self) -> f32 {
+      self.0 ^= self.0 << 13;
+      self.0 ^= self.0 >> 17;
+      self.0 ^= self.0 << 5;
+      (self.0 as f32 / u32::MAX as f32 - 0.5) * 2000.0
+    }
+
+    fn vec<const N: usize>(&mut self) -> [f32; N] {
+      let mut v = [0.0; N];
+      v.iter_mut().for_each(|c| *c = self.next());
+      v
+    }
+  }
+
+  fn arbitrary(values: &mut Values) -> Vmd {
+    let mut vmd = Vmd::<DefaultConfig>::default();
+    for frame_no in 0..64 {
+      vmd.motion_frames.push(MotionFrame::new(
+        "センター",
+        frame_no,
+        values.vec::<3>(),
+        values.vec::<4>(),
+      ));
+      vmd.camera_frames.push(CameraFrame {
+        frame_no,
+        distance: values.next(),
+        position: values.vec::<3>().into(),
+        rotation: values.vec::<3>().into(),
+        interpolation: CameraInterpolation::LINEAR.to_bytes(),
+        fov: 30,
+        orthographic: false,
+      });
+      vmd.light_frames.push(LightFrame {
+        frame_no,
+        color: values.vec::<3>().into(),
+        direction: values.vec::<3>().into(),
+      });
+    }
+    vmd
+  }
+
+  #[test]
+  fn test_flip_handedness_involution() {
+    let mut values = Values(0x2545_f491);
+    for _ in 0..16 {
+      let original = arbitrary(&mut values);
+      let mut vmd = original.clone();
+
+      vmd.flip_handedness();
+      assert_ne!(vmd, original);
+      vmd.flip_handedness();
+
+      assert_eq!(vmd, original);
+    }
+  }
+
+  #[test]
+  fn test_flip_handedness_fixtures() {
+    for bytes in [FIXTURE_MOTION_VMD, FIXTURE_CAMERA_VMD].iter() {
+      let original = Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(bytes)).unwrap();
+      let mut vmd = original.clone();
+
+      vmd.flip_handedness();
+      vmd.flip_handedness();
+
+      let mut written = Vec::new();
+      vmd.write(&mut written).unwrap();
+      let mut expected = Vec::new();
+      original.write(&mut expected).unwrap();
+      assert_eq!(written, expected);
+    }
+  }
+
+  #[test]
+  fn test_flip_handedness_components() {
+    let mut light = LightFrame::<DefaultConfig> {
+      frame_no: 0,
+      color: [1.0; 3].into(),
+      direction: [-0.5, -1.0, 0.5].into(),
+    };
+    light.flip_handedness();
+    assert_eq!(light.direction.as_slice(), [-0.5, -1.0, -0.5]);
+
+    let mut values = Values(7);
+    let mut vmd = arbitrary(&mut values);
+    let [x, y, z, w] = to_array(&vmd.motion_frames[0].rotation);
+    let [px, py, pz] = to_array(&vmd.motion_frames[0].position);
+    vmd.flip_handedness();
+    assert_eq!(vmd.motion_frames[0].rotation.as_slice(), [-x, -y, z, w]);
+    assert_eq!(vmd.motion_frames[0].position.as_slice(), [px, py, -pz]);
+  }
+
+  #[test]
+  fn test_scale_identity_bytes() {
+    for bytes in [FIXTURE_MOTION_VMD, FIXTURE_CAMERA_VMD].iter() {
+      let mut vmd = Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(bytes)).unwrap();
+      let mut expected = Vec::new();
+      vmd.write(&mut expected).unwrap();
+
+      vmd.scale(1.0);
+
+      let mut written = Vec::new();
+      vmd.write(&mut written).unwrap();
+      assert_eq!(written, expected);
+    }
+  }
+
+  #[test]
+  fn test_scale_motion() {
+    let original =
+      Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(FIXTURE_MOTION_VMD)).unwrap();
+    let mut vmd = original.clone();
+
+    vmd.scale(0.08);
+
+    for (scaled, frame) in vmd.motion_frames.iter().zip(&original.motion_frames) {
+      // IK bones are not special cased
+      let [x, y, z] = to_array(&frame.position);
+      assert_eq!(scaled.position.as_slice(), [x * 0.08, y * 0.08, z * 0.08]);
+      assert_eq!(scaled.rotation, frame.rotation);
+      assert_eq!(scaled.interpolation[..], frame.interpolation[..]);
+    }
+    assert!(vmd.motion_frames.iter().any(|f| f.name == "左足ＩＫ"));
+    assert_eq!(vmd.morph_frames, original.morph_frames);
+  }
+
+  #[test]
+  fn test_scale_camera() {
+    let original =
+      Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(FIXTURE_CAMERA_VMD)).unwrap();
+    let mut vmd = original.clone();
+
+    vmd.scale(0.5);
+
+    let (scaled, frame) = (&vmd.camera_frames[0], &original.camera_frames[0]);
+    assert_eq!(scaled.distance, -90.0);
+    let [x, y, z] = to_array(&frame.position);
+    assert_eq!(scaled.position.as_slice(), [x * 0.5, y * 0.5, z * 0.5]);
+    assert_eq!(scaled.rotation, frame.rotation);
+    assert_eq!(vmd.light_frames, original.light_frames);
+  }
+
+  #[test]
+  fn test_retime_double() {
+    let original =
+      Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(FIXTURE_CAMERA_VMD)).unwrap();
+    let mut vmd = original.clone();
+
+    assert_eq!(vmd.retime(2.0, Rounding::Nearest).unwrap(), 0);
+
+    assert_eq!(vmd.camera_frames[1].frame_no, 2);
+    assert_eq!(vmd.light_frames[1].frame_no, 2);
+    assert_eq!(vmd.shadow_frames[1].frame_no, 2);
+    assert_eq!(
+      vmd.camera_frames[1].position,
+      original.camera_frames[1].position
+    );
+
+    assert_eq!(vmd.retime(0.5, Rounding::Nearest).unwrap(), 0);
+    assert_eq!(vmd, original);
+  }
+
+  #[test]
+  fn test_retime_halve_collisions() {
+    let mut vmd = Vmd::<DefaultConfig> {
+      motion_frames: vec![
+        bone_frame("センター", 0, 0.0),
+        bone_frame("センター", 3, 3.0),
+        bone_frame("センター", 2, 2.0),
+        bone_frame("頭", 2, 5.0),
+      ],
+      ..Default::default()
+    };
+
+    // 3 * 0.5 and 2 * 0.5 both floor to frame 1, the later source frame wins
+    assert_eq!(vmd.retime(0.5, Rounding::Floor).unwrap(), 1);
+
+    let keys = vmd
+      .motion_frames
+      .iter()
+      .map(|f| (f.name.as_str(), f.frame_no, f.position[0]))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      keys,
+      [("センター", 0, 0.0), ("センター", 1, 3.0), ("頭", 1, 5.0)]
+    );
+  }
+
+  #[test]
+  fn test_retime_overflow() {
+    let mut vmd = Vmd::<DefaultConfig> {
+      motion_frames: vec![
+        bone_frame("センター", 0, 0.0),
+        bone_frame("センター", u32::MAX / 2 + 1, 0.0),
+      ],
+      ..Default::default()
+    };
+    let original = vmd.clone();
+
+    let err = vmd.retime(2.0, Rounding::Nearest).unwrap_err();
+
+    assert!(matches!(err, Error::RetimeOverflow { ratio, .. } if ratio == 2.0));
+    assert_eq!(vmd, original);
+    assert!(vmd.retime(-1.0, Rounding::Nearest).is_err());
+  }
+
+  #[test]
+  fn test_renormalize_rotations() {
+    let rotated = |frame_no: u32, rotation: [f32; 4]| MotionFrame {
+      rotation: rotation.into(),
+      ..bone_frame("センター", frame_no, 0.0)
+    };
+    let mut vmd = Vmd::<DefaultConfig> {
+      motion_frames: vec![
+        rotated(0, [0.0, 0.5, 0.0, 0.0]),
+        rotated(1, [0.0, 0.0, 0.0, 1.0001]),
+        rotated(2, [0.0; 4]),
+      ],
+      ..Default::default()
+    };
+
+    let err = vmd.validate_rotations(1e-3).unwrap_err();
+    assert!(matches!(err, Error::InvalidRotation { frame_no: 0, norm, .. } if norm == 0.5));
+
+    let touched = vmd.renormalize_rotations(1e-3);
+
+    assert_eq!(
+      touched,
+      [("センター".to_string(), 0), ("センター".to_string(), 2)]
+    );
+    assert_eq!(
+      vmd.motion_frames[0].rotation.as_slice(),
+      [0.0, 1.0, 0.0, 0.0]
+    );
+    assert_eq!(
+      vmd.motion_frames[1].rotation.as_slice(),
+      [0.0, 0.0, 0.0, 1.0001]
+    );
+    assert_eq!(
+      vmd.motion_frames[2].rotation.as_slice(),
+      [0.0, 0.0, 0.0, 1.0]
+    );
+    assert!(vmd.validate_rotations(1e-3).is_ok());
+    assert!(vmd.renormalize_rotations(1e-3).is_empty());
+  }
+
+  #[test]
+  fn test_validate_rotations_fixture() {
+    let vmd = Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(FIXTURE_MOTION_VMD)).unwrap();
+
+    assert!(vmd.validate_rotations(1e-3).is_ok());
+  }
+
+  #[test]
+  fn test_mirror_names() {
+    assert_eq!(mirror_name("左足ＩＫ").as_deref(), Some("右足ＩＫ"));
+    assert_eq!(mirror_name("右ひじ").as_deref(), Some("左ひじ"));
+    assert_eq!(mirror_name("ウィンク右").as_deref(), Some("ウィンク左"));
+    assert_eq!(
+      mirror_name("腰キャンセル左").as_deref(),
+      Some("腰キャンセル右")
+    );
+    assert_eq!(mirror_name("センター"), None);
+    assert_eq!(mirror_name(""), None);
+  }
+
+  #[test]
+  fn test_mirror_twice() {
+    let original =
+      Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(FIXTURE_MOTION_VMD)).unwrap();
+    let mut vmd = original.clone();
+
+    vmd.mirror();
+
+    let (mirrored, frame) = vmd
+      .motion_frames
+      .iter()
+      .zip(&original.motion_frames)
+      .find(|(_, f)| f.name == "左足ＩＫ")
+      .unwrap();
+    assert_eq!(mirrored.name, "右足ＩＫ");
+    let [x, y, z] = to_array(&frame.position);
+    assert_eq!(mirrored.position.as_slice(), [-x, y, z]);
+    assert_eq!(mirrored.interpolation[..], frame.interpolation[..]);
+    assert!(vmd.motion_frames.iter().any(|f| f.name == "センター"));
+
+    vmd.mirror();
+
+    assert_eq!(vmd, original);
+    let mut written = Vec::new();
+    vmd.write(&mut written).unwrap();
+    let mut expected = Vec::new();
+    original.write(&mut expected).unwrap();
+    assert_eq!(written, expected);
+  }
+
+  fn assert_near(a: &[f32], b: &[f32]) {
+    assert!(
+      a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-4),
+      "{:?} != {:?}",
+      a,
+      b
+    );
+  }
+
+  #[test]
+  fn test_root_transform_twice() {
+    let original =
+      Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(FIXTURE_MOTION_VMD)).unwrap();
+    let camera = Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(FIXTURE_CAMERA_VMD)).unwrap();
+    let half_turn = [0.0, 1.0, 0.0, 0.0];
+    let options = RootTransformOptions {
+      camera: true,
+      ..Default::default()
+    };
+
+    for original in &[original, camera] {
+      let mut vmd = original.clone();
+      vmd.apply_root_transform_with([0.0; 3], half_turn, &options);
+      vmd.apply_root_transform_with([0.0; 3], half_turn, &options);
+
+      for (frame, expected) in vmd.motion_frames.iter().zip(&original.motion_frames) {
+        assert_near(frame.position.as_slice(), expected.position.as_slice());
+        // A full turn may negate the quaternion, which is the same rotation
+        let cos = dot4(to_array(&frame.rotation), to_array(&expected.rotation));
+        assert!((cos.abs() - 1.0).abs() < 1e-4);
+      }
+      for (frame, expected) in vmd.camera_frames.iter().zip(&original.camera_frames) {
+        assert_near(frame.position.as_slice(), expected.position.as_slice());
+        // Two half turns add a whole turn to the yaw
+        let rotation = to_array::<3>(&frame.rotation);
+        let expected = to_array::<3>(&expected.rotation);
+        assert_near(
+          &[
+            rotation[0],
+            rotation[1] - std::f32::consts::PI * 2.0,
+            rotation[2],
+          ],
+          &expected,
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn test_root_transform_bones() {
+    let mut arm = bone_frame("左腕", 0, 1.0);
+    arm.rotation = [0.0, 0.0, 0.5f32.sin(), 0.5f32.cos()].into();
+    let mut vmd = Vmd {
+      motion_frames: vec![
+        bone_frame("センター", 0, 1.0),
+        bone_frame("グルーブ", 0, 1.0),
+        arm.clone(),
+      ],
+      camera_frames: vec![CameraFrame {
+        frame_no: 0,
+        distance: -30.0,
+        position: [1.0, 10.0, 0.0].into(),
+        rotation: [0.1, 6.0, 0.0].into(),
+        interpolation: [20; 24],
+        fov: 30,
+        orthographic: false,
+      }],
+      ..Default::default()
+    };
+    let (sin, cos) = std::f32::consts::FRAC_PI_4.sin_cos();
+    let quarter_turn = [0.0, sin, 0.0, cos];
+
+    vmd.apply_root_transform([0.0, 0.0, 5.0], quarter_turn);
+
+    // +X turns into -Z around Y
+    assert_near(vmd.motion_frames[0].position.as_slice(), &[0.0, 0.0, 4.0]);
+    assert_near(vmd.motion_frames[0].rotation.as_slice(), &quarter_turn);
+    // Only the outermost root moves
+    assert_eq!(vmd.motion_frames[1], bone_frame("グルーブ", 0, 1.0));
+    assert_eq!(vmd.motion_frames[2], arm);
+    assert_eq!(vmd.camera_frames[0].rotation.as_slice(), [0.1, 6.0, 0.0]);
+
+    let options = RootTransformOptions {
+      bones: vec!["グルーブ".to_string()],
+      camera: true,
+    };
+    vmd.apply_root_transform_with([0.0; 3], quarter_turn, &options);
+    assert_near(vmd.motion_frames[1].position.as_slice(), &[0.0, 0.0, -1.0]);
+    assert_near(vmd.camera_frames[0].position.as_slice(), &[0.0, 10.0, -1.0]);
+    // The yaw keeps counting turns instead of wrapping around
+    assert_near(
+      vmd.camera_frames[0].rotation.as_slice(),
+      &[0.1, 6.0 + std::f32::consts::FRAC_PI_2, 0.0],
+    );
+
+    // Other rotations go through a quaternion and back
+    let before = to_array::<3>(&vmd.camera_frames[0].rotation);
+    let (sin, cos) = 0.15f32.sin_cos();
+    vmd.apply_root_transform_with([0.0; 3], [sin, 0.0, 0.0, cos], &options);
+    let rotated = to_array::<3>(&vmd.camera_frames[0].rotation);
+    assert!(rotated
+      .iter()
+      .zip(&before)
+      .any(|(a, b)| (a - b).abs() > 0.1));
+    vmd.apply_root_transform_with([0.0; 3], [-sin, 0.0, 0.0, cos], &options);
+    assert_near(vmd.camera_frames[0].rotation.as_slice(), &before);
+  }
+}
@@ -0,0 +1,375 @@
+use std::convert::TryFrom;
+use std::io::Write;
+
+use byteorder::{WriteBytesExt, LE};
+use encoding_rs::SHIFT_JIS;
+
+use super::*;
+use crate::{AsSlice, Float};
+
+/// Encodes `s` as Shift_JIS, truncated to at most `size` bytes without splitting a character.
+pub(crate) fn encode_string(s: &str, size: usize) -> Vec<u8> {
+  let mut buf = Vec::with_capacity(size);
+  let mut char_buf = [0; 4];
+
+  for c in s.chars() {
+    let (bytes, _, _) = SHIFT_JIS.encode(c.encode_utf8(&mut char_buf));
+    if buf.len() + bytes.len() > size {
+      break;
+    }
+    buf.extend_from_slice(&bytes);
+  }
+
+  buf
+}
+
+/// Cuts `s` the way it is written into a field of `size` bytes, without splitting a double-byte
+/// character.
+pub(crate) fn truncate_string(s: &str, size: usize) -> String {
+  SHIFT_JIS
+    .decode_without_bom_handling(&encode_string(s, size))
+    .0
+    .into_owned()
+}
+
+fn write_string<W: Write>(write: &mut W, s: &str, size: usize) -> crate::Result<()> {
+  let mut buf = encode_string(s, size);
+  buf.resize(size, 0);
+  write.write_all(&buf)?;
+
+  Ok(())
+}
+
+/// Writes the raw bytes read from the file if they still decode to `s`, so that truncated names
+/// and the filler after the null byte survive a round trip.
+fn write_name<W: Write>(
+  write: &mut W,
+  s: &str,
+  raw: Option<&[u8]>,
+  size: usize,
+) -> crate::Result<()> {
+  match raw {
+    Some(raw) if raw.len() == size && decode_string(raw, DecodeMode::Lossy)? == s => {
+      write.write_all(raw)?;
+      Ok(())
+    }
+    _ => write_string(write, s, size),
+  }
+}
+
+fn write_vec<W: Write, F: Float>(write: &mut W, v: &[F]) -> crate::Result<()> {
+  for &c in v {
+    write.write_f32::<LE>(c.to_f32())?;
+  }
+
+  Ok(())
+}
+
+fn write_section_count<W: Write>(write: &mut W, count: usize) -> crate::Result<()> {
+  let count = u32::try_from(count)
+    .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "too many frames"))?;
+  write.write_u32::<LE>(count)?;
+
+  Ok(())
+}
+
+impl<C: Config> Vmd<C> {
+  pub fn write<W: Write>(&self, write: &mut W) -> crate::Result<()> {
+    self.header.write(write)?;
+    MotionFrame::write_all(write, &self.motion_frames)?;
+    MorphFrame::write_all(write, &self.morph_frames)?;
+    CameraFrame::write_all(write, &self.camera_frames)?;
+    LightFrame::write_all(write, &self.light_frames)?;
+    ShadowFrame::write_all(write, &self.shadow_frames)?;
+    PropertyFrame::write_all(write, &self.property_frames)?;
+
+    Ok(())
+  }
+}
+
+impl VmdHeader {
+  pub fn write<W: Write>(&self, write: &mut W) -> crate::Result<()> {
+    let magic = self.version.magic();
+    match self.raw_signature {
+      Some(raw) if raw.starts_with(magic) => write.write_all(&raw)?,
+      _ => {
+        let mut buf = [0; 30];
+        buf[..magic.len()].copy_from_slice(magic);
+        write.write_all(&buf)?;
+      }
+    }
+
+    write_name(
+      write,
+      &self.model_name,
+      self.raw_model_name.as_deref(),
+      self.version.model_name_size(),
+    )
+  }
+}
+
+impl<C: Config> MotionFrame<C> {
+  pub fn write_all<W: Write>(write: &mut W, frames: &[Self]) -> crate::Result<()> {
+    write_section_count(write, frames.len())?;
+
+    for frame in frames {
+      frame.write(write)?;
+    }
+
+    Ok(())
+  }
+
+  pub fn write<W: Write>(&self, write: &mut W) -> crate::Result<()> {
+    write_name(
+      write,
+      &self.name,
+      self.raw_name.as_ref().map(|raw| &raw[..]),
+      VMD_BONE_NAME_SIZE,
+    )?;
+    write.write_u32::<LE>(self.frame_no)?;
+    write_vec(write, self.position.as_slice())?;
+    write_vec(write, self.rotation.as_slice())?;
+    write.write_all(&self.interpolation)?;
+
+    Ok(())
+  }
+}
+
+impl MorphFrame {
+  pub fn write_all<W: Write>(write: &mut W, frames: &[Self]) -> crate::Result<()> {
+    write_section_count(write, frames.len())?;
+
+    for frame in frames {
+      frame.write(write)?;
+    }
+
+    Ok(())
+  }
+
+  pub fn write<W: Write>(&self, write: &mut W) -> crate::Result<()> {
+    write_name(
+      write,
+      &self.name,
+      self.raw_name.as_ref().map(|raw| &raw[..]),
+      VMD_BONE_NAME_SIZE,
+    )?;
+    write.write_u32::<LE>(self.frame_no)?;
+    write.write_f32::<LE>(self.weight)?;
+
+    Ok(())
+  }
+}
+
+impl<C: Config> CameraFrame<C> {
+  pub fn write_all<W: Write>(write: &mut W, frames: &[Self]) -> crate::Result<()> {
+    write_section_count(write, frames.len())?;
+
+    for frame in frames {
+      frame.write(write)?;
+    }
+
+    Ok(())
+  }
+
+  pub fn write<W: Write>(&self, write: &mut W) -> crate::Result<()> {
+    write.write_u32::<LE>(self.frame_no)?;
+    write.write_f32::<LE>(self.distance)?;
+    write_vec(write, self.position.as_slice())?;
+    write_vec(write, self.rotation.as_slice())?;
+    write.write_all(&self.interpolation)?;
+    write.write_u32::<LE>(self.fov)?;
+    write.write_u8(self.orthographic as u8)?;
+
+    Ok(())
+  }
+}
+
+impl<C: Config> LightFrame<C> {
+  pub fn write_all<W: Write>(write: &mut W, frames: &[Self]) -> crate::Result<()> {
+    write_section_count(write, frames.len())?;
+
+    for frame in frames {
+      frame.write(write)?;
+    }
+
+    Ok(())
+  }
+
+  pub fn write<W: Write>(&self, write: &mut W) -> crate::Result<()> {
+    write.write_u32::<LE>(self.frame_no)?;
+    write_vec(write, self.color.as_slice())?;
+    write_vec(write, self.direction.as_slice())?;
+
+    Ok(())
+  }
+}
+
+impl ShadowFrame {
+  pub fn write_all<W: Write>(write: &mut W, frames: &[Self]) -> crate::Result<()> {
+    write_section_count(write, frames.len())?;
+
+    for frame in frames {
+      frame.write(write)?;
+    }
+
+    Ok(())
+  }
+
+  pub fn write<W: Write>(&self, write: &mut W) -> crate::Result<()> {
+    write.write_u32::<LE>(self.frame_no)?;
+    write.write_u8(self.mode.into())?;
+    write.write_f32::<LE>(self.distance)?;
+
+    Ok(())
+  }
+}
+
+impl PropertyFrame {
+  pub fn write_all<W: Write>(write: &mut W, frames: &[Self]) -> crate::Result<()> {
+    write_section_count(write, frames.len())?;
+
+    for frame in frames {
+      frame.write(write)?;
+    }
+
+    Ok(())
+  }
+
+  pub fn write<W: Write>(&self, write: &mut W) -> crate::Result<()> {
+    write.write_u32::<LE>(self.frame_no)?;
+    write.write_u8(self.visible as u8)?;
+    write_section_count(write, self.ik_states.len())?;
+
+    for (name, enabled) in &self.ik_states {
+      write_string(write, name, VMD_IK_NAME_SIZE)?;
+      write.write_u8(*enabled as u8)?;
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const FIXTURE_MOTION_VMD: &[u8] = include_bytes!("../../fixtures/motion.vmd");
+  const FIXTURE_CAMERA_VMD: &[u8] = include_bytes!("../../fixtures/camera.vmd");
+  const FIXTURE_ISSUE1_VMD: &[u8] = include_bytes!("../../fixtures/issue1.vmd");
+
+  fn util_round_trip<C: Config + PartialEq + std::fmt::Debug>(bytes: &[u8]) {
+    let vmd = Vmd::<C>::read(&mut std::io::Cursor::new(bytes)).unwrap();
+
+    let mut buf = Vec::new();
+    vmd.write(&mut buf).unwrap();
+    let written = Vmd::<C>::read(&mut std::io::Cursor::new(&buf)).unwrap();
+
+    assert_eq!(written.header, vmd.header);
+    assert_eq!(written.motion_frames.len(), vmd.motion_frames.len());
+    for (a, b) in written.motion_frames.iter().zip(&vmd.motion_frames) {
+      assert_eq!(a, b);
+    }
+    assert_eq!(written, vmd);
+  }
+
+  #[test]
+  fn test_vmd_round_trip_motion() {
+    util_round_trip::<DefaultConfig>(FIXTURE_MOTION_VMD);
+    util_round_trip::<crate::vmd::tests::VectorConfig>(FIXTURE_MOTION_VMD);
+    util_round_trip::<crate::F64Config>(FIXTURE_MOTION_VMD);
+    #[cfg(feature = "nalgebra")]
+    util_round_trip::<crate::NalgebraConfig>(FIXTURE_MOTION_VMD);
+  }
+
+  #[test]
+  fn test_vmd_round_trip_camera() {
+    util_round_trip::<DefaultConfig>(FIXTURE_CAMERA_VMD);
+    util_round_trip::<crate::vmd::tests::VectorConfig>(FIXTURE_CAMERA_VMD);
+    util_round_trip::<crate::F64Config>(FIXTURE_CAMERA_VMD);
+  }
+
+  #[test]
+  fn test_vmd_encode_string_truncates_at_char_boundary() {
+    // 8 double-byte characters, the 8th one does not fit into 15 bytes
+    let encoded = encode_string("左腕捩れボーンズ", 15);
+
+    assert_eq!(encoded.len(), 14);
+    assert_eq!(SHIFT_JIS.decode(&encoded).0, "左腕捩れボーン");
+  }
+
+  #[test]
+  fn test_vmd_write_header_v1() {
+    let header = VmdHeader {
+      version: VmdVersion::V1,
+      raw_signature: None,
+      model_name: "初音ミク".to_string(),
+      raw_model_name: None,
+    };
+
+    let mut buf = Vec::new();
+    header.write(&mut buf).unwrap();
+
+    assert_eq!(buf.len(), 40);
+    let read = VmdHeader::read(&mut std::io::Cursor::new(&buf)).unwrap();
+    assert_eq!(read.version, header.version);
+    assert_eq!(read.model_name, header.model_name);
+    assert_eq!(read.raw_model_name.as_deref(), Some(&buf[30..]));
+  }
+
+  #[test]
+  fn test_vmd_write_header() {
+    let header = VmdHeader {
+      version: VmdVersion::V2,
+      raw_signature: None,
+      model_name: "初音ミク".to_string(),
+      raw_model_name: None,
+    };
+
+    let mut buf = Vec::new();
+    header.write(&mut buf).unwrap();
+
+    assert_eq!(buf.len(), 50);
+    assert_eq!(&buf[..VMD_HEADER.len()], VMD_HEADER);
+    let read = VmdHeader::read(&mut std::io::Cursor::new(&buf)).unwrap();
+    assert_eq!(read.version, header.version);
+    assert_eq!(read.model_name, header.model_name);
+    assert_eq!(read.raw_model_name.as_deref(), Some(&buf[30..]));
+  }
+
+  #[test]
+  fn test_vmd_round_trip_issue1_bytes() {
+    // The sections after the camera count are garbage, so only the sections before it
+    let end = 50 + 4 + 7 * 111 + 4 + 31 * 23;
+    let mut cursor = std::io::Cursor::new(FIXTURE_ISSUE1_VMD);
+    let header = VmdHeader::read(&mut cursor).unwrap();
+    let motion_frames = MotionFrame::<DefaultConfig>::read_all(&mut cursor).unwrap();
+    let morph_frames = MorphFrame::read_all(&mut cursor).unwrap();
+
+    let mut buf = Vec::new();
+    header.write(&mut buf).unwrap();
+    MotionFrame::write_all(&mut buf, &motion_frames).unwrap();
+    MorphFrame::write_all(&mut buf, &morph_frames).unwrap();
+
+    // The magic is followed by garbage and names are padded with 0xfd after the null byte
+    assert_eq!(buf[..50], FIXTURE_ISSUE1_VMD[..50]);
+    assert_eq!(buf[..], FIXTURE_ISSUE1_VMD[..end]);
+  }
+
+  #[test]
+  fn test_vmd_write_renamed() {
+    let mut cursor = std::io::Cursor::new(FIXTURE_ISSUE1_VMD);
+    VmdHeader::read(&mut cursor).unwrap();
+    let mut frame = MotionFrame::<DefaultConfig>::read(&mut cursor.clone()).unwrap();
+    let mut modified = frame.clone();
+    modified.name = "右目".to_string();
+    frame.set_name("右目");
+
+    for frame in [frame, modified].iter() {
+      let mut buf = Vec::new();
+      frame.write(&mut buf).unwrap();
+
+      assert_eq!(buf[..4], encode_string("右目", 15)[..]);
+      assert!(buf[4..15].iter().all(|&b| b == 0));
+    }
+  }
+}
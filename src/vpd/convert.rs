@@ -0,0 +1,180 @@
+//! Conversion between poses and motions.
+
+use super::{BoneTransform, MorphValue, Vpd};
+use crate::math::Widen;
+use crate::vmd::track::{BoneTrackSet, MorphTrackSet};
+use crate::vmd::{
+  truncate_string, MorphFrame, MotionFrame, Vmd, VmdHeader, VmdVersion, VMD_BONE_NAME_SIZE,
+  VMD_MODEL_NAME_SIZE,
+};
+use crate::Config;
+
+impl<C: Config> Vpd<C> {
+  /// A motion holding this pose at frame 0, with linear curves.
+  ///
+  /// Names are cut to the length of the motion format the same way `Vmd::write` does. The morph
+  /// offsets are not part of a motion and get lost.
+  pub fn to_vmd(&self, model_name: &str) -> Vmd<C> {
+    let motion_frames = self
+      .bone_transforms
+      .iter()
+      .map(|bone| {
+        MotionFrame::new(
+          truncate_string(&bone.name, VMD_BONE_NAME_SIZE),
+          0,
+          bone.position.clone(),
+          bone.rotation.clone(),
+        )
+      })
+      .collect();
+
+    let morph_frames = self
+      .morph_values
+      .iter()
+      .map(|morph| MorphFrame {
+        name: truncate_string(&morph.name, VMD_BONE_NAME_SIZE),
+        raw_name: None,
+        frame_no: 0,
+        weight: morph.weight,
+      })
+      .collect();
+
+    Vmd {
+      header: VmdHeader {
+        version: VmdVersion::V2,
+        raw_signature: None,
+        model_name: truncate_string(model_name, VMD_MODEL_NAME_SIZE),
+        raw_model_name: None,
+      },
+      motion_frames,
+      morph_frames,
+      ..Default::default()
+    }
+  }
+
+  /// The pose of a motion at `frame`, the inverse of `to_vmd`.
+  ///
+  /// Bones and morphs are numbered in the order they first appear in the motion, the pose is
+  /// unnamed and made for `parent_model`.
+  pub fn from_vmd(vmd: &Vmd<C>, frame: f32, parent_model: &str) -> Self {
+    let mut vpd = Vpd::new(String::new());
+    vpd.parent_model = parent_model.to_string();
+
+    let bones = BoneTrackSet::<C>::from_frames(
+      vmd
+        .motion_frames
+        .iter()
+        .map(|f| MotionFrame {
+          name: f.name.clone(),
+          raw_name: f.raw_name,
+          frame_no: f.frame_no,
+          position: f.position.clone(),
+          rotation: f.rotation.clone(),
+          interpolation: f.interpolation,
+        })
+        .collect(),
+    );
+    for motion_frame in &vmd.motion_frames {
+      let name = &motion_frame.name;
+      if vpd.bone_transforms.iter().any(|b| &b.name == name) {
+        continue;
+      }
+      if let Some(sample) = bones.track(name).and_then(|track| track.sample(frame)) {
+        vpd.bone_transforms.push(BoneTransform {
+          id: vpd.bone_transforms.len() as u32,
+          name: name.clone(),
+          position: sample.position,
+          rotation: sample.rotation,
+        });
+      }
+    }
+
+    let morphs = MorphTrackSet::from_frames(vmd.morph_frames.clone());
+    for morph in &vmd.morph_frames {
+      if vpd.morph_values.iter().any(|m| m.name == morph.name) {
+        continue;
+      }
+      if let Some(weight) = morphs.weight_at(&morph.name, frame) {
+        vpd.morph_values.push(MorphValue {
+          id: vpd.morph_values.len() as u32,
+          name: morph.name.clone(),
+          weight,
+          offset: [0.0; 3].widen(),
+        });
+      }
+    }
+
+    vpd
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::vmd::BezierInterpolation;
+  use crate::DefaultConfig;
+
+  const FIXTURE_POSE_VPD: &[u8] = include_bytes!("../../fixtures/pose.vpd");
+
+  #[test]
+  fn test_vpd_to_vmd() {
+    let vpd = Vpd::<DefaultConfig>::read(FIXTURE_POSE_VPD).unwrap();
+
+    let vmd = vpd.to_vmd("初音ミク");
+
+    assert_eq!(vmd.header.model_name, "初音ミク");
+    assert_eq!(vmd.motion_frames.len(), vpd.bone_transforms.len());
+    assert_eq!(vmd.morph_frames.len(), vpd.morph_values.len());
+    let frame = &vmd.motion_frames[0];
+    assert_eq!((frame.frame_no, frame.name.as_str()), (0, "操作中心"));
+    assert_eq!(frame.position, vpd.bone_transforms[0].position);
+    assert_eq!(frame.interpolation_curves(), BezierInterpolation::LINEAR);
+    assert!(vmd.camera_frames.is_empty());
+
+    // Names are cut like the writer does
+    let mut bytes = Vec::new();
+    vmd.write(&mut bytes).unwrap();
+    let written = Vmd::<DefaultConfig>::read(&mut std::io::Cursor::new(bytes)).unwrap();
+    for (a, b) in written.motion_frames.iter().zip(&vmd.motion_frames) {
+      assert_eq!(a.name, b.name);
+    }
+    for (a, b) in written.morph_frames.iter().zip(&vmd.morph_frames) {
+      assert_eq!(a.name, b.name);
+    }
+  }
+
+  #[test]
+  fn test_vpd_to_vmd_truncates() {
+    let mut vpd = Vpd::<DefaultConfig>::new(String::new());
+    vpd.bone_transforms.push(BoneTransform {
+      id: 0,
+      name: "左腕捩れボーンズ".to_string(),
+      position: [0.0; 3].into(),
+      rotation: [0.0, 0.0, 0.0, 1.0].into(),
+    });
+
+    let vmd = vpd.to_vmd("");
+
+    assert_eq!(vmd.motion_frames[0].name, "左腕捩れボーン");
+  }
+
+  #[test]
+  fn test_vpd_vmd_round_trip() {
+    let vpd = Vpd::<DefaultConfig>::read(FIXTURE_POSE_VPD).unwrap();
+
+    let round_trip = Vpd::from_vmd(&vpd.to_vmd("model"), 0.0, &vpd.parent_model);
+
+    // The fixture names all fit into a motion
+    assert_eq!(round_trip.bone_transforms, vpd.bone_transforms);
+    // Morphs listed twice in the pose end up as one
+    assert_eq!(round_trip.morph_values.len(), 75);
+    for morph in &vpd.morph_values {
+      let found = round_trip
+        .morph_values
+        .iter()
+        .find(|m| m.name == morph.name);
+      assert_eq!(found.map(|m| m.weight), Some(morph.weight));
+    }
+    assert_eq!(round_trip.parent_model, vpd.parent_model);
+  }
+}
@@ -0,0 +1,164 @@
+//! Lookup of bones and morphs by name.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+use super::{BoneTransform, MorphValue, Vpd};
+use crate::Config;
+
+/// How names are compared by a `VpdIndex`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum NameMatching {
+  #[default]
+  Exact,
+  /// Folds full-width ASCII to half-width, e.g. `ＩＫ` to `IK`, and trims whitespace.
+  Normalized,
+}
+
+impl NameMatching {
+  pub(crate) fn key<'a>(self, name: &'a str) -> Cow<'a, str> {
+    match self {
+      NameMatching::Exact => Cow::Borrowed(name),
+      NameMatching::Normalized => Cow::Owned(normalize_name(name)),
+    }
+  }
+}
+
+/// Folds full-width ASCII and the ideographic space to half-width and trims whitespace.
+pub fn normalize_name(name: &str) -> String {
+  name
+    .chars()
+    .map(|c| match c {
+      '\u{ff01}'..='\u{ff5e}' => char::from_u32(c as u32 - 0xff01 + 0x21).unwrap_or(c),
+      '\u{3000}' => ' ',
+      c => c,
+    })
+    .collect::<String>()
+    .trim()
+    .to_string()
+}
+
+/// Bones and morphs of a pose by name, see `Vpd::index`.
+#[derive(Debug, Clone)]
+pub struct VpdIndex<'a, C: Config> {
+  matching: NameMatching,
+  bones: HashMap<Cow<'a, str>, &'a BoneTransform<C>>,
+  morphs: HashMap<Cow<'a, str>, &'a MorphValue<C>>,
+}
+
+impl<'a, C: Config> VpdIndex<'a, C> {
+  pub fn bone(&self, name: &str) -> Option<&'a BoneTransform<C>> {
+    self.bones.get(&self.matching.key(name)).copied()
+  }
+
+  pub fn morph(&self, name: &str) -> Option<&'a MorphValue<C>> {
+    self.morphs.get(&self.matching.key(name)).copied()
+  }
+}
+
+/// Names listed more than once, in order of their second appearance.
+pub(crate) fn duplicated<'a>(names: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+  let mut seen = HashSet::new();
+  let mut duplicates = Vec::new();
+  for name in names {
+    if !seen.insert(name) && !duplicates.contains(&name) {
+      duplicates.push(name);
+    }
+  }
+
+  duplicates
+}
+
+impl<C: Config> Vpd<C> {
+  /// Builds an index for repeated lookups. Of duplicated names the first one is found.
+  pub fn index(&self, matching: NameMatching) -> VpdIndex<'_, C> {
+    let mut bones = HashMap::with_capacity(self.bone_transforms.len());
+    for bone in &self.bone_transforms {
+      bones.entry(matching.key(&bone.name)).or_insert(bone);
+    }
+    let mut morphs = HashMap::with_capacity(self.morph_values.len());
+    for morph in &self.morph_values {
+      morphs.entry(matching.key(&morph.name)).or_insert(morph);
+    }
+
+    VpdIndex {
+      matching,
+      bones,
+      morphs,
+    }
+  }
+
+  /// The first bone named exactly `name`, see `index` for many lookups.
+  pub fn bone(&self, name: &str) -> Option<&BoneTransform<C>> {
+    self.bone_transforms.iter().find(|b| b.name == name)
+  }
+
+  /// The first morph named exactly `name`, see `index` for many lookups.
+  pub fn morph(&self, name: &str) -> Option<&MorphValue<C>> {
+    self.morph_values.iter().find(|m| m.name == name)
+  }
+
+  /// Bone names listed more than once, which lookups only find the first of.
+  pub fn duplicates(&self) -> Vec<&str> {
+    duplicated(self.bone_transforms.iter().map(|b| b.name.as_str()))
+  }
+
+  /// Morph names listed more than once, which lookups only find the first of.
+  pub fn duplicate_morphs(&self) -> Vec<&str> {
+    duplicated(self.morph_values.iter().map(|m| m.name.as_str()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::DefaultConfig;
+
+  const FIXTURE_POSE_VPD: &[u8] = include_bytes!("../../fixtures/pose.vpd");
+
+  #[test]
+  fn test_normalize_name() {
+    assert_eq!(normalize_name("左足ＩＫ"), "左足IK");
+    assert_eq!(normalize_name("　右腕 "), "右腕");
+    assert_eq!(normalize_name("ｰ"), "ｰ");
+  }
+
+  #[test]
+  fn test_lookup_fixture() {
+    let vpd = Vpd::<DefaultConfig>::read(FIXTURE_POSE_VPD).unwrap();
+
+    assert_eq!(vpd.bone("右腕").map(|b| b.id), Some(18));
+    assert!(vpd.bone("左足IK").is_none());
+
+    let exact = vpd.index(NameMatching::Exact);
+    assert_eq!(exact.bone("左足ＩＫ").map(|b| b.id), Some(9));
+    assert!(exact.bone("左足IK").is_none());
+    assert_eq!(exact.morph("怒り").map(|m| m.id), Some(0));
+
+    let normalized = vpd.index(NameMatching::Normalized);
+    assert_eq!(normalized.bone("左足IK").map(|b| b.id), Some(9));
+    assert_eq!(normalized.bone(" 左足ＩＫ").map(|b| b.id), Some(9));
+    assert_eq!(normalized.morph("Eye_Doubt02").map(|m| m.id), Some(78));
+    assert!(normalized.bone("missing").is_none());
+  }
+
+  #[test]
+  fn test_duplicates() {
+    let mut vpd = Vpd::<DefaultConfig>::read(FIXTURE_POSE_VPD).unwrap();
+    assert!(vpd.duplicates().is_empty());
+    let mut morphs = vpd.duplicate_morphs();
+    morphs.sort();
+    assert_eq!(morphs, ["ウィンク", "ウィンク２", "口", "口角下げ?"]);
+
+    let mut copy = vpd.bone_transforms[18].clone();
+    copy.id = 1000;
+    vpd.bone_transforms.push(copy);
+
+    assert_eq!(vpd.duplicates(), ["右腕"]);
+    assert_eq!(vpd.bone("右腕").map(|b| b.id), Some(18));
+    assert_eq!(
+      vpd.index(NameMatching::Exact).bone("右腕").map(|b| b.id),
+      Some(18)
+    );
+  }
+}
@@ -1,7 +1,45 @@
-use crate::{Config, DefaultConfig};
+use alloc::{
+  format,
+  string::{String, ToString},
+  vec::Vec,
+};
+#[cfg(feature = "std")]
+use std::{fs::File, path::Path};
 
-const HEADER: &str = "Vocaloid Pose Data file";
+use encoding_rs::SHIFT_JIS;
 
+use crate::io::{BufRead, BufReader, Read};
+use crate::math::Widen;
+#[cfg(feature = "serde")]
+use crate::SerdeConfig;
+use crate::{Config, DefaultConfig, Float};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "std")]
+mod convert;
+#[cfg(feature = "std")]
+mod index;
+#[cfg(feature = "std")]
+mod pose;
+#[cfg(feature = "std")]
+mod writer;
+
+#[cfg(feature = "std")]
+pub(crate) use self::index::duplicated;
+#[cfg(feature = "std")]
+pub use self::index::{normalize_name, NameMatching, VpdIndex};
+
+pub(crate) const HEADER: &str = "Vocaloid Pose Data file";
+/// Skipped in front of the header of poses saved as UTF-8 by text editors.
+pub(crate) const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct BoneTransform<C: Config = DefaultConfig> {
   pub id: u32,
   pub name: String,
@@ -9,6 +47,12 @@ pub struct BoneTransform<C: Config = DefaultConfig> {
   pub rotation: C::Vec4,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct MorphValue<C: Config = DefaultConfig> {
   pub id: u32,
   pub name: String,
@@ -16,73 +60,537 @@ pub struct MorphValue<C: Config = DefaultConfig> {
   pub offset: C::Vec3,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(Serialize, Deserialize),
+  serde(bound = "C: SerdeConfig")
+)]
 pub struct Vpd<C: Config = DefaultConfig> {
+  /// Name of the pose itself. It is not stored in the file and taken from the file name by
+  /// `from_file`, e.g. `ピース` for `ピース.vpd`.
   pub name: String,
+  /// File name of the model the pose was made for, from the second line, e.g. `初音ミク.osm`.
+  pub parent_model: String,
   pub bone_transforms: Vec<BoneTransform<C>>,
   pub morph_values: Vec<MorphValue<C>>,
 }
 
+/// Non-empty lines with comments (starting with "//") removed, numbered from 1.
+struct Lines<R> {
+  read: R,
+  buf: Vec<u8>,
+  /// Number of the last line read.
+  last: usize,
+}
+
+impl<R: BufRead> Lines<R> {
+  fn new(read: R) -> Self {
+    Self {
+      read,
+      buf: Vec::new(),
+      last: 0,
+    }
+  }
+
+  fn next(&mut self) -> crate::Result<Option<(usize, String)>> {
+    loop {
+      self.buf.clear();
+      if self.read.read_until(b'\n', &mut self.buf)? == 0 {
+        return Ok(None);
+      }
+      self.last += 1;
+      if self.last == 1 && self.buf.starts_with(UTF8_BOM) {
+        self.buf.drain(..UTF8_BOM.len());
+      }
+
+      // Shift_JIS trail bytes are never '\n', so lines can be decoded one by one
+      let text = decode_text(&self.buf);
+      let line = match text.find("//") {
+        Some(pos) => &text[..pos],
+        None => &text,
+      };
+      let line = line.trim();
+      if !line.is_empty() {
+        return Ok(Some((self.last, line.to_string())));
+      }
+    }
+  }
+
+  /// The next line, failing with `message` at `line` at the end of the input.
+  fn expect(&mut self, line: Option<usize>, message: &str) -> crate::Result<(usize, String)> {
+    match self.next()? {
+      Some(line) => Ok(line),
+      None => Err(parse_error(line.unwrap_or(self.last), message.to_string())),
+    }
+  }
+}
+
+/// Decodes text as Shift_JIS, as written by MMD, and falls back to UTF-8 like VMD names. Text
+/// valid in neither gets replacement characters.
+fn decode_text(bytes: &[u8]) -> alloc::borrow::Cow<'_, str> {
+  let (text, _, is_malformed) = SHIFT_JIS.decode(bytes);
+  if is_malformed {
+    if let Ok(text) = core::str::from_utf8(bytes) {
+      return text.into();
+    }
+  }
+
+  text
+}
+
+/// How `Vpd::read_with` treats a bone count line that doesn't match the bone blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ReadMode {
+  /// Fail with `Error::CountMismatch`.
+  Strict,
+  /// Report `Warning::CountMismatch` and keep the blocks found.
+  #[default]
+  Lenient,
+}
+
+/// Something odd found by `Vpd::read_with`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+  /// The bone count line doesn't match the number of bone blocks.
+  CountMismatch { declared: u32, found: u32 },
+}
+
+fn parse_error(line: usize, message: String) -> crate::Error {
+  crate::Error::VpdParse { line, message }
+}
+
+/// The start of a line to quote in errors.
+fn snippet(text: &str) -> String {
+  const LENGTH: usize = 32;
+  match text.char_indices().nth(LENGTH) {
+    Some((end, _)) => format!("`{}…`", &text[..end]),
+    None => format!("`{}`", text),
+  }
+}
+
+/// Strips the `;` ending a value line.
+fn strip_semicolon((line, text): (usize, &str)) -> crate::Result<&str> {
+  text
+    .strip_suffix(';')
+    .ok_or_else(|| parse_error(line, format!("missing `;` at the end of {}", snippet(text))))
+}
+
+/// Parses a `x,y,...;` line of `N` numbers, as `f64` for configs with `f64` vectors.
+fn parse_values<const N: usize>((line, text): (usize, &str)) -> crate::Result<[f64; N]> {
+  let values = strip_semicolon((line, text))?;
+  let invalid = || {
+    parse_error(
+      line,
+      format!(
+        "expected {} comma-separated numbers, found {}",
+        N,
+        snippet(text)
+      ),
+    )
+  };
+
+  let mut result = [0.0; N];
+  let mut values = values.split(',');
+  for value in result.iter_mut() {
+    *value = values
+      .next()
+      .and_then(|v| v.trim().parse().ok())
+      .ok_or_else(invalid)?;
+  }
+  if values.next().is_some() {
+    return Err(invalid());
+  }
+
+  Ok(result)
+}
+
+/// An open `Bone0{name` or `Morph0{name` block.
+struct Block {
+  kind: &'static str,
+  id: u32,
+  name: String,
+  line: usize,
+}
+
+impl Block {
+  /// Parses the line opening a block.
+  fn parse((line, text): (usize, &str)) -> crate::Result<Self> {
+    let (kind, rest) = ["Bone", "Morph"]
+      .iter()
+      .find_map(|&kind| Some((kind, text.strip_prefix(kind)?)))
+      .ok_or_else(|| {
+        parse_error(
+          line,
+          format!(
+            "expected a `Bone` or `Morph` block, found {}",
+            snippet(text)
+          ),
+        )
+      })?;
+    let brace = rest
+      .find('{')
+      .ok_or_else(|| parse_error(line, format!("missing `{{` in {}", snippet(text))))?;
+    let id = rest[..brace].trim().parse().map_err(|_| {
+      parse_error(
+        line,
+        format!(
+          "invalid {} number in {}",
+          kind.to_lowercase(),
+          snippet(text)
+        ),
+      )
+    })?;
+
+    Ok(Self {
+      kind,
+      id,
+      name: rest[brace + 1..].trim().to_string(),
+      line,
+    })
+  }
+
+  /// The next line of the block.
+  fn line<R: BufRead>(&self, lines: &mut Lines<R>) -> crate::Result<(usize, String)> {
+    lines.expect(Some(self.line), &self.unclosed())
+  }
+
+  fn unclosed(&self) -> String {
+    format!("{} block of `{}` is not closed", self.kind, self.name)
+  }
+
+  fn end<R: BufRead>(&self, lines: &mut Lines<R>) -> crate::Result<()> {
+    match self.line(lines)? {
+      (_, text) if text == "}" => Ok(()),
+      (line, text) => Err(parse_error(
+        line,
+        format!(
+          "expected `}}` closing the {} block of `{}` from line {}, found {}",
+          self.kind,
+          self.name,
+          self.line,
+          snippet(&text)
+        ),
+      )),
+    }
+  }
+}
+
 impl<C: Config> Vpd<C> {
   pub fn new(name: String) -> Self {
     Self {
       name,
+      parent_model: String::new(),
       bone_transforms: Vec::new(),
       morph_values: Vec::new(),
     }
   }
 
-  pub fn read<R: std::io::Read>(mut reader: R) -> crate::Result<Self> {
-    let mut string_buf = String::new();
+  /// Reads a pose, see `read_with`. The name is left empty.
+  pub fn read<R: Read>(reader: R) -> crate::Result<Self> {
+    Self::read_with(reader, ReadMode::Lenient).map(|(vpd, _)| vpd)
+  }
 
-    fn read_line<'a, R: std::io::Read>(
-      reader: &mut R,
-      buf: &'a mut String,
-    ) -> crate::Result<(&'a str, usize)> {
-      buf.clear();
-      let bytes = reader.read_to_string(buf)?;
-      let mut line = buf.trim();
+  /// Reads a pose named after the file stem.
+  #[cfg(feature = "std")]
+  pub fn from_file<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+    let path = path.as_ref();
+    let mut vpd = Self::read(File::open(path)?)?;
+    if let Some(stem) = path.file_stem() {
+      vpd.name = stem.to_string_lossy().into_owned();
+    }
 
-      // Remove comments (starting with "//")
-      if let Some(pos) = line.find("//") {
-        line = &line[..pos].trim();
-      }
+    Ok(vpd)
+  }
 
-      Ok((line, bytes))
-    }
+  /// Reads a pose, checking the declared bone count according to `mode`. The name is left empty.
+  pub fn read_with<R: Read>(reader: R, mode: ReadMode) -> crate::Result<(Self, Vec<Warning>)> {
+    let mut lines = Lines::new(BufReader::new(reader));
 
-    // Read header
-    read_line(&mut reader, &mut string_buf)?;
-    if string_buf.trim() != HEADER {
-      return Err(crate::Error::InvalidHeader);
+    match lines.next()? {
+      Some((_, line)) if line == HEADER => {}
+      _ => return Err(crate::Error::InvalidHeader),
     }
 
-    loop {
-      string_buf.clear();
+    // The model file name and the number of bones
+    let (line, model) = lines.expect(None, "the file ends before the parent model name")?;
+    let parent_model = strip_semicolon((line, &model))?.trim().to_string();
+    let (line, count) = lines.expect(None, "the file ends before the bone count")?;
+    let declared: u32 = strip_semicolon((line, &count))?
+      .trim()
+      .parse()
+      .map_err(|_| {
+        parse_error(
+          line,
+          format!("expected the bone count, found {}", snippet(&count)),
+        )
+      })?;
+
+    let mut vpd = Self::new(String::new());
+    vpd.parent_model = parent_model;
 
-      let (line, total_bytes) = read_line(&mut reader, &mut string_buf)?;
+    while let Some((line, text)) = lines.next()? {
+      let block = Block::parse((line, &text))?;
+      if block.kind == "Bone" {
+        let (line, position) = block.line(&mut lines)?;
+        let position = parse_values::<3>((line, &position))?;
+        let (line, rotation) = block.line(&mut lines)?;
+        let rotation = parse_values::<4>((line, &rotation))?;
+        block.end(&mut lines)?;
 
-      // EOF
-      if total_bytes == 0 {
-        break;
+        vpd.bone_transforms.push(BoneTransform {
+          id: block.id,
+          name: block.name,
+          position: position.map(C::Float::from_f64).into(),
+          rotation: rotation.map(C::Float::from_f64).into(),
+        });
+      } else {
+        let (line, weight) = block.line(&mut lines)?;
+        let [weight] = parse_values::<1>((line, &weight))?;
+        block.end(&mut lines)?;
+
+        vpd.morph_values.push(MorphValue {
+          id: block.id,
+          name: block.name,
+          weight: weight as f32,
+          offset: [0.0; 3].widen(),
+        });
       }
+    }
 
-      // Skip empty lines
-      if line.is_empty() {
-        continue;
+    let mut warnings = Vec::new();
+    let found = vpd.bone_transforms.len() as u32;
+    if found != declared {
+      match mode {
+        ReadMode::Strict => return Err(crate::Error::CountMismatch { declared, found }),
+        ReadMode::Lenient => warnings.push(Warning::CountMismatch { declared, found }),
       }
+    }
 
-      if line.starts_with("Bone") {
-        let _id: u32 = line[4..].trim().parse().expect("Invalid bone ID");
+    Ok((vpd, warnings))
+  }
+}
 
-        todo!()
-      } else if line.starts_with("Morph") {
-        todo!()
-      } else {
-        // TODO: Better error handling
-        panic!("Invalid line: {}", line);
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::math::to_array;
+
+  const FIXTURE_POSE_VPD: &[u8] = include_bytes!("../../fixtures/pose.vpd");
+
+  #[cfg(feature = "glam")]
+  #[test]
+  fn test_vpd_read_glam() {
+    let vpd = Vpd::<crate::GlamConfig>::read(FIXTURE_POSE_VPD).unwrap();
+
+    assert_eq!(vpd.bone_transforms.len(), 355);
+    assert_eq!(
+      vpd.bone_transforms[0].position,
+      glam::Vec3::new(-3.178847, -2.327402, 0.0)
+    );
+  }
+
+  #[cfg(feature = "nalgebra")]
+  #[test]
+  fn test_vpd_read_nalgebra() {
+    let vpd = Vpd::<crate::NalgebraConfig>::read(FIXTURE_POSE_VPD).unwrap();
+
+    assert_eq!(vpd.bone_transforms.len(), 355);
+    assert_eq!(
+      vpd.bone_transforms[0].position,
+      nalgebra::Vector3::new(-3.178847, -2.327402, 0.0)
+    );
+  }
+
+  #[test]
+  fn test_vpd_read_shift_jis() {
+    let vpd = Vpd::<DefaultConfig>::read(FIXTURE_POSE_VPD).unwrap();
+
+    assert_eq!(vpd.name, "");
+    assert_eq!(vpd.parent_model, "星穹?道?桂乃芬.osm");
+    assert_eq!(vpd.bone_transforms.len(), 355);
+    assert_eq!(vpd.morph_values.len(), 79);
+    let bone = &vpd.bone_transforms[0];
+    assert_eq!((bone.id, bone.name.as_str()), (0, "操作中心"));
+    assert_eq!(to_array::<3>(&bone.position), [-3.178847, -2.327402, 0.0]);
+    assert_eq!(vpd.bone_transforms[9].name, "左足ＩＫ");
+    assert_eq!(vpd.bone_transforms[18].name, "右腕");
+    assert_eq!(vpd.morph_values[0].name, "怒り");
+    assert_eq!(vpd.morph_values[78].name, "Eye_Doubt02");
+  }
+
+  #[test]
+  fn test_vpd_read_f64() {
+    let vpd = Vpd::<crate::F64Config>::read(FIXTURE_POSE_VPD).unwrap();
+    // Parsed from the text, not through f32
+    assert_eq!(vpd.bone_transforms[0].position, [-3.178847, -2.327402, 0.0]);
+
+    let text =
+      "Vocaloid Pose Data file\n\nmodel.osm;\n1;\n\nBone0{右腕\n  0.1,0,0;\n  0,0,0,1;\n}\n";
+    let vpd = Vpd::<crate::F64Config>::read(text.as_bytes()).unwrap();
+    assert_eq!(vpd.bone_transforms[0].position[0], 0.1);
+    let vpd = Vpd::<DefaultConfig>::read(text.as_bytes()).unwrap();
+    assert_eq!(vpd.bone_transforms[0].position[0], 0.1f32);
+  }
+
+  #[test]
+  fn test_vpd_read_utf8() {
+    let text = "Vocaloid Pose Data file\n\nmodel.osm;\n1;\n\nBone0{右腕\n  0,0,0;\n  0,0,0,1;\n}\n";
+
+    let vpd = Vpd::<DefaultConfig>::read(text.as_bytes()).unwrap();
+
+    assert_eq!(vpd.bone_transforms[0].name, "右腕");
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn test_vpd_round_trip_fixture() {
+    let vpd = Vpd::<DefaultConfig>::read(FIXTURE_POSE_VPD).unwrap();
+    let mut buf = Vec::new();
+    vpd.write(&mut buf).unwrap();
+
+    assert_eq!(Vpd::<DefaultConfig>::read(&buf[..]).unwrap(), vpd);
+  }
+
+  #[test]
+  fn test_vpd_count_mismatch() {
+    let text = "Vocaloid Pose Data file\n\nmodel.osm;\n2;\n\nBone0{右腕\n  0,0,0;\n  0,0,0,1;\n}\n";
+
+    let (vpd, warnings) =
+      Vpd::<DefaultConfig>::read_with(text.as_bytes(), ReadMode::Lenient).unwrap();
+    assert_eq!(vpd.bone_transforms.len(), 1);
+    assert_eq!(
+      warnings,
+      [Warning::CountMismatch {
+        declared: 2,
+        found: 1
+      }]
+    );
+
+    match Vpd::<DefaultConfig>::read_with(text.as_bytes(), ReadMode::Strict) {
+      Err(crate::Error::CountMismatch {
+        declared: 2,
+        found: 1,
+      }) => {}
+      result => panic!("{:?}", result),
+    }
+
+    let (_, warnings) =
+      Vpd::<DefaultConfig>::read_with(FIXTURE_POSE_VPD, ReadMode::Strict).unwrap();
+    assert_eq!(warnings, []);
+  }
+
+  fn parse_error_at(text: &str) -> (usize, String) {
+    match Vpd::<DefaultConfig>::read(text.as_bytes()) {
+      Err(crate::Error::VpdParse { line, message }) => (line, message),
+      result => panic!("{:?}", result.map(|_| ())),
+    }
+  }
+
+  #[test]
+  fn test_vpd_parse_errors() {
+    let pose = |body: &str| format!("Vocaloid Pose Data file\n\nmodel.osm;\n1;\n\n{}", body);
+
+    let (line, message) =
+      parse_error_at(&pose("Bone0{右腕\n  0,0,0;\n  0,0,0,1;\n}\n\nCamera0{x\n"));
+    assert_eq!(line, 11);
+    assert_eq!(
+      message,
+      "expected a `Bone` or `Morph` block, found `Camera0{x`"
+    );
+
+    let (line, message) = parse_error_at(&pose("Bone0{右腕\n  0,abc,0;\n  0,0,0,1;\n}\n"));
+    assert_eq!(line, 7);
+    assert_eq!(
+      message,
+      "expected 3 comma-separated numbers, found `0,abc,0;`"
+    );
+
+    let (line, _) = parse_error_at(&pose("Bone0{右腕\n  0,0,0;\n  0,0,0;\n}\n"));
+    assert_eq!(line, 8);
+
+    let (line, message) = parse_error_at(&pose("Bone0{右腕\n  0,0,0;  // trans\n  0,0,0,1\n}\n"));
+    assert_eq!(line, 8);
+    assert_eq!(message, "missing `;` at the end of `0,0,0,1`");
+
+    let (line, message) = parse_error_at(&pose("Bone0{右腕\n  0,0,0;\n  0,0,0,1;\n\nBone1{左腕\n"));
+    assert_eq!(line, 10);
+    assert_eq!(
+      message,
+      "expected `}` closing the Bone block of `右腕` from line 6, found `Bone1{左腕`"
+    );
+
+    let (line, message) = parse_error_at(&pose("Morph0{あ\n  0.5;\n}\nBone1{左腕\n  0,0,0;\n"));
+    assert_eq!(line, 9);
+    assert_eq!(message, "Bone block of `左腕` is not closed");
+
+    let (line, message) = parse_error_at(&pose("Bone{右腕\n"));
+    assert_eq!(line, 6);
+    assert_eq!(message, "invalid bone number in `Bone{右腕`");
+
+    let (line, _) = parse_error_at("Vocaloid Pose Data file\n\nmodel.osm\n1;\n");
+    assert_eq!(line, 3);
+    let (line, message) = parse_error_at("Vocaloid Pose Data file\n\nmodel.osm;\n");
+    assert_eq!(line, 3);
+    assert_eq!(message, "the file ends before the bone count");
+
+    let error = Vpd::<DefaultConfig>::read(pose("Bone0{右腕\n").as_bytes())
+      .err()
+      .unwrap();
+    assert_eq!(
+      error.to_string(),
+      "Line 6: Bone block of `右腕` is not closed"
+    );
+  }
+
+  /// Hands out a single byte per read.
+  struct Trickle<'a>(&'a [u8]);
+
+  impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> crate::io::Result<usize> {
+      match (self.0.split_first(), buf.first_mut()) {
+        (Some((&byte, rest)), Some(out)) => {
+          *out = byte;
+          self.0 = rest;
+          Ok(1)
+        }
+        _ => Ok(0),
       }
     }
+  }
+
+  #[test]
+  fn test_vpd_read_lines() {
+    let mut text =
+      String::from("Vocaloid Pose Data file\r\n\r\nmodel.osm; // 親ファイル名\r\n200;\r\n");
+    for i in 0..200 {
+      text += &format!(
+        "\r\n// bone {}\r\n\r\nBone{}{{ボーン{}\r\n  {}.5,0,0; // trans\r\n\r\n  0,0,0,1;\r\n}}\r\n",
+        i, i, i, i
+      );
+    }
+    text += "\r\nMorph0{あ // comment\r\n  0.25;\r\n}";
+    let bytes = SHIFT_JIS.encode(&text).0;
+
+    let (vpd, warnings) =
+      Vpd::<DefaultConfig>::read_with(Trickle(&bytes), ReadMode::Strict).unwrap();
+
+    assert_eq!(warnings, []);
+    assert_eq!(vpd.bone_transforms.len(), 200);
+    let bone = &vpd.bone_transforms[123];
+    assert_eq!((bone.id, bone.name.as_str()), (123, "ボーン123"));
+    assert_eq!(to_array::<3>(&bone.position), [123.5, 0.0, 0.0]);
+    assert_eq!(vpd.morph_values[0].name, "あ");
+    assert_eq!(vpd.morph_values[0].weight, 0.25);
+  }
 
-    todo!()
+  #[cfg(feature = "serde")]
+  #[test]
+  fn test_vpd_serde_round_trip() {
+    let vpd = Vpd::<DefaultConfig>::read(FIXTURE_POSE_VPD).unwrap();
+    let json = serde_json::to_string(&vpd).unwrap();
+    assert_eq!(serde_json::from_str::<Vpd>(&json).unwrap(), vpd);
+    let bytes = bincode::serialize(&vpd).unwrap();
+    assert_eq!(bincode::deserialize::<Vpd>(&bytes).unwrap(), vpd);
   }
 }
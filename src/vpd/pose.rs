@@ -0,0 +1,365 @@
+//! Arithmetic on whole poses, matching bones and morphs by name.
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+use super::{BoneTransform, MorphValue, Vpd};
+use crate::math::{lerp, lerp3, quat_mul, rotate3, slerp, to_array, Widen};
+use crate::Config;
+
+const IDENTITY: ([f32; 3], [f32; 4]) = ([0.0; 3], [0.0, 0.0, 0.0, 1.0]);
+
+/// Pairs up entries of `a` and `b` by name, in the order of `a` followed by the entries only in
+/// `b`. Ids come from `a`, entries only in `b` are numbered after the largest one.
+fn pair_by_name<'a, T>(
+  a: &'a [T],
+  b: &'a [T],
+  key: impl Fn(&T) -> (u32, &str),
+) -> Vec<(u32, &'a str, Option<&'a T>, Option<&'a T>)> {
+  let in_b: HashMap<&str, &T> = b.iter().map(|entry| (key(entry).1, entry)).collect();
+  let in_a: HashMap<&str, &T> = a.iter().map(|entry| (key(entry).1, entry)).collect();
+
+  let mut pairs: Vec<_> = a
+    .iter()
+    .map(|entry| {
+      let (id, name) = key(entry);
+      (id, name, Some(entry), in_b.get(name).copied())
+    })
+    .collect();
+
+  let mut next_id = a.iter().map(|entry| key(entry).0 + 1).max().unwrap_or(0);
+  for entry in b {
+    let (_, name) = key(entry);
+    if !in_a.contains_key(name) {
+      pairs.push((next_id, name, None, Some(entry)));
+      next_id += 1;
+    }
+  }
+
+  pairs
+}
+
+fn bone_key<C: Config>(bone: &BoneTransform<C>) -> (u32, &str) {
+  (bone.id, &bone.name)
+}
+
+fn morph_key<C: Config>(morph: &MorphValue<C>) -> (u32, &str) {
+  (morph.id, &morph.name)
+}
+
+fn offset<C: Config>(morph: Option<&MorphValue<C>>) -> [f32; 3] {
+  morph.map_or([0.0; 3], |m| to_array(&m.offset))
+}
+
+fn weight<C: Config>(morph: Option<&MorphValue<C>>) -> f32 {
+  morph.map_or(0.0, |m| m.weight)
+}
+
+fn transform<C: Config>(bone: Option<&BoneTransform<C>>) -> ([f32; 3], [f32; 4]) {
+  bone.map_or(IDENTITY, |bone| {
+    (to_array(&bone.position), to_array(&bone.rotation))
+  })
+}
+
+impl<C: Config> Vpd<C> {
+  /// Interpolates from this pose at `t = 0` to `other` at `t = 1`, matching bones and morphs by
+  /// name since ids differ between files.
+  ///
+  /// Positions and morph weights are lerped and rotations slerped along the shortest arc. A bone
+  /// missing in one of the poses is taken as the identity there, a morph as weight 0. `t` is not
+  /// clamped, values outside `0.0..=1.0` extrapolate into an overshooting pose. The names and ids
+  /// of this pose are kept.
+  pub fn blend(&self, other: &Vpd<C>, t: f32) -> Vpd<C> {
+    let bone_transforms = pair_by_name(&self.bone_transforms, &other.bone_transforms, bone_key)
+      .into_iter()
+      .map(|(id, name, a, b)| {
+        let (a, b) = (transform(a), transform(b));
+        BoneTransform {
+          id,
+          name: name.to_string(),
+          position: lerp3(a.0, b.0, [t; 3]).widen(),
+          rotation: slerp(a.1, b.1, t).widen(),
+        }
+      })
+      .collect();
+
+    let morph_values = pair_by_name(&self.morph_values, &other.morph_values, morph_key)
+      .into_iter()
+      .map(|(id, name, a, b)| {
+        let weight = |morph: Option<&MorphValue<C>>| morph.map_or(0.0, |m| m.weight);
+        let offset =
+          |morph: Option<&MorphValue<C>>| morph.map_or([0.0; 3], |m| to_array(&m.offset));
+        MorphValue {
+          id,
+          name: name.to_string(),
+          weight: lerp(weight(a), weight(b), t),
+          offset: lerp3(offset(a), offset(b), [t; 3]).widen(),
+        }
+      })
+      .collect();
+
+    Vpd {
+      name: self.name.clone(),
+      parent_model: self.parent_model.clone(),
+      bone_transforms,
+      morph_values,
+    }
+  }
+
+  /// Applies `overlay` on top of this pose, adding morph weights without clamping.
+  ///
+  /// See `compose_with`.
+  pub fn compose(&self, overlay: &Vpd<C>) -> Vpd<C> {
+    self.compose_with(overlay, f32::NEG_INFINITY..=f32::INFINITY)
+  }
+
+  /// Applies `overlay` on top of this pose, matching bones and morphs by name like `blend`.
+  ///
+  /// The overlay transform of a bone is applied in the space of this pose: rotations multiply
+  /// and the overlay translation is rotated before it is added. `b.compose(&a.inverse())` is the
+  /// difference `d` between the poses, with `d.compose(&a)` giving `b` back. Bones and morphs in only one of the poses are kept as
+  /// they are. Morph weights add up and are clamped to `weights`.
+  pub fn compose_with(&self, overlay: &Vpd<C>, weights: RangeInclusive<f32>) -> Vpd<C> {
+    let bone_transforms = pair_by_name(&self.bone_transforms, &overlay.bone_transforms, bone_key)
+      .into_iter()
+      .map(|(id, name, a, b)| {
+        let ((p, q), (op, oq)) = (transform(a), transform(b));
+        let [x, y, z] = rotate3(q, op);
+        BoneTransform {
+          id,
+          name: name.to_string(),
+          position: [p[0] + x, p[1] + y, p[2] + z].widen(),
+          rotation: quat_mul(q, oq).widen(),
+        }
+      })
+      .collect();
+
+    let morph_values = pair_by_name(&self.morph_values, &overlay.morph_values, morph_key)
+      .into_iter()
+      .map(|(id, name, a, b)| {
+        let (offset_a, offset_b) = (offset(a), offset(b));
+        MorphValue {
+          id,
+          name: name.to_string(),
+          weight: (weight(a) + weight(b)).clamp(*weights.start(), *weights.end()),
+          offset: [
+            offset_a[0] + offset_b[0],
+            offset_a[1] + offset_b[1],
+            offset_a[2] + offset_b[2],
+          ]
+          .widen(),
+        }
+      })
+      .collect();
+
+    Vpd {
+      name: self.name.clone(),
+      parent_model: self.parent_model.clone(),
+      bone_transforms,
+      morph_values,
+    }
+  }
+
+  /// The pose undoing this one when composed with it, see `compose`.
+  ///
+  /// Rotations are conjugated and translations negated in the inverted rotation, morph weights
+  /// and offsets are negated.
+  pub fn inverse(&self) -> Vpd<C> {
+    let bone_transforms = self
+      .bone_transforms
+      .iter()
+      .map(|bone| {
+        let [x, y, z, w] = to_array(&bone.rotation);
+        let conjugate = [-x, -y, -z, w];
+        let [px, py, pz] = rotate3(conjugate, to_array(&bone.position));
+        BoneTransform {
+          id: bone.id,
+          name: bone.name.clone(),
+          position: [-px, -py, -pz].widen(),
+          rotation: conjugate.widen(),
+        }
+      })
+      .collect();
+
+    let morph_values = self
+      .morph_values
+      .iter()
+      .map(|morph| {
+        let [x, y, z] = to_array(&morph.offset);
+        MorphValue {
+          id: morph.id,
+          name: morph.name.clone(),
+          weight: -morph.weight,
+          offset: [-x, -y, -z].widen(),
+        }
+      })
+      .collect();
+
+    Vpd {
+      name: self.name.clone(),
+      parent_model: self.parent_model.clone(),
+      bone_transforms,
+      morph_values,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::DefaultConfig;
+
+  const EPSILON: f32 = 1e-5;
+  const FIXTURE_POSE_VPD: &[u8] = include_bytes!("../../fixtures/pose.vpd");
+
+  fn assert_near(a: &[f32], b: &[f32]) {
+    assert!(
+      a.iter().zip(b).all(|(a, b)| (a - b).abs() < EPSILON),
+      "{:?} {:?}",
+      a,
+      b
+    );
+  }
+
+  fn bone(id: u32, name: &str, position: [f32; 3], angle: f32) -> BoneTransform {
+    let (sin, cos) = (angle * 0.5).sin_cos();
+    BoneTransform {
+      id,
+      name: name.to_string(),
+      position: position.into(),
+      rotation: [0.0, sin, 0.0, cos].into(),
+    }
+  }
+
+  fn morph(id: u32, name: &str, weight: f32) -> MorphValue {
+    MorphValue {
+      id,
+      name: name.to_string(),
+      weight,
+      offset: [0.0; 3].into(),
+    }
+  }
+
+  #[test]
+  fn test_blend_self() {
+    let vpd = Vpd::<DefaultConfig>::read(FIXTURE_POSE_VPD).unwrap();
+
+    for &t in &[-1.0, 0.0, 0.3, 1.0, 2.5] {
+      let blended = vpd.blend(&vpd, t);
+
+      assert_eq!(blended.bone_transforms.len(), vpd.bone_transforms.len());
+      for (a, b) in blended.bone_transforms.iter().zip(&vpd.bone_transforms) {
+        assert_eq!((a.id, &a.name), (b.id, &b.name));
+        assert_near(a.position.as_slice(), b.position.as_slice());
+        assert_near(a.rotation.as_slice(), b.rotation.as_slice());
+      }
+      assert_eq!(blended.morph_values, vpd.morph_values);
+    }
+  }
+
+  #[test]
+  fn test_blend_by_name() {
+    let mut a = Vpd::<DefaultConfig>::new("a".to_string());
+    a.bone_transforms = vec![
+      bone(0, "センター", [0.0; 3], 0.0),
+      bone(1, "首", [1.0; 3], 1.0),
+    ];
+    a.morph_values = vec![morph(0, "あ", 1.0)];
+    let mut b = Vpd::<DefaultConfig>::new("b".to_string());
+    b.bone_transforms = vec![
+      bone(0, "頭", [0.0; 3], 0.5),
+      bone(3, "センター", [2.0, 0.0, -4.0], 1.0),
+    ];
+    b.morph_values = vec![morph(0, "い", 0.5)];
+
+    let blended = a.blend(&b, 0.5);
+
+    assert_eq!(blended.name, "a");
+    let names: Vec<_> = blended
+      .bone_transforms
+      .iter()
+      .map(|b| (b.id, b.name.as_str()))
+      .collect();
+    assert_eq!(names, [(0, "センター"), (1, "首"), (2, "頭")]);
+    let [center, neck, head] = [0, 1, 2].map(|i| &blended.bone_transforms[i]);
+    assert_near(center.position.as_slice(), &[1.0, 0.0, -2.0]);
+    assert_near(
+      center.rotation.as_slice(),
+      &[0.0, 0.25f32.sin(), 0.0, 0.25f32.cos()],
+    );
+    assert_near(neck.position.as_slice(), &[0.5; 3]);
+    assert_near(
+      head.rotation.as_slice(),
+      &[0.0, 0.125f32.sin(), 0.0, 0.125f32.cos()],
+    );
+    assert_eq!(blended.morph_values[0].weight, 0.5);
+    assert_eq!(blended.morph_values[1].weight, 0.25);
+
+    // Extrapolated past the other pose
+    let overshoot = a.blend(&b, 2.0);
+    assert_near(
+      overshoot.bone_transforms[0].position.as_slice(),
+      &[4.0, 0.0, -8.0],
+    );
+    assert_near(
+      overshoot.bone_transforms[0].rotation.as_slice(),
+      &[0.0, 1.0f32.sin(), 0.0, 1.0f32.cos()],
+    );
+  }
+
+  fn assert_identity(vpd: &Vpd) {
+    for bone in &vpd.bone_transforms {
+      assert_near(bone.position.as_slice(), &[0.0; 3]);
+      let [x, y, z, w] = to_array::<4>(&bone.rotation);
+      // Either sign of the quaternion is the identity
+      assert_near(&[x, y, z, w.abs()], &[0.0, 0.0, 0.0, 1.0]);
+    }
+    assert!(vpd.morph_values.iter().all(|m| m.weight.abs() < EPSILON));
+  }
+
+  #[test]
+  fn test_compose_inverse_fixture() {
+    let mut vpd = Vpd::<DefaultConfig>::read(FIXTURE_POSE_VPD).unwrap();
+    vpd.bone_transforms[1] = bone(1, "全ての親", [1.0, -2.0, 3.0], 0.7);
+    vpd.morph_values[0].weight = 0.4;
+
+    let composed = vpd.compose(&vpd.inverse());
+
+    assert_eq!(composed.bone_transforms.len(), vpd.bone_transforms.len());
+    assert_identity(&composed);
+    assert_identity(&vpd.inverse().compose(&vpd));
+  }
+
+  #[test]
+  fn test_compose_difference() {
+    let mut a = Vpd::<DefaultConfig>::new("a".to_string());
+    a.bone_transforms = vec![bone(0, "センター", [1.0, 0.0, 0.0], 1.0)];
+    a.morph_values = vec![morph(0, "あ", 0.75)];
+    let mut b = Vpd::<DefaultConfig>::new("b".to_string());
+    b.bone_transforms = vec![
+      bone(0, "センター", [0.0, 2.0, 0.0], -0.5),
+      bone(1, "首", [0.0; 3], 0.3),
+    ];
+    b.morph_values = vec![morph(0, "あ", 0.5)];
+
+    // Applying the difference to `a` gives `b`
+    let difference = b.compose(&a.inverse());
+    let result = difference.compose(&a);
+
+    let center = &result.bone_transforms[0];
+    assert_near(center.position.as_slice(), &[0.0, 2.0, 0.0]);
+    assert_near(
+      center.rotation.as_slice(),
+      b.bone_transforms[0].rotation.as_slice(),
+    );
+    assert_eq!(result.bone_transforms[1], b.bone_transforms[1]);
+    assert!((result.morph_values[0].weight - 0.5).abs() < EPSILON);
+
+    let clamped = a.compose_with(&a, 0.0..=1.0);
+    assert_eq!(clamped.morph_values[0].weight, 1.0);
+    assert_near(
+      clamped.bone_transforms[0].rotation.as_slice(),
+      &[0.0, 1.0f32.sin(), 0.0, 1.0f32.cos()],
+    );
+  }
+}
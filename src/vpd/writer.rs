@@ -0,0 +1,163 @@
+use std::fmt::Write as _;
+use std::io::Write;
+
+use encoding_rs::SHIFT_JIS;
+
+use super::{Vpd, HEADER};
+use crate::math::to_array;
+use crate::Config;
+
+/// Fails with the name if it can't be written in Shift_JIS.
+fn check_name(name: &str) -> crate::Result<()> {
+  if SHIFT_JIS.encode(name).2 {
+    return Err(crate::Error::EncodeName {
+      name: name.to_string(),
+    });
+  }
+
+  Ok(())
+}
+
+fn join(values: &[f32]) -> String {
+  values
+    .iter()
+    .map(|v| format!("{:.6}", v))
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+impl<C: Config> Vpd<C> {
+  /// Writes the pose the way MMD does, as Shift_JIS text with CRLF line endings. The name of the
+  /// pose is not stored.
+  pub fn write<W: Write>(&self, write: &mut W) -> crate::Result<()> {
+    check_name(&self.parent_model)?;
+    for name in self.bone_transforms.iter().map(|b| &b.name) {
+      check_name(name)?;
+    }
+    for name in self.morph_values.iter().map(|m| &m.name) {
+      check_name(name)?;
+    }
+
+    // Writing into a String can't fail
+    let mut text = String::new();
+    let _ = write!(text, "{}\r\n\r\n", HEADER);
+    let _ = write!(text, "{};\t\t// 親ファイル名\r\n", self.parent_model);
+    let _ = write!(
+      text,
+      "{};\t\t\t\t// 総ポーズボーン数\r\n\r\n",
+      self.bone_transforms.len()
+    );
+
+    for bone in &self.bone_transforms {
+      let _ = write!(
+        text,
+        "Bone{}{{{}\r\n  {};\t\t\t\t// trans x,y,z\r\n  {};\t\t// Quaternion x,y,z,w\r\n}}\r\n\r\n",
+        bone.id,
+        bone.name,
+        join(&to_array::<3>(&bone.position)),
+        join(&to_array::<4>(&bone.rotation)),
+      );
+    }
+
+    for morph in &self.morph_values {
+      let _ = write!(
+        text,
+        "Morph{}{{{}\r\n  {:.6};\r\n}}\r\n\r\n",
+        morph.id, morph.name, morph.weight,
+      );
+    }
+
+    write.write_all(&SHIFT_JIS.encode(&text).0)?;
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::{BoneTransform, MorphValue};
+  use super::*;
+  use crate::DefaultConfig;
+
+  fn pose(bone: &str) -> Vpd {
+    let mut vpd = Vpd::<DefaultConfig>::new(String::new());
+    vpd.parent_model = "model.osm".to_string();
+    vpd.bone_transforms.push(BoneTransform {
+      id: 0,
+      name: bone.to_string(),
+      position: [1.5, -0.25, 0.0].into(),
+      rotation: [0.0, 0.0, 0.5f32.sin(), 0.5f32.cos()].into(),
+    });
+    vpd.bone_transforms.push(BoneTransform {
+      id: 1,
+      name: "neck".to_string(),
+      position: [0.0; 3].into(),
+      rotation: [0.0, 0.0, 0.0, 1.0].into(),
+    });
+    vpd.morph_values.push(MorphValue {
+      id: 0,
+      name: "smile".to_string(),
+      weight: 0.75,
+      offset: [0.0; 3].into(),
+    });
+    vpd
+  }
+
+  #[test]
+  fn test_vpd_write_format() {
+    let mut buf = Vec::new();
+    pose("center").write(&mut buf).unwrap();
+    let text = SHIFT_JIS.decode(&buf).0;
+
+    let mut lines = text.split("\r\n");
+    assert_eq!(lines.next(), Some("Vocaloid Pose Data file"));
+    assert_eq!(lines.next(), Some(""));
+    assert_eq!(lines.next(), Some("model.osm;\t\t// 親ファイル名"));
+    assert_eq!(lines.next(), Some("2;\t\t\t\t// 総ポーズボーン数"));
+    assert_eq!(lines.next(), Some(""));
+    assert_eq!(lines.next(), Some("Bone0{center"));
+    assert_eq!(
+      lines.next(),
+      Some("  1.500000,-0.250000,0.000000;\t\t\t\t// trans x,y,z")
+    );
+    assert_eq!(
+      lines.next(),
+      Some("  0.000000,0.000000,0.479426,0.877583;\t\t// Quaternion x,y,z,w")
+    );
+    assert_eq!(lines.next(), Some("}"));
+    assert!(text.ends_with("Morph0{smile\r\n  0.750000;\r\n}\r\n\r\n"));
+  }
+
+  #[test]
+  fn test_vpd_write_round_trip() {
+    let vpd = pose("center");
+    let mut buf = Vec::new();
+    vpd.write(&mut buf).unwrap();
+
+    let read = Vpd::<DefaultConfig>::read(&buf[..]).unwrap();
+
+    assert_eq!(read.parent_model, vpd.parent_model);
+    assert_eq!(read.bone_transforms[1], vpd.bone_transforms[1]);
+    assert_eq!(read.morph_values, vpd.morph_values);
+    let rotation = to_array::<4>(&read.bone_transforms[0].rotation);
+    let expected = to_array::<4>(&vpd.bone_transforms[0].rotation);
+    assert!(rotation
+      .iter()
+      .zip(&expected)
+      .all(|(a, b)| (a - b).abs() < 1e-6));
+  }
+
+  #[test]
+  fn test_vpd_write_shift_jis() {
+    let mut buf = Vec::new();
+    pose("右腕").write(&mut buf).unwrap();
+
+    let encoded = SHIFT_JIS.encode("Bone0{右腕\r\n").0;
+    assert!(buf.windows(encoded.len()).any(|w| w == &encoded[..]));
+
+    match pose("💃").write(&mut Vec::new()) {
+      Err(crate::Error::EncodeName { name }) => assert_eq!(name, "💃"),
+      result => panic!("{:?}", result),
+    }
+  }
+}